@@ -1,3 +1,5 @@
 fn main() {
+    // Only the desktop app needs Tauri's build step
+    #[cfg(feature = "app")]
     tauri_build::build()
-}
\ No newline at end of file
+}
@@ -1,46 +1,373 @@
 use anyhow::{Context, Result, anyhow};
-use std::path::PathBuf;
+use async_trait::async_trait;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 use tracing::{debug, info, warn, error};
 
+use crate::version::{self, Version};
+
+/// What a built binary asks of the machine it runs on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BinaryInfo {
+    /// CPU architecture, as `uname -m` names it
+    pub arch: Option<&'static str>,
+    /// Oldest glibc that has every symbol version the binary links against;
+    /// `None` for a static binary, musl's included
+    pub glibc: Option<GlibcVersion>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GlibcVersion(pub u32, pub u32);
+
+impl fmt::Display for GlibcVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+impl GlibcVersion {
+    /// `2.35` or `2.35.1`, the patch level ignored
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some(Self(major, minor))
+    }
+}
+
+impl BinaryInfo {
+    /// Read an ELF binary's architecture from its header and its glibc
+    /// needs from the `GLIBC_2.x` symbol versions it carries
+    pub fn inspect(binary: &[u8]) -> Self {
+        let arch = match binary.get(..20) {
+            Some(header) if header.starts_with(b"\x7fELF") => match u16::from_le_bytes([header[18], header[19]]) {
+                0x03 => Some("i686"),
+                0x3e => Some("x86_64"),
+                0xb7 => Some("aarch64"),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        const MARKER: &[u8] = b"GLIBC_";
+        let glibc = binary
+            .windows(MARKER.len())
+            .enumerate()
+            .filter(|(_, window)| *window == MARKER)
+            .filter_map(|(at, _)| {
+                let rest = &binary[at + MARKER.len()..];
+                let end = rest.iter().position(|b| !(b.is_ascii_digit() || *b == b'.')).unwrap_or(rest.len());
+                GlibcVersion::parse(std::str::from_utf8(&rest[..end]).ok()?)
+            })
+            .max();
+
+        Self { arch, glibc }
+    }
+
+    pub async fn of(binary_path: &Path) -> Result<Self> {
+        let binary = tokio::fs::read(binary_path).await
+            .with_context(|| format!("Failed to read {:?}", binary_path))?;
+        Ok(Self::inspect(&binary))
+    }
+}
+
+/// Runs the programs the builder checks with; a stand-in in tests
+#[async_trait]
+pub trait Runner: Send + Sync {
+    /// Run `program` with `args` in `dir`, passing its output on to the
+    /// terminal as it comes; answers its exit status and all it wrote
+    async fn stream(&self, program: &str, args: &[String], dir: &Path) -> Result<(i32, String)>;
+
+    /// Run `program` with `args` quietly; answers its exit status and
+    /// standard output
+    async fn output(&self, program: &str, args: &[&str]) -> Result<(i32, String)>;
+}
+
+/// Runs programs on this machine
+pub struct Processes;
+
+#[async_trait]
+impl Runner for Processes {
+    async fn stream(&self, program: &str, args: &[String], dir: &Path) -> Result<(i32, String)> {
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn {}", program))?;
+        let mut stdout = BufReader::new(child.stdout.take().expect("piped")).lines();
+        let mut stderr = BufReader::new(child.stderr.take().expect("piped")).lines();
+        let (mut stdout_open, mut stderr_open) = (true, true);
+        let mut output = String::new();
+        while stdout_open || stderr_open {
+            tokio::select! {
+                line = stdout.next_line(), if stdout_open => match line? {
+                    Some(line) => {
+                        println!("{}", line);
+                        output.push_str(&line);
+                        output.push('\n');
+                    }
+                    None => stdout_open = false,
+                },
+                line = stderr.next_line(), if stderr_open => match line? {
+                    Some(line) => {
+                        eprintln!("{}", line);
+                        output.push_str(&line);
+                        output.push('\n');
+                    }
+                    None => stderr_open = false,
+                },
+            }
+        }
+        let status = child.wait().await
+            .with_context(|| format!("Failed to wait for {}", program))?;
+        Ok((status.code().unwrap_or(-1), output))
+    }
+
+    async fn output(&self, program: &str, args: &[&str]) -> Result<(i32, String)> {
+        let output = Command::new(program).args(args).output().await
+            .with_context(|| format!("Failed to run {}", program))?;
+        Ok((output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+}
+
+/// Checks run before the release build, the first to fail stopping the
+/// deploy
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Checks {
+    /// `cargo test --release`
+    pub test: bool,
+    /// `cargo clippy`, with warnings failing it
+    pub clippy: bool,
+    /// Check the whole workspace rather than just the relay
+    pub workspace: bool,
+}
+
+/// How the relay is built
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildOptions {
+    /// Target triple to build for, such as `x86_64-unknown-linux-musl`;
+    /// the build machine's own by default
+    pub target: Option<String>,
+    /// Build with `cross`, in a container with the target's toolchain
+    pub use_cross: bool,
+    /// The relay's cargo features to turn on, besides its defaults
+    pub features: Vec<String>,
+    /// The cargo profile to build with; `release` unless given
+    pub profile: Option<String>,
+    /// Where cargo builds; `relay-server/target` unless given
+    pub target_dir: Option<PathBuf>,
+    /// The relay's Cargo.toml; `relay-server/Cargo.toml` unless given
+    pub manifest_path: Option<PathBuf>,
+}
+
 pub struct Builder {
     workspace_root: PathBuf,
+    options: BuildOptions,
+    checks: Checks,
+    runner: Arc<dyn Runner>,
 }
 
 impl Builder {
-    pub fn new() -> Self {
+    pub fn new(mut options: BuildOptions, checks: Checks) -> Self {
         // Determine workspace root (should be parent of deploy-tool)
         let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        // Cargo runs elsewhere, so paths given relative to here are made whole
+        for path in [&mut options.target_dir, &mut options.manifest_path].into_iter().flatten() {
+            *path = current_dir.join(&*path);
+        }
         let workspace_root = if current_dir.file_name().map(|n| n == "deploy-tool").unwrap_or(false) {
             current_dir.parent().unwrap_or(&current_dir).to_path_buf()
         } else {
             current_dir
         };
 
-        Self { workspace_root }
+        Self { workspace_root, options, checks, runner: Arc::new(Processes) }
+    }
+
+    fn profile(&self) -> &str {
+        self.options.profile.as_deref().unwrap_or("release")
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.options.manifest_path.clone().unwrap_or_else(|| self.workspace_root.join("relay-server/Cargo.toml"))
+    }
+
+    /// Where builds go; given to cargo explicitly, as it would otherwise
+    /// pick the workspace's
+    fn target_dir(&self) -> PathBuf {
+        self.options.target_dir.clone().unwrap_or_else(|| self.workspace_root.join("relay-server/target"))
+    }
+
+    /// The arguments choosing the profile, manifest and target directory,
+    /// which the build and the checks share
+    fn common_args(&self) -> Vec<String> {
+        let mut args = match self.profile() {
+            "release" => vec!["--release".to_string()],
+            profile => vec!["--profile".to_string(), profile.to_string()],
+        };
+        args.extend([
+            "--manifest-path".to_string(),
+            self.manifest_path().display().to_string(),
+            "--target-dir".to_string(),
+            self.target_dir().display().to_string(),
+        ]);
+        args
+    }
+
+    /// `--features` naming `features`, if there are any
+    fn features_args(features: &[String]) -> Vec<String> {
+        if features.is_empty() {
+            return Vec::new();
+        }
+        vec!["--features".to_string(), features.join(",")]
+    }
+
+    /// The program to build with and its arguments
+    fn build_command(&self) -> (&'static str, Vec<String>) {
+        let program = if self.options.use_cross { "cross" } else { "cargo" };
+        let mut args = vec!["build".to_string()];
+        args.extend(self.common_args());
+        if let Some(target) = &self.options.target {
+            args.extend(["--target".to_string(), target.clone()]);
+        }
+        args.extend(Self::features_args(&self.options.features));
+        (program, args)
+    }
+
+    /// The cargo commands for the checks asked for, in the order they run.
+    /// They run on this machine whatever the target, with the build's
+    /// profile and features, and share its target directory so the build
+    /// reuses what they compiled.
+    fn check_commands(&self) -> Vec<Vec<String>> {
+        let mut scope = self.common_args();
+        if self.checks.workspace {
+            // Features are named for the relay when every crate is built
+            scope.push("--workspace".to_string());
+            let features: Vec<String> = self.options.features.iter().map(|feature| format!("silence-relay/{}", feature)).collect();
+            scope.extend(Self::features_args(&features));
+        } else {
+            scope.extend(Self::features_args(&self.options.features));
+        }
+        let mut commands = Vec::new();
+        if self.checks.test {
+            commands.push([vec!["test".to_string()], scope.clone()].concat());
+        }
+        if self.checks.clippy {
+            let lint = ["--all-targets", "--", "-D", "warnings"].map(String::from);
+            commands.push([vec!["clippy".to_string()], scope, lint.to_vec()].concat());
+        }
+        commands
+    }
+
+    /// Run the checks asked for, streaming their output; one that fails
+    /// stops the build with the summary of what went wrong
+    async fn run_checks(&self) -> Result<()> {
+        for args in self.check_commands() {
+            let name = format!("cargo {}", args[0]);
+            info!("🧪 Running {}...", name);
+            debug!("Running cargo {}", args.join(" "));
+            let (exit_status, output) = self.runner.stream("cargo", &args, &self.workspace_root).await?;
+            if exit_status != 0 {
+                return Err(anyhow!("{} failed with exit code {}, so nothing was built:\n{}", name, exit_status, failure_summary(&output)));
+            }
+            info!("✅ {} passed", name);
+        }
+        Ok(())
+    }
+
+    /// The environment the build gets: the commit for the relay to embed,
+    /// which cross has to be told to pass into its container
+    fn build_env(&self, version: &Version) -> Vec<(&'static str, String)> {
+        let Some(commit) = &version.commit else { return Vec::new() };
+        let mut env = vec![(version::COMMIT_VAR, commit.clone())];
+        if self.options.use_cross {
+            env.push(("CROSS_BUILD_ENV_PASSTHROUGH", version::COMMIT_VAR.to_string()));
+        }
+        env
+    }
+
+    async fn relay_crate_version(&self) -> Result<String> {
+        let manifest_path = self.manifest_path();
+        let manifest = tokio::fs::read_to_string(&manifest_path).await
+            .with_context(|| format!("Failed to read {:?}", manifest_path))?;
+        crate_version(&manifest).ok_or_else(|| anyhow!("No package version in {:?}", manifest_path))
+    }
+
+    /// What a build now is made from: the relay's crate version, the
+    /// checkout's commit if it is one, the time, and the profile and
+    /// features it's built with
+    pub async fn version(&self) -> Result<Version> {
+        let crate_version = self.relay_crate_version().await?;
+        let git = |args: &'static [&'static str]| {
+            let root = self.workspace_root.clone();
+            async move {
+                let output = Command::new("git").args(args).current_dir(root).output().await.ok()?;
+                output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+            }
+        };
+        let commit = match (git(&["rev-parse", "HEAD"]).await, git(&["status", "--porcelain"]).await) {
+            (Some(head), Some(changes)) => commit_name(&head, &changes),
+            _ => None,
+        };
+        Ok(Version {
+            profile: Some(self.profile().to_string()),
+            features: self.options.features.clone(),
+            ..Version::new(&crate_version, commit, SystemTime::now())
+        })
+    }
+
+    /// What the binary at `binary_path` was built from, as its build
+    /// recorded next to it; a binary built some other way is taken to be
+    /// the crate's current version, built when it was last written
+    pub async fn recorded_version(&self, binary_path: &Path) -> Result<Version> {
+        if let Ok(json) = tokio::fs::read_to_string(Version::path_for(binary_path)).await {
+            if let Some(version) = Version::parse(&json) {
+                return Ok(version);
+            }
+        }
+        warn!("No {} next to {:?}, so the commit it was built from isn't known", version::FILE_NAME, binary_path);
+        let built_at = tokio::fs::metadata(binary_path).await
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        Ok(Version::new(&self.relay_crate_version().await?, None, built_at))
     }
 
     pub async fn build(&self) -> Result<PathBuf> {
-        info!("Building relay server in release mode...");
+        info!("Building relay server with the {} profile...", self.profile());
         
         // Change to relay-server directory
-        let relay_server_dir = self.workspace_root.join("relay-server");
+        let manifest_path = self.manifest_path();
+        let relay_server_dir = manifest_path.parent().unwrap_or(Path::new(".")).to_path_buf();
         
-        if !relay_server_dir.exists() {
-            return Err(anyhow!("Relay server directory not found at {:?}", relay_server_dir));
+        if !manifest_path.exists() {
+            return Err(anyhow!("Relay server manifest not found at {:?}", manifest_path));
         }
 
         debug!("Building in directory: {:?}", relay_server_dir);
 
+        self.run_checks().await?;
+
+        let version = self.version().await?;
+        info!("🏷️  Building {}", version);
+
         // Execute cargo build --release
-        let build_cmd = Command::new("cargo")
-            .args(&["build", "--release"])
+        let (program, args) = self.build_command();
+        debug!("Running {} {}", program, args.join(" "));
+        let build_cmd = Command::new(program)
+            .args(&args)
+            .envs(self.build_env(&version))
             .current_dir(&relay_server_dir)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .context("Failed to spawn cargo build command")?;
+            .with_context(|| format!("Failed to spawn {} build command", program))?;
 
         // Wait for the build to complete
         let output = build_cmd.wait_with_output().await
@@ -64,7 +391,7 @@ impl Builder {
         }
 
         // Verify the binary exists
-        let binary_path = relay_server_dir.join("target/release/silence-relay");
+        let binary_path = self.get_binary_path();
         
         if !binary_path.exists() {
             return Err(anyhow!("Built binary not found at {:?}", binary_path));
@@ -92,17 +419,20 @@ impl Builder {
         let size_mb = metadata.len() as f64 / 1_048_576.0;
         info!("Binary size: {:.2} MB", size_mb);
 
+        // Recorded for packaging, now or with --skip-build later
+        tokio::fs::write(Version::path_for(&binary_path), version.to_json()).await
+            .context("Failed to record the build's version")?;
+
         Ok(binary_path)
     }
 
     pub async fn clean(&self) -> Result<()> {
         info!("Cleaning build artifacts...");
         
-        let relay_server_dir = self.workspace_root.join("relay-server");
-        
+        let manifest_path = self.manifest_path().display().to_string();
+        let target_dir = self.target_dir().display().to_string();
         let clean_cmd = Command::new("cargo")
-            .args(&["clean"])
-            .current_dir(&relay_server_dir)
+            .args(["clean", "--manifest-path", &manifest_path, "--target-dir", &target_dir])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
@@ -121,22 +451,30 @@ impl Builder {
         Ok(())
     }
 
+    /// Cargo puts a build for an explicit target under a directory named
+    /// for it, and names the directory for the profile other than for `dev`
+    /// and `test`, which build into `debug`, and `bench`, into `release`
     pub fn get_binary_path(&self) -> PathBuf {
-        self.workspace_root.join("relay-server/target/release/silence-relay")
+        let mut path = self.target_dir();
+        if let Some(target) = &self.options.target {
+            path.push(target);
+        }
+        let profile_dir = match self.profile() {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        };
+        path.join(profile_dir).join("silence-relay")
     }
 
     pub async fn verify_cargo_available(&self) -> Result<()> {
-        let output = Command::new("cargo")
-            .args(&["--version"])
-            .output()
-            .await
+        let (exit_status, version) = self.runner.output("cargo", &["--version"]).await
             .context("Failed to execute cargo --version")?;
 
-        if !output.status.success() {
+        if exit_status != 0 {
             return Err(anyhow!("Cargo is not available or not working properly"));
         }
 
-        let version = String::from_utf8_lossy(&output.stdout);
         debug!("Cargo version: {}", version.trim());
         
         Ok(())
@@ -148,13 +486,321 @@ impl Builder {
         self.verify_cargo_available().await
             .context("Cargo verification failed")?;
 
-        let relay_server_dir = self.workspace_root.join("relay-server");
-        
-        if !relay_server_dir.join("Cargo.toml").exists() {
-            return Err(anyhow!("relay-server/Cargo.toml not found"));
+        let manifest_path = self.manifest_path();
+        if !manifest_path.exists() {
+            return Err(anyhow!("{} not found", manifest_path.display()));
+        }
+
+        if self.options.use_cross {
+            let available = self.runner.output("cross", &["--version"]).await;
+            if !available.map(|(exit_status, _)| exit_status == 0).unwrap_or(false) {
+                return Err(anyhow!("cross is not available; install it with `cargo install cross` (it also needs Docker or Podman)"));
+            }
+        } else if let Some(target) = &self.options.target {
+            // cross brings its own toolchain, a plain cargo build needs the
+            // target installed
+            let (_, installed) = self.runner.output("rustup", &["target", "list", "--installed"]).await
+                .context("Failed to run rustup to check the installed targets")?;
+            check_target_installed(&installed, target)?;
+        }
+
+        // The checks run with the machine's own cargo, even for a cross build
+        if self.checks.clippy {
+            let available = self.runner.output("cargo", &["clippy", "--version"]).await;
+            if !available.map(|(exit_status, _)| exit_status == 0).unwrap_or(false) {
+                return Err(anyhow!("clippy is not installed; add it with `rustup component add clippy`"));
+            }
+        }
+        if self.checks.workspace && !self.workspace_root.join("Cargo.toml").exists() {
+            return Err(anyhow!("No workspace Cargo.toml in {:?} to check", self.workspace_root));
         }
 
         info!("✅ Build dependencies verified");
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// The `[package]` version in a Cargo.toml
+fn crate_version(manifest: &str) -> Option<String> {
+    let manifest: toml::Value = toml::from_str(manifest).ok()?;
+    Some(manifest.get("package")?.get("version")?.as_str()?.to_string())
+}
+
+/// The commit `git rev-parse HEAD` names, marked `-dirty` when
+/// `git status --porcelain` lists changes
+fn commit_name(head: &str, changes: &str) -> Option<String> {
+    let head = head.trim();
+    if head.is_empty() {
+        return None;
+    }
+    Some(if changes.trim().is_empty() { head.to_string() } else { format!("{}-dirty", head) })
+}
+
+/// The lines of a failed check's output that say what failed: cargo test's
+/// panics and results, clippy's and the compiler's errors and where they
+/// are; its last lines if none of those are there
+fn failure_summary(output: &str) -> String {
+    const MOST_LINES: usize = 40;
+    let lines: Vec<&str> = output.lines().collect();
+    let mut summary: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|line| {
+            line.starts_with("test result:") || line.starts_with("error") || line.starts_with("  --> ") || line.contains(" panicked at ")
+        })
+        .collect();
+    if summary.is_empty() {
+        summary = lines[lines.len().saturating_sub(MOST_LINES)..].to_vec();
+    }
+    let more = summary.len().saturating_sub(MOST_LINES);
+    summary.truncate(MOST_LINES);
+    let mut text = summary.join("\n");
+    if more > 0 {
+        text.push_str(&format!("\n... and {} more lines", more));
+    }
+    text
+}
+
+/// `installed` being `rustup target list --installed` output
+fn check_target_installed(installed: &str, target: &str) -> Result<()> {
+    if installed.lines().any(|line| line.trim() == target) {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Rust target {target} is not installed; add it with `rustup target add {target}`, \
+         or build in a container with --use-cross",
+        target = target
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Answers programs from a script, keyed by the whole command line, and
+    /// remembers what ran; anything unscripted succeeds with no output
+    #[derive(Default)]
+    struct ScriptedRunner {
+        replies: HashMap<String, (i32, String)>,
+        ran: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedRunner {
+        fn reply(mut self, command: &str, exit_status: i32, output: &str) -> Self {
+            self.replies.insert(command.to_string(), (exit_status, output.to_string()));
+            self
+        }
+
+        fn answer(&self, command: String) -> Result<(i32, String)> {
+            let reply = self.replies.get(&command).cloned().unwrap_or_default();
+            self.ran.lock().unwrap().push(command);
+            Ok(reply)
+        }
+    }
+
+    #[async_trait]
+    impl Runner for ScriptedRunner {
+        async fn stream(&self, program: &str, args: &[String], _dir: &Path) -> Result<(i32, String)> {
+            self.answer(format!("{} {}", program, args.join(" ")))
+        }
+
+        async fn output(&self, program: &str, args: &[&str]) -> Result<(i32, String)> {
+            self.answer(format!("{} {}", program, args.join(" ")))
+        }
+    }
+
+    fn built_with(options: BuildOptions) -> Builder {
+        Builder {
+            workspace_root: "/src/silence".into(),
+            options,
+            checks: Checks::default(),
+            runner: Arc::new(ScriptedRunner::default()),
+        }
+    }
+
+    fn builder(target: Option<&str>, use_cross: bool) -> Builder {
+        built_with(BuildOptions { target: target.map(String::from), use_cross, ..BuildOptions::default() })
+    }
+
+    fn checking(checks: Checks, runner: ScriptedRunner) -> (Builder, Arc<ScriptedRunner>) {
+        let runner = Arc::new(runner);
+        (Builder { checks, runner: runner.clone(), ..builder(None, false) }, runner)
+    }
+
+    #[test]
+    fn test_binary_path_follows_the_target_and_profile() {
+        assert_eq!(builder(None, false).get_binary_path(), Path::new("/src/silence/relay-server/target/release/silence-relay"));
+        assert_eq!(
+            builder(Some("x86_64-unknown-linux-musl"), false).get_binary_path(),
+            Path::new("/src/silence/relay-server/target/x86_64-unknown-linux-musl/release/silence-relay")
+        );
+
+        let musl = Some("x86_64-unknown-linux-musl".to_string());
+        let cases = [
+            (None, None, None, "/src/silence/relay-server/target/release"),
+            (Some("release"), None, None, "/src/silence/relay-server/target/release"),
+            (Some("min-size"), None, None, "/src/silence/relay-server/target/min-size"),
+            (Some("dev"), None, None, "/src/silence/relay-server/target/debug"),
+            (Some("test"), musl.clone(), None, "/src/silence/relay-server/target/x86_64-unknown-linux-musl/debug"),
+            (Some("bench"), None, Some("/tmp/shared"), "/tmp/shared/release"),
+            (Some("min-size"), musl, Some("/tmp/shared"), "/tmp/shared/x86_64-unknown-linux-musl/min-size"),
+        ];
+        for (profile, target, target_dir, dir) in cases {
+            let options = BuildOptions {
+                profile: profile.map(String::from),
+                target: target.clone(),
+                target_dir: target_dir.map(PathBuf::from),
+                ..BuildOptions::default()
+            };
+            assert_eq!(built_with(options).get_binary_path(), Path::new(dir).join("silence-relay"), "{:?} {:?}", profile, target);
+        }
+    }
+
+    #[test]
+    fn test_build_command() {
+        let (program, args) = builder(None, false).build_command();
+        assert_eq!(
+            (program, args.join(" ")),
+            ("cargo", "build --release --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target".to_string())
+        );
+        let (program, args) = builder(Some("x86_64-unknown-linux-musl"), true).build_command();
+        assert_eq!(program, "cross");
+        assert_eq!(
+            args.join(" "),
+            "build --release --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target --target x86_64-unknown-linux-musl"
+        );
+
+        let options = BuildOptions {
+            features: vec!["tls".to_string(), "metrics".to_string()],
+            profile: Some("min-size".to_string()),
+            target_dir: Some("/src/target".into()),
+            manifest_path: Some("/src/relay/Cargo.toml".into()),
+            ..BuildOptions::default()
+        };
+        assert_eq!(
+            built_with(options).build_command().1.join(" "),
+            "build --profile min-size --manifest-path /src/relay/Cargo.toml --target-dir /src/target --features tls,metrics"
+        );
+    }
+
+    #[test]
+    fn test_check_commands() {
+        assert!(builder(None, false).check_commands().is_empty());
+
+        let both = Checks { test: true, clippy: true, workspace: false };
+        let commands: Vec<String> = checking(both, ScriptedRunner::default()).0.check_commands().iter().map(|args| args.join(" ")).collect();
+        assert_eq!(
+            commands,
+            [
+                "test --release --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target",
+                "clippy --release --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target --all-targets -- -D warnings",
+            ]
+        );
+
+        // With the build's profile and features
+        let workspace = Checks { test: true, clippy: false, workspace: true };
+        let (builder, _) = checking(workspace, ScriptedRunner::default());
+        let options = BuildOptions { features: vec!["tls".to_string()], profile: Some("min-size".to_string()), ..BuildOptions::default() };
+        assert_eq!(
+            Builder { options, ..builder }.check_commands()[0].join(" "),
+            "test --profile min-size --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target --workspace --features silence-relay/tls"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_a_failing_check_stops_the_build() {
+        let output = "   Compiling silence-relay v0.1.0\n\
+                      running 2 tests\n\
+                      test tests::test_frames ... ok\n\
+                      test tests::test_drain ... FAILED\n\n\
+                      failures:\n\n\
+                      ---- tests::test_drain stdout ----\n\
+                      thread 'tests::test_drain' panicked at src/lib.rs:10:5:\n\
+                      assertion failed: drained\n\n\
+                      test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out\n\n\
+                      error: test failed, to rerun pass `-p silence-relay --lib`\n";
+        let both = Checks { test: true, clippy: true, workspace: false };
+        let test = "cargo test --release --manifest-path /src/silence/relay-server/Cargo.toml --target-dir /src/silence/relay-server/target";
+        let (builder, runner) = checking(both, ScriptedRunner::default().reply(test, 101, output));
+
+        let error = builder.run_checks().await.unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "cargo test failed with exit code 101, so nothing was built:\n\
+             thread 'tests::test_drain' panicked at src/lib.rs:10:5:\n\
+             test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out\n\
+             error: test failed, to rerun pass `-p silence-relay --lib`"
+        );
+        assert_eq!(*runner.ran.lock().unwrap(), [test], "clippy doesn't run after the tests fail");
+
+        let (builder, runner) = checking(both, ScriptedRunner::default());
+        builder.run_checks().await.unwrap();
+        assert_eq!(runner.ran.lock().unwrap().len(), 2);
+
+        // Output with nothing recognisable is kept to its last lines
+        let noisy: String = (1..=50).map(|n| format!("line {}\n", n)).collect();
+        let summary = failure_summary(&noisy);
+        assert!(summary.starts_with("line 11\n") && summary.ends_with("line 50"), "{}", summary);
+    }
+
+    #[tokio::test]
+    async fn test_clippy_must_be_installed_to_lint() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("relay-server")).unwrap();
+        std::fs::write(dir.path().join("relay-server/Cargo.toml"), "").unwrap();
+        let missing = ScriptedRunner::default().reply("cargo clippy --version", 101, "");
+        let (builder, _) = checking(Checks { clippy: true, ..Checks::default() }, missing);
+        let builder = Builder { workspace_root: dir.path().to_path_buf(), ..builder };
+        let error = builder.check_dependencies().await.unwrap_err().to_string();
+        assert!(error.contains("rustup component add clippy"), "{}", error);
+
+        let (builder, runner) = checking(Checks { test: true, workspace: true, ..Checks::default() }, ScriptedRunner::default());
+        let builder = Builder { workspace_root: dir.path().to_path_buf(), ..builder };
+        let error = builder.check_dependencies().await.unwrap_err().to_string();
+        assert!(error.contains("No workspace Cargo.toml"), "{}", error);
+        assert_eq!(*runner.ran.lock().unwrap(), ["cargo --version"], "tests need nothing beyond cargo");
+    }
+
+    #[test]
+    fn test_missing_target_explains_the_fix() {
+        let installed = "x86_64-unknown-linux-gnu\nx86_64-unknown-linux-musl\n";
+        assert!(check_target_installed(installed, "x86_64-unknown-linux-musl").is_ok());
+        let error = check_target_installed(installed, "aarch64-unknown-linux-musl").unwrap_err().to_string();
+        assert!(error.contains("rustup target add aarch64-unknown-linux-musl"), "{}", error);
+    }
+
+    #[test]
+    fn test_version_sources() {
+        let manifest = "[package]\nname = \"silence-relay\"\nversion = \"0.3.1\"\n\n[dependencies]\nbytes = \"1\"\n";
+        assert_eq!(crate_version(manifest).as_deref(), Some("0.3.1"));
+        assert_eq!(crate_version("[workspace]\nmembers = []\n"), None);
+
+        let head = "3fd0229e37401142d86ad7663c56e82f26b71d0b\n";
+        assert_eq!(commit_name(head, "").as_deref(), Some("3fd0229e37401142d86ad7663c56e82f26b71d0b"));
+        assert_eq!(commit_name(head, " M relay-server/src/lib.rs\n").as_deref(), Some("3fd0229e37401142d86ad7663c56e82f26b71d0b-dirty"));
+        assert_eq!(commit_name("", ""), None);
+
+        let version = |commit: Option<&str>| Version::new("0.3.1", commit.map(String::from), SystemTime::UNIX_EPOCH);
+        assert!(builder(None, false).build_env(&version(None)).is_empty());
+        assert_eq!(builder(None, false).build_env(&version(Some("3fd0229e"))), [("SILENCE_RELAY_COMMIT", "3fd0229e".to_string())]);
+        assert_eq!(
+            builder(Some("aarch64-unknown-linux-musl"), true).build_env(&version(Some("3fd0229e")))[1],
+            ("CROSS_BUILD_ENV_PASSTHROUGH", "SILENCE_RELAY_COMMIT".to_string())
+        );
+    }
+
+    #[test]
+    fn test_inspect_binary() {
+        let mut binary = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x03\0\x3e\0".to_vec();
+        binary.extend_from_slice(b"\0GLIBC_2.2.5\0GLIBC_2.34\0GLIBC_2.17\0GLIBC_PRIVATE\0");
+        assert_eq!(BinaryInfo::inspect(&binary), BinaryInfo { arch: Some("x86_64"), glibc: Some(GlibcVersion(2, 34)) });
+
+        // A static musl build names no glibc versions
+        binary.truncate(20);
+        binary[18] = 0xb7;
+        assert_eq!(BinaryInfo::inspect(&binary), BinaryInfo { arch: Some("aarch64"), glibc: None });
+        assert_eq!(BinaryInfo::inspect(b"#!/bin/sh"), BinaryInfo::default());
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+use crate::ssh::{Remote, UPLOAD_CHUNK};
+
+/// SHA-256 of `bytes` as `sha256sum` prints it: 64 lowercase hex digits
+#[cfg(test)]
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// SHA-256 of the file at `path`, read a chunk at a time
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; UPLOAD_CHUNK];
+    loop {
+        let read = file.read(&mut buffer).await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The digest a line of `sha256sum` output starts with
+pub fn parse_sha256sum(line: &str) -> Option<&str> {
+    let digest = line.split_whitespace().next()?;
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// The error for `what` having the digest `actual` rather than `expected`
+pub fn mismatch(what: &str, expected: &str, actual: &str) -> anyhow::Error {
+    anyhow!(
+        "{} is corrupt: its SHA-256 should be {} but the server's copy has {}",
+        what, expected, actual
+    )
+}
+
+/// Check the file at `path` on the server has the SHA-256 `expected`: the
+/// server compares, and only on a mismatch is asked for the digest it has
+pub async fn verify_remote(remote: &mut dyn Remote, path: &str, expected: &str, what: &str) -> Result<()> {
+    let check = format!("echo '{}  {}' | sha256sum -c --status", expected, path);
+    let (exit_status, _) = remote.run(&check).await
+        .with_context(|| format!("Failed to checksum {} on the server", path))?;
+    if exit_status == 0 {
+        return Ok(());
+    }
+    let (_, output) = remote.run(&format!("sha256sum {}", path)).await?;
+    let actual = parse_sha256sum(&output).unwrap_or("none, as it can't be read");
+    Err(mismatch(what, expected, actual))
+}
+
+/// The release's binary digest from a package's `deploy.conf`
+pub fn recorded_sha256(manifest: &str) -> Option<String> {
+    let manifest: toml::Value = toml::from_str(manifest).ok()?;
+    let digest = manifest.get("release")?.get("binary_sha256")?.as_str()?;
+    parse_sha256sum(digest).map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::tests::ScriptedRemote;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[tokio::test]
+    async fn test_digests() {
+        assert_eq!(sha256_hex(b"abc"), ABC);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b"abc").unwrap();
+        assert_eq!(sha256_file(file.path()).await.unwrap(), ABC);
+        // Read in several chunks
+        let large: Vec<u8> = (0..UPLOAD_CHUNK * 2 + 7).map(|i| i as u8).collect();
+        std::fs::write(file.path(), &large).unwrap();
+        assert_eq!(sha256_file(file.path()).await.unwrap(), sha256_hex(&large));
+        assert!(sha256_file(Path::new("/nonexistent/relay")).await.is_err());
+
+        assert_eq!(parse_sha256sum(&format!("{}  /opt/silence-relay/current/silence-relay\n", ABC)), Some(ABC));
+        assert_eq!(parse_sha256sum("sha256sum: silence-relay: No such file or directory"), None);
+        assert_eq!(parse_sha256sum(""), None);
+
+        let manifest = format!("[server]\nhost = \"relay.example\"\n\n[release]\nbinary_sha256 = \"{}\"\n", ABC);
+        assert_eq!(recorded_sha256(&manifest).as_deref(), Some(ABC));
+        assert_eq!(recorded_sha256("[server]\nhost = \"relay.example\"\n"), None, "packaged before digests");
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_the_server() {
+        let check = format!("echo '{}  /tmp/package.tar.gz' | sha256sum -c --status", ABC);
+        let mut intact = ScriptedRemote::default().reply(&check, 0, "");
+        verify_remote(&mut intact, "/tmp/package.tar.gz", ABC, "Uploaded package").await.unwrap();
+        assert_eq!(intact.ran, [check.as_str()]);
+
+        let truncated = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let mut corrupt = ScriptedRemote::default()
+            .reply(&check, 1, "")
+            .reply("sha256sum /tmp/package.tar.gz", 0, &format!("{}  /tmp/package.tar.gz\n", truncated));
+        let error = verify_remote(&mut corrupt, "/tmp/package.tar.gz", ABC, "Uploaded package").await.unwrap_err().to_string();
+        assert!(error.starts_with("Uploaded package is corrupt"), "{}", error);
+        assert!(error.contains(ABC) && error.contains(truncated), "{}", error);
+
+        let mut missing = ScriptedRemote::default();
+        let error = verify_remote(&mut missing, "/tmp/package.tar.gz", ABC, "Uploaded package").await.unwrap_err().to_string();
+        assert!(error.contains("none, as it can't be read"), "{}", error);
+    }
+}
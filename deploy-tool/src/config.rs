@@ -0,0 +1,375 @@
+// deploy.toml - the tool's settings, so they needn't be passed every time
+//
+// Keys are named after their flags (`max_clients` for `--max-clients`), save
+// `smoke_test = false` for `--no-smoke-test` and `[[hosts]]` tables, which
+// list hosts as an inventory file does. Values are merged with precedence
+// command line > environment > deploy.toml > built-in defaults.
+
+use anyhow::{Context, Result, anyhow};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::connect::JumpHost;
+use crate::deployer::Mode;
+use crate::hosts::HostEntry;
+use crate::{Cli, Command, RelayArgs};
+
+/// Where the settings are looked for without `--config`
+pub const DEFAULT_PATH: &str = "deploy.toml";
+
+/// What `init` writes: every key, commented out at its default
+pub const TEMPLATE: &str = r#"# Settings for the deploy tool. Command line options win over environment
+# variables, which win over this file. Uncomment what you need.
+
+# The server, and how to log in to it
+# host = "your-server.cherryservers.net"
+# user = "root"
+# ssh_key = "~/.ssh/id_rsa"
+# ssh_port = 22
+# jump_host = "admin@bastion.example:22"
+# password_auth = false
+# known_hosts = "~/.ssh/known_hosts"
+# accept_new_hostkey = false
+
+# How the relay runs there: "systemd", or "docker" for Docker Compose
+# mode = "systemd"
+
+# Retrying SSH operations that fail for a network reason, waiting
+# retry_delay seconds, then twice that, and so on
+# ssh_attempts = 3
+# retry_delay = 1
+
+# Several servers instead of `host`: an inventory file, relative to this one,
+# or [[hosts]] tables as at the end
+# inventory = "hosts.toml"
+# fail_fast = false
+# parallel = 1
+
+# The relay
+# port = 8080
+# max_clients = 100
+# max_message_size = 65536
+# bind_address = "0.0.0.0"
+# health_port = 8081
+
+# Deploys
+# target = "x86_64-unknown-linux-musl"
+# use_cross = false
+# features = ["tls", "metrics"]
+# profile = "release"
+# target_dir = "relay-server/target"
+# manifest_path = "relay-server/Cargo.toml"
+# test = false
+# clippy = false
+# check_workspace = false
+# keep_releases = 5
+# smoke_test = true
+# rollback_on_failure = false
+# configure_firewall = false
+# client_config = "relays.toml"
+
+# [[hosts]]
+# host = "relay1.example"
+#
+# [[hosts]]
+# host = "relay2.example"
+# user = "deploy"
+# ssh_key = "~/.ssh/relay2"
+# ssh_port = 2222
+# port = 9000
+"#;
+
+/// The settings deploy.toml can hold
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct ConfigFile {
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    pub ssh_port: Option<u16>,
+    pub jump_host: Option<JumpHost>,
+    pub password_auth: Option<bool>,
+    pub known_hosts: Option<PathBuf>,
+    pub accept_new_hostkey: Option<bool>,
+    pub mode: Option<Mode>,
+    pub ssh_attempts: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub inventory: Option<PathBuf>,
+    #[serde(default)]
+    pub hosts: Vec<HostEntry>,
+    pub fail_fast: Option<bool>,
+    pub parallel: Option<u32>,
+    pub port: Option<u16>,
+    pub max_clients: Option<u32>,
+    pub max_message_size: Option<u32>,
+    pub bind_address: Option<String>,
+    pub health_port: Option<u16>,
+    pub target: Option<String>,
+    pub use_cross: Option<bool>,
+    pub features: Option<Vec<String>>,
+    pub profile: Option<String>,
+    pub target_dir: Option<PathBuf>,
+    pub manifest_path: Option<PathBuf>,
+    pub test: Option<bool>,
+    pub clippy: Option<bool>,
+    pub check_workspace: Option<bool>,
+    pub keep_releases: Option<u16>,
+    pub smoke_test: Option<bool>,
+    pub rollback_on_failure: Option<bool>,
+    pub configure_firewall: Option<bool>,
+    pub client_config: Option<PathBuf>,
+    /// Keys the tool doesn't know, warned about rather than refused so an
+    /// older tool still reads a newer file
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Whether `id` was given on the command line or in the environment
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+impl ConfigFile {
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: Self = toml::from_str(text)?;
+        if file.keep_releases == Some(0) {
+            return Err(anyhow!("keep_releases must be at least 1"));
+        }
+        if file.ssh_attempts == Some(0) {
+            return Err(anyhow!("ssh_attempts must be at least 1"));
+        }
+        if file.parallel == Some(0) {
+            return Err(anyhow!("parallel must be at least 1"));
+        }
+        Ok(file)
+    }
+
+    /// Read `path`, taking the paths in it as relative to its directory
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut file = Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for relative in [&mut file.inventory, &mut file.client_config, &mut file.target_dir, &mut file.manifest_path].into_iter().flatten() {
+            *relative = dir.join(&*relative);
+        }
+        Ok(file)
+    }
+
+    pub fn unknown_keys(&self) -> impl Iterator<Item = &str> {
+        self.unknown.keys().map(String::as_str)
+    }
+
+    /// Fill in every option the command line and environment left unset
+    fn apply_to(self, cli: &mut Cli, matches: &ArgMatches) {
+        macro_rules! merge {
+            ($target:expr, $matches:expr, $($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field.clone() {
+                    if !explicit($matches, stringify!($field)) {
+                        $target.$field = value;
+                    }
+                }
+            )*};
+        }
+        macro_rules! merge_optional {
+            ($target:expr, $matches:expr, $($field:ident),* $(,)?) => {$(
+                if self.$field.is_some() && !explicit($matches, stringify!($field)) {
+                    $target.$field = self.$field.clone();
+                }
+            )*};
+        }
+
+        merge!(cli.ssh, matches, host, user, ssh_key, ssh_port, password_auth, known_hosts, accept_new_hostkey, mode);
+        merge!(cli, matches, fail_fast, parallel, ssh_attempts, retry_delay);
+        merge_optional!(cli.ssh, matches, jump_host, inventory);
+        // The command line's choice of hosts, of whatever kind, replaces the file's
+        if !["host", "hosts", "inventory"].iter().any(|id| explicit(matches, id)) {
+            cli.ssh.config_hosts = self.hosts.clone();
+        }
+
+        match &mut cli.command {
+            Command::Deploy(deploy) => {
+                let matches = matches.subcommand_matches("deploy").expect("deploy was parsed");
+                self.merge_relay(&mut deploy.relay, matches);
+                merge!(deploy, matches, use_cross, features, test, clippy, check_workspace, keep_releases, rollback_on_failure, configure_firewall);
+                merge_optional!(deploy, matches, target, profile, target_dir, manifest_path, client_config);
+                if self.smoke_test == Some(false) && !explicit(matches, "no_smoke_test") {
+                    deploy.no_smoke_test = true;
+                }
+            }
+            _ => {
+                // Other commands have no relay options of their own, but still
+                // need the port the relay is on
+                let matches = RelayArgs::command().no_binary_name(true).get_matches_from(Vec::<String>::new());
+                self.merge_relay(&mut cli.relay, &matches);
+            }
+        }
+    }
+
+    fn merge_relay(&self, relay: &mut RelayArgs, matches: &ArgMatches) {
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field.clone() {
+                    if !explicit(matches, stringify!($field)) {
+                        relay.$field = value;
+                    }
+                }
+            )*};
+        }
+        merge!(port, max_clients, max_message_size, bind_address);
+        if self.health_port.is_some() && !explicit(matches, "health_port") {
+            relay.health_port = self.health_port;
+        }
+    }
+}
+
+/// Merge the `--config` file, if there is one, into what was parsed; a
+/// missing deploy.toml is only an error if `--config` named it
+pub fn resolve(cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+    if matches!(cli.command, Command::Init { .. }) {
+        return Ok(());
+    }
+    if !explicit(matches, "config") && !cli.config.exists() {
+        return Ok(());
+    }
+
+    let file = ConfigFile::load(&cli.config)?;
+    for key in file.unknown_keys() {
+        warn!("⚠️  Unknown key `{}` in {}, ignored", key, cli.config.display());
+    }
+    file.apply_to(cli, matches);
+    if let Command::Deploy(deploy) = &cli.command {
+        if deploy.use_cross && deploy.target.is_none() {
+            return Err(anyhow!("use_cross needs a target to build for"));
+        }
+        if deploy.skip_build && (deploy.test || deploy.clippy) {
+            return Err(anyhow!("test and clippy check a build, so they can't go with --skip-build"));
+        }
+    }
+    Ok(())
+}
+
+/// Write the commented template to `path` for `init`
+pub fn write_template(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        return Err(anyhow!("{} already exists; pass --force to replace it", path.display()));
+    }
+    std::fs::write(path, TEMPLATE).with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("📝 Wrote {}; uncomment the settings you need", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::FromArgMatches;
+
+    /// Parse `args` as the tool would, with `file` as deploy.toml
+    fn resolved(args: &[&str], file: &str) -> Cli {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deploy.toml");
+        std::fs::write(&path, file).unwrap();
+        let config = path.to_str().unwrap();
+        let matches = Cli::command().try_get_matches_from([&["deploy", "--config", config], args].concat()).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        resolve(&mut cli, &matches).unwrap();
+        cli
+    }
+
+    #[test]
+    fn test_template_is_every_key_at_its_default() {
+        assert_eq!(ConfigFile::parse(TEMPLATE).unwrap(), ConfigFile::default());
+
+        let uncommented: String = TEMPLATE
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .filter(|line| line.contains(" = ") || line.starts_with("[["))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let file = ConfigFile::parse(&uncommented).unwrap();
+        assert_eq!(file.unknown_keys().count(), 0, "{:?}", file.unknown);
+        assert_eq!((file.port, file.keep_releases, file.smoke_test), (Some(8080), Some(5), Some(true)));
+        assert_eq!(file.hosts.len(), 2);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_named() {
+        let file = ConfigFile::parse("host = \"a.example\"\nprot = 9000\n[relay]\nport = 1\n").unwrap();
+        assert_eq!(file.host.as_deref(), Some("a.example"));
+        assert_eq!(file.unknown_keys().collect::<Vec<_>>(), ["prot", "relay"]);
+
+        assert!(ConfigFile::parse("port = \"8080\"").is_err());
+        assert!(ConfigFile::parse("keep_releases = 0").is_err());
+        assert!(ConfigFile::parse("parallel = 0").is_err());
+        assert!(ConfigFile::parse("jump_host = \"bastion:ssh\"").is_err());
+    }
+
+    #[test]
+    fn test_command_line_beats_the_file() {
+        let file = "host = \"file.example\"\nuser = \"deploy\"\nport = 9000\nkeep_releases = 2\nsmoke_test = false\n\
+                    jump_host = \"bastion.example:2222\"\n";
+
+        let cli = resolved(&["deploy"], file);
+        let Command::Deploy(deploy) = &cli.command else { panic!() };
+        assert_eq!((cli.ssh.host.as_str(), cli.ssh.user.as_str()), ("file.example", "deploy"));
+        assert_eq!((deploy.relay.port, deploy.keep_releases, deploy.no_smoke_test), (9000, 2, true));
+        assert_eq!(deploy.relay.max_clients, 100, "default where the file says nothing");
+        assert_eq!(cli.ssh.jump_host.as_ref().map(|jump| jump.port), Some(2222));
+
+        // Before or after the subcommand
+        let cli = resolved(&["--host", "cli.example", "deploy", "--port", "7000", "--user", "admin"], file);
+        let Command::Deploy(deploy) = &cli.command else { panic!() };
+        assert_eq!((cli.ssh.host.as_str(), cli.ssh.user.as_str()), ("cli.example", "admin"));
+        assert_eq!((deploy.relay.port, deploy.keep_releases), (7000, 2));
+
+        // Commands besides deploy still find the relay's port
+        assert_eq!(resolved(&["smoke-test"], file).relay.port, 9000);
+    }
+
+    #[test]
+    fn test_hosts_from_the_file() {
+        let file = "fail_fast = true\nparallel = 2\n[[hosts]]\nhost = \"a.example\"\n[[hosts]]\nhost = \"b.example\"\nport = 9000\n";
+        let cli = resolved(&["status"], file);
+        assert!(cli.fail_fast);
+        assert_eq!(cli.parallel, 2);
+        assert_eq!(cli.ssh.config_hosts.len(), 2);
+        assert!(resolved(&["status", "--host", "one.example"], file).ssh.config_hosts.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deploy.toml");
+        std::fs::write(&path, "inventory = \"hosts.toml\"\ntarget_dir = \"target\"\n").unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+        assert_eq!(file.inventory, Some(dir.path().join("hosts.toml")));
+        assert_eq!(file.target_dir, Some(dir.path().join("target")));
+    }
+
+    #[test]
+    fn test_checks_need_a_build() {
+        let file = "test = true\nclippy = true\n";
+        let Command::Deploy(deploy) = resolved(&["deploy"], file).command else { panic!() };
+        assert!(deploy.test && deploy.clippy && !deploy.check_workspace);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deploy.toml");
+        std::fs::write(&path, file).unwrap();
+        let matches = Cli::command().try_get_matches_from(["deploy", "--config", path.to_str().unwrap(), "deploy", "--skip-build"]).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let error = resolve(&mut cli, &matches).unwrap_err().to_string();
+        assert!(error.contains("--skip-build"), "{}", error);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let matches = Cli::command().try_get_matches_from(["deploy", "--config", "/nonexistent/deploy.toml", "status"]).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        assert!(resolve(&mut cli, &matches).is_err(), "named with --config");
+
+        let matches = Cli::command().try_get_matches_from(["deploy", "status"]).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        cli.config = PathBuf::from("/nonexistent/deploy.toml");
+        assert!(resolve(&mut cli, &matches).is_ok(), "the default, not there");
+    }
+}
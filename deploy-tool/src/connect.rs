@@ -0,0 +1,369 @@
+use anyhow::{Context, Result, anyhow};
+use ssh2::{Channel, Session};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::DeploymentConfig;
+use crate::known_hosts;
+use crate::ssh::{Prompt, SSH_PORT, authenticate};
+
+/// `--jump-host`: a bastion to reach the server through, as
+/// `[user@]host[:port]`; the user defaults to `--user`
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct JumpHost {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: u16,
+}
+
+impl JumpHost {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        let (user, address) = match value.rsplit_once('@') {
+            Some((user, address)) if !user.is_empty() => (Some(user.to_string()), address),
+            Some(_) => return Err("the user before '@' is empty".to_string()),
+            None => (None, value),
+        };
+        // A bracketed IPv6 address has colons of its own
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or("expected ':' after ']'")?)),
+                None => return Err("missing ']'".to_string()),
+            },
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        if host.is_empty() {
+            return Err("the host is empty".to_string());
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("{:?} isn't a port", port))?,
+            None => SSH_PORT,
+        };
+        Ok(Self { user, host: host.to_string(), port })
+    }
+}
+
+impl TryFrom<String> for JumpHost {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, String> {
+        Self::parse(&value)
+    }
+}
+
+/// One SSH server on the way to the relay's
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.user, self.host)?;
+        if self.port != SSH_PORT {
+            write!(f, ":{}", self.port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The server to log in to, and the bastion it's reached through, if any
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub jump: Option<Hop>,
+    pub target: Hop,
+}
+
+impl Route {
+    pub fn of(config: &DeploymentConfig) -> Self {
+        let jump = config.jump_host.as_ref().map(|jump| Hop {
+            user: jump.user.clone().unwrap_or_else(|| config.user.clone()),
+            host: jump.host.clone(),
+            port: jump.port,
+        });
+        let target = Hop { user: config.user.clone(), host: config.host.clone(), port: config.ssh_port };
+        Self { jump, target }
+    }
+}
+
+/// The steps of opening a logged-in session, apart from the network so the
+/// way a route is followed can be tested without servers
+pub trait Connector {
+    type Session;
+
+    /// Connect straight to `hop` and log in
+    fn direct(&mut self, hop: &Hop) -> Result<Self::Session>;
+
+    /// Reach `hop` through the session `via` has open, and log in
+    fn tunnel(&mut self, via: Self::Session, hop: &Hop) -> Result<Self::Session>;
+}
+
+/// Open a session along `route`, saying which server a failure was on
+pub fn establish<C: Connector>(connector: &mut C, route: &Route) -> Result<C::Session> {
+    let Some(jump) = &route.jump else {
+        return connector.direct(&route.target).with_context(|| format!("Failed to reach {}", route.target));
+    };
+    let bastion = connector.direct(jump).with_context(|| format!("Failed to reach jump host {}", jump))?;
+    debug!("Logged in to jump host {}", jump);
+    connector
+        .tunnel(bastion, &route.target)
+        .with_context(|| format!("Reached jump host {}, but failed to reach {} through it", jump, route.target))
+}
+
+/// A logged-in session, and the tunnel carrying it if it came through a
+/// jump host
+pub struct Connection {
+    pub session: Session,
+    pub tunnel: Option<Tunnel>,
+}
+
+/// Connects with libssh2, checking each server's host key and logging in
+/// to each as `config` says
+pub struct SshConnector<'a> {
+    pub config: &'a DeploymentConfig,
+    pub prompt: &'a mut dyn Prompt,
+}
+
+impl SshConnector<'_> {
+    /// Handshake over `stream`, check `hop`'s host key and log in to it
+    fn start(&mut self, stream: TcpStream, hop: &Hop) -> Result<Session> {
+        let mut session = Session::new().context("Failed to create SSH session")?;
+        session.set_tcp_stream(stream);
+        session.handshake().context("SSH handshake failed")?;
+        known_hosts::verify(&session, &self.config.known_hosts, self.config.host_key_policy, &hop.host, hop.port, self.prompt)?;
+
+        let mut config = self.config.clone();
+        config.user = hop.user.clone();
+        config.host = hop.host.clone();
+        let method = authenticate(&mut session, self.prompt, &config)?;
+        if !session.authenticated() {
+            return Err(anyhow!("SSH authentication failed for user {}", hop.user));
+        }
+        info!("Successfully connected to {} with {}", hop, method);
+        Ok(session)
+    }
+}
+
+impl Connector for SshConnector<'_> {
+    type Session = Connection;
+
+    fn direct(&mut self, hop: &Hop) -> Result<Connection> {
+        let stream = TcpStream::connect((hop.host.as_str(), hop.port))
+            .with_context(|| format!("Failed to connect to SSH server {}:{}", hop.host, hop.port))?;
+        Ok(Connection { session: self.start(stream, hop)?, tunnel: None })
+    }
+
+    fn tunnel(&mut self, via: Connection, hop: &Hop) -> Result<Connection> {
+        let channel = via.session.channel_direct_tcpip(&hop.host, hop.port, None)
+            .with_context(|| format!("The jump host couldn't open a connection to {}:{}", hop.host, hop.port))?;
+        let (local, tunnel) = Tunnel::start(via.session, channel)?;
+        Ok(Connection { session: self.start(local, hop)?, tunnel: Some(tunnel) })
+    }
+}
+
+/// Carries a session to the server over a channel of the jump host's: a
+/// thread copies between the channel and one end of a loopback connection,
+/// whose other end the server's session uses as its socket, since libssh2
+/// needs a real one
+pub struct Tunnel {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tunnel {
+    /// Start copying, answering the end for the server's session
+    fn start(bastion: Session, channel: Channel) -> Result<(TcpStream, Self)> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context("Failed to open the tunnel's loopback port")?;
+        let local = TcpStream::connect(listener.local_addr()?).context("Failed to connect the tunnel")?;
+        let (pumped, peer) = listener.accept().context("Failed to connect the tunnel")?;
+        // Anything else on this machine could have connected first
+        if peer != local.local_addr()? {
+            return Err(anyhow!("Something else connected to the tunnel's loopback port"));
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                if let Err(e) = pump(&bastion, channel, pumped, &stop) {
+                    debug!("Jump host tunnel closed: {:#}", e);
+                }
+            }
+        });
+        Ok((local, Self { stop, thread: Some(thread) }))
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Copy both ways between `channel` and `local` until either closes or
+/// `stop` is set
+fn pump(bastion: &Session, mut channel: Channel, mut local: TcpStream, stop: &AtomicBool) -> Result<()> {
+    bastion.set_blocking(false);
+    local.set_nonblocking(true)?;
+    let mut to_local = Vec::new();
+    let mut to_channel = Vec::new();
+    let mut buffer = vec![0u8; 32 * 1024];
+
+    while !stop.load(Ordering::SeqCst) {
+        let mut idle = true;
+        if to_local.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => to_local.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to read from the jump host"),
+            }
+        }
+        if !to_local.is_empty() {
+            match local.write(&to_local) {
+                Ok(written) => {
+                    to_local.drain(..written);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to write to the tunnel"),
+            }
+        }
+        if to_channel.is_empty() {
+            match local.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => to_channel.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to read from the tunnel"),
+            }
+        }
+        if !to_channel.is_empty() {
+            match channel.write(&to_channel) {
+                Ok(written) => {
+                    to_channel.drain(..written);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to write to the jump host"),
+            }
+        }
+        if idle {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    bastion.set_blocking(true);
+    let _ = channel.close();
+    let _ = bastion.disconnect(None, "Deployment completed", None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hop(user: &str, host: &str, port: u16) -> Hop {
+        Hop { user: user.to_string(), host: host.to_string(), port }
+    }
+
+    /// Sessions that are only the route they took; connecting to a host in
+    /// `down` fails
+    #[derive(Default)]
+    struct FakeConnector {
+        down: Vec<&'static str>,
+        calls: Vec<String>,
+    }
+
+    impl Connector for FakeConnector {
+        type Session = String;
+
+        fn direct(&mut self, hop: &Hop) -> Result<String> {
+            self.calls.push(format!("direct {}", hop));
+            match self.down.contains(&hop.host.as_str()) {
+                true => Err(anyhow!("connection refused")),
+                false => Ok(hop.to_string()),
+            }
+        }
+
+        fn tunnel(&mut self, via: String, hop: &Hop) -> Result<String> {
+            self.calls.push(format!("tunnel {} via {}", hop, via));
+            match self.down.contains(&hop.host.as_str()) {
+                true => Err(anyhow!("connection refused")),
+                false => Ok(format!("{} via {}", hop, via)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_jump_host_parsing() {
+        let parse = |value| JumpHost::parse(value).unwrap();
+        assert_eq!(parse("bastion.example"), JumpHost { user: None, host: "bastion.example".to_string(), port: 22 });
+        assert_eq!(
+            parse("admin@bastion.example:2222"),
+            JumpHost { user: Some("admin".to_string()), host: "bastion.example".to_string(), port: 2222 }
+        );
+        assert_eq!(parse("[2001:db8::1]:2222"), JumpHost { user: None, host: "2001:db8::1".to_string(), port: 2222 });
+        assert_eq!(parse("me@[2001:db8::1]").port, 22);
+        for bad in ["", "@bastion", "bastion:ssh", "bastion:70000", "[2001:db8::1", "[::1]2222", "admin@:22"] {
+            assert!(JumpHost::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut config = crate::deployer::tests::config();
+        config.ssh_port = 2200;
+        assert_eq!(Route::of(&config), Route { jump: None, target: hop("root", "relay.example", 2200) });
+
+        config.jump_host = Some(JumpHost::parse("bastion.example").unwrap());
+        assert_eq!(Route::of(&config).jump, Some(hop("root", "bastion.example", 22)), "as --user by default");
+        config.jump_host = Some(JumpHost::parse("admin@bastion.example:2222").unwrap());
+        assert_eq!(Route::of(&config).jump, Some(hop("admin", "bastion.example", 2222)));
+        assert_eq!(hop("admin", "bastion.example", 2222).to_string(), "admin@bastion.example:2222");
+    }
+
+    #[test]
+    fn test_establish_through_a_jump_host() {
+        let direct = Route { jump: None, target: hop("root", "relay.example", 22) };
+        let jumped = Route { jump: Some(hop("admin", "bastion.example", 2222)), target: hop("root", "relay.example", 22) };
+
+        let mut connector = FakeConnector::default();
+        assert_eq!(establish(&mut connector, &direct).unwrap(), "root@relay.example");
+        assert_eq!(establish(&mut connector, &jumped).unwrap(), "root@relay.example via admin@bastion.example:2222");
+        assert_eq!(connector.calls, [
+            "direct root@relay.example",
+            "direct admin@bastion.example:2222",
+            "tunnel root@relay.example via admin@bastion.example:2222",
+        ]);
+
+        // Each failure names the server it was on
+        let mut bastion_down = FakeConnector { down: vec!["bastion.example"], ..Default::default() };
+        let error = format!("{:#}", establish(&mut bastion_down, &jumped).unwrap_err());
+        assert_eq!(error, "Failed to reach jump host admin@bastion.example:2222: connection refused");
+        assert_eq!(bastion_down.calls.len(), 1, "no tunnel without the bastion");
+
+        let mut target_down = FakeConnector { down: vec!["relay.example"], ..Default::default() };
+        let error = format!("{:#}", establish(&mut target_down, &jumped).unwrap_err());
+        assert_eq!(
+            error,
+            "Reached jump host admin@bastion.example:2222, but failed to reach root@relay.example through it: connection refused"
+        );
+        let error = format!("{:#}", establish(&mut target_down, &direct).unwrap_err());
+        assert_eq!(error, "Failed to reach root@relay.example: connection refused");
+    }
+}
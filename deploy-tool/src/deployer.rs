@@ -1,68 +1,430 @@
 use anyhow::{Context, Result, anyhow};
-use std::path::Path;
+use serde::Deserialize;
+use std::io::Write;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
-use crate::{DeploymentConfig, ssh::SshClient};
+use crate::builder::{BinaryInfo, GlibcVersion};
+use crate::checksum;
+use crate::DeploymentConfig;
+use crate::docker;
+use crate::firewall;
+use crate::logs::{FilteredLines, JOURNAL_FORMAT, LogFilter};
+use crate::packager::Package;
+use crate::ssh::{FILE_MODE, Remote, Transfer};
+use crate::version::{self, Version};
+
+/// Longest a running relay is given to drain before it is stopped anyway; the
+/// relay's own drain timeout normally ends it well before this
+const DRAIN_WAIT: Duration = Duration::from_secs(60);
+
+/// Each deploy installs its binary in a directory of its own here...
+const RELEASES_DIR: &str = "/opt/silence-relay/releases";
+/// ...and points this symlink, which the service runs from, at it
+const CURRENT_LINK: &str = "/opt/silence-relay/current";
+
+/// How the relay runs on the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// As a systemd service
+    #[default]
+    Systemd,
+    /// In a container under Docker Compose
+    Docker,
+}
+
+/// Where the relay service stands, as `status` reports it
+#[derive(Debug, PartialEq)]
+pub struct ServiceStatus {
+    /// What `systemctl is-active` says: `active`, `inactive`, `failed`...
+    /// or in Docker mode the container's state: `running`, `exited`...
+    pub state: String,
+    /// `systemctl status` or `docker ps` output, for people
+    pub details: String,
+    /// The digest of the binary the service runs, if there's one installed
+    pub binary: Option<BinaryDigest>,
+    /// What the installed release was built from, if it recorded that
+    pub version: Option<Version>,
+    /// Whether something listens on the relay's port, if `ss` could say
+    pub listening: Option<bool>,
+    /// How long the relay has been running, if it is
+    pub uptime: Option<Duration>,
+}
+
+/// The SHA-256 of the installed binary, and the one recorded when its
+/// release was deployed, if it was deployed with one
+#[derive(Debug, PartialEq)]
+pub struct BinaryDigest {
+    pub sha256: String,
+    pub recorded: Option<String>,
+}
+
+impl BinaryDigest {
+    /// Whether the binary changed after it was deployed
+    pub fn drifted(&self) -> bool {
+        self.recorded.as_ref().is_some_and(|recorded| *recorded != self.sha256)
+    }
+}
+
+/// A finished deploy
+#[derive(Debug, PartialEq)]
+pub struct Deployment {
+    pub release: String,
+    /// The release it replaced, if any, for going back to
+    pub previous: Option<String>,
+    pub upload: Transfer,
+    /// SHA-256 of the binary installed, checked on the server
+    pub binary_sha256: String,
+}
+
+impl ServiceStatus {
+    pub fn is_active(&self) -> bool {
+        self.state == "active" || self.state == "running"
+    }
+
+    /// What `status` prints for the relay on `host`, which listens on `port`
+    pub fn report(&self, host: &str, port: u16) -> String {
+        let mut report = self.details.clone();
+        if let Some(version) = &self.version {
+            report.push_str(&format!("🏷️  Version {}\n", version));
+        }
+        if let Some(binary) = &self.binary {
+            report.push_str(&format!("🔏 Binary sha256 {}\n", binary.sha256));
+            if binary.drifted() {
+                report.push_str(&format!(
+                    "⚠️  The binary on {} has changed since it was deployed, with sha256 {}\n",
+                    host,
+                    binary.recorded.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        match self.listening {
+            Some(true) => report.push_str(&format!("👂 Listening on port {}\n", port)),
+            Some(false) => report.push_str(&format!("⚠️  Nothing is listening on port {}\n", port)),
+            None => {}
+        }
+        if let Some(uptime) = self.uptime {
+            report.push_str(&format!("⏱️  Up {}\n", humantime::format_duration(uptime)));
+        }
+        if self.is_active() {
+            report.push_str(&format!("✅ silence-relay on {} is running\n", host));
+        } else {
+            report.push_str(&format!("⚠️  silence-relay on {} is {}\n", host, self.state));
+        }
+        report
+    }
+}
 
 pub struct Deployer<'a> {
-    ssh_client: &'a mut SshClient,
+    ssh_client: &'a mut dyn Remote,
     config: &'a DeploymentConfig,
 }
 
 impl<'a> Deployer<'a> {
-    pub fn new(ssh_client: &'a mut SshClient, config: &'a DeploymentConfig) -> Self {
+    pub fn new(ssh_client: &'a mut dyn Remote, config: &'a DeploymentConfig) -> Self {
         Self { ssh_client, config }
     }
 
-    pub async fn deploy(&mut self, package_path: &Path) -> Result<()> {
+    /// Install the package as a new release, named `release` or else by
+    /// the server's clock, and switch the service to it, keeping the newest
+    /// `keep_releases`; with `configure_firewall`, the relay's ports are
+    /// opened first. Should anything fail
+    /// once the package is uploaded, the service goes back to the release
+    /// it ran before
+    pub async fn deploy(
+        &mut self,
+        package: &Package,
+        release: Option<&str>,
+        keep_releases: usize,
+        configure_firewall: bool,
+    ) -> Result<Deployment> {
         info!("Starting deployment to Cherry Server...");
 
         // Step 1: Upload deployment package
-        self.upload_package(package_path).await
+        let upload = self.upload_package(package).await
             .context("Failed to upload deployment package")?;
 
         // Step 2: Extract package on remote server
-        self.extract_package().await
+        self.extract_package(&package.binary_sha256).await
             .context("Failed to extract deployment package")?;
 
-        // Step 3: Run installation script
-        self.run_installation().await
+        let release = match release {
+            Some(release) => release.to_string(),
+            None => self.new_release_name().await?,
+        };
+        if self.list_releases().await?.contains(&release) {
+            return Err(anyhow!("Release {} is already on the server", release));
+        }
+        let previous = self.current_release().await?;
+
+        // Let clients through the firewall, if asked, before anything changes
+        if configure_firewall {
+            self.configure_firewall().await
+                .context("Failed to configure the firewall")?;
+        }
+
+        // Steps 3 to 7: install the release and switch over to it
+        let installed = match self.config.mode {
+            Mode::Systemd => self.install_release(&release).await,
+            Mode::Docker => self.install_container(&release).await,
+        };
+        if let Err(e) = installed {
+            self.revert(previous.as_deref(), &release).await;
+            return Err(e);
+        }
+
+        // Step 8: Drop releases beyond the newest few
+        if let Err(e) = self.prune_releases(keep_releases).await {
+            warn!("Could not remove old releases: {:#}", e);
+        }
+
+        // Step 9: Cleanup temporary files
+        self.cleanup_remote_files().await
+            .context("Failed to cleanup remote files")?;
+
+        info!("🎉 Deployment of release {} completed successfully!", release);
+        Ok(Deployment { release, previous, upload, binary_sha256: package.binary_sha256.clone() })
+    }
+
+    async fn install_release(&mut self, release: &str) -> Result<()> {
+        // Step 3: Let the running relay's sessions wind down
+        self.drain_service().await
+            .context("Failed to drain relay service")?;
+
+        // Step 4: Run installation script
+        self.run_installation(release).await
             .context("Failed to run installation")?;
 
-        // Step 4: Start the service
+        // Step 5: Point the service at the new release
+        self.switch_to(release).await?;
+
+        // Step 6: Start the service
         self.start_service().await
             .context("Failed to start relay service")?;
 
-        // Step 5: Verify deployment
+        // Step 7: Verify deployment
         self.verify_deployment().await
-            .context("Failed to verify deployment")?;
+            .context("Failed to verify deployment")
+    }
 
-        // Step 6: Cleanup temporary files
-        self.cleanup_remote_files().await
-            .context("Failed to cleanup remote files")?;
+    /// Docker's take on `install_release`: build the release's image and
+    /// have compose replace the container, which stops the old relay with
+    /// the signal that drains it
+    async fn install_container(&mut self, release: &str) -> Result<()> {
+        info!("🐳 Building image {}:{}...", docker::IMAGE, release);
+        self.ssh_client.execute_command(&docker::build(release)).await
+            .context("Failed to build the relay's image")?;
+        self.ssh_client.execute_command(&docker::install()).await
+            .context("Failed to install compose.yaml")?;
+
+        self.switch_to(release).await?;
+        self.wait_for_container().await
+            .context("Failed to start relay container")?;
 
-        info!("🎉 Deployment completed successfully!");
+        self.verify_deployment().await
+            .context("Failed to verify deployment")
+    }
+
+    /// Put `previous` back after `failed` didn't work out, and drop
+    /// `failed`; problems on the way are logged, as the deploy's own error
+    /// is the one to report
+    async fn revert(&mut self, previous: Option<&str>, failed: &str) {
+        match previous {
+            Some(previous) => {
+                warn!("↩️  Deployment failed, going back to release {}", previous);
+                let reverted = match self.switch_to(previous).await {
+                    Ok(()) => self.start_switched().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = reverted {
+                    warn!("Could not go back to release {}: {:#}", previous, e);
+                }
+            }
+            None => {
+                warn!("Deployment failed and there is no earlier release to go back to");
+                match self.config.mode {
+                    Mode::Systemd => {
+                        let _ = self.ssh_client.execute_command("sudo systemctl stop silence-relay").await;
+                        let _ = self.ssh_client.execute_command(&format!("sudo rm -f {}", CURRENT_LINK)).await;
+                    }
+                    Mode::Docker => {
+                        let _ = self.ssh_client.execute_command(&docker::compose_command("down")).await;
+                        let _ = self.ssh_client.execute_command(&format!("sudo rm -f {}/.env", docker::COMPOSE_DIR)).await;
+                    }
+                }
+            }
+        }
+        let drop = match self.config.mode {
+            Mode::Systemd => format!("sudo rm -rf {}/{}", RELEASES_DIR, failed),
+            Mode::Docker => docker::remove_images(&[failed.to_string()]),
+        };
+        let _ = self.ssh_client.execute_command(&drop).await;
+    }
+
+    /// A name for a new release from the server's clock, which sorts in the
+    /// order releases were made
+    async fn new_release_name(&mut self) -> Result<String> {
+        let now = self.ssh_client.execute_command("date -u +%Y%m%d%H%M%S").await
+            .context("Failed to read the server's clock")?;
+        Ok(now.trim().to_string())
+    }
+
+    /// The releases installed on the server, oldest first
+    pub async fn list_releases(&mut self) -> Result<Vec<String>> {
+        // Fails before the first release, when there's no releases directory
+        let list = match self.config.mode {
+            Mode::Systemd => format!("ls -1tr {}", RELEASES_DIR),
+            Mode::Docker => docker::list_releases(),
+        };
+        let (exit_status, output) = self.ssh_client.run(&list).await
+            .context("Failed to list releases")?;
+        if exit_status != 0 {
+            return Ok(Vec::new());
+        }
+        Ok(output.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect())
+    }
+
+    /// The release the service runs, if there is one yet
+    pub async fn current_release(&mut self) -> Result<Option<String>> {
+        let read = match self.config.mode {
+            Mode::Systemd => format!("readlink {}", CURRENT_LINK),
+            Mode::Docker => docker::current_release(),
+        };
+        let (exit_status, target) = self.ssh_client.run(&read).await
+            .context("Failed to read the current release")?;
+        let release = target.trim().rsplit('/').next().unwrap_or_default();
+        if exit_status != 0 || release.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(release.to_string()))
+    }
+
+    /// Point `current` at `release`, replacing the symlink in one rename so
+    /// there's never a moment without one; in Docker mode, point `.env` at
+    /// its image, which compose runs straight away
+    async fn switch_to(&mut self, release: &str) -> Result<()> {
+        let switch = match self.config.mode {
+            Mode::Systemd => format!(
+                "sudo ln -sfn releases/{release} {link}.new && sudo mv -T {link}.new {link}",
+                release = release,
+                link = CURRENT_LINK
+            ),
+            Mode::Docker => docker::switch(release),
+        };
+        self.ssh_client.execute_command(&switch).await
+            .with_context(|| format!("Failed to switch to release {}", release))?;
+        debug!("Switched to release {}", release);
+        Ok(())
+    }
+
+    /// Go back to `to`, or else the release before the current one, and
+    /// restart the service on it; answers the release now running
+    pub async fn rollback(&mut self, to: Option<&str>) -> Result<String> {
+        let releases = self.list_releases().await?;
+        let current = self.current_release().await?;
+        let target = match to {
+            Some(to) if releases.iter().any(|release| release == to) => to.to_string(),
+            Some(to) => return Err(anyhow!("No release {} on the server; `releases` lists them", to)),
+            None => previous_release(&releases, current.as_deref())
+                .ok_or_else(|| anyhow!("No earlier release to roll back to"))?
+                .to_string(),
+        };
+        if current.as_deref() == Some(target.as_str()) {
+            return Err(anyhow!("Release {} is already the current one", target));
+        }
+
+        info!("↩️  Rolling back to release {}...", target);
+        self.switch_to(&target).await?;
+        self.start_switched().await?;
+        info!("✅ Now running release {}", target);
+        Ok(target)
+    }
+
+    /// Remove all but the newest `keep` releases, never the current one
+    async fn prune_releases(&mut self, keep: usize) -> Result<()> {
+        let releases = self.list_releases().await?;
+        let current = self.current_release().await?;
+        let stale = releases_to_prune(&releases, current.as_deref(), keep);
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let remove = match self.config.mode {
+            Mode::Systemd => {
+                let paths: Vec<String> = stale.iter().map(|release| format!("{}/{}", RELEASES_DIR, release)).collect();
+                format!("sudo rm -rf {}", paths.join(" "))
+            }
+            Mode::Docker => docker::remove_images(&stale),
+        };
+        self.ssh_client.execute_command(&remove).await?;
+        info!("🧹 Removed old releases: {}", stale.join(", "));
         Ok(())
     }
 
-    async fn upload_package(&mut self, package_path: &Path) -> Result<()> {
+    /// Open the relay's port, and its health port if it has one, in the
+    /// server's firewall, noting what was opened for `uninstall`; a server
+    /// with no firewall the tool knows is left as it is
+    async fn configure_firewall(&mut self) -> Result<()> {
+        let Some(flavor) = firewall::detect(self.ssh_client).await? else {
+            info!("No ufw or firewalld running on {}, leaving its firewall alone", self.config.host);
+            return Ok(());
+        };
+        info!("🧱 Opening the relay's ports in {}...", flavor.name);
+        let ports: Vec<u16> = std::iter::once(self.config.port).chain(self.config.health_port).collect();
+        let added = firewall::open_ports(self.ssh_client, flavor, &ports).await?;
+        firewall::record(self.ssh_client, &added).await
+    }
+
+    /// Make sure the server can run the binary before anything changes:
+    /// the same CPU architecture, and a glibc at least as new as the one
+    /// the binary was linked against unless it is static. In Docker mode
+    /// the glibc is the image's, and the server needs Docker Compose.
+    pub async fn check_host(&mut self, binary: &BinaryInfo) -> Result<()> {
+        info!("🔍 Checking the server can run the binary...");
+        let (_, arch) = self.ssh_client.run("uname -m").await
+            .context("Failed to read the server's architecture")?;
+        if self.config.mode == Mode::Docker {
+            return self.check_docker(binary, arch.trim()).await;
+        }
+        let (_, ldd) = self.ssh_client.run("ldd --version 2>&1 | head -n 1").await
+            .context("Failed to read the server's libc version")?;
+        check_compatibility(binary, &self.config.host, arch.trim(), ldd.trim())
+    }
+
+    async fn check_docker(&mut self, binary: &BinaryInfo, arch: &str) -> Result<()> {
+        let (exit_status, _) = self.ssh_client.run("sudo docker compose version").await
+            .context("Failed to look for Docker Compose")?;
+        if exit_status != 0 {
+            return Err(anyhow!("{} doesn't have Docker Compose, which --mode docker needs", self.config.host));
+        }
+        check_compatibility(&BinaryInfo { glibc: None, ..*binary }, &self.config.host, arch, "")?;
+        match binary.glibc {
+            Some(required) if required > docker::BASE_GLIBC => Err(anyhow!(
+                "The relay binary needs glibc {} or newer but the image has {}; \
+                 build a static binary with --target x86_64-unknown-linux-musl",
+                required, docker::BASE_GLIBC
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn upload_package(&mut self, package: &Package) -> Result<Transfer> {
         info!("📤 Uploading deployment package...");
         
         let remote_package_path = "/tmp/silence-relay-deploy.tar.gz";
         
-        self.ssh_client.upload_file(package_path, remote_package_path).await
+        let transfer = self.ssh_client.upload_file(&package.path, remote_package_path, FILE_MODE).await
             .context("Failed to upload package to server")?;
 
-        // Verify upload completed successfully
-        if !self.ssh_client.file_exists(remote_package_path).await? {
-            return Err(anyhow!("Package upload verification failed"));
-        }
+        // Verify upload arrived whole
+        checksum::verify_remote(self.ssh_client, remote_package_path, &package.sha256, "Uploaded package").await?;
 
-        info!("✅ Package uploaded successfully");
-        Ok(())
+        info!("✅ Package uploaded: {}", transfer);
+        Ok(transfer)
     }
 
-    async fn extract_package(&mut self) -> Result<()> {
+    async fn extract_package(&mut self, binary_sha256: &str) -> Result<()> {
         info!("📦 Extracting deployment package...");
 
         // Create extraction directory
@@ -85,19 +447,60 @@ impl<'a> Deployer<'a> {
         debug!("Extracted files: {}", output);
 
         // Check for required files
-        let required_files = ["silence-relay", "silence-relay.service", "install.sh"];
+        let required_files = match self.config.mode {
+            Mode::Systemd => ["silence-relay", "silence-relay.service", "install.sh"],
+            Mode::Docker => ["silence-relay", "Dockerfile", "compose.yaml"],
+        };
         for file in &required_files {
             let file_path = format!("/tmp/silence-relay-extract/{}", file);
             if !self.ssh_client.file_exists(&file_path).await? {
                 return Err(anyhow!("Required file missing after extraction: {}", file));
             }
         }
+        checksum::verify_remote(self.ssh_client, "/tmp/silence-relay-extract/silence-relay", binary_sha256, "Extracted binary").await?;
 
         info!("✅ Package extracted successfully");
         Ok(())
     }
 
-    async fn run_installation(&mut self) -> Result<()> {
+    /// Put a running relay in drain mode and wait for it to exit, so clients
+    /// finish their conversations before the binary is replaced
+    async fn drain_service(&mut self) -> Result<()> {
+        let main_pid = "systemctl show --property MainPID --value silence-relay";
+        let running = self.ssh_client.execute_command(main_pid).await.unwrap_or_default();
+        let running = running.trim().to_string();
+        if running.is_empty() || running == "0" {
+            debug!("Relay service not running, nothing to drain");
+            return Ok(());
+        }
+
+        info!("⏳ Draining relay clients...");
+        self.ssh_client.execute_command("sudo systemctl kill --kill-who=main --signal=SIGUSR1 silence-relay").await
+            .context("Failed to put the relay in drain mode")?;
+
+        // The relay exits by itself once drained
+        let deadline = Instant::now() + DRAIN_WAIT;
+        loop {
+            let current = self.ssh_client.execute_command(main_pid).await.unwrap_or_default();
+            if current.trim() != running {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Relay still draining after {:?}, stopping it", DRAIN_WAIT);
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // Stop it before systemd restarts the old binary
+        self.ssh_client.execute_command("sudo systemctl stop silence-relay").await
+            .context("Failed to stop relay service")?;
+
+        info!("✅ Relay drained");
+        Ok(())
+    }
+
+    async fn run_installation(&mut self, release: &str) -> Result<()> {
         info!("🔧 Running installation script...");
 
         // Make install script executable (just in case)
@@ -105,26 +508,27 @@ impl<'a> Deployer<'a> {
             .context("Failed to make install script executable")?;
 
         // Run installation script with elevated privileges
-        let install_cmd = "cd /tmp/silence-relay-extract && sudo ./install.sh";
-        let output = self.ssh_client.execute_command(install_cmd).await
+        let install_cmd = format!("cd /tmp/silence-relay-extract && sudo ./install.sh {}", release);
+        let output = self.ssh_client.execute_command(&install_cmd).await
             .context("Failed to run installation script")?;
 
         debug!("Installation output: {}", output);
 
         // Verify installation was successful
-        self.verify_installation().await
+        self.verify_installation(release).await
             .context("Installation verification failed")?;
 
         info!("✅ Installation completed successfully");
         Ok(())
     }
 
-    async fn verify_installation(&mut self) -> Result<()> {
+    async fn verify_installation(&mut self, release: &str) -> Result<()> {
         debug!("Verifying installation...");
 
         // Check if binary was installed
-        if !self.ssh_client.file_exists("/opt/silence-relay/silence-relay").await? {
-            return Err(anyhow!("Binary not found at /opt/silence-relay/silence-relay"));
+        let binary = format!("{}/{}/silence-relay", RELEASES_DIR, release);
+        if !self.ssh_client.file_exists(&binary).await? {
+            return Err(anyhow!("Binary not found at {}", binary));
         }
 
         // Check if systemd service was installed
@@ -171,7 +575,7 @@ impl<'a> Deployer<'a> {
         let is_active = self.ssh_client.execute_command("sudo systemctl is-active silence-relay").await
             .context("Failed to check if service is active")?;
 
-        if !is_active.trim().contains("active") {
+        if is_active.trim() != "active" {
             return Err(anyhow!("Service is not active: {}", is_active.trim()));
         }
 
@@ -179,6 +583,28 @@ impl<'a> Deployer<'a> {
         Ok(())
     }
 
+    /// Give the container a moment to start, then check it's still up
+    /// rather than restarting after a crash
+    async fn wait_for_container(&mut self) -> Result<()> {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let state = self.ssh_client.execute_command(&docker::state()).await
+            .context("Failed to check the relay container")?;
+        if state.trim() != "running" {
+            return Err(anyhow!("Container is not running: {}", state.trim()));
+        }
+        info!("✅ Container is running");
+        Ok(())
+    }
+
+    /// Have the relay run the release just switched to: systemd needs a
+    /// restart for it, whereas compose has already replaced the container
+    async fn start_switched(&mut self) -> Result<()> {
+        match self.config.mode {
+            Mode::Systemd => self.restart_service().await,
+            Mode::Docker => self.wait_for_container().await,
+        }
+    }
+
     async fn verify_deployment(&mut self) -> Result<()> {
         info!("🔍 Verifying deployment...");
 
@@ -213,7 +639,11 @@ impl<'a> Deployer<'a> {
         }
 
         // Get recent logs to verify service is working
-        let logs = self.ssh_client.execute_command("sudo journalctl -u silence-relay --no-pager -n 10").await
+        let recent = match self.config.mode {
+            Mode::Systemd => "sudo journalctl -u silence-relay --no-pager -n 10".to_string(),
+            Mode::Docker => docker::logs(10, false),
+        };
+        let logs = self.ssh_client.execute_command(&recent).await
             .context("Failed to get service logs")?;
 
         debug!("Recent service logs: {}", logs);
@@ -244,7 +674,11 @@ impl<'a> Deployer<'a> {
     pub async fn stop_service(&mut self) -> Result<()> {
         info!("🛑 Stopping relay service...");
 
-        self.ssh_client.execute_command("sudo systemctl stop silence-relay").await
+        let stop = match self.config.mode {
+            Mode::Systemd => "sudo systemctl stop silence-relay".to_string(),
+            Mode::Docker => docker::compose_command("stop"),
+        };
+        self.ssh_client.execute_command(&stop).await
             .context("Failed to stop relay service")?;
 
         info!("✅ Service stopped");
@@ -254,6 +688,12 @@ impl<'a> Deployer<'a> {
     pub async fn restart_service(&mut self) -> Result<()> {
         info!("🔄 Restarting relay service...");
 
+        if self.config.mode == Mode::Docker {
+            self.ssh_client.execute_command(&docker::compose_command("restart")).await
+                .context("Failed to restart relay container")?;
+            return self.wait_for_container().await;
+        }
+
         self.ssh_client.execute_command("sudo systemctl restart silence-relay").await
             .context("Failed to restart relay service")?;
 
@@ -264,7 +704,7 @@ impl<'a> Deployer<'a> {
         let is_active = self.ssh_client.execute_command("sudo systemctl is-active silence-relay").await
             .context("Failed to check service status after restart")?;
 
-        if !is_active.trim().contains("active") {
+        if is_active.trim() != "active" {
             return Err(anyhow!("Service is not active after restart: {}", is_active.trim()));
         }
 
@@ -272,27 +712,135 @@ impl<'a> Deployer<'a> {
         Ok(())
     }
 
-    pub async fn get_service_status(&mut self) -> Result<String> {
-        let status = self.ssh_client.execute_command("sudo systemctl status silence-relay --no-pager").await
+    /// Both commands exit non-zero for a service that isn't running, which
+    /// is an answer here rather than a failure
+    pub async fn get_service_status(&mut self) -> Result<ServiceStatus> {
+        let (state, details) = match self.config.mode {
+            Mode::Systemd => (
+                "systemctl is-active silence-relay".to_string(),
+                "sudo systemctl status silence-relay --no-pager".to_string(),
+            ),
+            Mode::Docker => (docker::state(), docker::status()),
+        };
+        let (_, state) = self.ssh_client.run(&state).await
+            .context("Failed to get service state")?;
+        let (_, details) = self.ssh_client.run(&details).await
             .context("Failed to get service status")?;
+        let binary = self.installed_binary().await?;
+        let version = self.installed_version().await?;
+        let listening = self.listening().await?;
+        let uptime = self.uptime().await?;
 
-        Ok(status)
+        Ok(ServiceStatus { state: state.trim().to_string(), details, binary, version, listening, uptime })
     }
 
-    pub async fn get_service_logs(&mut self, lines: u32) -> Result<String> {
-        let log_cmd = format!("sudo journalctl -u silence-relay --no-pager -n {}", lines);
+    /// What the installed release's `version.json` says it was built from
+    async fn installed_version(&mut self) -> Result<Option<Version>> {
+        let path = format!("/{}", version::FILE_NAME);
+        let read = match self.config.mode {
+            Mode::Systemd => format!("cat {}{}", CURRENT_LINK, path),
+            Mode::Docker => docker::read_file(&path),
+        };
+        let (exit_status, json) = self.ssh_client.run(&read).await
+            .context("Failed to read the installed version")?;
+        Ok(if exit_status == 0 { Version::parse(&json) } else { None })
+    }
+
+    /// Whether anything listens on the relay's port; none if `ss` isn't
+    /// there to ask
+    async fn listening(&mut self) -> Result<Option<bool>> {
+        let (exit_status, sockets) = self.ssh_client.run(&format!("ss -Htln 'sport = :{}'", self.config.port)).await
+            .context("Failed to check the relay's port")?;
+        Ok((exit_status == 0).then(|| !sockets.trim().is_empty()))
+    }
+
+    /// How long the relay's process has run, from `ps`; none when there's
+    /// no process
+    async fn uptime(&mut self) -> Result<Option<Duration>> {
+        let pid = match self.config.mode {
+            Mode::Systemd => "systemctl show --property MainPID --value silence-relay".to_string(),
+            Mode::Docker => docker::pid(),
+        };
+        let (exit_status, seconds) = self.ssh_client.run(&format!("ps -o etimes= -p \"$({})\"", pid)).await
+            .context("Failed to get the relay's uptime")?;
+        if exit_status != 0 {
+            return Ok(None);
+        }
+        Ok(seconds.trim().parse().ok().map(Duration::from_secs))
+    }
+
+    /// The digest of the binary `current` points at, or the container
+    /// runs, and the one its release's manifest recorded
+    async fn installed_binary(&mut self) -> Result<Option<BinaryDigest>> {
+        let (binary, manifest) = match self.config.mode {
+            Mode::Systemd => (format!("sha256sum {}/silence-relay", CURRENT_LINK), format!("cat {}/deploy.conf", CURRENT_LINK)),
+            Mode::Docker => (format!("{} | sha256sum", docker::read_file("/silence-relay")), docker::read_file("/deploy.conf")),
+        };
+        let (_, output) = self.ssh_client.run(&binary).await
+            .context("Failed to checksum the installed binary")?;
+        let Some(sha256) = checksum::parse_sha256sum(&output) else { return Ok(None) };
+        let (exit_status, manifest) = self.ssh_client.run(&manifest).await
+            .context("Failed to read the release's manifest")?;
+        let recorded = if exit_status == 0 { checksum::recorded_sha256(&manifest) } else { None };
+        Ok(Some(BinaryDigest { sha256: sha256.to_string(), recorded }))
+    }
+
+    /// The last `lines` log lines, those `filter` lets through
+    pub async fn get_service_logs(&mut self, lines: u32, filter: &LogFilter) -> Result<String> {
+        let log_cmd = match self.config.mode {
+            Mode::Systemd => format!("sudo journalctl -u silence-relay --no-pager {} -n {}", JOURNAL_FORMAT, lines),
+            Mode::Docker => docker::logs(lines, false),
+        };
         let logs = self.ssh_client.execute_command(&log_cmd).await
             .context("Failed to get service logs")?;
 
-        Ok(logs)
+        Ok(filter.apply(&logs))
+    }
+
+    /// Copy the last `lines` log lines to `out`, then new ones as they're
+    /// written, a line at a time and those `filter` lets through, until the
+    /// connection drops or the tool is interrupted
+    pub async fn follow_service_logs(&mut self, lines: u32, filter: &LogFilter, out: &mut (dyn Write + Send)) -> Result<()> {
+        let (log_cmd, tool) = match self.config.mode {
+            Mode::Systemd => (format!("sudo journalctl -u silence-relay --no-pager {} -n {} -f", JOURNAL_FORMAT, lines), "journalctl"),
+            Mode::Docker => (docker::logs(lines, true), "docker logs"),
+        };
+        let mut filtered = FilteredLines::new(filter, out);
+        let exit_status = self.ssh_client.stream(&log_cmd, &mut filtered).await
+            .context("Failed to follow service logs")?;
+        filtered.finish().context("Failed to write service logs")?;
+
+        if exit_status != 0 {
+            return Err(anyhow!("{} exited with status {}", tool, exit_status));
+        }
+        Ok(())
     }
 
-    pub async fn uninstall(&mut self) -> Result<()> {
+    /// Remove the service and its releases; `purge` also removes the relay's
+    /// configuration and the `relay` user, which are kept otherwise so a
+    /// later deploy picks up where this one left off
+    pub async fn uninstall(&mut self, purge: bool) -> Result<()> {
         info!("🗑️  Uninstalling relay service...");
 
         // Stop and disable service
-        let _ = self.ssh_client.execute_command("sudo systemctl stop silence-relay").await;
-        let _ = self.ssh_client.execute_command("sudo systemctl disable silence-relay").await;
+        match self.config.mode {
+            Mode::Systemd => {
+                let _ = self.ssh_client.execute_command("sudo systemctl stop silence-relay").await;
+                let _ = self.ssh_client.execute_command("sudo systemctl disable silence-relay").await;
+            }
+            Mode::Docker => {
+                let _ = self.ssh_client.execute_command(&docker::compose_command("down")).await;
+            }
+        }
+
+        // Close the ports a deploy opened, while the list of them is still there
+        if let Err(e) = firewall::close_recorded(self.ssh_client).await {
+            warn!("Could not remove the firewall rules added: {:#}", e);
+        }
+
+        if self.config.mode == Mode::Docker {
+            return self.remove_container(purge).await;
+        }
 
         // Remove systemd service file
         let _ = self.ssh_client.execute_command("sudo rm -f /etc/systemd/system/silence-relay.service").await;
@@ -300,13 +848,516 @@ impl<'a> Deployer<'a> {
         // Reload systemd
         let _ = self.ssh_client.execute_command("sudo systemctl daemon-reload").await;
 
-        // Remove installation directory
-        let _ = self.ssh_client.execute_command("sudo rm -rf /opt/silence-relay").await;
+        if purge {
+            // Remove installation directory and the user it belonged to
+            self.ssh_client.execute_command("sudo rm -rf /opt/silence-relay").await
+                .context("Failed to remove /opt/silence-relay")?;
+            let _ = self.ssh_client.execute_command("sudo userdel relay").await;
+        } else {
+            let binaries = format!("sudo rm -rf {} {} /opt/silence-relay/silence-relay", RELEASES_DIR, CURRENT_LINK);
+            self.ssh_client.execute_command(&binaries).await
+                .context("Failed to remove the relay binaries")?;
+        }
+
+        // Nothing left behind that could still run
+        if self.ssh_client.file_exists("/etc/systemd/system/silence-relay.service").await? {
+            return Err(anyhow!("Systemd service file still present after uninstall"));
+        }
+
+        info!("✅ Uninstallation completed");
+        Ok(())
+    }
+
+    /// The rest of `uninstall` in Docker mode: the release images, and
+    /// compose's files unless purging removes all of /opt/silence-relay
+    async fn remove_container(&mut self, purge: bool) -> Result<()> {
+        let releases = self.list_releases().await?;
+        if !releases.is_empty() {
+            self.ssh_client.execute_command(&docker::remove_images(&releases)).await
+                .context("Failed to remove the relay's images")?;
+        }
 
-        // Remove user (optional, commented out for safety)
-        // let _ = self.ssh_client.execute_command("sudo userdel relay").await;
+        let files = if purge {
+            format!("sudo rm -rf {}", docker::COMPOSE_DIR)
+        } else {
+            format!("sudo rm -f {dir}/compose.yaml {dir}/.env", dir = docker::COMPOSE_DIR)
+        };
+        self.ssh_client.execute_command(&files).await
+            .context("Failed to remove the compose files")?;
+
+        // Nothing left behind that could still run
+        let state = self.ssh_client.execute_command(&docker::state()).await?;
+        if state.trim() != "missing" {
+            return Err(anyhow!("Relay container still present after uninstall"));
+        }
 
         info!("✅ Uninstallation completed");
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Whether a server answering `arch` to `uname -m` and `ldd` to
+/// `ldd --version` can run `binary`; an answer that can't be read is let
+/// through with a warning
+fn check_compatibility(binary: &BinaryInfo, host: &str, arch: &str, ldd: &str) -> Result<()> {
+    if let Some(expected) = binary.arch {
+        if arch.is_empty() {
+            warn!("Could not tell the architecture of {}", host);
+        } else if arch != expected {
+            return Err(anyhow!(
+                "The relay binary is built for {} but {} is {}; build for its target with --target",
+                expected, host, arch
+            ));
+        }
+    }
+
+    let Some(required) = binary.glibc else { return Ok(()) };
+    let static_hint = "build a static binary with --target x86_64-unknown-linux-musl";
+    match ldd.split_whitespace().last().and_then(GlibcVersion::parse) {
+        Some(available) if available < required => Err(anyhow!(
+            "The relay binary needs glibc {} or newer but {} has {}; {}",
+            required, host, available, static_hint
+        )),
+        Some(_) => Ok(()),
+        None if ldd.to_lowercase().contains("musl") => Err(anyhow!(
+            "The relay binary needs glibc {} but {} uses musl; {}",
+            required, host, static_hint
+        )),
+        None => {
+            warn!("Could not tell the glibc version of {}", host);
+            Ok(())
+        }
+    }
+}
+
+/// The release before `current` in `releases`, oldest first
+fn previous_release<'r>(releases: &'r [String], current: Option<&str>) -> Option<&'r str> {
+    let position = match current {
+        Some(current) => releases.iter().position(|release| release == current)?,
+        None => releases.len(),
+    };
+    releases[..position].last().map(String::as_str)
+}
+
+/// Which of `releases`, oldest first, go to leave the newest `keep`, always
+/// sparing `current`
+fn releases_to_prune(releases: &[String], current: Option<&str>, keep: usize) -> Vec<String> {
+    let stale = releases.len().saturating_sub(keep);
+    releases[..stale].iter().filter(|release| Some(release.as_str()) != current).cloned().collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    /// A server that answers commands from a script and remembers them
+    #[derive(Default)]
+    pub struct ScriptedRemote {
+        /// Exit status and output by command; anything else gets `otherwise`
+        pub replies: HashMap<String, (i32, String)>,
+        /// Exit 1 unless set
+        pub otherwise: Option<(i32, String)>,
+        pub ran: Vec<String>,
+    }
+
+    impl ScriptedRemote {
+        /// A server where every command not scripted succeeds with no output
+        pub fn succeeding() -> Self {
+            Self { otherwise: Some((0, String::new())), ..Self::default() }
+        }
+
+        pub fn reply(mut self, command: &str, exit_status: i32, output: &str) -> Self {
+            self.replies.insert(command.to_string(), (exit_status, output.to_string()));
+            self
+        }
+    }
+
+    #[async_trait]
+    impl Remote for ScriptedRemote {
+        async fn run(&mut self, command: &str) -> Result<(i32, String)> {
+            self.ran.push(command.to_string());
+            let otherwise = self.otherwise.clone().unwrap_or((1, String::new()));
+            Ok(self.replies.get(command).cloned().unwrap_or(otherwise))
+        }
+
+        async fn stream(&mut self, command: &str, out: &mut (dyn Write + Send)) -> Result<i32> {
+            let (exit_status, output) = self.run(command).await?;
+            out.write_all(output.as_bytes())?;
+            Ok(exit_status)
+        }
+
+        async fn upload_file(&mut self, _local_path: &Path, remote_path: &str, _mode: i32) -> Result<Transfer> {
+            self.ran.push(format!("upload {}", remote_path));
+            Ok(Transfer::default())
+        }
+    }
+
+    /// A package that isn't there, for servers that don't look at it
+    pub fn package() -> Package {
+        Package {
+            path: "package.tar.gz".into(),
+            sha256: "0".repeat(64),
+            binary_sha256: "1".repeat(64),
+        }
+    }
+
+    pub(crate) fn config() -> DeploymentConfig {
+        DeploymentConfig {
+            host: "relay.example".to_string(),
+            user: "root".to_string(),
+            ssh_key: "/home/me/.ssh/id_ed25519".into(),
+            port: 8080,
+            max_clients: 100,
+            max_message_size: 65536,
+            bind_address: "0.0.0.0".to_string(),
+            health_port: None,
+            password_auth: false,
+            mode: Mode::Systemd,
+            known_hosts: "/home/me/.ssh/known_hosts".into(),
+            host_key_policy: Default::default(),
+            ssh_port: 22,
+            jump_host: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status_of_a_running_service() {
+        let mut remote = ScriptedRemote::default()
+            .reply("systemctl is-active silence-relay", 0, "active\n")
+            .reply("sudo systemctl status silence-relay --no-pager", 0, "● silence-relay.service - Silence Relay Server\n");
+        let config = config();
+
+        let status = Deployer::new(&mut remote, &config).get_service_status().await.unwrap();
+        assert!(status.is_active());
+        assert_eq!(status.state, "active");
+        assert!(status.details.starts_with("● silence-relay.service"));
+        assert_eq!(status.binary, None, "nothing installed");
+    }
+
+    #[tokio::test]
+    async fn test_status_tells_of_a_changed_binary() {
+        let deployed = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let replaced = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let manifest = format!("[release]\nbinary_sha256 = \"{}\"\n", deployed);
+        let remote = |installed: &str| {
+            ScriptedRemote::default()
+                .reply("systemctl is-active silence-relay", 0, "active\n")
+                .reply("sha256sum /opt/silence-relay/current/silence-relay", 0, &format!("{}  /opt/silence-relay/current/silence-relay\n", installed))
+                .reply("cat /opt/silence-relay/current/deploy.conf", 0, &manifest)
+        };
+        let config = config();
+
+        let status = Deployer::new(&mut remote(deployed), &config).get_service_status().await.unwrap();
+        let binary = status.binary.unwrap();
+        assert_eq!(binary.sha256, deployed);
+        assert!(!binary.drifted());
+
+        let status = Deployer::new(&mut remote(replaced), &config).get_service_status().await.unwrap();
+        assert!(status.binary.unwrap().drifted());
+
+        let unrecorded = BinaryDigest { sha256: replaced.to_string(), recorded: None };
+        assert!(!unrecorded.drifted(), "deployed before digests were recorded");
+    }
+
+    #[tokio::test]
+    async fn test_status_of_a_stopped_service_is_no_error() {
+        // systemctl answers 3 for a unit that isn't running
+        let mut remote = ScriptedRemote::default()
+            .reply("systemctl is-active silence-relay", 3, "inactive\n")
+            .reply("sudo systemctl status silence-relay --no-pager", 3, "   Active: inactive (dead)\n");
+        let config = config();
+
+        let status = Deployer::new(&mut remote, &config).get_service_status().await.unwrap();
+        assert!(!status.is_active());
+        assert_eq!(status.state, "inactive");
+        assert_eq!((status.version, status.listening, status.uptime), (None, None, None));
+        assert_eq!(remote.ran.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_status_report() {
+        let built = Version::new("0.2.0", Some("3fd0229e37401142d86ad7663c56e82f26b71d0b".to_string()), SystemTime::UNIX_EPOCH);
+        let mut remote = ScriptedRemote::default()
+            .reply("systemctl is-active silence-relay", 0, "active\n")
+            .reply("sudo systemctl status silence-relay --no-pager", 0, "● silence-relay.service - Silence Relay Server\n")
+            .reply("cat /opt/silence-relay/current/version.json", 0, &built.to_json())
+            .reply("ss -Htln 'sport = :8080'", 0, "LISTEN 0      1024         0.0.0.0:8080      0.0.0.0:*\n")
+            .reply("ps -o etimes= -p \"$(systemctl show --property MainPID --value silence-relay)\"", 0, "  93784\n");
+        let config = config();
+
+        let status = Deployer::new(&mut remote, &config).get_service_status().await.unwrap();
+        assert_eq!(status.version.as_ref(), Some(&built));
+        assert_eq!(
+            status.report("relay.example", 8080),
+            "● silence-relay.service - Silence Relay Server\n\
+             🏷️  Version 0.2.0, commit 3fd0229e37401142d86ad7663c56e82f26b71d0b, built 1970-01-01T00:00:00Z\n\
+             👂 Listening on port 8080\n\
+             ⏱️  Up 1day 2h 3m 4s\n\
+             ✅ silence-relay on relay.example is running\n"
+        );
+
+        let stopped = ServiceStatus {
+            state: "failed".to_string(),
+            details: String::new(),
+            binary: Some(BinaryDigest { sha256: "a".repeat(64), recorded: Some("b".repeat(64)) }),
+            version: None,
+            listening: Some(false),
+            uptime: None,
+        };
+        assert_eq!(
+            stopped.report("relay.example", 8080),
+            format!(
+                "🔏 Binary sha256 {a}\n\
+                 ⚠️  The binary on relay.example has changed since it was deployed, with sha256 {b}\n\
+                 ⚠️  Nothing is listening on port 8080\n\
+                 ⚠️  silence-relay on relay.example is failed\n",
+                a = "a".repeat(64),
+                b = "b".repeat(64)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uninstall_keeps_the_config_unless_purging() {
+        let config = config();
+        let mut remote = ScriptedRemote::default()
+            .reply("sudo rm -rf /opt/silence-relay/releases /opt/silence-relay/current /opt/silence-relay/silence-relay", 0, "");
+        Deployer::new(&mut remote, &config).uninstall(false).await.unwrap();
+        assert!(!remote.ran.iter().any(|command| command == "sudo rm -rf /opt/silence-relay"));
+
+        let mut remote = ScriptedRemote::default().reply("sudo rm -rf /opt/silence-relay", 0, "");
+        Deployer::new(&mut remote, &config).uninstall(true).await.unwrap();
+        assert!(remote.ran.iter().any(|command| command == "sudo userdel relay"));
+    }
+
+    #[tokio::test]
+    async fn test_followed_logs_are_filtered() {
+        let config = config();
+        let mut remote = ScriptedRemote::default().reply(
+            "sudo journalctl -u silence-relay --no-pager --utc -o short-iso -n 5 -f",
+            0,
+            "2025-06-02T12:00:00+00:00 relay silence-relay[812]: Relay listening\r\n\
+             2025-06-02T12:00:05+00:00 relay silence-relay[812]: ERROR frame too large\r\n\
+             2025-06-02T12:00:06+00:00 relay silence-relay[812]: ERROR cut o",
+        );
+        let filter = LogFilter::new(None, Some("ERROR")).unwrap();
+        let mut out = Vec::new();
+        Deployer::new(&mut remote, &config).follow_service_logs(5, &filter, &mut out).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2025-06-02T12:00:05+00:00 relay silence-relay[812]: ERROR frame too large\n\
+             2025-06-02T12:00:06+00:00 relay silence-relay[812]: ERROR cut o\n"
+        );
+    }
+
+    fn releases(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pruning_keeps_the_newest_and_the_current() {
+        let all = releases(&["r1", "r2", "r3", "r4", "r5"]);
+        assert_eq!(releases_to_prune(&all, Some("r5"), 3), releases(&["r1", "r2"]));
+        // Rolled back to an old one, which stays
+        assert_eq!(releases_to_prune(&all, Some("r1"), 3), releases(&["r2"]));
+        assert!(releases_to_prune(&all, Some("r5"), 5).is_empty());
+        assert!(releases_to_prune(&[], None, 1).is_empty());
+
+        assert_eq!(previous_release(&all, Some("r3")), Some("r2"));
+        assert_eq!(previous_release(&all, Some("r1")), None);
+        assert_eq!(previous_release(&all, None), Some("r5"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rollback_flips_the_symlink_and_restarts() {
+        let config = config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply("ls -1tr /opt/silence-relay/releases", 0, "20260101000000\n20260201000000\n20260301000000\n")
+            .reply("readlink /opt/silence-relay/current", 0, "releases/20260301000000\n")
+            .reply("sudo systemctl is-active silence-relay", 0, "active\n");
+
+        let release = Deployer::new(&mut remote, &config).rollback(None).await.unwrap();
+        assert_eq!(release, "20260201000000");
+        let switched = remote.ran.iter().position(|command| {
+            command == "sudo ln -sfn releases/20260201000000 /opt/silence-relay/current.new \
+                        && sudo mv -T /opt/silence-relay/current.new /opt/silence-relay/current"
+        });
+        let restarted = remote.ran.iter().position(|command| command == "sudo systemctl restart silence-relay");
+        assert!(switched.unwrap() < restarted.unwrap());
+
+        let mut deployer = Deployer::new(&mut remote, &config);
+        assert_eq!(deployer.rollback(Some("20260101000000")).await.unwrap(), "20260101000000");
+        assert!(deployer.rollback(Some("20250101000000")).await.is_err(), "not on the server");
+        assert!(deployer.rollback(Some("20260301000000")).await.is_err(), "already current");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_deploy_goes_back_to_the_previous_release() {
+        let config = config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply("date -u +%Y%m%d%H%M%S", 0, "20260401000000\n")
+            .reply("ls -1tr /opt/silence-relay/releases", 0, "20260301000000\n")
+            .reply("readlink /opt/silence-relay/current", 0, "releases/20260301000000\n")
+            .reply("sudo systemctl is-active silence-relay", 3, "failed\n");
+
+        let result = Deployer::new(&mut remote, &config).deploy(&package(), None, 5, false).await;
+        assert!(result.is_err());
+
+        let ran = |prefix: &str| remote.ran.iter().position(|command| command.starts_with(prefix));
+        let installed = ran("cd /tmp/silence-relay-extract && sudo ./install.sh 20260401000000").unwrap();
+        let switched = ran("sudo ln -sfn releases/20260401000000 ").unwrap();
+        let reverted = ran("sudo ln -sfn releases/20260301000000 ").unwrap();
+        let dropped = ran("sudo rm -rf /opt/silence-relay/releases/20260401000000").unwrap();
+        assert!(installed < switched && switched < reverted && reverted < dropped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deploy_prunes_old_releases() {
+        let config = config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply("ls -1tr /opt/silence-relay/releases", 0, "r1\nr2\nr3\nr4\n")
+            .reply("readlink /opt/silence-relay/current", 0, "releases/r4\n")
+            .reply("sudo systemctl is-active silence-relay", 0, "active\n");
+
+        let mut deployer = Deployer::new(&mut remote, &config);
+        assert!(deployer.deploy(&package(), Some("r2"), 2, false).await.is_err(), "r2 is already there");
+        assert_eq!(deployer.deploy(&package(), Some("v5"), 2, false).await.unwrap().release, "v5");
+        assert!(remote.ran.iter().any(|command| command == "sudo rm -rf /opt/silence-relay/releases/r1 /opt/silence-relay/releases/r2"));
+    }
+
+    fn docker_config() -> DeploymentConfig {
+        DeploymentConfig { mode: Mode::Docker, ..config() }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_docker_deploy_builds_an_image_and_has_compose_run_it() {
+        let config = docker_config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply("date -u +%Y%m%d%H%M%S", 0, "20260401000000\n")
+            .reply(&docker::list_releases(), 0, "20260301000000\n")
+            .reply(&docker::current_release(), 0, "20260301000000\n")
+            .reply(&docker::state(), 0, "running\n");
+
+        let deployment = Deployer::new(&mut remote, &config).deploy(&package(), None, 5, false).await.unwrap();
+        assert_eq!(deployment.previous.as_deref(), Some("20260301000000"));
+
+        let ran = |command: &str| remote.ran.iter().position(|ran| ran == command);
+        let built = ran("cd /tmp/silence-relay-extract && sudo docker build --quiet -t silence-relay:20260401000000 .").unwrap();
+        let installed = ran("sudo mkdir -p /opt/silence-relay && sudo cp /tmp/silence-relay-extract/compose.yaml /opt/silence-relay/").unwrap();
+        let switched = ran(&docker::switch("20260401000000")).unwrap();
+        let checked = ran(&docker::state()).unwrap();
+        assert!(built < installed && installed < switched && switched < checked);
+        assert!(ran("test -f /tmp/silence-relay-extract/Dockerfile").is_some());
+        assert!(!remote.ran.iter().any(|command| command.contains("systemctl")), "{:?}", remote.ran);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_docker_deploy_goes_back_to_the_previous_image() {
+        let config = docker_config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply(&docker::list_releases(), 0, "v1\n")
+            .reply(&docker::current_release(), 0, "v1\n")
+            .reply(&docker::state(), 0, "restarting\n");
+
+        let error = Deployer::new(&mut remote, &config).deploy(&package(), Some("v2"), 5, false).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Container is not running: restarting"), "{:#}", error);
+
+        let ran = |command: &str| remote.ran.iter().position(|ran| ran == command);
+        let switched = ran(&docker::switch("v2")).unwrap();
+        let reverted = ran(&docker::switch("v1")).unwrap();
+        let dropped = ran("sudo docker image rm silence-relay:v2").unwrap();
+        assert!(switched < reverted && reverted < dropped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_docker_status_logs_and_restart() {
+        let config = docker_config();
+        let mut remote = ScriptedRemote::default()
+            .reply(&docker::state(), 0, "running\n")
+            .reply(&docker::status(), 0, "CONTAINER ID   IMAGE                 STATUS\n")
+            .reply(&docker::compose_command("restart"), 0, "")
+            .reply(
+                "sudo docker logs --timestamps --tail 20 silence-relay 2>&1",
+                0,
+                "2026-06-02T12:00:00.000000000Z Relay listening\n2026-06-02T12:00:05.000000000Z ERROR frame too large\n",
+            );
+        let mut deployer = Deployer::new(&mut remote, &config);
+
+        let status = deployer.get_service_status().await.unwrap();
+        assert!(status.is_active());
+        assert!(status.details.starts_with("CONTAINER ID"));
+        assert_eq!(status.binary, None, "nothing to read from a container that isn't there");
+
+        let filter = LogFilter::new(None, Some("ERROR")).unwrap();
+        assert_eq!(deployer.get_service_logs(20, &filter).await.unwrap(), "2026-06-02T12:00:05.000000000Z ERROR frame too large\n");
+
+        deployer.restart_service().await.unwrap();
+        assert!(remote.ran.contains(&"cd /opt/silence-relay && sudo docker compose restart".to_string()));
+        assert!(!remote.ran.iter().any(|command| command.contains("systemctl")));
+    }
+
+    #[tokio::test]
+    async fn test_docker_uninstall_removes_the_images() {
+        let config = docker_config();
+        let mut remote = ScriptedRemote::succeeding()
+            .reply(&docker::list_releases(), 0, "v1\nv2\n")
+            .reply(&docker::state(), 0, "missing\n");
+        Deployer::new(&mut remote, &config).uninstall(false).await.unwrap();
+        assert!(remote.ran.contains(&"cd /opt/silence-relay && sudo docker compose down".to_string()));
+        assert!(remote.ran.contains(&"sudo docker image rm silence-relay:v1 silence-relay:v2".to_string()));
+        assert!(remote.ran.contains(&"sudo rm -f /opt/silence-relay/compose.yaml /opt/silence-relay/.env".to_string()));
+    }
+
+    #[test]
+    fn test_host_compatibility() {
+        let dynamic = BinaryInfo { arch: Some("x86_64"), glibc: Some(GlibcVersion(2, 34)) };
+        let ubuntu_22 = "ldd (Ubuntu GLIBC 2.35-0ubuntu3.8) 2.35";
+        let debian_11 = "ldd (Debian GLIBC 2.31-13+deb11u11) 2.31";
+        let alpine = "musl libc (x86_64)";
+
+        assert!(check_compatibility(&dynamic, "relay.example", "x86_64", ubuntu_22).is_ok());
+        let error = check_compatibility(&dynamic, "relay.example", "x86_64", debian_11).unwrap_err().to_string();
+        assert!(error.contains("needs glibc 2.34 or newer but relay.example has 2.31"), "{}", error);
+        assert!(error.contains("--target x86_64-unknown-linux-musl"), "{}", error);
+        assert!(check_compatibility(&dynamic, "relay.example", "x86_64", alpine).is_err());
+        assert!(check_compatibility(&dynamic, "relay.example", "aarch64", ubuntu_22).is_err());
+        assert!(check_compatibility(&dynamic, "relay.example", "", "").is_ok(), "unknown is let through");
+
+        let musl = BinaryInfo { arch: Some("x86_64"), glibc: None };
+        assert!(check_compatibility(&musl, "relay.example", "x86_64", debian_11).is_ok());
+        assert!(check_compatibility(&musl, "relay.example", "x86_64", alpine).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_host_asks_the_server() {
+        let config = config();
+        let mut remote = ScriptedRemote::default()
+            .reply("uname -m", 0, "x86_64\n")
+            .reply("ldd --version 2>&1 | head -n 1", 0, "ldd (Debian GLIBC 2.31-13+deb11u11) 2.31\n");
+        let binary = BinaryInfo { arch: Some("x86_64"), glibc: Some(GlibcVersion(2, 34)) };
+        assert!(Deployer::new(&mut remote, &config).check_host(&binary).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_host_in_docker_mode() {
+        let config = docker_config();
+        let server = || ScriptedRemote::default().reply("uname -m", 0, "x86_64\n");
+        let glibc_2_34 = BinaryInfo { arch: Some("x86_64"), glibc: Some(GlibcVersion(2, 34)) };
+
+        // The server's own glibc doesn't matter, only the image's
+        let mut remote = server().reply("sudo docker compose version", 0, "Docker Compose version v2.29.1\n");
+        Deployer::new(&mut remote, &config).check_host(&glibc_2_34).await.unwrap();
+        assert!(!remote.ran.iter().any(|command| command.starts_with("ldd")));
+
+        let glibc_2_39 = BinaryInfo { glibc: Some(GlibcVersion(2, 39)), ..glibc_2_34 };
+        let error = Deployer::new(&mut remote, &config).check_host(&glibc_2_39).await.unwrap_err().to_string();
+        assert!(error.contains("needs glibc 2.39 or newer but the image has 2.36"), "{}", error);
+
+        let error = Deployer::new(&mut server(), &config).check_host(&glibc_2_34).await.unwrap_err().to_string();
+        assert!(error.contains("doesn't have Docker Compose"), "{}", error);
+    }
+}
@@ -0,0 +1,229 @@
+// Docker mode: the relay runs in a container under Docker Compose rather
+// than as a systemd service.
+//
+// The package carries a Dockerfile and compose.yaml alongside the binary.
+// The image is built on the server, one tag per release, and
+// /opt/silence-relay/.env names the tag compose runs, which makes that file
+// what `current` is in systemd mode. Compose stops the old container with
+// SIGUSR1, so the relay drains its clients before the new one takes over.
+
+use crate::builder::{BinaryInfo, GlibcVersion};
+use crate::DeploymentConfig;
+
+/// The image, tagged with each release's name
+pub const IMAGE: &str = "silence-relay";
+/// The container compose runs
+pub const CONTAINER: &str = "silence-relay";
+/// Where compose.yaml and the `.env` naming the current image live
+pub const COMPOSE_DIR: &str = "/opt/silence-relay";
+
+/// Base image for a static binary, which needs nothing else...
+const STATIC_BASE: &str = "scratch";
+/// ...and for one linked against glibc
+const GLIBC_BASE: &str = "gcr.io/distroless/cc-debian12";
+/// The glibc that image has, the newest a binary may need
+pub const BASE_GLIBC: GlibcVersion = GlibcVersion(2, 36);
+
+/// The uid the relay runs as inside the container, distroless's `nonroot`
+const RELAY_UID: u32 = 65532;
+
+/// Longest the relay is given to drain once told to stop, as in systemd mode
+const STOP_GRACE_SECONDS: u64 = 60;
+
+/// A minimal image holding just the relay, its settings, the package
+/// manifest and the version file
+pub fn dockerfile(binary: &BinaryInfo, config: &DeploymentConfig) -> String {
+    let base = if binary.glibc.is_some() { GLIBC_BASE } else { STATIC_BASE };
+    let mut exposed = format!("EXPOSE {}/tcp\n", config.port);
+    if let Some(health_port) = config.health_port {
+        exposed.push_str(&format!("EXPOSE {}/tcp\n", health_port));
+    }
+    format!(
+        r#"# Silence Relay Server, built by the deploy tool
+FROM {base}
+COPY silence-relay /silence-relay
+COPY relay.toml /etc/silence-relay/relay.toml
+COPY deploy.conf version.json /
+USER {uid}:{uid}
+{exposed}ENTRYPOINT ["/silence-relay", "--config", "/etc/silence-relay/relay.toml"]
+"#,
+        base = base,
+        uid = RELAY_UID,
+        exposed = exposed
+    )
+}
+
+/// `host:port:port` for publishing `port` on `address` of the server
+fn published(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}:{}", address, port, port)
+    } else {
+        format!("{}:{}:{}", address, port, port)
+    }
+}
+
+/// Runs the relay from the image `.env` names, restarting it unless it was
+/// stopped on purpose. The relay listens on every address inside the
+/// container; which of the server's it's reached on is up to the published
+/// ports, the health endpoint staying on loopback as it does in systemd mode.
+pub fn compose(config: &DeploymentConfig) -> String {
+    let mut ports = format!("      - \"{}\"\n", published(&config.bind_address, config.port));
+    if let Some(health_port) = config.health_port {
+        ports.push_str(&format!("      - \"{}\"\n", published("127.0.0.1", health_port)));
+    }
+    format!(
+        r#"# Silence Relay Server, deployed by the deploy tool
+services:
+  silence-relay:
+    image: ${{RELAY_IMAGE:?set in .env by the deploy tool}}
+    container_name: {container}
+    restart: unless-stopped
+    # The relay drains its clients on SIGUSR1, then exits
+    stop_signal: SIGUSR1
+    stop_grace_period: {grace}s
+    environment:
+      RUST_LOG: info
+    ports:
+{ports}    read_only: true
+    cap_drop:
+      - ALL
+    security_opt:
+      - no-new-privileges:true
+"#,
+        container = CONTAINER,
+        grace = STOP_GRACE_SECONDS,
+        ports = ports
+    )
+}
+
+/// Build the extracted package into the image for `release`
+pub fn build(release: &str) -> String {
+    format!("cd /tmp/silence-relay-extract && sudo docker build --quiet -t {}:{} .", IMAGE, release)
+}
+
+/// Put the package's compose file in place
+pub fn install() -> String {
+    format!("sudo mkdir -p {dir} && sudo cp /tmp/silence-relay-extract/compose.yaml {dir}/", dir = COMPOSE_DIR)
+}
+
+/// Point `.env` at `release` and have compose run it, replacing the
+/// container if the image changed
+pub fn switch(release: &str) -> String {
+    format!(
+        "echo 'RELAY_IMAGE={image}:{release}' | sudo tee {dir}/.env > /dev/null && {up}",
+        image = IMAGE,
+        release = release,
+        dir = COMPOSE_DIR,
+        up = compose_command("up -d")
+    )
+}
+
+/// `docker compose` with `arguments`, for the relay's project
+pub fn compose_command(arguments: &str) -> String {
+    format!("cd {} && sudo docker compose {}", COMPOSE_DIR, arguments)
+}
+
+/// Prints the release `.env` names
+pub fn current_release() -> String {
+    format!("sed -n 's/^RELAY_IMAGE={}://p' {}/.env", IMAGE, COMPOSE_DIR)
+}
+
+/// Prints the releases there are images for, oldest first
+pub fn list_releases() -> String {
+    format!("sudo docker image ls {} --format '{{{{.CreatedAt}}}}\t{{{{.Tag}}}}' | sort | cut -f2", IMAGE)
+}
+
+/// Removes the images of `releases`
+pub fn remove_images(releases: &[String]) -> String {
+    let tags: Vec<String> = releases.iter().map(|release| format!("{}:{}", IMAGE, release)).collect();
+    format!("sudo docker image rm {}", tags.join(" "))
+}
+
+/// Prints the container's state: `running`, `restarting`, `exited`..., or
+/// `missing` when there's no container
+pub fn state() -> String {
+    format!("sudo docker inspect -f '{{{{.State.Status}}}}' {} 2>/dev/null || echo missing", CONTAINER)
+}
+
+/// The container as `docker ps` shows it, for people
+pub fn status() -> String {
+    format!("sudo docker ps -a --filter 'name=^{}$'", CONTAINER)
+}
+
+/// Prints the container's last `lines` log lines, each stamped in UTC
+pub fn logs(lines: u32, follow: bool) -> String {
+    let follow = if follow { " -f" } else { "" };
+    format!("sudo docker logs --timestamps --tail {}{} {} 2>&1", lines, follow, CONTAINER)
+}
+
+/// Prints the pid of the container's relay, as the server sees it; 0 when
+/// it isn't running
+pub fn pid() -> String {
+    format!("sudo docker inspect -f '{{{{.State.Pid}}}}' {}", CONTAINER)
+}
+
+/// Prints a file from the container, and nothing if there's no container
+pub fn read_file(path: &str) -> String {
+    format!(
+        "sudo docker container inspect {container} > /dev/null && sudo docker cp {container}:{path} - | tar -xO",
+        container = CONTAINER,
+        path = path
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::tests::config;
+
+    #[test]
+    fn test_dockerfile() {
+        let musl = BinaryInfo { arch: Some("x86_64"), glibc: None };
+        let dockerfile = dockerfile(&musl, &config());
+        assert_eq!(
+            dockerfile,
+            r#"# Silence Relay Server, built by the deploy tool
+FROM scratch
+COPY silence-relay /silence-relay
+COPY relay.toml /etc/silence-relay/relay.toml
+COPY deploy.conf version.json /
+USER 65532:65532
+EXPOSE 8080/tcp
+ENTRYPOINT ["/silence-relay", "--config", "/etc/silence-relay/relay.toml"]
+"#
+        );
+
+        let glibc = BinaryInfo { arch: Some("x86_64"), glibc: Some(GlibcVersion(2, 34)) };
+        let with_health = DeploymentConfig { health_port: Some(8081), ..config() };
+        let dockerfile = super::dockerfile(&glibc, &with_health);
+        assert!(dockerfile.contains("\nFROM gcr.io/distroless/cc-debian12\n"), "{}", dockerfile);
+        assert!(dockerfile.contains("EXPOSE 8080/tcp\nEXPOSE 8081/tcp\n"), "{}", dockerfile);
+    }
+
+    #[test]
+    fn test_compose() {
+        let compose = compose(&config());
+        assert!(compose.contains("    image: ${RELAY_IMAGE:?set in .env by the deploy tool}\n"), "{}", compose);
+        assert!(compose.contains("    restart: unless-stopped\n"), "{}", compose);
+        assert!(compose.contains("    stop_signal: SIGUSR1\n    stop_grace_period: 60s\n"), "{}", compose);
+        assert!(compose.contains("    ports:\n      - \"0.0.0.0:8080:8080\"\n    read_only: true\n"), "{}", compose);
+
+        let config = DeploymentConfig { bind_address: "::".to_string(), health_port: Some(8081), port: 9000, ..config() };
+        let compose = super::compose(&config);
+        assert!(compose.contains("      - \"[::]:9000:9000\"\n      - \"127.0.0.1:8081:8081\"\n"), "{}", compose);
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            switch("v2"),
+            "echo 'RELAY_IMAGE=silence-relay:v2' | sudo tee /opt/silence-relay/.env > /dev/null \
+             && cd /opt/silence-relay && sudo docker compose up -d"
+        );
+        assert_eq!(list_releases(), "sudo docker image ls silence-relay --format '{{.CreatedAt}}\t{{.Tag}}' | sort | cut -f2");
+        assert_eq!(state(), "sudo docker inspect -f '{{.State.Status}}' silence-relay 2>/dev/null || echo missing");
+        assert_eq!(pid(), "sudo docker inspect -f '{{.State.Pid}}' silence-relay");
+        assert_eq!(logs(20, true), "sudo docker logs --timestamps --tail 20 -f silence-relay 2>&1");
+        assert_eq!(remove_images(&["r1".to_string(), "r2".to_string()]), "sudo docker image rm silence-relay:r1 silence-relay:r2");
+    }
+}
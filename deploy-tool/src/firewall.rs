@@ -0,0 +1,292 @@
+use anyhow::{Context, Result, anyhow};
+use std::fmt;
+use tracing::{debug, info, warn};
+
+use crate::ssh::Remote;
+
+/// Where the rules the tool added are listed, a `<firewall> <port>/tcp` line
+/// each, for `uninstall` to take out again
+pub const RULES_FILE: &str = "/opt/silence-relay/firewall-rules";
+
+/// The commands for one kind of firewall, `{port}` standing for the port
+#[derive(Debug, PartialEq)]
+pub struct Flavor {
+    pub name: &'static str,
+    /// Prints `running` while this firewall is up, and fails or prints
+    /// something else when it isn't there or is off
+    detect: &'static str,
+    running: &'static str,
+    /// Succeeds only if the port is open
+    is_open: &'static str,
+    open: &'static str,
+    close: &'static str,
+}
+
+/// The firewalls the tool can open ports in, tried in this order
+pub const FLAVORS: &[Flavor] = &[
+    Flavor {
+        name: "ufw",
+        detect: "sudo ufw status",
+        running: "Status: active",
+        is_open: "sudo ufw status | grep -Eq '^{port}/tcp +ALLOW'",
+        open: "sudo ufw allow {port}/tcp comment silence-relay",
+        close: "sudo ufw delete allow {port}/tcp",
+    },
+    Flavor {
+        name: "firewalld",
+        detect: "sudo firewall-cmd --state",
+        running: "running",
+        is_open: "sudo firewall-cmd --query-port={port}/tcp",
+        open: "sudo firewall-cmd --permanent --add-port={port}/tcp && sudo firewall-cmd --reload",
+        close: "sudo firewall-cmd --permanent --remove-port={port}/tcp && sudo firewall-cmd --reload",
+    },
+];
+
+impl Flavor {
+    fn named(name: &str) -> Option<&'static Flavor> {
+        FLAVORS.iter().find(|flavor| flavor.name == name)
+    }
+
+    fn command(template: &str, port: u16) -> String {
+        template.replace("{port}", &port.to_string())
+    }
+
+    async fn is_open(&self, remote: &mut dyn Remote, port: u16) -> Result<bool> {
+        let (exit_status, _) = remote.run(&Self::command(self.is_open, port)).await
+            .with_context(|| format!("Failed to ask {} about port {}", self.name, port))?;
+        Ok(exit_status == 0)
+    }
+}
+
+/// A port the tool opened
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub firewall: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}/tcp", self.firewall, self.port)
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let (firewall, port) = line.trim().split_once(' ')?;
+        let port = port.strip_suffix("/tcp")?.parse().ok()?;
+        Some(Self { firewall: firewall.to_string(), port })
+    }
+}
+
+/// The firewall running on the server, if it's one the tool knows
+pub async fn detect(remote: &mut dyn Remote) -> Result<Option<&'static Flavor>> {
+    for flavor in FLAVORS {
+        let (exit_status, output) = remote.run(flavor.detect).await
+            .with_context(|| format!("Failed to look for {}", flavor.name))?;
+        if exit_status == 0 && output.contains(flavor.running) {
+            return Ok(Some(flavor));
+        }
+    }
+    Ok(None)
+}
+
+/// Open each of `ports` to TCP in `flavor`, leaving those open already
+/// alone, and check they took; answers the rules added
+pub async fn open_ports(remote: &mut dyn Remote, flavor: &Flavor, ports: &[u16]) -> Result<Vec<Rule>> {
+    let mut added = Vec::new();
+    for &port in ports {
+        if flavor.is_open(remote, port).await? {
+            info!("✅ Port {}/tcp is already open in {}", port, flavor.name);
+            continue;
+        }
+        remote.execute_command(&Flavor::command(flavor.open, port)).await
+            .with_context(|| format!("Failed to open port {} in {}", port, flavor.name))?;
+        if !flavor.is_open(remote, port).await? {
+            return Err(anyhow!("Port {} is still closed in {} after opening it", port, flavor.name));
+        }
+        info!("🧱 Opened port {}/tcp in {}", port, flavor.name);
+        added.push(Rule { firewall: flavor.name.to_string(), port });
+    }
+    Ok(added)
+}
+
+/// The rules the tool added, as `RULES_FILE` lists them
+pub async fn recorded(remote: &mut dyn Remote) -> Result<Vec<Rule>> {
+    let (exit_status, text) = remote.run(&format!("cat {}", RULES_FILE)).await
+        .context("Failed to read the firewall rules added")?;
+    if exit_status != 0 {
+        return Ok(Vec::new());
+    }
+    Ok(text.lines().filter_map(Rule::parse).collect())
+}
+
+/// Add `rules` to `RULES_FILE`
+pub async fn record(remote: &mut dyn Remote, rules: &[Rule]) -> Result<()> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let mut all = recorded(remote).await?;
+    for rule in rules {
+        if !all.contains(rule) {
+            all.push(rule.clone());
+        }
+    }
+    let lines: Vec<String> = all.iter().map(|rule| format!("'{}'", rule)).collect();
+    let write = format!(
+        "sudo mkdir -p /opt/silence-relay && printf '%s\\n' {} | sudo tee {} > /dev/null",
+        lines.join(" "),
+        RULES_FILE
+    );
+    remote.execute_command(&write).await
+        .context("Failed to record the firewall rules added")?;
+    Ok(())
+}
+
+/// Take out the rules `RULES_FILE` lists; one that won't go is logged and
+/// left, as uninstalling shouldn't stop halfway for it
+pub async fn close_recorded(remote: &mut dyn Remote) -> Result<()> {
+    for rule in recorded(remote).await? {
+        let Some(flavor) = Flavor::named(&rule.firewall) else {
+            warn!("Not removing firewall rule {}: unknown firewall", rule);
+            continue;
+        };
+        match remote.execute_command(&Flavor::command(flavor.close, rule.port)).await {
+            Ok(_) => info!("🧱 Closed port {}/tcp in {}", rule.port, flavor.name),
+            Err(e) => warn!("Could not close port {}/tcp in {}: {:#}", rule.port, flavor.name, e),
+        }
+    }
+    debug!("Removing {}", RULES_FILE);
+    remote.execute_command(&format!("sudo rm -f {}", RULES_FILE)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::tests::ScriptedRemote;
+    use crate::ssh::Transfer;
+    use async_trait::async_trait;
+    use std::collections::BTreeSet;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// A server running `flavor`, answering its commands as it would
+    struct FakeFirewall {
+        flavor: &'static Flavor,
+        open: BTreeSet<u16>,
+        /// Opening a port says it worked but changes nothing
+        stuck: bool,
+        rules_file: Option<String>,
+        ran: Vec<String>,
+    }
+
+    impl FakeFirewall {
+        fn new(flavor: &'static Flavor, open: &[u16]) -> Self {
+            Self { flavor, open: open.iter().copied().collect(), stuck: false, rules_file: None, ran: Vec::new() }
+        }
+
+        fn port_in(&self, template: &str, command: &str) -> Option<u16> {
+            [22, 8080, 8081, 9090].into_iter().find(|&port| Flavor::command(template, port) == command)
+        }
+    }
+
+    #[async_trait]
+    impl Remote for FakeFirewall {
+        async fn run(&mut self, command: &str) -> Result<(i32, String)> {
+            self.ran.push(command.to_string());
+            if command == self.flavor.detect {
+                return Ok((0, format!("{}\n", self.flavor.running)));
+            }
+            if let Some(port) = self.port_in(self.flavor.is_open, command) {
+                return Ok((if self.open.contains(&port) { 0 } else { 1 }, String::new()));
+            }
+            if let Some(port) = self.port_in(self.flavor.open, command) {
+                if !self.stuck {
+                    self.open.insert(port);
+                }
+                return Ok((0, String::new()));
+            }
+            if let Some(port) = self.port_in(self.flavor.close, command) {
+                self.open.remove(&port);
+                return Ok((0, String::new()));
+            }
+            if command == format!("cat {}", RULES_FILE) {
+                return Ok(match &self.rules_file {
+                    Some(text) => (0, text.clone()),
+                    None => (1, String::new()),
+                });
+            }
+            if command.ends_with(&format!("| sudo tee {} > /dev/null", RULES_FILE)) {
+                let lines: Vec<&str> = command.split('\'').skip(3).step_by(2).collect();
+                self.rules_file = Some(lines.iter().map(|line| format!("{}\n", line)).collect());
+                return Ok((0, String::new()));
+            }
+            if command == format!("sudo rm -f {}", RULES_FILE) {
+                self.rules_file = None;
+                return Ok((0, String::new()));
+            }
+            // Anything else, such as another firewall's commands
+            Ok((127, String::new()))
+        }
+
+        async fn stream(&mut self, command: &str, _out: &mut (dyn Write + Send)) -> Result<i32> {
+            Ok(self.run(command).await?.0)
+        }
+
+        async fn upload_file(&mut self, _local_path: &Path, _remote_path: &str, _mode: i32) -> Result<Transfer> {
+            Ok(Transfer::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detection() {
+        for flavor in FLAVORS {
+            let mut server = FakeFirewall::new(flavor, &[]);
+            assert_eq!(detect(&mut server).await.unwrap(), Some(flavor));
+        }
+        let inactive = ScriptedRemote::default().reply("sudo ufw status", 0, "Status: inactive\n");
+        assert_eq!(detect(&mut { inactive }).await.unwrap(), None);
+        assert_eq!(detect(&mut ScriptedRemote::default()).await.unwrap(), None, "neither installed");
+    }
+
+    #[tokio::test]
+    async fn test_ports_are_opened_once_and_closed_again() {
+        for flavor in FLAVORS {
+            // SSH's port is open already, as it would be for the tool to get in
+            let mut server = FakeFirewall::new(flavor, &[22]);
+            let added = open_ports(&mut server, flavor, &[22, 8080, 8081]).await.unwrap();
+            assert_eq!(added.iter().map(|rule| rule.port).collect::<Vec<_>>(), [8080, 8081], "{}", flavor.name);
+            record(&mut server, &added).await.unwrap();
+            assert_eq!(server.open, BTreeSet::from([22, 8080, 8081]));
+
+            // A second deploy finds them open and adds nothing
+            let opens = server.ran.len();
+            assert!(open_ports(&mut server, flavor, &[8080, 8081]).await.unwrap().is_empty());
+            assert_eq!(server.ran.len(), opens + 2, "only asked, {}", flavor.name);
+            let another = Rule { firewall: flavor.name.to_string(), port: 9090 };
+            record(&mut server, std::slice::from_ref(&another)).await.unwrap();
+            let expected = format!("{} 8080/tcp\n{} 8081/tcp\n{} 9090/tcp\n", flavor.name, flavor.name, flavor.name);
+            assert_eq!(server.rules_file.as_deref(), Some(expected.as_str()));
+
+            server.open.insert(9090);
+            close_recorded(&mut server).await.unwrap();
+            assert_eq!(server.open, BTreeSet::from([22]), "the rule that was there before stays, {}", flavor.name);
+            assert_eq!(server.rules_file, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_a_rule_that_does_not_take_fails() {
+        for flavor in FLAVORS {
+            let mut server = FakeFirewall { stuck: true, ..FakeFirewall::new(flavor, &[]) };
+            let error = open_ports(&mut server, flavor, &[8080]).await.unwrap_err().to_string();
+            assert!(error.contains("still closed"), "{}", error);
+        }
+
+        assert_eq!(Rule::parse("ufw 8080/tcp"), Some(Rule { firewall: "ufw".to_string(), port: 8080 }));
+        assert_eq!(Rule::parse("ufw 8080"), None);
+        let mut nothing_recorded = ScriptedRemote::default().reply(&format!("sudo rm -f {}", RULES_FILE), 0, "");
+        close_recorded(&mut nothing_recorded).await.unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::deployer::Mode;
+use crate::{DeploymentConfig, expand_home_path};
+
+/// The servers to run a command on, from `--inventory`:
+///
+/// ```toml
+/// [[hosts]]
+/// host = "relay1.example"
+///
+/// [[hosts]]
+/// host = "relay2.example"
+/// user = "deploy"
+/// ssh_key = "~/.ssh/relay2"
+/// ssh_port = 2222
+/// port = 9000
+/// mode = "docker"
+/// ```
+///
+/// `user`, `ssh_key`, `ssh_port`, `port` (the relay's) and `mode` override
+/// the command line for that host only.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Inventory {
+    pub hosts: Vec<HostEntry>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HostEntry {
+    pub host: String,
+    pub user: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    pub ssh_port: Option<u16>,
+    pub port: Option<u16>,
+    pub mode: Option<Mode>,
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read inventory {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid inventory {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let inventory: Self = toml::from_str(text)?;
+        if inventory.hosts.is_empty() {
+            return Err(anyhow!("no hosts listed"));
+        }
+        if let Some(entry) = inventory.hosts.iter().find(|entry| entry.host.trim().is_empty()) {
+            return Err(anyhow!("a host entry has an empty host: {:?}", entry));
+        }
+        Ok(inventory)
+    }
+
+    /// Hosts from `--hosts`, each with the command line's settings
+    pub fn from_hosts(hosts: &[String]) -> Self {
+        let hosts = hosts
+            .iter()
+            .map(|host| host.trim())
+            .filter(|host| !host.is_empty())
+            .map(|host| HostEntry { host: host.to_string(), ..HostEntry::default() })
+            .collect();
+        Self { hosts }
+    }
+
+    /// Each host's settings: `base`, with the host's own overrides
+    pub fn configs(&self, base: &DeploymentConfig) -> Vec<DeploymentConfig> {
+        self.hosts.iter().map(|entry| entry.apply(base)).collect()
+    }
+}
+
+impl HostEntry {
+    pub fn apply(&self, base: &DeploymentConfig) -> DeploymentConfig {
+        let mut config = base.clone();
+        config.host = self.host.clone();
+        if let Some(user) = &self.user {
+            config.user = user.clone();
+        }
+        if let Some(ssh_key) = &self.ssh_key {
+            config.ssh_key = expand_home_path(ssh_key.clone());
+        }
+        if let Some(ssh_port) = self.ssh_port {
+            config.ssh_port = ssh_port;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(mode) = self.mode {
+            config.mode = mode;
+        }
+        config
+    }
+}
+
+/// How a command went on one host
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// Finished, with the exit code it answered; `status` answers 3 for a
+    /// relay that isn't running
+    Done(u8),
+    Failed(String),
+    /// Stopped part way, as another host failed with `--fail-fast`
+    Cancelled,
+    /// Not tried, as an earlier host failed with `--fail-fast`
+    Skipped,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostResult {
+    pub host: String,
+    pub outcome: Outcome,
+    /// More on how it went, such as the release a deploy installed
+    pub detail: Option<String>,
+    /// How long it ran for, if it was started
+    pub elapsed: Option<Duration>,
+    /// The relay version the host ended up with, where the command found out
+    pub version: Option<String>,
+}
+
+/// How a command that ran to the end went on one host
+#[derive(Debug, Default, PartialEq)]
+pub struct Finished {
+    /// The exit code it calls for, as `Outcome::Done`
+    pub code: u8,
+    /// As `HostResult::detail`
+    pub detail: Option<String>,
+    /// As `HostResult::version`
+    pub version: Option<String>,
+}
+
+/// What `run_all` does on each host
+pub type HostRun = Result<Finished>;
+
+/// Run `task` on each of `configs`, `parallel` at a time, starting them in
+/// order; answers how each went, in the same order. Each host's log lines
+/// are prefixed with its name. With `fail_fast` the first failure cancels
+/// the hosts still running and skips those not yet started.
+pub async fn run_all<F, Fut>(configs: &[DeploymentConfig], parallel: usize, fail_fast: bool, task: F) -> Vec<HostResult>
+where
+    F: Fn(DeploymentConfig) -> Fut,
+    Fut: Future<Output = HostRun> + Send + 'static,
+{
+    let mut results: Vec<HostResult> = configs
+        .iter()
+        .map(|config| HostResult { host: config.host.clone(), outcome: Outcome::Skipped, detail: None, elapsed: None, version: None })
+        .collect();
+    let mut started: Vec<Option<Instant>> = vec![None; configs.len()];
+    let mut running = JoinSet::new();
+    let mut next = 0;
+    let mut stopping = false;
+
+    loop {
+        while !stopping && running.len() < parallel.max(1) && next < configs.len() {
+            let config = configs[next].clone();
+            if parallel <= 1 {
+                info!("━━━ {} ━━━", config.host);
+            }
+            let span = info_span!("host", name = %config.host);
+            let index = next;
+            let run = task(config);
+            running.spawn(async move { (index, run.await) }.instrument(span));
+            started[next] = Some(Instant::now());
+            next += 1;
+        }
+
+        let Some(joined) = running.join_next().await else { break };
+        let (index, run) = match joined {
+            Ok(finished) => finished,
+            Err(e) if e.is_cancelled() => continue,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        let result = &mut results[index];
+        result.elapsed = started[index].map(|at| at.elapsed());
+        match run {
+            Ok(finished) => {
+                result.outcome = Outcome::Done(finished.code);
+                result.detail = finished.detail;
+                result.version = finished.version;
+            }
+            Err(e) => {
+                error!("❌ {}: {:#}", result.host, e);
+                result.outcome = Outcome::Failed(format!("{:#}", e));
+                if fail_fast && !stopping {
+                    stopping = true;
+                    if !running.is_empty() {
+                        info!("🛑 Cancelling the {} host(s) still running", running.len());
+                    }
+                    running.abort_all();
+                }
+            }
+        }
+        if configs.len() > 1 {
+            info!("📊 {}", progress(&results, running.len()));
+        }
+    }
+
+    // Those started and never finished were cancelled
+    for (result, started) in results.iter_mut().zip(&started) {
+        if let (Outcome::Skipped, Some(at)) = (&result.outcome, started) {
+            result.outcome = Outcome::Cancelled;
+            result.elapsed = Some(at.elapsed());
+        }
+    }
+    results
+}
+
+/// A line on how far a multi-host run has got
+fn progress(results: &[HostResult], running: usize) -> String {
+    let finished: Vec<_> = results.iter().filter(|result| matches!(result.outcome, Outcome::Done(_) | Outcome::Failed(_))).collect();
+    let failed = finished.iter().filter(|result| matches!(result.outcome, Outcome::Failed(_))).count();
+    let mut line = format!("{} of {} hosts finished", finished.len(), results.len());
+    if failed > 0 {
+        line.push_str(&format!(", {} failed", failed));
+    }
+    if running > 0 {
+        line.push_str(&format!(", {} running", running));
+    }
+    line
+}
+
+/// A table of how each host went and how long it took, for the end of a
+/// multi-host run, with the relay version each has where that's known
+pub fn summary(results: &[HostResult]) -> String {
+    let width = results.iter().map(|result| result.host.len()).max().unwrap_or(0).max("HOST".len());
+    let versions: Vec<&str> = results.iter().filter_map(|result| result.version.as_deref()).collect();
+    let version_width = versions.iter().map(|version| version.len()).max().map(|width| width.max("VERSION".len()));
+    let version_column = |version: &str| match version_width {
+        Some(version_width) => format!("{:<version_width$}  ", version, version_width = version_width),
+        None => String::new(),
+    };
+    let mut table = format!("{:<width$}  {:>7}  {}RESULT\n", "HOST", "TIME", version_column("VERSION"), width = width);
+    for result in results {
+        let outcome = match &result.outcome {
+            Outcome::Done(0) => "✅ ok".to_string(),
+            Outcome::Done(code) => format!("⚠️  exit {}", code),
+            Outcome::Failed(error) => format!("❌ {}", error),
+            Outcome::Cancelled => "🛑 cancelled".to_string(),
+            Outcome::Skipped => "⏭️  skipped".to_string(),
+        };
+        let outcome = match &result.detail {
+            Some(detail) => format!("{}: {}", outcome, detail),
+            None => outcome,
+        };
+        let elapsed = match result.elapsed {
+            Some(elapsed) => format!("{:.1}s", elapsed.as_secs_f64()),
+            None => "-".to_string(),
+        };
+        let version = version_column(result.version.as_deref().unwrap_or("-"));
+        table.push_str(&format!("{:<width$}  {:>7}  {}{}\n", result.host, elapsed, version, outcome, width = width));
+    }
+    if versions.iter().any(|version| *version != versions[0]) {
+        table.push_str("⚠️  The hosts are running different versions\n");
+    }
+    table
+}
+
+/// The exit code for the whole run: 1 if any host failed or was stopped,
+/// otherwise the first non-zero code a host answered, otherwise 0
+pub fn exit_code(results: &[HostResult]) -> u8 {
+    if results.iter().any(|result| matches!(result.outcome, Outcome::Failed(_) | Outcome::Cancelled | Outcome::Skipped)) {
+        return 1;
+    }
+    results
+        .iter()
+        .find_map(|result| match result.outcome {
+            Outcome::Done(code) if code != 0 => Some(code),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+/// Settings for the Silence app listing every deployed relay
+pub fn client_config(configs: &[DeploymentConfig]) -> String {
+    let relays: Vec<String> = configs.iter().map(|config| format!("{:?}", format!("{}:{}", config.host, config.port))).collect();
+    format!(
+        "# Relays deployed with the deploy tool, for the Silence config file\nrelay_servers = [{}]\n",
+        relays.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::tests::config;
+
+    #[test]
+    fn test_inventory_parsing() {
+        let inventory = Inventory::parse(
+            r#"
+            [[hosts]]
+            host = "relay1.example"
+
+            [[hosts]]
+            host = "relay2.example"
+            user = "deploy"
+            ssh_key = "/keys/relay2"
+            ssh_port = 2222
+            port = 9000
+            mode = "docker"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            inventory.hosts,
+            [
+                HostEntry { host: "relay1.example".to_string(), ..HostEntry::default() },
+                HostEntry {
+                    host: "relay2.example".to_string(),
+                    user: Some("deploy".to_string()),
+                    ssh_key: Some("/keys/relay2".into()),
+                    ssh_port: Some(2222),
+                    port: Some(9000),
+                    mode: Some(Mode::Docker),
+                },
+            ]
+        );
+
+        for bad in ["hosts = []", "[[hosts]]\nhost = \"\"", "[[hosts]]\nhost = \"a\"\nprot = 1", "[[hosts]]\nuser = \"a\"", "[[hosts]]\nhost = \"a\"\nmode = \"podman\""] {
+            assert!(Inventory::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_overrides_apply_to_their_host_only() {
+        let inventory = Inventory::parse(
+            "[[hosts]]\nhost = \"relay1.example\"\n\n[[hosts]]\nhost = \"relay2.example\"\nuser = \"deploy\"\nport = 9000\n",
+        )
+        .unwrap();
+        let base = config();
+        let configs = inventory.configs(&base);
+        assert_eq!((configs[0].host.as_str(), configs[0].user.as_str(), configs[0].port), ("relay1.example", "root", 8080));
+        assert_eq!((configs[1].host.as_str(), configs[1].user.as_str(), configs[1].port), ("relay2.example", "deploy", 9000));
+        assert!(configs.iter().all(|config| config.ssh_key == base.ssh_key && config.max_clients == 100));
+        assert!(configs.iter().all(|config| config.ssh_port == 22));
+
+        let entry = HostEntry { host: "h".to_string(), ssh_key: Some("~/.ssh/h".into()), ..HostEntry::default() };
+        assert!(!entry.apply(&config()).ssh_key.starts_with("~"));
+    }
+
+    #[test]
+    fn test_hosts_from_the_command_line() {
+        let hosts = ["a.example".to_string(), " b.example".to_string(), String::new()];
+        let configs = Inventory::from_hosts(&hosts).configs(&config());
+        let names: Vec<_> = configs.iter().map(|config| config.host.as_str()).collect();
+        assert_eq!(names, ["a.example", "b.example"]);
+        assert_eq!(
+            client_config(&configs),
+            "# Relays deployed with the deploy tool, for the Silence config file\n\
+             relay_servers = [\"a.example:8080\", \"b.example:8080\"]\n"
+        );
+    }
+
+    #[test]
+    fn test_summary_and_exit_code() {
+        let result = |host: &str, outcome| HostResult { host: host.to_string(), outcome, detail: None, elapsed: None, version: None };
+        let all_ok = [result("a.example", Outcome::Done(0)), result("b.example", Outcome::Done(0))];
+        assert_eq!(exit_code(&all_ok), 0);
+
+        let one_stopped = [result("a.example", Outcome::Done(0)), result("b.example", Outcome::Done(3))];
+        assert_eq!(exit_code(&one_stopped), 3);
+
+        let failed = [
+            result("a.example", Outcome::Done(3)),
+            result("long-name.example", Outcome::Failed("SSH handshake failed".to_string())),
+            result("c.example", Outcome::Skipped),
+        ];
+        assert_eq!(exit_code(&failed), 1);
+        let mut with_detail = failed.clone();
+        with_detail[0].detail = Some("release v2, uploaded 1.00 MB in 2.0s".to_string());
+        with_detail[0].elapsed = Some(Duration::from_millis(12_340));
+        with_detail[1].elapsed = Some(Duration::from_millis(800));
+        assert_eq!(
+            summary(&with_detail),
+            "HOST                  TIME  RESULT\n\
+             a.example            12.3s  ⚠️  exit 3: release v2, uploaded 1.00 MB in 2.0s\n\
+             long-name.example     0.8s  ❌ SSH handshake failed\n\
+             c.example                -  ⏭️  skipped\n"
+        );
+
+        let mut with_versions = with_detail.clone();
+        with_versions[0].version = Some("0.2.0 3fd0229e".to_string());
+        assert_eq!(
+            summary(&with_versions),
+            "HOST                  TIME  VERSION         RESULT\n\
+             a.example            12.3s  0.2.0 3fd0229e  ⚠️  exit 3: release v2, uploaded 1.00 MB in 2.0s\n\
+             long-name.example     0.8s  -               ❌ SSH handshake failed\n\
+             c.example                -  -               ⏭️  skipped\n"
+        );
+        with_versions[1].version = Some("0.1.0".to_string());
+        assert!(summary(&with_versions).ends_with("\n⚠️  The hosts are running different versions\n"));
+        with_versions[1].version = with_versions[0].version.clone();
+        assert!(!summary(&with_versions).contains("different"));
+        assert_eq!(exit_code(&[result("a.example", Outcome::Done(0)), result("b.example", Outcome::Cancelled)]), 1);
+    }
+
+    fn configs(hosts: &[&str]) -> Vec<DeploymentConfig> {
+        hosts.iter().map(|host| DeploymentConfig { host: host.to_string(), ..config() }).collect()
+    }
+
+    /// Stands in for deploying to a host: takes as long as the host's name
+    /// says in seconds, failing for those named `bad*`
+    async fn deploy(config: DeploymentConfig) -> HostRun {
+        let seconds: u64 = config.host.trim_start_matches(char::is_alphabetic).parse().unwrap();
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+        if config.host.starts_with("bad") {
+            return Err(anyhow!("SSH handshake failed"));
+        }
+        Ok(Finished { detail: Some(format!("release on {}", config.host)), ..Finished::default() })
+    }
+
+    fn outcomes(results: &[HostResult]) -> Vec<(&str, Outcome, Option<u64>)> {
+        results.iter().map(|result| (result.host.as_str(), result.outcome.clone(), result.elapsed.map(|elapsed| elapsed.as_secs()))).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hosts_run_in_parallel() {
+        let hosts = configs(&["a3", "bad1", "c2", "d5"]);
+        let started = Instant::now();
+        let results = run_all(&hosts, 2, false, deploy).await;
+        // a3 and bad1 start together; c2 takes bad1's place, d5 then c2's
+        assert_eq!(started.elapsed().as_secs(), 8);
+        assert_eq!(
+            outcomes(&results),
+            [
+                ("a3", Outcome::Done(0), Some(3)),
+                ("bad1", Outcome::Failed("SSH handshake failed".to_string()), Some(1)),
+                ("c2", Outcome::Done(0), Some(2)),
+                ("d5", Outcome::Done(0), Some(5)),
+            ]
+        );
+        assert_eq!(results[3].detail.as_deref(), Some("release on d5"));
+        assert_eq!(exit_code(&results), 1);
+
+        let started = Instant::now();
+        let all_at_once = run_all(&hosts, 10, false, deploy).await;
+        assert_eq!(started.elapsed().as_secs(), 5);
+        assert_eq!(outcomes(&all_at_once), outcomes(&results));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fail_fast_cancels_the_rest() {
+        let hosts = configs(&["a3", "bad1", "c2", "d5"]);
+        let started = Instant::now();
+        let results = run_all(&hosts, 2, true, deploy).await;
+        assert_eq!(started.elapsed().as_secs(), 1);
+        assert_eq!(
+            outcomes(&results),
+            [
+                ("a3", Outcome::Cancelled, Some(1)),
+                ("bad1", Outcome::Failed("SSH handshake failed".to_string()), Some(1)),
+                ("c2", Outcome::Skipped, None),
+                ("d5", Outcome::Skipped, None),
+            ]
+        );
+
+        // One at a time, as without --parallel, those after the failure are skipped
+        let results = run_all(&configs(&["a1", "bad1", "c1"]), 1, true, deploy).await;
+        let outcomes: Vec<_> = results.into_iter().map(|result| result.outcome).collect();
+        assert_eq!(outcomes, [Outcome::Done(0), Outcome::Failed("SSH handshake failed".to_string()), Outcome::Skipped]);
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::io::Write;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+use crate::ssh::Prompt;
+
+/// What to do about the server's host key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Check it against known_hosts, asking before trusting a new host
+    #[default]
+    Ask,
+    /// Check it, trusting and saving a new host without asking
+    AcceptNew,
+    /// Don't check it at all
+    Off,
+}
+
+/// How the server's host key compares with known_hosts
+#[derive(Debug, PartialEq)]
+pub enum HostKeyCheck {
+    Known,
+    Unknown,
+    /// known_hosts has other keys for the host, with these fingerprints
+    Changed { known: Vec<String> },
+}
+
+/// `SHA256:...` as `ssh-keygen -l` shows a key
+pub fn fingerprint(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest))
+}
+
+/// Compare `key` with the known_hosts file `text`. Lines are read one at a
+/// time so one libssh2 can't parse, such as a `@cert-authority` line,
+/// doesn't hide the rest; hashed hostnames are matched as usual.
+pub fn check(text: &str, host: &str, port: u16, key: &[u8]) -> Result<HostKeyCheck> {
+    let session = Session::new().context("Failed to create SSH session")?;
+    let mut changed = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut known_hosts = session.known_hosts().context("Failed to read known hosts")?;
+        if known_hosts.read_str(line, KnownHostFileKind::OpenSSH).is_err() {
+            debug!("Skipping known_hosts line: {}", line);
+            continue;
+        }
+        match known_hosts.check_port(host, port, key) {
+            CheckResult::Match => return Ok(HostKeyCheck::Known),
+            CheckResult::Mismatch => {
+                let entry = known_hosts.hosts()?.into_iter().next();
+                let known = entry
+                    .and_then(|entry| base64::engine::general_purpose::STANDARD.decode(entry.key()).ok())
+                    .map(|key| fingerprint(&key))
+                    .unwrap_or_else(|| "unreadable key".to_string());
+                changed.push(known);
+            }
+            CheckResult::NotFound | CheckResult::Failure => {}
+        }
+    }
+
+    match changed.is_empty() {
+        true => Ok(HostKeyCheck::Unknown),
+        false => Ok(HostKeyCheck::Changed { known: changed }),
+    }
+}
+
+/// Whether to go ahead and connect, saving the key to known_hosts if so
+/// and `Ok(true)`: a changed key always stops, a new one is trusted if the
+/// policy or the person at the prompt says so
+pub fn decide(
+    check: &HostKeyCheck,
+    policy: HostKeyPolicy,
+    host: &str,
+    fingerprint: &str,
+    prompt: &mut dyn Prompt,
+) -> Result<bool> {
+    match check {
+        HostKeyCheck::Known => Ok(false),
+        HostKeyCheck::Changed { known } => Err(anyhow!(
+            "\n\
+             @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+             @    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\n\
+             @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+             The host key of {host} is not the one in known_hosts. Someone may be\n\
+             intercepting the connection, or the server was reinstalled.\n\
+             Server's key:  {fingerprint}\n\
+             Known key:     {known}\n\
+             Check with the server's administrator, then remove the old entry with\n\
+             `ssh-keygen -R {host}` before deploying again.",
+            host = host,
+            fingerprint = fingerprint,
+            known = known.join(", ")
+        )),
+        HostKeyCheck::Unknown => match policy {
+            HostKeyPolicy::AcceptNew => {
+                info!("Trusting new host {} with key {}", host, fingerprint);
+                Ok(true)
+            }
+            HostKeyPolicy::Ask if prompt.accept_host_key(host, fingerprint)? => Ok(true),
+            HostKeyPolicy::Ask => Err(anyhow!(
+                "Host key of {} ({}) not trusted; accept it, or pass --accept-new-hostkey",
+                host,
+                fingerprint
+            )),
+            HostKeyPolicy::Off => Ok(false),
+        },
+    }
+}
+
+/// The known_hosts line for `key`, named `[host]:port` off port 22 as
+/// OpenSSH names it
+pub fn entry(host: &str, port: u16, key: &[u8]) -> Result<String> {
+    // The key blob starts with its type as a length-prefixed string
+    let key_type = key
+        .split_first_chunk::<4>()
+        .and_then(|(length, rest)| rest.get(..u32::from_be_bytes(*length) as usize))
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .ok_or_else(|| anyhow!("Host key of {} is malformed", host))?;
+    let name = match port {
+        22 => host.to_string(),
+        port => format!("[{}]:{}", host, port),
+    };
+    Ok(format!("{} {} {}\n", name, key_type, base64::engine::general_purpose::STANDARD.encode(key)))
+}
+
+/// Check the host key of `session`, just after its handshake, against the
+/// known_hosts file at `path`, as `policy` has it
+pub fn verify(
+    session: &Session,
+    path: &Path,
+    policy: HostKeyPolicy,
+    host: &str,
+    port: u16,
+    prompt: &mut dyn Prompt,
+) -> Result<()> {
+    if policy == HostKeyPolicy::Off {
+        warn!("⚠️  Not checking the host key of {} (--no-hostkey-check); the connection could be intercepted", host);
+        return Ok(());
+    }
+
+    let (key, _) = session.host_key().ok_or_else(|| anyhow!("{} sent no host key", host))?;
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let fingerprint = fingerprint(key);
+    if decide(&check(&text, host, port, key)?, policy, host, &fingerprint, prompt)? {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let separator = if text.is_empty() || text.ends_with('\n') { "" } else { "\n" };
+        write!(file, "{}{}", separator, entry(host, port, key)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Added {} to {}", host, path.display());
+    }
+    debug!("Host key of {} is {}", host, fingerprint);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/known_hosts");
+
+    /// relay.example's key on port 22, its key on port 2222, hashed
+    /// other.example's, and one no host has
+    const KEY_22: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIDdyLWfIPYdtkY1AVyuca9+CNcWBDSMext32bJNYLYDk";
+    const KEY_2222: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIGkq9H9k0L437fM5w3IT5kg6KyZ33yPuUMzl9j2cwdKH";
+    const KEY_HASHED: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIMIhr65N5iyFd3Tz9gSU0HF7f3guKU5obnBDXorbX+t4";
+    const KEY_NEW: &str = "AAAAC3NzaC1lZDI1NTE5AAAAINjVNbMQaOcSVgkU3SwRWD5ewJV6TABdLIXuuQlyc5Zg";
+
+    fn key(base64: &str) -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD.decode(base64).unwrap()
+    }
+
+    /// Accepts or refuses every new host, noting which it was asked about
+    struct Answer(bool, Vec<String>);
+
+    impl Prompt for Answer {
+        fn passphrase(&mut self, _key: &Path) -> Result<String> {
+            unreachable!()
+        }
+
+        fn password(&mut self, _user: &str, _host: &str) -> Result<String> {
+            unreachable!()
+        }
+
+        fn accept_host_key(&mut self, host: &str, _fingerprint: &str) -> Result<bool> {
+            self.1.push(host.to_string());
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_as_ssh_keygen_shows_it() {
+        assert_eq!(fingerprint(&key(KEY_22)), "SHA256:mispIaLdc8AUbCyrfkz2NcknDpCdmkkeLqoU0p0DacQ");
+    }
+
+    #[test]
+    fn test_check_against_the_fixture() {
+        assert_eq!(check(FIXTURE, "relay.example", 22, &key(KEY_22)).unwrap(), HostKeyCheck::Known);
+        assert_eq!(check(FIXTURE, "relay.example", 2222, &key(KEY_2222)).unwrap(), HostKeyCheck::Known);
+        assert_eq!(check(FIXTURE, "other.example", 22, &key(KEY_HASHED)).unwrap(), HostKeyCheck::Known);
+        assert_eq!(check(FIXTURE, "new.example", 22, &key(KEY_NEW)).unwrap(), HostKeyCheck::Unknown);
+        assert_eq!(check("", "relay.example", 22, &key(KEY_22)).unwrap(), HostKeyCheck::Unknown);
+
+        // The port 2222 key on port 22 is a changed key
+        assert_eq!(
+            check(FIXTURE, "relay.example", 22, &key(KEY_2222)).unwrap(),
+            HostKeyCheck::Changed { known: vec!["SHA256:mispIaLdc8AUbCyrfkz2NcknDpCdmkkeLqoU0p0DacQ".to_string()] }
+        );
+        assert!(matches!(check(FIXTURE, "other.example", 22, &key(KEY_NEW)).unwrap(), HostKeyCheck::Changed { .. }));
+    }
+
+    #[test]
+    fn test_decisions() {
+        let changed = HostKeyCheck::Changed { known: vec!["SHA256:old".to_string()] };
+        let mut yes = Answer(true, Vec::new());
+        let mut no = Answer(false, Vec::new());
+
+        assert!(!decide(&HostKeyCheck::Known, HostKeyPolicy::Ask, "h", "SHA256:new", &mut no).unwrap());
+        for policy in [HostKeyPolicy::Ask, HostKeyPolicy::AcceptNew] {
+            let error = decide(&changed, policy, "h", "SHA256:new", &mut yes).unwrap_err().to_string();
+            assert!(error.contains("SHA256:new") && error.contains("SHA256:old"), "{}", error);
+        }
+
+        assert!(decide(&HostKeyCheck::Unknown, HostKeyPolicy::AcceptNew, "h", "SHA256:new", &mut no).unwrap());
+        assert!(decide(&HostKeyCheck::Unknown, HostKeyPolicy::Ask, "h", "SHA256:new", &mut yes).unwrap());
+        let refused = decide(&HostKeyCheck::Unknown, HostKeyPolicy::Ask, "h", "SHA256:new", &mut no);
+        assert!(refused.unwrap_err().to_string().contains("--accept-new-hostkey"));
+        assert_eq!((yes.1.len(), no.1.len()), (1, 1), "asked only about the new host");
+    }
+
+    #[test]
+    fn test_saved_entries_are_found_again() {
+        let port_22 = entry("new.example", 22, &key(KEY_NEW)).unwrap();
+        assert_eq!(port_22, format!("new.example ssh-ed25519 {}\n", KEY_NEW));
+        let port_2222 = entry("new.example", 2222, &key(KEY_NEW)).unwrap();
+        assert!(port_2222.starts_with("[new.example]:2222 ssh-ed25519 "));
+
+        let text = format!("{}{}{}", FIXTURE, port_22, port_2222);
+        assert_eq!(check(&text, "new.example", 22, &key(KEY_NEW)).unwrap(), HostKeyCheck::Known);
+        assert_eq!(check(&text, "new.example", 2222, &key(KEY_NEW)).unwrap(), HostKeyCheck::Known);
+        assert!(entry("new.example", 22, b"\0\0").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// How `logs` asks journalctl for lines: stamped in UTC with the year, so
+/// `--since` can compare them as text
+pub const JOURNAL_FORMAT: &str = "--utc -o short-iso";
+
+/// Which log lines `logs` shows, from `--since` and `--grep`. Applied here
+/// rather than by journalctl, to the lines `--lines` fetched and to those
+/// followed after them.
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    /// `YYYY-MM-DDTHH:MM:SS` in UTC, as the lines start
+    since: Option<String>,
+    grep: Option<Regex>,
+}
+
+/// `--since` as a time: a duration such as `15m` back from now, or a UTC
+/// time such as `2026-10-16 12:00:00`
+pub fn parse_since(value: &str) -> std::result::Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(value) {
+        return SystemTime::now().checked_sub(ago).ok_or_else(|| format!("{} is too long ago", value));
+    }
+    humantime::parse_rfc3339_weak(value)
+        .map_err(|_| "use a duration such as 15m or 2h, or a UTC time such as \"2026-10-16 12:00:00\"".to_string())
+}
+
+/// `--grep`, checked to be a pattern `LogFilter` can use
+pub fn parse_grep(pattern: &str) -> std::result::Result<String, String> {
+    Regex::new(pattern).map(|_| pattern.to_string()).map_err(|e| e.to_string())
+}
+
+/// Whether `text` starts `YYYY-MM-DDTHH:MM:SS`
+fn is_timestamp(text: &str) -> bool {
+    text.len() == 19
+        && text.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            10 => b == b'T',
+            13 | 16 => b == b':',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+impl LogFilter {
+    pub fn new(since: Option<SystemTime>, grep: Option<&str>) -> Result<Self> {
+        let since = since.map(|since| humantime::format_rfc3339_seconds(since).to_string()[..19].to_string());
+        let grep = grep.map(Regex::new).transpose().context("Invalid --grep pattern")?;
+        Ok(Self { since, grep })
+    }
+
+    /// Lines without a time of their own, such as journalctl's `-- Boot`
+    /// markers, pass `--since`
+    pub fn matches(&self, line: &str) -> bool {
+        if let (Some(since), Some(stamp)) = (&self.since, line.get(..19)) {
+            if is_timestamp(stamp) && stamp < since.as_str() {
+                return false;
+            }
+        }
+        self.grep.as_ref().is_none_or(|grep| grep.is_match(line))
+    }
+
+    /// The lines of `text` this lets through
+    pub fn apply(&self, text: &str) -> String {
+        text.lines().filter(|line| self.matches(line)).map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+/// Passes what's written to it on to `out` a whole line at a time, leaving
+/// out the lines `filter` doesn't match and the carriage returns a terminal
+/// puts before each newline
+pub struct FilteredLines<'a> {
+    filter: &'a LogFilter,
+    out: &'a mut (dyn Write + Send),
+    /// The start of a line whose newline hasn't come yet
+    partial: Vec<u8>,
+}
+
+impl<'a> FilteredLines<'a> {
+    pub fn new(filter: &'a LogFilter, out: &'a mut (dyn Write + Send)) -> Self {
+        Self { filter, out, partial: Vec::new() }
+    }
+
+    fn emit(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if self.filter.matches(line) {
+            writeln!(self.out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Pass on a last line that never got its newline
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.emit(&line)?;
+        }
+        self.out.flush()
+    }
+}
+
+impl Write for FilteredLines<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.emit(&line[..end])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOURNAL: &str = "\
+2025-06-02T11:59:58+00:00 relay silence-relay[812]: Client joined session abc
+-- Boot 6f1c2e --
+2025-06-02T12:00:00+00:00 relay silence-relay[812]: Relay listening on 0.0.0.0:8080
+2025-06-02T12:00:05+00:00 relay silence-relay[812]: ERROR session xyz: frame too large
+";
+
+    fn since(value: &str) -> Option<SystemTime> {
+        Some(parse_since(value).unwrap())
+    }
+
+    #[test]
+    fn test_filters() {
+        let everything = LogFilter::default();
+        assert_eq!(everything.apply(JOURNAL), JOURNAL);
+
+        let recent = LogFilter::new(since("2025-06-02 12:00:00"), None).unwrap();
+        assert_eq!(recent.apply(JOURNAL).lines().collect::<Vec<_>>(), [
+            "-- Boot 6f1c2e --",
+            "2025-06-02T12:00:00+00:00 relay silence-relay[812]: Relay listening on 0.0.0.0:8080",
+            "2025-06-02T12:00:05+00:00 relay silence-relay[812]: ERROR session xyz: frame too large",
+        ]);
+
+        let errors = LogFilter::new(None, Some("ERROR|abc")).unwrap();
+        assert_eq!(errors.apply(JOURNAL).lines().count(), 2);
+        let both = LogFilter::new(since("2025-06-02T12:00:01Z"), Some("session")).unwrap();
+        assert_eq!(both.apply(JOURNAL), "2025-06-02T12:00:05+00:00 relay silence-relay[812]: ERROR session xyz: frame too large\n");
+
+        assert!(LogFilter::new(since("15m"), None).unwrap().apply(JOURNAL).starts_with("-- Boot"), "all older than 15m");
+        assert!(parse_since("yesterday-ish").is_err());
+        assert!(parse_grep("(unclosed").is_err());
+        assert!(LogFilter::new(None, Some("(unclosed")).is_err());
+    }
+
+    #[test]
+    fn test_lines_split_across_reads() {
+        // As a terminal sends journalctl's output, cut wherever the reads fell
+        let stream = JOURNAL.replace('\n', "\r\n");
+        let filter = LogFilter::new(None, Some("silence-relay")).unwrap();
+        for chunk_size in [1, 7, 64, stream.len()] {
+            let mut out = Vec::new();
+            let mut lines = FilteredLines::new(&filter, &mut out);
+            for chunk in stream.as_bytes().chunks(chunk_size) {
+                lines.write_all(chunk).unwrap();
+            }
+            lines.finish().unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), filter.apply(JOURNAL), "{} bytes at a time", chunk_size);
+        }
+
+        let mut out = Vec::new();
+        let mut lines = FilteredLines::new(&filter, &mut out);
+        lines.write_all(b"2025-06-02T12:00:06+00:00 relay silence-relay[812]: no newl").unwrap();
+        lines.flush().unwrap();
+        lines.write_all(b"ine yet").unwrap();
+        lines.finish().unwrap();
+        assert_eq!(out, b"2025-06-02T12:00:06+00:00 relay silence-relay[812]: no newline yet\n");
+    }
+}
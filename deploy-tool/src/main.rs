@@ -1,37 +1,308 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use std::path::PathBuf;
-use tracing::info;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
 
 mod ssh;
 mod builder;
+mod checksum;
+mod config;
+mod connect;
 mod packager;
 mod deployer;
+mod docker;
+mod firewall;
+mod hosts;
+mod known_hosts;
+mod logs;
+mod plan;
+mod progress;
+mod retry;
+mod smoke;
+mod version;
+mod worker;
 
 use ssh::SshClient;
-use builder::Builder;
-use packager::Packager;
-use deployer::Deployer;
+use builder::{BinaryInfo, BuildOptions, Builder, Checks};
+use connect::JumpHost;
+use packager::{Package, Packager};
+use deployer::{Deployer, Mode};
+use hosts::{Finished, HostEntry, HostRun, Inventory};
+use known_hosts::HostKeyPolicy;
+use logs::LogFilter;
+use plan::Plan;
+use retry::{Reconnect, Retrying, RetryPolicy};
+use version::Version;
+use ssh::Remote;
 
-#[derive(Parser, Clone)]
+#[derive(Parser, Clone, Debug)]
 #[command(
     name = "deploy",
     about = "Rust deployment tool for Silence Relay Server on Cherry Servers",
     version = "0.1.0"
 )]
-struct Args {
+struct Cli {
+    #[command(flatten)]
+    ssh: SshArgs,
+
+    /// Enable verbose logging
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// With several hosts, stop at the first that fails rather than carry on,
+    /// cancelling those still running
+    #[arg(long, global = true)]
+    fail_fast: bool,
+
+    /// With several hosts, how many to run on at once
+    #[arg(long, global = true, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    parallel: u32,
+
+    /// Build and package as usual, but only list what would be run and
+    /// uploaded on the server
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// How many times to try an SSH operation that fails for a network
+    /// reason, reconnecting in between
+    #[arg(long, global = true, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+    ssh_attempts: u32,
+
+    /// Seconds to wait before the first retry, doubling for each after it
+    #[arg(long, global = true, value_name = "SECONDS", default_value = "1")]
+    retry_delay: u64,
+
+    /// Settings file; options given here or in the environment win over it
+    #[arg(long, global = true, value_name = "PATH", default_value = config::DEFAULT_PATH)]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+
+    /// The relay's settings for commands other than `deploy`, which has its
+    /// own: the defaults, and whatever the settings file says
+    #[arg(skip)]
+    relay: RelayArgs,
+}
+
+/// Where to reach the server and how the relay runs there, for every
+/// subcommand
+#[derive(clap::Args, Clone, Debug, PartialEq)]
+struct SshArgs {
     /// Cherry Server hostname
-    #[arg(long, env = "CHERRY_HOST", default_value = "your-server.cherryservers.net")]
+    #[arg(long, global = true, env = "CHERRY_HOST", default_value = "your-server.cherryservers.net")]
     host: String,
 
     /// SSH username
-    #[arg(long, env = "CHERRY_USER", default_value = "root")]
+    #[arg(long, global = true, env = "CHERRY_USER", default_value = "root")]
     user: String,
 
     /// SSH private key path
-    #[arg(long, env = "SSH_KEY", default_value = "~/.ssh/id_rsa")]
+    #[arg(long, global = true, env = "SSH_KEY", default_value = "~/.ssh/id_rsa")]
     ssh_key: PathBuf,
 
+    /// Port the server's SSH listens on
+    #[arg(long, global = true, env = "SSH_PORT", default_value_t = ssh::SSH_PORT)]
+    ssh_port: u16,
+
+    /// Bastion to reach the server through, as [user@]host[:port]; logged
+    /// in to as `--user` with the same keys unless it names a user
+    #[arg(long, global = true, value_name = "HOST", value_parser = JumpHost::parse)]
+    jump_host: Option<JumpHost>,
+
+    /// Ask for a password when ssh-agent and the key file don't get in
+    #[arg(long, global = true)]
+    password_auth: bool,
+
+    /// Run the relay as a systemd service, or in a container under Docker
+    /// Compose
+    #[arg(long, global = true, env = "DEPLOY_MODE", value_enum, default_value_t = Mode::Systemd)]
+    mode: Mode,
+
+    /// Several hostnames, comma separated, to run on one after another
+    /// instead of `--host`
+    #[arg(long, global = true, value_delimiter = ',', conflicts_with = "inventory")]
+    hosts: Vec<String>,
+
+    /// TOML file listing the hosts to run on, each with its own user, SSH
+    /// key and relay port if need be
+    #[arg(long, global = true, value_name = "PATH")]
+    inventory: Option<PathBuf>,
+
+    /// Where the keys of known servers are kept
+    #[arg(long, global = true, value_name = "PATH", default_value = "~/.ssh/known_hosts")]
+    known_hosts: PathBuf,
+
+    /// Trust and save the key of a server not in known_hosts without asking,
+    /// as with no terminal to ask on; a changed key still stops the run
+    #[arg(long, global = true)]
+    accept_new_hostkey: bool,
+
+    /// Don't check the server's host key at all, leaving the connection open
+    /// to interception
+    #[arg(long, global = true, conflicts_with = "accept_new_hostkey")]
+    no_hostkey_check: bool,
+
+    /// The settings file's `[[hosts]]`, unless the command line names hosts
+    #[arg(skip)]
+    config_hosts: Vec<HostEntry>,
+}
+
+#[derive(Subcommand, Clone, Debug, PartialEq)]
+enum Command {
+    /// Build the relay, upload it and (re)start the service
+    Deploy(DeployArgs),
+    /// Show whether the service is running and its version; exits 3 when it isn't
+    Status,
+    /// Show the service's recent log
+    Logs {
+        /// How many lines to show
+        #[arg(short = 'n', long, default_value = "50")]
+        lines: u32,
+
+        /// Keep printing new lines as they're logged, until Ctrl+C
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only lines logged since then: a duration back from now, such as
+        /// 15m, or a UTC time such as "2026-10-16 12:00:00"
+        #[arg(long, value_parser = logs::parse_since)]
+        since: Option<SystemTime>,
+
+        /// Only lines matching this regular expression
+        #[arg(long, value_name = "PATTERN", value_parser = logs::parse_grep)]
+        grep: Option<String>,
+    },
+    /// Restart the service
+    Restart,
+    /// Switch the service back to an earlier release and restart it
+    Rollback {
+        /// The release to go back to, rather than the one before the current
+        #[arg(long, value_name = "RELEASE")]
+        to: Option<String>,
+    },
+    /// List the releases on the server, marking the current one
+    Releases,
+    /// Stop the service
+    Stop,
+    /// Remove the service and binary
+    Uninstall {
+        /// Also remove the relay's configuration and the `relay` user
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Check the relay passes a message between two clients, connecting
+    /// to it directly rather than over SSH
+    SmokeTest {
+        /// Relay port, rather than the inventory's or $RELAY_PORT (8080)
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Write a deploy.toml, or the `--config` file, listing every setting
+    Init {
+        /// Replace the file if it's already there
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Args, Clone, Debug, PartialEq)]
+struct DeployArgs {
+    #[command(flatten)]
+    relay: RelayArgs,
+
+    /// Skip building and use existing binary
+    #[arg(long)]
+    skip_build: bool,
+
+    /// Remove earlier build artifacts first, for a build from scratch
+    #[arg(long, conflicts_with = "skip_build")]
+    clean: bool,
+
+    /// Run the relay's tests in release mode first, deploying nothing if
+    /// any fail
+    #[arg(long, conflicts_with = "skip_build")]
+    test: bool,
+
+    /// Lint the relay with clippy first, deploying nothing if it warns
+    #[arg(long, conflicts_with = "skip_build")]
+    clippy: bool,
+
+    /// Have --test and --clippy check the whole workspace, not just the relay
+    #[arg(long)]
+    check_workspace: bool,
+
+    /// Target triple to build for, such as x86_64-unknown-linux-musl for a
+    /// static binary that runs whatever the server's glibc
+    #[arg(long, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Build with `cross`, in a container with the target's toolchain
+    #[arg(long, requires = "target")]
+    use_cross: bool,
+
+    /// The relay's cargo features to turn on, comma separated, such as
+    /// tls,metrics
+    #[arg(long, value_delimiter = ',', value_name = "FEATURES")]
+    features: Vec<String>,
+
+    /// Cargo profile to build with, such as one optimized for size; release
+    /// by default
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Where cargo builds, such as a workspace's shared target directory;
+    /// relay-server/target by default
+    #[arg(long, value_name = "PATH")]
+    target_dir: Option<PathBuf>,
+
+    /// The relay's Cargo.toml; relay-server/Cargo.toml by default
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+
+    /// Also write the Silence app's relay list for the deployed hosts here
+    #[arg(long, value_name = "PATH")]
+    client_config: Option<PathBuf>,
+
+    /// Name for the new release, such as its version; the server's UTC
+    /// time (YYYYMMDDHHMMSS) by default
+    #[arg(long, value_parser = parse_release_name)]
+    release: Option<String>,
+
+    /// How many releases to keep on the server for rolling back to
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u16).range(1..))]
+    keep_releases: u16,
+
+    /// Don't check the new relay passes messages once it's running
+    #[arg(long)]
+    no_smoke_test: bool,
+
+    /// Go back to the previous release if the new one fails its smoke test
+    #[arg(long, conflicts_with = "no_smoke_test")]
+    rollback_on_failure: bool,
+
+    /// Open the relay's port, and its health port if set, in ufw or
+    /// firewalld if the server runs either
+    #[arg(long)]
+    configure_firewall: bool,
+}
+
+/// Release names become directory names on the server and go into shell
+/// commands, so they're kept to a safe alphabet
+fn parse_release_name(name: &str) -> std::result::Result<String, String> {
+    let safe = name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if name.is_empty() || !safe || name.starts_with('.') {
+        return Err("use letters, digits, '.', '_' and '-', not starting with '.'".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// How the deployed relay is set up
+#[derive(Parser, Clone, Debug, PartialEq)]
+struct RelayArgs {
     /// Relay server port
     #[arg(long, env = "RELAY_PORT", default_value = "8080")]
     port: u16,
@@ -48,36 +319,80 @@ struct Args {
     #[arg(long, env = "BIND_ADDRESS", default_value = "0.0.0.0")]
     bind_address: String,
 
-    /// Skip building and use existing binary
-    #[arg(long)]
-    skip_build: bool,
+    /// Port for the relay's HTTP health endpoint, off unless given
+    #[arg(long, env = "HEALTH_PORT")]
+    health_port: Option<u16>,
+}
 
-    /// Enable verbose logging
-    #[arg(short, long)]
-    verbose: bool,
+impl Default for RelayArgs {
+    /// The settings `deploy` would use without flags, environment included
+    fn default() -> Self {
+        Self::parse_from(["deploy"])
+    }
 }
 
 pub struct DeploymentConfig {
     pub host: String,
     pub user: String,
     pub ssh_key: PathBuf,
+    pub known_hosts: PathBuf,
+    pub host_key_policy: HostKeyPolicy,
+    /// The server's SSH port, as opposed to `port`, the relay's
+    pub ssh_port: u16,
+    pub jump_host: Option<JumpHost>,
     pub port: u16,
     pub max_clients: u32,
     pub max_message_size: u32,
     pub bind_address: String,
+    /// The relay's health endpoint port, if it has one
+    pub health_port: Option<u16>,
+    pub password_auth: bool,
+    pub mode: Mode,
 }
 
-impl From<Args> for DeploymentConfig {
-    fn from(args: Args) -> Self {
+impl DeploymentConfig {
+    fn new(ssh: &SshArgs, relay: &RelayArgs) -> Self {
         Self {
-            host: args.host,
-            user: args.user,
-            ssh_key: expand_home_path(args.ssh_key),
-            port: args.port,
-            max_clients: args.max_clients,
-            max_message_size: args.max_message_size,
-            bind_address: args.bind_address,
+            host: ssh.host.clone(),
+            user: ssh.user.clone(),
+            ssh_key: expand_home_path(ssh.ssh_key.clone()),
+            ssh_port: ssh.ssh_port,
+            jump_host: ssh.jump_host.clone(),
+            port: relay.port,
+            max_clients: relay.max_clients,
+            max_message_size: relay.max_message_size,
+            bind_address: relay.bind_address.clone(),
+            health_port: relay.health_port,
+            password_auth: ssh.password_auth,
+            mode: ssh.mode,
+            known_hosts: expand_home_path(ssh.known_hosts.clone()),
+            host_key_policy: if ssh.no_hostkey_check {
+                HostKeyPolicy::Off
+            } else if ssh.accept_new_hostkey {
+                HostKeyPolicy::AcceptNew
+            } else {
+                HostKeyPolicy::Ask
+            },
+        }
+    }
+
+    /// One configuration per host to run on: the inventory's, else
+    /// `--hosts`, else just `--host`
+    fn for_hosts(ssh: &SshArgs, relay: &RelayArgs) -> Result<Vec<Self>> {
+        let base = Self::new(ssh, relay);
+        let inventory = if let Some(path) = &ssh.inventory {
+            Inventory::load(path)?
+        } else if !ssh.hosts.is_empty() {
+            Inventory::from_hosts(&ssh.hosts)
+        } else if !ssh.config_hosts.is_empty() {
+            Inventory { hosts: ssh.config_hosts.clone() }
+        } else {
+            return Ok(vec![base]);
+        };
+        if inventory.hosts.is_empty() {
+            anyhow::bail!("No hosts given");
         }
+        Ok(inventory.configs(&base))
     }
 }
 
@@ -94,52 +409,462 @@ fn expand_home_path(path: PathBuf) -> PathBuf {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let mut cli = match Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => e.exit(),
+    };
 
     // Initialize logging
-    let log_level = if args.verbose { "debug" } else { "info" };
+    let log_level = if cli.verbose { "debug" } else { "info" };
     tracing_subscriber::fmt()
         .with_env_filter(log_level)
         .with_target(false)
         .init();
 
-    let config = DeploymentConfig::from(args.clone());
+    if let Err(e) = config::resolve(&mut cli, &matches) {
+        eprintln!("❌ {:#}", e);
+        return ExitCode::FAILURE;
+    }
 
-    info!("🍒 Starting deployment to Cherry Servers...");
-    info!("Target: {}@{}", config.user, config.host);
-    info!("Port: {}", config.port);
+    match run(cli).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("❌ {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    // Step 1: Build the relay server binary (unless skipped)
-    let binary_path = if !args.skip_build {
+async fn run(cli: Cli) -> Result<ExitCode> {
+    if let Command::Init { force } = cli.command {
+        config::write_template(&cli.config, force)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let relay = match &cli.command {
+        Command::Deploy(deploy) => deploy.relay.clone(),
+        _ => cli.relay.clone(),
+    };
+    let configs = DeploymentConfig::for_hosts(&cli.ssh, &relay)?;
+    if configs.len() > 1 && matches!(cli.command, Command::Logs { follow: true, .. }) {
+        anyhow::bail!("logs --follow works on one host at a time");
+    }
+
+    // Built once before connecting anywhere, so a failing build doesn't hold
+    // a session open
+    let binary = match &cli.command {
+        Command::Deploy(deploy) => {
+            info!("🍒 Starting deployment to Cherry Servers...");
+            let (path, version) = build_binary(deploy).await?;
+            let info = BinaryInfo::of(&path).await?;
+            Some(Binary { path, info, version })
+        }
+        _ => None,
+    };
+
+    if let [config] = configs.as_slice() {
+        let finished = run_on_host(&cli.command, config, binary.as_ref(), &cli).await?;
+        write_client_config(&cli.command, &configs).await?;
+        return Ok(ExitCode::from(finished.code));
+    }
+
+    // Shared by every host, however many run at once
+    let cli = Arc::new(cli);
+    let binary = binary.map(Arc::new);
+    let results = hosts::run_all(&configs, cli.parallel as usize, cli.fail_fast, |config| {
+        let cli = cli.clone();
+        let binary = binary.clone();
+        async move { run_on_host(&cli.command, &config, binary.as_deref(), &cli).await }
+    })
+    .await;
+
+    println!();
+    print!("{}", hosts::summary(&results));
+    write_client_config(&cli.command, &configs).await?;
+    Ok(ExitCode::from(hosts::exit_code(&results)))
+}
+
+/// The relay binary a deploy built
+struct Binary {
+    path: PathBuf,
+    info: BinaryInfo,
+    /// What it was built from
+    version: Version,
+}
+
+/// Run `command` on one host, answering the exit code it calls for and a
+/// word on how it went for the summary; a dry run prints the plan instead,
+/// and leaves the package for a look inside
+async fn run_on_host(
+    command: &Command,
+    config: &DeploymentConfig,
+    binary: Option<&Binary>,
+    cli: &Cli,
+) -> HostRun {
+    let dry_run = cli.dry_run;
+    if let Command::SmokeTest { port } = command {
+        return smoke_test(config, *port, dry_run).await;
+    }
+
+    let package = match command {
+        Command::Deploy(_) => {
+            info!("Target: {}@{}", config.user, config.host);
+            info!("Port: {}", config.port);
+            let binary = binary.expect("built for deploy");
+            Some((package(config, binary).await?, binary))
+        }
+        _ => None,
+    };
+
+    if dry_run {
+        let mut plan = Plan::new(format!("{}@{}", config.user, config.host));
+        let code = run_steps(command, config, &mut plan, package.as_ref(), true).await;
+        print!("{}", plan);
+        if let Some((package, _)) = package {
+            println!("Package left at {}", package.path.display());
+        }
+        return code;
+    }
+
+    let policy = RetryPolicy { attempts: cli.ssh_attempts, delay: Duration::from_secs(cli.retry_delay) };
+    let mut remote = Retrying::new(Connect { config, alongside_others: cli.parallel > 1 }, policy);
+    remote.connect().await?;
+    let finished = run_steps(command, config, &mut remote, package.as_ref(), false).await?;
+    if let Some(ssh_client) = remote.into_remote() {
+        ssh_client.disconnect().await?;
+    }
+    if let Some((package, _)) = package {
+        Packager::new(config).cleanup_package(&package.path).await?;
+    }
+    Ok(finished)
+}
+
+/// Opens the SSH session a host's command runs over, and a new one should
+/// it drop. Hosts run alongside others log their uploads' progress rather
+/// than each drawing a bar over the others'.
+struct Connect<'a> {
+    config: &'a DeploymentConfig,
+    alongside_others: bool,
+}
+
+#[async_trait::async_trait]
+impl Reconnect for Connect<'_> {
+    type Remote = SshClient;
+
+    async fn connect(&mut self) -> Result<SshClient> {
+        info!("🔗 Connecting to Cherry Server...");
+        let mut ssh_client = SshClient::new(self.config).await
+            .context("Failed to create SSH client")?;
+        ssh_client.on_progress(if self.alongside_others { progress::progress_lines() } else { progress::upload_progress() });
+        Ok(ssh_client)
+    }
+}
+
+/// The part of `command` done on the server; a deploy's package comes with
+/// the binary it holds
+async fn run_steps(
+    command: &Command,
+    config: &DeploymentConfig,
+    remote: &mut dyn Remote,
+    package: Option<&(Package, &Binary)>,
+    dry_run: bool,
+) -> HostRun {
+    let mut deployer = Deployer::new(remote, config);
+    let code = match command {
+        Command::Deploy(deploy) => {
+            let (package, binary) = package.expect("packaged above");
+            deployer.check_host(&binary.info).await?;
+            info!("🚀 Deploying to server...");
+            let deployment = deployer.deploy(package, deploy.release.as_deref(), deploy.keep_releases.into(), deploy.configure_firewall).await
+                .context("Failed to deploy to server")?;
+            info!("🔏 Binary sha256 {}", deployment.binary_sha256);
+            let mut detail = format!(
+                "release {}, uploaded {}, sha256 {}",
+                deployment.release,
+                deployment.upload,
+                &deployment.binary_sha256[..12]
+            );
+            if let Some(options) = binary.version.build_options() {
+                detail.push_str(&format!(", {}", options));
+            }
+            if deploy.no_smoke_test {
+                info!("⏭️  Skipping smoke test");
+            } else if dry_run {
+                info!("📝 Would smoke test the relay at {}:{}", config.host, config.port);
+            } else {
+                match smoke::smoke_test(&relay_address(config, None), smoke::SMOKE_TIMEOUT).await {
+                    Ok(round_trip) => detail.push_str(&format!(", smoke test {}", milliseconds(round_trip))),
+                    Err(e) if deploy.rollback_on_failure => {
+                        error!("❌ {:#}", e);
+                        let Some(previous) = deployment.previous.as_deref() else {
+                            return Err(e.context(format!(
+                                "Release {} failed its smoke test, with no earlier release to roll back to",
+                                deployment.release
+                            )));
+                        };
+                        deployer.rollback(Some(previous)).await.with_context(|| {
+                            format!("Release {} failed its smoke test, and rolling back to {} failed too", deployment.release, previous)
+                        })?;
+                        return Err(e.context(format!("Release {} failed its smoke test; rolled back to {}", deployment.release, previous)));
+                    }
+                    Err(e) => {
+                        return Err(e.context(format!(
+                            "Release {} failed its smoke test; `rollback` goes back to the one before",
+                            deployment.release
+                        )));
+                    }
+                }
+            }
+            info!("✅ Deployment complete! Release {} should now be running on {}:{}",
+                  deployment.release, config.host, config.port);
+            return Ok(Finished { code: 0, detail: Some(detail), version: Some(binary.version.short()) });
+        }
+        Command::Status => {
+            let status = deployer.get_service_status().await?;
+            print!("{}", status.report(&config.host, config.port));
+            // As systemctl and LSB init scripts answer for a stopped service
+            let code = if status.is_active() { 0 } else { 3 };
+            return Ok(Finished { code, detail: None, version: status.version.map(|version| version.short()) });
+        }
+        Command::Logs { lines, follow, since, grep } => {
+            let filter = LogFilter::new(*since, grep.as_deref())?;
+            match follow {
+                true => deployer.follow_service_logs(*lines, &filter, &mut std::io::stdout()).await?,
+                false => print!("{}", deployer.get_service_logs(*lines, &filter).await?),
+            }
+            0
+        }
+        Command::Restart => {
+            deployer.restart_service().await?;
+            0
+        }
+        Command::Rollback { to } => {
+            deployer.rollback(to.as_deref()).await?;
+            0
+        }
+        Command::Releases => {
+            let current = deployer.current_release().await?;
+            for release in deployer.list_releases().await? {
+                let marker = if current.as_ref() == Some(&release) { "*" } else { " " };
+                println!("{} {}", marker, release);
+            }
+            0
+        }
+        Command::Stop => {
+            deployer.stop_service().await?;
+            0
+        }
+        Command::Uninstall { purge } => {
+            deployer.uninstall(*purge).await?;
+            0
+        }
+        Command::SmokeTest { .. } => unreachable!("smoke tests don't go over SSH"),
+        Command::Init { .. } => unreachable!("init only writes a local file"),
+    };
+    Ok(Finished { code, ..Finished::default() })
+}
+
+/// `host:port` of the relay, on `port` if given rather than the host's
+fn relay_address(config: &DeploymentConfig, port: Option<u16>) -> String {
+    format!("{}:{}", config.host, port.unwrap_or(config.port))
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// The `smoke-test` command for one host, answering the round trip's time
+/// for the summary
+async fn smoke_test(config: &DeploymentConfig, port: Option<u16>, dry_run: bool) -> HostRun {
+    let address = relay_address(config, port);
+    if dry_run {
+        println!("Dry run: would smoke test the relay at {}", address);
+        return Ok(Finished::default());
+    }
+    let round_trip = smoke::smoke_test(&address, smoke::SMOKE_TIMEOUT).await?;
+    Ok(Finished { detail: Some(format!("round trip {}", milliseconds(round_trip))), ..Finished::default() })
+}
+
+/// Build the relay, unless `--skip-build`, answering where its binary is
+/// and what it was built from
+async fn build_binary(deploy: &DeployArgs) -> Result<(PathBuf, Version)> {
+    let checks = Checks { test: deploy.test, clippy: deploy.clippy, workspace: deploy.check_workspace };
+    let options = BuildOptions {
+        target: deploy.target.clone(),
+        use_cross: deploy.use_cross,
+        features: deploy.features.clone(),
+        profile: deploy.profile.clone(),
+        target_dir: deploy.target_dir.clone(),
+        manifest_path: deploy.manifest_path.clone(),
+    };
+    let builder = Builder::new(options, checks);
+    let path = if deploy.skip_build {
+        info!("⏭️  Skipping build, using existing binary");
+        builder.get_binary_path()
+    } else {
         info!("📦 Building relay server...");
-        let builder = Builder::new();
+        builder.check_dependencies().await?;
+        if deploy.clean {
+            builder.clean().await?;
+        }
         builder.build().await
             .context("Failed to build relay server")?
-    } else {
-        info!("⏭️  Skipping build, using existing binary");
-        PathBuf::from("relay-server/target/release/silence-relay")
     };
+    let version = builder.recorded_version(&path).await?;
+    if deploy.skip_build {
+        info!("🏷️  Deploying {}", version);
+    }
+    Ok((path, version))
+}
 
-    // Step 2: Create deployment package
+/// Package the binary with one host's service and relay configuration
+async fn package(config: &DeploymentConfig, binary: &Binary) -> Result<Package> {
     info!("📦 Creating deployment package...");
-    let packager = Packager::new(&config);
-    let package_path = packager.create_package(&binary_path).await
+    let packager = Packager::new(config);
+    let package = packager.create_package(&binary.path, &binary.version).await
         .context("Failed to create deployment package")?;
+    packager.verify_package(&package.path).await?;
+    Ok(package)
+}
+
+/// After a deploy, show the Silence app's relay list for every host, and
+/// write it to `--client-config` if given
+async fn write_client_config(command: &Command, configs: &[DeploymentConfig]) -> Result<()> {
+    let Command::Deploy(deploy) = command else { return Ok(()) };
+    let client_config = hosts::client_config(configs);
+    println!("\n{}", client_config);
+    if let Some(path) = &deploy.client_config {
+        tokio::fs::write(path, &client_config).await
+            .with_context(|| format!("Failed to write client config {}", path.display()))?;
+        info!("📝 Client config written to {}", path.display());
+    }
+    Ok(())
+}
 
-    // Step 3: Connect to Cherry Server via SSH
-    info!("🔗 Connecting to Cherry Server...");
-    let mut ssh_client = SshClient::new(&config).await
-        .context("Failed to create SSH client")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Step 4: Deploy the package
-    info!("🚀 Deploying to server...");
-    let mut deployer = Deployer::new(&mut ssh_client, &config);
-    deployer.deploy(&package_path).await
-        .context("Failed to deploy to server")?;
+    #[test]
+    fn test_ssh_options_go_before_or_after_the_subcommand() {
+        let before = Cli::try_parse_from(["deploy", "--host", "relay.example", "--user", "admin", "status"]).unwrap();
+        let after = Cli::try_parse_from(["deploy", "status", "--host", "relay.example", "--user", "admin"]).unwrap();
+        assert_eq!(before.ssh, after.ssh);
+        assert_eq!(before.ssh.host, "relay.example");
+        assert_eq!(after.command, Command::Status);
 
-    info!("✅ Deployment complete! Relay server should now be running on {}:{}", 
-          config.host, config.port);
+        let jumped = Cli::try_parse_from(["deploy", "status", "--ssh-port", "2200", "--jump-host", "admin@bastion.example"]).unwrap();
+        let config = DeploymentConfig::new(&jumped.ssh, &RelayArgs::default());
+        assert_eq!((config.ssh_port, config.port), (2200, 8080));
+        assert_eq!(config.jump_host.map(|jump| (jump.user, jump.port)), Some((Some("admin".to_string()), 22)));
+        assert!(Cli::try_parse_from(["deploy", "status", "--jump-host", "bastion:ssh"]).is_err());
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn test_subcommands_and_their_options() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["deploy"], args].concat()).unwrap().command;
+        let logs = |lines, follow| Command::Logs { lines, follow, since: None, grep: None };
+        assert_eq!(parse(&["logs"]), logs(50, false));
+        assert_eq!(parse(&["logs", "--lines", "200", "--follow"]), logs(200, true));
+        assert_eq!(parse(&["logs", "-n", "5", "-f"]), logs(5, true));
+        let since = humantime::parse_rfc3339("2025-06-02T12:00:00Z").unwrap();
+        assert_eq!(
+            parse(&["logs", "-f", "--since", "2025-06-02 12:00:00", "--grep", "ERROR|WARN"]),
+            Command::Logs { lines: 50, follow: true, since: Some(since), grep: Some("ERROR|WARN".to_string()) }
+        );
+        assert!(Cli::try_parse_from(["deploy", "logs", "--grep", "(unclosed"]).is_err());
+        assert_eq!(parse(&["restart"]), Command::Restart);
+        assert_eq!(parse(&["stop"]), Command::Stop);
+        assert_eq!(parse(&["uninstall"]), Command::Uninstall { purge: false });
+        assert_eq!(parse(&["uninstall", "--purge"]), Command::Uninstall { purge: true });
+        assert_eq!(parse(&["rollback"]), Command::Rollback { to: None });
+        assert_eq!(parse(&["rollback", "--to", "v1.2.0"]), Command::Rollback { to: Some("v1.2.0".to_string()) });
+        assert_eq!(parse(&["releases"]), Command::Releases);
+        assert_eq!(parse(&["smoke-test"]), Command::SmokeTest { port: None });
+        assert_eq!(parse(&["smoke-test", "--port", "9000"]), Command::SmokeTest { port: Some(9000) });
+
+        let Command::Deploy(deploy) = parse(&["deploy", "--port", "9000", "--skip-build"]) else { panic!() };
+        assert_eq!(deploy.relay.port, 9000);
+        assert!(deploy.skip_build);
+        assert!(Cli::try_parse_from(["deploy", "deploy", "--skip-build", "--clean"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "deploy", "--skip-build", "--test"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "deploy", "--skip-build", "--clippy"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "deploy", "--use-cross"]).is_err(), "cross needs a target");
+        let Command::Deploy(deploy) = parse(&["deploy", "--target", "x86_64-unknown-linux-musl", "--use-cross"]) else { panic!() };
+        assert_eq!(deploy.target.as_deref(), Some("x86_64-unknown-linux-musl"));
+        assert!(deploy.use_cross);
+        let Command::Deploy(deploy) = parse(&["deploy", "--features", "tls,metrics", "--profile", "min-size", "--skip-build"]) else { panic!() };
+        assert_eq!((deploy.features.as_slice(), deploy.profile.as_deref()), (["tls".to_string(), "metrics".to_string()].as_slice(), Some("min-size")));
+
+        let Command::Deploy(deploy) = parse(&["deploy"]) else { panic!() };
+        assert_eq!((deploy.release, deploy.keep_releases), (None, 5));
+        let Command::Deploy(deploy) = parse(&["deploy", "--release", "v1.2.0", "--keep-releases", "2"]) else { panic!() };
+        assert_eq!((deploy.release.as_deref(), deploy.keep_releases), (Some("v1.2.0"), 2));
+        assert!(!deploy.no_smoke_test && !deploy.rollback_on_failure);
+        let Command::Deploy(deploy) = parse(&["deploy", "--rollback-on-failure"]) else { panic!() };
+        assert!(deploy.rollback_on_failure);
+        assert!(!deploy.configure_firewall && deploy.relay.health_port.is_none());
+        let Command::Deploy(deploy) = parse(&["deploy", "--configure-firewall", "--health-port", "8081"]) else { panic!() };
+        assert!(deploy.configure_firewall);
+        assert_eq!(deploy.relay.health_port, Some(8081));
+        let bad_args = [
+            &["--release", "../etc"][..],
+            &["--release", "a b"],
+            &["--release", ".hidden"],
+            &["--keep-releases", "0"],
+            &["--no-smoke-test", "--rollback-on-failure"],
+        ];
+        for bad in bad_args {
+            assert!(Cli::try_parse_from([&["deploy", "deploy"][..], bad].concat()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_relay_options_belong_to_deploy() {
+        assert!(Cli::try_parse_from(["deploy"]).is_err(), "a subcommand is required");
+        assert!(Cli::try_parse_from(["deploy", "status", "--port", "9000"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "logs", "--lines", "many"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "-v", "stop"]).unwrap().verbose);
+        assert!(Cli::try_parse_from(["deploy", "deploy", "--skip-build", "--dry-run"]).unwrap().dry_run);
+
+        let policy = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["deploy", "status"], args].concat()).unwrap();
+            DeploymentConfig::new(&cli.ssh, &RelayArgs::default()).host_key_policy
+        };
+        assert_eq!(policy(&[]), HostKeyPolicy::Ask);
+        assert_eq!(policy(&["--accept-new-hostkey"]), HostKeyPolicy::AcceptNew);
+        assert_eq!(policy(&["--no-hostkey-check"]), HostKeyPolicy::Off);
+        assert!(Cli::try_parse_from(["deploy", "status", "--accept-new-hostkey", "--no-hostkey-check"]).is_err());
+    }
+
+    #[test]
+    fn test_hosts_come_from_the_inventory_then_hosts_then_host() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["deploy"], args].concat()).unwrap();
+        let hosts_of = |cli: &Cli| -> Vec<String> {
+            DeploymentConfig::for_hosts(&cli.ssh, &RelayArgs::default()).unwrap().into_iter().map(|config| config.host).collect()
+        };
+
+        let single = parse(&["--host", "relay.example", "status"]);
+        assert_eq!(hosts_of(&single), ["relay.example"]);
+
+        let several = parse(&["status", "--host", "ignored.example", "--hosts", "a.example,b.example", "--fail-fast"]);
+        assert_eq!(hosts_of(&several), ["a.example", "b.example"]);
+        assert!(several.fail_fast);
+        assert_eq!(several.parallel, 1);
+        assert_eq!(parse(&["status", "--hosts", "a.example,b.example", "--parallel", "4"]).parallel, 4);
+        assert!(Cli::try_parse_from(["deploy", "status", "--parallel", "0"]).is_err());
+
+        let mut inventory = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut inventory, b"[[hosts]]\nhost = \"c.example\"\nuser = \"deploy\"\n").unwrap();
+        let path = inventory.path().to_str().unwrap();
+        let from_file = parse(&["--inventory", path, "--user", "admin", "restart"]);
+        let configs = DeploymentConfig::for_hosts(&from_file.ssh, &RelayArgs::default()).unwrap();
+        assert_eq!((configs[0].host.as_str(), configs[0].user.as_str()), ("c.example", "deploy"));
+
+        assert!(Cli::try_parse_from(["deploy", "--hosts", "a", "--inventory", path, "status"]).is_err());
+        let missing = parse(&["--inventory", "/nonexistent/inventory.toml", "status"]);
+        assert!(DeploymentConfig::for_hosts(&missing.ssh, &RelayArgs::default()).is_err());
+    }
+}
@@ -4,6 +4,22 @@ use tempfile::TempDir;
 use tracing::{debug, info};
 
 use crate::DeploymentConfig;
+use crate::builder::BinaryInfo;
+use crate::checksum;
+use crate::deployer::Mode;
+use crate::docker;
+use crate::version::{self, Version};
+
+/// A package ready to upload, with the SHA-256 digests to check it by on
+/// the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Package {
+    pub path: PathBuf,
+    /// Of the tarball
+    pub sha256: String,
+    /// Of the relay binary inside, also recorded in its `deploy.conf`
+    pub binary_sha256: String,
+}
 
 pub struct Packager<'a> {
     config: &'a DeploymentConfig,
@@ -14,9 +30,12 @@ impl<'a> Packager<'a> {
         Self { config }
     }
 
-    pub async fn create_package(&self, binary_path: &Path) -> Result<PathBuf> {
+    pub async fn create_package(&self, binary_path: &Path, version: &Version) -> Result<Package> {
         info!("Creating deployment package...");
 
+        let binary_sha256 = checksum::sha256_file(binary_path).await
+            .context("Failed to checksum the relay binary")?;
+
         // Create temporary directory for packaging
         let temp_dir = TempDir::new()
             .context("Failed to create temporary directory")?;
@@ -32,6 +51,58 @@ impl<'a> Packager<'a> {
 
         debug!("Copied binary to {:?}", binary_dest);
 
+        // Record what it was built from, for `status`
+        tokio::fs::write(package_dir.join(version::FILE_NAME), version.to_json()).await
+            .context("Failed to write version file")?;
+
+        // Create relay configuration file read by the service
+        let relay_config = self.create_relay_config();
+        let relay_config_file = package_dir.join("relay.toml");
+        tokio::fs::write(&relay_config_file, relay_config).await
+            .context("Failed to write relay configuration file")?;
+
+        debug!("Created relay configuration file at {:?}", relay_config_file);
+
+        // The service and how it's installed, or the image and how it's run
+        match self.config.mode {
+            Mode::Systemd => self.write_systemd_files(&package_dir).await?,
+            Mode::Docker => self.write_docker_files(&package_dir, binary_path).await?,
+        }
+
+        // Create deployment configuration file
+        let config_content = self.create_config_file(&binary_sha256)?;
+        let config_file = package_dir.join("deploy.conf");
+        tokio::fs::write(&config_file, config_content).await
+            .context("Failed to write configuration file")?;
+
+        debug!("Created configuration file at {:?}", config_file);
+
+        // Create tarball
+        let tarball_path = temp_dir.path().join("silence-relay-deploy.tar.gz");
+        self.create_tarball(&package_dir, &tarball_path).await
+            .context("Failed to create deployment tarball")?;
+
+        // Move tarball to a persistent location
+        let final_tarball_path = self.package_path();
+        tokio::fs::copy(&tarball_path, &final_tarball_path).await
+            .context("Failed to copy tarball to final location")?;
+
+        info!("✅ Package created: {:?}", final_tarball_path);
+
+        // Log package contents for verification
+        let metadata = tokio::fs::metadata(&final_tarball_path).await
+            .context("Failed to get package metadata")?;
+        info!("Package size: {:.2} MB", metadata.len() as f64 / 1_048_576.0);
+
+        let sha256 = checksum::sha256_file(&final_tarball_path).await
+            .context("Failed to checksum the package")?;
+        debug!("Package sha256 {}, binary sha256 {}", sha256, binary_sha256);
+
+        Ok(Package { path: final_tarball_path, sha256, binary_sha256 })
+    }
+
+    /// The systemd unit and the script that installs the release
+    async fn write_systemd_files(&self, package_dir: &Path) -> Result<()> {
         // Create systemd service file
         let service_content = self.create_systemd_service();
         let service_file = package_dir.join("silence-relay.service");
@@ -59,47 +130,49 @@ impl<'a> Packager<'a> {
         }
 
         debug!("Created installation script at {:?}", install_file);
+        Ok(())
+    }
 
-        // Create deployment configuration file
-        let config_content = self.create_config_file()?;
-        let config_file = package_dir.join("deploy.conf");
-        tokio::fs::write(&config_file, config_content).await
-            .context("Failed to write configuration file")?;
-
-        debug!("Created configuration file at {:?}", config_file);
-
-        // Create tarball
-        let tarball_path = temp_dir.path().join("silence-relay-deploy.tar.gz");
-        self.create_tarball(&package_dir, &tarball_path).await
-            .context("Failed to create deployment tarball")?;
-
-        // Move tarball to a persistent location
-        let final_tarball_path = std::env::temp_dir().join("silence-relay-deploy.tar.gz");
-        tokio::fs::copy(&tarball_path, &final_tarball_path).await
-            .context("Failed to copy tarball to final location")?;
+    /// The Dockerfile for the release's image, on a base to suit the
+    /// binary, and the compose file that runs it
+    async fn write_docker_files(&self, package_dir: &Path, binary_path: &Path) -> Result<()> {
+        let binary = BinaryInfo::of(binary_path).await?;
+        let dockerfile = package_dir.join("Dockerfile");
+        tokio::fs::write(&dockerfile, docker::dockerfile(&binary, self.config)).await
+            .context("Failed to write Dockerfile")?;
 
-        info!("✅ Package created: {:?}", final_tarball_path);
+        let compose_file = package_dir.join("compose.yaml");
+        tokio::fs::write(&compose_file, docker::compose(self.config)).await
+            .context("Failed to write compose file")?;
 
-        // Log package contents for verification
-        let metadata = tokio::fs::metadata(&final_tarball_path).await
-            .context("Failed to get package metadata")?;
-        info!("Package size: {:.2} MB", metadata.len() as f64 / 1_048_576.0);
+        debug!("Created {:?} and {:?}", dockerfile, compose_file);
+        Ok(())
+    }
 
-        Ok(final_tarball_path)
+    /// Where the package is left for uploading: one per host and relay
+    /// port, as hosts deployed to in parallel are packaged at the same time
+    fn package_path(&self) -> PathBuf {
+        let host: String = self.config.host
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        std::env::temp_dir().join(format!("silence-relay-deploy-{}-{}.tar.gz", host, self.config.port))
     }
 
     fn create_systemd_service(&self) -> String {
-        format!(r#"[Unit]
+        r#"[Unit]
 Description=Silence Relay Server
 After=network.target
 Wants=network.target
 
 [Service]
-Type=simple
+Type=notify
+NotifyAccess=main
+WatchdogSec=30
 User=relay
 Group=relay
 WorkingDirectory=/opt/silence-relay
-ExecStart=/opt/silence-relay/silence-relay --port {} --max-clients {} --max-message-size {} --bind-address {}
+ExecStart=/opt/silence-relay/current/silence-relay --config /opt/silence-relay/relay.toml
 Restart=always
 RestartSec=5
 Environment=RUST_LOG=info
@@ -121,18 +194,48 @@ MemoryDenyWriteExecute=yes
 
 [Install]
 WantedBy=multi-user.target
-"#, 
-            self.config.port, 
-            self.config.max_clients, 
-            self.config.max_message_size, 
-            self.config.bind_address
-        )
+"#.to_string()
+    }
+
+    /// Relay settings in the format accepted by `silence-relay --config`.
+    /// In a container the relay listens on every address, and the ports
+    /// compose publishes decide which of the server's it's reached on.
+    fn create_relay_config(&self) -> String {
+        let bind_address = match self.config.mode {
+            Mode::Systemd => self.config.bind_address.as_str(),
+            Mode::Docker => "0.0.0.0",
+        };
+        let mut relay_config = format!(r#"# Silence Relay Server configuration
+# Command line flags and environment variables override these values;
+# run `silence-relay --config relay.toml --print-config` to see the result.
+port = {}
+bind_address = "{}"
+max_clients = {}
+max_message_size = {}
+"#,
+            self.config.port,
+            bind_address,
+            self.config.max_clients,
+            self.config.max_message_size
+        );
+        if let Some(health_port) = self.config.health_port {
+            relay_config.push_str(&format!("health_port = {}\n", health_port));
+            if self.config.mode == Mode::Docker {
+                relay_config.push_str("health_bind_address = \"0.0.0.0\"\n");
+            }
+        }
+        relay_config
     }
 
+    /// Installs the binary as the release named by its argument; the
+    /// deployer points `current` at it once installed
     fn create_install_script(&self) -> String {
         r#"#!/bin/bash
 set -euo pipefail
 
+RELEASE="${1:?usage: install.sh <release>}"
+RELEASE_DIR="/opt/silence-relay/releases/$RELEASE"
+
 echo "🔧 Installing Silence Relay Server..."
 
 # Create user for the service
@@ -144,13 +247,18 @@ else
 fi
 
 # Create directories
-mkdir -p /opt/silence-relay
-chown relay:relay /opt/silence-relay
+mkdir -p /opt/silence-relay "$RELEASE_DIR"
+chown relay:relay /opt/silence-relay /opt/silence-relay/releases "$RELEASE_DIR"
 
-# Install binary
-cp silence-relay /opt/silence-relay/
-chmod +x /opt/silence-relay/silence-relay
-chown relay:relay /opt/silence-relay/silence-relay
+# Install binary as its own release
+cp silence-relay "$RELEASE_DIR/"
+chmod +x "$RELEASE_DIR/silence-relay"
+chown relay:relay "$RELEASE_DIR/silence-relay"
+cp deploy.conf version.json "$RELEASE_DIR/"
+
+# Install relay configuration
+cp relay.toml /opt/silence-relay/
+chown relay:relay /opt/silence-relay/relay.toml
 
 # Install systemd service
 cp silence-relay.service /etc/systemd/system/
@@ -160,7 +268,7 @@ systemctl enable silence-relay
 # Stop existing service if running
 systemctl stop silence-relay 2>/dev/null || true
 
-echo "✅ Installation complete"
+echo "✅ Installed release $RELEASE"
 echo ""
 echo "🚀 To start the service:"
 echo "  systemctl start silence-relay"
@@ -173,7 +281,9 @@ echo "  journalctl -u silence-relay -f"
 "#.to_string()
     }
 
-    fn create_config_file(&self) -> Result<String> {
+    /// The package manifest, kept with the release on the server so
+    /// `status` can tell if its binary changed since
+    fn create_config_file(&self, binary_sha256: &str) -> Result<String> {
         let config_toml = format!(r#"# Silence Relay Server Deployment Configuration
 [server]
 host = "{}"
@@ -190,13 +300,17 @@ service_name = "silence-relay"
 [security]
 create_user = true
 enable_systemd_security = true
+
+[release]
+binary_sha256 = "{}"
 "#,
             self.config.host,
             self.config.port,
             self.config.max_clients,
             self.config.max_message_size,
             self.config.bind_address,
-            self.config.user
+            self.config.user,
+            binary_sha256
         );
 
         Ok(config_toml)
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::ssh::{Remote, Transfer};
+
+/// What `--dry-run` puts in place of a release name read from the server's
+/// clock, so plans come out the same on every run
+pub const PLACEHOLDER_RELEASE: &str = "YYYYMMDDHHMMSS";
+
+/// One thing the tool would have done on the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Run(String),
+    Upload { local_path: PathBuf, remote_path: String, bytes: u64, mode: i32 },
+}
+
+/// A server that isn't there: for `--dry-run`, it records each command and
+/// upload in place of doing it. Commands succeed with no output, save for
+/// the few whose answer steers a deploy, which get the answer of a fresh
+/// server whose relay starts fine.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// `user@host`, for the summary
+    pub target: String,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new(target: impl Into<String>) -> Self {
+        Self { target: target.into(), steps: Vec::new() }
+    }
+
+    fn answer(command: &str) -> (i32, String) {
+        if command.starts_with("date ") {
+            (0, format!("{}\n", PLACEHOLDER_RELEASE))
+        } else if command.contains("systemctl is-active") {
+            (0, "active\n".to_string())
+        } else if command.starts_with("sudo docker inspect -f '{{.State.Status}}'") {
+            (0, "running\n".to_string())
+        } else if command.starts_with("readlink ") || command.starts_with("ls -1tr ") {
+            // No releases yet
+            (1, String::new())
+        } else if command.starts_with("ss ") {
+            // Whether the relay is listening can't be known without asking
+            (1, String::new())
+        } else {
+            (0, String::new())
+        }
+    }
+}
+
+#[async_trait]
+impl Remote for Plan {
+    async fn run(&mut self, command: &str) -> Result<(i32, String)> {
+        info!("📝 Would run: {}", command);
+        self.steps.push(Step::Run(command.to_string()));
+        Ok(Self::answer(command))
+    }
+
+    async fn stream(&mut self, command: &str, _out: &mut (dyn Write + Send)) -> Result<i32> {
+        let (exit_status, _) = self.run(command).await?;
+        Ok(exit_status)
+    }
+
+    async fn upload_file(&mut self, local_path: &Path, remote_path: &str, mode: i32) -> Result<Transfer> {
+        let bytes = tokio::fs::metadata(local_path).await
+            .with_context(|| format!("Failed to read {}", local_path.display()))?
+            .len();
+        info!("📝 Would upload {} ({} bytes) to {} with mode {:o}", local_path.display(), bytes, remote_path, mode);
+        self.steps.push(Step::Upload { local_path: local_path.to_path_buf(), remote_path: remote_path.to_string(), bytes, mode });
+        Ok(Transfer { bytes, elapsed: Default::default() })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commands = self.steps.iter().filter(|step| matches!(step, Step::Run(_))).count();
+        let uploads = self.steps.len() - commands;
+        let bytes: u64 = self.steps.iter().map(|step| match step {
+            Step::Upload { bytes, .. } => *bytes,
+            Step::Run(_) => 0,
+        }).sum();
+        writeln!(
+            f,
+            "Dry run on {}: {} commands, {} uploads ({} bytes), nothing done",
+            self.target, commands, uploads, bytes
+        )?;
+        for (number, step) in self.steps.iter().enumerate() {
+            match step {
+                Step::Run(command) => writeln!(f, "{:>4}. run     {}", number + 1, command)?,
+                Step::Upload { local_path, remote_path, bytes, mode } => writeln!(
+                    f,
+                    "{:>4}. upload  {} -> {} ({} bytes, mode {:o})",
+                    number + 1,
+                    local_path.display(),
+                    remote_path,
+                    bytes,
+                    mode
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::Deployer;
+    use crate::deployer::tests::{config, package};
+    use crate::packager::Package;
+    use crate::ssh::FILE_MODE;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deploy_plan() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), [0u8; 1234]).unwrap();
+        let config = config();
+        let mut plan = Plan::new("root@relay.example");
+
+        let package = Package { path: file.path().to_path_buf(), ..package() };
+        let deployment = Deployer::new(&mut plan, &config).deploy(&package, None, 5, false).await.unwrap();
+        assert_eq!(deployment.release, PLACEHOLDER_RELEASE);
+        assert_eq!(deployment.upload.bytes, 1234);
+
+        let expected = format!(
+            "Dry run on root@relay.example: 29 commands, 1 uploads (1234 bytes), nothing done
+   1. upload  {package} -> /tmp/silence-relay-deploy.tar.gz (1234 bytes, mode 644)
+   2. run     echo '{sha}  /tmp/silence-relay-deploy.tar.gz' | sha256sum -c --status
+   3. run     rm -rf /tmp/silence-relay-extract
+   4. run     mkdir -p /tmp/silence-relay-extract
+   5. run     cd /tmp/silence-relay-extract && tar -xzf /tmp/silence-relay-deploy.tar.gz
+   6. run     ls -la /tmp/silence-relay-extract/
+   7. run     test -f /tmp/silence-relay-extract/silence-relay
+   8. run     test -f /tmp/silence-relay-extract/silence-relay.service
+   9. run     test -f /tmp/silence-relay-extract/install.sh
+  10. run     echo '{binary_sha}  /tmp/silence-relay-extract/silence-relay' | sha256sum -c --status
+  11. run     date -u +%Y%m%d%H%M%S
+  12. run     ls -1tr /opt/silence-relay/releases
+  13. run     readlink /opt/silence-relay/current
+  14. run     systemctl show --property MainPID --value silence-relay
+  15. run     chmod +x /tmp/silence-relay-extract/install.sh
+  16. run     cd /tmp/silence-relay-extract && sudo ./install.sh YYYYMMDDHHMMSS
+  17. run     test -f /opt/silence-relay/releases/YYYYMMDDHHMMSS/silence-relay
+  18. run     test -f /etc/systemd/system/silence-relay.service
+  19. run     systemctl is-enabled silence-relay
+  20. run     sudo ln -sfn releases/YYYYMMDDHHMMSS /opt/silence-relay/current.new && sudo mv -T /opt/silence-relay/current.new /opt/silence-relay/current
+  21. run     sudo systemctl stop silence-relay
+  22. run     sudo systemctl start silence-relay
+  23. run     sudo systemctl status silence-relay
+  24. run     sudo systemctl is-active silence-relay
+  25. run     netstat -tuln | grep :8080
+  26. run     sudo journalctl -u silence-relay --no-pager -n 10
+  27. run     ls -1tr /opt/silence-relay/releases
+  28. run     readlink /opt/silence-relay/current
+  29. run     rm -rf /tmp/silence-relay-extract
+  30. run     rm -f /tmp/silence-relay-deploy.tar.gz
+",
+            package = package.path.display(),
+            sha = package.sha256,
+            binary_sha = package.binary_sha256
+        );
+        assert_eq!(plan.to_string(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_plan_answers_as_a_fresh_server() {
+        let mut plan = Plan::new("root@relay.example");
+        assert_eq!(plan.run("readlink /opt/silence-relay/current").await.unwrap().0, 1);
+        assert_eq!(plan.run("sudo systemctl restart silence-relay").await.unwrap(), (0, String::new()));
+        assert_eq!(plan.stream("sudo journalctl -u silence-relay -f", &mut Vec::new()).await.unwrap(), 0);
+        assert!(plan.upload_file(Path::new("/nonexistent/package.tar.gz"), "/tmp/x", FILE_MODE).await.is_err());
+        assert_eq!(plan.steps.len(), 3);
+    }
+}
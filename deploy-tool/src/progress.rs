@@ -0,0 +1,71 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::ssh::ProgressFn;
+
+/// How often an upload's progress is logged when there's no terminal to
+/// draw a bar on
+const LOG_EVERY: Duration = Duration::from_secs(5);
+
+/// Shows uploads as a progress bar on a terminal, and otherwise, as when
+/// piped to a file or CI log, as a line every few seconds
+pub fn upload_progress() -> ProgressFn {
+    if std::io::stdout().is_terminal() {
+        progress_bar()
+    } else {
+        progress_lines()
+    }
+}
+
+fn progress_bar() -> ProgressFn {
+    let style = ProgressStyle::with_template(
+        "📤 [{bar:40.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}",
+    )
+    .expect("valid template")
+    .progress_chars("=> ");
+    let mut bar: Option<ProgressBar> = None;
+
+    Box::new(move |sent, total| {
+        // Each upload starts from 0 with a bar of its own
+        if sent == 0 || bar.is_none() {
+            bar = Some(ProgressBar::new(total).with_style(style.clone()));
+        }
+        let Some(current) = &bar else { return };
+        current.set_position(sent);
+        if sent >= total {
+            current.finish_and_clear();
+        }
+    })
+}
+
+/// Logs an upload's progress every few seconds, terminal or not
+pub fn progress_lines() -> ProgressFn {
+    let mut started = Instant::now();
+    let mut logged = started;
+
+    Box::new(move |sent, total| {
+        if sent == 0 {
+            started = Instant::now();
+            logged = started;
+            return;
+        }
+        if sent < total && logged.elapsed() < LOG_EVERY {
+            return;
+        }
+        logged = Instant::now();
+
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { sent as f64 / elapsed } else { 0.0 };
+        let left = if rate > 0.0 { (total - sent) as f64 / rate } else { 0.0 };
+        info!(
+            "📤 Uploaded {:.1} of {:.1} MB ({}%), {:.2} MB/s, about {:.0}s left",
+            sent as f64 / 1_048_576.0,
+            total as f64 / 1_048_576.0,
+            (sent * 100).checked_div(total).unwrap_or(100),
+            rate / 1_048_576.0,
+            left
+        );
+    })
+}
@@ -0,0 +1,372 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::checksum;
+use crate::ssh::{Remote, Transfer};
+
+/// Longest wait between attempts, however many there have been
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+// libssh2's codes for a connection that dropped or stalled, and for a
+// channel the server wouldn't open
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_CHANNEL_FAILURE: i32 = -21;
+const LIBSSH2_ERROR_SOCKET_TIMEOUT: i32 = -30;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+
+/// How often an SSH operation is tried, and how long is waited in between:
+/// `delay`, doubling after each failure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { attempts: 3, delay: Duration::from_secs(1) }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait after the `failures`th failure
+    pub fn delay_after(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.delay.saturating_mul(factor).min(MAX_DELAY)
+    }
+}
+
+/// Whether `error` came from the network rather than the server saying
+/// no: a dropped or stalled connection, or a channel that couldn't be
+/// opened, is worth another try; a failed login or a command that exited
+/// non-zero isn't
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::NotConnected
+            );
+        }
+        if let Some(e) = cause.downcast_ref::<ssh2::Error>() {
+            return matches!(
+                e.code(),
+                ssh2::ErrorCode::Session(
+                    LIBSSH2_ERROR_SOCKET_SEND
+                        | LIBSSH2_ERROR_TIMEOUT
+                        | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                        | LIBSSH2_ERROR_CHANNEL_FAILURE
+                        | LIBSSH2_ERROR_SOCKET_TIMEOUT
+                        | LIBSSH2_ERROR_SOCKET_RECV
+                )
+            );
+        }
+        false
+    })
+}
+
+/// Opens a connection to the server, again whenever the last one broke
+#[async_trait]
+pub trait Reconnect: Send {
+    type Remote: Remote;
+
+    async fn connect(&mut self) -> Result<Self::Remote>;
+}
+
+/// A server reached through connections that are remade when they break:
+/// an operation failing for a transient reason is tried again over a fresh
+/// one, as the policy allows. Streams are not repeated, as their output has
+/// already gone out, and an upload that failed part way is checked on the
+/// server before it's sent again.
+pub struct Retrying<C: Reconnect> {
+    connector: C,
+    policy: RetryPolicy,
+    remote: Option<C::Remote>,
+}
+
+impl<C: Reconnect> Retrying<C> {
+    pub fn new(connector: C, policy: RetryPolicy) -> Self {
+        Self { connector, policy, remote: None }
+    }
+
+    /// Connect now rather than for the first operation
+    pub async fn connect(&mut self) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result = self.remote().await.map(|_| ());
+            match result {
+                Err(e) if self.retry_after(&e, "Connecting", attempt).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// The connection now open, if any, for closing it
+    pub fn into_remote(self) -> Option<C::Remote> {
+        self.remote
+    }
+
+    /// The open connection, connecting first if there's none
+    async fn remote(&mut self) -> Result<&mut C::Remote> {
+        if self.remote.is_none() {
+            self.remote = Some(self.connector.connect().await?);
+        }
+        Ok(self.remote.as_mut().expect("just connected"))
+    }
+
+    /// Whether to try `what` again after it failed with `error` on attempt
+    /// number `attempt`, counting from 1; if so the broken connection is
+    /// dropped and the backoff waited out first
+    async fn retry_after(&mut self, error: &anyhow::Error, what: &str, attempt: u32) -> bool {
+        if attempt >= self.policy.attempts || !is_transient(error) {
+            return false;
+        }
+        let delay = self.policy.delay_after(attempt);
+        warn!(
+            "🔁 {} failed: {:#}; reconnecting in {:.1}s (attempt {} of {})",
+            what,
+            error,
+            delay.as_secs_f64(),
+            attempt + 1,
+            self.policy.attempts
+        );
+        self.remote = None;
+        tokio::time::sleep(delay).await;
+        true
+    }
+}
+
+/// Whether `remote_path` on the server is `local_path` already, by size and
+/// SHA-256
+async fn already_uploaded(remote: &mut dyn Remote, local_path: &Path, remote_path: &str) -> Result<bool> {
+    let local_size = tokio::fs::metadata(local_path).await
+        .with_context(|| format!("Failed to read {}", local_path.display()))?
+        .len();
+    let local_digest = checksum::sha256_file(local_path).await?;
+    let (status, output) = remote.run(&format!("stat -c %s {} && sha256sum {}", remote_path, remote_path)).await?;
+    if status != 0 {
+        return Ok(false);
+    }
+    let mut lines = output.lines();
+    let size = lines.next().and_then(|size| size.trim().parse::<u64>().ok());
+    let digest = lines.next().and_then(checksum::parse_sha256sum);
+    Ok(size == Some(local_size) && digest == Some(local_digest.as_str()))
+}
+
+#[async_trait]
+impl<C: Reconnect> Remote for Retrying<C> {
+    async fn run(&mut self, command: &str) -> Result<(i32, String)> {
+        let mut attempt = 1;
+        loop {
+            let result = match self.remote().await {
+                Ok(remote) => remote.run(command).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if self.retry_after(&e, &format!("`{}`", command), attempt).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    async fn stream(&mut self, command: &str, out: &mut (dyn Write + Send)) -> Result<i32> {
+        self.connect().await?;
+        self.remote().await?.stream(command, out).await
+    }
+
+    async fn upload_file(&mut self, local_path: &Path, remote_path: &str, mode: i32) -> Result<Transfer> {
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let result = match self.remote().await {
+                Ok(remote) if attempt > 1 => match already_uploaded(remote, local_path, remote_path).await {
+                    Ok(true) => {
+                        info!("✅ {} arrived whole before the connection dropped", remote_path);
+                        let bytes = tokio::fs::metadata(local_path).await?.len();
+                        return Ok(Transfer { bytes, elapsed: started.elapsed() });
+                    }
+                    // Whatever part of it got there goes before it's sent again
+                    Ok(false) => match remote.execute_command(&format!("rm -f {}", remote_path)).await {
+                        Ok(_) => remote.upload_file(local_path, remote_path, mode).await,
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                },
+                Ok(remote) => remote.upload_file(local_path, remote_path, mode).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if self.retry_after(&e, &format!("Uploading {}", local_path.display()), attempt).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployer::tests::ScriptedRemote;
+    use crate::ssh::FILE_MODE;
+    use anyhow::anyhow;
+    use std::collections::VecDeque;
+    use std::io;
+
+    fn reset() -> anyhow::Error {
+        anyhow::Error::new(io::Error::from(ErrorKind::ConnectionReset)).context("Failed to read command output")
+    }
+
+    /// Hands out the remotes it's given in turn, failing to connect when it
+    /// runs out
+    struct Connections {
+        remotes: VecDeque<FlakyRemote>,
+        made: usize,
+    }
+
+    #[async_trait]
+    impl Reconnect for Connections {
+        type Remote = FlakyRemote;
+
+        async fn connect(&mut self) -> Result<FlakyRemote> {
+            self.made += 1;
+            self.remotes.pop_front().ok_or_else(|| anyhow!("no more connections"))
+        }
+    }
+
+    /// A scripted server whose connection drops after `drops_after`
+    /// operations
+    struct FlakyRemote {
+        server: ScriptedRemote,
+        drops_after: usize,
+        uploads: usize,
+    }
+
+    impl FlakyRemote {
+        fn new(server: ScriptedRemote, drops_after: usize) -> Self {
+            Self { server, drops_after, uploads: 0 }
+        }
+
+        fn operation(&mut self) -> Result<()> {
+            match self.drops_after.checked_sub(1) {
+                Some(left) => {
+                    self.drops_after = left;
+                    Ok(())
+                }
+                None => Err(reset()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Remote for FlakyRemote {
+        async fn run(&mut self, command: &str) -> Result<(i32, String)> {
+            self.operation()?;
+            self.server.run(command).await
+        }
+
+        async fn stream(&mut self, command: &str, out: &mut (dyn Write + Send)) -> Result<i32> {
+            self.operation()?;
+            self.server.stream(command, out).await
+        }
+
+        async fn upload_file(&mut self, local_path: &Path, remote_path: &str, mode: i32) -> Result<Transfer> {
+            self.operation()?;
+            self.uploads += 1;
+            self.server.upload_file(local_path, remote_path, mode).await
+        }
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(is_transient(&reset()));
+        assert!(is_transient(&anyhow::Error::new(io::Error::from(ErrorKind::TimedOut))));
+        let channel = ssh2::Error::new(ssh2::ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE), "channel open failure");
+        assert!(is_transient(&anyhow::Error::new(channel).context("Failed to open SSH channel")));
+        // A channel read hands back libssh2's error as an io::Error, which
+        // keeps the kind of a timeout but not the code
+        let stalled = ssh2::Error::new(ssh2::ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT), "timed out waiting");
+        assert!(is_transient(&io::Error::from(stalled).into()));
+
+        let auth = ssh2::Error::new(ssh2::ErrorCode::Session(-18), "authentication failed");
+        assert!(!is_transient(&anyhow::Error::new(auth)));
+        assert!(!is_transient(&anyhow!("Command 'test -f x' failed with exit status 1: ")));
+        assert!(!is_transient(&anyhow::Error::new(io::Error::from(ErrorKind::PermissionDenied))));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_a_limit() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<_> = (1..=7).map(|failures| policy.delay_after(failures).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_commands_are_retried_over_a_new_connection() {
+        let server = || ScriptedRemote::default().reply("uptime", 0, "up 3 days\n");
+        let connections = Connections { remotes: [FlakyRemote::new(server(), 0), FlakyRemote::new(server(), 1)].into(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(remote.run("uptime").await.unwrap(), (0, "up 3 days\n".to_string()));
+        assert_eq!(started.elapsed(), Duration::from_secs(1), "waited once");
+        assert_eq!(remote.connector.made, 2);
+
+        // Out of attempts: the last error is the one reported
+        let connections = Connections { remotes: (0..3).map(|_| FlakyRemote::new(server(), 0)).collect(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+        let error = remote.run("uptime").await.unwrap_err();
+        assert!(is_transient(&error));
+        assert_eq!(remote.connector.made, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_permanent_failures_are_not_retried() {
+        let server = ScriptedRemote::default().reply("false", 1, "");
+        let connections = Connections { remotes: [FlakyRemote::new(server, 5)].into(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+        assert!(remote.execute_command("false").await.is_err());
+        assert_eq!(remote.connector.made, 1);
+
+        let connections = Connections { remotes: VecDeque::new(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+        assert_eq!(remote.connect().await.unwrap_err().to_string(), "no more connections");
+        assert_eq!(remote.connector.made, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_uploads_are_checked_before_being_sent_again() {
+        let package = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(package.path(), b"relay package").unwrap();
+        let digest = checksum::sha256_hex(b"relay package");
+        let check = "stat -c %s /tmp/p.tar.gz && sha256sum /tmp/p.tar.gz";
+
+        // It got there whole before the connection dropped
+        let arrived = ScriptedRemote::default().reply(check, 0, &format!("13\n{}  /tmp/p.tar.gz\n", digest));
+        let connections = Connections { remotes: [FlakyRemote::new(ScriptedRemote::default(), 0), FlakyRemote::new(arrived, 5)].into(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+        assert_eq!(remote.upload_file(package.path(), "/tmp/p.tar.gz", FILE_MODE).await.unwrap().bytes, 13);
+        assert_eq!(remote.remote.as_ref().unwrap().uploads, 0);
+
+        // Only part of it did
+        let partial = ScriptedRemote::default()
+            .reply(check, 0, &format!("8\n{}  /tmp/p.tar.gz\n", "0".repeat(64)))
+            .reply("rm -f /tmp/p.tar.gz", 0, "");
+        let connections = Connections { remotes: [FlakyRemote::new(ScriptedRemote::default(), 0), FlakyRemote::new(partial, 5)].into(), made: 0 };
+        let mut remote = Retrying::new(connections, RetryPolicy::default());
+        remote.upload_file(package.path(), "/tmp/p.tar.gz", FILE_MODE).await.unwrap();
+        assert_eq!(remote.remote.as_ref().unwrap().uploads, 1);
+        let ran = &remote.remote.as_ref().unwrap().server.ran;
+        assert_eq!(ran[ran.len() - 2..], ["rm -f /tmp/p.tar.gz", "upload /tmp/p.tar.gz"], "the partial file removed first");
+    }
+}
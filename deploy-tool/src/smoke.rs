@@ -0,0 +1,203 @@
+use anyhow::{Context, Result, anyhow};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+/// Longest the whole smoke test may take, connecting included
+pub const SMOKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The relay wire protocol as a client speaks it: 4-byte big-endian length
+// prefixed frames, a join answered by a hello, then frames that lead with
+// a type byte
+const HELLO_LEN: usize = 1 + 16 + 4 + 1;
+const REJECT_MARKER: u8 = 0x00;
+const SIZE_SEPARATOR: u8 = 0x01;
+const FRAME_DATA: u8 = 0x01;
+const FRAME_PING: u8 = 0x02;
+const FRAME_PONG: u8 = 0x03;
+const FRAME_GOODBYE: u8 = 0x04;
+/// Largest frame the smoke clients ask the relay for
+const MAX_FRAME: u32 = 4096;
+
+/// The relay's hello, as far as the smoke test cares
+#[derive(Debug, PartialEq)]
+struct Hello {
+    version: u8,
+    peer_present: bool,
+}
+
+/// A join for `session`, at the client library's wire version
+fn join_frame(session: &str) -> Vec<u8> {
+    let mut frame = vec![silence::protocol::WIRE_VERSION];
+    frame.extend_from_slice(session.as_bytes());
+    frame.push(SIZE_SEPARATOR);
+    frame.extend_from_slice(&MAX_FRAME.to_be_bytes());
+    frame
+}
+
+fn decode_hello(frame: &[u8]) -> Result<Hello> {
+    match frame {
+        [REJECT_MARKER, reason @ ..] => Err(anyhow!("the relay refused to join: {}", reject_reason(reason))),
+        _ if frame.len() != HELLO_LEN => Err(anyhow!("the relay answered the join with {} bytes rather than a hello", frame.len())),
+        _ => Ok(Hello { version: frame[0], peer_present: frame[HELLO_LEN - 1] == 1 }),
+    }
+}
+
+/// Reason codes as the relay sends them; the client library has the full list
+fn reject_reason(reason: &[u8]) -> String {
+    match reason {
+        [1, ..] => "too many connections from this address".to_string(),
+        [2, ..] => "relay is draining".to_string(),
+        [3, ..] => "session is full".to_string(),
+        [4, ..] => "relay is full".to_string(),
+        [5, ..] => "address is banned".to_string(),
+        [6, ..] => "bandwidth quota exceeded".to_string(),
+        [7, min, max] => format!("protocol version not supported (relay supports {}-{})", min, max),
+        [8, ..] => "frames over the relay's size limit".to_string(),
+        [9, ..] => "relay is overloaded".to_string(),
+        [10, ..] => "relay requires an auth token".to_string(),
+        [11, ..] => "relay is shutting down".to_string(),
+        [12, ..] => "disconnected by the relay operator".to_string(),
+        [13, ..] => "idle for too long".to_string(),
+        [code, ..] => format!("reason {}", code),
+        [] => "no reason given".to_string(),
+    }
+}
+
+/// A session nobody else would pick
+fn session_code() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("deploy-smoke-{:x}-{:x}", std::process::id(), nanos)
+}
+
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<()> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let length = stream.read_u32().await.context("the relay closed the connection")?;
+    if length > MAX_FRAME {
+        return Err(anyhow!("the relay sent a {} byte frame, over the {} asked for", length, MAX_FRAME));
+    }
+    let mut frame = vec![0u8; length as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn join(addr: &str, session: &str) -> Result<(TcpStream, Hello)> {
+    let mut stream = TcpStream::connect(addr).await
+        .with_context(|| format!("Failed to connect to the relay at {}", addr))?;
+    stream.set_nodelay(true)?;
+    write_frame(&mut stream, &join_frame(session)).await?;
+    let hello = decode_hello(&read_frame(&mut stream).await?)?;
+    debug!("Joined {} at wire version {}", session, hello.version);
+    Ok((stream, hello))
+}
+
+/// Read data frames until one arrives, answering the relay's pings on the way
+async fn receive(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    loop {
+        let frame = read_frame(stream).await?;
+        match frame.split_first() {
+            Some((&FRAME_DATA, payload)) => return Ok(payload.to_vec()),
+            Some((&FRAME_PING, [])) => write_frame(stream, &[FRAME_PONG]).await?,
+            Some((&FRAME_GOODBYE, reason)) => {
+                return Err(anyhow!("the relay said goodbye: {}", reject_reason(reason)));
+            }
+            _ => debug!("Ignoring relay frame {:?}", frame),
+        }
+    }
+}
+
+/// Join a fresh session on the relay at `addr` with two clients, send a
+/// message from one and have the other echo it back; answers the round
+/// trip's time
+pub async fn smoke_test(addr: &str, timeout: Duration) -> Result<Duration> {
+    info!("💨 Smoke testing the relay at {}...", addr);
+    let test = async {
+        let session = session_code();
+        let (mut sender, _) = join(addr, &session).await?;
+        let (mut receiver, hello) = join(addr, &session).await?;
+        if !hello.peer_present {
+            return Err(anyhow!("the second client didn't find the first in their session"));
+        }
+
+        let message = format!("smoke test {}", session);
+        let mut frame = vec![FRAME_DATA];
+        frame.extend_from_slice(message.as_bytes());
+        let sent_at = Instant::now();
+        write_frame(&mut sender, &frame).await?;
+        let received = receive(&mut receiver).await?;
+        frame.truncate(1);
+        frame.extend_from_slice(&received);
+        write_frame(&mut receiver, &frame).await?;
+        let echoed = receive(&mut sender).await?;
+        let round_trip = sent_at.elapsed();
+        if echoed != message.as_bytes() {
+            return Err(anyhow!("the message came back changed: {:?}", String::from_utf8_lossy(&echoed)));
+        }
+        Ok(round_trip)
+    };
+
+    let round_trip = tokio::time::timeout(timeout, test).await
+        .map_err(|_| anyhow!("no message through the relay within {:?}", timeout))
+        .and_then(|finished| finished)
+        .with_context(|| format!("Smoke test of the relay at {} failed", addr))?;
+    info!("✅ Relay passed a message there and back in {:.1} ms", round_trip.as_secs_f64() * 1000.0);
+    Ok(round_trip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use silence_relay::{Args, CancellationToken};
+
+    #[test]
+    fn test_join_and_hello_frames() {
+        let join = join_frame("deploy-smoke-1");
+        assert_eq!(join[0], silence::protocol::WIRE_VERSION);
+        assert_eq!(&join[1..15], b"deploy-smoke-1");
+        assert_eq!(&join[15..], &[SIZE_SEPARATOR, 0, 0, 0x10, 0]);
+
+        let mut hello = vec![3];
+        hello.extend_from_slice(&[0xab; 16]);
+        hello.extend_from_slice(&65536u32.to_be_bytes());
+        hello.push(1);
+        assert_eq!(decode_hello(&hello).unwrap(), Hello { version: 3, peer_present: true });
+
+        let refused = decode_hello(&[REJECT_MARKER, 10]).unwrap_err().to_string();
+        assert!(refused.contains("auth token"), "{}", refused);
+        assert!(decode_hello(&[3, 1, 2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_smoke_test_against_a_relay() {
+        let shutdown = CancellationToken::new();
+        let args = Args::from_flags(["--bind-address", "127.0.0.1", "--port", "0"]).unwrap();
+        let relay = silence_relay::run_relay(args, shutdown.clone()).await.unwrap();
+
+        let round_trip = smoke_test(&relay.addr().to_string(), Duration::from_secs(5)).await.unwrap();
+        assert!(round_trip < Duration::from_secs(5));
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_smoke_test_of_something_else_fails() {
+        // Accepts and says nothing, as a relay that hung would
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let error = smoke_test(&addr, Duration::from_millis(300)).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("within"), "{:#}", error);
+
+        assert!(smoke_test("127.0.0.1:1", Duration::from_secs(2)).await.is_err(), "nothing listening");
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What a build records about itself, kept next to the binary both after
+/// the build and on the server
+pub const FILE_NAME: &str = "version.json";
+
+/// The environment variable a build passes the commit in, for the relay's
+/// `--version` and health endpoint
+pub const COMMIT_VAR: &str = "SILENCE_RELAY_COMMIT";
+
+/// What a relay binary was built from
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Version {
+    /// The relay crate's version
+    pub version: String,
+    /// The git commit built, ending `-dirty` if there were uncommitted
+    /// changes; none when it isn't known
+    pub commit: Option<String>,
+    /// When it was built, in UTC
+    pub built_at: String,
+    /// The cargo profile it was built with; none when that isn't known
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The relay's cargo features turned on beyond its defaults
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Version {
+    pub fn new(version: &str, commit: Option<String>, built_at: SystemTime) -> Self {
+        Self {
+            version: version.to_string(),
+            commit,
+            built_at: humantime::format_rfc3339_seconds(built_at).to_string(),
+            profile: None,
+            features: Vec::new(),
+        }
+    }
+
+    /// The profile and features it was built with, where they aren't the
+    /// defaults
+    pub fn build_options(&self) -> Option<String> {
+        let mut options = Vec::new();
+        if let Some(profile) = self.profile.as_deref().filter(|profile| *profile != "release") {
+            options.push(format!("profile {}", profile));
+        }
+        if !self.features.is_empty() {
+            options.push(format!("features {}", self.features.join(",")));
+        }
+        (!options.is_empty()).then(|| options.join(", "))
+    }
+
+    /// What a `version.json` says, if it can be read
+    pub fn parse(json: &str) -> Option<Self> {
+        serde_json::from_str(json).ok()
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{}\n", serde_json::to_string_pretty(self).expect("a version serializes"))
+    }
+
+    /// The version and an abbreviated commit, to tell relays apart at a
+    /// glance
+    pub fn short(&self) -> String {
+        let Some(commit) = &self.commit else { return self.version.clone() };
+        let (hash, dirty) = match commit.strip_suffix("-dirty") {
+            Some(hash) => (hash, "-dirty"),
+            None => (commit.as_str(), ""),
+        };
+        format!("{} {}{}", self.version, hash.chars().take(8).collect::<String>(), dirty)
+    }
+
+    /// Where a build's `version.json` is kept, next to its binary
+    pub fn path_for(binary_path: &Path) -> PathBuf {
+        binary_path.with_file_name(FILE_NAME)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.commit {
+            Some(commit) => write!(f, "{}, commit {}, built {}", self.version, commit, self.built_at)?,
+            None => write!(f, "{}, built {}", self.version, self.built_at)?,
+        }
+        match self.build_options() {
+            Some(options) => write!(f, ", {}", options),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_version_metadata() {
+        let built_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_780_000_000);
+        let commit = "3fd0229e37401142d86ad7663c56e82f26b71d0b";
+        let version = Version::new("0.1.0", Some(commit.to_string()), built_at);
+        assert_eq!(version.built_at, "2026-05-28T20:26:40Z");
+        assert_eq!(version.short(), "0.1.0 3fd0229e");
+        assert_eq!(version.to_string(), format!("0.1.0, commit {}, built 2026-05-28T20:26:40Z", commit));
+
+        let json = version.to_json();
+        assert!(json.contains(&format!("\"commit\": \"{}\"", commit)), "{}", json);
+        assert_eq!(Version::parse(&json), Some(version.clone()));
+        assert_eq!(Version::parse("cat: version.json: No such file or directory"), None);
+
+        let dirty = Version::new("0.1.0", Some(format!("{}-dirty", commit)), built_at);
+        assert_eq!(dirty.short(), "0.1.0 3fd0229e-dirty");
+        let unknown = Version::new("0.1.0", None, built_at);
+        assert_eq!((unknown.short().as_str(), unknown.to_string().as_str()), ("0.1.0", "0.1.0, built 2026-05-28T20:26:40Z"));
+
+        let built_with = Version { profile: Some("min-size".to_string()), features: vec!["tls".to_string(), "metrics".to_string()], ..unknown };
+        assert_eq!(built_with.to_string(), "0.1.0, built 2026-05-28T20:26:40Z, profile min-size, features tls,metrics");
+        assert_eq!(Version::parse(&built_with.to_json()), Some(built_with));
+        let release = Version { profile: Some("release".to_string()), ..version.clone() };
+        assert_eq!(release.build_options(), None);
+        // As recorded before profiles and features were
+        let older = format!("{{\"version\": \"0.1.0\", \"commit\": \"{}\", \"built_at\": \"2026-05-28T20:26:40Z\"}}", commit);
+        assert_eq!(Version::parse(&older), Some(version));
+
+        assert_eq!(Version::path_for(Path::new("target/release/silence-relay")), Path::new("target/release/version.json"));
+    }
+}
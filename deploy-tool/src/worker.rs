@@ -0,0 +1,179 @@
+// A thread of its own for something that blocks, such as an ssh2 session:
+// async code hands it jobs and awaits their answers, so the runtime's
+// threads go on with progress bars, other hosts and Ctrl+C meanwhile.
+//
+// A job whose caller stops waiting, as when it's cancelled, is told so
+// through `Cancelled`, and should give up at the next chance it gets; the
+// thread then carries on with the jobs after it. Dropping the `Worker` ends
+// the thread once the job it's on is done.
+
+use anyhow::{Result, anyhow};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::Span;
+
+type Job<S> = Box<dyn FnOnce(&mut S, &Cancelled) + Send>;
+
+/// Set once a job's caller stops waiting for it
+#[derive(Clone, Debug, Default)]
+pub struct Cancelled(Arc<AtomicBool>);
+
+impl Cancelled {
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Cancels a job when its caller's future is dropped
+struct CancelOnDrop(Cancelled);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.set();
+    }
+}
+
+/// A thread owning an `S`, running jobs on it one at a time
+pub struct Worker<S> {
+    jobs: mpsc::Sender<(Job<S>, Cancelled)>,
+}
+
+impl<S: 'static> Worker<S> {
+    /// Start a thread named `name` that makes its `S` with `start`; answers
+    /// once it has, or with why it couldn't. The thread logs in the span
+    /// it was started in.
+    pub async fn start<F>(name: &str, start: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<S> + Send + 'static,
+    {
+        let (jobs, queue) = mpsc::channel::<(Job<S>, Cancelled)>();
+        let (started, answer) = oneshot::channel();
+        let span = Span::current();
+        std::thread::Builder::new().name(name.to_string()).spawn(move || {
+            let _entered = span.entered();
+            let mut state = match start() {
+                Ok(state) => {
+                    let _ = started.send(Ok(()));
+                    state
+                }
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    return;
+                }
+            };
+            while let Ok((job, cancelled)) = queue.recv() {
+                job(&mut state, &cancelled);
+            }
+        })?;
+        answer.await.map_err(|_| anyhow!("The {} thread stopped while starting", name))??;
+        Ok(Self { jobs })
+    }
+
+    /// Queue `job` at once, answering a future for what it returns; dropping
+    /// the future cancels the job
+    pub fn call<T, F>(&self, job: F) -> impl Future<Output = Result<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut S, &Cancelled) -> T + Send + 'static,
+    {
+        let (answer, answered) = oneshot::channel();
+        let cancelled = Cancelled::default();
+        let job: Job<S> = Box::new(move |state, cancelled| {
+            let _ = answer.send(job(state, cancelled));
+        });
+        let queued = self.jobs.send((job, cancelled.clone())).is_ok();
+        let guard = CancelOnDrop(cancelled);
+        async move {
+            let _guard = guard;
+            if !queued {
+                return Err(anyhow!("The session's thread has stopped"));
+            }
+            answered.await.map_err(|_| anyhow!("The session's thread stopped before answering"))
+        }
+    }
+
+    /// Queue `job` without waiting for it
+    pub fn send<F>(&self, job: F)
+    where
+        F: FnOnce(&mut S) + Send + 'static,
+    {
+        let _ = self.jobs.send((Box::new(move |state, _| job(state)), Cancelled::default()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::time::{Duration, Instant};
+
+    /// Stands in for a session: each job blocks its thread, as ssh2 calls do
+    #[derive(Default)]
+    struct Blocking {
+        calls: u32,
+    }
+
+    #[tokio::test]
+    async fn test_a_long_job_does_not_starve_the_runtime() {
+        // The test runtime has the one thread, as blocking on it would
+        // stop everything else
+        let ticks = Arc::new(AtomicU32::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(10));
+                loop {
+                    interval.tick().await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let worker = Worker::start("test-session", || Ok(Blocking::default())).await.unwrap();
+        let started = Instant::now();
+        let calls = worker
+            .call(|session: &mut Blocking, _: &Cancelled| {
+                std::thread::sleep(Duration::from_millis(300));
+                session.calls += 1;
+                session.calls
+            })
+            .await
+            .unwrap();
+        ticker.abort();
+
+        assert_eq!(calls, 1);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        let ticks = ticks.load(Ordering::SeqCst);
+        assert!(ticks >= 10, "the ticker ticked {} times while the job blocked", ticks);
+    }
+
+    #[tokio::test]
+    async fn test_a_cancelled_job_does_not_wedge_the_worker() {
+        let worker = Worker::start("test-session", || Ok(Blocking::default())).await.unwrap();
+
+        // Runs until told its caller has gone, as an upload checks between chunks
+        let endless = worker.call(|session: &mut Blocking, cancelled: &Cancelled| {
+            while !cancelled.is_set() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            session.calls += 1;
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(50), endless).await.is_err());
+
+        let calls = tokio::time::timeout(Duration::from_secs(5), worker.call(|session: &mut Blocking, _: &Cancelled| session.calls))
+            .await
+            .expect("the worker went on to the next job")
+            .unwrap();
+        assert_eq!(calls, 1, "the cancelled job gave up and returned");
+
+        let failed = Worker::<Blocking>::start("test-session", || Err(anyhow!("handshake failed"))).await;
+        assert_eq!(failed.err().unwrap().to_string(), "handshake failed");
+    }
+}
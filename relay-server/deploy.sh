@@ -39,7 +39,9 @@ After=network.target
 Wants=network.target
 
 [Service]
-Type=simple
+Type=notify
+NotifyAccess=main
+WatchdogSec=30
 User=relay
 Group=relay
 WorkingDirectory=/opt/silence-relay
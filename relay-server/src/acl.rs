@@ -0,0 +1,206 @@
+// Address-based access control checked at accept time
+//
+// An empty allowlist admits everyone; a non-empty one admits only matching
+// addresses. The denylist always wins over the allowlist.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+/// An IPv4 or IPv6 prefix such as `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical_ip(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses as the IPv4 address they carry
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `addr/len`; a bare address is a single-host prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network = canonical_ip(addr.trim().parse::<IpAddr>()
+            .map_err(|_| format!("Invalid CIDR {}: bad address", s))?);
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("Invalid CIDR {}: prefix length must be 0-{}", s, max_len))?,
+            None => max_len,
+        };
+
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Which list an access rule belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessRule {
+    Allow,
+    Deny,
+}
+
+/// Allow and deny prefix lists
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether a client at this address may connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Add a prefix to a list; returns false if it was already there
+    pub fn insert(&mut self, rule: AccessRule, cidr: Cidr) -> bool {
+        let list = self.list_mut(rule);
+        if list.contains(&cidr) {
+            return false;
+        }
+        list.push(cidr);
+        true
+    }
+
+    /// Remove a prefix from a list; returns false if it wasn't there
+    pub fn remove(&mut self, rule: AccessRule, cidr: Cidr) -> bool {
+        let list = self.list_mut(rule);
+        let before = list.len();
+        list.retain(|existing| *existing != cidr);
+        list.len() != before
+    }
+
+    fn list_mut(&mut self, rule: AccessRule) -> &mut Vec<Cidr> {
+        match rule {
+            AccessRule::Allow => &mut self.allow,
+            AccessRule::Deny => &mut self.deny,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parsing() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("::ffff:10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("relay.example/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_matching() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.200.3.4")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_overlapping_prefixes_deny_wins() {
+        let access = AccessList::new(
+            vec![cidr("10.0.0.0/8"), cidr("2001:db8::/32")],
+            vec![cidr("10.1.0.0/16"), cidr("2001:db8:bad::/48")],
+        );
+
+        assert!(access.permits(ip("10.2.0.1")));
+        assert!(!access.permits(ip("10.1.2.3")), "deny must override a wider allow");
+        assert!(!access.permits(ip("192.0.2.1")), "allowlist must exclude everything else");
+        assert!(access.permits(ip("2001:db8:1::1")));
+        assert!(!access.permits(ip("2001:db8:bad::1")));
+    }
+
+    #[test]
+    fn test_runtime_updates() {
+        let mut access = AccessList::default();
+        assert!(access.permits(ip("198.51.100.4")), "empty lists admit everyone");
+
+        assert!(access.insert(AccessRule::Deny, cidr("198.51.100.0/24")));
+        assert!(!access.insert(AccessRule::Deny, cidr("198.51.100.0/24")));
+        assert!(!access.permits(ip("198.51.100.4")));
+
+        assert!(access.remove(AccessRule::Deny, cidr("198.51.100.0/24")));
+        assert!(!access.remove(AccessRule::Deny, cidr("198.51.100.0/24")));
+        assert!(access.permits(ip("198.51.100.4")));
+
+        access.insert(AccessRule::Allow, cidr("203.0.113.0/24"));
+        assert!(!access.permits(ip("198.51.100.4")));
+    }
+}
@@ -0,0 +1,358 @@
+// Local admin interface - JSON-line commands over a unix socket
+//
+// Each request is one JSON object per line, e.g. {"cmd":"list"},
+// {"cmd":"kick","id":"<uuid>"}, {"cmd":"stats","session":"<code>"} or
+// {"cmd":"unban","ip":"<address>"}; each
+// response is one JSON object per line.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::backpressure::BufferBudget;
+use crate::bans::BanList;
+use crate::registry::SharedRegistry;
+use crate::acl::{AccessList, AccessRule, Cidr};
+use crate::quota::QuotaTracker;
+use crate::stats::{RelayStats, session_label};
+use crate::store_forward::StoreForward;
+
+/// Longest request line accepted from an admin client
+const MAX_COMMAND_LEN: usize = 4096;
+
+/// Commands understood by the admin interface
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// List connected clients
+    List,
+    /// Disconnect a client by id
+    Kick { id: Uuid },
+    /// Aggregate relay counters, or one session's delivery counters
+    Stats { session: Option<String> },
+    /// Pause or resume accepting new clients (toggles when `enabled` is omitted)
+    Drain { enabled: Option<bool> },
+    /// Show the allow and deny lists
+    Acl,
+    /// Add a prefix to the allow or deny list
+    AclAdd { rule: AccessRule, cidr: Cidr },
+    /// Remove a prefix from the allow or deny list
+    AclRemove { rule: AccessRule, cidr: Cidr },
+    /// List addresses banned for protocol violations
+    Bans,
+    /// Lift an address's ban
+    Unban { ip: IpAddr },
+}
+
+/// One row of the `list` response
+#[derive(Debug, Serialize)]
+pub struct ClientSummary {
+    pub id: Uuid,
+    pub addr: String,
+    pub session: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames_dropped: u64,
+    /// Bytes waiting in the client's queue
+    pub queued_bytes: u64,
+    /// Bytes charged to the client's address this quota window, when quotas are on
+    pub quota_used: Option<u64>,
+    /// Unix timestamp (seconds) of when the client connected
+    pub connected_since: u64,
+}
+
+/// One member of a session in the `stats` response
+#[derive(Debug, Serialize)]
+pub struct MemberSummary {
+    pub id: Uuid,
+    pub addr: String,
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub frames_dropped: u64,
+    pub queued_bytes: u64,
+}
+
+/// Relay state the admin interface can inspect and act on
+#[derive(Clone)]
+pub struct AdminContext {
+    pub clients: SharedRegistry,
+    pub stats: Arc<RelayStats>,
+    pub draining: Arc<AtomicBool>,
+    pub store_forward: Option<Arc<std::sync::Mutex<StoreForward>>>,
+    pub access: Arc<std::sync::RwLock<AccessList>>,
+    pub quota: Option<Arc<std::sync::Mutex<QuotaTracker>>>,
+    pub bans: Arc<std::sync::Mutex<BanList>>,
+    /// Permits for the slots held back for priority tokens
+    pub reserved: Arc<Semaphore>,
+    pub reserved_slots: usize,
+    pub budget: Arc<BufferBudget>,
+}
+
+impl AdminContext {
+    /// Run a command and build its JSON response
+    pub async fn execute(&self, command: AdminCommand) -> Value {
+        match command {
+            AdminCommand::List => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let clients = self.clients.read().await;
+                let quota = self.quota.as_ref().map(|quota| quota.lock().unwrap_or_else(|e| e.into_inner()));
+                let mut rows: Vec<ClientSummary> = clients
+                    .values()
+                    .map(|client| ClientSummary {
+                        id: client.id,
+                        addr: client.addr.to_string(),
+                        session: client.session.clone(),
+                        bytes_in: client.counters.bytes_in.load(Ordering::Relaxed),
+                        bytes_out: client.counters.bytes_out.load(Ordering::Relaxed),
+                        frames_dropped: client.counters.frames_dropped.load(Ordering::Relaxed),
+                        queued_bytes: client.counters.queued_bytes.load(Ordering::Relaxed),
+                        quota_used: quota.as_ref().map(|quota| quota.used(crate::ip_bucket(client.addr.ip()), Instant::now())),
+                        connected_since: now.saturating_sub(client.connected_at.elapsed()).as_secs(),
+                    })
+                    .collect();
+                rows.sort_by_key(|row| row.connected_since);
+                json!({ "ok": true, "clients": rows })
+            }
+            AdminCommand::Kick { id } => {
+                let clients = self.clients.read().await;
+                match clients.get(&id) {
+                    Some(client) => {
+                        info!("Admin kicked client {} ({})", client.id, client.addr);
+                        client.kick.notify_one();
+                        json!({ "ok": true, "kicked": id })
+                    }
+                    None => json!({ "ok": false, "error": format!("No client with id {}", id) }),
+                }
+            }
+            AdminCommand::Stats { session: Some(session) } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let clients = self.clients.read().await;
+                let Some(counters) = clients.session_counters(&session) else {
+                    return json!({ "ok": false, "error": format!("No active session {}", session) });
+                };
+                let members: Vec<MemberSummary> = clients
+                    .session_members(&session)
+                    .map(|client| MemberSummary {
+                        id: client.id,
+                        addr: client.addr.to_string(),
+                        frames_in: client.counters.frames_in.load(Ordering::Relaxed),
+                        frames_out: client.counters.frames_out.load(Ordering::Relaxed),
+                        frames_dropped: client.counters.frames_dropped.load(Ordering::Relaxed),
+                        queued_bytes: client.counters.queued_bytes.load(Ordering::Relaxed),
+                    })
+                    .collect();
+                json!({
+                    "ok": true,
+                    "session": session,
+                    "metrics_label": session_label(&session),
+                    "created_at": now.saturating_sub(counters.created_at.elapsed()).as_secs(),
+                    "last_activity": now.saturating_sub(counters.idle_for()).as_secs(),
+                    "frames_in": counters.frames_in.load(Ordering::Relaxed),
+                    "frames_delivered": counters.frames_delivered.load(Ordering::Relaxed),
+                    "frames_dropped": counters.frames_dropped.load(Ordering::Relaxed),
+                    "frames_buffered": counters.frames_buffered.load(Ordering::Relaxed),
+                    "frames_forwarded": counters.frames_forwarded.load(Ordering::Relaxed),
+                    "members": members,
+                })
+            }
+            AdminCommand::Stats { session: None } => {
+                let active_clients = self.clients.read().await.len();
+                let buffered_bytes = self.store_forward.as_ref().map(|store| {
+                    store.lock().unwrap_or_else(|e| e.into_inner()).total_bytes()
+                });
+                json!({
+                    "ok": true,
+                    "active_clients": active_clients,
+                    "draining": self.draining.load(Ordering::Relaxed),
+                    "buffered_bytes": buffered_bytes,
+                    "queued_bytes": self.budget.queued(),
+                    "reserved_slots": self.reserved_slots,
+                    "reserved_slots_used": self.reserved_slots.saturating_sub(self.reserved.available_permits()),
+                    "counters": self.stats.snapshot(),
+                })
+            }
+            AdminCommand::Drain { enabled } => {
+                let enabled = enabled.unwrap_or(!self.draining.load(Ordering::Relaxed));
+                self.draining.store(enabled, Ordering::Relaxed);
+                info!("Admin {} accepting new clients", if enabled { "paused" } else { "resumed" });
+                json!({ "ok": true, "draining": enabled })
+            }
+            AdminCommand::Acl => {
+                let access = self.access.read().unwrap_or_else(|e| e.into_inner()).clone();
+                json!({ "ok": true, "allow": access.allow, "deny": access.deny })
+            }
+            AdminCommand::AclAdd { rule, cidr } => {
+                let added = self.access.write().unwrap_or_else(|e| e.into_inner()).insert(rule, cidr);
+                info!("Admin added {} to the {:?} list", cidr, rule);
+
+                // A new deny rule also disconnects clients already inside it
+                let mut kicked = 0;
+                if rule == AccessRule::Deny {
+                    for client in self.clients.read().await.values() {
+                        if cidr.contains(client.addr.ip()) {
+                            client.kick.notify_one();
+                            kicked += 1;
+                        }
+                    }
+                }
+                json!({ "ok": true, "added": added, "kicked": kicked })
+            }
+            AdminCommand::AclRemove { rule, cidr } => {
+                let removed = self.access.write().unwrap_or_else(|e| e.into_inner()).remove(rule, cidr);
+                info!("Admin removed {} from the {:?} list", cidr, rule);
+                json!({ "ok": true, "removed": removed })
+            }
+            AdminCommand::Bans => {
+                let bans = self.bans.lock().unwrap_or_else(|e| e.into_inner()).banned(Instant::now());
+                let rows: Vec<Value> = bans
+                    .into_iter()
+                    .map(|(ip, left)| json!({ "ip": ip, "remaining_secs": left.as_secs() }))
+                    .collect();
+                json!({ "ok": true, "bans": rows })
+            }
+            AdminCommand::Unban { ip } => {
+                let key = crate::ip_bucket(ip);
+                let unbanned = self.bans.lock().unwrap_or_else(|e| e.into_inner()).unban(key, Instant::now());
+                if unbanned {
+                    info!("Admin lifted the ban on {}", key);
+                }
+                json!({ "ok": true, "unbanned": unbanned })
+            }
+        }
+    }
+}
+
+/// Bind the admin socket, readable and writable by the relay's own user only
+pub fn bind_admin_socket(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // Replace a stale socket from a previous run, but never clobber other files
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accept admin connections until shutdown
+pub async fn serve_admin(listener: UnixListener, context: AdminContext, shutdown: CancellationToken) {
+    let relay_uid = socket_owner_uid(&listener);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+
+        match accepted {
+            Ok((stream, _)) => {
+                if !peer_is_trusted(&stream, relay_uid) {
+                    warn!("Refusing admin connection from untrusted user");
+                    continue;
+                }
+
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_admin_connection(stream, context).await {
+                        debug!("Admin connection ended: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept admin connection: {}", e),
+        }
+    }
+}
+
+/// Uid owning the admin socket file (the relay's own user)
+fn socket_owner_uid(listener: &UnixListener) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = listener.local_addr().ok()?.as_pathname()?.to_path_buf();
+    std::fs::metadata(path).ok().map(|m| m.uid())
+}
+
+/// Only root and the relay's own user may drive the admin interface
+fn peer_is_trusted(stream: &UnixStream, relay_uid: Option<u32>) -> bool {
+    match (stream.peer_cred(), relay_uid) {
+        (Ok(cred), Some(uid)) => cred.uid() == 0 || cred.uid() == uid,
+        _ => false,
+    }
+}
+
+async fn handle_admin_connection(stream: UnixStream, context: AdminContext) -> std::io::Result<()> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    loop {
+        let mut line = String::new();
+        let read = (&mut reader).take(MAX_COMMAND_LEN as u64).read_line(&mut line).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && read >= MAX_COMMAND_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Admin command too long"));
+        }
+
+        let response = match serde_json::from_str::<AdminCommand>(line.trim_end()) {
+            Ok(command) => context.execute(command).await,
+            Err(e) => json!({ "ok": false, "error": format!("Invalid command: {}", e) }),
+        };
+
+        let mut encoded = response.to_string();
+        encoded.push('\n');
+        write_half.write_all(encoded.as_bytes()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_parsing() {
+        assert_eq!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"list"}"#).unwrap(), AdminCommand::List);
+        assert_eq!(
+            serde_json::from_str::<AdminCommand>(r#"{"cmd":"drain","enabled":true}"#).unwrap(),
+            AdminCommand::Drain { enabled: Some(true) }
+        );
+        assert_eq!(
+            serde_json::from_str::<AdminCommand>(r#"{"cmd":"drain"}"#).unwrap(),
+            AdminCommand::Drain { enabled: None }
+        );
+        assert_eq!(
+            serde_json::from_str::<AdminCommand>(r#"{"cmd":"stats","session":"abc"}"#).unwrap(),
+            AdminCommand::Stats { session: Some("abc".to_string()) }
+        );
+        assert_eq!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"stats"}"#).unwrap(), AdminCommand::Stats { session: None });
+        assert!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"kick","id":"nope"}"#).is_err());
+        assert!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"reboot"}"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<AdminCommand>(r#"{"cmd":"acl_add","rule":"deny","cidr":"10.0.0.0/8"}"#).unwrap(),
+            AdminCommand::AclAdd { rule: AccessRule::Deny, cidr: "10.0.0.0/8".parse().unwrap() }
+        );
+        assert!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"acl_add","rule":"deny","cidr":"10/99"}"#).is_err());
+        assert_eq!(serde_json::from_str::<AdminCommand>(r#"{"cmd":"bans"}"#).unwrap(), AdminCommand::Bans);
+        assert_eq!(
+            serde_json::from_str::<AdminCommand>(r#"{"cmd":"unban","ip":"2001:db8::1"}"#).unwrap(),
+            AdminCommand::Unban { ip: "2001:db8::1".parse().unwrap() }
+        );
+    }
+}
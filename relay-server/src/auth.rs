@@ -0,0 +1,75 @@
+// Client auth tokens, presented in the join frame
+//
+// With --auth-token set every client has to join with one of those tokens.
+// Tokens listed with --priority-token are always accepted and also let the
+// client into the slots --reserved-slots holds back, so the operator's own
+// clients still get in while the public pool is full.
+
+/// Which client slots a join's token gives access to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+    Public,
+    /// May also take a reserved slot
+    Priority,
+}
+
+#[derive(Debug, Default)]
+pub struct TokenAuth {
+    tokens: Vec<String>,
+    priority: Vec<String>,
+}
+
+impl TokenAuth {
+    pub fn new(tokens: Vec<String>, priority: Vec<String>) -> Self {
+        Self { tokens, priority }
+    }
+
+    /// Class of client a join's token admits, or None when the join is
+    /// refused: an unknown token, or none at all while tokens are required
+    pub fn check(&self, token: Option<&str>) -> Option<TokenClass> {
+        let Some(token) = token else {
+            return self.tokens.is_empty().then_some(TokenClass::Public);
+        };
+        if contains(&self.priority, token) {
+            Some(TokenClass::Priority)
+        } else if contains(&self.tokens, token) {
+            Some(TokenClass::Public)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compare against every token in full, so response times don't tell how
+/// close a guess came
+fn contains(tokens: &[String], candidate: &str) -> bool {
+    tokens.iter().fold(false, |found, token| found | constant_time_eq(token.as_bytes(), candidate.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_decide_the_slot_class() {
+        let auth = TokenAuth::new(vec!["member".to_string()], vec!["owner".to_string()]);
+        assert_eq!(auth.check(Some("member")), Some(TokenClass::Public));
+        assert_eq!(auth.check(Some("owner")), Some(TokenClass::Priority));
+        assert_eq!(auth.check(Some("membe")), None);
+        assert_eq!(auth.check(None), None, "tokens are required once any are configured");
+    }
+
+    #[test]
+    fn test_open_relay_only_checks_priority_tokens() {
+        let auth = TokenAuth::new(Vec::new(), vec!["owner".to_string()]);
+        assert_eq!(auth.check(None), Some(TokenClass::Public));
+        assert_eq!(auth.check(Some("owner")), Some(TokenClass::Priority));
+        assert_eq!(auth.check(Some("guess")), None, "a wrong token is refused rather than ignored");
+
+        assert_eq!(TokenAuth::default().check(None), Some(TokenClass::Public));
+    }
+}
@@ -0,0 +1,280 @@
+// Relay-wide cap on bytes queued for delivery
+//
+// Each client queue is bounded in frames, but thousands of queues full of
+// large frames still add up. Every frame queued for a client counts against
+// one budget until that client's writer takes it. Past the soft limit,
+// clients whose frames are piling up in a recipient's queue stop being read
+// from until it drains, while those whose peers keep up carry on; past the
+// hard limit new frames are dropped and the client with the largest backlog
+// is disconnected.
+
+use bytes::Bytes;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::protocol::RejectReason;
+use crate::registry::ClientRegistry;
+use crate::stats::ClientCounters;
+
+/// What a client's writer is told to do next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundItem {
+    /// An encoded frame to write as is; only these count against the budget
+    Frame(Bytes),
+    /// Say goodbye with a reason and close the connection
+    Goodbye(RejectReason),
+    /// Check the client is still there
+    Ping,
+}
+
+/// Why a frame could not be queued for a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The client's own queue is full
+    Full,
+    /// The client is going away
+    Closed,
+    /// Queuing it would go past the relay-wide hard limit
+    OverBudget,
+}
+
+/// Bytes queued across all clients, against a soft and a hard limit
+#[derive(Debug)]
+pub struct BufferBudget {
+    soft_limit: AtomicU64,
+    hard_limit: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl BufferBudget {
+    /// Budget with a soft limit of `limit` bytes and a hard limit half as
+    /// much again; 0 only tracks usage
+    pub fn new(limit: u64) -> Self {
+        let budget = Self { soft_limit: AtomicU64::new(0), hard_limit: AtomicU64::new(0), queued: AtomicU64::new(0) };
+        budget.set_limit(limit);
+        budget
+    }
+
+    /// Change the limits, e.g. on a config reload; frames already queued stay
+    pub fn set_limit(&self, limit: u64) {
+        let (soft_limit, hard_limit) = match limit {
+            0 => (u64::MAX, u64::MAX),
+            limit => (limit, limit.saturating_add(limit / 2)),
+        };
+        self.soft_limit.store(soft_limit, Ordering::Relaxed);
+        self.hard_limit.store(hard_limit, Ordering::Relaxed);
+    }
+
+    /// Bytes currently queued across all clients
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Queue a frame for a client, unless that would break the hard limit
+    pub fn try_queue(
+        &self,
+        sender: &mpsc::Sender<OutboundItem>,
+        counters: &ClientCounters,
+        data: Bytes,
+    ) -> Result<(), QueueError> {
+        let bytes = data.len() as u64;
+        let hard_limit = self.hard_limit.load(Ordering::Relaxed);
+        let reserved = self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+            (queued.saturating_add(bytes) <= hard_limit).then_some(queued + bytes)
+        });
+        if reserved.is_err() {
+            return Err(QueueError::OverBudget);
+        }
+        self.send(sender, counters, data, bytes)
+    }
+
+    /// Queue a frame regardless of the hard limit: notices for the client
+    /// and frames already held in memory elsewhere
+    pub fn queue(&self, sender: &mpsc::Sender<OutboundItem>, counters: &ClientCounters, data: Bytes) -> bool {
+        let bytes = data.len() as u64;
+        self.queued.fetch_add(bytes, Ordering::Relaxed);
+        self.send(sender, counters, data, bytes).is_ok()
+    }
+
+    /// Send bytes already added to the total, taking them back off on failure
+    fn send(
+        &self,
+        sender: &mpsc::Sender<OutboundItem>,
+        counters: &ClientCounters,
+        data: Bytes,
+        bytes: u64,
+    ) -> Result<(), QueueError> {
+        counters.queued_bytes.fetch_add(bytes, Ordering::Relaxed);
+        sender.try_send(OutboundItem::Frame(data)).map_err(|e| {
+            self.release(counters, bytes);
+            match e {
+                mpsc::error::TrySendError::Full(_) => QueueError::Full,
+                mpsc::error::TrySendError::Closed(_) => QueueError::Closed,
+            }
+        })
+    }
+
+    /// A queued frame left the queue, written or not
+    fn release(&self, counters: &ClientCounters, bytes: u64) {
+        self.queued.fetch_sub(bytes, Ordering::Relaxed);
+        counters.queued_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Whether a client feeding a recipient with `backlog` bytes queued
+    /// should hold off: the relay is over the soft limit and the recipient
+    /// isn't keeping up
+    pub fn should_pause(&self, backlog: u64) -> bool {
+        backlog > 0 && self.queued() >= self.soft_limit.load(Ordering::Relaxed)
+    }
+}
+
+/// Receiving end of a client queue; frames stop counting against the budget
+/// as they are taken, or when the queue is dropped with frames still in it
+pub struct ClientQueue {
+    receiver: mpsc::Receiver<OutboundItem>,
+    counters: Arc<ClientCounters>,
+    budget: Arc<BufferBudget>,
+}
+
+impl ClientQueue {
+    pub fn new(receiver: mpsc::Receiver<OutboundItem>, counters: Arc<ClientCounters>, budget: Arc<BufferBudget>) -> Self {
+        Self { receiver, counters, budget }
+    }
+
+    pub async fn recv(&mut self) -> Option<OutboundItem> {
+        let item = self.receiver.recv().await?;
+        self.release(&item);
+        Some(item)
+    }
+
+    fn release(&self, item: &OutboundItem) {
+        if let OutboundItem::Frame(data) = item {
+            self.budget.release(&self.counters, data.len() as u64);
+        }
+    }
+}
+
+impl Drop for ClientQueue {
+    fn drop(&mut self) {
+        // Closing first means nothing can be queued after the drain
+        self.receiver.close();
+        while let Ok(item) = self.receiver.try_recv() {
+            self.release(&item);
+        }
+    }
+}
+
+/// Disconnect the client with the largest backlog to get back under the
+/// hard limit, unless it is already on its way out
+pub fn shed_heaviest_client(clients: &ClientRegistry) -> Option<Uuid> {
+    let heaviest = clients
+        .values()
+        .max_by_key(|client| client.counters.queued_bytes.load(Ordering::Relaxed))?;
+    if heaviest.counters.queued_bytes.load(Ordering::Relaxed) == 0
+        || heaviest.counters.overloaded.swap(true, Ordering::Relaxed)
+    {
+        return None;
+    }
+    warn!("Relay buffers over the hard limit, disconnecting client {} ({}) with {} bytes queued",
+          heaviest.id, heaviest.addr, heaviest.counters.queued_bytes.load(Ordering::Relaxed));
+    heaviest.kick.notify_one();
+    Some(heaviest.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queued_bytes_are_released_as_frames_leave() {
+        let budget = Arc::new(BufferBudget::new(1000));
+        let counters = Arc::new(ClientCounters::default());
+        let (sender, receiver) = mpsc::channel(8);
+        let mut queue = ClientQueue::new(receiver, Arc::clone(&counters), Arc::clone(&budget));
+
+        budget.try_queue(&sender, &counters, Bytes::from(vec![0; 300])).unwrap();
+        budget.try_queue(&sender, &counters, Bytes::from(vec![0; 200])).unwrap();
+        assert_eq!(budget.queued(), 500);
+        assert_eq!(counters.queued_bytes.load(Ordering::Relaxed), 500);
+
+        assert_eq!(queue.recv().await.unwrap(), OutboundItem::Frame(Bytes::from(vec![0; 300])));
+        assert_eq!(budget.queued(), 200);
+
+        // Commands for the writer carry no bytes
+        sender.try_send(OutboundItem::Ping).unwrap();
+        sender.try_send(OutboundItem::Goodbye(RejectReason::Draining)).unwrap();
+        assert_eq!(budget.queued(), 200);
+
+        // Whatever is still queued is released when the queue goes away
+        drop(queue);
+        assert_eq!(budget.queued(), 0);
+        assert_eq!(counters.queued_bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(budget.try_queue(&sender, &counters, Bytes::from(vec![0; 10])), Err(QueueError::Closed));
+        assert_eq!(budget.queued(), 0);
+    }
+
+    #[test]
+    fn test_hard_limit_refuses_frames_but_not_notices() {
+        let budget = BufferBudget::new(1000);
+        let counters = ClientCounters::default();
+        let (sender, _receiver) = mpsc::channel(8);
+
+        budget.try_queue(&sender, &counters, Bytes::from(vec![0; 1400])).unwrap();
+        assert_eq!(budget.try_queue(&sender, &counters, Bytes::from(vec![0; 101])), Err(QueueError::OverBudget));
+        assert_eq!(budget.queued(), 1400);
+        assert!(budget.queue(&sender, &counters, Bytes::from(vec![0; 101])));
+        assert_eq!(budget.queued(), 1501);
+    }
+
+    #[test]
+    fn test_only_senders_feeding_a_backlog_pause() {
+        let budget = BufferBudget::new(1000);
+        let counters = ClientCounters::default();
+        let (sender, _receiver) = mpsc::channel(8);
+
+        budget.try_queue(&sender, &counters, Bytes::from(vec![0; 700])).unwrap();
+        assert!(!budget.should_pause(700), "under the soft limit nobody waits");
+
+        budget.try_queue(&sender, &counters, Bytes::from(vec![0; 300])).unwrap();
+        assert!(budget.should_pause(1000));
+        assert!(!budget.should_pause(0), "recipients that keep up don't hold their senders back");
+
+        assert!(!BufferBudget::new(0).should_pause(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_heaviest_client_is_shed_once() {
+        use crate::ClientInfo;
+        use std::time::Instant;
+        use tokio::sync::Notify;
+
+        let budget = BufferBudget::new(1000);
+        let mut registry = ClientRegistry::default();
+        let mut receivers = Vec::new();
+        for backlog in [100, 600, 0] {
+            let (sender, receiver) = mpsc::channel(8);
+            let counters = Arc::new(ClientCounters::default());
+            budget.queue(&sender, &counters, Bytes::from(vec![0; backlog]));
+            receivers.push(receiver);
+            registry.insert(ClientInfo {
+                id: Uuid::new_v4(),
+                addr: "127.0.0.1:9".parse().unwrap(),
+                session: "room".to_string(),
+                sender,
+                connected_at: Instant::now(),
+                max_message_size: 65536,
+                counters,
+                kick: Arc::new(Notify::new()),
+            });
+        }
+
+        let shed = shed_heaviest_client(&registry).unwrap();
+        let heaviest = registry.get(&shed).unwrap();
+        assert_eq!(heaviest.counters.queued_bytes.load(Ordering::Relaxed), 600);
+        assert!(heaviest.counters.overloaded.load(Ordering::Relaxed));
+        assert_eq!(shed_heaviest_client(&registry), None, "already on its way out");
+    }
+}
@@ -0,0 +1,220 @@
+// Temporary bans for addresses that keep breaking the protocol
+//
+// Every protocol violation (garbage instead of a join frame, a hello that
+// never arrives, oversized or malformed frames, a failed TLS handshake) is a
+// strike against the sender's address bucket. Strikes older than the window
+// no longer count, so only a burst of them reaches the threshold, after which
+// the address is refused at accept time until the ban runs out. The table
+// holds a bounded number of addresses and forgets the least recently struck
+// first.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BanLimits {
+    /// Strikes within the window that get an address banned (0 disables bans)
+    pub strikes: u32,
+    pub window: Duration,
+    /// How long a ban lasts
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// When the strikes still within the window happened, oldest first
+    strikes: VecDeque<Instant>,
+    last_strike: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Strikes and bans per address
+#[derive(Debug)]
+pub struct BanList {
+    limits: BanLimits,
+    capacity: usize,
+    entries: HashMap<IpAddr, Entry>,
+}
+
+impl BanList {
+    pub fn new(limits: BanLimits, capacity: usize) -> Self {
+        Self { limits, capacity, entries: HashMap::new() }
+    }
+
+    /// Apply new limits from a config reload; strikes and bans so far stay
+    pub fn set_limits(&mut self, limits: BanLimits) {
+        self.limits = limits;
+    }
+
+    /// Count a violation against an address; returns the ban's length when
+    /// this strike gets the address banned
+    pub fn strike(&mut self, key: IpAddr, now: Instant) -> Option<Duration> {
+        if self.limits.strikes == 0 {
+            return None;
+        }
+        if !self.entries.contains_key(&key) {
+            self.make_room(now);
+        }
+        let limits = self.limits;
+        let entry = self.entries.entry(key).or_insert_with(|| Entry {
+            strikes: VecDeque::new(),
+            last_strike: now,
+            banned_until: None,
+        });
+        if entry.banned_until.is_some_and(|until| now < until) {
+            return None;
+        }
+
+        while entry.strikes.front().is_some_and(|at| now.duration_since(*at) >= limits.window) {
+            entry.strikes.pop_front();
+        }
+        entry.strikes.push_back(now);
+        entry.last_strike = now;
+        if entry.strikes.len() < limits.strikes as usize {
+            return None;
+        }
+        entry.strikes.clear();
+        entry.banned_until = Some(now + limits.duration);
+        Some(limits.duration)
+    }
+
+    /// Whether an address is banned right now
+    pub fn is_banned(&self, key: IpAddr, now: Instant) -> bool {
+        self.entries
+            .get(&key)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Banned addresses with the time their ban has left, longest first
+    pub fn banned(&self, now: Instant) -> Vec<(IpAddr, Duration)> {
+        let mut bans: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let until = entry.banned_until.filter(|until| now < *until)?;
+                Some((*key, until - now))
+            })
+            .collect();
+        bans.sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+        bans
+    }
+
+    /// Lift an address's ban and forget its strikes; false if it wasn't banned
+    pub fn unban(&mut self, key: IpAddr, now: Instant) -> bool {
+        let banned = self.is_banned(key, now);
+        self.entries.remove(&key);
+        banned
+    }
+
+    /// Make space for one more address: drop those with nothing left to
+    /// remember, then the least recently struck if that isn't enough
+    fn make_room(&mut self, now: Instant) {
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let window = self.limits.window;
+        self.entries.retain(|_, entry| {
+            entry.banned_until.is_some_and(|until| now < until) || now.duration_since(entry.last_strike) < window
+        });
+        while self.entries.len() >= self.capacity.max(1) {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, entry)| entry.last_strike).map(|(key, _)| *key) else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bans(strikes: u32, window_secs: u64, duration_secs: u64, capacity: usize) -> BanList {
+        BanList::new(
+            BanLimits {
+                strikes,
+                window: Duration::from_secs(window_secs),
+                duration: Duration::from_secs(duration_secs),
+            },
+            capacity,
+        )
+    }
+
+    #[test]
+    fn test_strikes_within_window_ban_until_expiry() {
+        let mut list = bans(3, 60, 300, 16);
+        let start = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(list.strike(a, start).is_none());
+        assert!(list.strike(a, start + Duration::from_secs(1)).is_none());
+        assert!(list.strike(b, start).is_none(), "other addresses have their own strikes");
+        assert!(!list.is_banned(a, start));
+        assert!(list.strike(a, start + Duration::from_secs(2)).is_some());
+        assert!(list.is_banned(a, start + Duration::from_secs(2)));
+        assert!(!list.is_banned(b, start + Duration::from_secs(2)));
+        assert_eq!(list.banned(start + Duration::from_secs(2)), vec![(a, Duration::from_secs(300))]);
+
+        let expired = start + Duration::from_secs(303);
+        assert!(!list.is_banned(a, expired));
+        assert!(list.banned(expired).is_empty());
+        assert!(list.strike(a, expired).is_none(), "strikes start over after a ban");
+    }
+
+    #[test]
+    fn test_strikes_outside_window_stop_counting() {
+        let mut list = bans(3, 60, 300, 16);
+        let start = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // One strike every half window never has three inside it
+        for i in 0..10 {
+            assert!(list.strike(a, start + Duration::from_secs(30 * i)).is_none());
+        }
+        assert!(!list.is_banned(a, start + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_unban_and_disabled_bans() {
+        let mut list = bans(1, 60, 300, 16);
+        let now = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(list.strike(a, now).is_some());
+        assert!(list.unban(a, now));
+        assert!(!list.is_banned(a, now));
+        assert!(!list.unban(a, now), "nothing left to lift");
+
+        let mut disabled = bans(0, 60, 300, 16);
+        for _ in 0..100 {
+            assert!(disabled.strike(a, now).is_none());
+        }
+        assert!(disabled.entries.is_empty());
+    }
+
+    #[test]
+    fn test_table_is_bounded_and_forgets_least_recent() {
+        let mut list = bans(2, 60, 300, 4);
+        let start = Instant::now();
+        let addr = |i: u8| IpAddr::from([198, 51, 100, i]);
+
+        assert!(list.strike(addr(0), start).is_none());
+        assert!(list.strike(addr(0), start).is_some());
+        for i in 1..=10u8 {
+            list.strike(addr(i), start + Duration::from_secs(i as u64));
+            assert!(list.entries.len() <= 4);
+        }
+        // The ban went with the least recently struck address, the newest stayed
+        assert!(!list.is_banned(addr(0), start + Duration::from_secs(10)));
+        assert!(list.entries.contains_key(&addr(10)));
+        assert!(!list.entries.contains_key(&addr(1)));
+
+        // Entries with nothing left to remember go first
+        let later = start + Duration::from_secs(200);
+        list.strike(addr(20), later);
+        assert_eq!(list.entries.len(), 1);
+    }
+}
@@ -0,0 +1,370 @@
+// Relay configuration file - TOML counterpart of the command line options
+//
+// Every key is optional and named after its flag (`max_clients` for
+// `--max-clients`). Values are merged with precedence
+// command line > environment > config file > built-in defaults.
+//
+// On SIGHUP the file is read again and the result swapped in whole; limits
+// apply to decisions made from then on, while settings tied to sockets and
+// tasks set up at startup keep their old values until a restart.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use clap::{ArgMatches, FromArgMatches};
+use clap::parser::ValueSource;
+use serde::{Deserialize, Serialize};
+
+use crate::Args;
+use crate::acl::Cidr;
+use crate::logging::LogFormat;
+use crate::quota::QuotaAction;
+
+/// Options that can be set from a config file
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clients: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clients_per_ip: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_slots: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority_token: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hello_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_read_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drain_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<LogFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_socket: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_session_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_datagram_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_cidr: Option<Vec<Cidr>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deny_cidr: Option<Vec<Cidr>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_bind_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_saturation: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_forward_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_forward_max_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_forward_total_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_action: Option<QuotaAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_strikes: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_buffered_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub federation_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_relay: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub federation_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub federation_max_hops: Option<u8>,
+}
+
+impl RelayConfig {
+    /// Parse a config file, naming the offending key on failure
+    pub fn from_toml(text: &str, origin: &Path) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| {
+            let location = match e.span().and_then(|span| offending_key(text, span.start)) {
+                Some(key) => format!("key `{}`", key),
+                None => "syntax".to_string(),
+            };
+            format!("Invalid config file {} ({}): {}", origin.display(), location, e.message())
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        Self::from_toml(&text, path)
+    }
+
+    /// Snapshot of the effective settings, for `--print-config`
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            port: Some(args.port),
+            bind_address: Some(args.bind_address.clone()),
+            listen: Some(args.listen.clone()),
+            proxy_protocol: Some(args.proxy_protocol),
+            max_clients: Some(args.max_clients),
+            max_clients_per_ip: Some(args.max_clients_per_ip),
+            max_message_size: Some(args.max_message_size),
+            reserved_slots: Some(args.reserved_slots),
+            auth_token: Some(args.auth_token.clone()),
+            priority_token: Some(args.priority_token.clone()),
+            hello_timeout: Some(args.hello_timeout),
+            idle_timeout: Some(args.idle_timeout),
+            client_read_timeout: Some(args.client_read_timeout),
+            ping_interval: Some(args.ping_interval),
+            drain_timeout: Some(args.drain_timeout),
+            stats_interval: Some(args.stats_interval),
+            log_format: Some(args.log_format),
+            tls_cert: args.tls_cert.clone(),
+            tls_key: args.tls_key.clone(),
+            admin_socket: args.admin_socket.clone(),
+            udp_port: args.udp_port,
+            udp_session_ttl: Some(args.udp_session_ttl),
+            max_datagram_size: Some(args.max_datagram_size),
+            allow_cidr: Some(args.allow_cidr.clone()),
+            deny_cidr: Some(args.deny_cidr.clone()),
+            health_port: args.health_port,
+            health_bind_address: Some(args.health_bind_address.clone()),
+            health_saturation: Some(args.health_saturation),
+            store_forward_ttl: Some(args.store_forward_ttl),
+            store_forward_max_bytes: Some(args.store_forward_max_bytes),
+            store_forward_total_bytes: Some(args.store_forward_total_bytes),
+            quota_bytes: Some(args.quota_bytes),
+            quota_window: Some(args.quota_window),
+            quota_action: Some(args.quota_action),
+            ban_strikes: Some(args.ban_strikes),
+            ban_window: Some(args.ban_window),
+            ban_duration: Some(args.ban_duration),
+            max_buffered_bytes: Some(args.max_buffered_bytes),
+            federation_port: args.federation_port,
+            peer_relay: Some(args.peer_relay.clone()),
+            federation_secret: args.federation_secret.clone(),
+            federation_max_hops: Some(args.federation_max_hops),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("relay config is always representable as TOML")
+    }
+
+    /// Fill in every option the command line and environment left unset
+    fn apply_to(self, args: &mut Args, matches: &ArgMatches) {
+        let explicit = |id: &str| {
+            matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+        };
+
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field {
+                    if !explicit(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            )*};
+        }
+        macro_rules! merge_optional {
+            ($($field:ident),* $(,)?) => {$(
+                if self.$field.is_some() && !explicit(stringify!($field)) {
+                    args.$field = self.$field;
+                }
+            )*};
+        }
+
+        merge!(
+            port, bind_address, listen, proxy_protocol, max_clients, max_clients_per_ip, max_message_size,
+            reserved_slots, auth_token, priority_token,
+            hello_timeout, idle_timeout, client_read_timeout, ping_interval, drain_timeout, stats_interval, log_format,
+            health_bind_address, health_saturation, allow_cidr, deny_cidr,
+            udp_session_ttl, max_datagram_size,
+            store_forward_ttl, store_forward_max_bytes, store_forward_total_bytes,
+            quota_bytes, quota_window, quota_action, ban_strikes, ban_window, ban_duration, max_buffered_bytes,
+            peer_relay, federation_max_hops,
+        );
+        merge_optional!(tls_cert, tls_key, admin_socket, health_port, udp_port, federation_port, federation_secret);
+    }
+}
+
+/// Build the effective arguments from parsed matches and the optional config file
+pub fn resolve_args(matches: &ArgMatches) -> Result<Args, Box<dyn std::error::Error>> {
+    let mut args = Args::from_arg_matches(matches)?;
+    if let Some(path) = args.config.clone() {
+        RelayConfig::load(&path)?.apply_to(&mut args, matches);
+    }
+    Ok(args)
+}
+
+/// Effective settings, replaced as a whole when the config file is reloaded
+pub struct LiveConfig {
+    current: RwLock<Arc<Args>>,
+}
+
+impl LiveConfig {
+    pub fn new(args: Args) -> Self {
+        Self { current: RwLock::new(Arc::new(args)) }
+    }
+
+    /// Settings as of now; hold on to the snapshot for one decision, not longer
+    pub fn current(&self) -> Arc<Args> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn replace(&self, args: Args) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(args);
+    }
+}
+
+/// Merge freshly loaded settings into the running ones: everything that only
+/// takes effect at startup keeps its current value, and the names of those
+/// that changed anyway are returned so they can be reported
+pub fn reloaded(current: &Args, mut new: Args) -> (Args, Vec<&'static str>) {
+    let mut needs_restart = Vec::new();
+
+    macro_rules! keep {
+        ($($field:ident),* $(,)?) => {$(
+            if new.$field != current.$field {
+                needs_restart.push(stringify!($field));
+                new.$field.clone_from(&current.$field);
+            }
+        )*};
+    }
+    keep!(
+        port, bind_address, listen, proxy_protocol, reserved_slots, tls_cert, tls_key, admin_socket, udp_port, udp_session_ttl, max_datagram_size,
+        health_port, health_bind_address, health_saturation, idle_timeout, stats_interval, log_format,
+        federation_port, peer_relay, federation_secret, federation_max_hops,
+    );
+
+    // Quotas and store-and-forward can be retuned but not switched on or off
+    if (new.quota_bytes == 0) != (current.quota_bytes == 0) {
+        needs_restart.push("quota_bytes");
+        new.quota_bytes = current.quota_bytes;
+    }
+    if (new.store_forward_ttl == 0) != (current.store_forward_ttl == 0) {
+        needs_restart.push("store_forward_ttl");
+        new.store_forward_ttl = current.store_forward_ttl;
+    }
+
+    (new, needs_restart)
+}
+
+/// Key on the line containing `offset`, if that line is a `key = value` pair
+fn offending_key(text: &str, offset: usize) -> Option<&str> {
+    let line_start = text[..offset.min(text.len())].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[line_start..].lines().next()?;
+    let (key, _) = line.split_once('=')?;
+    let key = key.trim();
+    (!key.is_empty()).then_some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn write_config(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("silence-relay-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn resolve(argv: &[&str]) -> Args {
+        let mut full = vec!["silence-relay"];
+        full.extend_from_slice(argv);
+        resolve_args(&Args::command().get_matches_from(full)).unwrap()
+    }
+
+    #[test]
+    fn test_file_overrides_defaults_and_cli_overrides_file() {
+        let path = write_config("port = 9000\nmax_clients = 7\nadmin_socket = \"/run/relay.sock\"\n");
+        let config = path.to_str().unwrap();
+
+        let args = resolve(&["--config", config]);
+        assert_eq!(args.port, 9000);
+        assert_eq!(args.max_clients, 7);
+        assert_eq!(args.admin_socket, Some(PathBuf::from("/run/relay.sock")));
+        assert_eq!(args.max_message_size, 65536, "unset keys keep their defaults");
+
+        let args = resolve(&["--config", config, "--port", "9100"]);
+        assert_eq!(args.port, 9100);
+        assert_eq!(args.max_clients, 7);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let path = write_config("drain_timeout = 3\n");
+        std::env::set_var("DRAIN_TIMEOUT", "7");
+        let args = resolve(&["--config", path.to_str().unwrap()]);
+        std::env::remove_var("DRAIN_TIMEOUT");
+
+        assert_eq!(args.drain_timeout, 7);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_file_names_the_key() {
+        let origin = Path::new("relay.toml");
+
+        let error = RelayConfig::from_toml("port = 8080\nmax_clients = \"lots\"\n", origin).unwrap_err();
+        assert!(error.contains("relay.toml"), "{}", error);
+        assert!(error.contains("key `max_clients`"), "{}", error);
+
+        let error = RelayConfig::from_toml("max_clinets = 5\n", origin).unwrap_err();
+        assert!(error.contains("key `max_clinets`"), "{}", error);
+        assert!(error.contains("unknown field"), "{}", error);
+    }
+
+    #[test]
+    fn test_reload_keeps_startup_only_settings() {
+        let current = resolve(&["--port", "9000", "--max-clients", "10", "--quota-bytes", "1000"]);
+        let new = resolve(&["--port", "9001", "--max-clients", "20", "--quota-bytes", "0", "--quota-window", "60"]);
+
+        let (merged, needs_restart) = reloaded(&current, new);
+        assert_eq!(merged.max_clients, 20);
+        assert_eq!(merged.quota_window, 60);
+        assert_eq!(merged.port, 9000);
+        assert_eq!(merged.quota_bytes, 1000, "quotas can't be switched off without a restart");
+        assert_eq!(needs_restart, vec!["port", "quota_bytes"]);
+    }
+
+    #[test]
+    fn test_printed_config_round_trips() {
+        let args = resolve(&["--port", "9200", "--tls-cert", "a.pem", "--tls-key", "b.pem"]);
+        let printed = RelayConfig::from_args(&args).to_toml();
+        assert_eq!(RelayConfig::from_toml(&printed, Path::new("printed")).unwrap(), RelayConfig::from_args(&args));
+        assert!(printed.contains("port = 9200"));
+        assert!(!printed.contains("admin_socket"));
+    }
+}
@@ -0,0 +1,728 @@
+// Federation between relays, so the members of a session can sit on different relays
+//
+// Relays link up over a dedicated port and prove to each other that they
+// know a shared secret. Each relay announces the sessions it has local
+// members in; announcements are passed on up to a hop limit, and every
+// relay remembers which link each announcement arrived on. Frames for a
+// session are then sent once to every relay announcing it, along the
+// shortest known route.
+//
+// Each forwarded announcement is later matched by exactly one leave along
+// the same path (links are ordered), so redundant links and loops in the
+// mesh can't leave stale routes behind. Announcements that come back to
+// their origin are dropped.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{ClientHandler, ClientInfo, queue_frame};
+use crate::backpressure::{self, BufferBudget};
+use crate::protocol::{MAX_SESSION_CODE_LEN, decode_session_code};
+use crate::registry::SharedRegistry;
+use crate::stats::RelayStats;
+
+type HmacSha256 = Hmac<Sha256>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Domain separation for the link authentication MAC
+const AUTH_LABEL: &[u8] = b"silence-federation-v1";
+
+/// Time a new link has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause between attempts to reach a configured peer relay
+const PEER_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Messages queued per link; must hold the announcements sent when a link comes up
+const LINK_QUEUE_CAPACITY: usize = 4096;
+
+/// Largest link message header: type, relay id, hop count and session code
+const LINK_HEADER_MAX_LEN: usize = 1 + 16 + 1 + 1 + MAX_SESSION_CODE_LEN;
+
+const MSG_HELLO: u8 = 0x01;
+const MSG_AUTH: u8 = 0x02;
+const MSG_JOIN: u8 = 0x03;
+const MSG_LEAVE: u8 = 0x04;
+const MSG_FRAME: u8 = 0x05;
+
+/// A relay with local members in a session, `hops` links away from the receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Announcement {
+    origin: Uuid,
+    hops: u8,
+    session: String,
+}
+
+impl Announcement {
+    /// The same announcement as passed on over one more link
+    fn next_hop(&self) -> Self {
+        Self { hops: self.hops + 1, ..self.clone() }
+    }
+}
+
+/// Messages exchanged over a federation link
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LinkMessage {
+    /// Opens the handshake: the sender's relay id and a fresh challenge
+    Hello { relay_id: Uuid, nonce: [u8; 16] },
+    /// Answers the peer's challenge
+    Auth { mac: Vec<u8> },
+    Join(Announcement),
+    Leave(Announcement),
+    /// A client frame for the members of `session` on relay `target`
+    Frame { target: Uuid, hops: u8, session: String, data: Vec<u8> },
+}
+
+impl LinkMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            LinkMessage::Hello { relay_id, nonce } => {
+                data.push(MSG_HELLO);
+                data.extend_from_slice(relay_id.as_bytes());
+                data.extend_from_slice(nonce);
+            }
+            LinkMessage::Auth { mac } => {
+                data.push(MSG_AUTH);
+                data.extend_from_slice(mac);
+            }
+            LinkMessage::Join(announcement) | LinkMessage::Leave(announcement) => {
+                data.push(if matches!(self, LinkMessage::Join(_)) { MSG_JOIN } else { MSG_LEAVE });
+                data.extend_from_slice(announcement.origin.as_bytes());
+                data.push(announcement.hops);
+                data.push(announcement.session.len() as u8);
+                data.extend_from_slice(announcement.session.as_bytes());
+            }
+            LinkMessage::Frame { target, hops, session, data: payload } => {
+                data.push(MSG_FRAME);
+                data.extend_from_slice(target.as_bytes());
+                data.push(*hops);
+                data.push(session.len() as u8);
+                data.extend_from_slice(session.as_bytes());
+                data.extend_from_slice(payload);
+            }
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (&kind, body) = data.split_first()?;
+        if kind == MSG_HELLO {
+            return Some(LinkMessage::Hello {
+                relay_id: Uuid::from_slice(body.get(..16)?).ok()?,
+                nonce: body.get(16..)?.try_into().ok()?,
+            });
+        }
+        if kind == MSG_AUTH {
+            return Some(LinkMessage::Auth { mac: body.to_vec() });
+        }
+
+        // The rest share a header: relay id, hop count, session code
+        let relay = Uuid::from_slice(body.get(..16)?).ok()?;
+        let hops = *body.get(16)?;
+        let session_len = *body.get(17)? as usize;
+        let session = decode_session_code(body.get(18..18 + session_len)?).ok()?;
+        let rest = &body[18 + session_len..];
+        match kind {
+            MSG_JOIN if rest.is_empty() => Some(LinkMessage::Join(Announcement { origin: relay, hops, session })),
+            MSG_LEAVE if rest.is_empty() => Some(LinkMessage::Leave(Announcement { origin: relay, hops, session })),
+            MSG_FRAME => Some(LinkMessage::Frame { target: relay, hops, session, data: rest.to_vec() }),
+            _ => None,
+        }
+    }
+}
+
+/// Read one length-prefixed link message; `None` on a clean close
+async fn read_link_message<R: AsyncRead + Unpin>(stream: &mut R, max_len: usize) -> Result<Option<LinkMessage>, BoxError> {
+    let length = match stream.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if length > max_len {
+        return Err(format!("link message of {} bytes exceeds limit {}", length, max_len).into());
+    }
+    let mut data = vec![0u8; length];
+    stream.read_exact(&mut data).await?;
+    LinkMessage::decode(&data).map(Some).ok_or_else(|| "malformed link message".into())
+}
+
+/// One way to reach a remote relay: over which link, and how many links away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Route {
+    link: u64,
+    hops: u8,
+}
+
+/// Routes to the remote relays with members in each session. Every
+/// announcement received counts, so each leave cancels exactly one join.
+#[derive(Debug, Default)]
+struct Routes {
+    sessions: HashMap<String, HashMap<Uuid, Vec<Route>>>,
+}
+
+impl Routes {
+    fn add(&mut self, session: &str, origin: Uuid, route: Route) {
+        self.sessions
+            .entry(session.to_string())
+            .or_default()
+            .entry(origin)
+            .or_default()
+            .push(route);
+    }
+
+    /// Remove one matching route; false if there was none
+    fn remove(&mut self, session: &str, origin: Uuid, route: Route) -> bool {
+        let Some(origins) = self.sessions.get_mut(session) else {
+            return false;
+        };
+        let Some(routes) = origins.get_mut(&origin) else {
+            return false;
+        };
+        let Some(index) = routes.iter().position(|r| *r == route) else {
+            return false;
+        };
+        routes.swap_remove(index);
+        if routes.is_empty() {
+            origins.remove(&origin);
+            if origins.is_empty() {
+                self.sessions.remove(session);
+            }
+        }
+        true
+    }
+
+    /// Remove every route over a link, returning what was learned over it
+    fn remove_link(&mut self, link: u64) -> Vec<Announcement> {
+        let mut removed = Vec::new();
+        for (session, origins) in &mut self.sessions {
+            for (origin, routes) in origins.iter_mut() {
+                routes.retain(|route| {
+                    if route.link == link {
+                        removed.push(Announcement { origin: *origin, hops: route.hops, session: session.clone() });
+                    }
+                    route.link != link
+                });
+            }
+            origins.retain(|_, routes| !routes.is_empty());
+        }
+        self.sessions.retain(|_, origins| !origins.is_empty());
+        removed
+    }
+
+    /// Shortest route to a relay for a session
+    fn best(&self, session: &str, origin: Uuid) -> Option<Route> {
+        self.sessions
+            .get(session)?
+            .get(&origin)?
+            .iter()
+            .min_by_key(|route| (route.hops, route.link))
+            .copied()
+    }
+
+    /// Remote relays with members in a session
+    fn origins<'a>(&'a self, session: &str) -> impl Iterator<Item = Uuid> + 'a {
+        self.sessions.get(session).into_iter().flat_map(|origins| origins.keys().copied())
+    }
+
+    fn has(&self, session: &str) -> bool {
+        self.sessions.contains_key(session)
+    }
+
+    /// Every route, as the announcement it was learned from
+    fn announcements(&self) -> impl Iterator<Item = Announcement> + '_ {
+        self.sessions.iter().flat_map(|(session, origins)| {
+            origins.iter().flat_map(move |(origin, routes)| {
+                routes.iter().map(move |route| Announcement { origin: *origin, hops: route.hops, session: session.clone() })
+            })
+        })
+    }
+}
+
+/// An authenticated link's outbound queue
+#[derive(Debug)]
+struct Link {
+    sender: mpsc::Sender<Vec<u8>>,
+    /// Cancelled to tear the link down
+    closed: CancellationToken,
+}
+
+impl Link {
+    /// Queue a join or leave. Losing one would leave the peer's routes
+    /// inconsistent, so a link too far behind to take it is closed instead.
+    fn send_control(&self, message: &LinkMessage) {
+        if self.sender.try_send(message.encode()).is_err() {
+            warn!("Federation link queue full, closing link");
+            self.closed.cancel();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    links: HashMap<u64, Link>,
+    next_link_id: u64,
+    routes: Routes,
+}
+
+impl LinkState {
+    /// Queue a join or leave on every link but one
+    fn send_control(&self, except: Option<u64>, message: &LinkMessage) {
+        for (id, link) in &self.links {
+            if Some(*id) != except {
+                link.send_control(message);
+            }
+        }
+    }
+}
+
+/// Federation settings
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    pub secret: Vec<u8>,
+    /// Links an announcement or frame may cross
+    pub max_hops: u8,
+    /// Largest client frame, which bounds link messages
+    pub max_message_size: usize,
+}
+
+/// This relay's view of the federation: its links and the routes learned over them
+pub struct Federation {
+    relay_id: Uuid,
+    config: FederationConfig,
+    clients: SharedRegistry,
+    stats: Arc<RelayStats>,
+    budget: Arc<BufferBudget>,
+    state: Mutex<LinkState>,
+}
+
+impl Federation {
+    pub fn new(
+        config: FederationConfig,
+        clients: SharedRegistry,
+        stats: Arc<RelayStats>,
+        budget: Arc<BufferBudget>,
+    ) -> Self {
+        Self {
+            relay_id: Uuid::new_v4(),
+            config,
+            clients,
+            stats,
+            budget,
+            state: Mutex::new(LinkState::default()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, LinkState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether another relay has announced members in a session
+    pub fn has_remote(&self, session: &str) -> bool {
+        self.state().routes.has(session)
+    }
+
+    /// Announce a session's first local member. Call with the client registry
+    /// write lock held so announcements keep the order of registrations.
+    pub fn local_joined(&self, session: &str) {
+        let announcement = Announcement { origin: self.relay_id, hops: 1, session: session.to_string() };
+        self.state().send_control(None, &LinkMessage::Join(announcement));
+    }
+
+    /// Withdraw a session whose last local member left, registry lock held
+    pub fn local_left(&self, session: &str) {
+        let announcement = Announcement { origin: self.relay_id, hops: 1, session: session.to_string() };
+        self.state().send_control(None, &LinkMessage::Leave(announcement));
+    }
+
+    /// Send a local client's frame to every relay with members in its
+    /// session; returns whether there were any
+    pub fn forward(&self, session: &str, data: &[u8]) -> bool {
+        let state = self.state();
+        let mut forwarded = false;
+        for target in state.routes.origins(session) {
+            forwarded = true;
+            if let Some(route) = state.routes.best(session, target) {
+                let frame = LinkMessage::Frame { target, hops: 1, session: session.to_string(), data: data.to_vec() };
+                self.send_frame(&state, route.link, &frame);
+            }
+        }
+        forwarded
+    }
+
+    /// Queue a frame on a link, dropping it if the link is behind
+    fn send_frame(&self, state: &LinkState, link: u64, frame: &LinkMessage) {
+        let Some(link) = state.links.get(&link) else {
+            return;
+        };
+        if link.sender.try_send(frame.encode()).is_err() {
+            self.stats.frames_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Accept links from peer relays until shutdown
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, shutdown: CancellationToken) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.cancelled() => break,
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                    tokio::spawn(Arc::clone(&self).run_link(stream, addr.to_string(), shutdown.clone()));
+                }
+                Err(e) => warn!("Failed to accept federation link: {}", e),
+            }
+        }
+    }
+
+    /// Keep a link to a configured peer relay up until shutdown
+    pub async fn connect(self: Arc<Self>, peer: String, shutdown: CancellationToken) {
+        loop {
+            let connected = tokio::select! {
+                connected = TcpStream::connect(&peer) => connected,
+                _ = shutdown.cancelled() => return,
+            };
+            match connected {
+                Ok(stream) => Arc::clone(&self).run_link(stream, peer.clone(), shutdown.clone()).await,
+                Err(e) => debug!("Failed to reach peer relay {}: {}", peer, e),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(PEER_RECONNECT_DELAY) => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    /// Authenticate a link, then exchange announcements and frames over it until it drops
+    async fn run_link(self: Arc<Self>, mut stream: TcpStream, peer: String, shutdown: CancellationToken) {
+        let peer_id = match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake(&mut stream)).await {
+            Ok(Ok(peer_id)) => peer_id,
+            Ok(Err(e)) => {
+                warn!("Federation handshake with {} failed: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                warn!("Federation handshake with {} timed out", peer);
+                return;
+            }
+        };
+        info!("Federation link up with relay {} ({})", peer_id, peer);
+
+        let (mut read_half, mut write_half) = stream.into_split();
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(LINK_QUEUE_CAPACITY);
+        let closed = shutdown.child_token();
+        let link_id = self.attach(Link { sender: tx, closed: closed.clone() }).await;
+
+        let writer_closed = closed.clone();
+        let writer = tokio::spawn(async move {
+            loop {
+                let data = tokio::select! {
+                    _ = writer_closed.cancelled() => break,
+                    received = rx.recv() => match received {
+                        Some(data) => data,
+                        None => break,
+                    },
+                };
+                if let Err(e) = ClientHandler::send_message(&mut write_half, &data).await {
+                    debug!("Failed to write to federation link: {}", e);
+                    break;
+                }
+            }
+            writer_closed.cancel();
+            let _ = write_half.shutdown().await;
+        });
+
+        let max_len = LINK_HEADER_MAX_LEN + self.config.max_message_size;
+        loop {
+            let message = tokio::select! {
+                message = read_link_message(&mut read_half, max_len) => message,
+                _ = closed.cancelled() => break,
+            };
+            match message {
+                Ok(Some(message)) => {
+                    if let Err(e) = self.receive(link_id, message).await {
+                        warn!("Federation link with relay {} ({}): {}", peer_id, peer, e);
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Federation link with relay {} ({}): {}", peer_id, peer, e);
+                    break;
+                }
+            }
+        }
+
+        closed.cancel();
+        self.detach(link_id);
+        let _ = writer.await;
+        info!("Federation link down with relay {} ({})", peer_id, peer);
+    }
+
+    /// MAC proving knowledge of the secret, bound to the challenge and the prover's id
+    fn link_mac(&self, nonce: &[u8; 16], prover: Uuid) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.config.secret).expect("HMAC takes keys of any length");
+        mac.update(AUTH_LABEL);
+        mac.update(nonce);
+        mac.update(prover.as_bytes());
+        mac
+    }
+
+    /// Mutual challenge-response; returns the peer's relay id
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<Uuid, BoxError> {
+        let nonce = Uuid::new_v4().into_bytes();
+        let hello = LinkMessage::Hello { relay_id: self.relay_id, nonce };
+        ClientHandler::send_message(stream, &hello.encode()).await?;
+
+        let (peer_id, peer_nonce) = match read_link_message(stream, LINK_HEADER_MAX_LEN).await? {
+            Some(LinkMessage::Hello { relay_id, nonce }) => (relay_id, nonce),
+            Some(_) => return Err("expected hello".into()),
+            None => return Err("connection closed".into()),
+        };
+        if peer_id == self.relay_id {
+            return Err("connected to itself".into());
+        }
+
+        let answer = self.link_mac(&peer_nonce, self.relay_id).finalize().into_bytes().to_vec();
+        ClientHandler::send_message(stream, &LinkMessage::Auth { mac: answer }.encode()).await?;
+
+        match read_link_message(stream, LINK_HEADER_MAX_LEN).await? {
+            Some(LinkMessage::Auth { mac }) => {
+                self.link_mac(&nonce, peer_id)
+                    .verify_slice(&mac)
+                    .map_err(|_| "peer failed authentication (federation secret mismatch?)")?;
+            }
+            Some(_) => return Err("expected authentication".into()),
+            None => return Err("connection closed during authentication (federation secret mismatch?)".into()),
+        }
+        Ok(peer_id)
+    }
+
+    /// Register an authenticated link and catch it up on every session we know of
+    async fn attach(&self, link: Link) -> u64 {
+        // The registry lock keeps local joins and leaves from slipping in
+        // between the snapshot and the link going live
+        let clients = self.clients.read().await;
+        let mut state = self.state();
+        let link_id = state.next_link_id;
+        state.next_link_id += 1;
+
+        for session in clients.session_codes() {
+            let announcement = Announcement { origin: self.relay_id, hops: 1, session: session.to_string() };
+            link.send_control(&LinkMessage::Join(announcement));
+        }
+        for announcement in state.routes.announcements() {
+            if announcement.hops < self.config.max_hops {
+                link.send_control(&LinkMessage::Join(announcement.next_hop()));
+            }
+        }
+        state.links.insert(link_id, link);
+        link_id
+    }
+
+    /// Forget a link and withdraw everything learned over it
+    fn detach(&self, link_id: u64) {
+        let mut state = self.state();
+        state.links.remove(&link_id);
+        for announcement in state.routes.remove_link(link_id) {
+            if announcement.hops < self.config.max_hops {
+                state.send_control(None, &LinkMessage::Leave(announcement.next_hop()));
+            }
+        }
+    }
+
+    async fn receive(&self, link_id: u64, message: LinkMessage) -> Result<(), BoxError> {
+        match message {
+            LinkMessage::Hello { .. } | LinkMessage::Auth { .. } => {
+                return Err("unexpected handshake message".into());
+            }
+            LinkMessage::Join(announcement) => self.learn(link_id, announcement),
+            LinkMessage::Leave(announcement) => self.unlearn(link_id, announcement),
+            LinkMessage::Frame { target, session, data, .. } if target == self.relay_id => {
+                let (recipients, counters) = {
+                    let clients = self.clients.read().await;
+                    let recipients: Vec<ClientInfo> = clients.session_members(&session).cloned().collect();
+                    (recipients, clients.session_counters(&session).cloned())
+                };
+                if !queue_frame(recipients, data.into(), &self.stats, &self.budget, counters.as_deref()) {
+                    backpressure::shed_heaviest_client(&*self.clients.read().await);
+                }
+            }
+            LinkMessage::Frame { target, hops, session, data } => {
+                // Passing through: on towards the target, never back where it came from
+                let state = self.state();
+                match state.routes.best(&session, target) {
+                    Some(route) if hops < self.config.max_hops && route.link != link_id => {
+                        let frame = LinkMessage::Frame { target, hops: hops + 1, session, data };
+                        self.send_frame(&state, route.link, &frame);
+                    }
+                    _ => debug!("No onward route to relay {} for session {}, dropping frame", target, session),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn learn(&self, link_id: u64, announcement: Announcement) {
+        if announcement.origin == self.relay_id || announcement.hops == 0 {
+            return;
+        }
+        let mut state = self.state();
+        let route = Route { link: link_id, hops: announcement.hops };
+        state.routes.add(&announcement.session, announcement.origin, route);
+        if announcement.hops < self.config.max_hops {
+            state.send_control(Some(link_id), &LinkMessage::Join(announcement.next_hop()));
+        }
+    }
+
+    fn unlearn(&self, link_id: u64, announcement: Announcement) {
+        let mut state = self.state();
+        let route = Route { link: link_id, hops: announcement.hops };
+        if state.routes.remove(&announcement.session, announcement.origin, route)
+            && announcement.hops < self.config.max_hops
+        {
+            state.send_control(Some(link_id), &LinkMessage::Leave(announcement.next_hop()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ClientRegistry;
+
+    fn federation(max_hops: u8) -> Federation {
+        let config = FederationConfig { secret: b"secret".to_vec(), max_hops, max_message_size: 1024 };
+        Federation::new(config, ClientRegistry::shared(), Arc::new(RelayStats::default()), Arc::new(BufferBudget::new(0)))
+    }
+
+    /// Attach a fake link, returning its id and what gets queued on it
+    async fn fake_link(federation: &Federation) -> (u64, mpsc::Receiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::channel(16);
+        let link_id = federation.attach(Link { sender, closed: CancellationToken::new() }).await;
+        (link_id, receiver)
+    }
+
+    fn queued(receiver: &mut mpsc::Receiver<Vec<u8>>) -> Vec<LinkMessage> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|data| LinkMessage::decode(&data).unwrap())
+            .collect()
+    }
+
+    fn announcement(origin: Uuid, hops: u8, session: &str) -> Announcement {
+        Announcement { origin, hops, session: session.to_string() }
+    }
+
+    #[test]
+    fn test_link_messages_round_trip() {
+        let messages = [
+            LinkMessage::Hello { relay_id: Uuid::new_v4(), nonce: [7; 16] },
+            LinkMessage::Auth { mac: vec![1, 2, 3] },
+            LinkMessage::Join(announcement(Uuid::new_v4(), 1, "room")),
+            LinkMessage::Leave(announcement(Uuid::new_v4(), 2, "room")),
+            LinkMessage::Frame { target: Uuid::new_v4(), hops: 1, session: "room".to_string(), data: vec![9; 32] },
+        ];
+        for message in messages {
+            assert_eq!(LinkMessage::decode(&message.encode()), Some(message));
+        }
+
+        let mut bad_session = LinkMessage::Join(announcement(Uuid::new_v4(), 1, "room")).encode();
+        bad_session[19] = b' ';
+        assert_eq!(LinkMessage::decode(&bad_session), None);
+        assert_eq!(LinkMessage::decode(&[MSG_HELLO, 1, 2]), None);
+        assert_eq!(LinkMessage::decode(&[0x7f]), None);
+    }
+
+    #[tokio::test]
+    async fn test_announcements_propagate_within_hop_limit() {
+        let federation = federation(2);
+        let (a, mut a_queue) = fake_link(&federation).await;
+        let (_b, mut b_queue) = fake_link(&federation).await;
+        let remote = Uuid::new_v4();
+
+        federation.learn(a, announcement(remote, 1, "room"));
+        assert!(federation.has_remote("room"));
+        assert!(queued(&mut a_queue).is_empty(), "never echoed back over the link it came from");
+        assert_eq!(queued(&mut b_queue), vec![LinkMessage::Join(announcement(remote, 2, "room"))]);
+
+        // At the hop limit: remembered, but not passed on
+        let distant = Uuid::new_v4();
+        federation.learn(a, announcement(distant, 2, "room"));
+        assert!(queued(&mut b_queue).is_empty());
+
+        // Our own announcement coming back around a loop is ignored
+        federation.learn(a, announcement(federation.relay_id, 2, "room"));
+        assert!(federation.state().routes.best("room", federation.relay_id).is_none());
+
+        federation.unlearn(a, announcement(remote, 1, "room"));
+        assert_eq!(queued(&mut b_queue), vec![LinkMessage::Leave(announcement(remote, 2, "room"))]);
+        federation.unlearn(a, announcement(distant, 2, "room"));
+        assert!(!federation.has_remote("room"));
+    }
+
+    #[tokio::test]
+    async fn test_lost_link_withdraws_its_routes() {
+        let federation = federation(3);
+        let (a, _a_queue) = fake_link(&federation).await;
+        let (b, mut b_queue) = fake_link(&federation).await;
+        let remote = Uuid::new_v4();
+
+        // Reachable both directly and the long way round
+        federation.learn(a, announcement(remote, 1, "room"));
+        federation.learn(b, announcement(remote, 2, "room"));
+        assert_eq!(federation.state().routes.best("room", remote), Some(Route { link: a, hops: 1 }));
+        queued(&mut b_queue);
+
+        federation.detach(a);
+        assert_eq!(federation.state().routes.best("room", remote), Some(Route { link: b, hops: 2 }));
+        assert_eq!(queued(&mut b_queue), vec![LinkMessage::Leave(announcement(remote, 2, "room"))]);
+    }
+
+    #[tokio::test]
+    async fn test_new_link_learns_known_sessions() {
+        let federation = federation(2);
+        let (a, _a_queue) = fake_link(&federation).await;
+        let remote = Uuid::new_v4();
+        federation.learn(a, announcement(remote, 1, "room"));
+
+        let (_b, mut b_queue) = fake_link(&federation).await;
+        assert_eq!(queued(&mut b_queue), vec![LinkMessage::Join(announcement(remote, 2, "room"))]);
+    }
+
+    #[tokio::test]
+    async fn test_frames_follow_best_route() {
+        let federation = federation(2);
+        let (a, mut a_queue) = fake_link(&federation).await;
+        let (b, mut b_queue) = fake_link(&federation).await;
+        let remote = Uuid::new_v4();
+
+        assert!(!federation.forward("room", b"hi"));
+        federation.learn(a, announcement(remote, 2, "room"));
+        federation.learn(b, announcement(remote, 1, "room"));
+        queued(&mut a_queue);
+
+        assert!(federation.forward("room", b"hi"));
+        let frame = LinkMessage::Frame { target: remote, hops: 1, session: "room".to_string(), data: b"hi".to_vec() };
+        assert_eq!(queued(&mut b_queue), vec![frame.clone()]);
+        assert!(queued(&mut a_queue).is_empty());
+
+        // Relayed onwards, but not back over the link it arrived on
+        federation.receive(a, frame.clone()).await.unwrap();
+        let onward = LinkMessage::Frame { target: remote, hops: 2, session: "room".to_string(), data: b"hi".to_vec() };
+        assert_eq!(queued(&mut b_queue), vec![onward]);
+        federation.receive(b, frame).await.unwrap();
+        assert!(queued(&mut a_queue).is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+// HTTP health probe for load balancers and uptime monitors
+//
+// Serves `GET /healthz` with a small JSON body. The status is 200 while the
+// relay accepts new clients and 503 when it is draining or saturated.
+// `GET /metrics` serves relay and per-session counters in the Prometheus
+// text format; sessions are labelled by a short hash prefix of their code.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::BUILD_COMMIT;
+use crate::registry::SharedRegistry;
+use crate::stats::{RelayStats, session_label};
+
+/// Largest request head read from a probe
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long a probe has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Per-session-bucket series on `/metrics`, in the order their values are summed
+const SESSION_SERIES: [&str; 6] = [
+    "silence_relay_session_frames_in",
+    "silence_relay_session_frames_delivered",
+    "silence_relay_session_frames_dropped",
+    "silence_relay_session_frames_buffered",
+    "silence_relay_session_frames_forwarded",
+    "silence_relay_session_count",
+];
+
+/// Relay state reported by the health endpoint
+#[derive(Clone)]
+pub struct HealthContext {
+    pub clients: SharedRegistry,
+    pub draining: Arc<AtomicBool>,
+    pub stats: Arc<RelayStats>,
+    pub started_at: Instant,
+    /// Active client count at which the relay reports itself unhealthy
+    pub saturation_clients: usize,
+    /// Addresses the relay accepts clients on
+    pub listeners: Vec<SocketAddr>,
+}
+
+impl HealthContext {
+    /// Status code and JSON body for a health probe
+    pub async fn report(&self) -> (u16, String) {
+        let active_clients = self.clients.read().await.len();
+        let accepting = !self.draining.load(Ordering::Relaxed) && active_clients < self.saturation_clients;
+        let body = json!({
+            "uptime_secs": self.started_at.elapsed().as_secs(),
+            "active_clients": active_clients,
+            "accepting": accepting,
+            "listeners": self.listeners.iter().map(SocketAddr::to_string).collect::<Vec<_>>(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "commit": BUILD_COMMIT,
+        });
+        (if accepting { 200 } else { 503 }, body.to_string())
+    }
+
+    /// Relay counters, then delivery counters summed over the open sessions
+    /// in each hash bucket
+    pub async fn metrics(&self) -> String {
+        let mut buckets: BTreeMap<String, [u64; SESSION_SERIES.len()]> = BTreeMap::new();
+        let (active_clients, active_sessions) = {
+            let clients = self.clients.read().await;
+            for session in clients.session_codes() {
+                let Some(counters) = clients.session_counters(session) else { continue };
+                let values = [
+                    counters.frames_in.load(Ordering::Relaxed),
+                    counters.frames_delivered.load(Ordering::Relaxed),
+                    counters.frames_dropped.load(Ordering::Relaxed),
+                    counters.frames_buffered.load(Ordering::Relaxed),
+                    counters.frames_forwarded.load(Ordering::Relaxed),
+                    1,
+                ];
+                let totals = buckets.entry(session_label(session)).or_default();
+                for (total, value) in totals.iter_mut().zip(values) {
+                    *total += value;
+                }
+            }
+            (clients.len(), clients.session_count())
+        };
+
+        let stats = self.stats.snapshot();
+        let mut out = String::new();
+        for (name, kind, value) in [
+            ("silence_relay_active_clients", "gauge", active_clients as u64),
+            ("silence_relay_active_sessions", "gauge", active_sessions as u64),
+            ("silence_relay_connections_accepted_total", "counter", stats.connections_accepted),
+            ("silence_relay_connections_rejected_total", "counter", stats.connections_rejected),
+            ("silence_relay_frames_relayed_total", "counter", stats.frames_relayed),
+            ("silence_relay_bytes_relayed_total", "counter", stats.bytes_relayed),
+            ("silence_relay_frames_dropped_total", "counter", stats.frames_dropped),
+            ("silence_relay_connections_closed_total", "counter", stats.connections_closed),
+            ("silence_relay_frames_delivered_total", "counter", stats.frames_delivered),
+            ("silence_relay_bytes_delivered_total", "counter", stats.bytes_delivered),
+        ] {
+            let _ = writeln!(out, "# TYPE {} {}\n{} {}", name, kind, name, value);
+        }
+
+        // Gauges rather than counters: a bucket's totals fall as its sessions end
+        for (index, name) in SESSION_SERIES.into_iter().enumerate() {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (label, totals) in &buckets {
+                let _ = writeln!(out, "{}{{session_bucket=\"{}\"}} {}", name, label, totals[index]);
+            }
+        }
+        out
+    }
+}
+
+/// Answer health probes until shutdown
+pub async fn serve_health(listener: TcpListener, context: HealthContext, shutdown: CancellationToken) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+
+        match accepted {
+            Ok((stream, addr)) => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_probe(stream, context).await {
+                        debug!("Health probe from {} failed: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept health probe: {}", e),
+        }
+    }
+}
+
+async fn handle_probe(mut stream: TcpStream, context: HealthContext) -> std::io::Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+
+    let mut parts = head.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => context.report().await,
+        (Some("GET"), Some("/metrics")) => (200, context.metrics().await),
+        (Some("GET"), Some(_)) => (404, json!({ "error": "not found" }).to_string()),
+        _ => (405, json!({ "error": "method not allowed" }).to_string()),
+    };
+    let content_type = if body.starts_with('{') { "application/json" } else { "text/plain; version=0.0.4" };
+
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status, reason, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read up to the blank line ending the request head
+async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..read]);
+        if head.len() > MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Request head too large"));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ClientRegistry;
+
+    async fn probe(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_healthz_reflects_drain_mode() {
+        let context = HealthContext {
+            clients: ClientRegistry::shared(),
+            draining: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(RelayStats::default()),
+            started_at: Instant::now(),
+            saturation_clients: 10,
+            listeners: vec!["127.0.0.1:8080".parse().unwrap(), "[::1]:443".parse().unwrap()],
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_health(listener, context.clone(), CancellationToken::new()));
+
+        let healthy = probe(addr, "GET /healthz HTTP/1.1\r\nHost: relay\r\n\r\n").await;
+        assert!(healthy.starts_with("HTTP/1.1 200 OK\r\n"), "{}", healthy);
+        let expected = format!(
+            r#"{{"accepting":true,"active_clients":0,"commit":{},"listeners":["127.0.0.1:8080","[::1]:443"],"uptime_secs":0,"version":"{}"}}"#,
+            serde_json::to_string(&BUILD_COMMIT).unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(healthy.ends_with(&expected), "{}", healthy);
+
+        context.draining.store(true, Ordering::Relaxed);
+        let draining = probe(addr, "GET /healthz HTTP/1.1\r\n\r\n").await;
+        assert!(draining.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", draining);
+        assert!(draining.contains(r#""accepting":false"#), "{}", draining);
+
+        let missing = probe(addr, "GET /status HTTP/1.1\r\n\r\n").await;
+        assert!(missing.starts_with("HTTP/1.1 404"), "{}", missing);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_relay_is_unhealthy() {
+        let context = HealthContext {
+            clients: ClientRegistry::shared(),
+            draining: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(RelayStats::default()),
+            started_at: Instant::now(),
+            saturation_clients: 0,
+            listeners: Vec::new(),
+        };
+        let (status, body) = context.report().await;
+        assert_eq!(status, 503);
+        assert!(body.contains(r#""accepting":false"#));
+    }
+}
@@ -0,0 +1,3395 @@
+// Silence Relay Server - Minimal TCP packet forwarding for P2P clients
+// Deploys on Cherry Servers bare metal for encrypted packet relay
+//
+// The relay lives in this library so it can also run inside another process:
+// the `silence-relay` binary is a thin command line wrapper around
+// `RelayServer::run`, while tests start one with `run_relay`.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Notify, Semaphore, mpsc, oneshot};
+use tokio_util::task::TaskTracker;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls;
+use tracing::{info, warn, error, debug};
+use uuid::Uuid;
+use clap::{CommandFactory, Parser};
+
+mod acl;
+mod admin;
+mod auth;
+mod backpressure;
+mod bans;
+mod config;
+mod federation;
+mod health;
+mod logging;
+mod notify;
+mod protocol;
+mod proxy_protocol;
+mod quota;
+mod registry;
+mod stats;
+mod udp;
+mod store_forward;
+
+use acl::{AccessList, Cidr};
+use admin::AdminContext;
+use auth::{TokenAuth, TokenClass};
+use backpressure::{BufferBudget, ClientQueue, OutboundItem, QueueError};
+use bans::{BanLimits, BanList};
+use config::{LiveConfig, RelayConfig};
+use federation::{Federation, FederationConfig};
+use health::HealthContext;
+use logging::{ConnectionTotals, DisconnectReason, LogFormat};
+use stats::{ClientCounters, RelayStats, SessionCounters};
+use store_forward::{StoreForward, StoreForwardLimits};
+use udp::{UdpRelay, UdpSessions};
+use registry::{ClientRegistry, SharedRegistry};
+use quota::{QuotaAction, QuotaLimits, QuotaTracker};
+use protocol::{
+    ClientJoin, Frame, ObservedAddress, Probe, ProbeReply, ProtocolError, RejectReason, ServerHello, ADDRESS_REQUEST,
+    ALPN_PROTOCOL, encode_rejection,
+};
+
+pub use config::resolve_args;
+/// Shuts down a relay started with `run_relay`
+pub use tokio_util::sync::CancellationToken;
+
+/// The git commit the relay was built from, which the deploy tool passes to
+/// its build as `SILENCE_RELAY_COMMIT`
+pub const BUILD_COMMIT: Option<&str> = option_env!("SILENCE_RELAY_COMMIT");
+
+/// `--version`'s answer: the crate version, and the commit when known
+pub fn long_version() -> String {
+    match BUILD_COMMIT {
+        Some(commit) => format!("{} (commit {})", env!("CARGO_PKG_VERSION"), commit),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// Command line arguments
+#[derive(Parser, Debug, Clone)]
+#[command(name = "silence-relay", version)]
+#[command(about = "Minimal TCP relay server for encrypted P2P communication")]
+pub struct Args {
+    /// TOML config file; command line flags and environment variables take precedence
+    #[arg(long, env = "RELAY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Port to bind the relay server
+    #[arg(short, long, default_value = "8080", env = "RELAY_PORT")]
+    port: u16,
+
+    /// Maximum number of concurrent clients
+    #[arg(short, long, default_value = "100", env = "MAX_CLIENTS")]
+    max_clients: usize,
+
+    /// Maximum message size in bytes
+    #[arg(long, default_value = "65536", env = "MAX_MESSAGE_SIZE")]
+    max_message_size: usize,
+
+    /// Bind address (default: all interfaces)
+    #[arg(short, long, default_value = "0.0.0.0", env = "BIND_ADDRESS")]
+    bind_address: String,
+
+    /// Address to accept clients on (host:port, repeatable); replaces --bind-address and --port
+    #[arg(long, env = "RELAY_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every client connection and
+    /// take the client address from it; only for listeners reachable solely
+    /// through a load balancer
+    #[arg(long, env = "PROXY_PROTOCOL")]
+    proxy_protocol: bool,
+
+    /// Seconds a new client has to send its join frame
+    #[arg(long, default_value = "10", env = "HELLO_TIMEOUT")]
+    hello_timeout: u64,
+
+    /// Seconds without inbound frames before a client is disconnected (0 disables)
+    #[arg(long, default_value = "300", env = "IDLE_TIMEOUT")]
+    idle_timeout: u64,
+
+    /// Seconds a connected client may send nothing, keepalives included, before it is dropped (0 disables)
+    #[arg(long, default_value = "0", env = "CLIENT_READ_TIMEOUT")]
+    client_read_timeout: u64,
+
+    /// Seconds between ping frames sent to each client (0 disables)
+    #[arg(long, default_value = "0", env = "PING_INTERVAL")]
+    ping_interval: u64,
+
+    /// Log line format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Seconds between heartbeat log lines summarizing relay activity (0 disables)
+    #[arg(long, default_value = "60", env = "STATS_INTERVAL")]
+    stats_interval: u64,
+
+    /// Maximum concurrent clients per source IP (IPv6 counted per /64)
+    #[arg(long, default_value = "4", env = "MAX_CLIENTS_PER_IP")]
+    max_clients_per_ip: usize,
+
+    /// Client slots out of --max-clients held back for clients with a priority token
+    #[arg(long, default_value = "0", env = "RESERVED_SLOTS")]
+    reserved_slots: usize,
+
+    /// Tokens clients join with; when set, clients without one are refused (repeatable)
+    #[arg(long, env = "AUTH_TOKENS", value_delimiter = ',', hide_env_values = true)]
+    auth_token: Vec<String>,
+
+    /// Tokens that are always accepted and also admit clients into the reserved slots (repeatable)
+    #[arg(long, env = "PRIORITY_TOKENS", value_delimiter = ',', hide_env_values = true)]
+    priority_token: Vec<String>,
+
+    /// Seconds a draining relay waits for its clients to leave before closing
+    /// them and exiting; also how long shutdown waits for connections to close
+    #[arg(long, default_value = "10", env = "DRAIN_TIMEOUT")]
+    drain_timeout: u64,
+
+    /// PEM certificate chain; enables TLS together with --tls-key
+    #[arg(long, env = "TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Unix socket path for the local admin interface (disabled when unset)
+    #[arg(long, env = "ADMIN_SOCKET")]
+    admin_socket: Option<PathBuf>,
+
+    /// UDP port for datagram relaying between session pairs (disabled when unset)
+    #[arg(long, env = "UDP_PORT")]
+    udp_port: Option<u16>,
+
+    /// Seconds a UDP session member may stay silent before it is forgotten
+    #[arg(long, default_value = "60", env = "UDP_SESSION_TTL")]
+    udp_session_ttl: u64,
+
+    /// Maximum UDP datagram size in bytes, including the relay header
+    #[arg(long, default_value = "1400", env = "MAX_DATAGRAM_SIZE")]
+    max_datagram_size: usize,
+
+    /// Only accept clients from these prefixes (repeatable; all addresses when unset)
+    #[arg(long, env = "ALLOW_CIDR", value_delimiter = ',')]
+    allow_cidr: Vec<Cidr>,
+
+    /// Refuse clients from these prefixes, even if allowed (repeatable)
+    #[arg(long, env = "DENY_CIDR", value_delimiter = ',')]
+    deny_cidr: Vec<Cidr>,
+
+    /// Port for the HTTP health endpoint (`GET /healthz`, disabled when unset)
+    #[arg(long, env = "HEALTH_PORT")]
+    health_port: Option<u16>,
+
+    /// Bind address for the health endpoint
+    #[arg(long, default_value = "127.0.0.1", env = "HEALTH_BIND_ADDRESS")]
+    health_bind_address: String,
+
+    /// Percentage of --max-clients at which the health endpoint reports 503
+    #[arg(long, default_value = "90", env = "HEALTH_SATURATION",
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    health_saturation: u8,
+
+    /// Seconds to hold frames for a session's absent peer (0 disables store-and-forward)
+    #[arg(long, default_value = "0", env = "STORE_FORWARD_TTL")]
+    store_forward_ttl: u64,
+
+    /// Maximum bytes buffered per session for store-and-forward
+    #[arg(long, default_value = "262144", env = "STORE_FORWARD_MAX_BYTES")]
+    store_forward_max_bytes: usize,
+
+    /// Maximum bytes buffered across all sessions; oldest sessions are evicted first
+    #[arg(long, default_value = "16777216", env = "STORE_FORWARD_TOTAL_BYTES")]
+    store_forward_total_bytes: usize,
+
+    /// Bytes a client address may send per quota window (0 disables quotas)
+    #[arg(long, default_value = "0", env = "QUOTA_BYTES")]
+    quota_bytes: u64,
+
+    /// Length of the quota window in seconds
+    #[arg(long, default_value = "3600", env = "QUOTA_WINDOW")]
+    quota_window: u64,
+
+    /// What to do with a client over its quota
+    #[arg(long, value_enum, default_value_t = QuotaAction::Disconnect, env = "QUOTA_ACTION")]
+    quota_action: QuotaAction,
+
+    /// Protocol violations within --ban-window after which an address is banned (0 disables bans)
+    #[arg(long, default_value = "5", env = "BAN_STRIKES")]
+    ban_strikes: u32,
+
+    /// Seconds over which protocol violations are counted towards a ban
+    #[arg(long, default_value = "60", env = "BAN_WINDOW")]
+    ban_window: u64,
+
+    /// Seconds a banned address is refused for
+    #[arg(long, default_value = "600", env = "BAN_DURATION")]
+    ban_duration: u64,
+
+    /// Bytes queued across all clients before the heaviest senders are paused (0 disables)
+    #[arg(long, default_value = "268435456", env = "MAX_BUFFERED_BYTES")]
+    max_buffered_bytes: u64,
+
+    /// Port for links from peer relays (federation listener disabled when unset)
+    #[arg(long, env = "FEDERATION_PORT")]
+    federation_port: Option<u16>,
+
+    /// Peer relay federation addresses (host:port) to link to (repeatable)
+    #[arg(long, env = "PEER_RELAYS", value_delimiter = ',')]
+    peer_relay: Vec<String>,
+
+    /// Shared secret peer relays authenticate each other with
+    #[arg(long, env = "FEDERATION_SECRET", hide_env_values = true)]
+    federation_secret: Option<String>,
+
+    /// Links a session announcement or frame may cross between relays
+    #[arg(long, default_value = "2", env = "FEDERATION_MAX_HOPS",
+          value_parser = clap::value_parser!(u8).range(1..))]
+    federation_max_hops: u8,
+}
+
+/// Frames queued per client before new frames for it are dropped
+const CLIENT_QUEUE_CAPACITY: usize = 64;
+
+/// Consecutive dropped frames after which a slow client is disconnected
+const SLOW_CLIENT_DROP_LIMIT: u64 = 128;
+
+/// Frames buffered per session; flushed in one go, so it must fit the client queue
+const STORE_FORWARD_MAX_FRAMES: usize = CLIENT_QUEUE_CAPACITY / 2;
+
+/// How often to update the systemd status line when no watchdog is configured
+const NOTIFY_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest oversized frame the relay reads past instead of disconnecting
+const MAX_DISCARD_LEN: usize = 1024 * 1024;
+
+/// Oversized frames tolerated per connection before the client is disconnected
+const OVERSIZED_FRAME_STRIKES: u32 = 3;
+
+/// How often a sender paused for the buffer budget checks whether it may go on
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bytes per second relayed for a client throttled by its quota
+const QUOTA_TRICKLE_RATE: f64 = 1024.0;
+
+/// How often a draining relay checks whether it can exit
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Addresses tracked for protocol violations; the least recently struck are forgotten first
+const BAN_TABLE_CAPACITY: usize = 10_000;
+
+/// Accepted connections waiting for the accept loop before listeners hold off
+const ACCEPT_QUEUE_CAPACITY: usize = 64;
+
+impl Args {
+    /// Arguments from command line flags, without the program name, resolved
+    /// like the binary's: environment variables and any `--config` file apply
+    pub fn from_flags<I, T>(flags: I) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let argv = std::iter::once(std::ffi::OsString::from("silence-relay")).chain(flags.into_iter().map(Into::into));
+        let matches = Self::command().try_get_matches_from(argv)?;
+        config::resolve_args(&matches)
+    }
+
+    /// The configuration these arguments amount to, as TOML
+    pub fn to_toml(&self) -> String {
+        RelayConfig::from_args(self).to_toml()
+    }
+
+    /// Client listen addresses: each `--listen`, or else `--bind-address` and `--port`
+    fn listen_addrs(&self) -> Vec<String> {
+        if self.listen.is_empty() {
+            vec![format!("{}:{}", self.bind_address, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+}
+
+/// Build a TLS acceptor from PEM files, advertising the relay ALPN id
+fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let cert_pem = std::fs::read(cert_path)
+        .map_err(|e| format!("Failed to read TLS certificate {}: {}", cert_path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid TLS certificate {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()).into());
+    }
+
+    let key_pem = std::fs::read(key_path)
+        .map_err(|e| format!("Failed to read TLS key {}: {}", key_path.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+        .map_err(|e| format!("Invalid TLS key {}: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("No private key found in {}", key_path.display()))?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Bucket a client address for per-IP accounting
+///
+/// IPv4-mapped IPv6 addresses count as their IPv4 address, and other IPv6
+/// addresses are grouped by /64 since a single host usually owns the whole prefix.
+fn ip_bucket(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = u128::from(v6) & !((1u128 << 64) - 1);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+/// Connections open from each `ip_bucket`, counted from accept until the
+/// connection's task ends, so ones that never join still count
+#[derive(Default)]
+struct OpenConnections(std::sync::Mutex<HashMap<IpAddr, usize>>);
+
+impl OpenConnections {
+    fn count(&self, bucket: IpAddr) -> usize {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).get(&bucket).copied().unwrap_or(0)
+    }
+
+    /// Count a connection from `bucket` until the returned guard is dropped
+    fn open(self: &Arc<Self>, bucket: IpAddr) -> OpenConnection {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(bucket).or_insert(0) += 1;
+        OpenConnection { connections: Arc::clone(self), bucket }
+    }
+}
+
+/// A connection counted in `OpenConnections`, no longer once dropped
+struct OpenConnection {
+    connections: Arc<OpenConnections>,
+    bucket: IpAddr,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut counts = self.connections.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.bucket) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.bucket);
+            }
+        }
+    }
+}
+
+/// A frame over the size limit whose payload was read and discarded
+#[derive(Debug)]
+struct OversizedFrame {
+    length: usize,
+    limit: usize,
+}
+
+impl std::fmt::Display for OversizedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Message too large: {} > {}", self.length, self.limit)
+    }
+}
+
+impl std::error::Error for OversizedFrame {}
+
+/// Limits a noisy log line to one emission per period
+struct LogThrottle {
+    period: Duration,
+    state: std::sync::Mutex<(Option<Instant>, u64)>,
+}
+
+impl LogThrottle {
+    fn new(period: Duration) -> Self {
+        Self {
+            period,
+            state: std::sync::Mutex::new((None, 0)),
+        }
+    }
+
+    /// Returns the number of suppressed events when the caller should log now
+    fn check(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, suppressed) = &mut *state;
+        match last {
+            Some(at) if at.elapsed() < self.period => {
+                *suppressed += 1;
+                None
+            }
+            _ => {
+                *last = Some(Instant::now());
+                Some(std::mem::take(suppressed))
+            }
+        }
+    }
+}
+
+/// Ban thresholds from the settings
+fn ban_limits(args: &Args) -> BanLimits {
+    BanLimits {
+        strikes: args.ban_strikes,
+        window: Duration::from_secs(args.ban_window),
+        duration: Duration::from_secs(args.ban_duration),
+    }
+}
+
+/// Client connection information
+#[derive(Debug, Clone)]
+struct ClientInfo {
+    id: Uuid,
+    addr: SocketAddr,
+    session: String,
+    sender: mpsc::Sender<OutboundItem>,
+    connected_at: Instant,
+    /// Largest frame the client may send or be sent, agreed on at join
+    max_message_size: usize,
+    counters: Arc<ClientCounters>,
+    /// Signals the connection task to disconnect the client
+    kick: Arc<Notify>,
+}
+
+impl ClientInfo {
+    /// Time since the client last sent a frame
+    fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.counters.last_activity_ms.load(Ordering::Relaxed));
+        self.connected_at.elapsed().saturating_sub(last_activity)
+    }
+}
+
+/// Relay server state
+pub struct RelayServer {
+    clients: SharedRegistry,
+    /// Effective settings, swapped when the config file is reloaded
+    config: LiveConfig,
+    /// Command line the settings were resolved from, needed to reload them
+    config_source: Option<clap::ArgMatches>,
+    /// Asks the accept loop to reload the config file
+    reload: Arc<Notify>,
+    /// One permit per public client slot, taken at accept and held until
+    /// the connection ends, so bursts can't overshoot `max_clients`
+    admission: Arc<Semaphore>,
+    /// Permits for the slots held back for priority tokens; lent to any
+    /// connection while the public ones are gone, until its hello shows
+    /// whether it may keep one
+    reserved: Arc<Semaphore>,
+    /// Connections per address, joined or not, for `max_clients_per_ip`
+    open_connections: Arc<OpenConnections>,
+    /// Tokens clients authenticate with, swapped on a config reload
+    auth: std::sync::RwLock<Arc<TokenAuth>>,
+    per_ip_reject_log: LogThrottle,
+    access_reject_log: LogThrottle,
+    /// Allow and deny lists, editable at runtime through the admin interface
+    access: Arc<std::sync::RwLock<AccessList>>,
+    shutdown: CancellationToken,
+    tls: Option<TlsAcceptor>,
+    stats: Arc<RelayStats>,
+    /// When set, new clients are turned away while existing ones continue
+    draining: Arc<AtomicBool>,
+    store_forward: Option<Arc<std::sync::Mutex<StoreForward>>>,
+    quota: Option<Arc<std::sync::Mutex<QuotaTracker>>>,
+    /// Addresses refused for repeated protocol violations
+    bans: Arc<std::sync::Mutex<BanList>>,
+    federation: Option<Arc<Federation>>,
+    /// Bytes queued for delivery across all clients
+    budget: Arc<BufferBudget>,
+    started_at: Instant,
+}
+
+impl RelayServer {
+    pub fn new(args: Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(load_tls_acceptor(cert, key)?),
+            (None, None) => None,
+            _ => return Err("TLS needs both tls_cert and tls_key".into()),
+        };
+
+        let stats = Arc::new(RelayStats::default());
+        let store_forward = (args.store_forward_ttl > 0).then(|| {
+            let limits = StoreForwardLimits {
+                ttl: Duration::from_secs(args.store_forward_ttl),
+                max_session_bytes: args.store_forward_max_bytes,
+                max_session_frames: STORE_FORWARD_MAX_FRAMES,
+                max_total_bytes: args.store_forward_total_bytes,
+            };
+            Arc::new(std::sync::Mutex::new(StoreForward::new(limits, Arc::clone(&stats))))
+        });
+
+        let quota = (args.quota_bytes > 0).then(|| {
+            let limits = QuotaLimits {
+                bytes: args.quota_bytes,
+                window: Duration::from_secs(args.quota_window),
+            };
+            Arc::new(std::sync::Mutex::new(QuotaTracker::new(limits)))
+        });
+
+        let bans = BanList::new(ban_limits(&args), BAN_TABLE_CAPACITY);
+
+        let clients = ClientRegistry::shared();
+        let budget = Arc::new(BufferBudget::new(args.max_buffered_bytes));
+        let federated = args.federation_port.is_some() || !args.peer_relay.is_empty();
+        let federation = match &args.federation_secret {
+            Some(secret) if federated => {
+                let config = FederationConfig {
+                    secret: secret.as_bytes().to_vec(),
+                    max_hops: args.federation_max_hops,
+                    max_message_size: args.max_message_size,
+                };
+                Some(Arc::new(Federation::new(config, Arc::clone(&clients), Arc::clone(&stats), Arc::clone(&budget))))
+            }
+            None if federated => return Err("Federation needs federation_secret".into()),
+            _ => None,
+        };
+
+        let access = AccessList::new(args.allow_cidr.clone(), args.deny_cidr.clone());
+        if args.reserved_slots > args.max_clients {
+            return Err("reserved_slots can't be more than max_clients".into());
+        }
+        let admission = Semaphore::new((args.max_clients - args.reserved_slots).min(Semaphore::MAX_PERMITS));
+        let reserved = Semaphore::new(args.reserved_slots.min(Semaphore::MAX_PERMITS));
+        let auth = TokenAuth::new(args.auth_token.clone(), args.priority_token.clone());
+
+        Ok(Self {
+            clients,
+            config: LiveConfig::new(args),
+            config_source: None,
+            reload: Arc::new(Notify::new()),
+            admission: Arc::new(admission),
+            reserved: Arc::new(reserved),
+            open_connections: Arc::default(),
+            auth: std::sync::RwLock::new(Arc::new(auth)),
+            per_ip_reject_log: LogThrottle::new(Duration::from_secs(10)),
+            access_reject_log: LogThrottle::new(Duration::from_secs(10)),
+            access: Arc::new(std::sync::RwLock::new(access)),
+            shutdown: CancellationToken::new(),
+            tls,
+            stats,
+            draining: Arc::new(AtomicBool::new(false)),
+            store_forward,
+            quota,
+            bans: Arc::new(std::sync::Mutex::new(bans)),
+            federation,
+            budget,
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Remember the command line so the config file can be reloaded on top of it
+    pub fn with_config_source(mut self, matches: clap::ArgMatches) -> Self {
+        self.config_source = Some(matches);
+        self
+    }
+
+    /// Shut down when `shutdown` is cancelled, for relays run in process
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Bind a listening socket for every client listen address; any one
+    /// failing is fatal
+    async fn bind(&self) -> Result<Vec<TcpListener>, Box<dyn std::error::Error>> {
+        let mut listeners = Vec::new();
+        for addr in self.config.current().listen_addrs() {
+            let listener = TcpListener::bind(&addr).await
+                .map_err(|e| format!("Failed to bind listener {}: {}", addr, e))?;
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Start the relay server, shutting down on Ctrl+C
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let listeners = self.bind().await?;
+        let args = self.config.current();
+
+        let addrs = listeners.iter().map(TcpListener::local_addr).collect::<std::io::Result<Vec<_>>>()?;
+        let addrs: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+        info!("Silence Relay Server starting on {} ({})", addrs.join(", "),
+              if self.tls.is_some() { "TLS" } else { "plain TCP" });
+        info!("Max clients: {}", args.max_clients);
+        info!("Max message size: {} bytes", args.max_message_size);
+        if self.store_forward.is_some() {
+            info!("Store-and-forward enabled: {}s TTL, {} bytes per session, {} bytes total",
+                  args.store_forward_ttl, args.store_forward_max_bytes,
+                  args.store_forward_total_bytes);
+        }
+        if self.quota.is_some() {
+            info!("Quota enabled: {} bytes per {}s per address, {:?} when exceeded",
+                  args.quota_bytes, args.quota_window, args.quota_action);
+        }
+
+        // Handle graceful shutdown
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+            info!("Shutdown signal received");
+            shutdown.cancel();
+        });
+
+        // SIGUSR1 toggles drain mode
+        let mut user1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        let draining = Arc::clone(&self.draining);
+        tokio::spawn(async move {
+            while user1.recv().await.is_some() {
+                let enabled = !draining.fetch_xor(true, Ordering::Relaxed);
+                info!("Drain signal received, {} new clients", if enabled { "refusing" } else { "accepting" });
+            }
+        });
+
+        // Reload the config file on SIGHUP
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let reload = Arc::clone(&self.reload);
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Reload signal received");
+                reload.notify_one();
+            }
+        });
+
+        self.serve(listeners).await
+    }
+
+    /// Accept and dispatch client connections until shutdown, then drain
+    async fn serve(&self, listeners: Vec<TcpListener>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = self.config.current();
+        let listen_addrs = listeners.iter().map(TcpListener::local_addr).collect::<std::io::Result<Vec<_>>>()?;
+        if args.idle_timeout > 0 {
+            let idle_timeout = Duration::from_secs(args.idle_timeout);
+            tokio::spawn(Self::sweep_idle_clients(
+                Arc::clone(&self.clients),
+                idle_timeout,
+                self.shutdown.clone(),
+            ));
+        }
+
+        if args.stats_interval > 0 {
+            tokio::spawn(Self::report_stats(
+                Arc::clone(&self.clients),
+                Arc::clone(&self.stats),
+                Duration::from_secs(args.stats_interval),
+                self.shutdown.clone(),
+            ));
+        }
+
+        if let Some(path) = &args.admin_socket {
+            let admin_listener = admin::bind_admin_socket(path)
+                .map_err(|e| format!("Failed to bind admin socket {}: {}", path.display(), e))?;
+            info!("Admin interface listening on {}", path.display());
+            tokio::spawn(admin::serve_admin(admin_listener, self.admin_context(), self.shutdown.clone()));
+        }
+
+        if let Some(port) = args.health_port {
+            let health_addr = format!("{}:{}", args.health_bind_address, port);
+            let health_listener = TcpListener::bind(&health_addr).await
+                .map_err(|e| format!("Failed to bind health endpoint {}: {}", health_addr, e))?;
+            info!("Health endpoint listening on http://{}/healthz", health_listener.local_addr()?);
+            let context = HealthContext {
+                clients: Arc::clone(&self.clients),
+                draining: Arc::clone(&self.draining),
+                stats: Arc::clone(&self.stats),
+                started_at: self.started_at,
+                saturation_clients: (args.max_clients * args.health_saturation as usize).div_ceil(100),
+                listeners: listen_addrs.clone(),
+            };
+            tokio::spawn(health::serve_health(health_listener, context, self.shutdown.clone()));
+        }
+
+        if let Some(port) = args.udp_port {
+            let udp_addr = format!("{}:{}", args.bind_address, port);
+            let socket = tokio::net::UdpSocket::bind(&udp_addr).await
+                .map_err(|e| format!("Failed to bind UDP relay {}: {}", udp_addr, e))?;
+            info!("UDP relay listening on {}", socket.local_addr()?);
+            let udp_relay = Arc::new(UdpRelay {
+                max_datagram_size: args.max_datagram_size,
+                session_ttl: Duration::from_secs(args.udp_session_ttl),
+                stats: Arc::clone(&self.stats),
+                draining: Arc::clone(&self.draining),
+                access: Arc::clone(&self.access),
+                sessions: Mutex::new(UdpSessions::default()),
+            });
+            tokio::spawn(udp_relay.serve(socket, self.shutdown.clone()));
+        }
+
+        if let Some(federation) = &self.federation {
+            if let Some(port) = args.federation_port {
+                let federation_addr = format!("{}:{}", args.bind_address, port);
+                let federation_listener = TcpListener::bind(&federation_addr).await
+                    .map_err(|e| format!("Failed to bind federation listener {}: {}", federation_addr, e))?;
+                info!("Federation listening on {}", federation_listener.local_addr()?);
+                tokio::spawn(Arc::clone(federation).serve(federation_listener, self.shutdown.clone()));
+            }
+            for peer in &args.peer_relay {
+                info!("Linking to peer relay {}", peer);
+                tokio::spawn(Arc::clone(federation).connect(peer.clone(), self.shutdown.clone()));
+            }
+        }
+
+        // Tell systemd we're up, then keep it posted while we stay responsive
+        let notifier = notify::Notifier::from_env().map(Arc::new);
+        let (probe_tx, mut probe_rx) = mpsc::channel::<oneshot::Sender<()>>(1);
+        if let Some(notifier) = &notifier {
+            notifier.notify("READY=1");
+            tokio::spawn(Self::supervise(
+                Arc::clone(notifier),
+                Arc::clone(&self.clients),
+                Arc::clone(&self.draining),
+                probe_tx,
+                notify::watchdog_interval(),
+                self.shutdown.clone(),
+            ));
+        }
+
+        // One accept loop per listener, all feeding the loop below
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(ACCEPT_QUEUE_CAPACITY);
+        let stop_accepting = CancellationToken::new();
+        let proxy_header_log = args.proxy_protocol.then(|| Arc::new(LogThrottle::new(Duration::from_secs(10))));
+        let acceptors: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(Self::accept_loop(
+                listener, accepted_tx.clone(), proxy_header_log.clone(), stop_accepting.clone(),
+            )))
+            .collect();
+        drop(accepted_tx);
+
+        let client_tasks = TaskTracker::new();
+        let mut drain_check = tokio::time::interval(DRAIN_POLL_INTERVAL);
+        let mut drain_started: Option<Instant> = None;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = accepted_rx.recv() => match accepted {
+                    Some(accepted) => accepted,
+                    None => break,
+                },
+                _ = drain_check.tick() => {
+                    // Draining: exit once the last client leaves or the deadline passes
+                    if !self.draining.load(Ordering::Relaxed) {
+                        drain_started = None;
+                        continue;
+                    }
+                    let drain_timeout = Duration::from_secs(self.config.current().drain_timeout);
+                    let started = *drain_started.get_or_insert_with(|| {
+                        info!("Draining: exiting once all clients leave, or in {:?}", drain_timeout);
+                        Instant::now()
+                    });
+                    let remaining = self.clients.read().await.len();
+                    if remaining == 0 {
+                        info!("All clients gone, drain complete");
+                    } else if started.elapsed() >= drain_timeout {
+                        warn!("{} clients still connected after draining for {:?}, closing them", remaining, drain_timeout);
+                    } else {
+                        continue;
+                    }
+                    self.shutdown.cancel();
+                    break;
+                }
+                Some(reply) = probe_rx.recv() => {
+                    // Supervisor probe: answering shows the accept loop isn't wedged
+                    let _ = reply.send(());
+                    continue;
+                }
+                _ = self.reload.notified() => {
+                    if let Err(e) = self.reload_config().await {
+                        error!("Config reload failed, keeping the current settings: {}", e);
+                    }
+                    continue;
+                }
+                _ = self.shutdown.cancelled() => break,
+            };
+
+            match accepted {
+                Ok((stream, addr)) => {
+                    let args = self.config.current();
+                    let permitted = self.access.read().unwrap_or_else(|e| e.into_inner()).permits(addr.ip());
+                    let bucket = ip_bucket(addr.ip());
+                    let same_ip_count = self.open_connections.count(bucket);
+
+                    let mut rejection = if !permitted {
+                        if let Some(suppressed) = self.access_reject_log.check() {
+                            warn!("Address {} not permitted by access lists, rejecting ({} similar rejections suppressed)",
+                                  addr, suppressed);
+                        }
+                        Some(RejectReason::NotAllowed)
+                    } else if self.bans.lock().unwrap_or_else(|e| e.into_inner()).is_banned(bucket, Instant::now()) {
+                        Some(RejectReason::Banned)
+                    } else if self.draining.load(Ordering::Relaxed) {
+                        Some(RejectReason::Draining)
+                    } else if same_ip_count >= args.max_clients_per_ip {
+                        if let Some(suppressed) = self.per_ip_reject_log.check() {
+                            warn!("Per-IP limit ({}) reached, rejecting connection from {} ({} similar rejections suppressed)",
+                                  args.max_clients_per_ip, addr, suppressed);
+                        }
+                        Some(RejectReason::TooManyFromAddress)
+                    } else if self.quota_exhausted(addr.ip()) {
+                        Some(RejectReason::QuotaExceeded)
+                    } else {
+                        None
+                    };
+
+                    // Take a slot last, so connections turned away for other
+                    // reasons never hold one
+                    let mut slot = None;
+                    let mut counted = None;
+                    let mut reserved_slot = false;
+                    if rejection.is_none() {
+                        let public = Arc::clone(&self.admission).try_acquire_owned();
+                        match public.or_else(|_| {
+                            reserved_slot = true;
+                            Arc::clone(&self.reserved).try_acquire_owned()
+                        }) {
+                            Ok(permit) => {
+                                slot = Some(permit);
+                                counted = Some(self.open_connections.open(bucket));
+                            }
+                            Err(_) => {
+                                warn!("Max clients ({}) reached, rejecting connection from {}",
+                                      args.max_clients, addr);
+                                rejection = Some(RejectReason::ServerFull);
+                            }
+                        }
+                    }
+
+                    let counter = match rejection {
+                        Some(reason) => {
+                            logging::rejected(addr, reason);
+                            &self.stats.connections_rejected
+                        }
+                        None => {
+                            logging::connected(addr);
+                            &self.stats.connections_accepted
+                        }
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+
+                    let client_handler = ClientHandler {
+                        clients: Arc::clone(&self.clients),
+                        max_message_size: args.max_message_size,
+                        hello_timeout: Duration::from_secs(args.hello_timeout),
+                        read_timeout: (args.client_read_timeout > 0)
+                            .then(|| Duration::from_secs(args.client_read_timeout)),
+                        ping_interval: (args.ping_interval > 0)
+                            .then(|| Duration::from_secs(args.ping_interval)),
+                        shutdown: self.shutdown.clone(),
+                        stats: Arc::clone(&self.stats),
+                        store_forward: self.store_forward.clone(),
+                        quota: self.quota.clone(),
+                        quota_action: args.quota_action,
+                        auth: Arc::clone(&self.auth.read().unwrap_or_else(|e| e.into_inner())),
+                        reserved_slot,
+                        bans: Arc::clone(&self.bans),
+                        federation: self.federation.clone(),
+                        budget: Arc::clone(&self.budget),
+                        draining: Arc::clone(&self.draining),
+                        started_at: self.started_at,
+                    };
+                    let tls = self.tls.clone();
+                    
+                    client_tasks.spawn(async move {
+                        // Released when the connection is over and the client unregistered
+                        let _slot = slot;
+                        let _counted = counted;
+                        match tls {
+                            Some(acceptor) => {
+                                let handshake = acceptor.accept(stream);
+                                match tokio::time::timeout(client_handler.hello_timeout, handshake).await {
+                                    Ok(Ok(tls_stream)) => client_handler.dispatch(tls_stream, addr, rejection).await,
+                                    Ok(Err(e)) => {
+                                        warn!("TLS handshake with {} failed: {}", addr, e);
+                                        client_handler.strike(addr, "failed TLS handshake");
+                                    }
+                                    Err(_) => {
+                                        warn!("TLS handshake with {} timed out", addr);
+                                        client_handler.strike(addr, "TLS handshake timeout");
+                                    }
+                                }
+                            }
+                            None => client_handler.dispatch(stream, addr, rejection).await,
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                }
+            }
+        }
+
+        // Stop accepting and give connected clients time to close cleanly
+        if let Some(notifier) = &notifier {
+            notifier.notify("STOPPING=1");
+        }
+        stop_accepting.cancel();
+        drop(accepted_rx);
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+        client_tasks.close();
+        info!("Draining {} client connections", client_tasks.len());
+
+        let drain_timeout = Duration::from_secs(self.config.current().drain_timeout);
+        if tokio::time::timeout(drain_timeout, client_tasks.wait()).await.is_err() {
+            warn!("{} client connections still open after {:?}, exiting anyway",
+                  client_tasks.len(), drain_timeout);
+        }
+
+        info!("Relay server stopped");
+        Ok(())
+    }
+
+    /// Accept connections on one listener and hand them to the main loop
+    /// until told to stop; the listener closes when this returns.
+    /// `proxy_header_log` is set when connections open with a PROXY header.
+    async fn accept_loop(
+        listener: TcpListener,
+        accepted: mpsc::Sender<std::io::Result<(TcpStream, SocketAddr)>>,
+        proxy_header_log: Option<Arc<LogThrottle>>,
+        stop: CancellationToken,
+    ) {
+        loop {
+            let result = tokio::select! {
+                result = listener.accept() => result,
+                _ = stop.cancelled() => return,
+            };
+            match (result, &proxy_header_log) {
+                // Read each header on its own task so a slow one holds up nobody else
+                (Ok((stream, peer)), Some(log)) => {
+                    let accepted = accepted.clone();
+                    let log = Arc::clone(log);
+                    tokio::spawn(async move {
+                        if let Some(client) = Self::read_proxy_header(stream, peer, &log).await {
+                            let _ = accepted.send(Ok(client)).await;
+                        }
+                    });
+                }
+                (result, _) => {
+                    if accepted.send(result).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Swap the load balancer's address for the client's from the PROXY
+    /// header; the connection is dropped when none arrives in time or it is
+    /// malformed
+    async fn read_proxy_header(
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        log: &LogThrottle,
+    ) -> Option<(TcpStream, SocketAddr)> {
+        let problem = match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+            Ok(Ok(client)) => return Some((stream, client.unwrap_or(peer))),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("No PROXY header within {:?}", proxy_protocol::HEADER_TIMEOUT),
+        };
+        if let Some(suppressed) = log.check() {
+            warn!("{} from {}, dropping the connection ({} similar suppressed)", problem, peer, suppressed);
+        }
+        None
+    }
+
+    /// State shared with the admin interface
+    fn admin_context(&self) -> AdminContext {
+        AdminContext {
+            clients: Arc::clone(&self.clients),
+            stats: Arc::clone(&self.stats),
+            draining: Arc::clone(&self.draining),
+            store_forward: self.store_forward.clone(),
+            access: Arc::clone(&self.access),
+            quota: self.quota.clone(),
+            bans: Arc::clone(&self.bans),
+            reserved: Arc::clone(&self.reserved),
+            reserved_slots: self.config.current().reserved_slots,
+            budget: Arc::clone(&self.budget),
+        }
+    }
+
+    /// Read the config file again and apply what can change while running;
+    /// on any error the current settings stay in place
+    async fn reload_config(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let matches = self.config_source.as_ref().ok_or("relay was not started from a command line")?;
+        let current = self.config.current();
+        let path = current.config.as_ref().ok_or("no config file to reload")?;
+        let (args, needs_restart) = config::reloaded(&current, config::resolve_args(matches)?);
+        for field in needs_restart {
+            warn!("Config reload: {} changed in {} but only takes effect after a restart", field, path.display());
+        }
+        if args.reserved_slots > args.max_clients {
+            return Err("reserved_slots can't be more than max_clients".into());
+        }
+
+        // The file's lists replace the current ones, runtime admin edits included
+        let access = AccessList::new(args.allow_cidr.clone(), args.deny_cidr.clone());
+        let mut kicked = 0;
+        for client in self.clients.read().await.values().filter(|client| !access.permits(client.addr.ip())) {
+            client.kick.notify_one();
+            kicked += 1;
+        }
+        *self.access.write().unwrap_or_else(|e| e.into_inner()) = access;
+
+        if let Some(quota) = &self.quota {
+            quota.lock().unwrap_or_else(|e| e.into_inner()).set_limits(QuotaLimits {
+                bytes: args.quota_bytes,
+                window: Duration::from_secs(args.quota_window),
+            });
+        }
+        if let Some(store_forward) = &self.store_forward {
+            store_forward.lock().unwrap_or_else(|e| e.into_inner()).set_limits(StoreForwardLimits {
+                ttl: Duration::from_secs(args.store_forward_ttl),
+                max_session_bytes: args.store_forward_max_bytes,
+                max_session_frames: STORE_FORWARD_MAX_FRAMES,
+                max_total_bytes: args.store_forward_total_bytes,
+            });
+        }
+        self.bans.lock().unwrap_or_else(|e| e.into_inner()).set_limits(ban_limits(&args));
+        self.budget.set_limit(args.max_buffered_bytes);
+        *self.auth.write().unwrap_or_else(|e| e.into_inner()) =
+            Arc::new(TokenAuth::new(args.auth_token.clone(), args.priority_token.clone()));
+        self.resize_admission(current.max_clients - current.reserved_slots, args.max_clients - args.reserved_slots);
+
+        self.config.replace(args);
+        info!("Reloaded config from {} ({} clients no longer permitted disconnected)", path.display(), kicked);
+        Ok(())
+    }
+
+    /// Grow or shrink the public client slots; slots still in use when shrinking are
+    /// retired as their clients leave
+    fn resize_admission(&self, from: usize, to: usize) {
+        let (from, to) = (from.min(Semaphore::MAX_PERMITS), to.min(Semaphore::MAX_PERMITS));
+        if to > from {
+            self.admission.add_permits(to - from);
+        } else if to < from {
+            let admission = Arc::clone(&self.admission);
+            let excess = u32::try_from(from - to).unwrap_or(u32::MAX);
+            tokio::spawn(async move {
+                if let Ok(retired) = admission.acquire_many_owned(excess).await {
+                    retired.forget();
+                }
+            });
+        }
+    }
+
+    /// Whether a disconnecting quota has no allowance left for this address
+    fn quota_exhausted(&self, ip: IpAddr) -> bool {
+        match &self.quota {
+            Some(quota) if self.config.current().quota_action == QuotaAction::Disconnect => {
+                quota.lock().unwrap_or_else(|e| e.into_inner()).exhausted(ip_bucket(ip), Instant::now())
+            }
+            _ => false,
+        }
+    }
+
+    /// Log a heartbeat line with current load and activity since the last one
+    async fn report_stats(
+        clients: SharedRegistry,
+        stats: Arc<RelayStats>,
+        period: Duration,
+        shutdown: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut previous = stats.snapshot();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+
+            // Each counter is read once per report and diffed against the last
+            // report, so nothing is lost or double-counted between intervals
+            let current = stats.snapshot();
+            let (active_clients, active_sessions) = {
+                let clients_guard = clients.read().await;
+                (clients_guard.len(), clients_guard.session_count())
+            };
+            stats::log_stats_report(active_clients, active_sessions, &current.delta_since(&previous));
+            previous = current;
+        }
+    }
+
+    /// Keep the systemd status line current and feed its watchdog, but only
+    /// while the accept loop and the client registry both respond
+    async fn supervise(
+        notifier: Arc<notify::Notifier>,
+        clients: SharedRegistry,
+        draining: Arc<AtomicBool>,
+        probe: mpsc::Sender<oneshot::Sender<()>>,
+        watchdog: Option<Duration>,
+        shutdown: CancellationToken,
+    ) {
+        let period = watchdog.unwrap_or(NOTIFY_STATUS_INTERVAL);
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+
+            let checks = async {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                probe.send(reply_tx).await.ok()?;
+                reply_rx.await.ok()?;
+                let clients_guard = clients.read().await;
+                Some((clients_guard.len(), clients_guard.session_count()))
+            };
+            match tokio::time::timeout(period, checks).await {
+                Ok(Some((active_clients, active_sessions))) => {
+                    let draining = draining.load(Ordering::Relaxed);
+                    notifier.notify(&notify::status_line(active_clients, active_sessions, draining));
+                    if watchdog.is_some() {
+                        notifier.notify("WATCHDOG=1");
+                    }
+                }
+                _ => warn!("Relay did not answer its supervisor within {:?}, withholding watchdog ping", period),
+            }
+        }
+    }
+
+    /// Periodically disconnect clients that have gone silent
+    async fn sweep_idle_clients(
+        clients: SharedRegistry,
+        idle_timeout: Duration,
+        shutdown: CancellationToken,
+    ) {
+        let period = (idle_timeout / 4).clamp(Duration::from_millis(250), Duration::from_secs(30));
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+
+            let clients_guard = clients.read().await;
+            for client in clients_guard.values() {
+                let idle_for = client.idle_for();
+                if idle_for >= idle_timeout {
+                    info!("Client {} ({}) idle for {:?}, disconnecting", client.id, client.addr, idle_for);
+                    client.counters.idle.store(true, Ordering::Relaxed);
+                    client.kick.notify_one();
+                }
+            }
+        }
+    }
+}
+
+/// Latency probes answered per connection and window; the rest go unanswered
+const PROBE_LIMIT: u32 = 4;
+const PROBE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Counts one connection's probes in fixed windows
+#[derive(Default)]
+struct ProbeLimit {
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+impl ProbeLimit {
+    fn allow(&mut self, now: Instant) -> bool {
+        if self.window_start.is_none_or(|start| now.duration_since(start) >= PROBE_WINDOW) {
+            self.window_start = Some(now);
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= PROBE_LIMIT
+    }
+}
+
+/// How long a client's writer may take to flush its last frame on close
+const WRITER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send a rejection frame and close the connection
+async fn reject_connection<S: AsyncWrite + Unpin>(mut stream: S, reason: RejectReason) {
+    let frame = encode_rejection(reason);
+    let write = ClientHandler::send_message(&mut stream, &frame);
+    let _ = tokio::time::timeout(Duration::from_secs(2), write).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Handles individual client connections
+struct ClientHandler {
+    clients: SharedRegistry,
+    max_message_size: usize,
+    hello_timeout: Duration,
+    /// Longest a registered client may go without sending a frame
+    read_timeout: Option<Duration>,
+    ping_interval: Option<Duration>,
+    shutdown: CancellationToken,
+    stats: Arc<RelayStats>,
+    store_forward: Option<Arc<std::sync::Mutex<StoreForward>>>,
+    quota: Option<Arc<std::sync::Mutex<QuotaTracker>>>,
+    quota_action: QuotaAction,
+    auth: Arc<TokenAuth>,
+    /// Holding a reserved slot, which only a priority token may keep
+    reserved_slot: bool,
+    bans: Arc<std::sync::Mutex<BanList>>,
+    federation: Option<Arc<Federation>>,
+    budget: Arc<BufferBudget>,
+    /// Set when the relay is draining, so clients closed at shutdown are told why
+    draining: Arc<AtomicBool>,
+    /// Probe replies carry receive times relative to this
+    started_at: Instant,
+}
+
+impl ClientHandler {
+    /// Serve an accepted connection, or turn it away with a rejection frame
+    async fn dispatch<S>(self, stream: S, addr: SocketAddr, rejection: Option<RejectReason>)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        match rejection {
+            Some(reason) => reject_connection(stream, reason).await,
+            None => {
+                if let Err(e) = self.handle_client(stream, addr).await {
+                    error!("Client handler error: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Handle a client connection over any byte stream (plain TCP or TLS)
+    async fn handle_client<S>(
+        &self, 
+        stream: S, 
+        addr: SocketAddr
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let client_id = Uuid::new_v4();
+        let (tx, rx) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+
+        // Split stream for concurrent read/write
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+
+        // The client must open with a join frame naming its session. Latency
+        // probes and address requests may come first and are answered at
+        // once, but the hello timeout runs from the connection all the same.
+        let hello_deadline = tokio::time::Instant::now() + self.hello_timeout;
+        let mut probe_limit = ProbeLimit::default();
+        let join_result = loop {
+            let join_read = tokio::time::timeout_at(hello_deadline, self.read_message(&mut read_half, self.max_message_size));
+            let result = tokio::select! {
+                result = join_read => result,
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+            let Ok(Ok(Some(data))) = &result else { break result };
+            let received_us = self.started_at.elapsed().as_micros() as u64;
+            let reply = if let Some(probe) = Probe::decode(data) {
+                ProbeReply { id: probe.id, received_us }.encode()
+            } else if data[..] == ADDRESS_REQUEST {
+                ObservedAddress { addr }.encode()
+            } else {
+                break result;
+            };
+            if !probe_limit.allow(Instant::now()) {
+                debug!("Ignoring probe from {}: over the limit of {} per {:?}", addr, PROBE_LIMIT, PROBE_WINDOW);
+                continue;
+            }
+            if Self::send_message(&mut write_half, &reply).await.is_err() {
+                return Ok(());
+            }
+        };
+        let join = match join_result {
+            Ok(Ok(Some(data))) => match ClientJoin::decode(&data) {
+                Ok(join) => join,
+                Err(ProtocolError::UnsupportedVersion(version)) => {
+                    warn!("Rejecting client {}: unsupported protocol version {}", addr, version);
+                    logging::rejected(addr, RejectReason::UnsupportedVersion);
+                    reject_connection(write_half, RejectReason::UnsupportedVersion).await;
+                    return Ok(());
+                }
+                Err(ProtocolError::MissingVersion) => {
+                    warn!("Disconnecting client {}: join frame has no protocol version (outdated client?)", addr);
+                    self.strike(addr, "join frame without a version");
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Rejecting client {}: {}", addr, e);
+                    self.strike(addr, "invalid join frame");
+                    return Ok(());
+                }
+            },
+            Ok(Ok(None)) => {
+                debug!("Client {} disconnected before joining", addr);
+                return Ok(());
+            }
+            Ok(Err(e)) => {
+                warn!("Rejecting client {}: {}", addr, e);
+                // A connection that merely failed is no violation
+                if !e.is::<std::io::Error>() {
+                    self.strike(addr, "oversized join frame");
+                }
+                return Ok(());
+            }
+            Err(_) => {
+                warn!("Client {} did not send a join frame within {:?}", addr, self.hello_timeout);
+                self.strike(addr, "hello timeout");
+                return Ok(());
+            }
+        };
+
+        // Only now is it known which slots the client may use
+        match self.auth.check(join.token.as_deref()) {
+            None => {
+                warn!("Rejecting client {}: missing or unknown auth token", addr);
+                logging::rejected(addr, RejectReason::Unauthorized);
+                if join.token.is_some() {
+                    self.strike(addr, "unknown auth token");
+                }
+                reject_connection(write_half, RejectReason::Unauthorized).await;
+                return Ok(());
+            }
+            Some(TokenClass::Public) if self.reserved_slot => {
+                debug!("Rejecting client {}: only reserved slots left and it has no priority token", addr);
+                logging::rejected(addr, RejectReason::ServerFull);
+                reject_connection(write_half, RejectReason::ServerFull).await;
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+
+        // The client may only lower the relay's frame size limit for itself
+        let max_message_size = join
+            .max_message_size
+            .map_or(self.max_message_size, |requested| self.max_message_size.min(requested as usize));
+
+        // Register client and reply with the hello
+        let connected_at = Instant::now();
+        let counters = Arc::new(ClientCounters::default());
+        let kick = Arc::new(Notify::new());
+        let (peer_present, session_counters) = {
+            let mut clients = self.clients.write().await;
+            let local_peer = clients.session_members(&join.session_code).next().is_some();
+            let remote_peer = self.federation.as_ref().is_some_and(|f| f.has_remote(&join.session_code));
+            let client_info = ClientInfo {
+                id: client_id,
+                addr,
+                session: join.session_code.clone(),
+                sender: tx.clone(),
+                connected_at,
+                max_message_size,
+                counters: Arc::clone(&counters),
+                kick: Arc::clone(&kick),
+            };
+            let session_counters = clients.insert(client_info);
+            if let Some(federation) = &self.federation {
+                if !local_peer {
+                    federation.local_joined(&join.session_code);
+                }
+            }
+
+            // Queue anything buffered for this session while the clients lock
+            // is held, so it stays ahead of frames relayed from now on
+            if let Some(store_forward) = &self.store_forward {
+                let buffered = store_forward
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take(&join.session_code, Instant::now());
+                if !buffered.is_empty() {
+                    debug!("Flushing {} buffered frames to client {}", buffered.len(), client_id);
+                }
+                for frame in buffered.into_iter().filter(|frame| frame.len() <= max_message_size) {
+                    self.budget.queue(&tx, &counters, frame);
+                }
+            }
+            (local_peer || remote_peer, session_counters)
+        };
+        logging::joined(client_id, addr, &join.session_code);
+
+        // The session speaks the client's version, which the join check made
+        // sure we speak too
+        let hello = ServerHello {
+            version: join.version,
+            client_id,
+            max_message_size: max_message_size as u32,
+            peer_present,
+        };
+        if let Err(e) = Self::send_message(&mut write_half, &hello.encode()).await {
+            self.unregister(client_id, &join.session_code).await;
+            return Err(e);
+        }
+        
+        // Spawn task to handle outbound messages to this client. It stops between
+        // frames once the connection winds down so a frame is never cut short.
+        let writer_stop = CancellationToken::new();
+        let writer_stop_for_task = writer_stop.clone();
+        let client_id_for_writer = client_id;
+        let counters_for_writer = Arc::clone(&counters);
+        let mut queue = ClientQueue::new(rx, Arc::clone(&counters), Arc::clone(&self.budget));
+        // Shedding the client skips its backlog and sends only the goodbye
+        let shed = CancellationToken::new();
+        let shed_for_task = shed.clone();
+        // Lets the read loop below stop when the writer can't go on
+        let write_failed = CancellationToken::new();
+        let write_failed_for_task = write_failed.clone();
+        let mut write_task = tokio::spawn(async move {
+            loop {
+                let item = tokio::select! {
+                    biased;
+                    _ = shed_for_task.cancelled() => OutboundItem::Goodbye(RejectReason::Overloaded),
+                    _ = writer_stop_for_task.cancelled() => break,
+                    received = queue.recv() => match received {
+                        Some(item) => item,
+                        None => break,
+                    },
+                };
+
+                let written = match &item {
+                    OutboundItem::Frame(data) => Self::send_message(&mut write_half, data).await
+                        .map(|()| counters_for_writer.record_outbound(data.len())),
+                    OutboundItem::Ping => Self::send_message(&mut write_half, &Frame::Ping.encode()).await,
+                    OutboundItem::Goodbye(reason) => {
+                        let _ = Self::send_message(&mut write_half, &Frame::Goodbye(Some(*reason)).encode()).await;
+                        break;
+                    }
+                };
+                if let Err(e) = written {
+                    error!("Failed to send message to client {}: {}", client_id_for_writer, e);
+                    write_failed_for_task.cancel();
+                    break;
+                }
+            }
+
+            // Close our side of the connection so the client sees a clean EOF
+            let _ = write_half.shutdown().await;
+        });
+
+        // Ping the client on a schedule; a live client answers with a pong
+        let pinger = self.ping_interval.map(|period| {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut pings = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    pings.tick().await;
+                    if tx.send(OutboundItem::Ping).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+
+        // Handle inbound messages from this client
+        let quota_key = ip_bucket(addr.ip());
+        let mut throttled_until: Option<Instant> = None;
+        let mut farewell = None;
+        let mut oversized_strikes = 0;
+        // Recipient with the largest backlog among those of the last frame relayed
+        let mut heaviest_recipient: Option<Arc<ClientCounters>> = None;
+        let reason = loop {
+            let read = async {
+                if let Some(until) = throttled_until {
+                    tokio::time::sleep_until(until.into()).await;
+                }
+                // Over the buffer budget: stop reading from a client whose frames
+                // are piling up until its recipient catches up
+                if let Some(recipient) = &heaviest_recipient {
+                    while self.budget.should_pause(recipient.queued_bytes.load(Ordering::Relaxed)) {
+                        tokio::time::sleep(BACKPRESSURE_POLL_INTERVAL).await;
+                    }
+                }
+                match self.read_timeout {
+                    Some(limit) => tokio::time::timeout(limit, self.read_message(&mut read_half, max_message_size)).await.ok(),
+                    None => Some(self.read_message(&mut read_half, max_message_size).await),
+                }
+            };
+            let result = tokio::select! {
+                result = read => match result {
+                    Some(result) => result,
+                    None => break DisconnectReason::ReadTimeout,
+                },
+                _ = kick.notified() => {
+                    if counters.overloaded.load(Ordering::Relaxed) {
+                        break DisconnectReason::Overloaded;
+                    }
+                    if counters.idle.load(Ordering::Relaxed) {
+                        farewell = Some(RejectReason::Idle);
+                        break DisconnectReason::Idle;
+                    }
+                    farewell = Some(RejectReason::Kicked);
+                    break DisconnectReason::Kicked;
+                }
+                _ = write_failed.cancelled() => break DisconnectReason::WriteError,
+                _ = self.shutdown.cancelled() => {
+                    // Tell the client the relay is going away on purpose, so
+                    // it can move on without treating it as a network failure
+                    farewell = Some(if self.draining.load(Ordering::Relaxed) {
+                        // Still here when the drain ran out
+                        RejectReason::Draining
+                    } else {
+                        RejectReason::ShuttingDown
+                    });
+                    break DisconnectReason::Shutdown;
+                }
+            };
+
+            let data = match result {
+                Ok(Some(data)) => data,
+                Ok(None) => break DisconnectReason::Closed,
+                Err(e) if e.is::<OversizedFrame>() => {
+                    // The payload was skipped, so the stream is still in sync
+                    oversized_strikes += 1;
+                    if oversized_strikes >= OVERSIZED_FRAME_STRIKES {
+                        warn!("Client {} ({}): {}, disconnecting after {} oversized frames",
+                              client_id, addr, e, oversized_strikes);
+                        farewell = Some(RejectReason::FrameTooLarge);
+                        break DisconnectReason::OversizedFrames;
+                    }
+                    warn!("Client {} ({}): {}, discarded", client_id, addr, e);
+                    let notice = Frame::Oversized(max_message_size as u32).encode();
+                    self.budget.queue(&tx, &counters, notice.into());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Error reading from client {}: {}", client_id, e);
+                    break DisconnectReason::ReadError;
+                }
+            };
+
+            match Frame::decode(&data) {
+                Ok(Frame::Pong) => {
+                    // Answer to our ping: proves the client is there, but has
+                    // nothing to relay
+                    counters.record_keepalive(connected_at.elapsed().as_millis() as u64);
+                }
+                Ok(Frame::Ping) => {
+                    counters.record_keepalive(connected_at.elapsed().as_millis() as u64);
+                    self.budget.queue(&tx, &counters, Frame::Pong.encode().into());
+                }
+                Ok(Frame::Goodbye(_)) => break DisconnectReason::Closed,
+                Ok(Frame::Data(_)) => {
+                    debug!("Received {} bytes from client {}", data.len(), client_id);
+                    counters.record_inbound(data.len(), connected_at.elapsed().as_millis() as u64);
+                    session_counters.record_inbound();
+
+                    if !self.charge_quota(quota_key, data.len()) {
+                        match self.quota_action {
+                            QuotaAction::Disconnect => {
+                                warn!("Client {} ({}) exceeded its quota, disconnecting", client_id, addr);
+                                self.stats.quota_exceeded.fetch_add(1, Ordering::Relaxed);
+                                farewell = Some(RejectReason::QuotaExceeded);
+                                break DisconnectReason::QuotaExceeded;
+                            }
+                            QuotaAction::Throttle => {
+                                if throttled_until.is_none() {
+                                    warn!("Client {} ({}) exceeded its quota, throttling", client_id, addr);
+                                    self.stats.quota_exceeded.fetch_add(1, Ordering::Relaxed);
+                                }
+                                let pause = Duration::from_secs_f64(data.len() as f64 / QUOTA_TRICKLE_RATE);
+                                throttled_until = Some(Instant::now() + pause);
+                            }
+                        }
+                    }
+                    self.stats.record_frame(data.len());
+                    
+                    // Forward the frame as is, type byte included, to the
+                    // other clients in this session
+                    let frame_len = data.len();
+                    let broadcast = self.broadcast_message(client_id, &join.session_code, data.into()).await;
+                    heaviest_recipient = broadcast.heaviest;
+                    if let Some(limit) = broadcast.too_large_for {
+                        // Tell the sender, as for a frame over its own limit
+                        debug!("Client {} ({}): {} byte frame over a recipient's {} byte limit, not delivered to it",
+                               client_id, addr, frame_len, limit);
+                        let notice = Frame::Oversized(limit as u32).encode();
+                        self.budget.queue(&tx, &counters, notice.into());
+                    }
+                }
+                Ok(Frame::Oversized(_)) | Err(_) => {
+                    warn!("Client {} ({}) sent an invalid frame, disconnecting", client_id, addr);
+                    break DisconnectReason::InvalidFrame;
+                }
+            }
+        };
+
+        // Cleanup: stop routing to the client, then let the writer finish its
+        // current frame before closing. A goodbye goes out behind whatever is
+        // already queued and ends the writer; a client shed for its backlog
+        // gets its goodbye instead of the backlog.
+        self.unregister(client_id, &join.session_code).await;
+        if let Some(pinger) = pinger {
+            pinger.abort();
+        }
+        let farewell_queued = farewell.is_some_and(|reason| tx.try_send(OutboundItem::Goodbye(reason)).is_ok());
+        if reason == DisconnectReason::Overloaded {
+            shed.cancel();
+        }
+        if reason == DisconnectReason::ReadTimeout {
+            // The peer is most likely gone, so a pending write would only hang
+            write_task.abort();
+        } else {
+            if !farewell_queued {
+                writer_stop.cancel();
+            }
+            if tokio::time::timeout(WRITER_CLOSE_TIMEOUT, &mut write_task).await.is_err() {
+                write_task.abort();
+            }
+        }
+
+        let totals = ConnectionTotals {
+            frames_in: counters.frames_in.load(Ordering::Relaxed),
+            frames_out: counters.frames_out.load(Ordering::Relaxed),
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            frames_dropped: counters.frames_dropped.load(Ordering::Relaxed),
+            duration: connected_at.elapsed(),
+        };
+        self.stats.record_disconnect(&totals);
+        logging::disconnected(client_id, addr, &join.session_code, reason, totals);
+        if reason.is_violation() {
+            self.strike(addr, reason.as_str());
+        }
+
+        Ok(())
+    }
+
+    /// Count a protocol violation against the client's address, banning it
+    /// once they add up
+    fn strike(&self, addr: SocketAddr, violation: &str) {
+        let key = ip_bucket(addr.ip());
+        let banned = self.bans.lock().unwrap_or_else(|e| e.into_inner()).strike(key, Instant::now());
+        if let Some(duration) = banned {
+            warn!("Banning {} for {:?} after repeated protocol violations (last: {})", key, duration, violation);
+        }
+    }
+
+    /// Charge an inbound frame to the sender's quota; false once it is over
+    fn charge_quota(&self, key: IpAddr, bytes: usize) -> bool {
+        match &self.quota {
+            Some(quota) => quota.lock().unwrap_or_else(|e| e.into_inner()).charge(key, bytes, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Read a message from the stream (length-prefixed)
+    async fn read_message<R: AsyncReadExt + Unpin>(&self, stream: &mut R, limit: usize) -> 
+        Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        
+        // Read 4-byte length prefix
+        let length = match stream.read_u32().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if length > limit {
+            if length > MAX_DISCARD_LEN {
+                return Err(format!("Message too large: {} > {}", length, limit).into());
+            }
+
+            // Skip the payload so the next frame can still be read
+            let skipped = tokio::io::copy(&mut (&mut *stream).take(length as u64), &mut tokio::io::sink()).await?;
+            if skipped < length as u64 {
+                return Ok(None);
+            }
+            return Err(Box::new(OversizedFrame { length, limit }));
+        }
+
+        // Read message data
+        let mut buffer = vec![0u8; length];
+        stream.read_exact(&mut buffer).await?;
+        
+        Ok(Some(buffer))
+    }
+
+    /// Send a message to the stream (length-prefixed)
+    async fn send_message<W: AsyncWriteExt + Unpin>(stream: &mut W, data: &[u8]) -> 
+        Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        
+        // One write per frame so the prefix and payload share a segment
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        stream.write_all(&frame).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Remove a client from the registry, withdrawing the session from the
+    /// federation once its last local member is gone
+    async fn unregister(&self, client_id: Uuid, session: &str) {
+        let mut clients = self.clients.write().await;
+        clients.remove(&client_id);
+        if let Some(federation) = &self.federation {
+            if clients.session_members(session).next().is_none() {
+                federation.local_left(session);
+            }
+        }
+    }
+
+    /// Broadcast message to all clients in the session except sender
+    async fn broadcast_message(&self, sender_id: Uuid, session: &str, data: Bytes) -> Broadcast {
+        // Only clone the recipients' handles under the read lock; queueing
+        // happens after it is released
+        let (recipients, session_counters) = {
+            let clients_guard = self.clients.read().await;
+            let recipients: Vec<ClientInfo> = clients_guard
+                .session_members(session)
+                .filter(|client| client.id != sender_id) // Don't echo back to sender
+                .cloned()
+                .collect();
+            let session_counters = clients_guard.session_counters(session).cloned();
+
+            let forwarded = self.federation.as_ref().is_some_and(|f| f.forward(session, &data));
+            if let (true, Some(counters)) = (forwarded, &session_counters) {
+                counters.frames_forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Nobody else in the session yet, here or on a peer relay: hold the
+            // frame for whoever joins next. Registration takes the write lock,
+            // so it can't slip in between.
+            if recipients.is_empty() && !forwarded {
+                if let Some(store_forward) = &self.store_forward {
+                    let stored = store_forward
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(session, data, Instant::now());
+                    if let Some(counters) = &session_counters {
+                        let counter = if stored { &counters.frames_buffered } else { &counters.frames_dropped };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return Broadcast::default();
+            }
+            (recipients, session_counters)
+        };
+
+        let heaviest = recipients
+            .iter()
+            .map(|client| &client.counters)
+            .max_by_key(|counters| counters.queued_bytes.load(Ordering::Relaxed))
+            .cloned();
+        let too_large_for = recipients
+            .iter()
+            .map(|client| client.max_message_size)
+            .filter(|&limit| data.len() > limit)
+            .min();
+        if !queue_frame(recipients, data, &self.stats, &self.budget, session_counters.as_deref()) {
+            backpressure::shed_heaviest_client(&*self.clients.read().await);
+        }
+        Broadcast { heaviest, too_large_for }
+    }
+}
+
+/// What became of a relayed frame
+#[derive(Default)]
+struct Broadcast {
+    /// Recipient with the largest backlog
+    heaviest: Option<Arc<ClientCounters>>,
+    /// Smallest size limit among the recipients the frame was too large for
+    too_large_for: Option<usize>,
+}
+
+/// Queue a frame for each recipient, dropping it for clients that are behind
+/// or whose size limit it exceeds; false if it was dropped for going over the
+/// buffer budget's hard limit
+fn queue_frame(
+    recipients: Vec<ClientInfo>,
+    data: Bytes,
+    stats: &RelayStats,
+    budget: &BufferBudget,
+    session: Option<&SessionCounters>,
+) -> bool {
+    let mut within_budget = true;
+    for client_info in recipients {
+        if data.len() > client_info.max_message_size {
+            // Larger than the client agreed to receive
+            stats.frames_dropped.fetch_add(1, Ordering::Relaxed);
+            client_info.counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
+            if let Some(session) = session {
+                session.frames_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            continue;
+        }
+        let result = budget.try_queue(&client_info.sender, &client_info.counters, data.clone());
+        if let Some(session) = session {
+            match result {
+                Ok(()) => session.frames_delivered.fetch_add(1, Ordering::Relaxed),
+                Err(QueueError::Full | QueueError::OverBudget) => session.frames_dropped.fetch_add(1, Ordering::Relaxed),
+                Err(QueueError::Closed) => 0,
+            };
+        }
+        match result {
+            Ok(()) => client_info.counters.consecutive_drops.store(0, Ordering::Relaxed),
+            Err(QueueError::Full) => {
+                // Drop the newest frame rather than stall the whole session,
+                // and cut the client loose if it never catches up
+                stats.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                let consecutive = client_info.counters.record_dropped();
+                if consecutive == 1 {
+                    debug!("Client {} queue full, dropping frames", client_info.id);
+                }
+                if consecutive == SLOW_CLIENT_DROP_LIMIT {
+                    warn!("Client {} ({}) dropped {} frames in a row, disconnecting",
+                          client_info.id, client_info.addr, consecutive);
+                    client_info.kick.notify_one();
+                }
+            }
+            Err(QueueError::OverBudget) => {
+                stats.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                client_info.counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                within_budget = false;
+            }
+            Err(QueueError::Closed) => {
+                // Client is going away; its connection task cleans up
+                debug!("Client {} ({}) channel closed during broadcast", client_info.id, client_info.addr);
+            }
+        }
+    }
+    within_budget
+}
+
+/// Install the log subscriber the arguments ask for as the global default
+pub fn init_logging(args: &Args) -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    tracing::subscriber::set_global_default(logging::subscriber(args.log_format, std::io::stdout))
+}
+
+/// A relay started with `run_relay`
+pub struct BoundInfo {
+    /// Client listen addresses in `--listen` order, ephemeral ports resolved
+    pub addrs: Vec<SocketAddr>,
+    /// Serves until shutdown, then finishes once clients have drained
+    pub task: tokio::task::JoinHandle<Result<(), String>>,
+}
+
+impl BoundInfo {
+    /// The first client listen address
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+}
+
+/// Run a relay inside this process: returns as soon as its listeners are
+/// bound and keeps serving in the background until `shutdown` is cancelled.
+/// Unlike the binary it installs no signal handlers or log subscriber.
+pub async fn run_relay(args: Args, shutdown: CancellationToken) -> Result<BoundInfo, Box<dyn std::error::Error>> {
+    let server = RelayServer::new(args)?.with_shutdown(shutdown);
+    let listeners = server.bind().await?;
+    let addrs = listeners.iter().map(TcpListener::local_addr).collect::<std::io::Result<Vec<_>>>()?;
+    let task = tokio::spawn(async move { server.serve(listeners).await.map_err(|e| e.to_string()) });
+    Ok(BoundInfo { addrs, task })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    struct TestRelay {
+        addr: SocketAddr,
+        addrs: Vec<SocketAddr>,
+        server: Arc<RelayServer>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    async fn spawn_relay(extra_args: &[&str]) -> TestRelay {
+        let mut argv = vec!["silence-relay", "--bind-address", "127.0.0.1", "--port", "0"];
+        argv.extend_from_slice(extra_args);
+        let server = Arc::new(RelayServer::new(Args::parse_from(argv)).unwrap());
+        let listeners = server.bind().await.unwrap();
+        let addrs: Vec<_> = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+        let addr = addrs[0];
+
+        let serving = Arc::clone(&server);
+        let task = tokio::spawn(async move {
+            serving.serve(listeners).await.expect("relay serve failed");
+        });
+
+        TestRelay { addr, addrs, server, task }
+    }
+
+    async fn start_relay(extra_args: &[&str]) -> SocketAddr {
+        spawn_relay(extra_args).await.addr
+    }
+
+    async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) {
+        ClientHandler::send_message(stream, data).await.unwrap();
+    }
+
+    async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Option<Vec<u8>> {
+        let length = stream.read_u32().await.ok()? as usize;
+        let mut buffer = vec![0u8; length];
+        stream.read_exact(&mut buffer).await.ok()?;
+        Some(buffer)
+    }
+
+    /// Send a payload as a data frame
+    async fn send_data<S: AsyncWrite + Unpin>(stream: &mut S, payload: &[u8]) {
+        write_frame(stream, &Frame::Data(payload).encode()).await;
+    }
+
+    /// Payload of the next frame, which has to be a data frame
+    async fn recv_data<S: AsyncRead + Unpin>(stream: &mut S) -> Option<Vec<u8>> {
+        let frame = read_frame(stream).await?;
+        match Frame::decode(&frame) {
+            Ok(Frame::Data(payload)) => Some(payload.to_vec()),
+            other => panic!("expected a data frame, got {:?}", other),
+        }
+    }
+
+    fn join_frame(session: &str) -> Vec<u8> {
+        ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: session.to_string(),
+            max_message_size: None,
+            token: None,
+        }.encode()
+    }
+
+    async fn join(addr: SocketAddr, session: &str) -> (TcpStream, ServerHello) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &join_frame(session)).await;
+        let hello = read_frame(&mut stream).await.expect("no hello from relay");
+        (stream, ServerHello::decode(&hello).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_hello_reports_id_limits_and_peer_presence() {
+        let addr = start_relay(&["--max-message-size", "2048"]).await;
+
+        let (_first, hello) = join(addr, "session-a").await;
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        assert_eq!(hello.max_message_size, 2048);
+        assert!(!hello.peer_present);
+
+        let (_second, second_hello) = join(addr, "session-a").await;
+        assert!(second_hello.peer_present);
+        assert_ne!(hello.client_id, second_hello.client_id);
+    }
+
+    #[tokio::test]
+    async fn test_frames_stay_within_session() {
+        let addr = start_relay(&[]).await;
+
+        let (mut alice, _) = join(addr, "shared").await;
+        let (mut bob, _) = join(addr, "shared").await;
+        let (mut eve, _) = join(addr, "other").await;
+
+        send_data(&mut alice, b"hello bob").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"hello bob");
+
+        let leaked = tokio::time::timeout(Duration::from_millis(200), read_frame(&mut eve)).await;
+        assert!(leaked.is_err(), "frame leaked into another session");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_discarded_with_notice() {
+        let addr = start_relay(&["--max-message-size", "1024"]).await;
+        let (mut alice, _) = join(addr, "sized").await;
+        let (mut bob, _) = join(addr, "sized").await;
+
+        // One oversized frame is skipped and reported; the connection carries on
+        send_data(&mut alice, &[0x11; 2000]).await;
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Oversized(1024).encode());
+        send_data(&mut alice, b"fits").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"fits");
+
+        // Repeat offenders are disconnected with a goodbye after the last notice
+        for _ in 1..OVERSIZED_FRAME_STRIKES {
+            send_data(&mut alice, &[0x11; 2000]).await;
+        }
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Oversized(1024).encode());
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Goodbye(Some(RejectReason::FrameTooLarge)).encode());
+        assert!(read_frame(&mut alice).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_frame_beyond_discard_cap_disconnects() {
+        let addr = start_relay(&["--max-message-size", "1024"]).await;
+        let (mut alice, _) = join(addr, "sized").await;
+
+        alice.write_u32(MAX_DISCARD_LEN as u32 + 1).await.unwrap();
+        assert!(read_frame(&mut alice).await.is_none());
+    }
+
+    /// Access log events captured so far, waiting up to two seconds for `until`
+    async fn access_events(output: &logging::CapturedOutput, until: &str) -> Vec<serde_json::Value> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !output.contents().contains(&format!(r#""event":"{}""#, until)) {
+            assert!(Instant::now() < deadline, "no {} event logged", until);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        output
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("log line is not JSON"))
+            .filter(|event: &serde_json::Value| event["target"] == logging::ACCESS_TARGET)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_json_access_log_for_short_session() {
+        let output = logging::CapturedOutput::default();
+        let writer = output.clone();
+        let _guard = tracing::subscriber::set_default(logging::subscriber(LogFormat::Json, move || writer.clone()));
+
+        let relay = spawn_relay(&["--max-clients", "1"]).await;
+        let (mut alice, hello) = join(relay.addr, "audited").await;
+        send_data(&mut alice, b"logged").await;
+        let mut refused = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut refused).await.unwrap(), encode_rejection(RejectReason::ServerFull));
+        drop(alice);
+
+        let events = access_events(&output, "disconnect").await;
+        let find = |name: &str| events.iter().find(|event| event["event"] == name).cloned().unwrap();
+        let client_id = hello.client_id.to_string();
+
+        assert!(find("connect")["peer_addr"].is_string());
+        let joined = find("join");
+        assert_eq!(joined["client_id"], client_id.as_str());
+        assert_eq!(joined["session"], "audited");
+        assert_eq!(find("reject")["reason"], "server_full");
+
+        let disconnect = find("disconnect");
+        assert_eq!(disconnect["client_id"], client_id.as_str());
+        assert_eq!(disconnect["peer_addr"], joined["peer_addr"]);
+        assert_eq!(disconnect["session"], "audited");
+        assert_eq!(disconnect["frames_in"], 1);
+        assert_eq!(disconnect["frames_out"], 0);
+        assert_eq!(disconnect["bytes_in"], 7);
+        assert_eq!(disconnect["bytes_out"], 0);
+        assert!(disconnect["duration_ms"].is_u64());
+        assert_eq!(disconnect["reason"], "closed");
+        assert_eq!(relay.server.stats.connections_closed.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_json_access_log_for_read_error() {
+        let output = logging::CapturedOutput::default();
+        let writer = output.clone();
+        let _guard = tracing::subscriber::set_default(logging::subscriber(LogFormat::Json, move || writer.clone()));
+
+        let relay = spawn_relay(&[]).await;
+        let (mut alice, hello) = join(relay.addr, "broken").await;
+        let (mut bob, _) = join(relay.addr, "broken").await;
+        send_data(&mut bob, b"to alice").await;
+        assert_eq!(recv_data(&mut alice).await.unwrap(), b"to alice");
+        send_data(&mut alice, b"to bob").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"to bob");
+
+        // A length prefix far past the discard cap can't be skipped
+        alice.write_u32(64 << 20).await.unwrap();
+        assert_eq!(read_frame(&mut alice).await, None);
+
+        let events = access_events(&output, "disconnect").await;
+        let disconnect = events.iter().find(|event| event["event"] == "disconnect").unwrap();
+        assert_eq!(disconnect["client_id"], hello.client_id.to_string().as_str());
+        assert_eq!(disconnect["session"], "broken");
+        assert_eq!(disconnect["frames_in"], 1);
+        assert_eq!(disconnect["frames_out"], 1);
+        assert_eq!(disconnect["bytes_in"], 7);
+        assert_eq!(disconnect["bytes_out"], 9);
+        assert_eq!(disconnect["reason"], "read_error");
+
+        let stats = relay.server.stats.snapshot();
+        assert_eq!(stats.connections_closed, 1);
+        assert_eq!(stats.frames_delivered, 1);
+        assert_eq!(stats.bytes_delivered, 9);
+    }
+
+    #[tokio::test]
+    async fn test_garbage_join_is_rejected() {
+        let addr = start_relay(&[]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &[PROTOCOL_VERSION, 0xde, 0xad, 0xbe, 0xef]).await;
+        assert!(read_frame(&mut stream).await.is_none());
+
+        // A leading byte outside the version range is answered with the range
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &[0xde, 0xad, 0xbe, 0xef]).await;
+        assert_eq!(read_frame(&mut stream).await.unwrap(), encode_rejection(RejectReason::UnsupportedVersion));
+        assert!(read_frame(&mut stream).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_disconnected() {
+        let addr = start_relay(&["--idle-timeout", "1"]).await;
+
+        let (mut idle, _) = join(addr, "quiet").await;
+        let goodbye = tokio::time::timeout(Duration::from_secs(4), read_frame(&mut idle)).await;
+        assert_eq!(goodbye.expect("idle client was not disconnected"), Some(Frame::Goodbye(Some(RejectReason::Idle)).encode()));
+        assert_eq!(read_frame(&mut idle).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_active_client_survives_idle_sweep() {
+        let addr = start_relay(&["--idle-timeout", "1"]).await;
+
+        let (mut alice, _) = join(addr, "busy").await;
+        let (mut bob, _) = join(addr, "busy").await;
+
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            send_data(&mut alice, b"ping").await;
+            assert_eq!(recv_data(&mut bob).await.unwrap(), b"ping");
+            send_data(&mut bob, b"pong").await;
+            assert_eq!(recv_data(&mut alice).await.unwrap(), b"pong");
+        }
+    }
+
+    #[test]
+    fn test_ip_bucket_groups_ipv6_by_prefix() {
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:ffff::9".parse().unwrap();
+        let other: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(ip_bucket(a), ip_bucket(b));
+        assert_ne!(ip_bucket(a), ip_bucket(other));
+
+        let mapped: IpAddr = "::ffff:192.0.2.7".parse().unwrap();
+        assert_eq!(ip_bucket(mapped), "192.0.2.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_join_protocol_versions() {
+        let addr = start_relay(&[]).await;
+
+        // Current and older-but-supported versions both get a hello
+        let (_current, hello) = join(addr, "versions").await;
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        let mut older = TcpStream::connect(addr).await.unwrap();
+        let join = ClientJoin {
+            version: MIN_PROTOCOL_VERSION,
+            session_code: "versions".to_string(),
+            max_message_size: None,
+            token: None,
+        };
+        write_frame(&mut older, &join.encode()).await;
+        assert!(ServerHello::decode(&read_frame(&mut older).await.unwrap()).unwrap().peer_present);
+
+        // A version outside the range is told which versions are supported
+        let mut newer = TcpStream::connect(addr).await.unwrap();
+        let join = ClientJoin {
+            version: PROTOCOL_VERSION + 1,
+            session_code: "versions".to_string(),
+            max_message_size: None,
+            token: None,
+        };
+        write_frame(&mut newer, &join.encode()).await;
+        assert_eq!(
+            read_frame(&mut newer).await.unwrap(),
+            vec![0x00, RejectReason::UnsupportedVersion as u8, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION]
+        );
+        assert!(read_frame(&mut newer).await.is_none());
+
+        // Version 2 clients send untyped frames, so they are refused too
+        let mut untyped = TcpStream::connect(addr).await.unwrap();
+        let join = ClientJoin { version: 2, session_code: "versions".to_string(), max_message_size: None, token: None };
+        write_frame(&mut untyped, &join.encode()).await;
+        assert_eq!(read_frame(&mut untyped).await.unwrap(), encode_rejection(RejectReason::UnsupportedVersion));
+
+        // A pre-versioning client sending a bare session code is disconnected
+        let mut legacy = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut legacy, b"versions").await;
+        assert!(read_frame(&mut legacy).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_probes_are_answered_before_joining() {
+        let addr = start_relay(&["--auth-token", "secret"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // Replies echo each probe's id, with receive times that never go back
+        let mut last_received = 0;
+        for i in 0..PROBE_LIMIT as u8 {
+            let id = [i; 8];
+            write_frame(&mut stream, &Probe { id }.encode()).await;
+            let reply = ProbeReply::decode(&read_frame(&mut stream).await.unwrap()).unwrap();
+            assert_eq!(reply.id, id);
+            assert!(reply.received_us >= last_received);
+            last_received = reply.received_us;
+        }
+
+        // Past the limit probes go unanswered, yet the connection stays open
+        write_frame(&mut stream, &Probe { id: [0xff; 8] }.encode()).await;
+        assert!(tokio::time::timeout(Duration::from_millis(300), read_frame(&mut stream)).await.is_err());
+
+        // Probing needs no token, joining still does
+        let join = ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: "probed".to_string(),
+            max_message_size: None,
+            token: Some("secret".to_string()),
+        };
+        write_frame(&mut stream, &join.encode()).await;
+        let hello = ServerHello::decode(&read_frame(&mut stream).await.unwrap()).unwrap();
+        assert!(!hello.peer_present);
+
+        // The limit is per connection
+        let mut later = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut later, &Probe { id: [1; 8] }.encode()).await;
+        assert_eq!(ProbeReply::decode(&read_frame(&mut later).await.unwrap()).unwrap().id, [1; 8]);
+    }
+
+    #[tokio::test]
+    async fn test_address_requests_echo_the_client_address() {
+        let addr = start_relay(&[]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &ADDRESS_REQUEST).await;
+        let observed = ObservedAddress::decode(&read_frame(&mut stream).await.unwrap()).unwrap();
+        assert_eq!(observed.addr, stream.local_addr().unwrap());
+
+        // Joining afterwards works as ever
+        let join = ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: "echoed".to_string(),
+            max_message_size: None,
+            token: None,
+        };
+        write_frame(&mut stream, &join.encode()).await;
+        assert!(ServerHello::decode(&read_frame(&mut stream).await.unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_probe_limit_resets_each_window() {
+        let mut limit = ProbeLimit::default();
+        let start = Instant::now();
+        assert!((0..PROBE_LIMIT).all(|_| limit.allow(start)));
+        assert!(!limit.allow(start + PROBE_WINDOW / 2));
+        assert!(limit.allow(start + PROBE_WINDOW));
+    }
+
+    #[tokio::test]
+    async fn test_clients_negotiate_smaller_frame_limits() {
+        let addr = start_relay(&["--max-message-size", "2048"]).await;
+        let join_with_limit = |limit| ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: "sized".to_string(),
+            max_message_size: Some(limit),
+            token: None,
+        };
+
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut alice, &join_with_limit(512).encode()).await;
+        let hello = ServerHello::decode(&read_frame(&mut alice).await.unwrap()).unwrap();
+        assert_eq!(hello.max_message_size, 512);
+
+        // Asking for more than the relay allows gets the relay's limit
+        let mut bob = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut bob, &join_with_limit(1 << 20).encode()).await;
+        let hello = ServerHello::decode(&read_frame(&mut bob).await.unwrap()).unwrap();
+        assert_eq!(hello.max_message_size, 2048);
+
+        // Alice's own frames are held to her limit
+        send_data(&mut alice, &[1; 600]).await;
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Oversized(512).encode());
+
+        // Frames over it don't reach her either, and the sender learns why
+        send_data(&mut bob, &[2; 600]).await;
+        assert_eq!(read_frame(&mut bob).await.unwrap(), Frame::Oversized(512).encode());
+        send_data(&mut bob, &[3; 100]).await;
+        assert_eq!(recv_data(&mut alice).await.unwrap(), vec![3; 100]);
+    }
+
+    #[tokio::test]
+    async fn test_full_relay_sends_server_full() {
+        let addr = start_relay(&["--max-clients", "1"]).await;
+        let (_first, _) = join(addr, "solo").await;
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let rejection = read_frame(&mut second).await.expect("no rejection frame");
+        assert_eq!(rejection, encode_rejection(RejectReason::ServerFull));
+        assert!(read_frame(&mut second).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_per_ip_limit_rejects_excess_connections() {
+        let addr = start_relay(&["--max-clients-per-ip", "2"]).await;
+
+        let (_first, _) = join(addr, "crowd").await;
+        let (_second, _) = join(addr, "crowd").await;
+
+        // Third connection from the same address gets a rejection frame
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let rejection = read_frame(&mut third).await.expect("no rejection frame");
+        assert_eq!(rejection, encode_rejection(RejectReason::TooManyFromAddress));
+        assert!(read_frame(&mut third).await.is_none());
+
+        // A different loopback source address has its own budget
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut other = socket.connect(addr).await.unwrap();
+        write_frame(&mut other, &join_frame("crowd")).await;
+        let hello = ServerHello::decode(&read_frame(&mut other).await.unwrap()).unwrap();
+        assert!(hello.peer_present);
+    }
+
+    #[tokio::test]
+    async fn test_per_ip_limit_counts_connections_that_never_join() {
+        let relay = spawn_relay(&["--max-clients-per-ip", "2", "--hello-timeout", "30"]).await;
+
+        // Connected but silent, each still holding a slot
+        let mut idle = Vec::new();
+        for _ in 0..2 {
+            idle.push(TcpStream::connect(relay.addr).await.unwrap());
+        }
+        let loopback = ip_bucket(relay.addr.ip());
+        while relay.server.open_connections.count(loopback) < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut third = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut third).await.unwrap(), encode_rejection(RejectReason::TooManyFromAddress));
+
+        // Their slots come back once they hang up
+        drop(idle);
+        while relay.server.open_connections.count(loopback) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (_joined, _) = join(relay.addr, "after").await;
+    }
+
+    #[tokio::test]
+    async fn test_config_reload_applies_new_limits() {
+        let path = std::env::temp_dir().join(format!("silence-relay-{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "max_clients_per_ip = 1\nstats_interval = 0\n").unwrap();
+        let matches = Args::command().get_matches_from([
+            "silence-relay", "--bind-address", "127.0.0.1", "--port", "0", "--config", path.to_str().unwrap(),
+        ]);
+        let args = config::resolve_args(&matches).unwrap();
+        let server = Arc::new(RelayServer::new(args).unwrap().with_config_source(matches));
+        let listeners = server.bind().await.unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let serving = Arc::clone(&server);
+        tokio::spawn(async move { serving.serve(listeners).await.unwrap() });
+
+        let (_first, _) = join(addr, "reloaded").await;
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut refused).await.unwrap(), encode_rejection(RejectReason::TooManyFromAddress));
+
+        // A broken file is refused and the running limits stay
+        std::fs::write(&path, "max_clients_per_ip = \"many\"\n").unwrap();
+        assert!(server.reload_config().await.is_err());
+        assert_eq!(server.config.current().max_clients_per_ip, 1);
+
+        // New limits apply to the next connection; startup-only settings don't change
+        std::fs::write(&path, "max_clients_per_ip = 4\nstats_interval = 30\n").unwrap();
+        server.reload_config().await.unwrap();
+        assert_eq!(server.config.current().max_clients_per_ip, 4);
+        assert_eq!(server.config.current().stats_interval, 0);
+        let (_second, hello) = join(addr, "reloaded").await;
+        assert!(hello.peer_present);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_log_throttle_counts_suppressed() {
+        let throttle = LogThrottle::new(Duration::from_secs(60));
+        assert_eq!(throttle.check(), Some(0));
+        assert_eq!(throttle.check(), None);
+        assert_eq!(throttle.check(), None);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_clients_and_exits() {
+        let relay = spawn_relay(&["--drain-timeout", "5"]).await;
+
+        let (mut alice, _) = join(relay.addr, "bye").await;
+        let (mut bob, _) = join(relay.addr, "bye").await;
+        send_data(&mut alice, b"last words").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"last words");
+
+        relay.server.shutdown.cancel();
+
+        // Both clients are told the relay is going away, their connections
+        // close and the server task finishes
+        let goodbye = Frame::Goodbye(Some(RejectReason::ShuttingDown)).encode();
+        assert_eq!(read_frame(&mut alice).await, Some(goodbye.clone()));
+        assert_eq!(read_frame(&mut alice).await, None);
+        assert_eq!(read_frame(&mut bob).await, Some(goodbye));
+        assert_eq!(read_frame(&mut bob).await, None);
+        tokio::time::timeout(Duration::from_secs(5), relay.task)
+            .await
+            .expect("relay did not exit after shutdown")
+            .unwrap();
+        assert_eq!(relay.server.clients.read().await.len(), 0);
+
+        // The listener is gone
+        assert!(TcpStream::connect(relay.addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_relay_serves_until_shutdown() {
+        let flags = ["--bind-address", "127.0.0.1", "--port", "0", "--max-message-size", "2048"];
+        let shutdown = CancellationToken::new();
+        let relay = run_relay(Args::from_flags(flags).unwrap(), shutdown.clone()).await.unwrap();
+        assert_ne!(relay.addr().port(), 0, "the bound port is reported");
+
+        let (mut alice, hello) = join(relay.addr(), "embedded").await;
+        assert_eq!(hello.max_message_size, 2048);
+        let (mut bob, _) = join(relay.addr(), "embedded").await;
+        send_data(&mut alice, b"in process").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"in process");
+
+        shutdown.cancel();
+        drop((alice, bob));
+        let finished = tokio::time::timeout(Duration::from_secs(10), relay.task).await.expect("relay kept running");
+        finished.unwrap().unwrap();
+        assert!(TcpStream::connect(relay.addrs[0]).await.is_err());
+    }
+
+    #[test]
+    fn test_from_flags_reports_bad_flags() {
+        assert!(Args::from_flags(["--port", "not-a-port"]).is_err());
+        assert_eq!(Args::from_flags(["--port", "9000"]).unwrap().listen_addrs(), vec!["0.0.0.0:9000".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_listeners_share_sessions() {
+        let relay = spawn_relay(&["--listen", "127.0.0.1:0", "--listen", "127.0.0.1:0"]).await;
+        assert_eq!(relay.addrs.len(), 2);
+        assert_ne!(relay.addrs[0], relay.addrs[1]);
+
+        let (mut alice, first) = join(relay.addrs[0], "two-doors").await;
+        let (mut bob, second) = join(relay.addrs[1], "two-doors").await;
+        assert!(!first.peer_present);
+        assert!(second.peer_present);
+
+        send_data(&mut alice, b"via the first").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"via the first");
+        send_data(&mut bob, b"via the second").await;
+        assert_eq!(recv_data(&mut alice).await.unwrap(), b"via the second");
+        assert_eq!(relay.server.clients.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure_names_the_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = taken.local_addr().unwrap().to_string();
+        let server = RelayServer::new(Args::parse_from([
+            "silence-relay", "--listen", "127.0.0.1:0", "--listen", &taken_addr,
+        ])).unwrap();
+
+        let err = server.bind().await.unwrap_err().to_string();
+        assert!(err.contains(&taken_addr), "{}", err);
+    }
+
+    /// Join after sending a PROXY header the way a load balancer would
+    async fn join_proxied(addr: SocketAddr, header: &[u8], session: &str) -> (TcpStream, ServerHello) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(header).await.unwrap();
+        write_frame(&mut stream, &join_frame(session)).await;
+        let hello = read_frame(&mut stream).await.expect("no hello from relay");
+        (stream, ServerHello::decode(&hello).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_header_supplies_client_address() {
+        let relay = spawn_relay(&["--proxy-protocol", "--max-clients-per-ip", "1"]).await;
+
+        let (mut alice, alice_hello) =
+            join_proxied(relay.addr, b"PROXY TCP4 203.0.113.7 192.0.2.1 51000 8080\r\n", "balanced").await;
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\0\x24".to_vec();
+        v2.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        v2.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v2.extend_from_slice(&[0x01, 0xbb, 0x1f, 0x90]);
+        let (mut bob, bob_hello) = join_proxied(relay.addr, &v2, "balanced").await;
+
+        {
+            let clients = relay.server.clients.read().await;
+            assert_eq!(clients.get(&alice_hello.client_id).unwrap().addr, "203.0.113.7:51000".parse().unwrap());
+            assert_eq!(clients.get(&bob_hello.client_id).unwrap().addr, "[2001:db8::7]:443".parse().unwrap());
+        }
+        send_data(&mut alice, b"through the balancer").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"through the balancer");
+
+        // Per-IP limits count the real client, not the balancer
+        let mut again = TcpStream::connect(relay.addr).await.unwrap();
+        again.write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 51001 8080\r\n").await.unwrap();
+        assert_eq!(read_frame(&mut again).await.unwrap(), encode_rejection(RejectReason::TooManyFromAddress));
+
+        // The balancer's own checks keep its address
+        let (_check, check_hello) = join_proxied(relay.addr, b"PROXY UNKNOWN\r\n", "health").await;
+        let check_addr = relay.server.clients.read().await.get(&check_hello.client_id).unwrap().addr;
+        assert!(check_addr.ip().is_loopback());
+
+        // Connections without a valid header are dropped before they join
+        let mut bare = TcpStream::connect(relay.addr).await.unwrap();
+        write_frame(&mut bare, &join_frame("balanced")).await;
+        assert_eq!(read_frame(&mut bare).await, None);
+        let mut malformed = TcpStream::connect(relay.addr).await.unwrap();
+        malformed.write_all(b"PROXY TCP4 not-an-address 192.0.2.1 1 2\r\n").await.unwrap();
+        assert_eq!(read_frame(&mut malformed).await, None);
+        assert_eq!(relay.server.clients.read().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_headers_are_ignored_unless_enabled() {
+        let relay = spawn_relay(&[]).await;
+
+        let (_alice, hello) = join(relay.addr, "direct").await;
+        let addr = relay.server.clients.read().await.get(&hello.client_id).unwrap().addr;
+        assert!(addr.ip().is_loopback());
+
+        // A spoofed header is just a broken join frame
+        let mut spoofed = TcpStream::connect(relay.addr).await.unwrap();
+        spoofed.write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 51000 8080\r\n").await.unwrap();
+        write_frame(&mut spoofed, &join_frame("direct")).await;
+        if let Some(reply) = read_frame(&mut spoofed).await {
+            assert!(ServerHello::decode(&reply).is_err(), "spoofed connection was welcomed");
+        }
+        let clients = relay.server.clients.read().await;
+        assert!(clients.values().all(|client| client.addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn test_drain_exits_once_clients_leave() {
+        let relay = spawn_relay(&["--drain-timeout", "30"]).await;
+        let (alice, _) = join(relay.addr, "winding-down").await;
+        let (mut bob, _) = join(relay.addr, "winding-down").await;
+
+        relay.server.draining.store(true, Ordering::Relaxed);
+        let mut late = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut late).await.unwrap(), encode_rejection(RejectReason::Draining));
+
+        // Conversations in progress carry on
+        let mut alice = alice;
+        send_data(&mut alice, b"still here").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"still here");
+        tokio::time::sleep(DRAIN_POLL_INTERVAL * 2).await;
+        assert!(!relay.task.is_finished(), "relay exited with clients still connected");
+
+        drop((alice, bob));
+        tokio::time::timeout(Duration::from_secs(3), relay.task)
+            .await
+            .expect("relay did not exit once drained")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_deadline_closes_stragglers_with_goodbye() {
+        let relay = spawn_relay(&["--drain-timeout", "1"]).await;
+        let (mut alice, _) = join(relay.addr, "lingering").await;
+
+        // Turning drain off again cancels the exit
+        relay.server.draining.store(true, Ordering::Relaxed);
+        tokio::time::sleep(DRAIN_POLL_INTERVAL * 2).await;
+        relay.server.draining.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!relay.task.is_finished(), "relay exited after drain was turned off");
+
+        let started = Instant::now();
+        relay.server.draining.store(true, Ordering::Relaxed);
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Goodbye(Some(RejectReason::Draining)).encode());
+        assert_eq!(read_frame(&mut alice).await, None);
+        assert!(started.elapsed() >= Duration::from_secs(1), "straggler closed before the drain deadline");
+        tokio::time::timeout(Duration::from_secs(3), relay.task)
+            .await
+            .expect("relay did not exit at the drain deadline")
+            .unwrap();
+    }
+
+    /// Self-signed localhost certificate written to a scratch directory
+    fn write_test_cert() -> (PathBuf, PathBuf, rustls::pki_types::CertificateDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("silence-relay-tls-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        (cert_path, key_path, certified.cert.der().clone())
+    }
+
+    async fn tls_connect(
+        addr: SocketAddr,
+        cert: rustls::pki_types::CertificateDer<'static>,
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let domain = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        connector.connect(domain, tcp).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tls_clients_relay_frames() {
+        let (cert_path, key_path, cert) = write_test_cert();
+        let addr = start_relay(&[
+            "--tls-cert", cert_path.to_str().unwrap(),
+            "--tls-key", key_path.to_str().unwrap(),
+        ]).await;
+
+        let mut alice = tls_connect(addr, cert.clone()).await;
+        assert_eq!(alice.get_ref().1.alpn_protocol(), Some(ALPN_PROTOCOL));
+        write_frame(&mut alice, &join_frame("secure")).await;
+        let hello = ServerHello::decode(&read_frame(&mut alice).await.unwrap()).unwrap();
+        assert!(!hello.peer_present);
+
+        let mut bob = tls_connect(addr, cert).await;
+        write_frame(&mut bob, &join_frame("secure")).await;
+        assert!(ServerHello::decode(&read_frame(&mut bob).await.unwrap()).unwrap().peer_present);
+
+        send_data(&mut alice, b"over tls").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"over tls");
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_client_fails_fast_on_tls_port() {
+        let (cert_path, key_path, _) = write_test_cert();
+        let addr = start_relay(&[
+            "--tls-cert", cert_path.to_str().unwrap(),
+            "--tls-key", key_path.to_str().unwrap(),
+        ]).await;
+
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        send_data(&mut plain, b"public").await;
+
+        let mut buffer = [0u8; 64];
+        let result = tokio::time::timeout(Duration::from_secs(2), plain.read(&mut buffer)).await;
+        let outcome = result.expect("plaintext client was left hanging");
+        // rustls may answer with an alert before closing, but never with a hello
+        if let Ok(n) = outcome {
+            assert!(n == 0 || buffer[0] == 0x15, "unexpected reply to plaintext client");
+        }
+    }
+
+    #[test]
+    fn test_missing_tls_files_are_reported() {
+        let result = RelayServer::new(Args::parse_from([
+            "silence-relay", "--tls-cert", "/nonexistent/cert.pem", "--tls-key", "/nonexistent/key.pem",
+        ]));
+        let error = result.err().expect("missing TLS files accepted").to_string();
+        assert!(error.contains("/nonexistent/cert.pem"));
+    }
+
+    async fn admin_request(
+        lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+        writer: &mut tokio::net::unix::OwnedWriteHalf,
+        request: &str,
+    ) -> serde_json::Value {
+        writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        let line = lines.next_line().await.unwrap().expect("admin closed connection");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_list_kick_and_drain() {
+        use tokio::io::AsyncBufReadExt;
+
+        let socket_path = std::env::temp_dir().join(format!("silence-admin-{}.sock", Uuid::new_v4()));
+        let addr = start_relay(&["--admin-socket", socket_path.to_str().unwrap()]).await;
+
+        let (mut alice, alice_hello) = join(addr, "admin-test").await;
+        let (_bob, _) = join(addr, "admin-test").await;
+        send_data(&mut alice, b"counted").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let admin = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let (read_half, mut writer) = admin.into_split();
+        let mut lines = tokio::io::BufReader::new(read_half).lines();
+
+        let listed = admin_request(&mut lines, &mut writer, r#"{"cmd":"list"}"#).await;
+        let clients = listed["clients"].as_array().unwrap();
+        assert_eq!(clients.len(), 2);
+        let alice_row = clients.iter().find(|c| c["id"] == alice_hello.client_id.to_string()).unwrap();
+        assert_eq!(alice_row["session"], "admin-test");
+        assert_eq!(alice_row["bytes_in"], 8);
+
+        let kicked = admin_request(
+            &mut lines,
+            &mut writer,
+            &format!(r#"{{"cmd":"kick","id":"{}"}}"#, alice_hello.client_id),
+        ).await;
+        assert_eq!(kicked["ok"], true);
+        assert_eq!(read_frame(&mut alice).await, Some(Frame::Goodbye(Some(RejectReason::Kicked)).encode()));
+        assert_eq!(read_frame(&mut alice).await, None);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let listed = admin_request(&mut lines, &mut writer, r#"{"cmd":"list"}"#).await;
+        assert_eq!(listed["clients"].as_array().unwrap().len(), 1);
+
+        let stats = admin_request(&mut lines, &mut writer, r#"{"cmd":"stats"}"#).await;
+        assert_eq!(stats["counters"]["frames_relayed"], 1);
+        assert_eq!(stats["active_clients"], 1);
+
+        // Draining turns new clients away with a reason
+        let drained = admin_request(&mut lines, &mut writer, r#"{"cmd":"drain"}"#).await;
+        assert_eq!(drained["draining"], true);
+        let mut late = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut late).await.unwrap(), encode_rejection(RejectReason::Draining));
+
+        let bad = admin_request(&mut lines, &mut writer, "not json").await;
+        assert_eq!(bad["ok"], false);
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_violations_ban_the_address() {
+        use tokio::io::AsyncBufReadExt;
+
+        let socket_path = std::env::temp_dir().join(format!("silence-admin-{}.sock", Uuid::new_v4()));
+        let addr = start_relay(&[
+            "--admin-socket", socket_path.to_str().unwrap(),
+            "--ban-strikes", "3", "--ban-duration", "1",
+        ]).await;
+        let misbehave = || async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            write_frame(&mut stream, &join_frame("not a session code!")).await;
+            assert_eq!(read_frame(&mut stream).await, None);
+        };
+
+        // A broken frame after joining counts too
+        let (mut alice, _) = join(addr, "strikes").await;
+        write_frame(&mut alice, &[]).await;
+        assert_eq!(read_frame(&mut alice).await, None);
+        misbehave().await;
+        let (_still_fine, _) = join(addr, "strikes").await;
+        misbehave().await;
+
+        let mut banned = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut banned).await.unwrap(), encode_rejection(RejectReason::Banned));
+
+        let admin = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let (read_half, mut writer) = admin.into_split();
+        let mut lines = tokio::io::BufReader::new(read_half).lines();
+        let bans = admin_request(&mut lines, &mut writer, r#"{"cmd":"bans"}"#).await;
+        assert_eq!(bans["bans"][0]["ip"], "127.0.0.1");
+        let unbanned = admin_request(&mut lines, &mut writer, r#"{"cmd":"unban","ip":"127.0.0.1"}"#).await;
+        assert_eq!(unbanned["unbanned"], true);
+        let (_welcome_back, _) = join(addr, "strikes").await;
+
+        // Bans run out on their own
+        for _ in 0..3 {
+            misbehave().await;
+        }
+        let mut banned = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut banned).await.unwrap(), encode_rejection(RejectReason::Banned));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (_after_expiry, _) = join(addr, "strikes").await;
+        let bans = admin_request(&mut lines, &mut writer, r#"{"cmd":"bans"}"#).await;
+        assert_eq!(bans["bans"].as_array().unwrap().len(), 0);
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_session_counters_follow_a_pair() {
+        use tokio::io::AsyncBufReadExt;
+
+        let socket_path = std::env::temp_dir().join(format!("silence-admin-{}.sock", Uuid::new_v4()));
+        let relay = spawn_relay(&["--admin-socket", socket_path.to_str().unwrap()]).await;
+        let (mut alice, alice_hello) = join(relay.addr, "counted-pair").await;
+        let (mut bob, _) = join(relay.addr, "counted-pair").await;
+        for frame in [&b"one"[..], b"two", b"three"] {
+            send_data(&mut alice, frame).await;
+            assert_eq!(recv_data(&mut bob).await.unwrap(), frame);
+        }
+        send_data(&mut bob, b"back").await;
+        assert_eq!(recv_data(&mut alice).await.unwrap(), b"back");
+
+        let admin = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let (read_half, mut writer) = admin.into_split();
+        let mut lines = tokio::io::BufReader::new(read_half).lines();
+        let request = r#"{"cmd":"stats","session":"counted-pair"}"#;
+
+        let session = admin_request(&mut lines, &mut writer, request).await;
+        assert_eq!(session["ok"], true, "{}", session);
+        assert_eq!(session["frames_in"], 4);
+        assert_eq!(session["frames_delivered"], 4);
+        assert_eq!(session["frames_dropped"], 0);
+        let members = session["members"].as_array().unwrap();
+        let alice_row = members.iter().find(|m| m["id"] == alice_hello.client_id.to_string()).unwrap();
+        assert_eq!(alice_row["frames_in"], 3);
+        let label = session["metrics_label"].as_str().unwrap().to_string();
+        assert_eq!(label.len(), 2);
+
+        let health = HealthContext {
+            clients: Arc::clone(&relay.server.clients),
+            draining: Arc::clone(&relay.server.draining),
+            stats: Arc::clone(&relay.server.stats),
+            started_at: relay.server.started_at,
+            saturation_clients: 1,
+            listeners: relay.addrs.clone(),
+        };
+        let series = format!("silence_relay_session_frames_delivered{{session_bucket=\"{}\"}} 4", label);
+        let metrics = health.metrics().await;
+        assert!(metrics.contains(&series), "{}", metrics);
+        assert!(!metrics.contains("counted-pair"), "session codes stay out of metrics");
+
+        // The counters go away with the session
+        drop((alice, bob));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while relay.server.clients.read().await.session_counters("counted-pair").is_some() {
+            assert!(Instant::now() < deadline, "session counters outlived the session");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let ended = admin_request(&mut lines, &mut writer, request).await;
+        assert_eq!(ended["ok"], false);
+        assert!(!health.metrics().await.contains(&format!("session_bucket=\"{}\"", label)));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_times_out_before_join() {
+        let addr = start_relay(&["--hello-timeout", "1"]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(3), read_frame(&mut stream)).await;
+        assert_eq!(closed.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_times_out_after_join() {
+        let relay = spawn_relay(&["--client-read-timeout", "1", "--idle-timeout", "0"]).await;
+
+        // Joins, then never writes again, like a peer whose machine vanished
+        let (mut stream, _) = join(relay.addr, "half-open").await;
+        let closed = tokio::time::timeout(Duration::from_secs(3), read_frame(&mut stream)).await;
+        assert_eq!(closed.unwrap(), None);
+        assert_eq!(relay.server.clients.read().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ping_answers_keep_client_connected() {
+        let relay = spawn_relay(&["--client-read-timeout", "2", "--ping-interval", "1"]).await;
+        let (mut stream, _) = join(relay.addr, "pinged").await;
+
+        // Answer each ping with a pong for longer than the timeout
+        for _ in 0..3 {
+            let ping = tokio::time::timeout(Duration::from_secs(2), read_frame(&mut stream)).await;
+            assert_eq!(ping.unwrap().unwrap(), Frame::Ping.encode());
+            write_frame(&mut stream, &Frame::Pong.encode()).await;
+        }
+        assert_eq!(relay.server.clients.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_ping_is_answered_with_pong() {
+        let addr = start_relay(&[]).await;
+        let (mut alice, _) = join(addr, "echo").await;
+        let (mut bob, _) = join(addr, "echo").await;
+
+        write_frame(&mut alice, &Frame::Ping.encode()).await;
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Pong.encode());
+
+        // Control frames stay between the client and the relay
+        send_data(&mut alice, b"after ping").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"after ping");
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_untyped_frames_disconnect() {
+        let addr = start_relay(&[]).await;
+
+        // Empty frames used to be keepalives; now they are a protocol error
+        let (mut stream, _) = join(addr, "strict").await;
+        write_frame(&mut stream, &[]).await;
+        assert_eq!(read_frame(&mut stream).await, None);
+
+        let (mut stream, _) = join(addr, "strict").await;
+        write_frame(&mut stream, b"no type byte").await;
+        assert_eq!(read_frame(&mut stream).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_client_goodbye_closes_connection() {
+        let relay = spawn_relay(&[]).await;
+        let (mut stream, _) = join(relay.addr, "polite").await;
+
+        write_frame(&mut stream, &Frame::Goodbye(None).encode()).await;
+        assert_eq!(read_frame(&mut stream).await, None);
+        assert_eq!(relay.server.clients.read().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_frames_sent_before_join_are_forwarded() {
+        let relay = spawn_relay(&["--store-forward-ttl", "30"]).await;
+
+        let (mut alice, hello) = join(relay.addr, "waiting").await;
+        assert!(!hello.peer_present);
+        send_data(&mut alice, b"first").await;
+        send_data(&mut alice, b"second").await;
+
+        // Wait for the relay to buffer both frames before the partner joins
+        for _ in 0..50 {
+            if relay.server.stats.frames_buffered.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let (mut bob, _) = join(relay.addr, "waiting").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"first");
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"second");
+
+        send_data(&mut alice, b"live").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"live");
+    }
+
+
+    #[tokio::test]
+    async fn test_stalled_client_is_dropped_without_slowing_peers() {
+        let relay = spawn_relay(&[]).await;
+
+        // Bob joins with a tiny receive buffer and then never reads
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut bob = socket.connect(relay.addr).await.unwrap();
+        write_frame(&mut bob, &join_frame("stall")).await;
+        read_frame(&mut bob).await.expect("no hello from relay");
+
+        let (mut alice, _) = join(relay.addr, "stall").await;
+        let (mut carol, _) = join(relay.addr, "stall").await;
+
+        let frame = vec![0x5a; 60_000];
+        let mut kicked = false;
+        for sent in 1..=2000 {
+            send_data(&mut alice, &frame).await;
+            assert_eq!(recv_data(&mut carol).await.unwrap().len(), frame.len(),
+                       "fast peer missed frame {}", sent);
+
+            if relay.server.clients.read().await.len() == 2 {
+                kicked = true;
+                break;
+            }
+        }
+
+        assert!(kicked, "stalled client was never disconnected");
+        let stats = relay.server.stats.snapshot();
+        assert!(stats.frames_dropped >= SLOW_CLIENT_DROP_LIMIT);
+    }
+
+
+    #[tokio::test]
+    async fn test_access_lists_update_at_runtime() {
+        use acl::AccessRule;
+        use admin::AdminCommand;
+
+        let relay = spawn_relay(&["--deny-cidr", "127.0.0.0/8"]).await;
+        let admin = relay.server.admin_context();
+
+        let mut refused = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut refused).await.unwrap(), encode_rejection(RejectReason::NotAllowed));
+        assert!(read_frame(&mut refused).await.is_none());
+
+        let loopback: Cidr = "127.0.0.0/8".parse().unwrap();
+        let removed = admin.execute(AdminCommand::AclRemove { rule: AccessRule::Deny, cidr: loopback }).await;
+        assert_eq!(removed["removed"], true);
+
+        let (mut client, _) = join(relay.addr, "acl").await;
+
+        let added = admin.execute(AdminCommand::AclAdd { rule: AccessRule::Deny, cidr: loopback }).await;
+        assert_eq!(added["kicked"], 1);
+        let goodbye = tokio::time::timeout(Duration::from_secs(2), read_frame(&mut client)).await;
+        assert_eq!(goodbye.unwrap(), Some(Frame::Goodbye(Some(RejectReason::Kicked)).encode()));
+        assert_eq!(read_frame(&mut client).await, None, "banned client stayed connected");
+    }
+
+
+    #[tokio::test]
+    async fn test_quota_disconnects_and_survives_reconnect() {
+        let relay = spawn_relay(&["--quota-bytes", "1000"]).await;
+        let (mut alice, _) = join(relay.addr, "metered").await;
+        let (mut bob, _) = join(relay.addr, "metered").await;
+
+        let frame = vec![0x5a; 300];
+        for _ in 0..4 {
+            send_data(&mut alice, &frame).await;
+        }
+        for _ in 0..3 {
+            assert_eq!(recv_data(&mut bob).await.unwrap(), frame);
+        }
+        assert_eq!(read_frame(&mut alice).await.unwrap(), Frame::Goodbye(Some(RejectReason::QuotaExceeded)).encode());
+        assert!(read_frame(&mut alice).await.is_none());
+        assert_eq!(relay.server.stats.quota_exceeded.load(Ordering::Relaxed), 1);
+
+        // Usage is tracked per address, so reconnecting doesn't reset it
+        let mut again = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut again).await.unwrap(), encode_rejection(RejectReason::QuotaExceeded));
+
+        let listed = relay.server.admin_context().execute(admin::AdminCommand::List).await;
+        // Four frames of 300 bytes plus their type bytes
+        assert_eq!(listed["clients"][0]["quota_used"], 1204);
+    }
+
+    #[tokio::test]
+    async fn test_quota_throttle_slows_client_down() {
+        let addr = start_relay(&["--quota-bytes", "100", "--quota-action", "throttle"]).await;
+        let (mut alice, _) = join(addr, "trickle").await;
+        let (mut bob, _) = join(addr, "trickle").await;
+
+        // The first frame goes over quota but is still relayed; the next one
+        // waits out the trickle delay for 512 bytes (half a second)
+        let frame = vec![0x5a; 512];
+        send_data(&mut alice, &frame).await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), frame);
+
+        let started = Instant::now();
+        send_data(&mut alice, &frame).await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), frame);
+        assert!(started.elapsed() >= Duration::from_millis(400), "throttled frame arrived after {:?}", started.elapsed());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_joins_respect_max_clients() {
+        let relay = spawn_relay(&["--max-clients", "3", "--max-clients-per-ip", "100"]).await;
+
+        // Connect everyone before anyone joins, so all pass the accept-time check
+        let mut streams = Vec::new();
+        for _ in 0..10 {
+            streams.push(TcpStream::connect(relay.addr).await.unwrap());
+        }
+        let joins = streams.into_iter().map(|mut stream| {
+            tokio::spawn(async move {
+                write_frame(&mut stream, &join_frame("crowd")).await;
+                let reply = read_frame(&mut stream).await.unwrap();
+                if reply == encode_rejection(RejectReason::ServerFull) {
+                    None
+                } else {
+                    Some(stream)
+                }
+            })
+        });
+        let mut admitted = Vec::new();
+        for join in joins {
+            if let Some(stream) = join.await.unwrap() {
+                admitted.push(stream);
+            }
+        }
+
+        assert_eq!(admitted.len(), 3);
+        assert_eq!(relay.server.clients.read().await.len(), 3);
+    }
+
+    /// Join with an auth token; the relay's reply, hello or rejection
+    async fn join_with_token(addr: SocketAddr, session: &str, token: &str) -> (TcpStream, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let join = ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: session.to_string(),
+            max_message_size: None,
+            token: Some(token.to_string()),
+        };
+        write_frame(&mut stream, &join.encode()).await;
+        let reply = read_frame(&mut stream).await.expect("no reply from relay");
+        (stream, reply)
+    }
+
+    #[tokio::test]
+    async fn test_auth_tokens_are_required_once_configured() {
+        let addr = start_relay(&["--auth-token", "member-token", "--priority-token", "owner-token"]).await;
+
+        let mut anonymous = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut anonymous, &join_frame("members")).await;
+        assert_eq!(read_frame(&mut anonymous).await.unwrap(), encode_rejection(RejectReason::Unauthorized));
+        let (_guess, reply) = join_with_token(addr, "members", "guessed-token").await;
+        assert_eq!(reply, encode_rejection(RejectReason::Unauthorized));
+
+        let (_member, reply) = join_with_token(addr, "members", "member-token").await;
+        assert!(ServerHello::decode(&reply).is_ok());
+        let (_owner, reply) = join_with_token(addr, "members", "owner-token").await;
+        assert!(ServerHello::decode(&reply).unwrap().peer_present);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_slots_admit_priority_clients_only() {
+        use tokio::io::AsyncBufReadExt;
+
+        let socket_path = std::env::temp_dir().join(format!("silence-admin-{}.sock", Uuid::new_v4()));
+        let relay = spawn_relay(&[
+            "--max-clients", "3", "--reserved-slots", "1", "--priority-token", "owner-token",
+            "--max-clients-per-ip", "10", "--admin-socket", socket_path.to_str().unwrap(),
+        ]).await;
+
+        // A public client arriving once the public pool is full borrows the
+        // reserved slot, but loses it at the hello
+        let (_first, _) = join(relay.addr, "public-1").await;
+        let (_second, _) = join(relay.addr, "public-2").await;
+        let mut turned_away = TcpStream::connect(relay.addr).await.unwrap();
+        write_frame(&mut turned_away, &join_frame("public-3")).await;
+        assert_eq!(read_frame(&mut turned_away).await.unwrap(), encode_rejection(RejectReason::ServerFull));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while relay.server.reserved.available_permits() == 0 {
+            assert!(Instant::now() < deadline, "borrowed reserved slot was never given back");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The priority client still gets in, and after that nobody else does
+        let (_owner, reply) = join_with_token(relay.addr, "owner", "owner-token").await;
+        assert!(ServerHello::decode(&reply).is_ok(), "priority client refused");
+        let mut refused = TcpStream::connect(relay.addr).await.unwrap();
+        assert_eq!(read_frame(&mut refused).await.unwrap(), encode_rejection(RejectReason::ServerFull));
+
+        let admin = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let (read_half, mut writer) = admin.into_split();
+        let mut lines = tokio::io::BufReader::new(read_half).lines();
+        let stats = admin_request(&mut lines, &mut writer, r#"{"cmd":"stats"}"#).await;
+        assert_eq!(stats["reserved_slots"], 1);
+        assert_eq!(stats["reserved_slots_used"], 1);
+        assert_eq!(stats["active_clients"], 3);
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[test]
+    fn test_reserved_slots_must_fit_max_clients() {
+        let result = RelayServer::new(Args::parse_from(["silence-relay", "--max-clients", "2", "--reserved-slots", "3"]));
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_connection_burst_never_overshoots_max_clients() {
+        const MAX_CLIENTS: usize = 8;
+        let relay = spawn_relay(&["--max-clients", "8", "--max-clients-per-ip", "100"]).await;
+
+        // Watch the registry while twice as many clients connect and join at once
+        let done = CancellationToken::new();
+        let watcher = tokio::spawn({
+            let clients = Arc::clone(&relay.server.clients);
+            let done = done.clone();
+            async move {
+                let mut peak = 0;
+                while !done.is_cancelled() {
+                    peak = peak.max(clients.read().await.len());
+                    tokio::task::yield_now().await;
+                }
+                peak
+            }
+        });
+        let attempts: Vec<_> = (0..MAX_CLIENTS * 2)
+            .map(|_| {
+                let addr = relay.addr;
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    write_frame(&mut stream, &join_frame("burst")).await;
+                    let reply = read_frame(&mut stream).await.unwrap();
+                    (reply != encode_rejection(RejectReason::ServerFull)).then_some(stream)
+                })
+            })
+            .collect();
+        let mut admitted = Vec::new();
+        for attempt in attempts {
+            admitted.extend(attempt.await.unwrap());
+        }
+        done.cancel();
+
+        assert_eq!(admitted.len(), MAX_CLIENTS);
+        assert!(watcher.await.unwrap() <= MAX_CLIENTS);
+        assert_eq!(relay.server.admission.available_permits(), 0);
+
+        // Slots come back as clients leave
+        drop(admitted);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while relay.server.admission.available_permits() < MAX_CLIENTS {
+            assert!(Instant::now() < deadline, "client slots were not released");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Throughput with many concurrent sessions; run with `--ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn load_test_many_session_pairs() {
+        const PAIRS: usize = 50;
+        const ROUNDS: usize = 100;
+        // Frames in flight per pair, kept under the client queue so none are dropped
+        const WINDOW: usize = CLIENT_QUEUE_CAPACITY / 2;
+
+        let addr = start_relay(&["--max-clients", "1000", "--max-clients-per-ip", "1000"]).await;
+        let payload = vec![0x42; 256];
+
+        let mut pairs = Vec::new();
+        for pair in 0..PAIRS {
+            let session = format!("load-{}", pair);
+            let (sender, _) = join(addr, &session).await;
+            let (receiver, _) = join(addr, &session).await;
+            pairs.push((sender, receiver));
+        }
+
+        let started = Instant::now();
+        let mut tasks = Vec::new();
+        for (mut sender, mut receiver) in pairs {
+            let payload = payload.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..ROUNDS {
+                    for _ in 0..WINDOW {
+                        send_data(&mut sender, &payload).await;
+                    }
+                    for _ in 0..WINDOW {
+                        read_frame(&mut receiver).await.expect("relay closed the receiver");
+                    }
+                    send_data(&mut receiver, b"ack").await;
+                    read_frame(&mut sender).await.expect("relay closed the sender");
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let elapsed = started.elapsed();
+        let total = PAIRS * ROUNDS * (WINDOW + 1);
+        println!("{} messages across {} pairs in {:?}: {:.0} messages/sec",
+                 total, PAIRS, elapsed, total as f64 / elapsed.as_secs_f64());
+    }
+
+    /// A port that was free a moment ago
+    fn free_port() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string()
+    }
+
+    /// Wait until a relay does (or no longer does) see a session on a peer relay
+    async fn wait_for_remote(relay: &TestRelay, session: &str, present: bool) {
+        let federation = relay.server.federation.as_ref().unwrap();
+        for _ in 0..500 {
+            if federation.has_remote(session) == present {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("remote presence of session {} never became {}", session, present);
+    }
+
+    #[tokio::test]
+    async fn test_federated_relays_pair_sessions() {
+        let port = free_port();
+        let hub = spawn_relay(&["--federation-port", &port, "--federation-secret", "s3cret"]).await;
+        let peer = format!("127.0.0.1:{}", port);
+        let edge = spawn_relay(&["--peer-relay", &peer, "--federation-secret", "s3cret"]).await;
+
+        let (mut alice, hello) = join(hub.addr, "cross-relay").await;
+        assert!(!hello.peer_present);
+        wait_for_remote(&edge, "cross-relay", true).await;
+
+        let (mut bob, hello) = join(edge.addr, "cross-relay").await;
+        assert!(hello.peer_present, "peer on the other relay counts as present");
+        wait_for_remote(&hub, "cross-relay", true).await;
+
+        send_data(&mut alice, b"hello edge").await;
+        assert_eq!(recv_data(&mut bob).await.unwrap(), b"hello edge");
+        send_data(&mut bob, b"hello hub").await;
+        assert_eq!(recv_data(&mut alice).await.unwrap(), b"hello hub");
+
+        // Other sessions stay local
+        let (_carol, hello) = join(edge.addr, "elsewhere").await;
+        assert!(!hello.peer_present);
+
+        drop(bob);
+        wait_for_remote(&hub, "cross-relay", false).await;
+    }
+
+    #[tokio::test]
+    async fn test_federation_requires_matching_secret() {
+        let unsecured = RelayServer::new(Args::parse_from(["silence-relay", "--peer-relay", "127.0.0.1:1"]));
+        assert!(unsecured.is_err());
+
+        let port = free_port();
+        let hub = spawn_relay(&["--federation-port", &port, "--federation-secret", "right"]).await;
+        let peer = format!("127.0.0.1:{}", port);
+        let edge = spawn_relay(&["--peer-relay", &peer, "--federation-secret", "wrong"]).await;
+
+        let (_alice, _) = join(hub.addr, "guarded").await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (_bob, hello) = join(edge.addr, "guarded").await;
+        assert!(!hello.peer_present);
+        assert!(!hub.server.federation.as_ref().unwrap().has_remote("guarded"));
+    }
+
+    /// A client registered straight into the relay's registry whose queue
+    /// only empties when the test reads it
+    async fn slow_consumer(relay: &TestRelay, session: &str) -> ClientQueue {
+        let (sender, receiver) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+        let counters = Arc::new(ClientCounters::default());
+        relay.server.clients.write().await.insert(ClientInfo {
+            id: Uuid::new_v4(),
+            addr: "127.0.0.1:9".parse().unwrap(),
+            session: session.to_string(),
+            sender,
+            connected_at: Instant::now(),
+            max_message_size: 65536,
+            counters: Arc::clone(&counters),
+            kick: Arc::new(Notify::new()),
+        });
+        ClientQueue::new(receiver, counters, Arc::clone(&relay.server.budget))
+    }
+
+    #[tokio::test]
+    async fn test_buffer_cap_holds_with_slow_consumers_and_recovers() {
+        const PAIRS: usize = 4;
+        const FRAMES: usize = 200;
+        const LIMIT: u64 = 32 * 1024;
+        let relay = spawn_relay(&[
+            "--max-message-size", "1024", "--max-clients-per-ip", "16", "--max-buffered-bytes", &LIMIT.to_string(),
+        ]).await;
+
+        let mut senders = Vec::new();
+        let mut consumers = Vec::new();
+        for pair in 0..PAIRS {
+            let session = format!("backlog-{}", pair);
+            let (mut sender, _) = join(relay.addr, &session).await;
+            consumers.push(slow_consumer(&relay, &session).await);
+            senders.push(tokio::spawn(async move {
+                for _ in 0..FRAMES {
+                    send_data(&mut sender, &[7u8; 1000]).await;
+                }
+                sender
+            }));
+        }
+
+        // Nobody is reading: the backlog grows to the cap, and the senders
+        // are paused there instead of overflowing the queues
+        let mut peak = 0;
+        for _ in 0..50 {
+            peak = peak.max(relay.server.budget.queued());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(peak >= LIMIT, "backlog never reached the cap: {}", peak);
+        assert!(peak <= LIMIT + LIMIT / 2, "backlog went past the hard limit: {}", peak);
+        assert!(relay.server.stats.frames_relayed.load(Ordering::Relaxed) < (PAIRS * FRAMES) as u64);
+
+        // Once the consumers catch up everything drains, without a frame lost
+        let consumers: Vec<_> = consumers
+            .into_iter()
+            .map(|mut queue| tokio::spawn(async move {
+                for _ in 0..FRAMES {
+                    let frame = tokio::time::timeout(Duration::from_secs(5), queue.recv()).await;
+                    frame.expect("frames stopped coming").expect("consumer was dropped");
+                }
+            }))
+            .collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        for consumer in consumers {
+            consumer.await.unwrap();
+        }
+        assert_eq!(relay.server.budget.queued(), 0);
+        assert_eq!(relay.server.stats.frames_dropped.load(Ordering::Relaxed), 0);
+    }
+}
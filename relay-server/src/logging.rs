@@ -0,0 +1,163 @@
+// Log output setup and the connection access log
+//
+// Connection lifecycle events go to the `access` target with stable field
+// names, so `--log-format json` output can be fed straight into abuse
+// tooling: one event per connect, join, reject and disconnect.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use uuid::Uuid;
+
+use crate::protocol::RejectReason;
+
+/// Target of the access log events, e.g. `RUST_LOG=access=off` to silence them
+pub const ACCESS_TARGET: &str = "access";
+
+/// Log line format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, event fields at the top level
+    Json,
+}
+
+/// Build the subscriber for a log format; `RUST_LOG` filters, defaulting to info
+pub fn subscriber<W>(format: LogFormat, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+/// Why a registered client's connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection
+    Closed,
+    /// Disconnected by the relay: admin kick, slow reader or access list
+    Kicked,
+    /// Sent nothing for longer than the idle timeout
+    Idle,
+    Shutdown,
+    ReadTimeout,
+    ReadError,
+    /// Writing to the client failed
+    WriteError,
+    /// The client sent an empty frame or one of an unknown type
+    InvalidFrame,
+    QuotaExceeded,
+    OversizedFrames,
+    /// Shed to get the relay's buffers back under the hard limit
+    Overloaded,
+}
+
+impl DisconnectReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Idle => "idle",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::ReadTimeout => "read_timeout",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::WriteError => "write_error",
+            DisconnectReason::InvalidFrame => "invalid_frame",
+            DisconnectReason::QuotaExceeded => "quota_exceeded",
+            DisconnectReason::OversizedFrames => "oversized_frames",
+            DisconnectReason::Overloaded => "overloaded",
+        }
+    }
+
+    /// Whether the client broke the protocol, which counts towards a ban
+    pub fn is_violation(self) -> bool {
+        matches!(self, DisconnectReason::InvalidFrame | DisconnectReason::OversizedFrames)
+    }
+}
+
+/// Traffic totals for a finished connection
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTotals {
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames_dropped: u64,
+    pub duration: Duration,
+}
+
+pub fn connected(peer_addr: SocketAddr) {
+    info!(target: ACCESS_TARGET, event = "connect", peer_addr = %peer_addr, "New client connection");
+}
+
+pub fn joined(client_id: Uuid, peer_addr: SocketAddr, session: &str) {
+    info!(target: ACCESS_TARGET, event = "join", client_id = %client_id, peer_addr = %peer_addr, session,
+          "Client registered");
+}
+
+pub fn rejected(peer_addr: SocketAddr, reason: RejectReason) {
+    info!(target: ACCESS_TARGET, event = "reject", peer_addr = %peer_addr, reason = reason.as_str(),
+          "Connection rejected");
+}
+
+pub fn disconnected(
+    client_id: Uuid,
+    peer_addr: SocketAddr,
+    session: &str,
+    reason: DisconnectReason,
+    totals: ConnectionTotals,
+) {
+    info!(
+        target: ACCESS_TARGET,
+        event = "disconnect",
+        client_id = %client_id,
+        peer_addr = %peer_addr,
+        session,
+        frames_in = totals.frames_in,
+        frames_out = totals.frames_out,
+        bytes_in = totals.bytes_in,
+        bytes_out = totals.bytes_out,
+        frames_dropped = totals.frames_dropped,
+        duration_ms = totals.duration.as_millis() as u64,
+        reason = reason.as_str(),
+        "Client unregistered"
+    );
+}
+
+/// In-memory log sink for tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedOutput {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
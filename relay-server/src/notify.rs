@@ -0,0 +1,113 @@
+// systemd service notifications (sd_notify protocol)
+//
+// When started by systemd with `Type=notify`, NOTIFY_SOCKET names a unix
+// datagram socket that accepts newline-separated `KEY=VALUE` state updates.
+// Outside systemd the variable is unset and every call here is a no-op.
+
+use std::ffi::OsString;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::debug;
+
+/// Handle on the service manager's notification socket
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Notifier {
+    /// Connect to `$NOTIFY_SOCKET`, or `None` when not running under systemd
+    pub fn from_env() -> Option<Self> {
+        Self::from_var(std::env::var_os("NOTIFY_SOCKET"))
+    }
+
+    fn from_var(value: Option<OsString>) -> Option<Self> {
+        let value = value.filter(|value| !value.is_empty())?;
+
+        // A leading `@` names a socket in the abstract namespace
+        let path = match value.to_str().and_then(|value| value.strip_prefix('@')) {
+            Some(name) => {
+                let mut abstract_path = OsString::from("\0");
+                abstract_path.push(name);
+                PathBuf::from(abstract_path)
+            }
+            None => PathBuf::from(value),
+        };
+
+        match UnixDatagram::unbound() {
+            Ok(socket) => Some(Self { socket, path }),
+            Err(e) => {
+                debug!("Failed to create notify socket: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Send a state update such as `READY=1`; failures are only logged
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to(state.as_bytes(), &self.path) {
+            debug!("Failed to notify service manager: {}", e);
+        }
+    }
+}
+
+/// Interval at which to send `WATCHDOG=1`: half the configured timeout
+pub fn watchdog_interval() -> Option<Duration> {
+    // The variables may be inherited by children; only act on our own
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// `STATUS=` line shown by `systemctl status`
+pub fn status_line(active_clients: usize, active_sessions: usize, draining: bool) -> String {
+    let mut status = format!(
+        "STATUS={} client{} in {} session{}",
+        active_clients,
+        if active_clients == 1 { "" } else { "s" },
+        active_sessions,
+        if active_sessions == 1 { "" } else { "s" },
+    );
+    if draining {
+        status.push_str(", draining");
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unset_socket_is_a_no_op() {
+        assert!(Notifier::from_var(None).is_none());
+        assert!(Notifier::from_var(Some(OsString::new())).is_none());
+    }
+
+    #[test]
+    fn test_notifications_reach_the_socket() {
+        let path = std::env::temp_dir().join(format!("silence-notify-{}.sock", uuid::Uuid::new_v4()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::from_var(Some(path.clone().into_os_string())).unwrap();
+        notifier.notify("READY=1");
+
+        let mut buffer = [0u8; 64];
+        let read = listener.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_status_line_format() {
+        assert_eq!(status_line(0, 0, false), "STATUS=0 clients in 0 sessions");
+        assert_eq!(status_line(1, 1, false), "STATUS=1 client in 1 session");
+        assert_eq!(status_line(12, 6, true), "STATUS=12 clients in 6 sessions, draining");
+    }
+}
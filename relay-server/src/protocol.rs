@@ -0,0 +1,680 @@
+// Relay wire protocol - handshake frames exchanged after accept
+//
+// Every frame on the wire is a 4-byte big-endian length prefix followed by
+// the payload. The first frame a client sends is its join (protocol version
+// byte, session code, optionally a 0x01 byte and the largest frame the client
+// wants to receive, then optionally a zero byte and an auth token); the relay
+// answers with a hello carrying the frame size limit agreed on before any
+// relayed traffic flows. Before joining, a client may also send latency
+// probes and ask which address the relay sees it at, which the relay answers
+// straight away without any session being set up. Every frame after the hello leads with a type byte
+// telling relayed data apart from the relay's own control frames, so a
+// zero-length frame is never valid.
+
+use silence::protocol::{self as wire, Compatibility};
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+/// Protocol version spoken by this relay, the client library's
+pub use silence::protocol::WIRE_VERSION as PROTOCOL_VERSION;
+
+/// Oldest client protocol version the relay still serves
+pub use silence::protocol::MIN_SUPPORTED as MIN_PROTOCOL_VERSION;
+
+/// Maximum length of a session code in bytes
+pub const MAX_SESSION_CODE_LEN: usize = 64;
+
+/// Maximum length of an auth token in bytes
+pub const MAX_TOKEN_LEN: usize = 128;
+
+/// Separates the session code from an auth token in a join frame
+pub const TOKEN_SEPARATOR: u8 = 0x00;
+
+/// Leads the frame size limit a client asks for in its join, a big-endian u32
+pub const SIZE_SEPARATOR: u8 = 0x01;
+
+/// Smallest frame size limit a client may ask for
+pub const MIN_REQUESTED_MESSAGE_SIZE: u32 = 64;
+
+/// Encoded size of a server hello frame
+pub const SERVER_HELLO_LEN: usize = 1 + 16 + 4 + 1;
+
+/// ALPN protocol id negotiated on TLS listeners
+pub const ALPN_PROTOCOL: &[u8] = b"silence-relay/1";
+
+/// Leading byte of a rejection frame (never a valid protocol version)
+pub const REJECT_MARKER: u8 = 0x00;
+
+/// Frame type bytes, leading every frame after the hello
+pub const FRAME_DATA: u8 = 0x01;
+pub const FRAME_PING: u8 = 0x02;
+pub const FRAME_PONG: u8 = 0x03;
+pub const FRAME_GOODBYE: u8 = 0x04;
+pub const FRAME_ERROR: u8 = 0x05;
+
+/// Leads a control frame a client sends before joining; no protocol version
+/// is 0, so it can't be taken for a join
+pub const CONTROL_MARKER: u8 = 0x00;
+
+/// Length of a latency probe: the control marker, the ping type byte and an
+/// 8-byte id
+pub const PROBE_LEN: usize = 2 + 8;
+
+/// Length of a probe reply: the pong type byte, the probe's id and the
+/// relay's receive timestamp
+pub const PROBE_REPLY_LEN: usize = 1 + 8 + 8;
+
+/// Type byte of an address request and of the relay's answer, only ever
+/// sent before joining
+pub const FRAME_ADDRESS: u8 = 0x06;
+
+/// Asks, in place of a join, which address the relay sees the client at.
+/// Relays predating it take it for a join of version 0 and refuse it.
+pub const ADDRESS_REQUEST: [u8; 2] = [CONTROL_MARKER, FRAME_ADDRESS];
+
+/// The relay's answer to an address request: the address type byte, 4 or
+/// 6, the IP's octets and the big-endian port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObservedAddress {
+    pub addr: SocketAddr,
+}
+
+impl ObservedAddress {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![FRAME_ADDRESS];
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                data.push(4);
+                data.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                data.push(6);
+                data.extend_from_slice(&ip.octets());
+            }
+        }
+        data.extend_from_slice(&self.addr.port().to_be_bytes());
+        data
+    }
+
+    #[allow(dead_code)] // Client-side half of the exchange
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let ip = match data {
+            [FRAME_ADDRESS, 4, rest @ ..] if rest.len() == 4 + 2 => {
+                IpAddr::from(<[u8; 4]>::try_from(&rest[..4]).ok()?)
+            }
+            [FRAME_ADDRESS, 6, rest @ ..] if rest.len() == 16 + 2 => {
+                IpAddr::from(<[u8; 16]>::try_from(&rest[..16]).ok()?)
+            }
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(data[data.len() - 2..].try_into().ok()?);
+        Some(Self { addr: SocketAddr::new(ip, port) })
+    }
+}
+
+/// Handshake decoding errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    InvalidSessionCode,
+    /// Join frame without a leading version byte (pre-versioning client)
+    MissingVersion,
+    UnsupportedVersion(u8),
+    #[allow(dead_code)] // Only produced by the client-side hello decoder
+    InvalidHello,
+    /// Frame after the hello that is empty or has an unknown type or layout
+    InvalidFrame,
+    /// Auth token that is empty, too long or not printable ASCII
+    InvalidToken,
+    /// Requested frame size limit that is truncated or too small
+    InvalidMaxMessageSize,
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::InvalidSessionCode => write!(f, "Invalid session code"),
+            ProtocolError::MissingVersion => write!(f, "Join frame has no protocol version"),
+            ProtocolError::UnsupportedVersion(version) => write!(
+                f, "Unsupported protocol version {} (supported {}-{})",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            ProtocolError::InvalidHello => write!(f, "Invalid hello frame"),
+            ProtocolError::InvalidFrame => write!(f, "Invalid frame"),
+            ProtocolError::InvalidToken => write!(f, "Invalid auth token"),
+            ProtocolError::InvalidMaxMessageSize => write!(
+                f, "Invalid requested max message size (at least {} bytes)", MIN_REQUESTED_MESSAGE_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Whether a byte may appear in a session code
+fn is_session_code_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'_'
+}
+
+/// Validate a session code as sent by a client or a federated relay
+pub fn decode_session_code(data: &[u8]) -> Result<String, ProtocolError> {
+    if data.is_empty() || data.len() > MAX_SESSION_CODE_LEN {
+        return Err(ProtocolError::InvalidSessionCode);
+    }
+
+    let valid = data
+        .iter()
+        .all(|b| is_session_code_byte(*b));
+    if !valid {
+        return Err(ProtocolError::InvalidSessionCode);
+    }
+
+    // Only ASCII survived the check above, so this cannot fail
+    String::from_utf8(data.to_vec()).map_err(|_| ProtocolError::InvalidSessionCode)
+}
+
+/// Validate an auth token from a join frame
+fn decode_token(data: &[u8]) -> Result<String, ProtocolError> {
+    if data.is_empty() || data.len() > MAX_TOKEN_LEN || !data.iter().all(|b| b.is_ascii_graphic()) {
+        return Err(ProtocolError::InvalidToken);
+    }
+    String::from_utf8(data.to_vec()).map_err(|_| ProtocolError::InvalidToken)
+}
+
+/// Client's first frame: its protocol version, the session it wants to join,
+/// the frame size limit it asks for and the token it authenticates with, if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientJoin {
+    pub version: u8,
+    pub session_code: String,
+    /// Largest frame the client wants to send or receive; the relay's own
+    /// limit applies when this is higher or missing
+    pub max_message_size: Option<u32>,
+    pub token: Option<String>,
+}
+
+impl ClientJoin {
+    #[allow(dead_code)] // Client-side half of the exchange
+    pub fn encode(&self) -> Vec<u8> {
+        let size_len = if self.max_message_size.is_some() { 5 } else { 0 };
+        let token_len = self.token.as_ref().map_or(0, |token| 1 + token.len());
+        let mut data = Vec::with_capacity(1 + self.session_code.len() + size_len + token_len);
+        data.push(self.version);
+        data.extend_from_slice(self.session_code.as_bytes());
+        if let Some(size) = self.max_message_size {
+            data.push(SIZE_SEPARATOR);
+            data.extend_from_slice(&size.to_be_bytes());
+        }
+        if let Some(token) = &self.token {
+            data.push(TOKEN_SEPARATOR);
+            data.extend_from_slice(token.as_bytes());
+        }
+        data
+    }
+
+    /// Decode a join frame, rejecting unsupported versions and anything that
+    /// isn't a plausible session code
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (&version, data) = data.split_first().ok_or(ProtocolError::InvalidSessionCode)?;
+
+        // Versions are small integers, never session code characters, so a
+        // join that starts with one came from a client that predates versioning
+        if is_session_code_byte(version) {
+            return Err(ProtocolError::MissingVersion);
+        }
+        // Newer clients included: the relay can't read what they changed,
+        // and they learn the supported range from the rejection
+        if wire::is_compatible(version) == Compatibility::Incompatible {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let end = data
+            .iter()
+            .position(|&b| b == TOKEN_SEPARATOR || b == SIZE_SEPARATOR)
+            .unwrap_or(data.len());
+        let (session_code, mut rest) = data.split_at(end);
+        let session_code = decode_session_code(session_code)?;
+
+        let mut max_message_size = None;
+        if let [SIZE_SEPARATOR, after @ ..] = rest {
+            let (size, after) = after.split_first_chunk::<4>().ok_or(ProtocolError::InvalidMaxMessageSize)?;
+            let size = u32::from_be_bytes(*size);
+            if size < MIN_REQUESTED_MESSAGE_SIZE {
+                return Err(ProtocolError::InvalidMaxMessageSize);
+            }
+            max_message_size = Some(size);
+            rest = after;
+        }
+        let token = match rest {
+            [] => None,
+            [TOKEN_SEPARATOR, token @ ..] => Some(decode_token(token)?),
+            _ => return Err(ProtocolError::InvalidMaxMessageSize),
+        };
+        Ok(Self { version, session_code, max_message_size, token })
+    }
+}
+
+/// Latency probe sent in place of a join, echoed back with the time it arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Probe {
+    /// Chosen by the client to match the reply to its probe
+    pub id: [u8; 8],
+}
+
+impl Probe {
+    #[allow(dead_code)] // Client-side half of the exchange
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PROBE_LEN);
+        data.extend_from_slice(&[CONTROL_MARKER, FRAME_PING]);
+        data.extend_from_slice(&self.id);
+        data
+    }
+
+    /// A probe, or None for any other frame
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match data {
+            [CONTROL_MARKER, FRAME_PING, id @ ..] => Some(Self { id: id.try_into().ok()? }),
+            _ => None,
+        }
+    }
+}
+
+/// The relay's answer to a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeReply {
+    pub id: [u8; 8],
+    /// Microseconds since the relay started at which the probe arrived
+    pub received_us: u64,
+}
+
+impl ProbeReply {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PROBE_REPLY_LEN);
+        data.push(FRAME_PONG);
+        data.extend_from_slice(&self.id);
+        data.extend_from_slice(&self.received_us.to_be_bytes());
+        data
+    }
+
+    #[allow(dead_code)] // Client-side half of the exchange
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != PROBE_REPLY_LEN || data[0] != FRAME_PONG {
+            return None;
+        }
+        Some(Self {
+            id: data[1..9].try_into().ok()?,
+            received_us: u64::from_be_bytes(data[9..].try_into().ok()?),
+        })
+    }
+}
+
+/// Relay's first frame: assigned identity, limits and session state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerHello {
+    pub version: u8,
+    pub client_id: Uuid,
+    pub max_message_size: u32,
+    pub peer_present: bool,
+}
+
+impl ServerHello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(SERVER_HELLO_LEN);
+        data.push(self.version);
+        data.extend_from_slice(self.client_id.as_bytes());
+        data.extend_from_slice(&self.max_message_size.to_be_bytes());
+        data.push(self.peer_present as u8);
+        data
+    }
+
+    #[allow(dead_code)] // Client-side half of the exchange
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        if data.len() != SERVER_HELLO_LEN {
+            return Err(ProtocolError::InvalidHello);
+        }
+
+        // The length check leaves these no way to fail
+        let (&version, rest) = data.split_first().ok_or(ProtocolError::InvalidHello)?;
+        let (client_id, rest) = rest.split_first_chunk().ok_or(ProtocolError::InvalidHello)?;
+        let (max_message_size, rest) = rest.split_first_chunk().ok_or(ProtocolError::InvalidHello)?;
+        let peer_present = match rest {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProtocolError::InvalidHello),
+        };
+
+        Ok(Self {
+            version,
+            client_id: Uuid::from_bytes(*client_id),
+            max_message_size: u32::from_be_bytes(*max_message_size),
+            peer_present,
+        })
+    }
+}
+
+/// Why the relay refused a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    TooManyFromAddress = 1,
+    Draining = 2,
+    SessionFull = 3,
+    ServerFull = 4,
+    Banned = 5,
+    QuotaExceeded = 6,
+    /// Followed by the lowest and highest supported protocol versions
+    UnsupportedVersion = 7,
+    /// Mid-session only: frames were discarded for exceeding the size limit
+    FrameTooLarge = 8,
+    /// Mid-session only: the relay is over its buffer limit and this client
+    /// had the largest backlog
+    Overloaded = 9,
+    /// The join carried no auth token the relay accepts
+    Unauthorized = 10,
+    /// Mid-session only: the relay is stopping, most likely to restart
+    ShuttingDown = 11,
+    /// Mid-session only: an operator disconnected the client
+    Kicked = 12,
+    /// Mid-session only: the client sent nothing for the idle timeout
+    Idle = 13,
+    /// The relay's access lists don't let the client's address in
+    NotAllowed = 14,
+}
+
+impl RejectReason {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(RejectReason::TooManyFromAddress),
+            2 => Some(RejectReason::Draining),
+            3 => Some(RejectReason::SessionFull),
+            4 => Some(RejectReason::ServerFull),
+            5 => Some(RejectReason::Banned),
+            6 => Some(RejectReason::QuotaExceeded),
+            7 => Some(RejectReason::UnsupportedVersion),
+            8 => Some(RejectReason::FrameTooLarge),
+            9 => Some(RejectReason::Overloaded),
+            10 => Some(RejectReason::Unauthorized),
+            11 => Some(RejectReason::ShuttingDown),
+            12 => Some(RejectReason::Kicked),
+            13 => Some(RejectReason::Idle),
+            14 => Some(RejectReason::NotAllowed),
+            _ => None,
+        }
+    }
+
+    /// Stable name for logs
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RejectReason::TooManyFromAddress => "too_many_from_address",
+            RejectReason::Draining => "draining",
+            RejectReason::SessionFull => "session_full",
+            RejectReason::ServerFull => "server_full",
+            RejectReason::Banned => "banned",
+            RejectReason::QuotaExceeded => "quota_exceeded",
+            RejectReason::UnsupportedVersion => "unsupported_version",
+            RejectReason::FrameTooLarge => "frame_too_large",
+            RejectReason::Overloaded => "overloaded",
+            RejectReason::Unauthorized => "unauthorized",
+            RejectReason::ShuttingDown => "shutting_down",
+            RejectReason::Kicked => "kicked",
+            RejectReason::Idle => "idle",
+            RejectReason::NotAllowed => "not_allowed",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::TooManyFromAddress => write!(f, "too many connections from address"),
+            RejectReason::Draining => write!(f, "relay is draining"),
+            RejectReason::SessionFull => write!(f, "session already has two members"),
+            RejectReason::ServerFull => write!(f, "relay is full"),
+            RejectReason::Banned => write!(f, "address is banned"),
+            RejectReason::QuotaExceeded => write!(f, "bandwidth quota exceeded"),
+            RejectReason::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            RejectReason::FrameTooLarge => write!(f, "frame too large"),
+            RejectReason::Overloaded => write!(f, "relay is overloaded"),
+            RejectReason::Unauthorized => write!(f, "missing or unknown auth token"),
+            RejectReason::ShuttingDown => write!(f, "relay is shutting down"),
+            RejectReason::Kicked => write!(f, "disconnected by the operator"),
+            RejectReason::Idle => write!(f, "idle for too long"),
+            RejectReason::NotAllowed => write!(f, "address not allowed"),
+        }
+    }
+}
+
+/// Sent instead of a hello when the relay refuses a connection
+pub fn encode_rejection(reason: RejectReason) -> Vec<u8> {
+    let mut frame = vec![REJECT_MARKER, reason as u8];
+    if reason == RejectReason::UnsupportedVersion {
+        frame.extend_from_slice(&[MIN_PROTOCOL_VERSION, PROTOCOL_VERSION]);
+    }
+    frame
+}
+
+/// A frame exchanged after the hello, told apart by its leading type byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// Opaque payload relayed to the rest of the session; never empty
+    Data(&'a [u8]),
+    /// Liveness check, answered with a pong
+    Ping,
+    Pong,
+    /// The sender is closing the connection; the relay says why
+    Goodbye(Option<RejectReason>),
+    /// A frame was discarded for exceeding the size limit that follows
+    Oversized(u32),
+}
+
+impl<'a> Frame<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Frame::Data(payload) => {
+                let mut frame = Vec::with_capacity(1 + payload.len());
+                frame.push(FRAME_DATA);
+                frame.extend_from_slice(payload);
+                frame
+            }
+            Frame::Ping => vec![FRAME_PING],
+            Frame::Pong => vec![FRAME_PONG],
+            Frame::Goodbye(None) => vec![FRAME_GOODBYE],
+            Frame::Goodbye(Some(reason)) => vec![FRAME_GOODBYE, reason as u8],
+            Frame::Oversized(limit) => {
+                let mut frame = vec![FRAME_ERROR, RejectReason::FrameTooLarge as u8];
+                frame.extend_from_slice(&limit.to_be_bytes());
+                frame
+            }
+        }
+    }
+
+    pub fn decode(data: &'a [u8]) -> Result<Self, ProtocolError> {
+        let (&frame_type, body) = data.split_first().ok_or(ProtocolError::InvalidFrame)?;
+        match (frame_type, body) {
+            (FRAME_DATA, []) => Err(ProtocolError::InvalidFrame),
+            (FRAME_DATA, payload) => Ok(Frame::Data(payload)),
+            (FRAME_PING, []) => Ok(Frame::Ping),
+            (FRAME_PONG, []) => Ok(Frame::Pong),
+            (FRAME_GOODBYE, []) => Ok(Frame::Goodbye(None)),
+            (FRAME_GOODBYE, &[code]) => RejectReason::from_code(code)
+                .map(|reason| Frame::Goodbye(Some(reason)))
+                .ok_or(ProtocolError::InvalidFrame),
+            (FRAME_ERROR, &[code, a, b, c, d]) if code == RejectReason::FrameTooLarge as u8 => {
+                Ok(Frame::Oversized(u32::from_be_bytes([a, b, c, d])))
+            }
+            _ => Err(ProtocolError::InvalidFrame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_round_trip() {
+        let join = ClientJoin {
+            version: PROTOCOL_VERSION,
+            session_code: "alpha-42_x".to_string(),
+            max_message_size: None,
+            token: None,
+        };
+        assert_eq!(ClientJoin::decode(&join.encode()).unwrap(), join);
+
+        let join = ClientJoin { token: Some("s3cr3t/token+=".to_string()), ..join };
+        assert_eq!(join.encode(), b"\x03alpha-42_x\x00s3cr3t/token+=");
+        assert_eq!(ClientJoin::decode(&join.encode()).unwrap(), join);
+
+        // A size limit goes between the session code and the token, and may
+        // hold zero bytes of its own
+        let join = ClientJoin { max_message_size: Some(256), ..join };
+        assert_eq!(join.encode(), b"\x03alpha-42_x\x01\x00\x00\x01\x00\x00s3cr3t/token+=");
+        assert_eq!(ClientJoin::decode(&join.encode()).unwrap(), join);
+        let join = ClientJoin { token: None, ..join };
+        assert_eq!(ClientJoin::decode(&join.encode()).unwrap(), join);
+    }
+
+    #[test]
+    fn test_join_rejects_bad_size_limits() {
+        let too_small = [b"room\x01".as_slice(), &(MIN_REQUESTED_MESSAGE_SIZE - 1).to_be_bytes()].concat();
+        assert_eq!(ClientJoin::decode(&versioned(&too_small)), Err(ProtocolError::InvalidMaxMessageSize));
+        assert_eq!(ClientJoin::decode(&versioned(b"room\x01\x00\x01")), Err(ProtocolError::InvalidMaxMessageSize));
+        assert_eq!(
+            ClientJoin::decode(&versioned(b"room\x01\x00\x00\x10\x00junk")),
+            Err(ProtocolError::InvalidMaxMessageSize)
+        );
+        assert_eq!(ClientJoin::decode(&versioned(b"\x01\x00\x00\x10\x00")), Err(ProtocolError::InvalidSessionCode));
+    }
+
+    #[test]
+    fn test_probe_round_trip() {
+        let probe = Probe { id: *b"12345678" };
+        assert_eq!(probe.encode(), b"\x00\x0212345678");
+        assert_eq!(Probe::decode(&probe.encode()), Some(probe));
+        assert_eq!(Probe::decode(&[CONTROL_MARKER, FRAME_PING]), None, "a probe needs its id");
+        assert_eq!(Probe::decode(b"\x00\x02123456789"), None);
+        // Not even an old version 2 join with an 8-character session code
+        let old_join = ClientJoin { version: 2, session_code: "versions".into(), max_message_size: None, token: None };
+        assert_eq!(Probe::decode(&old_join.encode()), None);
+
+        let reply = ProbeReply { id: probe.id, received_us: 1_234_567 };
+        assert_eq!(reply.encode().len(), PROBE_REPLY_LEN);
+        assert_eq!(ProbeReply::decode(&reply.encode()), Some(reply));
+        assert_eq!(ProbeReply::decode(&reply.encode()[..PROBE_REPLY_LEN - 1]), None);
+    }
+
+    #[test]
+    fn test_observed_address_round_trip() {
+        for addr in ["203.0.113.9:51234", "[2001:db8::7]:443"] {
+            let observed = ObservedAddress { addr: addr.parse().unwrap() };
+            assert_eq!(ObservedAddress::decode(&observed.encode()), Some(observed));
+        }
+        let v4 = ObservedAddress { addr: "203.0.113.9:258".parse().unwrap() }.encode();
+        assert_eq!(v4, [FRAME_ADDRESS, 4, 203, 0, 113, 9, 1, 2]);
+        assert_eq!(ObservedAddress::decode(&v4[..v4.len() - 1]), None);
+        assert_eq!(ObservedAddress::decode(&[FRAME_ADDRESS, 5, 1, 2, 3, 4, 0, 1]), None);
+
+        // The request is neither a probe nor a join a relay would accept
+        assert_eq!(Probe::decode(&ADDRESS_REQUEST), None);
+        assert_eq!(ClientJoin::decode(&ADDRESS_REQUEST), Err(ProtocolError::UnsupportedVersion(0)));
+    }
+
+    #[test]
+    fn test_join_rejects_bad_tokens() {
+        assert_eq!(ClientJoin::decode(&versioned(b"room\x00")), Err(ProtocolError::InvalidToken));
+        assert_eq!(ClientJoin::decode(&versioned(b"room\x00two words")), Err(ProtocolError::InvalidToken));
+        assert_eq!(ClientJoin::decode(&versioned(b"room\x00a\x00b")), Err(ProtocolError::InvalidToken));
+        let long = [b"room\x00".as_slice(), &[b't'; MAX_TOKEN_LEN + 1]].concat();
+        assert_eq!(ClientJoin::decode(&versioned(&long)), Err(ProtocolError::InvalidToken));
+        assert_eq!(ClientJoin::decode(&versioned(b"\x00token")), Err(ProtocolError::InvalidSessionCode));
+    }
+
+    fn versioned(session: &[u8]) -> Vec<u8> {
+        let mut data = vec![PROTOCOL_VERSION];
+        data.extend_from_slice(session);
+        data
+    }
+
+    #[test]
+    fn test_join_rejects_garbage() {
+        assert_eq!(ClientJoin::decode(b""), Err(ProtocolError::InvalidSessionCode));
+        assert_eq!(ClientJoin::decode(&versioned(b"")), Err(ProtocolError::InvalidSessionCode));
+        assert_eq!(ClientJoin::decode(&versioned(b"has space")), Err(ProtocolError::InvalidSessionCode));
+        assert_eq!(ClientJoin::decode(&versioned(&[0xff, 0x00, 0x13])), Err(ProtocolError::InvalidSessionCode));
+        assert_eq!(
+            ClientJoin::decode(&versioned(&[b'a'; MAX_SESSION_CODE_LEN + 1])),
+            Err(ProtocolError::InvalidSessionCode)
+        );
+    }
+
+    #[test]
+    fn test_join_version_handling() {
+        assert_eq!(ClientJoin::decode(b"legacy-session"), Err(ProtocolError::MissingVersion));
+        assert_eq!(ClientJoin::decode(b"\x00abc"), Err(ProtocolError::UnsupportedVersion(0)));
+        assert_eq!(ClientJoin::decode(b"\x09abc"), Err(ProtocolError::UnsupportedVersion(9)));
+        assert_eq!(ClientJoin::decode(b"\x02abc"), Err(ProtocolError::UnsupportedVersion(2)));
+        assert_eq!(ClientJoin::decode(&[MIN_PROTOCOL_VERSION, b'a']).unwrap().version, MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let hello = ServerHello {
+            version: PROTOCOL_VERSION,
+            client_id: Uuid::new_v4(),
+            max_message_size: 65536,
+            peer_present: true,
+        };
+        let encoded = hello.encode();
+        assert_eq!(encoded.len(), SERVER_HELLO_LEN);
+        assert_eq!(ServerHello::decode(&encoded).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_hello_rejects_bad_length() {
+        assert_eq!(ServerHello::decode(&[1, 2, 3]), Err(ProtocolError::InvalidHello));
+    }
+
+    #[test]
+    fn test_rejection_is_not_a_hello() {
+        let frame = encode_rejection(RejectReason::TooManyFromAddress);
+        assert_eq!(frame, vec![REJECT_MARKER, 1]);
+        assert!(ServerHello::decode(&frame).is_err());
+        assert_eq!(RejectReason::from_code(frame[1]), Some(RejectReason::TooManyFromAddress));
+        assert_eq!(RejectReason::from_code(0xee), None);
+
+        let frame = encode_rejection(RejectReason::UnsupportedVersion);
+        assert_eq!(frame, vec![REJECT_MARKER, 7, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION]);
+    }
+
+    #[test]
+    fn test_frame_round_trips() {
+        let frames = [
+            Frame::Data(b"opaque"),
+            Frame::Ping,
+            Frame::Pong,
+            Frame::Goodbye(None),
+            Frame::Goodbye(Some(RejectReason::Draining)),
+            Frame::Goodbye(Some(RejectReason::ShuttingDown)),
+            Frame::Goodbye(Some(RejectReason::Kicked)),
+            Frame::Goodbye(Some(RejectReason::Idle)),
+            Frame::Oversized(65536),
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+        }
+        assert_eq!(Frame::Data(b"x").encode(), vec![FRAME_DATA, b'x']);
+        assert_eq!(Frame::Goodbye(Some(RejectReason::QuotaExceeded)).encode(), vec![FRAME_GOODBYE, 6]);
+        assert_eq!(Frame::Oversized(1024).encode(), vec![FRAME_ERROR, 8, 0, 0, 4, 0]);
+    }
+
+    #[test]
+    fn test_empty_and_malformed_frames_are_invalid() {
+        assert_eq!(Frame::decode(&[]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[FRAME_DATA]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[FRAME_PING, 0]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[FRAME_GOODBYE, 0xee]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[FRAME_ERROR, 8, 0, 4]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[FRAME_ERROR, 4, 0, 0, 4, 0]), Err(ProtocolError::InvalidFrame));
+        assert_eq!(Frame::decode(&[0x42, 1, 2]), Err(ProtocolError::InvalidFrame));
+        // The old handshake rejection layout is not a frame either
+        assert_eq!(Frame::decode(&encode_rejection(RejectReason::Overloaded)), Err(ProtocolError::InvalidFrame));
+    }
+}
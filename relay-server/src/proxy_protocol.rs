@@ -0,0 +1,227 @@
+// PROXY protocol headers from a load balancer in front of the relay
+//
+// Behind HAProxy or an NLB every connection comes from the balancer, so with
+// --proxy-protocol each accepted connection has to open with a v1 (text) or
+// v2 (binary) header naming the real client, which then stands in for the
+// peer address in limits, access lists and logs. Anyone able to reach the
+// listeners directly could claim any address this way, so it is only safe
+// when nothing but the balancer can.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a connection has to send its whole header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First bytes of a v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header allowed by the spec, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug)]
+pub enum ProxyHeaderError {
+    /// The connection failed or closed before the header was complete
+    Io(std::io::Error),
+    /// The connection doesn't start with a PROXY header
+    Missing,
+    /// A header that doesn't follow the spec
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for ProxyHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProxyHeaderError::Io(e) => write!(f, "Failed to read PROXY header: {}", e),
+            ProxyHeaderError::Missing => write!(f, "No PROXY header"),
+            ProxyHeaderError::Malformed(why) => write!(f, "Malformed PROXY header: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for ProxyHeaderError {}
+
+impl From<std::io::Error> for ProxyHeaderError {
+    fn from(e: std::io::Error) -> Self {
+        ProxyHeaderError::Io(e)
+    }
+}
+
+/// Read a PROXY header off the start of a connection, leaving the stream at
+/// the first byte the client sent. Returns the client's address, or None
+/// when the balancer doesn't name one (its own health checks) and the
+/// connection's peer address stands.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    // Both versions are at least this long, so nothing past the header is read
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut body = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+        parse_v2(fixed[0], fixed[1], &body)
+    } else if start.starts_with(b"PROXY ") {
+        // Byte at a time, as whatever follows the CRLF belongs to the client
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(ProxyHeaderError::Malformed("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(ProxyHeaderError::Missing)
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`, TCP6 alike, or
+/// `PROXY UNKNOWN ...\r\n`
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_prefix("PROXY "))
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or(ProxyHeaderError::Malformed("v1 header is not a text line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let parse_ip = |text: &str| -> Result<IpAddr, ProxyHeaderError> {
+        let ip = match fields[0] {
+            "TCP4" => text.parse::<Ipv4Addr>().map(IpAddr::V4),
+            _ => text.parse::<Ipv6Addr>().map(IpAddr::V6),
+        };
+        ip.map_err(|_| ProxyHeaderError::Malformed("v1 address doesn't match its family"))
+    };
+    let parse_port = |text: &str| text.parse::<u16>().map_err(|_| ProxyHeaderError::Malformed("v1 port is not a number"));
+
+    match fields[0] {
+        "UNKNOWN" => Ok(None),
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let source = parse_ip(fields[1])?;
+            parse_ip(fields[2])?;
+            let port = parse_port(fields[3])?;
+            parse_port(fields[4])?;
+            Ok(Some(SocketAddr::new(source, port)))
+        }
+        "TCP4" | "TCP6" => Err(ProxyHeaderError::Malformed("v1 header has the wrong number of fields")),
+        _ => Err(ProxyHeaderError::Malformed("v1 header has an unknown protocol")),
+    }
+}
+
+/// Version and command byte, family and protocol byte, then the addresses
+/// and any TLVs, which are skipped
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    if version_command >> 4 != 2 {
+        return Err(ProxyHeaderError::Malformed("v2 header has an unknown version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the balancer talking for itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(ProxyHeaderError::Malformed("v2 header has an unknown command")),
+    }
+
+    let truncated = ProxyHeaderError::Malformed("v2 addresses are truncated");
+    match family {
+        0x00 => Ok(None),
+        // TCP over IPv4: source, destination, source port, destination port
+        0x11 => {
+            let addresses = body.get(..12).ok_or(truncated)?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6, laid out the same
+        0x21 => {
+            let addresses = body.get(..36).ok_or(truncated)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        _ => Err(ProxyHeaderError::Malformed("v2 header is not for a TCP connection")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> (Result<Option<SocketAddr>, ProxyHeaderError>, Vec<u8>) {
+        let mut stream = input;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    fn v2_header(version_command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[version_command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn test_v1_headers() {
+        let (result, rest) = read(b"PROXY TCP4 203.0.113.7 192.0.2.1 51000 8080\r\njoin").await;
+        assert_eq!(result.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"join", "client bytes after the header are left alone");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 443 8080\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::7]:443".parse().unwrap()));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn test_v2_headers() {
+        let mut body = vec![203, 0, 113, 7, 192, 0, 2, 1];
+        body.extend_from_slice(&51000u16.to_be_bytes());
+        body.extend_from_slice(&8080u16.to_be_bytes());
+        // A TLV after the addresses is skipped
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let mut input = v2_header(0x21, 0x11, &body);
+        input.extend_from_slice(b"join");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"join");
+
+        let mut body = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0x01, 0xbb, 0x1f, 0x90]);
+        let (result, _) = read(&v2_header(0x21, 0x21, &body)).await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::7]:443".parse().unwrap()));
+
+        // The balancer's own connections keep the peer address
+        let (result, _) = read(&v2_header(0x20, 0x00, &[])).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_headers_are_rejected() {
+        let malformed: Vec<Vec<u8>> = vec![
+            b"PROXY TCP4 2001:db8::7 192.0.2.1 51000 8080\r\n".to_vec(),
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51000\r\n".to_vec(),
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51000 http\r\n".to_vec(),
+            b"PROXY UDP4 203.0.113.7 192.0.2.1 51000 8080\r\n".to_vec(),
+            [b"PROXY UNKNOWN ".as_slice(), &[b'x'; 120], b"\r\n"].concat(),
+            v2_header(0x31, 0x11, &[0; 12]),
+            v2_header(0x22, 0x11, &[0; 12]),
+            v2_header(0x21, 0x11, &[0; 8]),
+            v2_header(0x21, 0x12, &[0; 12]),
+        ];
+        for input in malformed {
+            let (result, _) = read(&input).await;
+            assert!(matches!(result, Err(ProxyHeaderError::Malformed(_))), "{:?}: {:?}", input, result);
+        }
+
+        let (result, _) = read(b"\0\0\0\x05\x03hello world").await;
+        assert!(matches!(result, Err(ProxyHeaderError::Missing)), "{:?}", result);
+        let (result, _) = read(b"PROXY TCP4 203.0.113.7").await;
+        assert!(matches!(result, Err(ProxyHeaderError::Io(_))), "{:?}", result);
+    }
+}
@@ -0,0 +1,132 @@
+// Per-address bandwidth quotas over a fixed window
+//
+// Usage is keyed on the client's address bucket rather than its connection,
+// so reconnecting doesn't reset the allowance before the window ends.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+/// What happens to a client that uses up its quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaAction {
+    /// Send a quota-exceeded frame and close the connection
+    #[default]
+    Disconnect,
+    /// Keep relaying, but only at a trickle until the window ends
+    Throttle,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaLimits {
+    /// Bytes a client may send per window
+    pub bytes: u64,
+    pub window: Duration,
+}
+
+#[derive(Debug)]
+struct Usage {
+    window_start: Instant,
+    bytes: u64,
+}
+
+/// Bytes sent per address within the current window
+#[derive(Debug)]
+pub struct QuotaTracker {
+    limits: QuotaLimits,
+    usage: HashMap<IpAddr, Usage>,
+    next_expiry: Instant,
+}
+
+impl QuotaTracker {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            usage: HashMap::new(),
+            next_expiry: Instant::now() + limits.window,
+        }
+    }
+
+    /// Apply new limits from a config reload; usage so far still counts
+    pub fn set_limits(&mut self, limits: QuotaLimits) {
+        self.limits = limits;
+    }
+
+    /// Charge bytes to an address; returns false once it is over quota
+    pub fn charge(&mut self, key: IpAddr, bytes: usize, now: Instant) -> bool {
+        self.expire(now);
+        let window = self.limits.window;
+        let usage = self.usage.entry(key).or_insert(Usage { window_start: now, bytes: 0 });
+        if now.duration_since(usage.window_start) >= window {
+            *usage = Usage { window_start: now, bytes: 0 };
+        }
+        usage.bytes = usage.bytes.saturating_add(bytes as u64);
+        usage.bytes <= self.limits.bytes
+    }
+
+    /// Bytes charged to an address in its current window
+    pub fn used(&self, key: IpAddr, now: Instant) -> u64 {
+        match self.usage.get(&key) {
+            Some(usage) if now.duration_since(usage.window_start) < self.limits.window => usage.bytes,
+            _ => 0,
+        }
+    }
+
+    /// Whether an address has nothing left to send in its current window
+    pub fn exhausted(&self, key: IpAddr, now: Instant) -> bool {
+        self.used(key, now) >= self.limits.bytes
+    }
+
+    /// Forget addresses whose window has ended, at most once per window
+    fn expire(&mut self, now: Instant) {
+        if now < self.next_expiry {
+            return;
+        }
+        let window = self.limits.window;
+        self.usage.retain(|_, usage| now.duration_since(usage.window_start) < window);
+        self.next_expiry = now + window;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(bytes: u64, window_secs: u64) -> QuotaTracker {
+        QuotaTracker::new(QuotaLimits { bytes, window: Duration::from_secs(window_secs) })
+    }
+
+    #[test]
+    fn test_quota_is_shared_per_address() {
+        let mut quota = tracker(100, 60);
+        let now = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(quota.charge(a, 60, now));
+        assert!(quota.charge(a, 40, now));
+        assert!(quota.exhausted(a, now));
+        assert!(!quota.charge(a, 1, now));
+        assert_eq!(quota.used(a, now), 101);
+
+        assert!(quota.charge(b, 100, now), "other addresses have their own allowance");
+    }
+
+    #[test]
+    fn test_quota_resets_after_window() {
+        let mut quota = tracker(10, 60);
+        let start = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(!quota.charge(a, 11, start));
+        let later = start + Duration::from_secs(61);
+        assert_eq!(quota.used(a, later), 0);
+        assert!(!quota.exhausted(a, later));
+        assert!(quota.charge(a, 10, later));
+
+        quota.expire(later + Duration::from_secs(120));
+        assert!(quota.usage.is_empty());
+    }
+}
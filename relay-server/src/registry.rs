@@ -0,0 +1,158 @@
+// Registry of connected clients, indexed by id and by session
+//
+// Shared behind a read-write lock: routing a frame only reads, while
+// registration and removal (rare by comparison) take the write lock.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::ClientInfo;
+use crate::stats::SessionCounters;
+
+/// Registry handle shared by connection tasks, the admin interface and probes
+pub type SharedRegistry = Arc<RwLock<ClientRegistry>>;
+
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    clients: HashMap<Uuid, ClientInfo>,
+    sessions: HashMap<String, Session>,
+}
+
+/// Members of a session and its delivery counters, dropped with the last member
+#[derive(Debug, Default)]
+struct Session {
+    members: Vec<Uuid>,
+    counters: Arc<SessionCounters>,
+}
+
+impl ClientRegistry {
+    pub fn shared() -> SharedRegistry {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&ClientInfo> {
+        self.clients.get(id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ClientInfo> {
+        self.clients.values()
+    }
+
+    /// Clients currently in a session
+    pub fn session_members<'a>(&'a self, session: &str) -> impl Iterator<Item = &'a ClientInfo> + 'a {
+        self.sessions
+            .get(session)
+            .into_iter()
+            .flat_map(|session| &session.members)
+            .filter_map(|id| self.clients.get(id))
+    }
+
+    /// Delivery counters of a session with members
+    pub fn session_counters(&self, session: &str) -> Option<&Arc<SessionCounters>> {
+        self.sessions.get(session).map(|session| &session.counters)
+    }
+
+    /// Sessions with at least one member
+    pub fn session_codes(&self) -> impl Iterator<Item = &str> {
+        self.sessions.keys().map(String::as_str)
+    }
+
+    /// Register a client, returning the counters of the session it joined
+    pub fn insert(&mut self, client: ClientInfo) -> Arc<SessionCounters> {
+        self.remove(&client.id);
+        let session = self.sessions.entry(client.session.clone()).or_default();
+        session.members.push(client.id);
+        let counters = Arc::clone(&session.counters);
+        self.clients.insert(client.id, client);
+        counters
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<ClientInfo> {
+        let client = self.clients.remove(id)?;
+        self.unindex(&client);
+        Some(client)
+    }
+
+    fn unindex(&mut self, client: &ClientInfo) {
+        if let Some(session) = self.sessions.get_mut(&client.session) {
+            session.members.retain(|member| *member != client.id);
+            if session.members.is_empty() {
+                self.sessions.remove(&client.session);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::sync::{Notify, mpsc};
+    use crate::stats::ClientCounters;
+
+    fn client(session: &str) -> ClientInfo {
+        let (sender, _) = mpsc::channel(1);
+        ClientInfo {
+            id: Uuid::new_v4(),
+            addr: "127.0.0.1:9".parse().unwrap(),
+            session: session.to_string(),
+            sender,
+            connected_at: Instant::now(),
+            max_message_size: 65536,
+            counters: Arc::new(ClientCounters::default()),
+            kick: Arc::new(Notify::new()),
+        }
+    }
+
+    #[test]
+    fn test_session_index_tracks_membership() {
+        let mut registry = ClientRegistry::default();
+        let (a, b, c) = (client("one"), client("one"), client("two"));
+        let (a_id, b_id) = (a.id, b.id);
+        registry.insert(a);
+        registry.insert(b);
+        registry.insert(c);
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.session_count(), 2);
+        assert_eq!(registry.session_members("one").count(), 2);
+
+        registry.remove(&a_id);
+        let members: Vec<Uuid> = registry.session_members("one").map(|c| c.id).collect();
+        assert_eq!(members, vec![b_id]);
+
+        registry.remove(&b_id);
+        assert_eq!(registry.session_count(), 1);
+        assert_eq!(registry.session_members("one").count(), 0);
+    }
+
+    #[test]
+    fn test_session_counters_live_as_long_as_the_session() {
+        let mut registry = ClientRegistry::default();
+        let (a, b) = (client("pair"), client("pair"));
+        let a_id = a.id;
+        let b_id = b.id;
+        let counters = registry.insert(a);
+        assert!(Arc::ptr_eq(&counters, &registry.insert(b)), "members share their session's counters");
+        counters.record_inbound();
+
+        registry.remove(&a_id);
+        assert_eq!(registry.session_counters("pair").unwrap().frames_in.load(std::sync::atomic::Ordering::Relaxed), 1);
+        registry.remove(&b_id);
+        assert!(registry.session_counters("pair").is_none());
+
+        // A session that starts over under the same code starts from zero
+        let fresh = registry.insert(client("pair"));
+        assert_eq!(fresh.frames_in.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,287 @@
+// Relay counters shared by connection tasks and the admin interface
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::logging::ConnectionTotals;
+
+/// Relay-wide counters, updated lock-free from connection tasks
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    pub connections_accepted: AtomicU64,
+    pub connections_rejected: AtomicU64,
+    pub frames_relayed: AtomicU64,
+    pub bytes_relayed: AtomicU64,
+    /// Frames queued by store-and-forward for an absent peer
+    pub frames_buffered: AtomicU64,
+    /// Buffered frames lost to TTL expiry, queue limits or eviction
+    pub frames_buffer_dropped: AtomicU64,
+    /// Frames dropped because a recipient's queue was full
+    pub frames_dropped: AtomicU64,
+    /// Clients that went over their bandwidth quota
+    pub quota_exceeded: AtomicU64,
+    /// Joined clients that have since disconnected, for whatever reason
+    pub connections_closed: AtomicU64,
+    /// Frames and bytes written to clients that have disconnected
+    pub frames_delivered: AtomicU64,
+    pub bytes_delivered: AtomicU64,
+    /// Time those clients spent connected, in milliseconds
+    pub connected_ms: AtomicU64,
+}
+
+/// Point-in-time copy of the relay counters
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    pub frames_relayed: u64,
+    pub bytes_relayed: u64,
+    pub frames_buffered: u64,
+    pub frames_buffer_dropped: u64,
+    pub frames_dropped: u64,
+    pub quota_exceeded: u64,
+    pub connections_closed: u64,
+    pub frames_delivered: u64,
+    pub bytes_delivered: u64,
+    pub connected_ms: u64,
+}
+
+impl RelayStats {
+    pub fn record_frame(&self, bytes: usize) {
+        self.frames_relayed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_relayed.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Fold a finished connection's totals into the relay's; its inbound
+    /// frames were already counted as they were relayed
+    pub fn record_disconnect(&self, totals: &ConnectionTotals) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+        self.frames_delivered.fetch_add(totals.frames_out, Ordering::Relaxed);
+        self.bytes_delivered.fetch_add(totals.bytes_out, Ordering::Relaxed);
+        self.connected_ms.fetch_add(totals.duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections_accepted: self.connections_accepted.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            frames_relayed: self.frames_relayed.load(Ordering::Relaxed),
+            bytes_relayed: self.bytes_relayed.load(Ordering::Relaxed),
+            frames_buffered: self.frames_buffered.load(Ordering::Relaxed),
+            frames_buffer_dropped: self.frames_buffer_dropped.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            quota_exceeded: self.quota_exceeded.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+            frames_delivered: self.frames_delivered.load(Ordering::Relaxed),
+            bytes_delivered: self.bytes_delivered.load(Ordering::Relaxed),
+            connected_ms: self.connected_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Counter increases since an earlier snapshot
+    pub fn delta_since(&self, earlier: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            connections_accepted: self.connections_accepted.saturating_sub(earlier.connections_accepted),
+            connections_rejected: self.connections_rejected.saturating_sub(earlier.connections_rejected),
+            frames_relayed: self.frames_relayed.saturating_sub(earlier.frames_relayed),
+            bytes_relayed: self.bytes_relayed.saturating_sub(earlier.bytes_relayed),
+            frames_buffered: self.frames_buffered.saturating_sub(earlier.frames_buffered),
+            frames_buffer_dropped: self.frames_buffer_dropped.saturating_sub(earlier.frames_buffer_dropped),
+            frames_dropped: self.frames_dropped.saturating_sub(earlier.frames_dropped),
+            quota_exceeded: self.quota_exceeded.saturating_sub(earlier.quota_exceeded),
+            connections_closed: self.connections_closed.saturating_sub(earlier.connections_closed),
+            frames_delivered: self.frames_delivered.saturating_sub(earlier.frames_delivered),
+            bytes_delivered: self.bytes_delivered.saturating_sub(earlier.bytes_delivered),
+            connected_ms: self.connected_ms.saturating_sub(earlier.connected_ms),
+        }
+    }
+}
+
+/// Emit the periodic heartbeat line: current load plus activity over the interval
+pub fn log_stats_report(active_clients: usize, active_sessions: usize, interval: &StatsSnapshot) {
+    info!(
+        active_clients,
+        active_sessions,
+        frames = interval.frames_relayed,
+        bytes = interval.bytes_relayed,
+        accepted = interval.connections_accepted,
+        rejected = interval.connections_rejected,
+        closed = interval.connections_closed,
+        dropped = interval.frames_dropped + interval.frames_buffer_dropped,
+        "Relay stats"
+    );
+}
+
+/// Per-client counters shared between the connection task and the clients map
+#[derive(Debug, Default)]
+pub struct ClientCounters {
+    /// Milliseconds after connect of the last inbound frame
+    pub last_activity_ms: AtomicU64,
+    pub frames_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub frames_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Frames dropped because this client's queue was full
+    pub frames_dropped: AtomicU64,
+    /// Drops since the last frame successfully queued for this client
+    pub consecutive_drops: AtomicU64,
+    /// Bytes waiting in this client's queue
+    pub queued_bytes: AtomicU64,
+    /// Set when the client is disconnected to relieve the relay's buffers
+    pub overloaded: AtomicBool,
+    /// Set when the idle sweep disconnects the client
+    pub idle: AtomicBool,
+}
+
+impl ClientCounters {
+    pub fn record_inbound(&self, bytes: usize, at_ms: u64) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity_ms.store(at_ms, Ordering::Relaxed);
+    }
+
+    /// A keepalive frame only counts as activity
+    pub fn record_keepalive(&self, at_ms: u64) {
+        self.last_activity_ms.store(at_ms, Ordering::Relaxed);
+    }
+
+    pub fn record_outbound(&self, bytes: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a dropped frame, returning the current run of consecutive drops
+    pub fn record_dropped(&self) -> u64 {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_drops.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Per-session delivery counters, kept for as long as the session has members
+#[derive(Debug)]
+pub struct SessionCounters {
+    pub created_at: Instant,
+    /// Milliseconds after creation of the last frame from a member
+    pub last_activity_ms: AtomicU64,
+    pub frames_in: AtomicU64,
+    /// Frames queued for a member on this relay
+    pub frames_delivered: AtomicU64,
+    /// Frames a member missed because it was behind or the relay was over its buffer limit
+    pub frames_dropped: AtomicU64,
+    /// Frames held by store-and-forward for a member yet to join
+    pub frames_buffered: AtomicU64,
+    /// Frames sent on to members on peer relays
+    pub frames_forwarded: AtomicU64,
+}
+
+impl Default for SessionCounters {
+    fn default() -> Self {
+        Self {
+            created_at: Instant::now(),
+            last_activity_ms: AtomicU64::new(0),
+            frames_in: AtomicU64::new(0),
+            frames_delivered: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            frames_buffered: AtomicU64::new(0),
+            frames_forwarded: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SessionCounters {
+    pub fn record_inbound(&self) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.last_activity_ms.store(self.created_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since a member last sent a frame, or since the session began
+    pub fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+        self.created_at.elapsed().saturating_sub(last_activity)
+    }
+}
+
+/// Hex digits of a session code's hash used to label it in metrics; sessions
+/// sharing a prefix are summed, so there are never more than 256 series
+const SESSION_LABEL_LEN: usize = 2;
+
+/// Metrics label for a session: a short hash prefix, which keeps the number
+/// of series bounded and the code itself out of the metrics
+pub fn session_label(session: &str) -> String {
+    let digest = Sha256::digest(session.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()[..SESSION_LABEL_LEN].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::CapturedOutput;
+
+    #[test]
+    fn test_snapshot_reflects_recorded_frames() {
+        let stats = RelayStats::default();
+        stats.record_frame(100);
+        stats.record_frame(28);
+        stats.connections_accepted.fetch_add(2, Ordering::Relaxed);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.frames_relayed, 2);
+        assert_eq!(snapshot.bytes_relayed, 128);
+        assert_eq!(snapshot.connections_accepted, 2);
+        assert_eq!(snapshot.connections_rejected, 0);
+    }
+
+    #[test]
+    fn test_delta_since_counts_only_new_activity() {
+        let stats = RelayStats::default();
+        stats.record_frame(10);
+        let earlier = stats.snapshot();
+
+        stats.record_frame(5);
+        stats.connections_rejected.fetch_add(3, Ordering::Relaxed);
+        let delta = stats.snapshot().delta_since(&earlier);
+
+        assert_eq!(delta.frames_relayed, 1);
+        assert_eq!(delta.bytes_relayed, 5);
+        assert_eq!(delta.connections_rejected, 3);
+    }
+
+    #[test]
+    fn test_stats_report_line_format() {
+        let output = CapturedOutput::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+
+        let interval = StatsSnapshot {
+            connections_accepted: 4,
+            connections_rejected: 1,
+            frames_relayed: 120,
+            bytes_relayed: 48_000,
+            frames_buffered: 0,
+            frames_buffer_dropped: 2,
+            frames_dropped: 3,
+            quota_exceeded: 0,
+            connections_closed: 2,
+            frames_delivered: 90,
+            bytes_delivered: 36_000,
+            connected_ms: 5_000,
+        };
+        tracing::subscriber::with_default(subscriber, || log_stats_report(6, 3, &interval));
+
+        let line = output.contents();
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.contains("INFO"), "{}", line);
+        assert!(line.contains(
+            "Relay stats active_clients=6 active_sessions=3 frames=120 bytes=48000 accepted=4 rejected=1 closed=2 dropped=5"
+        ), "{}", line);
+    }
+}
@@ -0,0 +1,236 @@
+// Store-and-forward buffering for sessions whose partner hasn't joined yet
+//
+// Frames sent into a session with nobody else in it are queued here and
+// handed to the next client that joins. Buffers are bounded per session
+// (frames and bytes), expire after a TTL, and share a global byte budget
+// that evicts the oldest sessions first.
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::stats::RelayStats;
+
+/// Limits applied to buffered frames
+#[derive(Debug, Clone, Copy)]
+pub struct StoreForwardLimits {
+    pub ttl: Duration,
+    pub max_session_bytes: usize,
+    pub max_session_frames: usize,
+    pub max_total_bytes: usize,
+}
+
+struct SessionBuffer {
+    created_at: Instant,
+    frames: VecDeque<(Instant, Bytes)>,
+    bytes: usize,
+}
+
+/// Buffered frames for all sessions awaiting a partner
+pub struct StoreForward {
+    limits: StoreForwardLimits,
+    sessions: HashMap<String, SessionBuffer>,
+    total_bytes: usize,
+    stats: Arc<RelayStats>,
+}
+
+impl StoreForward {
+    pub fn new(limits: StoreForwardLimits, stats: Arc<RelayStats>) -> Self {
+        Self {
+            limits,
+            sessions: HashMap::new(),
+            total_bytes: 0,
+            stats,
+        }
+    }
+
+    /// Apply new limits from a config reload; frames already buffered stay
+    /// until they expire or are evicted
+    pub fn set_limits(&mut self, limits: StoreForwardLimits) {
+        self.limits = limits;
+    }
+
+    /// Queue a frame for a session; returns false if it had to be dropped
+    pub fn push(&mut self, session: &str, frame: Bytes, now: Instant) -> bool {
+        self.expire(now);
+
+        let len = frame.len();
+        if len > self.limits.max_session_bytes || len > self.limits.max_total_bytes {
+            self.record_dropped(1);
+            return false;
+        }
+
+        if let Some(buffer) = self.sessions.get(session) {
+            if buffer.frames.len() >= self.limits.max_session_frames
+                || buffer.bytes + len > self.limits.max_session_bytes
+            {
+                debug!("Store-and-forward buffer full for session {}, dropping frame", session);
+                self.record_dropped(1);
+                return false;
+            }
+        }
+
+        // Make room globally by evicting whole sessions, oldest first
+        while self.total_bytes + len > self.limits.max_total_bytes {
+            let oldest = self
+                .sessions
+                .iter()
+                .filter(|(code, _)| code.as_str() != session)
+                .min_by_key(|(_, buffer)| buffer.created_at)
+                .map(|(code, _)| code.clone());
+
+            match oldest {
+                Some(code) => {
+                    debug!("Store-and-forward global cap reached, evicting session {}", code);
+                    self.remove_session(&code);
+                }
+                None => {
+                    self.record_dropped(1);
+                    return false;
+                }
+            }
+        }
+
+        let buffer = self.sessions.entry(session.to_string()).or_insert_with(|| SessionBuffer {
+            created_at: now,
+            frames: VecDeque::new(),
+            bytes: 0,
+        });
+        buffer.frames.push_back((now, frame));
+        buffer.bytes += len;
+        self.total_bytes += len;
+        self.stats.frames_buffered.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Remove and return a session's unexpired frames in arrival order
+    pub fn take(&mut self, session: &str, now: Instant) -> Vec<Bytes> {
+        self.expire(now);
+
+        match self.sessions.remove(session) {
+            Some(buffer) => {
+                self.total_bytes -= buffer.bytes;
+                buffer.frames.into_iter().map(|(_, frame)| frame).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Bytes currently held across all sessions
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Drop frames older than the TTL
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.limits.ttl;
+        let mut expired = 0;
+        let mut freed = 0;
+
+        self.sessions.retain(|_, buffer| {
+            while let Some((queued_at, frame)) = buffer.frames.front() {
+                if now.saturating_duration_since(*queued_at) < ttl {
+                    break;
+                }
+                buffer.bytes -= frame.len();
+                freed += frame.len();
+                expired += 1;
+                buffer.frames.pop_front();
+            }
+            !buffer.frames.is_empty()
+        });
+
+        self.total_bytes -= freed;
+        self.record_dropped(expired);
+    }
+
+    fn remove_session(&mut self, session: &str) {
+        if let Some(buffer) = self.sessions.remove(session) {
+            self.total_bytes -= buffer.bytes;
+            self.record_dropped(buffer.frames.len() as u64);
+        }
+    }
+
+    fn record_dropped(&self, frames: u64) {
+        if frames > 0 {
+            self.stats.frames_buffer_dropped.fetch_add(frames, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> StoreForwardLimits {
+        StoreForwardLimits {
+            ttl: Duration::from_secs(30),
+            max_session_bytes: 100,
+            max_session_frames: 4,
+            max_total_bytes: 150,
+        }
+    }
+
+    fn store() -> (StoreForward, Arc<RelayStats>) {
+        let stats = Arc::new(RelayStats::default());
+        (StoreForward::new(limits(), Arc::clone(&stats)), stats)
+    }
+
+    #[test]
+    fn test_frames_flush_in_order() {
+        let (mut store, stats) = store();
+        let now = Instant::now();
+
+        assert!(store.push("a", Bytes::from_static(b"one"), now));
+        assert!(store.push("a", Bytes::from_static(b"two"), now));
+        assert_eq!(store.take("a", now), vec![Bytes::from_static(b"one"), Bytes::from_static(b"two")]);
+        assert!(store.take("a", now).is_empty());
+        assert_eq!(store.total_bytes(), 0);
+        assert_eq!(stats.frames_buffered.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_ttl_expiry_drops_frames() {
+        let (mut store, stats) = store();
+        let start = Instant::now();
+
+        store.push("a", Bytes::from_static(b"stale"), start);
+        store.push("a", Bytes::from_static(b"fresh"), start + Duration::from_secs(20));
+
+        let later = start + Duration::from_secs(31);
+        assert_eq!(store.take("a", later), vec![Bytes::from_static(b"fresh")]);
+        assert_eq!(stats.frames_buffer_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_per_session_limits() {
+        let (mut store, stats) = store();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert!(store.push("a", Bytes::from(vec![0u8; 10]), now));
+        }
+        assert!(!store.push("a", Bytes::from(vec![0u8; 10]), now), "frame count limit ignored");
+        assert!(!store.push("b", Bytes::from(vec![0u8; 101]), now), "byte limit ignored");
+        assert_eq!(stats.frames_buffer_dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_global_cap_evicts_oldest_session() {
+        let (mut store, stats) = store();
+        let start = Instant::now();
+
+        store.push("oldest", Bytes::from(vec![0u8; 60]), start);
+        store.push("middle", Bytes::from(vec![0u8; 60]), start + Duration::from_secs(1));
+        store.push("newest", Bytes::from(vec![0u8; 60]), start + Duration::from_secs(2));
+
+        let now = start + Duration::from_secs(3);
+        assert!(store.take("oldest", now).is_empty());
+        assert_eq!(store.take("middle", now).len(), 1);
+        assert_eq!(store.take("newest", now).len(), 1);
+        assert_eq!(stats.frames_buffer_dropped.load(Ordering::Relaxed), 1);
+    }
+}
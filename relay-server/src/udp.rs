@@ -0,0 +1,359 @@
+// UDP relay mode - forwards datagrams between the two members of a session
+//
+// Datagrams start with a type byte:
+//   0x01 register: join frame (version byte + session code); the relay answers
+//        0x01 + token (16 bytes) +
+//        peer present (1 byte), or a 0x00 + reason rejection
+//   0x02 data: token (16 bytes) + payload; the partner receives 0x02 + payload.
+//        An empty payload is a keepalive and is not forwarded.
+// The token authenticates data, so a member whose NAT mapping changes keeps
+// its slot: the relay just starts sending to the new source address.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::acl::AccessList;
+use crate::protocol::{ClientJoin, ProtocolError, RejectReason, encode_rejection};
+use crate::stats::RelayStats;
+
+pub const UDP_REGISTER: u8 = 0x01;
+pub const UDP_DATA: u8 = 0x02;
+
+/// Token length in register replies and data datagrams
+pub const UDP_TOKEN_LEN: usize = 16;
+
+/// Members per UDP session
+const UDP_SESSION_MEMBERS: usize = 2;
+
+struct UdpMember {
+    token: Uuid,
+    endpoint: SocketAddr,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct UdpSession {
+    members: Vec<UdpMember>,
+    bytes_forwarded: u64,
+}
+
+/// Outcome of a register datagram
+#[derive(Debug, PartialEq, Eq)]
+pub enum Registration {
+    Registered { token: Uuid, peer_present: bool },
+    SessionFull,
+}
+
+/// Session table mapping codes to member endpoints
+#[derive(Default)]
+pub struct UdpSessions {
+    by_code: HashMap<String, UdpSession>,
+    by_token: HashMap<Uuid, String>,
+}
+
+impl UdpSessions {
+    /// Add an endpoint to a session; re-registering the same endpoint returns its token
+    pub fn register(&mut self, code: &str, endpoint: SocketAddr, now: Instant) -> Registration {
+        let session = self.by_code.entry(code.to_string()).or_default();
+
+        if let Some(member) = session.members.iter_mut().find(|m| m.endpoint == endpoint) {
+            member.last_seen = now;
+            let token = member.token;
+            let peer_present = session.members.len() > 1;
+            return Registration::Registered { token, peer_present };
+        }
+
+        if session.members.len() >= UDP_SESSION_MEMBERS {
+            return Registration::SessionFull;
+        }
+
+        let token = Uuid::new_v4();
+        let peer_present = !session.members.is_empty();
+        session.members.push(UdpMember { token, endpoint, last_seen: now });
+        self.by_token.insert(token, code.to_string());
+        Registration::Registered { token, peer_present }
+    }
+
+    /// Record traffic from a member and return its partner's endpoint, if any
+    ///
+    /// A valid token from a new source address moves the member to that address.
+    pub fn route(&mut self, token: Uuid, from: SocketAddr, bytes: usize, now: Instant) -> Option<Option<SocketAddr>> {
+        let code = self.by_token.get(&token)?;
+        let session = self.by_code.get_mut(code)?;
+
+        let member = session.members.iter_mut().find(|m| m.token == token)?;
+        if member.endpoint != from {
+            info!("UDP member of session {} moved from {} to {}", code, member.endpoint, from);
+            member.endpoint = from;
+        }
+        member.last_seen = now;
+
+        let partner = session.members.iter().find(|m| m.token != token).map(|m| m.endpoint);
+        if partner.is_some() {
+            session.bytes_forwarded += bytes as u64;
+        }
+        Some(partner)
+    }
+
+    /// Forget members silent for longer than `ttl`, and sessions left empty
+    pub fn expire(&mut self, ttl: Duration, now: Instant) {
+        let by_token = &mut self.by_token;
+        self.by_code.retain(|code, session| {
+            session.members.retain(|member| {
+                let alive = now.saturating_duration_since(member.last_seen) < ttl;
+                if !alive {
+                    by_token.remove(&member.token);
+                }
+                alive
+            });
+            if session.members.is_empty() {
+                debug!("UDP session {} expired after forwarding {} bytes", code, session.bytes_forwarded);
+            }
+            !session.members.is_empty()
+        });
+    }
+
+    #[cfg(test)]
+    pub fn bytes_forwarded(&self, code: &str) -> Option<u64> {
+        self.by_code.get(code).map(|session| session.bytes_forwarded)
+    }
+}
+
+/// Settings and shared state for the UDP listener
+pub struct UdpRelay {
+    pub max_datagram_size: usize,
+    pub session_ttl: Duration,
+    pub stats: Arc<RelayStats>,
+    pub draining: Arc<AtomicBool>,
+    pub access: Arc<std::sync::RwLock<AccessList>>,
+    pub sessions: Mutex<UdpSessions>,
+}
+
+impl UdpRelay {
+    /// Forward datagrams until shutdown
+    pub async fn serve(self: Arc<Self>, socket: UdpSocket, shutdown: CancellationToken) {
+        let sweeper = Arc::clone(&self);
+        let sweep_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let period = (sweeper.session_ttl / 4).clamp(Duration::from_millis(250), Duration::from_secs(30));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = sweep_shutdown.cancelled() => return,
+                }
+                sweeper.sessions.lock().await.expire(sweeper.session_ttl, Instant::now());
+            }
+        });
+
+        // One byte of headroom detects datagrams over the limit
+        let mut buffer = vec![0u8; self.max_datagram_size + 1];
+        loop {
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buffer) => received,
+                _ = shutdown.cancelled() => return,
+            };
+
+            match received {
+                Ok((len, from)) => {
+                    if len > self.max_datagram_size {
+                        debug!("Dropping oversized datagram ({} bytes) from {}", len, from);
+                        continue;
+                    }
+                    if let Some((reply, to)) = self.handle_datagram(&buffer[..len], from).await {
+                        if let Err(e) = socket.send_to(&reply, to).await {
+                            debug!("Failed to send datagram to {}: {}", to, e);
+                        }
+                    }
+                }
+                Err(e) => warn!("UDP receive failed: {}", e),
+            }
+        }
+    }
+
+    /// Process one datagram, returning what to send and where
+    async fn handle_datagram(&self, datagram: &[u8], from: SocketAddr) -> Option<(Vec<u8>, SocketAddr)> {
+        let (&kind, body) = datagram.split_first()?;
+        match kind {
+            UDP_REGISTER => {
+                if !self.access.read().unwrap_or_else(|e| e.into_inner()).permits(from.ip()) {
+                    return None;
+                }
+                if self.draining.load(Ordering::Relaxed) {
+                    return Some((encode_rejection(RejectReason::Draining), from));
+                }
+
+                let join = match ClientJoin::decode(body) {
+                    Ok(join) => join,
+                    Err(ProtocolError::UnsupportedVersion(_)) => {
+                        return Some((encode_rejection(RejectReason::UnsupportedVersion), from));
+                    }
+                    Err(_) => return None,
+                };
+                let registration = self.sessions.lock().await.register(&join.session_code, from, Instant::now());
+                match registration {
+                    Registration::Registered { token, peer_present } => {
+                        debug!("UDP endpoint {} registered in session {}", from, join.session_code);
+                        let mut reply = Vec::with_capacity(2 + UDP_TOKEN_LEN);
+                        reply.push(UDP_REGISTER);
+                        reply.extend_from_slice(token.as_bytes());
+                        reply.push(peer_present as u8);
+                        Some((reply, from))
+                    }
+                    Registration::SessionFull => Some((encode_rejection(RejectReason::SessionFull), from)),
+                }
+            }
+            UDP_DATA if body.len() >= UDP_TOKEN_LEN => {
+                let token = Uuid::from_slice(&body[..UDP_TOKEN_LEN]).ok()?;
+                let payload = &body[UDP_TOKEN_LEN..];
+                let partner = self.sessions.lock().await.route(token, from, payload.len(), Instant::now())?;
+
+                match partner {
+                    Some(partner) if !payload.is_empty() => {
+                        self.stats.record_frame(payload.len());
+                        let mut forwarded = Vec::with_capacity(1 + payload.len());
+                        forwarded.push(UDP_DATA);
+                        forwarded.extend_from_slice(payload);
+                        Some((forwarded, partner))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn token_of(registration: Registration) -> Uuid {
+        match registration {
+            Registration::Registered { token, .. } => token,
+            Registration::SessionFull => panic!("session unexpectedly full"),
+        }
+    }
+
+    #[test]
+    fn test_sessions_pair_two_endpoints() {
+        let mut sessions = UdpSessions::default();
+        let now = Instant::now();
+
+        let first = sessions.register("pair", addr("192.0.2.1:5000"), now);
+        assert!(matches!(first, Registration::Registered { peer_present: false, .. }));
+        let first = token_of(first);
+
+        let second = sessions.register("pair", addr("198.51.100.2:6000"), now);
+        assert!(matches!(second, Registration::Registered { peer_present: true, .. }));
+
+        assert_eq!(sessions.register("pair", addr("203.0.113.3:7000"), now), Registration::SessionFull);
+        assert_eq!(token_of(sessions.register("pair", addr("192.0.2.1:5000"), now)), first);
+
+        assert_eq!(sessions.route(first, addr("192.0.2.1:5000"), 10, now), Some(Some(addr("198.51.100.2:6000"))));
+        assert_eq!(sessions.route(Uuid::new_v4(), addr("192.0.2.1:5000"), 10, now), None);
+        assert_eq!(sessions.bytes_forwarded("pair"), Some(10));
+    }
+
+    #[test]
+    fn test_rebinding_follows_authenticated_traffic() {
+        let mut sessions = UdpSessions::default();
+        let now = Instant::now();
+
+        let first = token_of(sessions.register("nat", addr("192.0.2.1:5000"), now));
+        let second = token_of(sessions.register("nat", addr("198.51.100.2:6000"), now));
+
+        // First member's NAT picks a new port
+        sessions.route(first, addr("192.0.2.1:5999"), 4, now);
+        assert_eq!(sessions.route(second, addr("198.51.100.2:6000"), 4, now), Some(Some(addr("192.0.2.1:5999"))));
+    }
+
+    #[test]
+    fn test_silent_members_expire() {
+        let mut sessions = UdpSessions::default();
+        let start = Instant::now();
+        let ttl = Duration::from_secs(30);
+
+        let first = token_of(sessions.register("idle", addr("192.0.2.1:5000"), start));
+        let second = token_of(sessions.register("idle", addr("198.51.100.2:6000"), start));
+        sessions.route(second, addr("198.51.100.2:6000"), 0, start + Duration::from_secs(20));
+
+        sessions.expire(ttl, start + Duration::from_secs(31));
+        assert_eq!(sessions.route(first, addr("192.0.2.1:5000"), 1, start), None, "expired member kept its token");
+        assert_eq!(sessions.route(second, addr("198.51.100.2:6000"), 1, start), Some(None));
+
+        sessions.expire(ttl, start + Duration::from_secs(60));
+        assert_eq!(sessions.bytes_forwarded("idle"), None);
+    }
+
+    async fn start_udp_relay(session_ttl: Duration) -> SocketAddr {
+        let relay = Arc::new(UdpRelay {
+            max_datagram_size: 1200,
+            session_ttl,
+            stats: Arc::new(RelayStats::default()),
+            draining: Arc::new(AtomicBool::new(false)),
+            access: Arc::new(std::sync::RwLock::new(AccessList::default())),
+            sessions: Mutex::new(UdpSessions::default()),
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(relay.serve(socket, CancellationToken::new()));
+        addr
+    }
+
+    async fn register(relay: SocketAddr, code: &str) -> (UdpSocket, Vec<u8>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(relay).await.unwrap();
+        let mut datagram = vec![UDP_REGISTER, PROTOCOL_VERSION];
+        datagram.extend_from_slice(code.as_bytes());
+        socket.send(&datagram).await.unwrap();
+
+        let reply = recv(&socket).await.expect("no register reply");
+        assert_eq!(reply[0], UDP_REGISTER);
+        (socket, reply[1..1 + UDP_TOKEN_LEN].to_vec())
+    }
+
+    async fn send_data(socket: &UdpSocket, token: &[u8], payload: &[u8]) {
+        let mut datagram = vec![UDP_DATA];
+        datagram.extend_from_slice(token);
+        datagram.extend_from_slice(payload);
+        socket.send(&datagram).await.unwrap();
+    }
+
+    async fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 2048];
+        let len = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buffer)).await.ok()?.ok()?;
+        Some(buffer[..len].to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_udp_forwarding_both_ways_then_expiry() {
+        let relay = start_udp_relay(Duration::from_secs(1)).await;
+
+        let (alice, alice_token) = register(relay, "udp-pair").await;
+        let (bob, bob_token) = register(relay, "udp-pair").await;
+
+        send_data(&alice, &alice_token, b"ping").await;
+        assert_eq!(recv(&bob).await.unwrap(), b"\x02ping");
+        send_data(&bob, &bob_token, b"pong").await;
+        assert_eq!(recv(&alice).await.unwrap(), b"\x02pong");
+
+        // Both go quiet past the TTL, so the session is forgotten
+        tokio::time::sleep(Duration::from_millis(1600)).await;
+        send_data(&alice, &alice_token, b"late").await;
+        assert!(recv(&bob).await.is_none(), "datagram forwarded for an expired session");
+    }
+}
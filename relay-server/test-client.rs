@@ -1,10 +1,30 @@
 // Simple test client for Silence Relay Server
 // Usage: cargo run --bin test-client -- --relay-server 127.0.0.1:8080
+//        cargo run --bin test-client -- --relay-server 127.0.0.1:8080 --interactive
+//        cargo run --bin test-client -- --relay-server 127.0.0.1:8080 --self-test
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use clap::Parser;
-use std::time::Duration;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)] // Shared with the relay; only the client half is used here
+#[path = "src/protocol.rs"]
+mod protocol;
+
+use protocol::{ClientJoin, Frame, RejectReason, ServerHello, PROTOCOL_VERSION, REJECT_MARKER};
+
+/// Frames waiting to be written before the sender has to wait
+const OUTGOING_QUEUE_CAPACITY: usize = 256;
+
+/// Bytes of a binary frame shown when printing it
+const PREVIEW_LEN: usize = 16;
+
+/// Prefix of the frames `/ping` sends; a test client answers them in kind
+const PING_PREFIX: &[u8] = b"/ping ";
+const PONG_PREFIX: &[u8] = b"/pong ";
 
 #[derive(Parser)]
 #[command(name = "test-client")]
@@ -12,12 +32,74 @@ use std::time::Duration;
 struct Args {
     #[arg(long, default_value = "127.0.0.1:8080")]
     relay_server: String,
-    
+
     #[arg(long, default_value = "client")]
     name: String,
+
+    #[arg(long, default_value = "public")]
+    session: String,
+
+    /// Auth token to join with, for relays that require one
+    #[arg(long, env = "RELAY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Send lines typed on stdin instead of the canned test messages
+    #[arg(long)]
+    interactive: bool,
+
+    /// Pair two connections through the relay, check every frame arrives
+    /// intact both ways, print a JSON summary and exit non-zero on failure
+    #[arg(long, conflicts_with = "interactive")]
+    self_test: bool,
+
+    /// Frames sent each way (and round trips timed) by the self-test
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    count: u32,
+
+    /// Payload size of each self-test frame in bytes
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    size: u32,
+
+    /// Seconds each self-test phase may take before it counts as failed
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+}
+
+/// What a line typed in interactive mode asks for
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Send(Vec<u8>),
+    /// `count` frames of `size` bytes each, as fast as the relay takes them
+    Spam { count: usize, size: usize },
+    Ping,
+    Quit,
+}
+
+impl Command {
+    /// Parse a line of input; anything that isn't a slash-command is sent as is
+    fn parse(line: &str) -> Result<Self, String> {
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(Command::Send(line.as_bytes().to_vec()));
+        };
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("quit") => Ok(Command::Quit),
+            Some("ping") => Ok(Command::Ping),
+            Some("spam") => {
+                let usage = || "usage: /spam N SIZE".to_string();
+                let count = words.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?;
+                let size = words.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?;
+                if words.next().is_some() || size > u32::MAX as usize {
+                    return Err(usage());
+                }
+                Ok(Command::Spam { count, size })
+            }
+            _ => Err(format!("unknown command /{} (try /quit, /spam N SIZE, /ping)", command)),
+        }
+    }
 }
 
-async fn send_message(stream: &mut TcpStream, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+async fn send_message<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let length = data.len() as u32;
     stream.write_u32(length).await?;
     stream.write_all(data).await?;
@@ -25,7 +107,7 @@ async fn send_message(stream: &mut TcpStream, data: &[u8]) -> Result<(), Box<dyn
     Ok(())
 }
 
-async fn read_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
     let length = match stream.read_u32().await {
         Ok(len) => len as usize,
         Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
@@ -37,73 +119,517 @@ async fn read_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Box<dyn
     Ok(Some(buffer))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    
-    println!("Connecting to relay server at {}", args.relay_server);
-    let mut stream = TcpStream::connect(&args.relay_server).await?;
-    println!("Connected successfully!");
-    
-    // Send test messages
-    let test_messages = vec![
-        format!("Hello from {}!", args.name),
-        format!("{} is testing the relay", args.name),
-        format!("Encrypted message from {}", args.name),
-        format!("Final test message from {}", args.name),
-    ];
-    
-    let mut receive_task = {
-        let mut read_stream = stream.try_clone()?;
-        tokio::spawn(async move {
-            loop {
-                match read_message(&mut read_stream).await {
-                    Ok(Some(data)) => {
-                        if let Ok(message) = String::from_utf8(data) {
-                            println!("📨 Received: {}", message);
-                        } else {
-                            println!("📨 Received {} bytes of binary data", data.len());
-                        }
-                    }
-                    Ok(None) => {
-                        println!("🔌 Connection closed by server");
-                        break;
+/// Name the rejection the relay sent instead of a hello, if this is one
+fn describe_rejection(data: &[u8]) -> Option<String> {
+    match data {
+        [REJECT_MARKER, code, rest @ ..] => {
+            let reason = RejectReason::from_code(*code)?;
+            match (reason, rest) {
+                (RejectReason::UnsupportedVersion, [min, max]) => {
+                    Some(format!("{} (relay speaks {}-{})", reason, min, max))
+                }
+                (_, []) => Some(reason.to_string()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Name a control frame from the relay worth showing, if this is one
+fn describe_notice(frame: &Frame) -> Option<String> {
+    match frame {
+        Frame::Goodbye(Some(reason)) => Some(format!("closing the connection: {}", reason)),
+        Frame::Goodbye(None) => Some("closing the connection".to_string()),
+        Frame::Oversized(limit) => {
+            Some(format!("{} (limit {} bytes)", RejectReason::FrameTooLarge, limit))
+        }
+        Frame::Data(_) | Frame::Ping | Frame::Pong => None,
+    }
+}
+
+/// One line describing a received frame, stamped with the time since connecting
+fn format_received(elapsed: Duration, frame: &Frame) -> String {
+    let stamp = format!("[{:>8.3}s]", elapsed.as_secs_f64());
+    if let Some(notice) = describe_notice(frame) {
+        return format!("{} ⚠️  Relay notice: {}", stamp, notice);
+    }
+    let data = match frame {
+        Frame::Data(data) => *data,
+        _ => return format!("{} {:?}", stamp, frame),
+    };
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.chars().any(char::is_control) => {
+            format!("{} 📨 {} bytes: {}", stamp, data.len(), text)
+        }
+        _ => {
+            let preview: Vec<String> = data.iter().take(PREVIEW_LEN).map(|b| format!("{:02x}", b)).collect();
+            let more = if data.len() > PREVIEW_LEN { " …" } else { "" };
+            format!("{} 📨 {} bytes of binary data: {}{}", stamp, data.len(), preview.join(" "), more)
+        }
+    }
+}
+
+/// A ping frame carrying the time it was sent, in microseconds since `epoch`
+fn ping_frame(epoch: Instant) -> Vec<u8> {
+    let mut frame = PING_PREFIX.to_vec();
+    frame.extend_from_slice(epoch.elapsed().as_micros().to_string().as_bytes());
+    frame
+}
+
+/// Round trip of a pong answering one of our own pings
+fn pong_round_trip(data: &[u8], epoch: Instant) -> Option<Duration> {
+    let sent: u64 = std::str::from_utf8(data.strip_prefix(PONG_PREFIX)?).ok()?.parse().ok()?;
+    Some(epoch.elapsed().saturating_sub(Duration::from_micros(sent)))
+}
+
+/// Join the session and wait for the relay hello
+async fn join_session(
+    stream: &mut TcpStream,
+    session: &str,
+    token: Option<&str>,
+) -> Result<ServerHello, Box<dyn std::error::Error + Send + Sync>> {
+    let join = ClientJoin {
+        version: PROTOCOL_VERSION,
+        session_code: session.to_string(),
+        max_message_size: None,
+        token: token.map(str::to_string),
+    };
+    send_message(stream, &join.encode()).await?;
+    match read_message(stream).await? {
+        Some(frame) => match describe_rejection(&frame) {
+            Some(notice) => Err(format!("Relay refused to join: {}", notice).into()),
+            None => Ok(ServerHello::decode(&frame)?),
+        },
+        None => Err("Relay closed the connection before sending a hello".into()),
+    }
+}
+
+/// Print incoming frames, answering relay pings and peer pings
+async fn receive_frames<R: AsyncRead + Unpin>(mut reader: R, outgoing: mpsc::Sender<Vec<u8>>, epoch: Instant) {
+    loop {
+        match read_message(&mut reader).await {
+            Ok(Some(data)) => match Frame::decode(&data) {
+                Ok(Frame::Ping) => {
+                    // Relay keepalive ping; answering keeps the read timeout at bay
+                    let _ = outgoing.send(Frame::Pong.encode()).await;
+                }
+                Ok(Frame::Pong) => {}
+                Ok(Frame::Data(data)) => {
+                    if let Some(round_trip) = pong_round_trip(data, epoch) {
+                        println!("🏓 Pong from peer, round trip {:.1} ms", round_trip.as_secs_f64() * 1000.0);
+                        continue;
                     }
-                    Err(e) => {
-                        println!("❌ Read error: {}", e);
-                        break;
+                    println!("{}", format_received(epoch.elapsed(), &Frame::Data(data)));
+                    if let Some(sent) = data.strip_prefix(PING_PREFIX) {
+                        let mut pong = PONG_PREFIX.to_vec();
+                        pong.extend_from_slice(sent);
+                        let _ = outgoing.send(Frame::Data(&pong).encode()).await;
                     }
                 }
+                Ok(frame) => println!("{}", format_received(epoch.elapsed(), &frame)),
+                Err(e) => println!("❌ {} from relay ({} bytes)", e, data.len()),
+            },
+            Ok(None) => {
+                println!("🔌 Connection closed by server");
+                break;
             }
-        })
-    };
-    
-    // Send test messages with delays
+            Err(e) => {
+                println!("❌ Read error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Send the canned test messages a couple of seconds apart
+async fn send_test_messages(name: &str, outgoing: &mpsc::Sender<Vec<u8>>) {
+    let test_messages = [
+        format!("Hello from {}!", name),
+        format!("{} is testing the relay", name),
+        format!("Encrypted message from {}", name),
+        format!("Final test message from {}", name),
+    ];
+
     for (i, message) in test_messages.iter().enumerate() {
         tokio::time::sleep(Duration::from_secs(2)).await;
-        
+
         println!("📤 Sending: {}", message);
-        if let Err(e) = send_message(&mut stream, message.as_bytes()).await {
-            println!("❌ Send error: {}", e);
-            break;
+        if outgoing.send(Frame::Data(message.as_bytes()).encode()).await.is_err() {
+            println!("❌ Send error: connection closed");
+            return;
         }
-        
+
         if i == 0 {
             println!("💡 If you have another test client running, you should see messages being relayed");
         }
     }
-    
-    // Keep connection alive to receive messages
+
     println!("⏳ Waiting for messages (press Ctrl+C to exit)...");
-    
+}
+
+/// Send each line typed on stdin, acting on slash-commands, until EOF or /quit
+async fn run_interactive(outgoing: &mpsc::Sender<Vec<u8>>, epoch: Instant) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("⌨️  Type a message and press Enter to send it; /quit, /spam N SIZE and /ping are commands");
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let frames = match Command::parse(&line) {
+            Ok(Command::Quit) => break,
+            Ok(Command::Send(data)) if data.is_empty() => continue,
+            Ok(Command::Send(data)) => vec![data],
+            Ok(Command::Ping) => vec![ping_frame(epoch)],
+            Ok(Command::Spam { count, size }) => {
+                println!("📤 Sending {} frames of {} bytes", count, size);
+                (0..count).map(|i| vec![(i % 251) as u8 + 1; size]).collect()
+            }
+            Err(e) => {
+                println!("❓ {}", e);
+                continue;
+            }
+        };
+        for frame in frames {
+            if outgoing.send(Frame::Data(&frame).encode()).await.is_err() {
+                return Err("connection closed".into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of a self-test, printed as a single JSON object
+#[derive(Debug, Default, Serialize)]
+struct SelfTestSummary {
+    ok: bool,
+    relay: String,
+    session: String,
+    count: u32,
+    size: u32,
+    a_to_b: Transfer,
+    b_to_a: Transfer,
+    round_trip_ms: Option<RoundTrips>,
+    error: Option<String>,
+}
+
+/// Frames sent one way and how many of them came through intact
+#[derive(Debug, Default, Serialize)]
+struct Transfer {
+    sent: u32,
+    received: u32,
+    mismatched: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct RoundTrips {
+    min: f64,
+    avg: f64,
+    max: f64,
+}
+
+impl RoundTrips {
+    fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let millis: Vec<f64> = samples.iter().map(|rtt| rtt.as_secs_f64() * 1000.0).collect();
+        if millis.is_empty() {
+            return None;
+        }
+        Some(Self {
+            min: millis.iter().copied().fold(f64::INFINITY, f64::min),
+            avg: millis.iter().sum::<f64>() / millis.len() as f64,
+            max: millis.iter().copied().fold(0.0, f64::max),
+        })
+    }
+}
+
+/// Payload of self-test frame `index`; the pattern differs per frame so a
+/// dropped, duplicated or reordered frame shows up as a mismatch
+fn self_test_frame(index: u32, size: u32) -> Vec<u8> {
+    (0..size).map(|offset| (index.wrapping_mul(31).wrapping_add(offset) % 251) as u8 + 1).collect()
+}
+
+async fn connect(addr: &str) -> Result<TcpStream, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = TcpStream::connect(addr).await?;
+    // Length prefix and payload go out as separate writes; don't let Nagle hold them
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Payload of the next data frame, skipping relay pings; relay notices and
+/// a closed connection are errors
+async fn read_data(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let Some(data) = read_message(stream).await? else {
+            return Err("relay closed the connection".into());
+        };
+        let frame = Frame::decode(&data)?;
+        match describe_notice(&frame) {
+            Some(notice) => return Err(format!("relay notice: {}", notice).into()),
+            None => if let Frame::Data(payload) = frame {
+                return Ok(payload.to_vec());
+            },
+        }
+    }
+}
+
+/// Send a payload as a data frame
+async fn send_data<S: AsyncWrite + Unpin>(stream: &mut S, payload: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    send_message(stream, &Frame::Data(payload).encode()).await
+}
+
+/// Send `count` frames one way while reading them on the other end
+async fn transfer(
+    from: &mut TcpStream,
+    to: &mut TcpStream,
+    count: u32,
+    size: u32,
+    result: &mut Transfer,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sent = &mut result.sent;
+    let sending = async {
+        for index in 0..count {
+            send_data(from, &self_test_frame(index, size)).await?;
+            *sent += 1;
+        }
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+    };
+    let (received, mismatched) = (&mut result.received, &mut result.mismatched);
+    let receiving = async {
+        for index in 0..count {
+            let data = read_data(to).await?;
+            *received += 1;
+            if data != self_test_frame(index, size) {
+                *mismatched += 1;
+            }
+        }
+        Ok(())
+    };
+    tokio::try_join!(sending, receiving)?;
+    Ok(())
+}
+
+/// Bounce frames off the second connection and time each round trip
+async fn round_trips(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    count: u32,
+) -> Result<Vec<Duration>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut samples = Vec::new();
+    for index in 0..count {
+        let probe = index.to_be_bytes();
+        let started = Instant::now();
+        send_data(a, &probe).await?;
+        let echoed = read_data(b).await?;
+        send_data(b, &echoed).await?;
+        if read_data(a).await? != probe {
+            return Err(format!("round trip {} came back altered", index).into());
+        }
+        samples.push(started.elapsed());
+    }
+    Ok(samples)
+}
+
+/// Run one self-test phase under the per-phase timeout
+async fn phase<T>(
+    name: &str,
+    limit: Duration,
+    work: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    match tokio::time::timeout(limit, work).await {
+        Ok(result) => result.map_err(|e| format!("{}: {}", name, e).into()),
+        Err(_) => Err(format!("{}: timed out after {:?}", name, limit).into()),
+    }
+}
+
+async fn run_self_test(args: &Args, summary: &mut SelfTestSummary) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let limit = Duration::from_secs(args.timeout);
+
+    let (mut a, mut b) = phase("join", limit, async {
+        let mut a = connect(&args.relay_server).await?;
+        join_session(&mut a, &summary.session, args.token.as_deref()).await?;
+        let mut b = connect(&args.relay_server).await?;
+        let hello = join_session(&mut b, &summary.session, args.token.as_deref()).await?;
+        if !hello.peer_present {
+            return Err("relay did not pair the two connections".into());
+        }
+        if args.size > hello.max_message_size {
+            return Err(format!("--size {} is over the relay's limit of {} bytes",
+                               args.size, hello.max_message_size).into());
+        }
+        Ok((a, b))
+    }).await?;
+    println!("🤝 Both connections joined session '{}'", summary.session);
+
+    phase("a_to_b", limit, transfer(&mut a, &mut b, args.count, args.size, &mut summary.a_to_b)).await?;
+    println!("📤 A → B: {}/{} frames, {} mismatched", summary.a_to_b.received, args.count, summary.a_to_b.mismatched);
+    phase("b_to_a", limit, transfer(&mut b, &mut a, args.count, args.size, &mut summary.b_to_a)).await?;
+    println!("📥 B → A: {}/{} frames, {} mismatched", summary.b_to_a.received, args.count, summary.b_to_a.mismatched);
+
+    let samples = phase("round_trip", limit, round_trips(&mut a, &mut b, args.count)).await?;
+    summary.round_trip_ms = RoundTrips::from_samples(&samples);
+    if let Some(rtt) = &summary.round_trip_ms {
+        println!("🏓 Round trip: min {:.2} ms, avg {:.2} ms, max {:.2} ms", rtt.min, rtt.avg, rtt.max);
+    }
+    Ok(())
+}
+
+/// Check the relay end to end with two connections of our own
+async fn self_test(args: &Args) -> SelfTestSummary {
+    let mut summary = SelfTestSummary {
+        relay: args.relay_server.clone(),
+        session: format!("selftest-{}", uuid::Uuid::new_v4().simple()),
+        count: args.count,
+        size: args.size,
+        ..Default::default()
+    };
+
+    if let Err(e) = run_self_test(args, &mut summary).await {
+        summary.error = Some(e.to_string());
+    }
+    let mismatched = summary.a_to_b.mismatched + summary.b_to_a.mismatched;
+    if summary.error.is_none() && mismatched > 0 {
+        summary.error = Some(format!("{} frames arrived altered or out of order", mismatched));
+    }
+    summary.ok = summary.error.is_none();
+    summary
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+
+    if args.self_test {
+        println!("Self-testing relay server at {}", args.relay_server);
+        let summary = self_test(&args).await;
+        match &summary.error {
+            None => println!("✅ Self-test passed"),
+            Some(e) => println!("❌ Self-test failed: {}", e),
+        }
+        println!("{}", serde_json::to_string(&summary)?);
+        if !summary.ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    println!("Connecting to relay server at {}", args.relay_server);
+    let mut stream = connect(&args.relay_server).await?;
+    println!("Connected successfully!");
+
+    let hello = join_session(&mut stream, &args.session, args.token.as_deref()).await?;
+    println!("🤝 Joined session '{}' as {} (peer present: {}, max message size: {} bytes)",
+             args.session, hello.client_id, hello.peer_present, hello.max_message_size);
+    let epoch = Instant::now();
+
+    // One task owns each half: the writer drains a queue fed by the input
+    // side and by replies from the reader
+    let (reader, mut writer) = stream.into_split();
+    let (outgoing, mut queued) = mpsc::channel::<Vec<u8>>(OUTGOING_QUEUE_CAPACITY);
+    let mut send_task = tokio::spawn(async move {
+        while let Some(frame) = queued.recv().await {
+            if let Err(e) = send_message(&mut writer, &frame).await {
+                println!("❌ Send error: {}", e);
+                break;
+            }
+        }
+    });
+    let mut receive_task = tokio::spawn(receive_frames(reader, outgoing.clone(), epoch));
+
+    let input = async {
+        if args.interactive {
+            run_interactive(&outgoing, epoch).await
+        } else {
+            send_test_messages(&args.name, &outgoing).await;
+            std::future::pending().await
+        }
+    };
+
     tokio::select! {
+        result = input => {
+            if let Err(e) = result {
+                println!("❌ {}", e);
+            }
+            println!("👋 Leaving session");
+        }
         _ = tokio::signal::ctrl_c() => {
             println!("🛑 Shutting down...");
         }
         _ = &mut receive_task => {
             println!("🔌 Receive task completed");
         }
+        _ = &mut send_task => {
+            println!("🔌 Send task completed");
+        }
     }
-    
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_parse() {
+        assert_eq!(Command::parse("hello"), Ok(Command::Send(b"hello".to_vec())));
+        assert_eq!(Command::parse("/quit"), Ok(Command::Quit));
+        assert_eq!(Command::parse("/ping"), Ok(Command::Ping));
+        assert_eq!(Command::parse("/spam 10 512"), Ok(Command::Spam { count: 10, size: 512 }));
+        assert!(Command::parse("/spam 10").is_err());
+        assert!(Command::parse("/spam ten 512").is_err());
+        assert!(Command::parse("/nope").is_err());
+    }
+
+    #[test]
+    fn test_received_frames_are_formatted() {
+        let elapsed = Duration::from_millis(1500);
+        assert_eq!(format_received(elapsed, &Frame::Data(b"hi there")), "[   1.500s] 📨 8 bytes: hi there");
+
+        let binary = format_received(elapsed, &Frame::Data(&[0xde, 0xad, 0xbe, 0xef]));
+        assert!(binary.ends_with("4 bytes of binary data: de ad be ef"), "{}", binary);
+        let long = format_received(elapsed, &Frame::Data(&[0xff; 40]));
+        assert!(long.contains("40 bytes") && long.ends_with(" …"), "{}", long);
+
+        let notice = format_received(elapsed, &Frame::Oversized(1024));
+        assert!(notice.contains("frame too large (limit 1024 bytes)"), "{}", notice);
+        let goodbye = format_received(elapsed, &Frame::Goodbye(Some(RejectReason::Draining)));
+        assert!(goodbye.ends_with("Relay notice: closing the connection: relay is draining"), "{}", goodbye);
+
+        let refused = protocol::encode_rejection(RejectReason::UnsupportedVersion);
+        assert_eq!(describe_rejection(&refused).unwrap(), "unsupported protocol version (relay speaks 3-3)");
+    }
+
+    #[test]
+    fn test_self_test_frames_differ_per_index() {
+        let first = self_test_frame(0, 300);
+        assert_eq!(first.len(), 300);
+        assert_ne!(first, self_test_frame(1, 300));
+        assert_eq!(first, self_test_frame(0, 300));
+    }
+
+    #[test]
+    fn test_self_test_summary_is_json() {
+        let samples = [Duration::from_millis(2), Duration::from_millis(4)];
+        let summary = SelfTestSummary {
+            ok: true,
+            count: 2,
+            round_trip_ms: RoundTrips::from_samples(&samples),
+            ..Default::default()
+        };
+        let json: serde_json::Value = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["ok"], true);
+        assert_eq!(json["a_to_b"]["mismatched"], 0);
+        assert_eq!(json["round_trip_ms"]["avg"], 3.0);
+        assert!(json["error"].is_null());
+        assert!(RoundTrips::from_samples(&[]).is_none());
+    }
+
+    #[test]
+    fn test_pong_measures_round_trip() {
+        let epoch = Instant::now() - Duration::from_secs(1);
+        let ping = ping_frame(epoch);
+        let mut pong = PONG_PREFIX.to_vec();
+        pong.extend_from_slice(ping.strip_prefix(PING_PREFIX).unwrap());
+        assert!(pong_round_trip(&pong, epoch).unwrap() < Duration::from_secs(1));
+        assert_eq!(pong_round_trip(b"/pong soon", epoch), None);
+        assert_eq!(pong_round_trip(&ping, epoch), None);
+    }
+}
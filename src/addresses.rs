@@ -0,0 +1,194 @@
+// Addresses the desktop app could be reached at, for sharing with a peer
+//
+// Candidates come from the machine's own interfaces, from the router when
+// it maps the listening port, and from a relay telling us the address it
+// sees us at, which behind NAT is otherwise the only public one we can
+// learn. Every candidate but the mapped one carries the listening port; for
+// a relay-observed address that needs the port forwarded on the NAT.
+
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// Where a candidate address comes from, in the order they're listed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressKind {
+    /// An interface address on a private network
+    Lan,
+    /// An interface address routable on the internet
+    Public,
+    /// The router's external address, forwarding to us; see `portmap`
+    PortMapped,
+    /// Our address as a relay sees it
+    RelayObserved,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LocalAddress {
+    pub kind: AddressKind,
+    /// What a peer would connect to, like `192.168.1.20:8080` or
+    /// `[2001:db8::1]:8080`
+    pub address: String,
+    /// The interface the address is on, or the relay that saw it
+    pub source: String,
+}
+
+/// The `get_local_addresses` answer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LocalAddresses {
+    pub port: u16,
+    /// Private network addresses first, then public, port mapped and
+    /// relay-observed
+    pub addresses: Vec<LocalAddress>,
+}
+
+impl LocalAddresses {
+    /// Candidates for `port` on `interfaces`, given as name and address;
+    /// loopback addresses and those only usable with a scope, like IPv6
+    /// link-local ones, are left out
+    pub fn new(port: u16, interfaces: impl IntoIterator<Item = (String, IpAddr)>) -> Self {
+        let mut addresses = LocalAddresses { port, addresses: Vec::new() };
+        for (name, ip) in interfaces {
+            if let Some(kind) = classify(ip) {
+                addresses.add(kind, SocketAddr::new(ip, port), name);
+            }
+        }
+        addresses
+    }
+
+    /// Add the external address the router forwards to us, found through
+    /// `protocol`, unless an interface has it already
+    pub fn add_mapped(&mut self, protocol: String, external: SocketAddr) {
+        self.add(AddressKind::PortMapped, external, protocol);
+    }
+
+    /// Add the address `relay` sees us at, unless an interface has it already
+    pub fn add_observed(&mut self, relay: String, ip: IpAddr) {
+        self.add(AddressKind::RelayObserved, SocketAddr::new(ip, self.port), relay);
+    }
+
+    fn add(&mut self, kind: AddressKind, address: SocketAddr, source: String) {
+        let address = address.to_string();
+        if self.addresses.iter().any(|known| known.address == address) {
+            return;
+        }
+        // Stable, so each kind keeps the order it was found in
+        let at = self.addresses.partition_point(|known| known.kind <= kind);
+        self.addresses.insert(at, LocalAddress { kind, address, source });
+    }
+}
+
+/// The machine's interface addresses, by interface name; none when they
+/// can't be listed
+pub fn interfaces() -> Vec<(String, IpAddr)> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces.into_iter().map(|interface| (interface.name.clone(), interface.ip())).collect(),
+        Err(e) => {
+            tracing::warn!(error = %e, "Couldn't list network interfaces");
+            Vec::new()
+        }
+    }
+}
+
+/// Whether a peer could use `ip` and from where; `None` for addresses that
+/// only reach this machine or need an interface scope
+fn classify(ip: IpAddr) -> Option<AddressKind> {
+    if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+        return None;
+    }
+    match ip {
+        IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() => Some(AddressKind::Lan),
+        // Unique local, fc00::/7
+        IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 => Some(AddressKind::Lan),
+        // Link-local, fe80::/10
+        IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => None,
+        _ => Some(AddressKind::Public),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, ip: &str) -> (String, IpAddr) {
+        (name.to_string(), ip.parse().unwrap())
+    }
+
+    fn addresses(found: &LocalAddresses) -> Vec<(AddressKind, &str)> {
+        found.addresses.iter().map(|address| (address.kind, address.address.as_str())).collect()
+    }
+
+    #[test]
+    fn test_interface_addresses_are_labeled_and_formatted() {
+        let found = LocalAddresses::new(
+            8080,
+            [
+                interface("lo", "127.0.0.1"),
+                interface("lo", "::1"),
+                interface("eth0", "198.51.100.4"),
+                interface("eth0", "fe80::1c2a:3bff:fe4d:5e6f"),
+                interface("eth0", "2001:db8::20"),
+                interface("wlan0", "192.168.1.20"),
+                interface("wlan0", "fd12:3456::20"),
+            ],
+        );
+        assert_eq!(
+            addresses(&found),
+            [
+                (AddressKind::Lan, "192.168.1.20:8080"),
+                (AddressKind::Lan, "[fd12:3456::20]:8080"),
+                (AddressKind::Public, "198.51.100.4:8080"),
+                (AddressKind::Public, "[2001:db8::20]:8080"),
+            ]
+        );
+        assert_eq!(found.addresses[0].source, "wlan0");
+    }
+
+    #[test]
+    fn test_observed_address_comes_last_with_our_port() {
+        let mut found = LocalAddresses::new(7642, [interface("eth0", "10.0.0.5")]);
+        found.add_observed("relay.example.org:8080".to_string(), "203.0.113.9".parse().unwrap());
+        assert_eq!(
+            addresses(&found),
+            [(AddressKind::Lan, "10.0.0.5:7642"), (AddressKind::RelayObserved, "203.0.113.9:7642")]
+        );
+        assert_eq!(found.addresses[1].source, "relay.example.org:8080");
+
+        // Without NAT the relay sees an address we already have
+        let mut found = LocalAddresses::new(7642, [interface("eth0", "198.51.100.4")]);
+        found.add_observed("relay.example.org:8080".to_string(), "198.51.100.4".parse().unwrap());
+        assert_eq!(addresses(&found), [(AddressKind::Public, "198.51.100.4:7642")]);
+    }
+
+    #[test]
+    fn test_mapped_address_keeps_its_port() {
+        let mut found = LocalAddresses::new(7642, [interface("eth0", "10.0.0.5")]);
+        found.add_observed("relay:8080".to_string(), "203.0.113.9".parse().unwrap());
+        found.add_mapped("UPnP".to_string(), "203.0.113.9:7643".parse().unwrap());
+        assert_eq!(
+            addresses(&found),
+            [
+                (AddressKind::Lan, "10.0.0.5:7642"),
+                (AddressKind::PortMapped, "203.0.113.9:7643"),
+                (AddressKind::RelayObserved, "203.0.113.9:7642"),
+            ]
+        );
+        assert_eq!(found.addresses[1].source, "UPnP");
+    }
+
+    #[test]
+    fn test_payload() {
+        let mut found = LocalAddresses::new(8080, [interface("eth0", "192.168.1.20")]);
+        found.add_observed("relay:8080".to_string(), "203.0.113.9".parse().unwrap());
+        assert_eq!(
+            serde_json::to_value(&found).unwrap(),
+            serde_json::json!({
+                "port": 8080,
+                "addresses": [
+                    { "kind": "lan", "address": "192.168.1.20:8080", "source": "eth0" },
+                    { "kind": "relay_observed", "address": "203.0.113.9:8080", "source": "relay:8080" },
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,1662 @@
+// Application configuration, loaded from a TOML file
+//
+// Every key is optional: whatever the file leaves out keeps its default, so
+// a config file only needs the settings it changes. Unknown keys are an
+// error rather than ignored, so a misspelt setting doesn't silently do
+// nothing.
+//
+// SILENCE_* environment variables override the file in turn, so deployments
+// and CI can change a setting without writing one: env > file > defaults.
+// Neither checks that the values make sense together; `validate` does, and
+// the crypto engine and connection manager run it before using a config.
+//
+// Code building a config itself goes through `Config::builder()`: the struct
+// is non-exhaustive, so new settings don't break it.
+//
+// A profile is a named bundle of the security-relevant settings, for users
+// who'd rather pick "paranoid" than a rotation interval. The profile fills in
+// its settings first and anything set explicitly, in the file or through the
+// builder, still wins.
+//
+// The app itself starts through `load_or_init`, which writes a commented
+// file of defaults on first run and falls back to the defaults, rather than
+// failing, when the file is broken.
+//
+// `save` writes a config back as TOML, atomically and, on unix, readable by
+// the owner alone, since settings such as relay tokens are secrets.
+
+use crate::{ConnectionMode, ParseConnectionModeError};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Application configuration
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// Profile the settings started from; see `Profile`
+    pub profile: Profile,
+    pub listen_port: u16,
+    #[serde(with = "duration")]
+    pub key_rotation_interval: Duration,
+    pub max_message_size: usize,
+    #[serde(with = "duration")]
+    pub connection_timeout: Duration,
+    pub relay_servers: Vec<RelayEntry>,
+    /// Used when a connect request doesn't ask for a mode
+    pub connection_mode: ConnectionMode,
+    /// Turn off Nagle's algorithm on peer and relay sockets
+    pub tcp_nodelay: bool,
+    /// Chat messages the app keeps in memory, oldest dropped first; 0 keeps none
+    pub message_history: usize,
+    /// Ask the router, through UPnP or NAT-PMP, to forward `listen_port` on
+    /// listening
+    pub port_mapping: bool,
+    /// Largest file, in bytes, the app sends or takes from a peer
+    pub max_file_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        // The balanced profile is the defaults
+        let balanced = ProfileSettings::BALANCED;
+        Self {
+            profile: Profile::Balanced,
+            listen_port: 7642,
+            key_rotation_interval: balanced.key_rotation_interval,
+            max_message_size: balanced.max_message_size,
+            connection_timeout: balanced.connection_timeout,
+            relay_servers: vec![
+                RelayEntry::new("185.191.116.220:8080"),
+            ],
+            connection_mode: balanced.connection_mode,
+            tcp_nodelay: balanced.tcp_nodelay,
+            message_history: 1000,
+            port_mapping: false,
+            max_file_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and change what's needed
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// The defaults with `profile`'s settings
+    pub fn profile(profile: Profile) -> Self {
+        Self::builder().profile(profile).config
+    }
+
+    /// Profile settings this config has changed from its profile's values
+    pub fn profile_overrides(&self) -> Vec<&'static str> {
+        let profile = Self::profile(self.profile);
+        self.changed_fields(&profile)
+            .into_iter()
+            .filter(|field| PROFILE_FIELDS.contains(field))
+            .collect()
+    }
+
+    /// Read a config file, with defaults for anything it leaves out
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&text, path)
+    }
+
+    /// Like `from_file`, but a missing file just means the defaults
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        match Self::from_file(path) {
+            Err(ConfigError::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            result => result,
+        }
+    }
+
+    /// Parse config file contents; `path` is only used in error messages
+    pub fn from_toml(text: &str, path: &Path) -> Result<Self, ConfigError> {
+        let parse_error = |e: toml::de::Error| {
+            let offset = e.span().map(|span| span.start);
+            ConfigError::Parse {
+                path: path.to_path_buf(),
+                line: offset.map(|offset| line_number(text, offset)),
+                key: offset.and_then(|offset| offending_key(text, offset)).map(str::to_string),
+                message: e.message().to_string(),
+            }
+        };
+        let mut config: Self = toml::from_str(text).map_err(parse_error)?;
+
+        // Settings the file leaves out come from its profile, not the defaults
+        let keys: toml::Table = text.parse().map_err(parse_error)?;
+        config.profile.settings().apply(&mut config, |key| keys.contains_key(key));
+        Ok(config)
+    }
+
+    /// Write the config as TOML, replacing the file in one step so a crash
+    /// midway leaves the old one intact
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        let text = toml::to_string(self).map_err(|e| ConfigError::Write {
+            path: path.to_path_buf(),
+            source: std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        })?;
+        write_atomically(path, &text)
+    }
+
+    /// Load the app's config file from `default_path`, writing a commented
+    /// one with the defaults on first run. A file that fails to load or
+    /// validate is logged and left alone, and the defaults are used instead,
+    /// so a bad edit never keeps the app from starting.
+    pub fn load_or_init() -> LoadedConfig {
+        match Self::default_path() {
+            Some(path) => Self::load_or_init_at(path),
+            None => LoadedConfig { config: Self::default(), path: None, created: false, errors: Vec::new() },
+        }
+    }
+
+    /// `load_or_init` with the file at `path`
+    pub fn load_or_init_at(path: impl Into<PathBuf>) -> LoadedConfig {
+        let path = path.into();
+        let mut loaded = LoadedConfig { config: Self::default(), path: None, created: false, errors: Vec::new() };
+        match Self::from_file(&path) {
+            Ok(config) => match config.validate() {
+                Ok(()) => loaded.config = config,
+                Err(errors) => loaded.errors = errors,
+            },
+            Err(ConfigError::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => {
+                match write_atomically(&path, DEFAULT_CONFIG_FILE) {
+                    Ok(()) => loaded.created = true,
+                    Err(e) => loaded.errors.push(e),
+                }
+            }
+            Err(e) => loaded.errors.push(e),
+        }
+        for error in &loaded.errors {
+            tracing::warn!(%error, "Using default settings");
+        }
+        loaded.path = Some(path);
+        loaded
+    }
+
+    /// Defaults overridden by whatever SILENCE_* variables are set
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::default().apply_env_overrides()
+    }
+
+    /// Override fields from the environment:
+    ///
+    /// - `SILENCE_LISTEN_PORT`: port number
+    /// - `SILENCE_KEY_ROTATION_INTERVAL`: duration, as seconds or with units
+    ///   like the config file (`90s`, `2m`, `1h 30m`)
+    /// - `SILENCE_MAX_MESSAGE_SIZE`: bytes
+    /// - `SILENCE_CONNECTION_TIMEOUT`: duration, as above
+    /// - `SILENCE_RELAY_SERVERS`: comma separated `host:port` list, each
+    ///   with default options; empty entries are skipped, so an empty value
+    ///   means no relays
+    /// - `SILENCE_CONNECTION_MODE`: `auto`, `direct_only` or `relay_only`,
+    ///   spelled any way `ConnectionMode`'s `FromStr` accepts
+    /// - `SILENCE_TCP_NODELAY`: `true` or `false`
+    /// - `SILENCE_MESSAGE_HISTORY`: number of messages
+    /// - `SILENCE_PORT_MAPPING`: `true` or `false`
+    /// - `SILENCE_MAX_FILE_SIZE`: bytes
+    pub fn apply_env_overrides(mut self) -> Result<Self, ConfigError> {
+        env_override(ENV_LISTEN_PORT, &mut self.listen_port, |v| v.parse().map_err(|e| format!("not a port number: {}", e)))?;
+        env_override(ENV_KEY_ROTATION_INTERVAL, &mut self.key_rotation_interval, parse_duration)?;
+        env_override(ENV_MAX_MESSAGE_SIZE, &mut self.max_message_size, |v| v.parse().map_err(|e| format!("not a size in bytes: {}", e)))?;
+        env_override(ENV_CONNECTION_TIMEOUT, &mut self.connection_timeout, parse_duration)?;
+        env_override(ENV_RELAY_SERVERS, &mut self.relay_servers, |v| {
+            Ok(parse_list(v).into_iter().map(RelayEntry::new).collect())
+        })?;
+        env_override(ENV_CONNECTION_MODE, &mut self.connection_mode, |v| {
+            v.parse().map_err(|e: ParseConnectionModeError| e.to_string())
+        })?;
+        env_override(ENV_TCP_NODELAY, &mut self.tcp_nodelay, |v| v.parse().map_err(|_| "not true or false".to_string()))?;
+        env_override(ENV_MESSAGE_HISTORY, &mut self.message_history, |v| v.parse().map_err(|e| format!("not a number of messages: {}", e)))?;
+        env_override(ENV_PORT_MAPPING, &mut self.port_mapping, |v| v.parse().map_err(|_| "not true or false".to_string()))?;
+        env_override(ENV_MAX_FILE_SIZE, &mut self.max_file_size, |v| v.parse().map_err(|e| format!("not a size in bytes: {}", e)))?;
+        Ok(self)
+    }
+
+    /// Names of the fields whose values differ from `other`'s
+    pub fn changed_fields(&self, other: &Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                // Naming every field here keeps this list complete
+                let Config { $($field: _),* } = self;
+                $(
+                    if self.$field != other.$field {
+                        fields.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+        compare!(
+            profile,
+            listen_port,
+            key_rotation_interval,
+            max_message_size,
+            connection_timeout,
+            relay_servers,
+            connection_mode,
+            tcp_nodelay,
+            message_history,
+            port_mapping,
+            max_file_size
+        );
+        fields
+    }
+
+    /// Check values and how they fit together, reporting every problem
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut invalid = |key, message: String| errors.push(ConfigError::Invalid { key, message });
+
+        if self.listen_port == 0 {
+            invalid("listen_port", "must be a fixed port, not 0".to_string());
+        }
+        if self.key_rotation_interval < crate::MIN_ROTATION_INTERVAL {
+            invalid("key_rotation_interval", "must be at least 1 second".to_string());
+        }
+        if self.max_message_size <= crate::MESSAGE_OVERHEAD {
+            invalid(
+                "max_message_size",
+                format!(
+                    "must be over {} bytes, the space encryption and framing take up",
+                    crate::MESSAGE_OVERHEAD
+                ),
+            );
+        }
+        if self.connection_timeout.is_zero() {
+            invalid("connection_timeout", "must be at least 1 second".to_string());
+        }
+        if self.max_file_size == 0 {
+            invalid("max_file_size", "must be at least 1 byte".to_string());
+        }
+        for relay in &self.relay_servers {
+            if let Err(message) = check_host_port(&relay.addr) {
+                invalid("relay_servers", format!("{:?} {}", relay.addr, message));
+            }
+            if relay.token.as_deref() == Some("") {
+                invalid("relay_servers", format!("{:?} has an empty token", relay.addr));
+            }
+            if relay.connect_timeout.is_some_and(|timeout| timeout.is_zero()) {
+                invalid("relay_servers", format!("{:?} has a zero connect_timeout", relay.addr));
+            }
+        }
+        if self.relay_servers.is_empty() && self.connection_mode == ConnectionMode::RelayOnly {
+            invalid("relay_servers", "can't be empty while connection_mode is relay_only".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The app's directory under the platform's config directory
+    /// (`~/.config/silence` on Linux), if the platform has one
+    pub fn config_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("silence"))
+    }
+
+    /// Where the app looks for its config file: `config.toml` in `config_dir`
+    pub fn default_path() -> Option<PathBuf> {
+        Self::config_dir().map(|dir| dir.join("config.toml"))
+    }
+}
+
+/// What `Config::load_or_init` found
+#[derive(Debug)]
+pub struct LoadedConfig {
+    /// Settings to run with: the file's, or the defaults
+    pub config: Config,
+    /// The config file, or None when the platform has no config directory
+    pub path: Option<PathBuf>,
+    /// Whether this run wrote the file, as none was there yet
+    pub created: bool,
+    /// Why the file's settings aren't in use, empty when they are
+    pub errors: Vec<ConfigError>,
+}
+
+/// What `load_or_init` writes on first run: every setting at its default,
+/// commented out so that choosing a profile still changes them
+const DEFAULT_CONFIG_FILE: &str = r#"# Silence settings
+#
+# Every setting is optional; the commented-out values are the defaults.
+# SILENCE_<SETTING> environment variables, e.g. SILENCE_LISTEN_PORT,
+# override this file. Changes apply while the app runs.
+
+# Security profile: "paranoid", "balanced" or "performance". It sets the key
+# rotation interval, message size, connection timeout, connection mode and
+# tcp_nodelay below, and any of those set here win over it.
+profile = "balanced"
+
+# Port to listen on for direct connections
+# listen_port = 7642
+
+# How often to replace the encryption keys, e.g. "15s" or "2m"
+# key_rotation_interval = "15s"
+
+# Largest frame to send or accept, in bytes; message text gets this less
+# the framing and encryption overhead
+# max_message_size = 4096
+
+# How long to wait for a peer or relay to answer when connecting
+# connection_timeout = "30s"
+
+# Relays to fall back on, as "host:port" or with options:
+# { addr = "host:port", priority = 1, token = "...", connect_timeout = "5s" }
+# relay_servers = ["185.191.116.220:8080"]
+
+# Connection mode when none is picked: "auto", "direct_only" or "relay_only"
+# connection_mode = "auto"
+
+# Send small messages without waiting to batch them
+# tcp_nodelay = false
+
+# Chat messages kept in memory while the app runs, 0 for none
+# message_history = 1000
+
+# Ask the router to forward listen_port, through UPnP or NAT-PMP, so peers
+# outside can dial in
+# port_mapping = false
+
+# Largest file to send or accept from a peer, in bytes
+# max_file_size = 104857600
+"#;
+
+/// Named bundles of the security-relevant settings
+///
+/// | setting                 | paranoid    | balanced | performance |
+/// |-------------------------|-------------|----------|-------------|
+/// | `key_rotation_interval` | 5s          | 15s      | 60s         |
+/// | `connection_timeout`    | 10s         | 30s      | 30s         |
+/// | `max_message_size`      | 4096        | 4096     | 65536       |
+/// | `connection_mode`       | direct_only | auto     | auto        |
+/// | `tcp_nodelay`           | false       | false    | true        |
+///
+/// Paranoid keeps traffic off third-party relays and keys short-lived;
+/// performance trades rotation frequency for throughput and latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    Paranoid,
+    #[default]
+    Balanced,
+    Performance,
+}
+
+/// Fields a profile sets, in `Config` order
+const PROFILE_FIELDS: &[&str] = &[
+    "key_rotation_interval",
+    "max_message_size",
+    "connection_timeout",
+    "connection_mode",
+    "tcp_nodelay",
+];
+
+/// The settings one profile stands for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileSettings {
+    pub key_rotation_interval: Duration,
+    pub max_message_size: usize,
+    pub connection_timeout: Duration,
+    pub connection_mode: ConnectionMode,
+    pub tcp_nodelay: bool,
+}
+
+impl ProfileSettings {
+    pub const PARANOID: Self = Self {
+        key_rotation_interval: Duration::from_secs(5),
+        max_message_size: 4096,
+        connection_timeout: Duration::from_secs(10),
+        connection_mode: ConnectionMode::DirectOnly,
+        tcp_nodelay: false,
+    };
+
+    pub const BALANCED: Self = Self {
+        key_rotation_interval: Duration::from_secs(15),
+        max_message_size: 4096,
+        connection_timeout: Duration::from_secs(30),
+        connection_mode: ConnectionMode::Auto,
+        tcp_nodelay: false,
+    };
+
+    pub const PERFORMANCE: Self = Self {
+        key_rotation_interval: Duration::from_secs(60),
+        max_message_size: 64 * 1024,
+        connection_timeout: Duration::from_secs(30),
+        connection_mode: ConnectionMode::Auto,
+        tcp_nodelay: true,
+    };
+
+    /// Copy these settings into `config`, leaving the fields `keep` names
+    fn apply(&self, config: &mut Config, keep: impl Fn(&str) -> bool) {
+        if !keep("key_rotation_interval") {
+            config.key_rotation_interval = self.key_rotation_interval;
+        }
+        if !keep("max_message_size") {
+            config.max_message_size = self.max_message_size;
+        }
+        if !keep("connection_timeout") {
+            config.connection_timeout = self.connection_timeout;
+        }
+        if !keep("connection_mode") {
+            config.connection_mode = self.connection_mode;
+        }
+        if !keep("tcp_nodelay") {
+            config.tcp_nodelay = self.tcp_nodelay;
+        }
+    }
+}
+
+impl Profile {
+    pub fn settings(self) -> &'static ProfileSettings {
+        match self {
+            Self::Paranoid => &ProfileSettings::PARANOID,
+            Self::Balanced => &ProfileSettings::BALANCED,
+            Self::Performance => &ProfileSettings::PERFORMANCE,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Paranoid => "paranoid",
+            Self::Balanced => "balanced",
+            Self::Performance => "performance",
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A relay to connect through, and how
+///
+/// In a config file a relay is either just its address, the form older files
+/// use, or a table with options:
+///
+/// ```toml
+/// relay_servers = [
+///     "relay.example:8080",
+///     { addr = "backup.example:8443", priority = 1, token = "…", connect_timeout = "5s" },
+/// ]
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RelayEntry {
+    /// `host:port`
+    pub addr: String,
+    /// Relays are tried highest priority first, in list order among equals
+    pub priority: u8,
+    /// Auth token presented when joining, for relays that require one
+    pub token: Option<String>,
+    /// Whether the relay expects TLS; this client can't speak it yet, so
+    /// such relays are skipped
+    pub tls: bool,
+    /// Overrides `Config::connection_timeout` for this relay
+    pub connect_timeout: Option<Duration>,
+}
+
+impl RelayEntry {
+    /// A relay with default options
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            priority: 0,
+            token: None,
+            tls: false,
+            connect_timeout: None,
+        }
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether every option is at its default, so the bare address says it all
+    fn is_plain(&self) -> bool {
+        *self == Self::new(self.addr.clone())
+    }
+}
+
+impl From<&str> for RelayEntry {
+    fn from(addr: &str) -> Self {
+        Self::new(addr)
+    }
+}
+
+impl From<String> for RelayEntry {
+    fn from(addr: String) -> Self {
+        Self::new(addr)
+    }
+}
+
+/// The table form of a relay entry
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RelayTable {
+    addr: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    priority: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    tls: bool,
+    #[serde(default, with = "optional_duration", skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<Duration>,
+}
+
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
+impl Serialize for RelayEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_plain() {
+            return serializer.serialize_str(&self.addr);
+        }
+        RelayTable {
+            addr: self.addr.clone(),
+            priority: self.priority,
+            token: self.token.clone(),
+            tls: self.tls,
+            connect_timeout: self.connect_timeout,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RelayEntry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = RelayEntry;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a \"host:port\" string or a table with an addr key")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, addr: &str) -> Result<RelayEntry, E> {
+                Ok(RelayEntry::new(addr))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<RelayEntry, A::Error> {
+                let table = RelayTable::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(RelayEntry {
+                    addr: table.addr,
+                    priority: table.priority,
+                    token: table.token,
+                    tls: table.tls,
+                    connect_timeout: table.connect_timeout,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// A change to some settings, as the app's settings screen sends it: each
+/// field left out stays as it is. Durations take the config file's forms.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettingsPatch {
+    pub listen_port: Option<u16>,
+    #[serde(with = "optional_duration")]
+    pub key_rotation_interval: Option<Duration>,
+    pub max_message_size: Option<usize>,
+    #[serde(with = "optional_duration")]
+    pub connection_timeout: Option<Duration>,
+    pub relay_servers: Option<Vec<RelayEntry>>,
+    pub connection_mode: Option<ConnectionMode>,
+    pub tcp_nodelay: Option<bool>,
+    pub message_history: Option<usize>,
+    pub port_mapping: Option<bool>,
+    pub max_file_size: Option<u64>,
+}
+
+impl SettingsPatch {
+    /// Set the fields this patch has on `config`
+    pub fn apply(&self, config: &mut Config) {
+        macro_rules! set {
+            ($($field:ident),*) => {
+                // Naming every field here keeps this list complete
+                let SettingsPatch { $($field: _),* } = self;
+                $(
+                    if let Some(value) = &self.$field {
+                        config.$field = value.clone();
+                    }
+                )*
+            };
+        }
+        set!(
+            listen_port,
+            key_rotation_interval,
+            max_message_size,
+            connection_timeout,
+            relay_servers,
+            connection_mode,
+            tcp_nodelay,
+            message_history,
+            port_mapping,
+            max_file_size
+        );
+    }
+}
+
+/// Builds a `Config` from the defaults, checking it on `build`
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+    /// Whether the relay list still holds the defaults
+    relays_added: bool,
+}
+
+impl ConfigBuilder {
+    /// Switch to `profile`'s settings. Call it before the setters that
+    /// should override it, as it replaces the values they set.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.config.profile = profile;
+        profile.settings().apply(&mut self.config, |_| false);
+        self
+    }
+
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.config.listen_port = port;
+        self
+    }
+
+    pub fn rotation_interval(mut self, interval: Duration) -> Self {
+        self.config.key_rotation_interval = interval;
+        self
+    }
+
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.max_message_size = bytes;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connection_timeout = timeout;
+        self
+    }
+
+    /// Add a relay, either a `RelayEntry` or a bare `host:port`; the first
+    /// one added replaces the default list
+    pub fn add_relay(mut self, relay: impl Into<RelayEntry>) -> Self {
+        if !self.relays_added {
+            self.config.relay_servers.clear();
+            self.relays_added = true;
+        }
+        self.config.relay_servers.push(relay.into());
+        self
+    }
+
+    /// Replace the relay list, with an empty one for no relays at all
+    pub fn relay_servers<I>(mut self, relays: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<RelayEntry>,
+    {
+        self.config.relay_servers = relays.into_iter().map(Into::into).collect();
+        self.relays_added = true;
+        self
+    }
+
+    /// Mode for connect requests that don't ask for one
+    pub fn connection_mode_default(mut self, mode: ConnectionMode) -> Self {
+        self.config.connection_mode = mode;
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.config.tcp_nodelay = nodelay;
+        self
+    }
+
+    pub fn message_history(mut self, messages: usize) -> Self {
+        self.config.message_history = messages;
+        self
+    }
+
+    pub fn port_mapping(mut self, enabled: bool) -> Self {
+        self.config.port_mapping = enabled;
+        self
+    }
+
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.config.max_file_size = bytes;
+        self
+    }
+
+    /// The config, if it passes `Config::validate`
+    pub fn build(self) -> Result<Config, Vec<ConfigError>> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Config file errors
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, source: std::io::Error },
+    Write { path: PathBuf, source: std::io::Error },
+    /// The file isn't valid TOML, or a value doesn't fit its key
+    Parse {
+        path: PathBuf,
+        line: Option<usize>,
+        key: Option<String>,
+        message: String,
+    },
+    /// An override variable holds something its field can't take
+    Env { var: &'static str, value: String, message: String },
+    /// A value `validate` refused
+    Invalid { key: &'static str, message: String },
+    /// The file watcher couldn't be set up
+    Watch { path: PathBuf, message: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "Failed to read config file {}: {}", path.display(), source)
+            }
+            ConfigError::Write { path, source } => {
+                write!(f, "Failed to write config file {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, line, key, message } => {
+                write!(f, "Invalid config file {}", path.display())?;
+                if let Some(line) = line {
+                    write!(f, ", line {}", line)?;
+                }
+                if let Some(key) = key {
+                    write!(f, ", key `{}`", key)?;
+                }
+                write!(f, ": {}", message)
+            }
+            ConfigError::Env { var, value, message } => {
+                write!(f, "Invalid {}={:?}: {}", var, value, message)
+            }
+            ConfigError::Invalid { key, message } => write!(f, "Invalid `{}`: {}", key, message),
+            ConfigError::Watch { path, message } => {
+                write!(f, "Failed to watch config file {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } | ConfigError::Write { source, .. } => Some(source),
+            ConfigError::Parse { .. }
+            | ConfigError::Env { .. }
+            | ConfigError::Invalid { .. }
+            | ConfigError::Watch { .. } => None,
+        }
+    }
+}
+
+const ENV_LISTEN_PORT: &str = "SILENCE_LISTEN_PORT";
+const ENV_KEY_ROTATION_INTERVAL: &str = "SILENCE_KEY_ROTATION_INTERVAL";
+const ENV_MAX_MESSAGE_SIZE: &str = "SILENCE_MAX_MESSAGE_SIZE";
+const ENV_CONNECTION_TIMEOUT: &str = "SILENCE_CONNECTION_TIMEOUT";
+const ENV_RELAY_SERVERS: &str = "SILENCE_RELAY_SERVERS";
+const ENV_CONNECTION_MODE: &str = "SILENCE_CONNECTION_MODE";
+const ENV_TCP_NODELAY: &str = "SILENCE_TCP_NODELAY";
+const ENV_MESSAGE_HISTORY: &str = "SILENCE_MESSAGE_HISTORY";
+const ENV_PORT_MAPPING: &str = "SILENCE_PORT_MAPPING";
+const ENV_MAX_FILE_SIZE: &str = "SILENCE_MAX_FILE_SIZE";
+
+/// All of `validate`'s complaints on one line
+pub(crate) fn join_errors(errors: &[ConfigError]) -> String {
+    errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+}
+
+/// Replace `field` with the parsed value of `var`, if it's set
+fn env_override<T>(
+    var: &'static str,
+    field: &mut T,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Result<(), ConfigError> {
+    let value = match std::env::var(var) {
+        Ok(value) => value,
+        Err(std::env::VarError::NotPresent) => return Ok(()),
+        Err(std::env::VarError::NotUnicode(value)) => {
+            return Err(ConfigError::Env {
+                var,
+                value: value.to_string_lossy().into_owned(),
+                message: "not valid UTF-8".to_string(),
+            })
+        }
+    };
+    *field = parse(value.trim()).map_err(|message| ConfigError::Env { var, value, message })?;
+    Ok(())
+}
+
+/// Bare seconds, or a duration with units (`90s`, `2m`, `1h 30m`)
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    humantime_serde::re::humantime::parse_duration(value).map_err(|e| format!("not a duration: {}", e))
+}
+
+/// Whether a relay entry names a host and a usable port
+fn check_host_port(entry: &str) -> Result<(), &'static str> {
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return if addr.port() == 0 { Err("has port 0") } else { Ok(()) };
+    }
+    let Some((host, port)) = entry.rsplit_once(':') else {
+        return Err("is not host:port");
+    };
+    let valid_host = !host.is_empty()
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if !valid_host {
+        return Err("has no valid host before the port");
+    }
+    match port.parse::<u16>() {
+        Ok(0) => Err("has port 0"),
+        Ok(_) => Ok(()),
+        Err(_) => Err("has no valid port after the host"),
+    }
+}
+
+/// Comma separated entries, trimmed, without empty ones
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(str::to_string).collect()
+}
+
+/// Replace `path` with `text` in one step, so a crash midway leaves the old
+/// file intact; the file is readable by the owner alone on unix
+pub(crate) fn write_atomically(path: &Path, text: &str) -> Result<(), ConfigError> {
+    let write_error = |source| ConfigError::Write { path: path.to_path_buf(), source };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir).map_err(write_error)?;
+    let file_name = path.file_name().map_or_else(|| "config".into(), |name| name.to_string_lossy());
+    let temp = dir.join(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    write_private(&temp, text.as_bytes())
+        .and_then(|()| std::fs::rename(&temp, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp);
+            write_error(e)
+        })
+}
+
+/// Create `path` holding `contents`, readable by the owner alone on unix
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Durations in config files: written like `15s` or `2m`, and read either
+/// that way or as bare seconds
+mod duration {
+    use serde::{de, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        humantime_serde::serialize(value, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(Visitor)
+    }
+
+    struct Visitor;
+
+    impl de::Visitor<'_> for Visitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a duration such as \"90s\" or \"2m\", or a number of seconds")
+        }
+
+        fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Duration, E> {
+            Ok(Duration::from_secs(seconds))
+        }
+
+        fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Duration, E> {
+            u64::try_from(seconds)
+                .map(Duration::from_secs)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(seconds), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Duration, E> {
+            humantime_serde::re::humantime::parse_duration(text)
+                .map_err(|e| E::custom(format!("invalid duration {:?}: {}", text, e)))
+        }
+    }
+}
+
+/// `duration` for optional fields
+mod optional_duration {
+    use serde::{Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::duration::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        super::duration::deserialize(deserializer).map(Some)
+    }
+}
+
+/// 1-based line of a byte offset
+fn line_number(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// Key assigned on the line holding `offset`, if that line is an assignment
+fn offending_key(text: &str, offset: usize) -> Option<&str> {
+    let line_start = text[..offset.min(text.len())].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[line_start..].lines().next()?;
+    let (key, _) = line.split_once('=')?;
+    let key = key.trim();
+    (!key.is_empty()).then_some(key)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Serializes tests that touch the environment, which is process wide
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Sets variables for the life of the guard and clears every SILENCE_*
+    /// override besides, restoring them all on drop
+    pub(crate) struct ScopedEnv {
+        saved: Vec<(&'static str, Option<std::ffi::OsString>)>,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl ScopedEnv {
+        pub(crate) fn set(vars: &[(&'static str, &str)]) -> Self {
+            let lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let all = [
+                ENV_LISTEN_PORT,
+                ENV_KEY_ROTATION_INTERVAL,
+                ENV_MAX_MESSAGE_SIZE,
+                ENV_CONNECTION_TIMEOUT,
+                ENV_RELAY_SERVERS,
+                ENV_CONNECTION_MODE,
+                ENV_TCP_NODELAY,
+                ENV_MESSAGE_HISTORY,
+                ENV_PORT_MAPPING,
+                ENV_MAX_FILE_SIZE,
+            ];
+            let saved = all.iter().map(|var| (*var, std::env::var_os(var))).collect();
+            for var in all {
+                std::env::remove_var(var);
+            }
+            for (var, value) in vars {
+                std::env::set_var(var, value);
+            }
+            Self { saved, _lock: lock }
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            for (var, value) in &self.saved {
+                match value {
+                    Some(value) => std::env::set_var(var, value),
+                    None => std::env::remove_var(var),
+                }
+            }
+        }
+    }
+
+    fn write_config(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("silence-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_full_file_sets_every_field() {
+        let path = write_config(
+            r#"
+listen_port = 9000
+key_rotation_interval = "1m"
+max_message_size = 8192
+connection_timeout = 10  # bare seconds still work
+relay_servers = ["relay.example:8080", "198.51.100.7:8080"]
+connection_mode = "relay_only"
+tcp_nodelay = true
+message_history = 50
+port_mapping = true
+max_file_size = 1048576
+"#,
+        );
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                profile: Profile::Balanced,
+                listen_port: 9000,
+                key_rotation_interval: Duration::from_secs(60),
+                max_message_size: 8192,
+                connection_timeout: Duration::from_secs(10),
+                relay_servers: vec![RelayEntry::new("relay.example:8080"), RelayEntry::new("198.51.100.7:8080")],
+                connection_mode: ConnectionMode::RelayOnly,
+                tcp_nodelay: true,
+                message_history: 50,
+                port_mapping: true,
+                max_file_size: 1024 * 1024,
+            }
+        );
+    }
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let path = write_config("# only the port\nlisten_port = 9000\n");
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config, Config { listen_port: 9000, ..Config::default() });
+        assert_eq!(Config::from_toml("", &path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_relay_entries_take_strings_and_tables() {
+        let config = Config::from_toml(
+            r#"
+relay_servers = [
+    "relay.example:8080",
+    { addr = "backup.example:8443", priority = 2, token = "s3cret", tls = true, connect_timeout = "5s" },
+    { addr = "198.51.100.7:8080" },
+]
+"#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.relay_servers,
+            vec![
+                RelayEntry::new("relay.example:8080"),
+                RelayEntry::new("backup.example:8443")
+                    .priority(2)
+                    .token("s3cret")
+                    .tls(true)
+                    .connect_timeout(Duration::from_secs(5)),
+                RelayEntry::new("198.51.100.7:8080"),
+            ]
+        );
+
+        // Entries without options are written back in the short form
+        let saved = toml::to_string(&config).unwrap();
+        assert!(saved.contains("\"198.51.100.7:8080\""), "{}", saved);
+        assert!(!saved.contains("addr = \"198.51.100.7:8080\""), "{}", saved);
+
+        let error = Config::from_toml(
+            "relay_servers = [{ addr = \"relay.example:8080\", prio = 1 }]\n",
+            Path::new("config.toml"),
+        )
+        .unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { line: Some(1), .. }), "{}", error);
+        assert!(error.to_string().contains("prio"), "{}", error);
+    }
+
+    #[test]
+    fn test_malformed_files_name_the_line_and_key() {
+        let path = Path::new("config.toml");
+
+        let error = Config::from_toml("listen_port = 9000\nmax_message_size = \"big\"\n", path).unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { line: Some(2), key: Some(key), .. } if key == "max_message_size"));
+        assert!(error.to_string().contains("line 2, key `max_message_size`"), "{}", error);
+
+        let error = Config::from_toml("listen_port = 70000\n", path).unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { line: Some(1), key: Some(key), .. } if key == "listen_port"));
+
+        let error = Config::from_toml("\n\nlisten_prot = 9000\n", path).unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { line: Some(3), key: Some(key), .. } if key == "listen_prot"));
+        assert!(error.to_string().contains("unknown field"), "{}", error);
+
+        let error = Config::from_toml("connection_timeout = \"soon\"\n", path).unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { line: Some(1), key: Some(key), .. } if key == "connection_timeout"));
+        assert!(error.to_string().contains("invalid duration"), "{}", error);
+
+        let error = Config::from_toml("listen_port = [9000\n", path).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse { line: Some(_), .. }));
+    }
+
+    #[test]
+    fn test_missing_file_falls_back_to_defaults() {
+        let path = std::env::temp_dir().join(format!("silence-config-{}.toml", uuid::Uuid::new_v4()));
+        assert!(matches!(Config::from_file(&path), Err(ConfigError::Io { .. })));
+        assert_eq!(Config::load_or_default(&path).unwrap(), Config::default());
+
+        // A file that is there but broken is still an error
+        let path = write_config("listen_port = \n");
+        let result = Config::load_or_default(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn test_env_overrides_every_field() {
+        let _env = ScopedEnv::set(&[
+            ("SILENCE_LISTEN_PORT", "9000"),
+            ("SILENCE_KEY_ROTATION_INTERVAL", "2m"),
+            ("SILENCE_MAX_MESSAGE_SIZE", "8192"),
+            ("SILENCE_CONNECTION_TIMEOUT", " 45 "),
+            ("SILENCE_RELAY_SERVERS", " relay.example:8080, ,198.51.100.7:8080,"),
+            ("SILENCE_CONNECTION_MODE", "direct_only"),
+            ("SILENCE_TCP_NODELAY", "true"),
+            ("SILENCE_MESSAGE_HISTORY", "0"),
+            ("SILENCE_PORT_MAPPING", "true"),
+            ("SILENCE_MAX_FILE_SIZE", "1048576"),
+        ]);
+        assert_eq!(
+            Config::from_env().unwrap(),
+            Config {
+                profile: Profile::Balanced,
+                listen_port: 9000,
+                key_rotation_interval: Duration::from_secs(120),
+                max_message_size: 8192,
+                connection_timeout: Duration::from_secs(45),
+                relay_servers: vec![RelayEntry::new("relay.example:8080"), RelayEntry::new("198.51.100.7:8080")],
+                connection_mode: ConnectionMode::DirectOnly,
+                tcp_nodelay: true,
+                message_history: 0,
+                port_mapping: true,
+                max_file_size: 1024 * 1024,
+            }
+        );
+    }
+
+    #[test]
+    fn test_env_beats_file_beats_defaults() {
+        let _env = ScopedEnv::set(&[("SILENCE_LISTEN_PORT", "9001"), ("SILENCE_RELAY_SERVERS", "")]);
+        let path = write_config("listen_port = 9000\nconnection_timeout = 10\n");
+        let config = Config::from_file(&path).unwrap().apply_env_overrides().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                listen_port: 9001,
+                connection_timeout: Duration::from_secs(10),
+                relay_servers: Vec::new(),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_bad_env_values_name_the_variable() {
+        let cases = [
+            ("SILENCE_LISTEN_PORT", "70000", "not a port number"),
+            ("SILENCE_LISTEN_PORT", "", "not a port number"),
+            ("SILENCE_MAX_MESSAGE_SIZE", "-1", "not a size in bytes"),
+            ("SILENCE_KEY_ROTATION_INTERVAL", "15 parsecs", "not a duration"),
+            ("SILENCE_CONNECTION_TIMEOUT", "m", "not a duration"),
+            ("SILENCE_CONNECTION_TIMEOUT", "-5", "not a duration"),
+            ("SILENCE_CONNECTION_TIMEOUT", "99999999999999999999h", "not a duration"),
+            ("SILENCE_CONNECTION_MODE", "RelayOnly", "not a connection mode"),
+            ("SILENCE_TCP_NODELAY", "yes", "not true or false"),
+            ("SILENCE_PORT_MAPPING", "on", "not true or false"),
+            ("SILENCE_MAX_FILE_SIZE", "1MB", "not a size in bytes"),
+        ];
+        for (var, value, expected) in cases {
+            let _env = ScopedEnv::set(&[(var, value)]);
+            let error = Config::from_env().unwrap_err();
+            assert!(matches!(&error, ConfigError::Env { var: v, value: got, .. } if *v == var && got == value));
+            let message = error.to_string();
+            assert!(message.contains(var) && message.contains(expected), "{}", message);
+        }
+    }
+
+    /// Keys `validate` complains about, in order
+    fn invalid_keys(config: &Config) -> Vec<&'static str> {
+        error_keys(&config.validate().unwrap_err())
+    }
+
+    fn error_keys(errors: &[ConfigError]) -> Vec<&'static str> {
+        errors
+            .iter()
+            .map(|error| match error {
+                ConfigError::Invalid { key, .. } => *key,
+                other => panic!("unexpected {}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+        let config = Config {
+            relay_servers: vec![RelayEntry::new("relay.example:8080"), RelayEntry::new("[2001:db8::1]:443")],
+            connection_mode: ConnectionMode::RelayOnly,
+            max_message_size: crate::MESSAGE_OVERHEAD + 1,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_checks_each_rule() {
+        let cases: [(Config, &str); 6] = [
+            (Config { listen_port: 0, ..Config::default() }, "listen_port"),
+            (Config { key_rotation_interval: Duration::from_millis(999), ..Config::default() }, "key_rotation_interval"),
+            (Config { max_message_size: 0, ..Config::default() }, "max_message_size"),
+            (Config { max_message_size: crate::MESSAGE_OVERHEAD, ..Config::default() }, "max_message_size"),
+            (Config { connection_timeout: Duration::ZERO, ..Config::default() }, "connection_timeout"),
+            (
+                Config {
+                    relay_servers: Vec::new(),
+                    connection_mode: ConnectionMode::RelayOnly,
+                    ..Config::default()
+                },
+                "relay_servers",
+            ),
+        ];
+        for (config, key) in cases {
+            assert_eq!(invalid_keys(&config), vec![key]);
+        }
+
+        // Without relay_only an empty relay list is fine
+        assert!(Config { relay_servers: Vec::new(), ..Config::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_checks_each_relay_entry() {
+        for relay in ["relay.example", ":8080", "relay.example:", "relay.example:http", "relay.example:0",
+            "127.0.0.1:0", "relay example:8080", "relay.example:70000"]
+        {
+            let config = Config { relay_servers: vec![RelayEntry::new(relay)], ..Config::default() };
+            let errors = config.validate().unwrap_err();
+            assert_eq!(errors.len(), 1, "{}", relay);
+            assert!(errors[0].to_string().contains(relay), "{}", errors[0]);
+        }
+
+        for entry in [
+            RelayEntry::new("relay.example:8080").token(""),
+            RelayEntry::new("relay.example:8080").connect_timeout(Duration::ZERO),
+        ] {
+            let config = Config { relay_servers: vec![entry.clone()], ..Config::default() };
+            assert_eq!(invalid_keys(&config), vec!["relay_servers"], "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let config = Config {
+            profile: Profile::Balanced,
+            listen_port: 0,
+            key_rotation_interval: Duration::ZERO,
+            max_message_size: 10,
+            connection_timeout: Duration::ZERO,
+            relay_servers: vec![RelayEntry::new("nowhere"), RelayEntry::new("")],
+            connection_mode: ConnectionMode::RelayOnly,
+            tcp_nodelay: false,
+            message_history: 0,
+            port_mapping: false,
+            max_file_size: 0,
+        };
+        assert_eq!(
+            invalid_keys(&config),
+            vec![
+                "listen_port",
+                "key_rotation_interval",
+                "max_message_size",
+                "connection_timeout",
+                "max_file_size",
+                "relay_servers",
+                "relay_servers",
+            ]
+        );
+        assert_eq!(
+            config.validate().unwrap_err()[2].to_string(),
+            format!(
+                "Invalid `max_message_size`: must be over {} bytes, the space encryption and framing take up",
+                crate::MESSAGE_OVERHEAD
+            )
+        );
+    }
+
+    #[test]
+    fn test_builder_sets_every_field() {
+        let config = Config::builder()
+            .listen_port(9000)
+            .rotation_interval(Duration::from_secs(60))
+            .max_message_size(8192)
+            .connection_timeout(Duration::from_millis(10_900))
+            .add_relay("relay.example:8080")
+            .add_relay("198.51.100.7:8080")
+            .connection_mode_default(ConnectionMode::RelayOnly)
+            .tcp_nodelay(true)
+            .message_history(50)
+            .port_mapping(true)
+            .max_file_size(1024 * 1024)
+            .build()
+            .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                profile: Profile::Balanced,
+                listen_port: 9000,
+                key_rotation_interval: Duration::from_secs(60),
+                max_message_size: 8192,
+                connection_timeout: Duration::from_millis(10_900),
+                relay_servers: vec![RelayEntry::new("relay.example:8080"), RelayEntry::new("198.51.100.7:8080")],
+                connection_mode: ConnectionMode::RelayOnly,
+                tcp_nodelay: true,
+                message_history: 50,
+                port_mapping: true,
+                max_file_size: 1024 * 1024,
+            }
+        );
+        assert_eq!(Config::builder().build().unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_builder_relay_list() {
+        let relays = |builder: ConfigBuilder| {
+            builder.build().unwrap().relay_servers.into_iter().map(|entry| entry.addr).collect::<Vec<_>>()
+        };
+        assert_eq!(relays(Config::builder()), vec!["185.191.116.220:8080"]);
+        assert_eq!(relays(Config::builder().add_relay("a.example:1")), vec!["a.example:1"]);
+        assert!(relays(Config::builder().relay_servers(Vec::<String>::new())).is_empty());
+        assert_eq!(
+            relays(Config::builder().relay_servers(["a.example:1"]).add_relay("b.example:2")),
+            vec!["a.example:1", "b.example:2"],
+            "adding after replacing keeps the replacement"
+        );
+    }
+
+    #[test]
+    fn test_profiles_hold_their_documented_values() {
+        let settings = |config: Config| {
+            (
+                config.key_rotation_interval,
+                config.max_message_size,
+                config.connection_timeout,
+                config.connection_mode,
+                config.tcp_nodelay,
+            )
+        };
+        assert_eq!(
+            settings(Config::profile(Profile::Paranoid)),
+            (Duration::from_secs(5), 4096, Duration::from_secs(10), ConnectionMode::DirectOnly, false)
+        );
+        assert_eq!(
+            settings(Config::profile(Profile::Balanced)),
+            (Duration::from_secs(15), 4096, Duration::from_secs(30), ConnectionMode::Auto, false)
+        );
+        assert_eq!(
+            settings(Config::profile(Profile::Performance)),
+            (Duration::from_secs(60), 65536, Duration::from_secs(30), ConnectionMode::Auto, true)
+        );
+        assert_eq!(Config::profile(Profile::Balanced), Config::default());
+
+        for profile in [Profile::Paranoid, Profile::Balanced, Profile::Performance] {
+            let config = Config::profile(profile);
+            assert_eq!(config.profile, profile);
+            assert!(config.validate().is_ok(), "{}", profile);
+            assert!(config.profile_overrides().is_empty(), "{}", profile);
+        }
+    }
+
+    #[test]
+    fn test_settings_override_a_profile() {
+        let config = Config::builder()
+            .profile(Profile::Paranoid)
+            .rotation_interval(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        assert_eq!(config.key_rotation_interval, Duration::from_secs(2));
+        assert_eq!(config.connection_mode, ConnectionMode::DirectOnly);
+        assert_eq!(config.profile_overrides(), vec!["key_rotation_interval"]);
+
+        // In a file, keys it sets win over its profile wherever they appear
+        let config = Config::from_toml(
+            "connection_mode = \"auto\"\nprofile = \"paranoid\"\nlisten_port = 9000\n",
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        assert_eq!(config.profile, Profile::Paranoid);
+        assert_eq!(config.key_rotation_interval, Duration::from_secs(5));
+        assert_eq!(config.connection_mode, ConnectionMode::Auto);
+        assert_eq!(config.profile_overrides(), vec!["connection_mode"]);
+
+        let error = Config::from_toml("profile = \"reckless\"\n", Path::new("config.toml")).unwrap_err();
+        assert!(matches!(&error, ConfigError::Parse { key: Some(key), .. } if key == "profile"), "{}", error);
+    }
+
+    #[test]
+    fn test_builder_propagates_validation_errors() {
+        let errors = Config::builder()
+            .listen_port(0)
+            .rotation_interval(Duration::from_millis(500))
+            .relay_servers(Vec::<String>::new())
+            .connection_mode_default(ConnectionMode::RelayOnly)
+            .build()
+            .unwrap_err();
+        assert_eq!(error_keys(&errors), vec!["listen_port", "key_rotation_interval", "relay_servers"]);
+    }
+
+    fn non_default_config() -> Config {
+        Config::builder()
+            .profile(Profile::Performance)
+            .listen_port(9000)
+            .rotation_interval(Duration::from_secs(90))
+            .max_message_size(8192)
+            .connection_timeout(Duration::from_millis(2500))
+            .add_relay("relay.example:8080")
+            .add_relay(
+                RelayEntry::new("[2001:db8::1]:443")
+                    .priority(3)
+                    .token("s3cret")
+                    .connect_timeout(Duration::from_secs(5)),
+            )
+            .connection_mode_default(ConnectionMode::DirectOnly)
+            .tcp_nodelay(true)
+            .message_history(10)
+            .port_mapping(true)
+            .max_file_size(1024 * 1024)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_saved_config_loads_back_unchanged() {
+        let dir = std::env::temp_dir().join(format!("silence-config-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("nested").join("config.toml");
+        let config = non_default_config();
+
+        config.save(&path).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), config);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("key_rotation_interval = \"1m 30s\""), "{}", text);
+        assert!(text.contains("connection_mode = \"direct_only\""), "{}", text);
+
+        // Saving again replaces the file and leaves no temp files behind
+        Config::default().save(&path).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), Config::default());
+        assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saved_config_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("silence-config-{}.toml", uuid::Uuid::new_v4()));
+        non_default_config().save(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_save_reports_unwritable_path() {
+        let file = write_config("");
+        let result = Config::default().save(file.join("config.toml"));
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(result, Err(ConfigError::Write { .. })));
+    }
+
+    #[test]
+    fn test_config_dir_holds_default_path() {
+        if let (Some(dir), Some(path)) = (Config::config_dir(), Config::default_path()) {
+            assert!(dir.ends_with("silence"));
+            assert_eq!(path.parent(), Some(dir.as_path()));
+        }
+    }
+
+    #[test]
+    fn test_first_run_writes_a_commented_default_file() {
+        let dir = std::env::temp_dir().join(format!("silence-init-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("silence").join("config.toml");
+
+        let loaded = Config::load_or_init_at(&path);
+        assert!(loaded.created);
+        assert!(loaded.errors.is_empty());
+        assert_eq!(loaded.config, Config::default());
+        assert_eq!(loaded.path.as_deref(), Some(path.as_path()));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, DEFAULT_CONFIG_FILE);
+
+        // Later runs read the file, edits included, without rewriting it
+        std::fs::write(&path, text.replace("# listen_port = 7642", "listen_port = 9000")).unwrap();
+        let loaded = Config::load_or_init_at(&path);
+        assert!(!loaded.created);
+        assert!(loaded.errors.is_empty());
+        assert_eq!(loaded.config, Config { listen_port: 9000, ..Config::default() });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_file_matches_the_defaults() {
+        // Uncommenting every setting must not change anything either
+        let uncommented: String = DEFAULT_CONFIG_FILE
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.contains(" = ") && !setting.starts_with('{') => setting,
+                _ => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let path = Path::new("config.toml");
+        assert_eq!(Config::from_toml(DEFAULT_CONFIG_FILE, path).unwrap(), Config::default());
+        assert_eq!(Config::from_toml(&uncommented, path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_broken_file_falls_back_without_being_replaced() {
+        let dir = std::env::temp_dir().join(format!("silence-init-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(&path, "listen_port = \"soon\"\n").unwrap();
+        let loaded = Config::load_or_init_at(&path);
+        assert!(!loaded.created);
+        assert_eq!(loaded.config, Config::default());
+        assert!(matches!(&loaded.errors[..], [ConfigError::Parse { line: Some(1), .. }]), "{:?}", loaded.errors);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "listen_port = \"soon\"\n");
+
+        // Parsing isn't enough, the settings also have to make sense
+        std::fs::write(&path, "listen_port = 0\nconnection_timeout = 0\n").unwrap();
+        let loaded = Config::load_or_init_at(&path);
+        assert_eq!(loaded.config, Config::default());
+        assert_eq!(error_keys(&loaded.errors), vec!["listen_port", "connection_timeout"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_settings_patch_sets_only_what_it_has() {
+        let patch: SettingsPatch = serde_json::from_value(serde_json::json!({
+            "key_rotation_interval": "90s",
+            "connection_mode": "relay",
+            "relay_servers": ["relay.example:8080", { "addr": "backup.example:8443", "priority": 1 }],
+        }))
+        .unwrap();
+        let mut config = Config::default();
+        patch.apply(&mut config);
+        assert_eq!(config.key_rotation_interval, Duration::from_secs(90));
+        assert_eq!(config.connection_mode, ConnectionMode::RelayOnly);
+        assert_eq!(config.relay_servers[1], RelayEntry::new("backup.example:8443").priority(1));
+        assert_eq!(Config::default().changed_fields(&config), vec!["key_rotation_interval", "relay_servers", "connection_mode"]);
+
+        // An empty patch changes nothing, and settings there are none of are refused
+        let mut unchanged = config.clone();
+        SettingsPatch::default().apply(&mut unchanged);
+        assert_eq!(unchanged, config);
+        assert!(serde_json::from_value::<SettingsPatch>(serde_json::json!({ "cover_traffic": true })).is_err());
+    }
+
+    #[test]
+    fn test_changed_fields_names_each_difference() {
+        let config = non_default_config();
+        assert!(config.changed_fields(&config.clone()).is_empty());
+        assert_eq!(
+            Config::default().changed_fields(&config),
+            vec![
+                "profile",
+                "listen_port",
+                "key_rotation_interval",
+                "max_message_size",
+                "connection_timeout",
+                "relay_servers",
+                "connection_mode",
+                "tcp_nodelay",
+                "message_history",
+                "port_mapping",
+                "max_file_size",
+            ]
+        );
+    }
+}
@@ -1,11 +1,20 @@
 // Cryptographic core for Ephemeral Key Cascade protocol
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, aead::{Aead, KeyInit}};
 use hkdf::Hkdf;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use rand::{rngs::OsRng, RngCore};
-// Removed zeroize import - manual secure deletion for now
+use zeroize::Zeroize;
 use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "network")]
+use crate::config::{join_errors, Config, ConfigError};
+
+/// Bytes an encrypted message adds to its plaintext once serialized: the
+/// nonce, the ciphertext's length prefix, the Poly1305 tag and the timestamp
+pub const ENCRYPTION_OVERHEAD: usize = 12 + 8 + 16 + 8;
+
+/// Shortest key rotation interval the engine accepts
+pub const MIN_ROTATION_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Error types for cryptographic operations
 #[derive(Debug)]
@@ -15,6 +24,9 @@ pub enum CryptoError {
     Decryption,
     KeyDerivation,
     InvalidNonce,
+    /// The settings the engine was built from don't hold together
+    #[cfg(feature = "network")]
+    InvalidConfig(Vec<ConfigError>),
 }
 
 impl std::fmt::Display for CryptoError {
@@ -25,6 +37,8 @@ impl std::fmt::Display for CryptoError {
             CryptoError::Decryption => write!(f, "Decryption failed"),
             CryptoError::KeyDerivation => write!(f, "Key derivation failed"),
             CryptoError::InvalidNonce => write!(f, "Invalid nonce"),
+            #[cfg(feature = "network")]
+            CryptoError::InvalidConfig(errors) => write!(f, "Invalid config: {}", join_errors(errors)),
         }
     }
 }
@@ -33,13 +47,14 @@ impl std::error::Error for CryptoError {}
 
 /// Ephemeral key material with automatic zeroing
 pub struct EphemeralKeys {
-    #[allow(dead_code)]
     master_key: [u8; 32],
     session_key: [u8; 32],
     encryption_key: [u8; 32],
     mac_key: [u8; 32],
     created_at: Instant,
     rotation_interval: Duration,
+    /// Rotations since the keys were generated
+    epoch: u64,
 }
 
 impl EphemeralKeys {
@@ -47,7 +62,11 @@ impl EphemeralKeys {
     pub fn new(rotation_interval_secs: u64) -> Result<Self, CryptoError> {
         let mut master_key = [0u8; 32];
         OsRng.fill_bytes(&mut master_key);
-        
+        Self::from_master_key(master_key, rotation_interval_secs)
+    }
+
+    /// Keys derived from `master_key`, so two engines can start out alike
+    fn from_master_key(master_key: [u8; 32], rotation_interval_secs: u64) -> Result<Self, CryptoError> {
         let mut keys = Self {
             master_key,
             session_key: [0u8; 32],
@@ -55,6 +74,7 @@ impl EphemeralKeys {
             mac_key: [0u8; 32],
             created_at: Instant::now(),
             rotation_interval: Duration::from_secs(rotation_interval_secs),
+            epoch: 0,
         };
         
         keys.derive_keys()?;
@@ -91,6 +111,7 @@ impl EphemeralKeys {
         
         self.derive_keys()?;
         self.created_at = Instant::now();
+        self.epoch += 1;
         Ok(())
     }
     
@@ -98,6 +119,20 @@ impl EphemeralKeys {
     pub fn encryption_key(&self) -> &[u8; 32] {
         &self.encryption_key
     }
+
+    /// Overwrite every key with zeros; done on drop as well
+    pub fn wipe(&mut self) {
+        self.master_key.zeroize();
+        self.session_key.zeroize();
+        self.encryption_key.zeroize();
+        self.mac_key.zeroize();
+    }
+}
+
+impl Drop for EphemeralKeys {
+    fn drop(&mut self) {
+        self.wipe();
+    }
 }
 
 /// Encrypted message format
@@ -108,16 +143,82 @@ pub struct EncryptedMessage {
     pub timestamp: u64,
 }
 
+/// Identifies a crypto session: taken from the first keys, so rotation
+/// doesn't change it. What people compare out of band is a connection's,
+/// `shared_with` the peer's session fingerprint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    fn of(keys: &EphemeralKeys) -> Result<Self, CryptoError> {
+        let mut fingerprint = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &keys.master_key)
+            .expand(b"SILENCE_FINGERPRINT", &mut fingerprint)
+            .map_err(|_| CryptoError::KeyDerivation)?;
+        Ok(Self(fingerprint))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The fingerprint of a connection between this session and the peer's
+    /// `theirs`, the same worked out at either end
+    pub fn shared_with(&self, theirs: &Fingerprint) -> Fingerprint {
+        let (low, high) = if self.0 <= theirs.0 { (self, theirs) } else { (theirs, self) };
+        Self(Sha256::digest([b"SILENCE_CONNECTION".as_slice(), &low.0, &high.0].concat()).into())
+    }
+
+    /// Four groups of five digits, short enough to read out over the phone
+    pub fn short_auth_string(&self) -> String {
+        self.0
+            .chunks_exact(4)
+            .take(4)
+            .map(|chunk| format!("{:05}", u32::from_be_bytes(chunk.try_into().unwrap()) % 100_000))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The full fingerprint, in hex
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
 /// Main cryptographic engine
 pub struct SilenceCrypto {
     keys: EphemeralKeys,
+    fingerprint: Fingerprint,
 }
 
 impl SilenceCrypto {
     /// Initialize new crypto engine
     pub fn new(rotation_interval_secs: u64) -> Result<Self, CryptoError> {
         let keys = EphemeralKeys::new(rotation_interval_secs)?;
-        Ok(Self { keys })
+        let fingerprint = Fingerprint::of(&keys)?;
+        Ok(Self { keys, fingerprint })
+    }
+
+    /// An engine starting from `master_key`, as the two ends of a session
+    /// would after a key exchange
+    #[cfg(all(test, feature = "app"))]
+    pub(crate) fn from_master_key(master_key: [u8; 32], rotation_interval_secs: u64) -> Result<Self, CryptoError> {
+        let keys = EphemeralKeys::from_master_key(master_key, rotation_interval_secs)?;
+        let fingerprint = Fingerprint::of(&keys)?;
+        Ok(Self { keys, fingerprint })
+    }
+
+    /// Create a crypto engine from validated app settings
+    #[cfg(feature = "network")]
+    pub fn from_config(config: &Config) -> Result<Self, CryptoError> {
+        config.validate().map_err(CryptoError::InvalidConfig)?;
+        Self::new(config.key_rotation_interval.as_secs())
     }
     
     /// Encrypt a message
@@ -166,14 +267,37 @@ impl SilenceCrypto {
         self.keys.rotate()
     }
     
+    /// Change how often keys rotate, without touching the current keys.
+    /// The next rotation is due `interval` after they were created, so a
+    /// shorter interval can make it due at once. Intervals under
+    /// `MIN_ROTATION_INTERVAL` are raised to it.
+    pub fn set_rotation_interval(&mut self, interval: Duration) {
+        self.keys.rotation_interval = interval.max(MIN_ROTATION_INTERVAL);
+    }
+
+    /// Current key rotation interval
+    pub fn rotation_interval(&self) -> Duration {
+        self.keys.rotation_interval
+    }
+
+    /// Time until the next key rotation is due, zero once it is
+    pub fn time_until_rotation(&self) -> Duration {
+        self.keys.rotation_interval.saturating_sub(self.keys.created_at.elapsed())
+    }
+
     /// Get time until next key rotation
     pub fn seconds_until_rotation(&self) -> u64 {
-        let elapsed = self.keys.created_at.elapsed();
-        if elapsed >= self.keys.rotation_interval {
-            0
-        } else {
-            (self.keys.rotation_interval - elapsed).as_secs()
-        }
+        self.time_until_rotation().as_secs()
+    }
+
+    /// How many times the keys have rotated, however it was set off
+    pub fn key_epoch(&self) -> u64 {
+        self.keys.epoch
+    }
+
+    /// This session's fingerprint, the same for the engine's whole life
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
     }
 }
 
@@ -185,7 +309,7 @@ mod tests {
     fn test_key_generation() {
         let crypto = SilenceCrypto::new(15).expect("Failed to create crypto engine");
         let seconds_remaining = crypto.seconds_until_rotation();
-        assert!(seconds_remaining >= 14 && seconds_remaining <= 15, 
+        assert!((14..=15).contains(&seconds_remaining), 
                 "Expected 14-15 seconds, got {}", seconds_remaining);
     }
     
@@ -200,6 +324,87 @@ mod tests {
         assert_eq!(message, decrypted.as_slice());
     }
     
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_from_config_validates_first() {
+        assert!(SilenceCrypto::from_config(&Config::default()).is_ok());
+
+        let config = Config { key_rotation_interval: Duration::ZERO, ..Config::default() };
+        assert!(matches!(SilenceCrypto::from_config(&config), Err(CryptoError::InvalidConfig(_))));
+    }
+
+    /// An engine whose keys are `age` old
+    fn aged_crypto(interval_secs: u64, age: Duration) -> SilenceCrypto {
+        let mut crypto = SilenceCrypto::new(interval_secs).unwrap();
+        crypto.keys.created_at = Instant::now().checked_sub(age).unwrap();
+        crypto
+    }
+
+    #[test]
+    fn test_lengthened_interval_counts_from_key_creation() {
+        let mut crypto = aged_crypto(60, Duration::from_secs(40));
+        crypto.set_rotation_interval(Duration::from_secs(120));
+        assert_eq!(crypto.rotation_interval(), Duration::from_secs(120));
+        assert!((79..=80).contains(&crypto.seconds_until_rotation()), "{}", crypto.seconds_until_rotation());
+        assert!(!crypto.keys.should_rotate());
+    }
+
+    #[test]
+    fn test_shortened_interval_can_be_due_at_once() {
+        let mut crypto = aged_crypto(60, Duration::from_secs(40));
+        crypto.set_rotation_interval(Duration::from_secs(50));
+        assert!((9..=10).contains(&crypto.seconds_until_rotation()), "{}", crypto.seconds_until_rotation());
+
+        crypto.set_rotation_interval(Duration::from_secs(30));
+        assert_eq!(crypto.time_until_rotation(), Duration::ZERO);
+        assert!(crypto.keys.should_rotate());
+
+        // The next encryption rotates, and the new keys get a full interval
+        let old_key = *crypto.keys.encryption_key();
+        crypto.encrypt(b"rotated first").unwrap();
+        assert_ne!(old_key, *crypto.keys.encryption_key());
+        assert!((29..=30).contains(&crypto.seconds_until_rotation()));
+    }
+
+    #[test]
+    fn test_rotation_interval_has_a_floor() {
+        let mut crypto = SilenceCrypto::new(60).unwrap();
+        crypto.set_rotation_interval(Duration::ZERO);
+        assert_eq!(crypto.rotation_interval(), MIN_ROTATION_INTERVAL);
+    }
+
+    #[test]
+    fn test_fingerprint_survives_rotation() {
+        let mut crypto = SilenceCrypto::new(60).unwrap();
+        let fingerprint = crypto.fingerprint();
+        crypto.rotate_keys().unwrap();
+        assert_eq!(crypto.fingerprint(), fingerprint);
+        assert_ne!(SilenceCrypto::new(60).unwrap().fingerprint(), fingerprint);
+
+        assert_eq!(fingerprint.to_string().len(), 64);
+        let short = fingerprint.short_auth_string();
+        assert_eq!(short.len(), 4 * 5 + 3, "{}", short);
+        assert!(short.split(' ').all(|group| group.len() == 5 && group.bytes().all(|b| b.is_ascii_digit())), "{}", short);
+
+        let fixed = Fingerprint([0xff; 32]);
+        assert_eq!(fixed.short_auth_string(), "67295 67295 67295 67295");
+    }
+
+    #[test]
+    fn test_both_ends_of_a_connection_share_its_fingerprint() {
+        let ours = SilenceCrypto::new(60).unwrap().fingerprint();
+        let theirs = SilenceCrypto::new(60).unwrap().fingerprint();
+        let connection = ours.shared_with(&theirs);
+        assert_eq!(theirs.shared_with(&ours), connection);
+        assert_eq!(theirs.shared_with(&ours).short_auth_string(), connection.short_auth_string());
+        assert_ne!(connection, ours);
+        assert_ne!(connection, theirs);
+
+        // Another peer makes another connection
+        let someone_else = SilenceCrypto::new(60).unwrap().fingerprint();
+        assert_ne!(ours.shared_with(&someone_else), connection);
+    }
+
     #[test]
     fn test_key_rotation() {
         let mut keys = EphemeralKeys::new(1).expect("Key generation failed");
@@ -208,7 +413,18 @@ mod tests {
         std::thread::sleep(Duration::from_secs(1));
         assert!(keys.should_rotate());
         
+        assert_eq!(keys.epoch, 0);
         keys.rotate().expect("Key rotation failed");
         assert_ne!(old_key, *keys.encryption_key());
+        assert_eq!(keys.epoch, 1);
+    }
+
+    #[test]
+    fn test_wipe_zeroes_every_key() {
+        let mut keys = EphemeralKeys::new(60).expect("Key generation failed");
+        keys.wipe();
+        for key in [keys.master_key, keys.session_key, keys.encryption_key, keys.mac_key] {
+            assert_eq!(key, [0u8; 32]);
+        }
     }
 }
\ No newline at end of file
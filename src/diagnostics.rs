@@ -0,0 +1,331 @@
+// Diagnostic bundles, for attaching to a bug report
+//
+// A bundle is a zip of the recent app log, the settings in effect, each
+// connection's traffic counters, and the version and platform. None of it
+// may give away what was said or the keys it was said with: relay tokens
+// are taken out of the settings, and every file goes through a `Redactor`
+// on its way into the zip, which blanks out the message texts and other
+// secrets it was given, and any long run of hex digits, the shape keys take
+// when printed.
+
+use crate::config::Config;
+use crate::session::PeerInfo;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far back the log goes when no window is asked for
+pub const DEFAULT_LOG_HOURS: u64 = 24;
+
+/// What redacted text is replaced with
+pub const REDACTED: &str = "[redacted]";
+
+/// Secrets shorter than this are left alone: blanking every "ok" would
+/// garble the log without hiding anything
+const MIN_SECRET_LEN: usize = 4;
+
+/// Hex runs this long or longer are taken for key material: 16 bytes
+const MIN_HEX_RUN: usize = 32;
+
+/// Blanks out secrets in text bound for a bundle
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    /// Longest first, so one containing another goes whole
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blank out `secret` wherever it appears, also as the log writes it
+    /// inside quotes, with escapes
+    pub fn secret(&mut self, secret: impl Into<String>) {
+        let secret = secret.into();
+        if secret.chars().count() < MIN_SECRET_LEN {
+            return;
+        }
+        let debug = format!("{:?}", secret);
+        let escaped = &debug[1..debug.len() - 1];
+        if escaped != secret {
+            self.secrets.push(escaped.to_string());
+        }
+        self.secrets.push(secret);
+        self.secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        self.secrets.dedup();
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        redact_hex(&text)
+    }
+}
+
+/// Replace each run of at least `MIN_HEX_RUN` hex digits
+fn redact_hex(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_hexdigit()) {
+        let run = rest[start..].find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(rest.len() - start);
+        redacted.push_str(&rest[..start]);
+        if run >= MIN_HEX_RUN {
+            redacted.push_str(REDACTED);
+        } else {
+            redacted.push_str(&rest[start..start + run]);
+        }
+        rest = &rest[start + run..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// What goes into a bundle, before redaction
+#[derive(Clone, Debug)]
+pub struct Bundle {
+    /// Log lines written since the window's start, oldest first, relay
+    /// tokens blanked out
+    pub log: String,
+    /// The settings in effect, relay tokens replaced
+    pub config: Config,
+    pub peers: Vec<PeerInfo>,
+}
+
+#[derive(Serialize)]
+struct About {
+    version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    /// Milliseconds since the Unix epoch
+    generated_at: u64,
+}
+
+#[derive(Serialize)]
+struct Connections<'a> {
+    peers: &'a [PeerInfo],
+}
+
+impl Bundle {
+    /// A bundle of the log in `log_dir` from `since` on, `config` and the
+    /// connections in `peers`
+    pub fn new(log_dir: Option<&Path>, since: SystemTime, mut config: Config, peers: Vec<PeerInfo>) -> Self {
+        let mut tokens = Redactor::new();
+        for relay in &mut config.relay_servers {
+            if let Some(token) = &mut relay.token {
+                tokens.secret(std::mem::replace(token, REDACTED.to_string()));
+            }
+        }
+        let since = since.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let log = log_dir.map(|dir| tokens.redact(&recent_log(dir, since))).unwrap_or_default();
+        Self { log, config, peers }
+    }
+
+    /// Write the bundle to `path` as a zip, each file passed through
+    /// `redactor` first
+    pub fn write(&self, path: &Path, redactor: &Redactor) -> std::io::Result<()> {
+        let about = About {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            generated_at: crate::events::now_millis(),
+        };
+        // Nothing in these can fail to serialize
+        let files = [
+            ("about.toml", toml::to_string(&about).unwrap()),
+            ("config.toml", toml::to_string(&self.config).unwrap()),
+            ("connections.toml", toml::to_string(&Connections { peers: &self.peers }).unwrap()),
+            ("silence.log", self.log.clone()),
+        ];
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path)?);
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, contents) in files {
+            zip.start_file(name, options)?;
+            zip.write_all(redactor.redact(&contents).as_bytes())?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+/// The lines of the log files in `dir` written from `since`, in Unix
+/// seconds, on; a line without a timestamp goes with the one before it
+fn recent_log(dir: &Path, since: u64) -> String {
+    let mut files: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| Some(entry.ok()?.path())).filter(|path| path.is_file()).collect(),
+        Err(e) => {
+            tracing::warn!(dir = %dir.display(), error = %e, "Couldn't list the log files");
+            return String::new();
+        }
+    };
+    // Named by date, so this is oldest first
+    files.sort();
+
+    let mut log = String::new();
+    for file in files {
+        let text = match std::fs::read_to_string(&file) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!(file = %file.display(), error = %e, "Couldn't read a log file");
+                continue;
+            }
+        };
+        let mut keep = false;
+        for line in text.lines() {
+            if let Some(at) = line.split_whitespace().next().and_then(parse_timestamp) {
+                keep = at >= since;
+            }
+            if keep {
+                log.push_str(line);
+                log.push('\n');
+            }
+        }
+    }
+    log
+}
+
+/// Unix seconds from a UTC timestamp as the log writes it, like
+/// `2026-10-16T12:00:00.123456Z`
+fn parse_timestamp(text: &str) -> Option<u64> {
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<u64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch from a civil date, March-based so the leap day
+    // comes last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RelayEntry;
+    use crate::network::ConnectionStats;
+    use crate::session::{Origin, PeerState};
+    use std::io::Read;
+    use std::time::Duration;
+
+    const MESSAGE: &str = "meet me behind the old mill at nine";
+    const TOKEN: &str = "s3cret-relay-token";
+    const KEY: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    /// 2026-01-01T00:00:00Z
+    const NEW_YEAR: u64 = 1767225600;
+
+    fn read_zip(path: &Path) -> Vec<(String, String)> {
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+        (0..zip.len())
+            .map(|i| {
+                let mut file = zip.by_index(i).unwrap();
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                (file.name().to_string(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("2026-01-01T00:00:00.000000Z"), Some(NEW_YEAR));
+        assert_eq!(parse_timestamp("2024-02-29T12:30:15.5Z"), Some(1709209815));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("INFO"), None);
+        assert_eq!(parse_timestamp("2026-13-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_redactor() {
+        let mut redactor = Redactor::new();
+        redactor.secret("say \"cheese\"");
+        redactor.secret("ok");
+        assert_eq!(redactor.redact(r#"body="say \"cheese\"" reply=ok"#), "body=\"[redacted]\" reply=ok");
+        assert_eq!(
+            redactor.redact(&format!("key={} peer_id=6f5012c0-1188-4e2a-9c1d-7be869011930", KEY)),
+            "key=[redacted] peer_id=6f5012c0-1188-4e2a-9c1d-7be869011930"
+        );
+    }
+
+    #[test]
+    fn test_bundle_leaves_out_secrets() {
+        let dir = std::env::temp_dir().join(format!("silence-diagnostics-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("silence.2025-12-31.log"),
+            "2025-12-31T23:59:59.000000Z  INFO silence: Too old to include\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("silence.2026-01-01.log"),
+            format!(
+                "2026-01-01T09:00:00.000000Z  INFO silence::network: Peer connected peer=192.0.2.7:7642\n\
+                 2026-01-01T09:00:01.000000Z DEBUG silence: Received body={:?} key={} token={}\n\
+                 2026-01-01T09:00:02.000000Z  WARN silence::network: Relay failed relay=relay.example:8080\n",
+                MESSAGE, KEY, TOKEN
+            ),
+        )
+        .unwrap();
+
+        let config = Config::builder().add_relay(RelayEntry::new("relay.example:8080").token(TOKEN)).build().unwrap();
+        let peer = PeerInfo {
+            peer_id: "6f5012c0-1188-4e2a-9c1d-7be869011930".to_string(),
+            label: None,
+            peer: "192.0.2.7:7642".parse().unwrap(),
+            fingerprint: None,
+            verified: true,
+            state: PeerState::Connected,
+            relay: false,
+            origin: Origin::Inbound,
+            connected_at: NEW_YEAR * 1000,
+            disconnected_at: None,
+            stats: ConnectionStats {
+                messages_sent: 3,
+                messages_received: 2,
+                bytes_sent: 620,
+                bytes_received: 410,
+                rtt_ms: Some(42),
+            },
+            unread: 0,
+        };
+        let since = UNIX_EPOCH + Duration::from_secs(NEW_YEAR);
+        let bundle = Bundle::new(Some(&dir), since, config, vec![peer]);
+        let mut redactor = Redactor::new();
+        redactor.secret(MESSAGE);
+
+        let path = dir.join("bundle.zip");
+        bundle.write(&path, &redactor).unwrap();
+        let files = read_zip(&path);
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["about.toml", "config.toml", "connections.toml", "silence.log"]);
+        for (name, contents) in &files {
+            for secret in [MESSAGE, TOKEN, KEY] {
+                assert!(!contents.contains(secret), "{} has {:?}:\n{}", name, secret, contents);
+            }
+        }
+
+        let file = |name: &str| &files.iter().find(|(file, _)| file == name).unwrap().1;
+        assert!(file("about.toml").contains(env!("CARGO_PKG_VERSION")));
+        assert!(file("config.toml").contains("relay.example:8080"));
+        assert!(file("connections.toml").contains("bytes_sent = 620"));
+        let log = file("silence.log");
+        assert!(log.contains("Peer connected") && log.contains("Relay failed"), "{}", log);
+        assert!(!log.contains("Too old"), "{}", log);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
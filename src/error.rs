@@ -0,0 +1,121 @@
+// One error type for code that uses more than one part of the library
+//
+// Each module keeps its own error type; `Error` wraps them, so an
+// application can use `?` on crypto, network and config calls alike, the
+// list `Config::validate` returns included. It is transparent: it displays
+// as the error it wraps and reports that error's source as its own.
+
+#[cfg(feature = "network")]
+use crate::config::{join_errors, ConfigError};
+use crate::crypto::CryptoError;
+#[cfg(feature = "network")]
+use crate::network::NetworkError;
+
+/// Any error the library returns
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Crypto(CryptoError),
+    #[cfg(feature = "network")]
+    Network(NetworkError),
+    #[cfg(feature = "network")]
+    Config(ConfigError),
+    /// Settings that failed `Config::validate`, every problem listed
+    #[cfg(feature = "network")]
+    InvalidConfig(Vec<ConfigError>),
+}
+
+/// `Result` with the library's `Error`
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Crypto(e) => e.fmt(f),
+            #[cfg(feature = "network")]
+            Error::Network(e) => e.fmt(f),
+            #[cfg(feature = "network")]
+            Error::Config(e) => e.fmt(f),
+            #[cfg(feature = "network")]
+            Error::InvalidConfig(errors) => write!(f, "Invalid config: {}", join_errors(errors)),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Crypto(e) => e.source(),
+            #[cfg(feature = "network")]
+            Error::Network(e) => e.source(),
+            #[cfg(feature = "network")]
+            Error::Config(e) => e.source(),
+            #[cfg(feature = "network")]
+            Error::InvalidConfig(_) => None,
+        }
+    }
+}
+
+impl From<CryptoError> for Error {
+    fn from(err: CryptoError) -> Self {
+        Error::Crypto(err)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<NetworkError> for Error {
+    fn from(err: NetworkError) -> Self {
+        Error::Network(err)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<Vec<ConfigError>> for Error {
+    fn from(errors: Vec<ConfigError>) -> Self {
+        Error::InvalidConfig(errors)
+    }
+}
+
+#[cfg(all(test, feature = "network"))]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_wrapped_errors_keep_message_and_source() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let error = Error::from(NetworkError::Connection(io));
+        assert_eq!(error.to_string(), "Connection error: refused");
+        assert_eq!(error.source().unwrap().to_string(), "refused");
+
+        let error: Error = CryptoError::Decryption.into();
+        assert!(matches!(error, Error::Crypto(CryptoError::Decryption)));
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_question_mark_converts_each_kind() {
+        fn load() -> Result<crate::Config> {
+            Ok(crate::Config::from_toml("listen_port = \"x\"\n", std::path::Path::new("config.toml"))?)
+        }
+        fn engine() -> Result<crate::SilenceCrypto> {
+            let config = crate::Config { listen_port: 0, ..crate::Config::default() };
+            Ok(crate::SilenceCrypto::from_config(&config)?)
+        }
+        fn build() -> Result<crate::Config> {
+            Ok(crate::Config::builder().listen_port(0).build()?)
+        }
+        assert!(matches!(load(), Err(Error::Config(ConfigError::Parse { .. }))));
+        let error = build().unwrap_err();
+        assert!(matches!(&error, Error::InvalidConfig(errors) if errors.len() == 1));
+        assert!(error.to_string().starts_with("Invalid config: Invalid `listen_port`"), "{}", error);
+        assert!(matches!(engine(), Err(Error::Crypto(CryptoError::InvalidConfig(_)))));
+    }
+}
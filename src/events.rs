@@ -0,0 +1,303 @@
+// Events the desktop app sends its frontend
+//
+// The names and payloads here are the contract with the JavaScript side,
+// which listens for them by name and reads the payload's fields as
+// serialized here. Every payload here carries the `peer_id` of the
+// conversation it belongs to, as `connect_to_peer` returned it. Addresses go
+// over as strings, times as milliseconds since the Unix epoch, as
+// JavaScript's `Date` takes them.
+
+use crate::history::MessageStatus;
+use crate::network::FileOffer;
+use crate::portmap::{MappingProtocol, PortMapping};
+use crate::session::Origin;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A chat message arrived, with a `MessageReceived` payload
+pub const MESSAGE_RECEIVED: &str = "message-received";
+/// A message we sent was written, confirmed or given up on, with a
+/// `MessageStatusChanged` payload
+pub const MESSAGE_STATUS: &str = "message-status";
+/// A connection to a peer is up, with a `PeerConnected` payload
+pub const PEER_CONNECTED: &str = "peer-connected";
+/// The connection to a peer ended, with a `PeerDisconnected` payload
+pub const PEER_DISCONNECTED: &str = "peer-disconnected";
+/// Receiving failed and the connection was dropped, with a `ReceiveError`
+/// payload; `peer-disconnected` follows
+pub const RECEIVE_ERROR: &str = "receive-error";
+/// The connection status changed, with a `status::ConnectionStatus` payload
+pub const CONNECTION_STATUS: &str = "connection-status";
+/// A peer's app instance came back with another connection fingerprint than
+/// last time, with a `FingerprintChanged` payload; the peer isn't verified
+pub const FINGERPRINT_CHANGED: &str = "fingerprint-changed";
+/// The keys were rotated, with a `KeyRotated` payload
+pub const KEY_ROTATED: &str = "key-rotated";
+/// Automatic key rotation failed and the old keys stay in use, with a
+/// `KeyRotationFailed` payload
+pub const KEY_ROTATION_FAILED: &str = "key-rotation-failed";
+/// A peer connected, disconnected, was verified, named or pinged or sent or
+/// got a message, with the roster as `list_peers` answers
+pub const PEERS_CHANGED: &str = "peers-changed";
+/// `panic_wipe` destroyed the session, with a `Wiped` payload; the app
+/// starts over with new keys and nothing connected or listening
+pub const WIPED: &str = "wiped";
+/// The router forwards the listen port, with a `PortMapped` payload
+pub const PORT_MAPPED: &str = "port-mapped";
+/// The router wouldn't map the listen port, or renew the mapping, with a
+/// `PortMappingFailed` payload; listening goes on without it
+pub const PORT_MAPPING_FAILED: &str = "port-mapping-failed";
+/// A peer offers a file, with a `FileOffered` payload, for `accept_file` or
+/// `reject_file` to answer
+pub const FILE_OFFER: &str = "file-offer";
+/// More of a file got through, either way, with a `FileProgress` payload
+pub const FILE_PROGRESS: &str = "file-progress";
+/// A file got through whole, either way, with a `FileComplete` payload
+pub const FILE_COMPLETE: &str = "file-complete";
+/// A file was turned down, given up on or cut off by the connection ending,
+/// either way, with a `FileFailed` payload; nothing of it is kept
+pub const FILE_FAILED: &str = "file-failed";
+
+/// A decrypted message from the peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MessageReceived {
+    /// Unique per message, for the frontend to key its list on
+    pub id: String,
+    pub peer_id: String,
+    /// Address the message came from, the relay's for relayed connections
+    pub from: String,
+    pub body: String,
+    /// When the message arrived
+    pub timestamp: u64,
+}
+
+impl MessageReceived {
+    pub fn new(peer_id: String, from: SocketAddr, body: String) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            peer_id,
+            from: from.to_string(),
+            body,
+            timestamp: now_millis(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerConnected {
+    pub peer_id: String,
+    pub peer: String,
+    /// Whether the connection goes through a relay
+    pub relay: bool,
+    /// Whether we dialed or were dialed
+    pub origin: Origin,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerDisconnected {
+    pub peer_id: String,
+    pub peer: String,
+    pub reason: DisconnectReason,
+}
+
+/// Who ended a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// We hung up
+    Local,
+    /// The peer closed the connection
+    Remote,
+    /// Receiving failed; a `receive-error` event came first
+    Error,
+    /// The peer was connected twice, and this was the connection dropped
+    Duplicate,
+    /// We hung up on a peer that came with an invite that's used up,
+    /// expired or wasn't ours
+    Refused,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ReceiveError {
+    pub peer_id: String,
+    pub peer: String,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MessageStatusChanged {
+    /// The id `send_message` answered
+    pub id: String,
+    pub peer_id: String,
+    pub status: MessageStatus,
+    /// Why it failed, for `failed`
+    pub error: Option<String>,
+    pub timestamp: u64,
+}
+
+impl MessageStatusChanged {
+    pub fn new(id: String, peer_id: String, status: MessageStatus, error: Option<String>) -> Self {
+        Self { id, peer_id, status, error, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FingerprintChanged {
+    pub peer_id: String,
+    pub peer: String,
+    /// The connection fingerprint last time, in hex
+    pub previous_fingerprint: String,
+    /// The connection fingerprint now, in hex
+    pub fingerprint: String,
+    /// Whether the user had verified the previous one
+    pub verified: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct KeyRotated {
+    /// Rotations so far, counting this one
+    pub epoch: u64,
+    /// When the keys rotated
+    pub timestamp: u64,
+    /// When the next rotation is due, for the GUI to count down to
+    pub next_rotation_at: u64,
+}
+
+impl KeyRotated {
+    pub fn new(epoch: u64, next_due: Duration) -> Self {
+        let timestamp = now_millis();
+        Self { epoch, timestamp, next_rotation_at: timestamp + next_due.as_millis() as u64 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct KeyRotationFailed {
+    pub error: String,
+    pub timestamp: u64,
+}
+
+impl KeyRotationFailed {
+    pub fn new(error: String) -> Self {
+        Self { error, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Wiped {
+    /// Connections dropped
+    pub dropped_connections: usize,
+    /// Steps that failed, the rest went ahead regardless
+    pub errors: Vec<String>,
+    pub timestamp: u64,
+}
+
+impl Wiped {
+    pub fn new(dropped_connections: usize, errors: Vec<String>) -> Self {
+        Self { dropped_connections, errors, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PortMapped {
+    pub protocol: MappingProtocol,
+    /// Where peers outside can dial in
+    pub external: String,
+    pub internal_port: u16,
+    /// Seconds the router holds the mapping between renewals, 0 for ever
+    pub lease_secs: u64,
+    pub timestamp: u64,
+}
+
+impl PortMapped {
+    pub fn new(mapping: &PortMapping) -> Self {
+        Self {
+            protocol: mapping.protocol,
+            external: mapping.external.to_string(),
+            internal_port: mapping.internal_port,
+            lease_secs: mapping.lifetime.as_secs(),
+            timestamp: now_millis(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PortMappingFailed {
+    pub error: String,
+    pub timestamp: u64,
+}
+
+impl PortMappingFailed {
+    pub fn new(error: String) -> Self {
+        Self { error, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileOffered {
+    /// For `accept_file` or `reject_file`, and the events that follow
+    pub transfer_id: String,
+    pub peer_id: String,
+    /// The file's name, without its directories
+    pub name: String,
+    /// Bytes
+    pub size: u64,
+    pub timestamp: u64,
+}
+
+impl FileOffered {
+    pub fn new(peer_id: String, offer: FileOffer) -> Self {
+        Self { transfer_id: offer.transfer_id, peer_id, name: offer.name, size: offer.size, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileProgress {
+    pub transfer_id: String,
+    pub peer_id: String,
+    /// Bytes through so far, sent or received
+    pub sent: u64,
+    /// The file's size
+    pub total: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileComplete {
+    pub transfer_id: String,
+    pub peer_id: String,
+    /// Where a file received was saved, `None` for one sent
+    pub path: Option<String>,
+    pub timestamp: u64,
+}
+
+impl FileComplete {
+    pub fn new(transfer_id: String, peer_id: String, path: Option<&Path>) -> Self {
+        let path = path.map(|path| path.display().to_string());
+        Self { transfer_id, peer_id, path, timestamp: now_millis() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileFailed {
+    pub transfer_id: String,
+    pub peer_id: String,
+    pub error: String,
+    pub timestamp: u64,
+}
+
+impl FileFailed {
+    pub fn new(transfer_id: String, peer_id: String, error: String) -> Self {
+        Self { transfer_id, peer_id, error, timestamp: now_millis() }
+    }
+}
+
+/// Milliseconds since the Unix epoch `after` from now
+pub fn millis_from_now(after: Duration) -> u64 {
+    now_millis() + after.as_millis() as u64
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
@@ -0,0 +1,241 @@
+// The desktop app's chat history
+//
+// Kept in memory only, newest last, and capped by `Config::message_history`:
+// once full, every new message drops the oldest. Nothing is written to disk.
+
+use crate::events::now_millis;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Where a message is on its way; received messages are always `Received`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    /// Handed to the connection, not yet written
+    Sending,
+    /// Written to the connection
+    Sent,
+    /// The peer confirmed it arrived
+    Delivered,
+    /// Couldn't be sent, or wasn't confirmed in time
+    Failed,
+    Received,
+}
+
+impl MessageStatus {
+    /// Whether a message can go from `self` to `next`: only on its way,
+    /// never back, and nowhere once delivered or failed
+    pub fn can_become(self, next: MessageStatus) -> bool {
+        use MessageStatus::*;
+        matches!((self, next), (Sending, Sent | Delivered | Failed) | (Sent, Delivered | Failed))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChatMessage {
+    pub id: String,
+    /// The conversation it belongs to
+    pub peer_id: String,
+    pub direction: Direction,
+    pub body: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub status: MessageStatus,
+}
+
+impl ChatMessage {
+    /// A message we're about to send, with a new id
+    pub fn sending(peer_id: String, body: String) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            peer_id,
+            direction: Direction::Sent,
+            body,
+            timestamp: now_millis(),
+            status: MessageStatus::Sending,
+        }
+    }
+
+    /// A message that arrived, under the id its `message-received` event had
+    pub fn received(id: String, peer_id: String, body: String, timestamp: u64) -> Self {
+        Self { id, peer_id, direction: Direction::Received, body, timestamp, status: MessageStatus::Received }
+    }
+}
+
+/// The last `limit` messages
+#[derive(Clone, Debug, Default)]
+pub struct MessageHistory {
+    messages: VecDeque<ChatMessage>,
+    limit: usize,
+}
+
+impl MessageHistory {
+    pub fn new(limit: usize) -> Self {
+        Self { messages: VecDeque::new(), limit }
+    }
+
+    /// Change the cap, dropping the oldest messages over it
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        self.messages.push_back(message);
+        self.trim();
+    }
+
+    /// Up to `limit` messages, oldest first, from just before `before_id` or
+    /// from the newest when it's `None`. `None` when `before_id` isn't in
+    /// the history, which may mean it was dropped.
+    pub fn page(&self, limit: usize, before_id: Option<&str>) -> Option<Vec<ChatMessage>> {
+        let end = match before_id {
+            Some(id) => self.position(id)?,
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+        Some(self.messages.range(start..end).cloned().collect())
+    }
+
+    /// Move a message on to `status`; false when it isn't in the history
+    /// or can't go there from where it is, like a confirmation arriving
+    /// after the message was given up on
+    pub fn set_status(&mut self, id: &str, status: MessageStatus) -> bool {
+        match self.position(id) {
+            Some(index) if self.messages[index].status.can_become(status) => {
+                self.messages[index].status = status;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take a message out, as when it turned out it couldn't be sent at all
+    pub fn remove(&mut self, id: &str) -> Option<ChatMessage> {
+        let index = self.position(id)?;
+        self.messages.remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        // Recent messages are the ones asked about
+        self.messages.iter().rposition(|message| message.id == id)
+    }
+
+    fn trim(&mut self) {
+        let excess = self.messages.len().saturating_sub(self.limit);
+        self.messages.drain(..excess);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_of(count: usize, limit: usize) -> MessageHistory {
+        let mut history = MessageHistory::new(limit);
+        for n in 0..count {
+            history.push(ChatMessage::received(n.to_string(), "peer".to_string(), format!("message {}", n), n as u64));
+        }
+        history
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|message| message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_history_drops_the_oldest_past_its_limit() {
+        let mut history = history_of(5, 3);
+        assert_eq!(ids(&history.page(10, None).unwrap()), ["2", "3", "4"]);
+
+        history.set_limit(1);
+        assert_eq!(ids(&history.page(10, None).unwrap()), ["4"]);
+        history.set_limit(0);
+        history.push(ChatMessage::sending("peer".to_string(), "kept nowhere".to_string()));
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_history_pages_back_from_an_id() {
+        let history = history_of(10, 100);
+        assert_eq!(ids(&history.page(3, None).unwrap()), ["7", "8", "9"]);
+        assert_eq!(ids(&history.page(3, Some("7")).unwrap()), ["4", "5", "6"]);
+        assert_eq!(ids(&history.page(3, Some("2")).unwrap()), ["0", "1"]);
+        assert!(history.page(3, Some("0")).unwrap().is_empty());
+        assert!(history.page(3, Some("unknown")).is_none());
+
+        // Paging from a message that has since been dropped
+        let history = history_of(10, 5);
+        assert!(history.page(3, Some("2")).is_none());
+    }
+
+    #[test]
+    fn test_status_updates_change_the_entry_in_place() {
+        let mut history = history_of(2, 10);
+        let message = ChatMessage::sending("peer".to_string(), "hello".to_string());
+        let id = message.id.clone();
+        history.push(message);
+        history.push(ChatMessage::received("later".to_string(), "peer".to_string(), "hi".to_string(), 0));
+
+        for status in [MessageStatus::Sent, MessageStatus::Delivered] {
+            assert!(history.set_status(&id, status));
+            let page = history.page(10, None).unwrap();
+            assert_eq!(page.len(), 4);
+            assert_eq!(page[2].status, status);
+            assert_eq!(page[2].body, "hello");
+        }
+        assert!(!history.set_status("unknown", MessageStatus::Failed));
+    }
+
+    #[test]
+    fn test_status_only_moves_on() {
+        let mut history = MessageHistory::new(10);
+        let sent = |history: &mut MessageHistory| {
+            let message = ChatMessage::sending("peer".to_string(), "hello".to_string());
+            let id = message.id.clone();
+            history.push(message);
+            assert!(history.set_status(&id, MessageStatus::Sent));
+            id
+        };
+
+        // Sent, then delivered: a late timeout doesn't undo it
+        let delivered = sent(&mut history);
+        assert!(history.set_status(&delivered, MessageStatus::Delivered));
+        assert!(!history.set_status(&delivered, MessageStatus::Failed));
+        assert!(!history.set_status(&delivered, MessageStatus::Sent));
+
+        // Sent, then failed: a late confirmation doesn't either
+        let failed = sent(&mut history);
+        assert!(history.set_status(&failed, MessageStatus::Failed));
+        assert!(!history.set_status(&failed, MessageStatus::Delivered));
+
+        let received = ChatMessage::received("in".to_string(), "peer".to_string(), "hi".to_string(), 0);
+        history.push(received);
+        assert!(!history.set_status("in", MessageStatus::Delivered));
+
+        let statuses: Vec<_> = history.page(10, None).unwrap().iter().map(|message| message.status).collect();
+        assert_eq!(statuses, [MessageStatus::Delivered, MessageStatus::Failed, MessageStatus::Received]);
+        assert_eq!(history.remove(&failed).map(|message| message.status), Some(MessageStatus::Failed));
+        assert_eq!(history.remove(&failed), None);
+        assert_eq!(history.len(), 2);
+    }
+}
@@ -0,0 +1,294 @@
+// Invite codes, for pairing without reading out an `ip:port`
+//
+// An invite carries the addresses we can be reached at, best first, the
+// session fingerprint for the peer to compare once connected, a random
+// pairing secret, and when it stops being good. It goes over as `SILENCE-`
+// and Crockford base32, which survives being retyped: case doesn't matter,
+// nor do dashes or spaces. The same code goes in a QR code, to scan instead.
+//
+// The peer connecting with an invite hands its pairing secret back, and the
+// inviting side takes each secret once, until the invite expires; a peer
+// presenting one that's used up, expired or was never handed out is hung up
+// on. Connecting without an invite goes on as before.
+//
+// Layout before encoding: version, expiry as big-endian Unix seconds, the
+// 32 fingerprint bytes, the 16 pairing secret bytes, the address count,
+// each address as type (4 or 6), IP octets and big-endian port, then the
+// first 4 bytes of the SHA-256 of all that.
+
+use crate::crypto::Fingerprint;
+use base64::Engine as _;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Leads every invite code
+pub const INVITE_PREFIX: &str = "SILENCE-";
+
+/// How long a new invite stays good
+pub const INVITE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Most addresses an invite carries
+pub const MAX_INVITE_ADDRESSES: usize = 8;
+
+/// Bytes of an invite's pairing secret
+pub const PAIRING_SECRET_LEN: usize = 16;
+
+/// Smallest side of an invite's QR code, in pixels
+const QR_CODE_SIZE: u32 = 256;
+
+const INVITE_VERSION: u8 = 1;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// Where to connect, best first
+    pub addresses: Vec<SocketAddr>,
+    /// The inviting side's session fingerprint
+    pub fingerprint: Fingerprint,
+    /// Handed back by the peer connecting with the invite, to show it was
+    /// given it; good once
+    pub secret: [u8; PAIRING_SECRET_LEN],
+    /// Unix seconds after which the invite is refused
+    pub expires_at: u64,
+}
+
+/// Why an invite code was refused
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InviteError {
+    /// Not an invite code, or cut short
+    Malformed,
+    /// The code was mistyped or changed
+    Checksum,
+    /// Made by a newer version of the app
+    UnsupportedVersion { version: u8 },
+    Expired { expires_at: u64 },
+    NoAddresses,
+    /// None of the invite's addresses could be connected to
+    Unreachable { error: String },
+}
+
+impl std::fmt::Display for InviteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InviteError::Malformed => write!(f, "Not an invite code"),
+            InviteError::Checksum => write!(f, "Invite code is mistyped"),
+            InviteError::UnsupportedVersion { version } => {
+                write!(f, "Invite code version {} needs a newer version of the app", version)
+            }
+            InviteError::Expired { .. } => write!(f, "Invite code has expired"),
+            InviteError::NoAddresses => write!(f, "Invite code has no addresses"),
+            InviteError::Unreachable { error } => write!(f, "Couldn't reach the inviting peer: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+impl Invite {
+    /// An invite to `addresses` with a new pairing secret, good for
+    /// `valid_for` from now; only the first `MAX_INVITE_ADDRESSES` are kept
+    pub fn new(mut addresses: Vec<SocketAddr>, fingerprint: Fingerprint, valid_for: Duration) -> Self {
+        addresses.truncate(MAX_INVITE_ADDRESSES);
+        Self { addresses, fingerprint, secret: rand::random(), expires_at: unix_now() + valid_for.as_secs() }
+    }
+
+    /// The code as a QR code, an SVG image in a `data:` URI for an `<img>`
+    pub fn qr_code(&self) -> String {
+        // A few hundred characters at most, which any QR code version past
+        // the smallest holds
+        let code = QrCode::new(self.encode()).expect("invite codes fit a QR code");
+        let image = code.render::<svg::Color>().min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE).build();
+        format!("data:image/svg+xml;base64,{}", base64::engine::general_purpose::STANDARD.encode(image))
+    }
+
+    pub fn encode(&self) -> String {
+        let mut data = vec![INVITE_VERSION];
+        data.extend_from_slice(&self.expires_at.to_be_bytes());
+        data.extend_from_slice(self.fingerprint.as_bytes());
+        data.extend_from_slice(&self.secret);
+        data.push(self.addresses.len() as u8);
+        for addr in &self.addresses {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    data.push(4);
+                    data.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    data.push(6);
+                    data.extend_from_slice(&ip.octets());
+                }
+            }
+            data.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        let checksum = checksum(&data);
+        data.extend_from_slice(&checksum);
+        format!("{}{}", INVITE_PREFIX, base32::encode(base32::Alphabet::Crockford, &data))
+    }
+
+    /// Read an invite code, refusing expired ones
+    pub fn decode(code: &str) -> Result<Self, InviteError> {
+        Self::decode_at(code, unix_now())
+    }
+
+    fn decode_at(code: &str, now: u64) -> Result<Self, InviteError> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        let prefix: String = INVITE_PREFIX.chars().filter(|c| *c != '-').collect();
+        let body = match code.get(..prefix.len()) {
+            Some(start) if start.eq_ignore_ascii_case(&prefix) => &code[prefix.len()..],
+            _ => return Err(InviteError::Malformed),
+        };
+        let data = base32::decode(base32::Alphabet::Crockford, body).ok_or(InviteError::Malformed)?;
+        if data.len() < CHECKSUM_LEN {
+            return Err(InviteError::Malformed);
+        }
+        let (data, sum) = data.split_at(data.len() - CHECKSUM_LEN);
+        if checksum(data) != sum {
+            return Err(InviteError::Checksum);
+        }
+
+        let mut reader = Reader(data);
+        let version = reader.byte()?;
+        if version != INVITE_VERSION {
+            return Err(InviteError::UnsupportedVersion { version });
+        }
+        let expires_at = u64::from_be_bytes(reader.array()?);
+        let fingerprint = Fingerprint::from_bytes(reader.array()?);
+        let secret = reader.array()?;
+        let count = reader.byte()?;
+        let mut addresses = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let ip = match reader.byte()? {
+                4 => IpAddr::V4(Ipv4Addr::from(reader.array::<4>()?)),
+                6 => IpAddr::V6(Ipv6Addr::from(reader.array::<16>()?)),
+                _ => return Err(InviteError::Malformed),
+            };
+            addresses.push(SocketAddr::new(ip, u16::from_be_bytes(reader.array()?)));
+        }
+        if !reader.0.is_empty() {
+            return Err(InviteError::Malformed);
+        }
+
+        if now > expires_at {
+            return Err(InviteError::Expired { expires_at });
+        }
+        if addresses.is_empty() {
+            return Err(InviteError::NoAddresses);
+        }
+        Ok(Self { addresses, fingerprint, secret, expires_at })
+    }
+}
+
+/// What's left of an invite being read
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, InviteError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], InviteError> {
+        if self.0.len() < N {
+            return Err(InviteError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(taken.try_into().unwrap())
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(data)[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SilenceCrypto;
+    use crate::network::{P2PConnection, P2PServer};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn invite() -> Invite {
+        let addresses = vec!["192.168.1.20:7642".parse().unwrap(), "[2001:db8::20]:7642".parse().unwrap()];
+        Invite::new(addresses, SilenceCrypto::new(60).unwrap().fingerprint(), INVITE_LIFETIME)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let invite = invite();
+        let code = invite.encode();
+        assert!(code.starts_with(INVITE_PREFIX));
+        assert_eq!(Invite::decode(&code).unwrap(), invite);
+
+        // Retyped in lower case, in groups
+        let retyped = code.to_lowercase().chars().collect::<Vec<_>>().chunks(5).map(String::from_iter).collect::<Vec<_>>();
+        assert_eq!(Invite::decode(&retyped.join(" ")).unwrap(), invite);
+    }
+
+    #[test]
+    fn test_each_invite_has_its_own_secret() {
+        let (first, second) = (invite(), invite());
+        assert_ne!(first.secret, second.secret);
+        assert_eq!(Invite::decode(&first.encode()).unwrap().secret, first.secret);
+    }
+
+    #[test]
+    fn test_qr_code_is_an_svg_data_uri() {
+        let qr_code = invite().qr_code();
+        let image = qr_code.strip_prefix("data:image/svg+xml;base64,").unwrap();
+        let image = String::from_utf8(base64::engine::general_purpose::STANDARD.decode(image).unwrap()).unwrap();
+        assert!(image.starts_with("<?xml"), "{}", image);
+        assert!(image.contains("<svg"), "{}", image);
+    }
+
+    #[test]
+    fn test_refused_codes() {
+        let invite = invite();
+        let code = invite.encode();
+        assert_eq!(
+            Invite::decode_at(&code, invite.expires_at + 1),
+            Err(InviteError::Expired { expires_at: invite.expires_at })
+        );
+
+        assert_eq!(Invite::decode("192.168.1.20:7642"), Err(InviteError::Malformed));
+        assert!(Invite::decode(&code[..code.len() - 10]).is_err());
+        let mut mistyped = code.into_bytes();
+        mistyped[20] = if mistyped[20] == b'A' { b'B' } else { b'A' };
+        assert_eq!(Invite::decode(&String::from_utf8(mistyped).unwrap()), Err(InviteError::Checksum));
+
+        let empty = Invite { addresses: Vec::new(), ..invite };
+        assert_eq!(Invite::decode(&empty.encode()), Err(InviteError::NoAddresses));
+    }
+
+    #[test]
+    fn test_error_payload() {
+        assert_eq!(
+            serde_json::to_value(InviteError::Expired { expires_at: 1700000000 }).unwrap(),
+            serde_json::json!({ "kind": "expired", "expires_at": 1700000000 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connecting_through_an_invite() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 4096).await.unwrap();
+        let fingerprint = crypto.lock().await.fingerprint();
+        let code = Invite::new(vec![server.local_addr().unwrap()], fingerprint, INVITE_LIFETIME).encode();
+
+        let invite = Invite::decode(&code).unwrap();
+        assert_eq!(invite.fingerprint, fingerprint);
+        let mut client = P2PConnection::connect(invite.addresses[0], crypto, 4096, false).await.unwrap();
+        let mut accepted = server.accept().await.unwrap();
+        client.send_text("paired").await.unwrap();
+        assert_eq!(accepted.receive_message().await.unwrap().unwrap(), "paired");
+    }
+}
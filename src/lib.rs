@@ -1,42 +1,203 @@
 // Silence Crypto - Ephemeral Key Cascade P2P Communication Library
 
+//! Ephemeral key cascade encryption, and the P2P and relay networking built
+//! on it.
+//!
+//! # Features
+//!
+//! | feature   | default | enables                                              |
+//! |-----------|---------|------------------------------------------------------|
+//! | `network` | yes     | `config`, `network` and `reload`: settings files, peer and relay connections, live reload; pulls in tokio, bincode and uuid |
+//! | `app`     | yes     | the Tauri desktop app binary, its `logging` setup, the `events` it sends the GUI, its connection `session`, connection `status`, chat `history`, the local `addresses` it shares, the `invite` codes it pairs with, the `portmap` mappings on the router for its listen port, the `relays` reachability it shows, the `diagnostics` bundles for bug reports and the panic `wipe`; implies `network` |
+//! | `clap`    | no      | `clap::ValueEnum` for `ConnectionMode`               |
+//!
+//! With `default-features = false` only the crypto core is left:
+//! `SilenceCrypto`, `EphemeralKeys`, `ConnectionMode`, `Error` and the
+//! relay wire versions in `protocol`, needing nothing beyond
+//! chacha20poly1305, hkdf, sha2, rand and serde.
+
+#[cfg(feature = "app")]
+pub mod addresses;
+#[cfg(feature = "network")]
+pub mod config;
 pub mod crypto;
+#[cfg(feature = "app")]
+pub mod diagnostics;
+pub mod error;
+#[cfg(feature = "app")]
+pub mod events;
+#[cfg(feature = "app")]
+pub mod history;
+#[cfg(feature = "app")]
+pub mod invite;
+#[cfg(feature = "network")]
 pub mod network;
+#[cfg(feature = "app")]
+pub mod portmap;
+pub mod protocol;
+#[cfg(feature = "network")]
+pub mod reload;
+#[cfg(feature = "app")]
+pub mod relays;
+#[cfg(feature = "app")]
+pub mod session;
+#[cfg(feature = "app")]
+pub mod status;
+#[cfg(feature = "app")]
+pub mod logging;
+#[cfg(feature = "app")]
+pub mod wipe;
+
+#[cfg(feature = "network")]
+pub use config::{Config, ConfigBuilder, ConfigError, LoadedConfig, Profile, ProfileSettings, RelayEntry, SettingsPatch};
+pub use crypto::{CryptoError, EncryptedMessage, Fingerprint, SilenceCrypto, ENCRYPTION_OVERHEAD, MIN_ROTATION_INTERVAL};
+pub use error::{Error, Result};
+#[cfg(feature = "network")]
+pub use network::{
+    ConnectionManager, ConnectionReader, ConnectionStats, ConnectionWriter, FileChunk, FileMessage, FileOffer, NetworkError,
+    P2PConnection, P2PServer, PeerMessage, RelayHello, RelayProbe, RelayRejectReason, DEFAULT_RELAY_SESSION, MESSAGE_OVERHEAD,
+    RELAY_MESSAGE_OVERHEAD,
+};
+#[cfg(feature = "network")]
+pub use reload::{spawn_key_rotation, spawn_key_rotation_with, ConfigChange, ConfigHandle, ConfigWatcher, Rotation};
 
-pub use crypto::*;
-pub use network::*;
+/// What most applications need, for a single glob import:
+/// `use silence::prelude::*;`
+pub mod prelude {
+    pub use crate::{ConnectionMode, Error, SilenceCrypto};
+    #[cfg(feature = "network")]
+    pub use crate::{Config, ConnectionManager, P2PConnection};
+}
 
 /// Connection mode for P2P communication
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+///
+/// Written `auto`, `direct_only` or `relay_only`; parsing also takes `direct`
+/// and `relay`, ignores case and accepts `-` for `_`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "clap", value(rename_all = "snake_case"))]
 pub enum ConnectionMode {
     /// Try direct P2P first, fallback to relay
+    #[default]
     Auto,
     /// Direct P2P connection only
+    #[cfg_attr(feature = "clap", value(alias = "direct", alias = "direct-only"))]
     DirectOnly,
     /// Relay connection only
+    #[cfg_attr(feature = "clap", value(alias = "relay", alias = "relay-only"))]
     RelayOnly,
 }
 
-/// Application configuration
-#[derive(Clone)]
-pub struct Config {
-    pub listen_port: u16,
-    pub key_rotation_interval: u64,
-    pub max_message_size: usize,
-    pub connection_timeout: u64,
-    pub relay_servers: Vec<String>,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            listen_port: 7642,
-            key_rotation_interval: 15, // seconds
-            max_message_size: 4096,    // 4KB
-            connection_timeout: 30,    // seconds
-            relay_servers: vec![
-                "185.191.116.220:8080".to_string(),
-            ],
+impl ConnectionMode {
+    /// Canonical name, as written in config files
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::DirectOnly => "direct_only",
+            Self::RelayOnly => "relay_only",
         }
     }
-}
\ No newline at end of file
+}
+
+impl std::fmt::Display for ConnectionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ConnectionMode {
+    type Err = ParseConnectionModeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "auto" => Ok(Self::Auto),
+            "direct" | "direct_only" => Ok(Self::DirectOnly),
+            "relay" | "relay_only" => Ok(Self::RelayOnly),
+            _ => Err(ParseConnectionModeError { value: value.to_string() }),
+        }
+    }
+}
+
+impl TryFrom<String> for ConnectionMode {
+    type Error = ParseConnectionModeError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A string that names no connection mode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseConnectionModeError {
+    pub value: String,
+}
+
+impl std::fmt::Display for ParseConnectionModeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not a connection mode: {:?}, expected auto, direct_only or relay_only", self.value)
+    }
+}
+
+impl std::error::Error for ParseConnectionModeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_mode_spellings() {
+        let cases = [
+            ("auto", ConnectionMode::Auto),
+            ("AUTO", ConnectionMode::Auto),
+            ("direct", ConnectionMode::DirectOnly),
+            ("direct_only", ConnectionMode::DirectOnly),
+            ("Direct-Only", ConnectionMode::DirectOnly),
+            ("relay", ConnectionMode::RelayOnly),
+            (" relay_only ", ConnectionMode::RelayOnly),
+            ("RELAY-ONLY", ConnectionMode::RelayOnly),
+        ];
+        for (text, mode) in cases {
+            assert_eq!(text.parse::<ConnectionMode>(), Ok(mode), "{}", text);
+        }
+        for mode in [ConnectionMode::Auto, ConnectionMode::DirectOnly, ConnectionMode::RelayOnly] {
+            assert_eq!(mode.to_string().parse::<ConnectionMode>(), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn test_unknown_connection_mode_is_an_error() {
+        for text in ["", "dirct", "relayonly", "p2p"] {
+            let error = text.parse::<ConnectionMode>().unwrap_err();
+            assert_eq!(error.value, text);
+            assert!(error.to_string().contains("expected auto, direct_only or relay_only"), "{}", error);
+        }
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_connection_mode_as_cli_argument() {
+        use clap::ValueEnum;
+        for (text, mode) in [("direct_only", ConnectionMode::DirectOnly), ("relay", ConnectionMode::RelayOnly)] {
+            assert_eq!(ConnectionMode::from_str(text, true), Ok(mode));
+        }
+        let names: Vec<_> = ConnectionMode::value_variants()
+            .iter()
+            .map(|mode| mode.to_possible_value().unwrap().get_name().to_string())
+            .collect();
+        assert_eq!(names, ["auto", "direct_only", "relay_only"]);
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_connection_mode_serde_uses_the_same_names() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct File {
+            mode: ConnectionMode,
+        }
+        let file: File = toml::from_str("mode = \"Relay\"").unwrap();
+        assert_eq!(file.mode, ConnectionMode::RelayOnly);
+        assert_eq!(toml::to_string(&file).unwrap().trim(), "mode = \"relay_only\"");
+        assert!(toml::from_str::<File>("mode = \"sometimes\"").is_err());
+    }
+}
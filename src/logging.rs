@@ -0,0 +1,167 @@
+// Log output for the desktop app
+//
+// The library itself only emits `tracing` events and leaves it to whoever
+// embeds it to collect them. The app collects them here: into a log file
+// under the platform's local data directory, rotated daily, and, for
+// warnings and errors, into a channel the app forwards to the GUI. A
+// windowed build has no console, so neither may depend on stdout or stderr.
+//
+// RUST_LOG picks what gets logged, `info` and up when it isn't set.
+
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Log files kept before the oldest is deleted, one per day
+const KEPT_LOG_FILES: usize = 7;
+
+/// A warning or error, as sent to the GUI
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LogEvent {
+    pub level: String,
+    pub target: String,
+    /// The event's message followed by its fields, `key=value`
+    pub message: String,
+}
+
+/// Keeps the log file written; dropping it flushes what's buffered
+pub struct Logging {
+    /// Warnings and errors, for showing in the GUI
+    pub events: mpsc::UnboundedReceiver<LogEvent>,
+    _file: Option<WorkerGuard>,
+}
+
+/// Where the app keeps its logs: `silence/logs` under the platform's local
+/// data directory (`~/.local/share` on Linux), if it has one
+pub fn log_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("silence").join("logs"))
+}
+
+/// Install the app's subscriber process-wide, logging to `dir` when given.
+/// A log directory that can't be created leaves file logging off, with a
+/// warning through the GUI channel.
+pub fn init(dir: Option<&Path>) -> Logging {
+    let (subscriber, logging) = subscriber(dir);
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        tracing::warn!("A tracing subscriber was already installed; app logging is off");
+    }
+    logging
+}
+
+/// The subscriber `init` installs, for installing some other way
+pub fn subscriber(dir: Option<&Path>) -> (impl Subscriber + Send + Sync, Logging) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (sender, events) = mpsc::unbounded_channel();
+
+    let mut file_error = None;
+    let file = dir.and_then(|dir| {
+        RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("silence")
+            .filename_suffix("log")
+            .max_log_files(KEPT_LOG_FILES)
+            .build(dir)
+            .map_err(|e| file_error = Some(format!("Can't log to {}: {}", dir.display(), e)))
+            .ok()
+    });
+    let (file_layer, guard) = match file {
+        Some(appender) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(false);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    if let Some(message) = file_error {
+        let _ = sender.send(LogEvent { level: Level::WARN.to_string(), target: module_path!().to_string(), message });
+    }
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(file_layer)
+        .with(ForwardLayer { events: sender });
+    (subscriber, Logging { events, _file: guard })
+}
+
+/// Sends warnings and errors on to the GUI
+struct ForwardLayer {
+    events: mpsc::UnboundedSender<LogEvent>,
+}
+
+impl<S: Subscriber> Layer<S> for ForwardLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // Levels compare by verbosity, so this skips info and below
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+        // Nobody listening any more is fine
+        let _ = self.events.send(LogEvent {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: message.text,
+        });
+    }
+}
+
+/// Formats an event's message, then its other fields
+#[derive(Default)]
+struct MessageVisitor {
+    text: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.text);
+            let _ = write!(self.text, "{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.text, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.text.insert_str(0, value);
+        } else {
+            let _ = write!(self.text, " {}={}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_reach_the_file_and_warnings_the_gui() {
+        let dir = std::env::temp_dir().join(format!("silence-logs-{}", uuid::Uuid::new_v4()));
+        let (subscriber, mut logging) = subscriber(Some(&dir));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(peer = "127.0.0.1:7642", "Peer connected");
+            tracing::warn!(relay = "relay.example:8080", error = %"refused", "Relay failed");
+        });
+        let forwarded = logging.events.try_recv().unwrap();
+        assert_eq!(forwarded.level, "WARN");
+        assert_eq!(forwarded.message, "Relay failed relay=relay.example:8080 error=refused");
+        assert!(logging.events.try_recv().is_err(), "info stays out of the GUI");
+
+        // Dropping the guard flushes the file
+        drop(logging);
+        let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let text = std::fs::read_to_string(&file).unwrap();
+        assert!(text.contains("Peer connected peer=\"127.0.0.1:7642\""), "{}", text);
+        assert!(text.contains("Relay failed"), "{}", text);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,165 +1,942 @@
 // Silence Crypto - Main Application Entry Point
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Mutex;
-use tauri::{command, generate_handler, Builder, State};
+use tokio::task::AbortHandle;
+use tauri::{command, generate_handler, AppHandle, Builder, Emitter, Manager, RunEvent, State};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use silence::{
-    SilenceCrypto, 
-    P2PConnection, 
-    ConnectionManager,
-    Config
-};
+use silence::prelude::*;
+use silence::addresses::{self, LocalAddresses};
+use silence::diagnostics::{Bundle, Redactor, DEFAULT_LOG_HOURS};
+use silence::events::{self, DisconnectReason};
+use silence::history::{ChatMessage, MessageHistory, MessageStatus};
+use silence::session::{ConnectionInfo, FileError, Inbox, LastPeer, MessageBudget, Origin, PeerInfo, Peers, Received, RotateError, RotationReport, SendError, DELIVERY_TIMEOUT, LAST_PEER_FILE, PING_INTERVAL, ROTATION_ACK_TIMEOUT};
+use silence::invite::{Invite, InviteError, INVITE_LIFETIME};
+use silence::portmap::{Gateways, MappedPort, PortMapping, MAPPING_LIFETIME, MAPPING_TIMEOUT};
+use silence::relays::{RelayStatus, RelayStatusCache};
+use silence::status::{Change, ConnectionStatus};
+use silence::wipe;
+use silence::{ConfigChange, ConfigHandle, Rotation, SettingsPatch};
+
+/// How long the listener waits after a failed accept before the next
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 /// Application state shared across Tauri commands
 #[derive(Clone)]
 pub struct AppState {
     crypto: Arc<Mutex<SilenceCrypto>>,
     connection_manager: Arc<ConnectionManager>,
-    active_connection: Arc<Mutex<Option<P2PConnection>>>,
-    config: Config,
+    /// Open conversations, by peer id
+    peers: Peers,
+    config: ConfigHandle,
+    /// Where settings changed in the app are saved, if the platform has a
+    /// config directory
+    config_path: Option<PathBuf>,
+    /// Messages sent and received, newest last
+    history: Arc<Mutex<MessageHistory>>,
+    /// The last peer we dialed, and where it's saved if anywhere
+    last_peer: Arc<std::sync::Mutex<Option<LastPeer>>>,
+    last_peer_path: Option<PathBuf>,
+    /// Listeners started, to stop on a wipe
+    listeners: Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+    /// The listen port's mapping on the router, while listening with
+    /// `port_mapping` on
+    port_mapping: Arc<Mutex<Option<MappedPort>>>,
+    /// The relays' reachability, as `get_relay_status` last probed it
+    relay_status: Arc<RelayStatusCache>,
+    /// Last sent in a `connection-status` event
+    status: Arc<std::sync::Mutex<ConnectionStatus>>,
 }
 
-/// Tauri command to connect to a peer
+/// Tauri command to connect to a peer, returning its peer id and how the
+/// connection was made
 #[command]
 async fn connect_to_peer(
     address: String,
-    mode: String,
+    mode: Option<String>,
+    app: AppHandle,
     state: State<'_, AppState>,
-) -> Result<String, String> {
+) -> Result<ConnectionInfo, String> {
     let addr: SocketAddr = address.parse()
         .map_err(|e| format!("Invalid address format: {}", e))?;
     
-    // Parse connection mode
-    let connection_mode = match mode.as_str() {
-        "direct" => silence::ConnectionMode::DirectOnly,
-        "relay" => silence::ConnectionMode::RelayOnly,
-        _ => silence::ConnectionMode::Auto, // default
+    // Without a mode the configured default applies
+    let connection_mode = match mode.as_deref().map(str::trim) {
+        None | Some("") => state.config.current().connection_mode,
+        Some(mode) => mode.parse::<ConnectionMode>().map_err(|e| e.to_string())?,
     };
     
-    let connection = state.connection_manager
-        .connect_with_mode(addr, connection_mode)
-        .await
-        .map_err(|e| format!("Connection failed: {}", e))?;
+    update_status(&app, &state, Change::Connecting { peer: addr, mode: connection_mode });
+    let connection = match state.connection_manager.connect_with_mode(addr, connection_mode).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            update_status(&app, &state, Change::ConnectFailed { error: e.to_string() });
+            return Err(format!("Connection failed: {}", e));
+        }
+    };
     
-    // Store the active connection and start message receiving
-    {
-        let mut active_conn = state.active_connection.lock().await;
-        *active_conn = Some(connection);
-    }
+    // Store the connection and start message receiving
+    let relay = connection.relay_hello().is_some();
+    remember_peer(&state, LastPeer::new(addr, connection_mode, relay));
+    let (peer_id, inbox) = state.peers.add(connection, Origin::Outbound);
+    let fingerprint = state.crypto.lock().await.fingerprint();
+    let info = state.peers.connection_info(&peer_id, addr, fingerprint).ok_or_else(|| "Connection lost".to_string())?;
+    tracing::info!(peer = %addr, %peer_id, mode = %info.mode_used, "Connected");
+    announce_connected(&app, &state, &peer_id, addr, relay, Origin::Outbound);
+    introduce(&state, &peer_id).await;
+    tokio::spawn(receive_messages(app, AppState::clone(&state), peer_id, inbox));
     
-    // Start message receiving loop for client connection
-    let active_connection = Arc::clone(&state.active_connection);
-    tokio::spawn(async move {
-        loop {
-            let mut active_conn = active_connection.lock().await;
-            if let Some(ref mut conn) = active_conn.as_mut() {
-                match conn.receive_message().await {
-                    Ok(Some(message)) => {
-                        println!("Received message: {}", message);
-                        // TODO: Forward message to GUI via Tauri events
-                    }
-                    Ok(None) => {
-                        // Connection closed
-                        println!("Connection closed by peer");
-                        *active_conn = None;
-                        break;
-                    }
-                    Err(e) => {
-                        eprintln!("Receive error: {}", e);
-                        *active_conn = None;
-                        break;
-                    }
-                }
-            } else {
-                break;
-            }
+    Ok(info)
+}
+
+/// Tauri command to dial the last peer connected to again, the same way
+#[command]
+async fn reconnect_last_peer(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<ConnectionInfo, String> {
+    let last = state.last_peer.lock().unwrap().clone().ok_or_else(|| "No previous peer".to_string())?;
+    let mode = last.mode()?;
+    connect_to_peer(last.address.to_string(), Some(mode.to_string()), app, state).await
+}
+
+/// Tauri command to get the last peer connected to, for the connect dialog
+#[command]
+async fn get_last_peer(
+    state: State<'_, AppState>,
+) -> Result<Option<LastPeer>, String> {
+    Ok(state.last_peer.lock().unwrap().clone())
+}
+
+fn remember_peer(state: &AppState, last: LastPeer) {
+    if let Some(path) = &state.last_peer_path {
+        if let Err(e) = last.save(path) {
+            tracing::warn!(error = %e, "Couldn't save the last peer");
         }
-    });
-    
-    Ok(format!("Connected to {}", address))
+    }
+    *state.last_peer.lock().unwrap() = Some(last);
 }
 
 /// Tauri command to start listening for connections
 #[command]
 async fn start_listening(
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<String, String> {
-    let bind_addr = format!("0.0.0.0:{}", state.config.listen_port)
+    let listen_port = state.config.current().listen_port;
+    let bind_addr = format!("0.0.0.0:{}", listen_port)
         .parse::<SocketAddr>()
         .map_err(|e| format!("Invalid bind address: {}", e))?;
     
-    // Start server in background task to accept incoming connection
-    let connection_manager = Arc::clone(&state.connection_manager);
-    let active_connection = Arc::clone(&state.active_connection);
+    if is_listening(&state) {
+        return Err("Already listening".to_string());
+    }
+    let state = AppState::clone(&state);
+    update_status(&app, &state, Change::Listening);
+    let server = match state.connection_manager.listen(bind_addr).await {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!(error = %e, "Server error");
+            update_status(&app, &state, Change::ConnectFailed { error: e.to_string() });
+            return Err(format!("Listen failed: {}", e));
+        }
+    };
     
-    tokio::spawn(async move {
-        match connection_manager.start_server(bind_addr).await {
-            Ok(connection) => {
-                println!("Peer connected successfully");
-                
-                // Store the connection
-                {
-                    let mut active_conn = active_connection.lock().await;
-                    *active_conn = Some(connection);
+    // Keep accepting in the background, alongside any connections we make
+    let listeners = Arc::clone(&state.listeners);
+    let mapping = state.config.current().port_mapping.then(|| (app.clone(), AppState::clone(&state)));
+    let listener = tokio::spawn(async move {
+        loop {
+            let connection = match state.connection_manager.accept(&server).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Accepting a connection failed");
+                    // Don't spin on an error that persists, like running out of file descriptors
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
                 }
-                
-                // Start message receiving loop
-                loop {
-                    let mut active_conn = active_connection.lock().await;
-                    if let Some(ref mut conn) = active_conn.as_mut() {
-                        match conn.receive_message().await {
-                            Ok(Some(message)) => {
-                                println!("Received message: {}", message);
-                                // TODO: Forward message to GUI via Tauri events
-                            }
-                            Ok(None) => {
-                                // Connection closed
-                                println!("Connection closed by peer");
-                                *active_conn = None;
-                                break;
-                            }
-                            Err(e) => {
-                                eprintln!("Receive error: {}", e);
-                                *active_conn = None;
-                                break;
-                            }
-                        }
-                    } else {
-                        break;
-                    }
+            };
+            let peer = connection.peer_addr();
+            let relay = connection.relay_hello().is_some();
+            
+            // Store the connection
+            let (peer_id, inbox) = state.peers.add(connection, Origin::Inbound);
+            tracing::info!(%peer, %peer_id, "Peer connected successfully");
+            announce_connected(&app, &state, &peer_id, peer, relay, Origin::Inbound);
+            introduce(&state, &peer_id).await;
+            tokio::spawn(receive_messages(app.clone(), AppState::clone(&state), peer_id, inbox));
+        }
+    });
+    let mut listeners = listeners.lock().unwrap();
+    listeners.retain(|listener| !listener.is_finished());
+    listeners.push(listener.abort_handle());
+
+    // The router may take a while to answer, or never will; listening
+    // doesn't wait on it, and goes on without the mapping if it fails
+    if let Some((app, state)) = mapping {
+        tokio::spawn(async move {
+            // Failing is reported to the GUI as it happens
+            let _ = map_port(&app, &state, listen_port).await;
+        });
+    }
+    
+    Ok(format!("Listening on port {}", listen_port))
+}
+
+/// Tauri command to stop accepting connections, taking the listen port's
+/// mapping off the router; connections already made stay up
+#[command]
+async fn stop_listening(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let listeners: Vec<_> = state.listeners.lock().unwrap().drain(..).collect();
+    if listeners.is_empty() {
+        return Err("Not listening".to_string());
+    }
+    for listener in listeners {
+        listener.abort();
+    }
+    unmap_port(&state).await;
+    update_status(&app, &state, Change::StoppedListening);
+    Ok(())
+}
+
+fn is_listening(state: &AppState) -> bool {
+    state.listeners.lock().unwrap().iter().any(|listener| !listener.is_finished())
+}
+
+/// Ask the router to forward `port` to us and keep the mapping renewed,
+/// telling the GUI how that went
+async fn map_port(app: &AppHandle, state: &AppState, port: u16) -> Result<events::PortMapped, String> {
+    let mapping = match PortMapping::create(&Gateways::system(), port, MAPPING_LIFETIME, MAPPING_TIMEOUT).await {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            tracing::warn!(port, error = %e, "Couldn't map the listen port");
+            let _ = app.emit(events::PORT_MAPPING_FAILED, events::PortMappingFailed::new(e.to_string()));
+            return Err(e.to_string());
+        }
+    };
+    let mapped = events::PortMapped::new(&mapping);
+
+    let mut slot = state.port_mapping.lock().await;
+    if !is_listening(state) {
+        // Listening stopped while the router answered
+        drop(slot);
+        if let Err(e) = mapping.remove(MAPPING_TIMEOUT).await {
+            tracing::warn!(error = %e, "Couldn't remove the port mapping");
+        }
+        return Err("Stopped listening before the port was mapped".to_string());
+    }
+    if slot.is_none() {
+        // Otherwise the port was mapped meanwhile, and this is the same mapping
+        let renew_app = app.clone();
+        *slot = Some(MappedPort::start(mapping, move |e| {
+            let _ = renew_app.emit(events::PORT_MAPPING_FAILED, events::PortMappingFailed::new(e.to_string()));
+        }));
+    }
+    drop(slot);
+    let _ = app.emit(events::PORT_MAPPED, mapped.clone());
+    Ok(mapped)
+}
+
+/// Take the listen port's mapping, if any, off the router
+async fn unmap_port(state: &AppState) {
+    if let Some(mapping) = state.port_mapping.lock().await.take() {
+        remove_mapping(mapping).await;
+    }
+}
+
+async fn remove_mapping(mapping: MappedPort) {
+    // The router drops it when the lease runs out regardless
+    match mapping.stop(MAPPING_TIMEOUT).await {
+        Ok(()) => tracing::info!("Port mapping removed"),
+        Err(e) => tracing::warn!(error = %e, "Couldn't remove the port mapping"),
+    }
+}
+
+/// Send peer `peer_id` our hello, so a second connection to it can be told
+/// apart; without one it can't be, which is no reason to hang up
+async fn introduce(state: &AppState, peer_id: &str) {
+    if let Err(e) = state.peers.introduce(peer_id).await {
+        tracing::warn!(%peer_id, error = %e, "Couldn't send the peer our hello");
+    }
+}
+
+/// Receive messages until the connection to `peer_id` ends, passing them on
+/// to the GUI
+async fn receive_messages(app: AppHandle, state: AppState, peer_id: String, mut inbox: Inbox) {
+    loop {
+        match state.peers.receive(&peer_id, &mut inbox).await {
+            Received::Message { from, body } => {
+                tracing::debug!(%peer_id, bytes = body.len(), "Received message");
+                let event = events::MessageReceived::new(peer_id.clone(), from, body);
+                let message = ChatMessage::received(
+                    event.id.clone(),
+                    peer_id.clone(),
+                    event.body.clone(),
+                    event.timestamp,
+                );
+                state.history.lock().await.push(message);
+                state.peers.received(&peer_id, &event.id);
+                let _ = app.emit(events::MESSAGE_RECEIVED, event);
+                emit_peers(&app, &state);
+            }
+            Received::Delivered { message_id } => {
+                set_message_status(&app, &state, &peer_id, &message_id, MessageStatus::Delivered, None).await;
+            }
+            Received::Closed { peer } => {
+                tracing::info!(%peer_id, "Connection closed by peer");
+                emit_disconnected(&app, &state, &peer_id, peer, DisconnectReason::Remote);
+                update_status(&app, &state, Change::Closed { peer_id: peer_id.clone() });
+                break;
+            }
+            Received::Failed { peer, error } => {
+                tracing::error!(%peer_id, %error, "Receive error");
+                let error = error.to_string();
+                let event = events::ReceiveError { peer_id: peer_id.clone(), peer: peer.to_string(), error: error.clone() };
+                let _ = app.emit(events::RECEIVE_ERROR, event);
+                emit_disconnected(&app, &state, &peer_id, peer, DisconnectReason::Error);
+                update_status(&app, &state, Change::ReceiveFailed { peer_id: peer_id.clone(), error });
+                break;
+            }
+            Received::Duplicate { dropped, peer, kept } => {
+                emit_disconnected(&app, &state, &dropped, peer, DisconnectReason::Duplicate);
+                if let Some(kept_peer) = state.peers.info(&kept) {
+                    update_status(&app, &state, Change::Replaced { peer_id: dropped.clone(), by: kept, peer: kept_peer.peer });
+                }
+                if dropped == peer_id {
+                    break;
                 }
             }
-            Err(e) => {
-                eprintln!("Server error: {}", e);
+            // Whoever hung up tells the GUI
+            Received::HungUp => break,
+            Received::FileOffered { offer } => {
+                tracing::info!(%peer_id, transfer_id = %offer.transfer_id, bytes = offer.size, "File offered");
+                let _ = app.emit(events::FILE_OFFER, events::FileOffered::new(peer_id.clone(), offer));
+            }
+            Received::FileProgress { transfer_id, received, size } => {
+                let event = events::FileProgress { transfer_id, peer_id: peer_id.clone(), sent: received, total: size };
+                let _ = app.emit(events::FILE_PROGRESS, event);
+            }
+            Received::FileReceived { transfer_id, path } => {
+                let _ = app.emit(events::FILE_COMPLETE, events::FileComplete::new(transfer_id, peer_id.clone(), Some(path.as_path())));
+            }
+            Received::FileFailed { transfer_id, error } => {
+                let _ = app.emit(events::FILE_FAILED, events::FileFailed::new(transfer_id, peer_id.clone(), error));
+            }
+            Received::FingerprintChanged { peer, previous, fingerprint, verified } => {
+                let event = events::FingerprintChanged {
+                    peer_id: peer_id.clone(),
+                    peer: peer.to_string(),
+                    previous_fingerprint: previous.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                    verified,
+                };
+                let _ = app.emit(events::FINGERPRINT_CHANGED, event);
+                emit_peers(&app, &state);
+            }
+            Received::RoundTrip { .. } => emit_peers(&app, &state),
+            Received::Refused { peer } => {
+                emit_disconnected(&app, &state, &peer_id, peer, DisconnectReason::Refused);
+                update_status(&app, &state, Change::Closed { peer_id: peer_id.clone() });
+                break;
             }
         }
-    });
-    
-    Ok(format!("Listening on port {}", state.config.listen_port))
+    }
+    // Nothing confirms them now
+    for message_id in state.peers.take_undelivered(&peer_id) {
+        let error = Some("Connection ended".to_string());
+        set_message_status(&app, &state, &peer_id, &message_id, MessageStatus::Failed, error).await;
+    }
+    // Nor does any more of these come
+    for transfer_id in state.peers.take_abandoned_files(&peer_id) {
+        let event = events::FileFailed::new(transfer_id, peer_id.clone(), "Connection ended".to_string());
+        let _ = app.emit(events::FILE_FAILED, event);
+    }
+}
+
+/// Move message `id` on to `status` in the history, telling the GUI when it
+/// moved; a late confirmation doesn't undo a failure or the other way round
+async fn set_message_status(
+    app: &AppHandle,
+    state: &AppState,
+    peer_id: &str,
+    id: &str,
+    status: MessageStatus,
+    error: Option<String>,
+) {
+    if state.history.lock().await.set_status(id, status) {
+        let event = events::MessageStatusChanged::new(id.to_string(), peer_id.to_string(), status, error);
+        let _ = app.emit(events::MESSAGE_STATUS, event);
+    }
+}
+
+fn emit_disconnected(app: &AppHandle, state: &AppState, peer_id: &str, peer: SocketAddr, reason: DisconnectReason) {
+    let event = events::PeerDisconnected { peer_id: peer_id.to_string(), peer: peer.to_string(), reason };
+    let _ = app.emit(events::PEER_DISCONNECTED, event);
+    emit_peers(app, state);
+}
+
+/// Send the GUI the roster, after anything in it changed
+fn emit_peers(app: &AppHandle, state: &AppState) {
+    let _ = app.emit(events::PEERS_CHANGED, roster(state));
+}
+
+/// The connected peers, oldest first, then those disconnected lately, most
+/// recent first
+fn roster(state: &AppState) -> Vec<PeerInfo> {
+    let mut peers = state.peers.list();
+    peers.extend(state.peers.recent());
+    peers
+}
+
+fn announce_connected(app: &AppHandle, state: &AppState, peer_id: &str, peer: SocketAddr, relay: bool, origin: Origin) {
+    let event = events::PeerConnected { peer_id: peer_id.to_string(), peer: peer.to_string(), relay, origin };
+    let _ = app.emit(events::PEER_CONNECTED, event);
+    emit_peers(app, state);
+    update_status(app, state, Change::Connected { peer_id: peer_id.to_string(), peer, relay });
 }
 
-/// Tauri command to send a message
+/// Move the connection status along, telling the GUI when it changes
+fn update_status(app: &AppHandle, state: &AppState, change: Change) {
+    let next = {
+        let mut status = state.status.lock().unwrap();
+        let Some(next) = status.apply(change) else {
+            return;
+        };
+        *status = next.clone();
+        next
+    };
+    let _ = app.emit(events::CONNECTION_STATUS, next);
+}
+
+/// Tauri command to hang up on the most recent peer
+#[command]
+async fn disconnect(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    match state.peers.latest() {
+        Some(peer_id) => disconnect_peer(peer_id, app, state).await,
+        None => Ok("Not connected".to_string()),
+    }
+}
+
+/// Tauri command to hang up on one peer
+#[command]
+async fn disconnect_peer(
+    peer_id: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    match state.peers.disconnect(&peer_id).await {
+        Some(peer) => {
+            tracing::info!(%peer, %peer_id, "Disconnected");
+            emit_disconnected(&app, &state, &peer_id, peer, DisconnectReason::Local);
+            update_status(&app, &state, Change::Closed { peer_id });
+            Ok(format!("Disconnected from {}", peer))
+        }
+        None => Ok("Not connected".to_string()),
+    }
+}
+
+/// Tauri command to destroy the session at once: every connection dropped
+/// without a goodbye, the keys zeroed and replaced, the history, the last
+/// peer and the logs forgotten, and nothing left listening, as `wipe::wipe`
+/// goes about it. Each step goes ahead even if one before it failed; the
+/// `wiped` event lists what did.
+#[command]
+async fn panic_wipe(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<events::Wiped, String> {
+    let wiped = wipe::wipe(wipe::Session {
+        crypto: &state.crypto,
+        config: &state.config.current(),
+        peers: &state.peers,
+        history: &state.history,
+        last_peer: &state.last_peer,
+        last_peer_path: state.last_peer_path.as_deref(),
+        listeners: &state.listeners,
+        port_mapping: &state.port_mapping,
+        log_dir: silence::logging::log_dir().as_deref(),
+    })
+    .await;
+
+    let status = ConnectionStatus::default();
+    *state.status.lock().unwrap() = status.clone();
+    let _ = app.emit(events::CONNECTION_STATUS, status);
+
+    emit_peers(&app, &state);
+    let _ = app.emit(events::WIPED, wiped.clone());
+    Ok(wiped)
+}
+
+/// Tauri command to send a message to the most recent peer
 #[command]
 async fn send_message(
     content: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, SendError> {
+    let peer_id = state.peers.latest().ok_or(SendError::NoConnection)?;
+    send_message_to(peer_id, content, app, state).await
+}
+
+/// Tauri command to send a message to one peer. Answers the message's id
+/// once it's written; `message-status` events with that id follow as the
+/// peer confirms it, or as it's given up on. Nothing is written, and the
+/// history keeps nothing, for an error; text over the connection's
+/// `get_message_budget` is `too_large`.
+#[command]
+async fn send_message_to(
+    peer_id: String,
+    content: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, SendError> {
+    let message = ChatMessage::sending(peer_id.clone(), content);
+    let id = message.id.clone();
+    state.history.lock().await.push(message.clone());
+
+    // Doesn't wait on the receive loop, which has the other half
+    match state.peers.send(&peer_id, &id, &message.body).await {
+        Ok(confirmed) => {
+            set_message_status(&app, &state, &peer_id, &id, MessageStatus::Sent, None).await;
+            if confirmed {
+                let state = AppState::clone(&state);
+                let (app, peer_id, id) = (app.clone(), peer_id.clone(), id.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(DELIVERY_TIMEOUT).await;
+                    if state.peers.expire(&id) {
+                        let error = Some("Not confirmed in time".to_string());
+                        set_message_status(&app, &state, &peer_id, &id, MessageStatus::Failed, error).await;
+                    }
+                });
+            }
+        }
+        // Written in part at most, so it stays in the history as failed
+        Err(SendError::Failed { error }) => {
+            tracing::warn!(%peer_id, %error, "Send failed");
+            set_message_status(&app, &state, &peer_id, &id, MessageStatus::Failed, Some(error)).await;
+        }
+        Err(e) => {
+            state.history.lock().await.remove(&id);
+            return Err(e);
+        }
+    }
+    emit_peers(&app, &state);
+    Ok(id)
+}
+
+/// Tauri command to offer peer `peer_id` the file at `path`, answering the
+/// transfer id. The file goes once the peer takes it, with `file-progress`
+/// events on the way and `file-complete` once it's all sent; `file-failed`
+/// says it was turned down or didn't get through. Files over
+/// `max_file_size` are `too_large`.
+#[command]
+async fn send_file(
+    peer_id: String,
+    path: PathBuf,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, FileError> {
+    let offered = state.peers.offer_file(&peer_id, &path).await?;
+    let transfer_id = offered.transfer_id.clone();
+    tracing::info!(%peer_id, %transfer_id, bytes = offered.size, "File offered to peer");
+    let state = AppState::clone(&state);
+    let (id, total) = (transfer_id.clone(), offered.size);
+    tokio::spawn(async move {
+        let progress = |sent| {
+            let event = events::FileProgress { transfer_id: id.clone(), peer_id: peer_id.clone(), sent, total };
+            let _ = app.emit(events::FILE_PROGRESS, event);
+        };
+        let result = state.peers.send_file(offered, progress).await;
+        match result {
+            Ok(()) => {
+                let _ = app.emit(events::FILE_COMPLETE, events::FileComplete::new(id, peer_id, None));
+            }
+            Err(e) => {
+                tracing::warn!(%peer_id, transfer_id = %id, error = %e, "File not sent");
+                let _ = app.emit(events::FILE_FAILED, events::FileFailed::new(id, peer_id, e.to_string()));
+            }
+        }
+    });
+    Ok(transfer_id)
+}
+
+/// Tauri command to take a file a `file-offer` event offered, saving it in
+/// folder `dest_dir` under its own name, or a free one like it. It comes in
+/// with `file-progress` events and ends with `file-complete`, or with
+/// `file-failed`, which leaves nothing of it behind.
+#[command]
+async fn accept_file(
+    transfer_id: String,
+    dest_dir: PathBuf,
+    state: State<'_, AppState>,
+) -> Result<(), FileError> {
+    state.peers.accept_file(&transfer_id, &dest_dir).await
+}
+
+/// Tauri command to turn down a file a `file-offer` event offered, or to
+/// give up on one coming in, deleting what arrived of it
+#[command]
+async fn reject_file(
+    transfer_id: String,
+    state: State<'_, AppState>,
+) -> Result<(), FileError> {
+    state.peers.reject_file(&transfer_id).await
+}
+
+/// Tauri command to get how many bytes of text a message to `peer_id`, or
+/// to the most recent peer, can hold, for a counter as the user types
+#[command]
+async fn get_message_budget(
+    peer_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<MessageBudget, String> {
+    let peer_id = peer_id.or_else(|| state.peers.latest()).ok_or_else(|| "No active connection".to_string())?;
+    state.peers.budget(&peer_id).ok_or_else(|| "Unknown peer".to_string())
+}
+
+/// Tauri command to list the connected peers, oldest first, then the last
+/// few disconnected, most recent first, with their traffic and round trip;
+/// `peers-changed` events send the same list as it changes
+#[command]
+async fn list_peers(
+    state: State<'_, AppState>,
+) -> Result<Vec<PeerInfo>, String> {
+    Ok(roster(&state))
+}
+
+/// Tauri command to call peer `peer_id` `label` in the roster, or with no
+/// label nothing; the name carries over if the peer comes back soon
+#[command]
+async fn set_peer_label(
+    peer_id: String,
+    label: Option<String>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let label = label.map(|label| label.trim().to_string()).filter(|label| !label.is_empty());
+    if !state.peers.set_label(&peer_id, label) {
+        return Err("Unknown peer".to_string());
+    }
+    emit_peers(&app, &state);
+    Ok(())
+}
+
+/// Tauri command to page through the chat history: up to `limit` messages
+/// (all by default), oldest first, from just before `before_id` or from the
+/// newest
+#[command]
+async fn get_message_history(
+    limit: Option<usize>,
+    before_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<Vec<ChatMessage>, String> {
+    state
+        .history
+        .lock()
+        .await
+        .page(limit.unwrap_or(usize::MAX), before_id.as_deref())
+        .ok_or_else(|| "Message not in history".to_string())
+}
+
+/// Tauri command to forget every message
+#[command]
+async fn clear_history(
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state.history.lock().await.clear();
+    state.peers.clear_unread();
+    Ok(())
+}
+
+/// Tauri command to say which conversation the GUI shows, or with `None`
+/// that the window isn't in front; messages arriving elsewhere count as
+/// unread
+#[command]
+async fn set_active_conversation(
+    peer_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state.peers.set_active(peer_id);
+    Ok(())
+}
+
+/// Tauri command to get the unread message counts by peer id, for every
+/// peer with any, including ones no longer connected
+#[command]
+async fn get_unread_counts(
+    state: State<'_, AppState>,
+) -> Result<HashMap<String, usize>, String> {
+    Ok(state.peers.unread_counts())
+}
+
+/// Tauri command to mark the messages from `peer_id` read, up to and
+/// including `up_to_message_id`, answering how many that was
+#[command]
+async fn mark_read(
+    peer_id: String,
+    up_to_message_id: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<usize, String> {
+    let read = state.peers.mark_read(&peer_id, &up_to_message_id);
+    if !read.is_empty() {
+        emit_peers(&app, &state);
+    }
+    Ok(read.len())
+}
+
+/// Tauri command to write a diagnostic bundle for a bug report to `path`:
+/// the last `hours` of the log (24 by default), the settings, connection
+/// stats and version, with message texts, peer names, relay tokens and
+/// anything shaped like a key blanked out. Returns where it was written.
+#[command]
+async fn export_logs(
+    path: String,
+    hours: Option<u64>,
     state: State<'_, AppState>,
 ) -> Result<String, String> {
-    if content.len() > state.config.max_message_size {
-        return Err("Message too large".to_string());
+    let window = Duration::from_secs(hours.unwrap_or(DEFAULT_LOG_HOURS).saturating_mul(3600));
+    let since = SystemTime::now().checked_sub(window).unwrap_or(UNIX_EPOCH);
+    let mut redactor = Redactor::new();
+    for message in state.history.lock().await.page(usize::MAX, None).unwrap_or_default() {
+        redactor.secret(message.body);
     }
-    
-    let mut active_conn = state.active_connection.lock().await;
-    
-    if let Some(ref mut connection) = active_conn.as_mut() {
-        connection.send_text(&content).await
-            .map_err(|e| format!("Send failed: {}", e))?;
-        Ok("Message sent".to_string())
-    } else {
-        Err("No active connection".to_string())
+    // What the user calls people is theirs too
+    for label in state.peers.list().into_iter().filter_map(|peer| peer.label) {
+        redactor.secret(label);
+    }
+
+    let bundle = Bundle::new(silence::logging::log_dir().as_deref(), since, state.config.current(), state.peers.list());
+    let path = PathBuf::from(path);
+    bundle
+        .write(&path, &redactor)
+        .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    tracing::info!(path = %path.display(), "Diagnostic bundle written");
+    Ok(path.display().to_string())
+}
+
+/// Tauri command to list the addresses a peer could connect to us at, with
+/// the one the router forwards when the port is mapped and the one a relay
+/// sees us at when one answers
+#[command]
+async fn get_local_addresses(
+    state: State<'_, AppState>,
+) -> Result<LocalAddresses, String> {
+    let port = state.config.current().listen_port;
+    let mut found = LocalAddresses::new(port, addresses::interfaces());
+    if let Some(mapped) = state.port_mapping.lock().await.as_ref() {
+        let mapping = mapped.mapping().await;
+        found.add_mapped(mapping.protocol.to_string(), mapping.external);
+    }
+    if let Some((relay, observed)) = state.connection_manager.observed_address().await {
+        found.add_observed(relay, observed.ip());
+    }
+    Ok(found)
+}
+
+/// Tauri command to check which configured relays answer, reachable ones
+/// fastest first, whether or not a session is up; polling it probes the
+/// relays at most every `RELAY_STATUS_TTL`
+#[command]
+async fn get_relay_status(
+    state: State<'_, AppState>,
+) -> Result<Vec<RelayStatus>, String> {
+    Ok(state.relay_status.get(&state.connection_manager).await)
+}
+
+/// Tauri command to get the fingerprint of the connection to peer
+/// `peer_id`, or the most recent peer, for the user to compare with the
+/// peer's out of band; both ends show the same once the peer's hello is in
+#[command]
+async fn get_session_fingerprint(
+    peer_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<SessionFingerprint, String> {
+    let peer_id = peer_id.or_else(|| state.peers.latest()).ok_or_else(|| "No active connection".to_string())?;
+    let fingerprint =
+        state.peers.fingerprint(&peer_id).ok_or_else(|| "Unknown peer, or it hasn't said hello yet".to_string())?;
+    Ok(SessionFingerprint {
+        fingerprint: fingerprint.to_string(),
+        short_auth_string: fingerprint.short_auth_string(),
+    })
+}
+
+#[derive(serde::Serialize)]
+struct SessionFingerprint {
+    /// In hex
+    fingerprint: String,
+    /// Four groups of five digits, to read out to the peer
+    short_auth_string: String,
+}
+
+/// Tauri command to record whether the user compared the connection's
+/// fingerprint with a peer and found it the same
+#[command]
+async fn mark_peer_verified(
+    peer_id: String,
+    verified: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    if !state.peers.set_verified(&peer_id, verified) {
+        return Err("Unknown peer, or it hasn't said hello yet".to_string());
+    }
+    tracing::info!(%peer_id, verified, "Peer verification changed");
+    emit_peers(&app, &state);
+    Ok(())
+}
+
+/// Tauri command to rotate the keys now instead of when due, with every
+/// connected peer rotating along; `local_only` in the answer says no peer
+/// was connected. Refused for a few seconds after the last time.
+#[command]
+async fn rotate_keys_now(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<RotationReport, String> {
+    let report = state
+        .peers
+        .rotate_keys(&state.crypto, ROTATION_ACK_TIMEOUT)
+        .await
+        .map_err(|e| {
+            if let RotateError::Request { rotated, .. } = &e {
+                let _ = app.emit(events::KEY_ROTATED, rotated);
+            }
+            e.to_string()
+        })?;
+    tracing::info!(
+        epoch = report.rotated.epoch,
+        confirmed = report.confirmed.len(),
+        unconfirmed = report.unconfirmed.len(),
+        "Keys rotated on request"
+    );
+    let _ = app.emit(events::KEY_ROTATED, &report.rotated);
+    Ok(report)
+}
+
+/// Tauri command to make an invite code, and its QR code, for one peer to
+/// connect with, listening first if the app isn't already
+#[command]
+async fn generate_invite(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<InviteCode, String> {
+    if !is_listening(&state) {
+        start_listening(app, state.clone()).await?;
+    }
+    let found = get_local_addresses(state.clone()).await?;
+    let addresses = found.addresses.iter().filter_map(|found| found.address.parse().ok()).collect();
+    let fingerprint = state.crypto.lock().await.fingerprint();
+    let invite = Invite::new(addresses, fingerprint, INVITE_LIFETIME);
+    if invite.addresses.is_empty() {
+        return Err("No addresses to invite to".to_string());
+    }
+    state.peers.expect_invite(&invite);
+    Ok(InviteCode { code: invite.encode(), qr_code: invite.qr_code(), expires_at: invite.expires_at })
+}
+
+#[derive(serde::Serialize)]
+struct InviteCode {
+    code: String,
+    /// The code as a QR code, a `data:` URI for an `<img>`
+    qr_code: String,
+    /// Unix seconds
+    expires_at: u64,
+}
+
+/// Tauri command to connect with an invite code, trying its addresses in
+/// turn, and hand the inviting side back its pairing secret; answers the
+/// peer id, with the connection's fingerprint for the user to compare
+#[command]
+async fn accept_invite(
+    code: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<AcceptedInvite, InviteError> {
+    let invite = Invite::decode(&code)?;
+    let mut error = String::new();
+    for addr in &invite.addresses {
+        match connect_to_peer(addr.to_string(), None, app.clone(), state.clone()).await {
+            Ok(info) => {
+                // A connection too broken to take it shows up in the receive loop
+                if let Err(e) = state.peers.present_invite(&info.peer_id, &invite).await {
+                    tracing::warn!(peer_id = %info.peer_id, error = %e, "Couldn't hand back the invite");
+                }
+                // The same the hellos work out, if the inviting side is who the invite says
+                let fingerprint = state.crypto.lock().await.fingerprint().shared_with(&invite.fingerprint);
+                return Ok(AcceptedInvite {
+                    peer_id: info.peer_id,
+                    fingerprint: fingerprint.to_string(),
+                    short_auth_string: fingerprint.short_auth_string(),
+                })
+            }
+            Err(e) => {
+                tracing::debug!(peer = %addr, error = %e, "Invite address unreachable");
+                error = e;
+            }
+        }
     }
+    Err(InviteError::Unreachable { error })
+}
+
+#[derive(serde::Serialize)]
+struct AcceptedInvite {
+    peer_id: String,
+    /// The connection's fingerprint, from the invite's, in hex
+    fingerprint: String,
+    short_auth_string: String,
+}
+
+/// Tauri command to change some settings, saving them to the config file.
+/// Answers which changes took effect and which wait for a reconnect; a patch
+/// with any invalid setting changes nothing.
+#[command]
+async fn update_settings(
+    partial: SettingsPatch,
+    state: State<'_, AppState>,
+) -> Result<ConfigChange, String> {
+    let edit = |config: &mut Config| partial.apply(config);
+    let result = match &state.config_path {
+        Some(path) => state.config.update_and_save(path, edit),
+        None => {
+            tracing::warn!("No config directory, settings last until exit");
+            state.config.update(edit)
+        }
+    };
+    result.map_err(|errors| errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))
+}
+
+/// Tauri command to turn on `port_mapping`, saving it, and map the listen
+/// port now if listening already; otherwise it's mapped on listening.
+/// Answers the mapping made, if any. The setting stays on when the router
+/// refuses; the error says why.
+#[command]
+async fn enable_port_mapping(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<events::PortMapped>, String> {
+    let patch = SettingsPatch { port_mapping: Some(true), ..SettingsPatch::default() };
+    update_settings(patch, state.clone()).await?;
+    if !is_listening(&state) {
+        return Ok(None);
+    }
+    if let Some(mapped) = state.port_mapping.lock().await.as_ref() {
+        return Ok(Some(events::PortMapped::new(&mapped.mapping().await)));
+    }
+    let port = state.config.current().listen_port;
+    map_port(&app, &state, port).await.map(Some)
 }
 
 /// Tauri command to get security status
@@ -169,14 +946,19 @@ async fn get_security_status(
 ) -> Result<SecurityStatus, String> {
     let crypto = state.crypto.lock().await;
     let seconds_until_rotation = crypto.seconds_until_rotation();
+    let next_rotation_at = events::millis_from_now(crypto.time_until_rotation());
+    let config = state.config.current();
     
     Ok(SecurityStatus {
         encryption_active: true,
         key_rotation_seconds: seconds_until_rotation,
-        connection_active: {
-            let conn = state.active_connection.lock().await;
-            conn.is_some()
-        },
+        next_rotation_at,
+        key_epoch: crypto.key_epoch(),
+        profile: config.profile.to_string(),
+        profile_overrides: config.profile_overrides(),
+        connection_active: state.peers.is_connected(),
+        connection: state.status.lock().unwrap().clone(),
+        peers: state.peers.list(),
     })
 }
 
@@ -184,70 +966,206 @@ async fn get_security_status(
 struct SecurityStatus {
     encryption_active: bool,
     key_rotation_seconds: u64,
+    /// When the next rotation is due, in milliseconds since the Unix epoch
+    next_rotation_at: u64,
+    /// Rotations so far
+    key_epoch: u64,
+    /// Security profile the settings come from
+    profile: String,
+    /// Profile settings changed by hand since
+    profile_overrides: Vec<&'static str>,
     connection_active: bool,
+    /// As last sent in a `connection-status` event
+    connection: ConnectionStatus,
+    /// Open conversations, oldest first, with whether each peer is verified
+    peers: Vec<PeerInfo>,
 }
 
-/// Initialize crypto and start key rotation background task
-async fn initialize_crypto(config: &Config) -> Arc<Mutex<SilenceCrypto>> {
+/// Initialize crypto and start key rotation background task, which reports
+/// each rotation for the GUI
+async fn initialize_crypto(config: &ConfigHandle) -> (Arc<Mutex<SilenceCrypto>>, UnboundedReceiver<Rotation>) {
     let crypto = Arc::new(Mutex::new(
-        SilenceCrypto::new(config.key_rotation_interval)
+        SilenceCrypto::from_config(&config.current())
             .expect("Failed to initialize crypto")
     ));
     
-    // Start automatic key rotation task
-    let crypto_for_rotation = Arc::clone(&crypto);
-    let rotation_interval = config.key_rotation_interval;
-    
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(rotation_interval)
-        );
-        
-        loop {
-            interval.tick().await;
-            let mut crypto_guard = crypto_for_rotation.lock().await;
-            if let Err(e) = crypto_guard.rotate_keys() {
-                eprintln!("Automatic key rotation failed: {}", e);
-            }
-            // Key rotation now silent - status shown in UI timestamp
-        }
+    // Start automatic key rotation task, which follows interval changes
+    let (sender, rotations) = tokio::sync::mpsc::unbounded_channel();
+    silence::spawn_key_rotation_with(Arc::clone(&crypto), config, move |rotation| {
+        let _ = sender.send(rotation);
     });
     
-    crypto
+    (crypto, rotations)
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize configuration
-    let config = Config::default();
+    // Log to a file, and warnings to the GUI, as a windowed build has no
+    // console; the guard inside keeps the file written until exit
+    let logging = silence::logging::init(silence::logging::log_dir().as_deref());
+    let mut log_events = logging.events;
+
+    // Load configuration from the platform config directory, writing a
+    // default file on first run, with SILENCE_* environment variables
+    // taking precedence
+    // (load_or_init logs why it fell back to defaults, if it did)
+    let loaded = Config::load_or_init();
+    if let (true, Some(path)) = (loaded.created, &loaded.path) {
+        tracing::info!(path = %path.display(), "Wrote default settings");
+    }
+    let config = loaded
+        .config
+        .clone()
+        .apply_env_overrides()
+        .map_err(|e| vec![e])
+        .and_then(|config| config.validate().map(|()| config))
+        .unwrap_or_else(|errors| {
+            for error in errors {
+                tracing::warn!(%error, "Ignoring SILENCE_* overrides");
+            }
+            loaded.config
+        });
+    let config = ConfigHandle::new(config);
+    
+    // Pick up edits to the config file while running
+    let config_path = loaded.path.clone();
+    let last_peer_path = Config::config_dir().map(|dir| dir.join(LAST_PEER_FILE));
+    let last_peer = last_peer_path.as_deref().and_then(LastPeer::load);
+    let _config_watcher = loaded.path.and_then(|path| {
+        config
+            .watch_file(path)
+            .map_err(|e| tracing::warn!(error = %e, "Config changes need a restart"))
+            .ok()
+    });
     
     // Initialize cryptographic engine
-    let crypto = initialize_crypto(&config).await;
+    let (crypto, mut rotations) = initialize_crypto(&config).await;
     
     // Initialize connection manager with relay servers
-    let connection_manager = Arc::new(ConnectionManager::with_relays(
-        Arc::clone(&crypto),
-        config.max_message_size,
-        config.relay_servers.clone(),
-    ));
+    let connection_manager = Arc::new(
+        ConnectionManager::with_handle(Arc::clone(&crypto), config.clone())
+            .expect("Failed to initialize connection manager"),
+    );
     
     // Create application state
     let app_state = AppState {
         crypto,
         connection_manager,
-        active_connection: Arc::new(Mutex::new(None)),
-        config,
+        peers: Peers::new(),
+        config: config.clone(),
+        config_path,
+        last_peer: Arc::new(std::sync::Mutex::new(last_peer)),
+        last_peer_path,
+        listeners: Arc::default(),
+        port_mapping: Arc::default(),
+        relay_status: Arc::default(),
+        history: Arc::new(Mutex::new(MessageHistory::new(config.current().message_history))),
+        status: Arc::default(),
     };
+    let history = Arc::clone(&app_state.history);
+    let peers = app_state.peers.clone();
+    let pinged = peers.clone();
+    peers.set_max_file_size(config.current().max_file_size);
     
     // Start Tauri application
     Builder::default()
+        .setup(move |app| {
+            // Show warnings and errors in the GUI
+            let log_app = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                while let Some(event) = log_events.recv().await {
+                    let _ = log_app.emit("log", event);
+                }
+            });
+
+            // Tell the GUI when the keys rotate, and when they failed to
+            let rotation_app = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                while let Some(rotation) = rotations.recv().await {
+                    let _ = match rotation {
+                        Rotation::Rotated { epoch, next_due } => {
+                            rotation_app.emit(events::KEY_ROTATED, events::KeyRotated::new(epoch, next_due))
+                        }
+                        Rotation::Failed { error } => {
+                            rotation_app.emit(events::KEY_ROTATION_FAILED, events::KeyRotationFailed::new(error))
+                        }
+                    };
+                }
+            });
+
+            // Time the round trip to every peer now and then, for the roster
+            tauri::async_runtime::spawn(async move {
+                let mut pings = tokio::time::interval(PING_INTERVAL);
+                loop {
+                    pings.tick().await;
+                    pinged.ping_all().await;
+                }
+            });
+
+            // Tell the GUI which settings changed and which wait for a reconnect
+            let app = app.handle().clone();
+            let mut changes = config.changes();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    match changes.recv().await {
+                        Ok(change) => {
+                            if change.applied.contains(&"message_history") {
+                                history.lock().await.set_limit(config.current().message_history);
+                            }
+                            if change.applied.contains(&"max_file_size") {
+                                peers.set_max_file_size(config.current().max_file_size);
+                            }
+                            let _ = app.emit("config-changed", change);
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+            Ok(())
+        })
         .manage(app_state)
         .invoke_handler(generate_handler![
             connect_to_peer,
+            reconnect_last_peer,
+            get_last_peer,
             start_listening,
+            stop_listening,
+            disconnect,
+            disconnect_peer,
+            panic_wipe,
             send_message,
+            send_message_to,
+            send_file,
+            accept_file,
+            reject_file,
+            get_message_budget,
+            list_peers,
+            set_peer_label,
+            get_message_history,
+            clear_history,
+            set_active_conversation,
+            get_unread_counts,
+            mark_read,
+            export_logs,
+            get_local_addresses,
+            get_relay_status,
+            generate_invite,
+            accept_invite,
+            get_session_fingerprint,
+            mark_peer_verified,
+            rotate_keys_now,
+            update_settings,
+            enable_port_mapping,
             get_security_status
         ])
-        .run(tauri::generate_context!())
-        .expect("Error while running Tauri application");
+        .build(tauri::generate_context!())
+        .expect("Error while running Tauri application")
+        .run(|app, event| {
+            // Leave no mapping on the router behind us
+            if let RunEvent::Exit = event {
+                let state = app.state::<AppState>();
+                tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(unmap_port(&state)));
+            }
+        });
 }
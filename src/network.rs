@@ -1,11 +1,94 @@
 // P2P networking layer for Silence Crypto
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::crypto::{SilenceCrypto, EncryptedMessage, CryptoError};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+use crate::crypto::{SilenceCrypto, EncryptedMessage, CryptoError, Fingerprint, ENCRYPTION_OVERHEAD};
+use crate::config::{join_errors, Config, ConfigError, RelayEntry};
+use crate::protocol::{self, Compatibility, Features, WIRE_VERSION};
+use crate::reload::ConfigHandle;
+
+/// Bytes a direct message adds to its text on the wire, before the length
+/// prefix: the id string and its length, the message type and the
+/// encryption overhead. Relay frames add less.
+pub const MESSAGE_OVERHEAD: usize = 8 + 36 + 4 + ENCRYPTION_OVERHEAD;
+
+/// Relay session joined when the caller doesn't name one
+pub const DEFAULT_RELAY_SESSION: &str = "public";
+
+/// How long to wait for the relay's hello after joining
+const RELAY_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Encoded size of the relay hello frame
+const RELAY_HELLO_LEN: usize = 22;
+
+/// Leading byte of a relay rejection frame (never a valid protocol version)
+const RELAY_REJECT_MARKER: u8 = 0x00;
+
+/// Reason code of the relay's notice that a frame exceeded its limit
+const RELAY_FRAME_TOO_LARGE: u8 = 8;
+
+/// Leads the frame size limit we ask the relay for in the join frame
+const RELAY_SIZE_SEPARATOR: u8 = 0x01;
+
+/// Leads the auth token in the join frame, for relays that require one
+const RELAY_TOKEN_SEPARATOR: u8 = 0x00;
+
+/// Smallest frame size limit a relay lets a client ask for
+const RELAY_MIN_REQUESTED_SIZE: usize = 64;
+
+/// Type bytes leading every relay frame after the hello
+const RELAY_FRAME_DATA: u8 = 0x01;
+const RELAY_FRAME_PING: u8 = 0x02;
+const RELAY_FRAME_PONG: u8 = 0x03;
+const RELAY_FRAME_GOODBYE: u8 = 0x04;
+const RELAY_FRAME_ERROR: u8 = 0x05;
+
+/// Bytes a relayed message adds to its text: the frame type and the
+/// encryption overhead
+pub const RELAY_MESSAGE_OVERHEAD: usize = 1 + ENCRYPTION_OVERHEAD;
+
+/// Latency probes sent per relay; the fastest round trip counts
+const RELAY_PROBE_COUNT: usize = 3;
+
+/// Length of the relay's probe reply: pong type byte, probe id, receive time
+const RELAY_PROBE_REPLY_LEN: usize = 1 + 8 + 8;
+
+/// Type byte of an address request sent before joining, and of the answer
+const RELAY_FRAME_ADDRESS: u8 = 0x06;
+
+/// Leads the plaintext of every relayed message but text, with its type code
+/// after it and then its payload: relays pass on only the encrypted payload,
+/// so the type goes inside. Relayed text is UTF-8, which never starts with
+/// this byte.
+const RELAY_CONTROL_MARKER: u8 = 0xFF;
+
+/// Type codes of relayed messages, after the marker
+const RELAY_FILE_OFFER: u8 = 0x01;
+const RELAY_FILE_CHUNK: u8 = 0x02;
+const RELAY_FILE_ACCEPT: u8 = 0x03;
+const RELAY_FILE_REJECT: u8 = 0x04;
+const RELAY_KEY_ROTATION: u8 = 0x05;
+const RELAY_KEY_ROTATION_ACK: u8 = 0x06;
+const RELAY_HELLO: u8 = 0x07;
+const RELAY_HEARTBEAT: u8 = 0x08;
+const RELAY_PAIRING: u8 = 0x09;
+const RELAY_PING: u8 = 0x0A;
+const RELAY_PONG: u8 = 0x0B;
+
+/// Bytes a file chunk's payload adds to its data: the transfer id string
+/// and its length, the offset and the data's length. Relayed chunks add the
+/// control marker and type code on top.
+const FILE_CHUNK_OVERHEAD: usize = 8 + 36 + 8 + 8;
+
+/// Longest answer to an address request: type byte, address type byte,
+/// IPv6 address, port
+const RELAY_ADDRESS_REPLY_MAX_LEN: usize = 1 + 1 + 16 + 2;
 
 /// Network errors
 #[derive(Debug)]
@@ -16,6 +99,26 @@ pub enum NetworkError {
     InvalidMessage,
     MessageTooLarge,
     Timeout,
+    RelayRejected { reason: RelayRejectReason },
+    /// The relay ended an established connection on purpose and said why
+    RelayClosed { reason: RelayRejectReason },
+    /// The relay answered in a wire version we don't speak
+    IncompatibleVersion { version: u8 },
+    /// The settings the manager was built from don't hold together
+    InvalidConfig(Vec<ConfigError>),
+    /// Only direct peers confirm messages: relayed text carries no id
+    NotOverRelay,
+    /// The peer confirmed a key rotation under keys that don't match ours
+    KeysOutOfStep,
+}
+
+impl NetworkError {
+    /// Whether to reconnect at once, through another relay if need be: a
+    /// relay saying goodbye is restarting, draining or done with us, which
+    /// unlike a network failure is no reason to back off
+    pub fn fail_over_immediately(&self) -> bool {
+        matches!(self, NetworkError::RelayClosed { .. })
+    }
 }
 
 impl std::fmt::Display for NetworkError {
@@ -27,11 +130,29 @@ impl std::fmt::Display for NetworkError {
             NetworkError::InvalidMessage => write!(f, "Invalid message format"),
             NetworkError::MessageTooLarge => write!(f, "Message too large"),
             NetworkError::Timeout => write!(f, "Operation timeout"),
+            NetworkError::RelayRejected { reason } => write!(f, "Relay rejected connection: {}", reason),
+            NetworkError::RelayClosed { reason } => write!(f, "Relay closed connection: {}", reason),
+            NetworkError::IncompatibleVersion { version } => write!(
+                f, "Relay speaks protocol version {}, we speak {}-{}",
+                version, protocol::MIN_SUPPORTED, WIRE_VERSION
+            ),
+            NetworkError::InvalidConfig(errors) => write!(f, "Invalid config: {}", join_errors(errors)),
+            NetworkError::NotOverRelay => write!(f, "Not possible through a relay"),
+            NetworkError::KeysOutOfStep => write!(f, "Peer's keys don't match ours after rotating"),
         }
     }
 }
 
-impl std::error::Error for NetworkError {}
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::Connection(e) => Some(e),
+            NetworkError::Serialization(e) => Some(e),
+            NetworkError::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for NetworkError {
     fn from(err: std::io::Error) -> Self {
@@ -64,15 +185,367 @@ pub enum MessageType {
     Text,
     KeyRotation,
     Heartbeat,
+    /// Answers `KeyRotation`, encrypted under the new keys
+    KeyRotationAck,
+    /// Names the app instance at the other end, so two connections to the
+    /// same one can be told apart from connections to two, carrying its
+    /// session fingerprint and then its instance id
+    Hello,
+    /// Confirms a `Text` arrived, carrying its id
+    Delivered,
+    /// Offers the peer a file, carrying a `FileOffer`
+    FileOffer,
+    /// A piece of an accepted file, carrying a `FileChunk`
+    FileChunk,
+    /// Takes an offered file, carrying its transfer id
+    FileAccept,
+    /// Turns an offered file down, or gives up on one partway, carrying its
+    /// transfer id
+    FileReject,
+    /// Shows the peer we were given its invite, carrying the invite's
+    /// pairing secret
+    Pairing,
+    /// Asks the peer for a `Pong`, carrying a number for it to send back
+    Ping,
+    /// Answers a `Ping`, carrying its number
+    Pong,
+}
+
+impl MessageType {
+    /// The code a relayed message of this type goes under, behind
+    /// `RELAY_CONTROL_MARKER`; `None` for text, which goes unmarked, and
+    /// confirmations, which only direct peers send
+    fn relay_code(&self) -> Option<u8> {
+        match self {
+            MessageType::Text | MessageType::Delivered => None,
+            MessageType::KeyRotation => Some(RELAY_KEY_ROTATION),
+            MessageType::Heartbeat => Some(RELAY_HEARTBEAT),
+            MessageType::KeyRotationAck => Some(RELAY_KEY_ROTATION_ACK),
+            MessageType::Hello => Some(RELAY_HELLO),
+            MessageType::FileOffer => Some(RELAY_FILE_OFFER),
+            MessageType::FileChunk => Some(RELAY_FILE_CHUNK),
+            MessageType::FileAccept => Some(RELAY_FILE_ACCEPT),
+            MessageType::FileReject => Some(RELAY_FILE_REJECT),
+            MessageType::Pairing => Some(RELAY_PAIRING),
+            MessageType::Ping => Some(RELAY_PING),
+            MessageType::Pong => Some(RELAY_PONG),
+        }
+    }
+
+    fn from_relay_code(code: u8) -> Option<Self> {
+        Some(match code {
+            RELAY_KEY_ROTATION => MessageType::KeyRotation,
+            RELAY_HEARTBEAT => MessageType::Heartbeat,
+            RELAY_KEY_ROTATION_ACK => MessageType::KeyRotationAck,
+            RELAY_HELLO => MessageType::Hello,
+            RELAY_FILE_OFFER => MessageType::FileOffer,
+            RELAY_FILE_CHUNK => MessageType::FileChunk,
+            RELAY_FILE_ACCEPT => MessageType::FileAccept,
+            RELAY_FILE_REJECT => MessageType::FileReject,
+            RELAY_PAIRING => MessageType::Pairing,
+            RELAY_PING => MessageType::Ping,
+            RELAY_PONG => MessageType::Pong,
+            _ => return None,
+        })
+    }
+}
+
+/// A file one peer offers another, before any of it is sent
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub transfer_id: String,
+    /// The file's name, without its directories
+    pub name: String,
+    /// Bytes
+    pub size: u64,
+}
+
+/// The bytes of an accepted file at `offset`, sent in order
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub transfer_id: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// A file transfer message, either way
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileMessage {
+    Offer(FileOffer),
+    Chunk(FileChunk),
+    Accept { transfer_id: String },
+    Reject { transfer_id: String },
+}
+
+impl FileMessage {
+    /// The transfer this is part of
+    pub fn transfer_id(&self) -> &str {
+        match self {
+            FileMessage::Offer(offer) => &offer.transfer_id,
+            FileMessage::Chunk(chunk) => &chunk.transfer_id,
+            FileMessage::Accept { transfer_id } | FileMessage::Reject { transfer_id } => transfer_id,
+        }
+    }
+
+    fn message_type(&self) -> MessageType {
+        match self {
+            FileMessage::Offer(_) => MessageType::FileOffer,
+            FileMessage::Chunk(_) => MessageType::FileChunk,
+            FileMessage::Accept { .. } => MessageType::FileAccept,
+            FileMessage::Reject { .. } => MessageType::FileReject,
+        }
+    }
+
+    /// The message's payload: offers and chunks bincode encoded, answers
+    /// their transfer id
+    fn encode(&self) -> Result<Vec<u8>, NetworkError> {
+        Ok(match self {
+            FileMessage::Offer(offer) => bincode::serialize(offer)?,
+            FileMessage::Chunk(chunk) => bincode::serialize(chunk)?,
+            FileMessage::Accept { transfer_id } | FileMessage::Reject { transfer_id } => transfer_id.as_bytes().to_vec(),
+        })
+    }
+
+    fn decode(message_type: MessageType, payload: &[u8]) -> Result<Self, NetworkError> {
+        let transfer_id = || String::from_utf8(payload.to_vec()).map_err(|_| NetworkError::InvalidMessage);
+        Ok(match message_type {
+            MessageType::FileOffer => FileMessage::Offer(bincode::deserialize(payload)?),
+            MessageType::FileChunk => FileMessage::Chunk(bincode::deserialize(payload)?),
+            MessageType::FileAccept => FileMessage::Accept { transfer_id: transfer_id()? },
+            MessageType::FileReject => FileMessage::Reject { transfer_id: transfer_id()? },
+            _ => return Err(NetworkError::InvalidMessage),
+        })
+    }
+}
+
+/// Why a relay refused or dropped a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayRejectReason {
+    TooManyFromAddress,
+    Draining,
+    SessionFull,
+    ServerFull,
+    Banned,
+    QuotaExceeded,
+    /// Dropped mid-session after sending too many frames over the relay's size limit
+    FrameTooLarge,
+    /// Dropped mid-session: the relay ran short of buffer space and we were furthest behind
+    Overloaded,
+    /// The relay only admits clients with an auth token
+    Unauthorized,
+    /// Dropped mid-session: the relay is stopping, most likely to restart
+    ShuttingDown,
+    /// Dropped mid-session by the relay's operator
+    Kicked,
+    /// Dropped mid-session after sending nothing for the relay's idle timeout
+    Idle,
+    /// The relay only admits addresses its access lists allow, and ours isn't one
+    NotAllowed,
+    /// The relay doesn't speak our protocol version; it serves `min..=max`
+    UnsupportedVersion { min: u8, max: u8 },
+    /// A reason code this client doesn't know yet
+    Unknown(u8),
+}
+
+impl RelayRejectReason {
+    /// Decode the reason code and any detail bytes following the marker
+    fn from_frame(frame: &[u8]) -> Self {
+        match *frame {
+            [7, min, max] => RelayRejectReason::UnsupportedVersion { min, max },
+            [code, ..] => Self::from_code(code),
+            [] => RelayRejectReason::Unknown(0),
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => RelayRejectReason::TooManyFromAddress,
+            2 => RelayRejectReason::Draining,
+            3 => RelayRejectReason::SessionFull,
+            4 => RelayRejectReason::ServerFull,
+            5 => RelayRejectReason::Banned,
+            6 => RelayRejectReason::QuotaExceeded,
+            RELAY_FRAME_TOO_LARGE => RelayRejectReason::FrameTooLarge,
+            9 => RelayRejectReason::Overloaded,
+            10 => RelayRejectReason::Unauthorized,
+            11 => RelayRejectReason::ShuttingDown,
+            12 => RelayRejectReason::Kicked,
+            13 => RelayRejectReason::Idle,
+            14 => RelayRejectReason::NotAllowed,
+            other => RelayRejectReason::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for RelayRejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RelayRejectReason::TooManyFromAddress => write!(f, "too many connections from this address"),
+            RelayRejectReason::Draining => write!(f, "relay is draining"),
+            RelayRejectReason::SessionFull => write!(f, "session is full"),
+            RelayRejectReason::ServerFull => write!(f, "relay is full"),
+            RelayRejectReason::Banned => write!(f, "address is banned"),
+            RelayRejectReason::QuotaExceeded => write!(f, "bandwidth quota exceeded"),
+            RelayRejectReason::FrameTooLarge => write!(f, "frames over the relay's size limit"),
+            RelayRejectReason::Overloaded => write!(f, "relay is overloaded"),
+            RelayRejectReason::Unauthorized => write!(f, "relay requires an auth token"),
+            RelayRejectReason::ShuttingDown => write!(f, "relay is shutting down"),
+            RelayRejectReason::Kicked => write!(f, "disconnected by the relay operator"),
+            RelayRejectReason::Idle => write!(f, "idle for too long"),
+            RelayRejectReason::NotAllowed => write!(f, "relay doesn't admit this address"),
+            RelayRejectReason::UnsupportedVersion { min, max } => write!(
+                f, "protocol version {} not supported (relay supports {}-{})",
+                WIRE_VERSION, min, max
+            ),
+            RelayRejectReason::Unknown(code) => write!(f, "unknown reason {}", code),
+        }
+    }
+}
+
+/// Relay handshake reply received after joining a session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayHello {
+    /// Wire version the session speaks, no newer than ours
+    pub version: u8,
+    /// Identifier the relay assigned to this connection
+    pub client_id: uuid::Uuid,
+    /// Largest frame the relay will accept
+    pub max_message_size: u32,
+    /// Whether another peer was already waiting in the session
+    pub peer_present: bool,
+}
+
+impl RelayHello {
+    fn decode(data: &[u8]) -> Result<Self, NetworkError> {
+        if data.len() != RELAY_HELLO_LEN {
+            return Err(NetworkError::InvalidMessage);
+        }
+
+        // The length check leaves these no way to fail
+        let (&version, rest) = data.split_first().ok_or(NetworkError::InvalidMessage)?;
+        let (client_id, rest) = rest.split_first_chunk().ok_or(NetworkError::InvalidMessage)?;
+        let (max_message_size, rest) = rest.split_first_chunk().ok_or(NetworkError::InvalidMessage)?;
+        let peer_present = match rest {
+            [0] => false,
+            [1] => true,
+            _ => return Err(NetworkError::InvalidMessage),
+        };
+
+        Ok(Self {
+            version,
+            client_id: uuid::Uuid::from_bytes(*client_id),
+            max_message_size: u32::from_be_bytes(*max_message_size),
+            peer_present,
+        })
+    }
+
+    /// Protocol features the session has
+    pub fn features(&self) -> Features {
+        Features::for_version(self.version)
+    }
 }
 
 /// P2P connection handler
+///
+/// `into_split` parts it into a reader and a writer, so sending doesn't wait
+/// on a receive that is waiting for the peer.
 pub struct P2PConnection {
-    stream: TcpStream,
+    reader: ConnectionReader,
+    relay_hello: Option<RelayHello>,
+}
+
+/// Traffic on a connection so far; bytes are counted as framed on the wire,
+/// messages are chat messages only
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Milliseconds the last answered ping took to come back, `None` until
+    /// one is
+    pub rtt_ms: Option<u64>,
+}
+
+/// `ConnectionStats` as both halves of a connection update it
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Microseconds, 0 until a ping is answered
+    rtt: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            rtt_ms: match self.rtt.load(Ordering::Relaxed) {
+                0 => None,
+                micros => Some(micros / 1000),
+            },
+        }
+    }
+}
+
+/// What `ConnectionReader::next_message` brings in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// A chat message
+    Text(String),
+    /// The peer's app instance id, and the connection's fingerprint from
+    /// the peer's session fingerprint and ours, as the peer works it out too
+    Hello { instance: String, fingerprint: Fingerprint },
+    /// The peer got the text we sent under `id`
+    Delivered { id: String },
+    /// Part of a file transfer
+    File(FileMessage),
+    /// The pairing secret of the invite the peer connected with
+    Pairing { secret: Vec<u8> },
+    /// The peer answered our last ping, `rtt` after it went
+    Pong { rtt: Duration },
+}
+
+/// One frame off the wire, as far as the reader's caller is concerned
+enum Incoming {
+    Message(PeerMessage),
+    /// Handled by the reader itself: heartbeats, key rotations, relay pings
+    Control,
+    Closed,
+}
+
+/// Receiving half of a connection, from `P2PConnection::into_split`
+pub struct ConnectionReader {
+    stream: OwnedReadHalf,
+    /// Answers the relay's pings
+    writer: ConnectionWriter,
+}
+
+/// Sending half of a connection, from `P2PConnection::into_split`. Clones
+/// send on the same connection, a whole frame at a time.
+#[derive(Clone)]
+pub struct ConnectionWriter {
+    stream: Arc<Mutex<OwnedWriteHalf>>,
     peer_addr: SocketAddr,
     crypto: Arc<Mutex<SilenceCrypto>>,
     max_message_size: usize,
     is_relay: bool,
+    counters: Arc<Counters>,
+    /// Woken when the peer confirms a key rotation we asked for
+    rotation_acked: Arc<Notify>,
+    /// Woken when it confirms one under keys that don't match ours
+    rotation_rejected: Arc<Notify>,
+    /// The number of the last ping sent, and when, until it's answered
+    ping: Arc<std::sync::Mutex<Option<(u64, Instant)>>>,
 }
 
 impl P2PConnection {
@@ -84,30 +557,393 @@ impl P2PConnection {
         max_message_size: usize,
         is_relay: bool,
     ) -> Self {
-        Self {
-            stream,
+        let (read, write) = stream.into_split();
+        let writer = ConnectionWriter {
+            stream: Arc::new(Mutex::new(write)),
             peer_addr,
             crypto,
             max_message_size,
             is_relay,
+            counters: Arc::default(),
+            rotation_acked: Arc::default(),
+            rotation_rejected: Arc::default(),
+            ping: Arc::default(),
+        };
+        Self {
+            reader: ConnectionReader { stream: read, writer },
+            relay_hello: None,
         }
     }
     
-    /// Connect to a peer
+    /// Connect to a peer (relay connections join the default session)
     pub async fn connect(
         addr: SocketAddr,
         crypto: Arc<Mutex<SilenceCrypto>>,
         max_message_size: usize,
         is_relay: bool,
     ) -> Result<Self, NetworkError> {
+        if is_relay {
+            return Self::connect_relay(addr, DEFAULT_RELAY_SESSION, crypto, max_message_size).await;
+        }
+
         let stream = TcpStream::connect(addr).await?;
-        Ok(Self::new(stream, addr, crypto, max_message_size, is_relay).await)
+        Ok(Self::new(stream, addr, crypto, max_message_size, false).await)
+    }
+
+    /// Connect to a relay and join the given session
+    pub async fn connect_relay(
+        addr: SocketAddr,
+        session_code: &str,
+        crypto: Arc<Mutex<SilenceCrypto>>,
+        max_message_size: usize,
+    ) -> Result<Self, NetworkError> {
+        Self::connect_relay_with_token(addr, session_code, None, crypto, max_message_size).await
+    }
+
+    /// Connect to a relay and join the given session, presenting `token` to
+    /// relays that only admit clients holding one
+    pub async fn connect_relay_with_token(
+        addr: SocketAddr,
+        session_code: &str,
+        token: Option<&str>,
+        crypto: Arc<Mutex<SilenceCrypto>>,
+        max_message_size: usize,
+    ) -> Result<Self, NetworkError> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut connection = Self::new(stream, addr, crypto, max_message_size, true).await;
+
+        // Join frame carries our protocol version, the session code, our
+        // frame size limit and any token, the relay answers with its hello
+        let requested_size = max_message_size.clamp(RELAY_MIN_REQUESTED_SIZE, u32::MAX as usize) as u32;
+        let token_len = token.map_or(0, |token| 1 + token.len());
+        let mut join = Vec::with_capacity(1 + session_code.len() + 5 + token_len);
+        join.push(WIRE_VERSION);
+        join.extend_from_slice(session_code.as_bytes());
+        join.push(RELAY_SIZE_SEPARATOR);
+        join.extend_from_slice(&requested_size.to_be_bytes());
+        if let Some(token) = token {
+            join.push(RELAY_TOKEN_SEPARATOR);
+            join.extend_from_slice(token.as_bytes());
+        }
+        connection.write_frame(&join).await?;
+        let hello = match tokio::time::timeout(RELAY_HELLO_TIMEOUT, connection.read_frame()).await {
+            // A relay that refuses us sends a marker byte and reason code instead
+            Ok(Ok(Some(data))) if data.len() >= 2 && data[0] == RELAY_REJECT_MARKER => {
+                return Err(NetworkError::RelayRejected { reason: RelayRejectReason::from_frame(&data[1..]) });
+            }
+            Ok(Ok(Some(data))) => RelayHello::decode(&data)?,
+            Ok(Ok(None)) => return Err(NetworkError::InvalidMessage),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(NetworkError::Timeout),
+        };
+        match protocol::is_compatible(hello.version) {
+            Compatibility::Full => {}
+            Compatibility::Degraded(missing) => {
+                tracing::debug!(relay = %addr, version = hello.version, %missing, "Relay speaks an older protocol");
+            }
+            Compatibility::Incompatible => return Err(NetworkError::IncompatibleVersion { version: hello.version }),
+        }
+
+        // The relay's limit may be lower than ours; frames over it would only
+        // come back as errors
+        let writer = &mut connection.reader.writer;
+        writer.max_message_size = writer.max_message_size.min(hello.max_message_size as usize);
+        connection.relay_hello = Some(hello);
+        Ok(connection)
     }
     
     /// Send a text message
     pub async fn send_text(&mut self, content: &str) -> Result<(), NetworkError> {
+        self.reader.writer.send_text(content).await
+    }
+
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), NetworkError> {
+        self.reader.writer.write_frame(data).await
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, NetworkError> {
+        self.reader.read_frame().await
+    }
+
+    /// Receive a network message
+    pub async fn receive_message(&mut self) -> Result<Option<String>, NetworkError> {
+        self.reader.receive_message().await
+    }
+
+    /// Part the connection into a reader and a writer that work
+    /// independently, for receiving and sending from different tasks
+    pub fn into_split(self) -> (ConnectionReader, ConnectionWriter) {
+        let writer = self.reader.writer.clone();
+        (self.reader, writer)
+    }
+    
+    /// Get peer address
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.reader.writer.peer_addr
+    }
+
+    /// Relay handshake details, `None` for direct connections
+    pub fn relay_hello(&self) -> Option<&RelayHello> {
+        self.relay_hello.as_ref()
+    }
+
+    /// Largest frame this connection sends or accepts; for relay connections
+    /// the lower of our limit and the one the relay agreed to
+    pub fn max_message_size(&self) -> usize {
+        self.reader.writer.max_message_size
+    }
+
+    /// Longest message text, in bytes, that fits in one frame
+    pub fn max_plaintext(&self) -> usize {
+        self.reader.writer.max_plaintext()
+    }
+
+    /// The path this connection took: `DirectOnly` or `RelayOnly`, whichever
+    /// `Auto` ended up with
+    pub fn mode_used(&self) -> crate::ConnectionMode {
+        match self.reader.writer.is_relay {
+            true => crate::ConnectionMode::RelayOnly,
+            false => crate::ConnectionMode::DirectOnly,
+        }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.reader.writer.stats()
+    }
+
+    /// Send small frames at once instead of batching them (Nagle off)
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), NetworkError> {
+        Ok(self.reader.stream.as_ref().set_nodelay(nodelay)?)
+    }
+
+    /// Whether small frames go out at once
+    pub fn nodelay(&self) -> Result<bool, NetworkError> {
+        Ok(self.reader.stream.as_ref().nodelay()?)
+    }
+    
+    /// Hang up: shut down our sending side, so the peer (or the relay) reads
+    /// end of stream, and drop the connection
+    pub async fn close(self) -> Result<(), NetworkError> {
+        self.reader.writer.close().await
+    }
+
+    /// Send heartbeat
+    pub async fn send_heartbeat(&mut self) -> Result<(), NetworkError> {
+        self.reader.writer.send_heartbeat().await
+    }
+}
+
+impl ConnectionReader {
+    /// Read a length-prefixed frame, `None` on clean EOF
+    async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, NetworkError> {
+        let peer_addr = self.writer.peer_addr;
+        let max_message_size = self.writer.max_message_size;
+        // Read length prefix
+        let length = match self.stream.read_u32().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(NetworkError::Connection(e)),
+        };
+        
+        if length > max_message_size {
+            tracing::warn!(peer = %peer_addr, bytes = length, limit = max_message_size, "Oversized frame refused");
+            return Err(NetworkError::MessageTooLarge);
+        }
+        
+        // Read message data
+        let mut buffer = vec![0u8; length];
+        self.stream.read_exact(&mut buffer).await?;
+        Counters::add(&self.writer.counters.bytes_received, 4 + length);
+        tracing::trace!(peer = %peer_addr, bytes = length, "Frame received");
+        Ok(Some(buffer))
+    }
+    
+    /// Receive a network message; `None` when the connection has closed,
+    /// and for frames that carry no message, like heartbeats
+    pub async fn receive_message(&mut self) -> Result<Option<String>, NetworkError> {
+        match self.receive().await? {
+            Incoming::Message(PeerMessage::Text(text)) => Ok(Some(text)),
+            Incoming::Message(_) | Incoming::Control
+            | Incoming::Closed => {
+                Ok(None)
+            }
+        }
+    }
+
+    /// Wait for the next message, passing over frames that carry none;
+    /// `None` only once the connection has closed
+    pub async fn next_message(&mut self) -> Result<Option<PeerMessage>, NetworkError> {
+        loop {
+            match self.receive().await? {
+                Incoming::Message(message) => return Ok(Some(message)),
+                Incoming::Control => continue,
+                Incoming::Closed => return Ok(None),
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Incoming, NetworkError> {
+        let buffer = match self.read_frame().await? {
+            Some(buffer) => buffer,
+            None => return Ok(Incoming::Closed),
+        };
+        let peer_addr = self.writer.peer_addr;
+        
+        let (message_type, id, decrypted) = if self.writer.is_relay {
+            // Every relay frame leads with its type, so an empty one is invalid
+            let payload = match buffer.split_first() {
+                Some((&RELAY_FRAME_DATA, payload)) if !payload.is_empty() => payload,
+                // The relay checking we're still here
+                Some((&RELAY_FRAME_PING, [])) => {
+                    self.writer.write_frame(&[RELAY_FRAME_PONG]).await?;
+                    return Ok(Incoming::Control);
+                }
+                Some((&RELAY_FRAME_PONG, [])) => return Ok(Incoming::Control),
+                // The relay is disconnecting us (shutting down, draining, over
+                // quota, shedding load, kicked, idle)
+                Some((&RELAY_FRAME_GOODBYE, reason)) => {
+                    let reason = RelayRejectReason::from_frame(reason);
+                    tracing::info!(relay = %peer_addr, %reason, "Relay closed the connection");
+                    return Err(NetworkError::RelayClosed { reason });
+                }
+                // The relay discarded one of our frames: reason, then its u32 limit
+                Some((&RELAY_FRAME_ERROR, [RELAY_FRAME_TOO_LARGE, _, _, _, _])) => {
+                    return Err(NetworkError::MessageTooLarge);
+                }
+                _ => return Err(NetworkError::InvalidMessage),
+            };
+
+            // For relay connections, the payload is serialized encrypted data from the other peer.
+            // The type is sealed in with the rest, so unlike a direct one, a
+            // rotation ack we can't decrypt can't be told apart and fails the read.
+            let encrypted_data: crate::crypto::EncryptedMessage = bincode::deserialize(payload)?;
+            let decrypted = self.writer.crypto.lock().await.decrypt(&encrypted_data)?;
+            match decrypted.split_first() {
+                Some((&RELAY_CONTROL_MARKER, marked)) => {
+                    let (code, payload) = marked.split_first().ok_or(NetworkError::InvalidMessage)?;
+                    let message_type = MessageType::from_relay_code(*code).ok_or(NetworkError::InvalidMessage)?;
+                    (message_type, None, Ok(payload.to_vec()))
+                }
+                _ => (MessageType::Text, None, Ok(decrypted)),
+            }
+        } else {
+            // For direct P2P connections, deserialize NetworkMessage
+            let message: NetworkMessage = bincode::deserialize(&buffer)?;
+            let decrypted = match message.message_type {
+                // Taken by their type alone
+                MessageType::Heartbeat | MessageType::KeyRotation => Ok(Vec::new()),
+                _ => self.writer.crypto.lock().await.decrypt(&message.encrypted_data),
+            };
+            (message.message_type, Some(message.id), decrypted)
+        };
+        let relay = self.writer.is_relay;
+
+        // Process based on type
+        match message_type {
+            MessageType::Text => {
+                let text = String::from_utf8(decrypted?).map_err(|_| NetworkError::InvalidMessage)?;
+                tracing::debug!(peer = %peer_addr, bytes = buffer.len(), relay, "Message received");
+                Counters::add(&self.writer.counters.messages_received, 1);
+                // Only a direct peer's text has an id to confirm. The text is
+                // here either way; a connection too broken to confirm it
+                // shows up on the next read
+                if let Some(id) = id {
+                    if let Err(e) = self.writer.send_control(MessageType::Delivered, id.as_bytes()).await {
+                        tracing::debug!(peer = %peer_addr, error = %e, "Couldn't confirm a message arrived");
+                    }
+                }
+                Ok(Incoming::Message(PeerMessage::Text(text)))
+            }
+            MessageType::KeyRotation => {
+                // Handle key rotation notification
+                tracing::debug!(peer = %peer_addr, "Peer asked for key rotation");
+                self.writer.crypto.lock().await.rotate_keys()?;
+                // Answered under the new keys, which shows the peer they match
+                self.writer.send_control(MessageType::KeyRotationAck, b"rotated").await?;
+                Ok(Incoming::Control)
+            }
+            MessageType::KeyRotationAck => {
+                match decrypted {
+                    Ok(_) => {
+                        tracing::debug!(peer = %peer_addr, "Peer rotated its keys");
+                        self.writer.rotation_acked.notify_waiters();
+                    }
+                    Err(e) => {
+                        tracing::warn!(peer = %peer_addr, error = %e, "Peer's keys don't match ours after rotating");
+                        self.writer.rotation_rejected.notify_waiters();
+                    }
+                }
+                Ok(Incoming::Control)
+            }
+            MessageType::Hello => {
+                let decrypted = decrypted?;
+                let (theirs, instance) = decrypted.split_first_chunk().ok_or(NetworkError::InvalidMessage)?;
+                let instance = String::from_utf8(instance.to_vec()).map_err(|_| NetworkError::InvalidMessage)?;
+                let fingerprint = self.writer.crypto.lock().await.fingerprint().shared_with(&Fingerprint::from_bytes(*theirs));
+                Ok(Incoming::Message(PeerMessage::Hello { instance, fingerprint }))
+            }
+            MessageType::Delivered => {
+                let id = String::from_utf8(decrypted?).map_err(|_| NetworkError::InvalidMessage)?;
+                Ok(Incoming::Message(PeerMessage::Delivered { id }))
+            }
+            MessageType::Pairing => Ok(Incoming::Message(PeerMessage::Pairing { secret: decrypted? })),
+            MessageType::Ping => {
+                self.writer.send_control(MessageType::Pong, &decrypted?).await?;
+                Ok(Incoming::Control)
+            }
+            MessageType::Pong => {
+                let number = u64::from_be_bytes(decrypted?.try_into().map_err(|_| NetworkError::InvalidMessage)?);
+                let mut ping = self.writer.ping.lock().unwrap();
+                // Only the last ping counts; an answer to an earlier one is late
+                match *ping {
+                    Some((sent, at)) if sent == number => {
+                        *ping = None;
+                        let rtt = at.elapsed();
+                        let micros = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX).max(1);
+                        self.writer.counters.rtt.store(micros, Ordering::Relaxed);
+                        tracing::trace!(peer = %peer_addr, ?rtt, "Ping answered");
+                        Ok(Incoming::Message(PeerMessage::Pong { rtt }))
+                    }
+                    _ => Ok(Incoming::Control),
+                }
+            }
+            MessageType::Heartbeat => {
+                // Handle heartbeat
+                Ok(Incoming::Control) // Don't return heartbeat as user message
+            }
+            message_type @ (MessageType::FileOffer
+            | MessageType::FileChunk
+            | MessageType::FileAccept
+            | MessageType::FileReject) => {
+                let file = FileMessage::decode(message_type, &decrypted?)?;
+                tracing::debug!(peer = %peer_addr, bytes = buffer.len(), relay, "File message received");
+                Counters::add(&self.writer.counters.messages_received, 1);
+                Ok(Incoming::Message(PeerMessage::File(file)))
+            }
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.writer.peer_addr
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.writer.stats()
+    }
+}
+
+impl ConnectionWriter {
+    /// Send a text message
+    pub async fn send_text(&self, content: &str) -> Result<(), NetworkError> {
+        self.send_text_as(&uuid::Uuid::new_v4().to_string(), content).await
+    }
+
+    /// Send a text message under `id`, which a direct peer confirms it got
+    /// with a `PeerMessage::Delivered`; relays carry the text alone, so
+    /// relayed messages go unconfirmed
+    pub async fn send_text_as(&self, id: &str, content: &str) -> Result<(), NetworkError> {
         let message = NetworkMessage {
-            id: uuid::Uuid::new_v4().to_string(),
+            id: id.to_string(),
             message_type: MessageType::Text,
             encrypted_data: {
                 let mut crypto = self.crypto.lock().await;
@@ -115,24 +951,24 @@ impl P2PConnection {
             },
         };
         
-        self.send_message(&message).await
+        self.send_message(&message).await?;
+        Counters::add(&self.counters.messages_sent, 1);
+        Ok(())
     }
     
     /// Send a network message
-    async fn send_message(&mut self, message: &NetworkMessage) -> Result<(), NetworkError> {
+    async fn send_message(&self, message: &NetworkMessage) -> Result<(), NetworkError> {
         if self.is_relay {
-            // For relay connections, send serialized encrypted message
-            let data = bincode::serialize(&message.encrypted_data)?;
+            // For relay connections, send serialized encrypted message in a data frame
+            let mut frame = vec![RELAY_FRAME_DATA];
+            bincode::serialize_into(&mut frame, &message.encrypted_data)?;
             
-            if data.len() > self.max_message_size {
+            if frame.len() > self.max_message_size {
                 return Err(NetworkError::MessageTooLarge);
             }
             
-            // Send length prefix (4 bytes) followed by serialized encrypted data
-            let length = data.len() as u32;
-            self.stream.write_u32(length).await?;
-            self.stream.write_all(&data).await?;
-            self.stream.flush().await?;
+            self.write_frame(&frame).await?;
+            tracing::debug!(peer = %self.peer_addr, bytes = frame.len(), relay = true, "Message sent");
         } else {
             // For direct P2P connections, send full NetworkMessage
             let serialized = bincode::serialize(message)?;
@@ -141,81 +977,125 @@ impl P2PConnection {
                 return Err(NetworkError::MessageTooLarge);
             }
             
-            // Send length prefix (4 bytes) followed by message
-            let length = serialized.len() as u32;
-            self.stream.write_u32(length).await?;
-            self.stream.write_all(&serialized).await?;
-            self.stream.flush().await?;
+            self.write_frame(&serialized).await?;
+            tracing::debug!(peer = %self.peer_addr, bytes = serialized.len(), relay = false, "Message sent");
         }
         
         Ok(())
     }
-    
-    /// Receive a network message
-    pub async fn receive_message(&mut self) -> Result<Option<String>, NetworkError> {
-        // Read length prefix
-        let length = match self.stream.read_u32().await {
-            Ok(len) => len as usize,
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(NetworkError::Connection(e)),
-        };
-        
-        if length > self.max_message_size {
-            return Err(NetworkError::MessageTooLarge);
-        }
-        
-        // Read message data
-        let mut buffer = vec![0u8; length];
-        self.stream.read_exact(&mut buffer).await?;
-        
-        if self.is_relay {
-            // For relay connections, buffer contains serialized encrypted data from other peer
-            let encrypted_data: crate::crypto::EncryptedMessage = bincode::deserialize(&buffer)?;
-            let mut crypto = self.crypto.lock().await;
-            let decrypted = crypto.decrypt(&encrypted_data)?;
-            let text = String::from_utf8(decrypted)
-                .map_err(|_| NetworkError::InvalidMessage)?;
-            Ok(Some(text))
-        } else {
-            // For direct P2P connections, deserialize NetworkMessage
-            let message: NetworkMessage = bincode::deserialize(&buffer)?;
-            
-            // Decrypt and process based on type
-            match message.message_type {
-                MessageType::Text => {
-                    let mut crypto = self.crypto.lock().await;
-                    let decrypted = crypto.decrypt(&message.encrypted_data)?;
-                    let text = String::from_utf8(decrypted)
-                        .map_err(|_| NetworkError::InvalidMessage)?;
-                    Ok(Some(text))
-                }
-                MessageType::KeyRotation => {
-                    // Handle key rotation notification
-                    let mut crypto = self.crypto.lock().await;
-                    crypto.rotate_keys()?;
-                    Ok(None) // Don't return key rotation as user message
-                }
-                MessageType::Heartbeat => {
-                    // Handle heartbeat
-                    Ok(None) // Don't return heartbeat as user message
-                }
-            }
+
+    /// Write a length-prefixed frame
+    async fn write_frame(&self, data: &[u8]) -> Result<(), NetworkError> {
+        // Send length prefix (4 bytes) followed by payload
+        let length = data.len() as u32;
+        let mut stream = self.stream.lock().await;
+        stream.write_u32(length).await?;
+        stream.write_all(data).await?;
+        stream.flush().await?;
+        Counters::add(&self.counters.bytes_sent, 4 + data.len());
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+
+    /// Largest frame this connection sends
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Longest message text, in bytes, that fits in one frame
+    pub fn max_plaintext(&self) -> usize {
+        let overhead = if self.is_relay { RELAY_MESSAGE_OVERHEAD } else { MESSAGE_OVERHEAD };
+        self.max_message_size.saturating_sub(overhead)
+    }
+
+    /// Most file bytes one `FileChunk` carries on this connection
+    pub fn max_chunk(&self) -> usize {
+        let overhead = if self.is_relay { FILE_CHUNK_OVERHEAD + 2 } else { FILE_CHUNK_OVERHEAD };
+        self.max_plaintext().saturating_sub(overhead)
+    }
+
+    /// Send a file transfer message
+    pub async fn send_file_message(&self, file: &FileMessage) -> Result<(), NetworkError> {
+        self.send_control(file.message_type(), &file.encode()?).await?;
+        Counters::add(&self.counters.messages_sent, 1);
+        Ok(())
+    }
+
+    /// Hang up: shut down the sending side, so the peer (or the relay) reads
+    /// end of stream; the reader sees the connection end once the peer
+    /// closes theirs
+    pub async fn close(&self) -> Result<(), NetworkError> {
+        Ok(self.stream.lock().await.shutdown().await?)
+    }
+
+    /// Send heartbeat
+    pub async fn send_heartbeat(&self) -> Result<(), NetworkError> {
+        self.send_control(MessageType::Heartbeat, b"heartbeat").await
+    }
+
+    /// Ping the peer, to time how long its answer takes to come back
+    /// through the reader, as `PeerMessage::Pong` and in `stats`. Pinging
+    /// again before an answer forgets the earlier ping.
+    pub async fn send_ping(&self) -> Result<(), NetworkError> {
+        let number: u64 = rand::random();
+        *self.ping.lock().unwrap() = Some((number, Instant::now()));
+        self.send_control(MessageType::Ping, &number.to_be_bytes()).await
+    }
+
+    /// Ask the peer to rotate its keys, which should then match ours if we
+    /// just rotated, and wait up to `timeout` for it to confirm. The
+    /// confirmation comes in through the reader, so that has to be
+    /// receiving; one we can't decrypt fails with `KeysOutOfStep`, or
+    /// through a relay fails the reader.
+    pub async fn request_key_rotation(&self, timeout: Duration) -> Result<(), NetworkError> {
+        // Registered before asking, so a quick answer isn't missed
+        let acked = self.rotation_acked.notified();
+        let rejected = self.rotation_rejected.notified();
+        self.send_control(MessageType::KeyRotation, b"rotate").await?;
+        tokio::select! {
+            _ = acked => Ok(()),
+            _ = rejected => Err(NetworkError::KeysOutOfStep),
+            _ = tokio::time::sleep(timeout) => Err(NetworkError::Timeout),
         }
     }
-    
-    /// Get peer address
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.peer_addr
+
+    /// Tell the peer which app instance this is, and our session
+    /// fingerprint for the connection's
+    pub async fn send_hello(&self, instance: &str) -> Result<(), NetworkError> {
+        let payload = [self.crypto.lock().await.fingerprint().as_bytes(), instance.as_bytes()].concat();
+        self.send_control(MessageType::Hello, &payload).await
     }
-    
-    /// Send heartbeat
-    pub async fn send_heartbeat(&mut self) -> Result<(), NetworkError> {
+
+    /// Give the peer the pairing secret of the invite we connected with
+    pub async fn send_pairing(&self, secret: &[u8]) -> Result<(), NetworkError> {
+        self.send_control(MessageType::Pairing, secret).await
+    }
+
+    /// Send a message other than text. Relays carry it as text would go,
+    /// marked so the peer can tell it apart.
+    async fn send_control(&self, message_type: MessageType, payload: &[u8]) -> Result<(), NetworkError> {
+        let marked;
+        let payload = match (self.is_relay, message_type.relay_code()) {
+            (false, _) => payload,
+            (true, Some(code)) => {
+                marked = [[RELAY_CONTROL_MARKER, code].as_slice(), payload].concat();
+                &marked
+            }
+            (true, None) => return Err(NetworkError::NotOverRelay),
+        };
         let message = NetworkMessage {
             id: uuid::Uuid::new_v4().to_string(),
-            message_type: MessageType::Heartbeat,
+            message_type,
             encrypted_data: {
                 let mut crypto = self.crypto.lock().await;
-                crypto.encrypt(b"heartbeat")?
+                crypto.encrypt(payload)?
             },
         };
         
@@ -248,6 +1128,7 @@ impl P2PServer {
     /// Accept a single connection (P2P limitation)
     pub async fn accept(&self) -> Result<P2PConnection, NetworkError> {
         let (stream, addr) = self.listener.accept().await?;
+        tracing::info!(peer = %addr, "Peer connected");
         Ok(P2PConnection::new(
             stream,
             addr,
@@ -265,21 +1146,106 @@ impl P2PServer {
 
 // Simplified without complex trait bounds to avoid Send issues
 
+/// Measure the round trip to a relay with latency probes, which it answers
+/// before any join; connecting isn't counted, so the result reflects the
+/// relay's responsiveness rather than TCP setup
+pub async fn probe_relay(addr: SocketAddr, timeout: Duration) -> Result<Duration, NetworkError> {
+    let probe = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        let mut fastest = Duration::MAX;
+        for _ in 0..RELAY_PROBE_COUNT {
+            let id: [u8; 8] = rand::random();
+            let mut frame = vec![RELAY_REJECT_MARKER, RELAY_FRAME_PING];
+            frame.extend_from_slice(&id);
+            let sent_at = std::time::Instant::now();
+            stream.write_u32(frame.len() as u32).await?;
+            stream.write_all(&frame).await?;
+
+            let length = stream.read_u32().await? as usize;
+            if length > RELAY_PROBE_REPLY_LEN {
+                return Err(NetworkError::InvalidMessage);
+            }
+            let mut reply = vec![0u8; length];
+            stream.read_exact(&mut reply).await?;
+            match reply.split_first() {
+                Some((&RELAY_FRAME_PONG, rest)) if reply.len() == RELAY_PROBE_REPLY_LEN && rest[..8] == id => {}
+                // Relays refusing our address say so even to a probe
+                Some((&RELAY_REJECT_MARKER, reason)) if !reason.is_empty() => {
+                    return Err(NetworkError::RelayRejected { reason: RelayRejectReason::from_frame(reason) });
+                }
+                _ => return Err(NetworkError::InvalidMessage),
+            }
+            fastest = fastest.min(sent_at.elapsed());
+        }
+        Ok(fastest)
+    };
+    tokio::time::timeout(timeout, probe).await.map_err(|_| NetworkError::Timeout)?
+}
+
+/// Ask a relay which address it sees us at, as it answers before any join;
+/// behind NAT that is our public IP, with a port only that connection had
+pub async fn observed_address(addr: SocketAddr, timeout: Duration) -> Result<SocketAddr, NetworkError> {
+    let request = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let frame = [RELAY_REJECT_MARKER, RELAY_FRAME_ADDRESS];
+        stream.write_u32(frame.len() as u32).await?;
+        stream.write_all(&frame).await?;
+
+        let length = stream.read_u32().await? as usize;
+        if length > RELAY_ADDRESS_REPLY_MAX_LEN {
+            return Err(NetworkError::InvalidMessage);
+        }
+        let mut reply = vec![0u8; length];
+        stream.read_exact(&mut reply).await?;
+        match reply.split_first() {
+            // Relays that don't know the request take it for a bad join
+            Some((&RELAY_REJECT_MARKER, reason)) if !reason.is_empty() => {
+                Err(NetworkError::RelayRejected { reason: RelayRejectReason::from_frame(reason) })
+            }
+            _ => decode_observed_address(&reply).ok_or(NetworkError::InvalidMessage),
+        }
+    };
+    tokio::time::timeout(timeout, request).await.map_err(|_| NetworkError::Timeout)?
+}
+
+/// The address in a relay's answer to an address request: the address type,
+/// 4 or 6, the IP's octets and the big-endian port
+fn decode_observed_address(reply: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match reply {
+        [RELAY_FRAME_ADDRESS, 4, rest @ ..] if rest.len() == 4 + 2 => {
+            let (ip, port) = rest.split_at(4);
+            (IpAddr::from(<[u8; 4]>::try_from(ip).ok()?), port)
+        }
+        [RELAY_FRAME_ADDRESS, 6, rest @ ..] if rest.len() == 16 + 2 => {
+            let (ip, port) = rest.split_at(16);
+            (IpAddr::from(<[u8; 16]>::try_from(ip).ok()?), port)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes(port.try_into().ok()?)))
+}
+
+/// Outcome of probing one relay
+#[derive(Debug)]
+pub struct RelayProbe {
+    pub relay: String,
+    pub rtt: Result<Duration, NetworkError>,
+}
+
 /// Connection manager for handling P2P connections
 pub struct ConnectionManager {
     crypto: Arc<Mutex<SilenceCrypto>>,
-    max_message_size: usize,
-    relay_servers: Vec<String>,
+    /// Read on every connection attempt, so config updates reach new
+    /// connections without rebuilding the manager
+    config: ConfigHandle,
 }
 
 impl ConnectionManager {
     /// Create new connection manager
     pub fn new(crypto: Arc<Mutex<SilenceCrypto>>, max_message_size: usize) -> Self {
-        Self {
-            crypto,
-            max_message_size,
-            relay_servers: Vec::new(),
-        }
+        Self::with_relays(crypto, max_message_size, Vec::new())
     }
     
     /// Create new connection manager with relay servers
@@ -288,23 +1254,55 @@ impl ConnectionManager {
         max_message_size: usize,
         relay_servers: Vec<String>
     ) -> Self {
+        let config = Config {
+            max_message_size,
+            relay_servers: relay_servers.into_iter().map(RelayEntry::new).collect(),
+            ..Config::default()
+        };
         Self {
             crypto,
-            max_message_size,
-            relay_servers,
+            config: ConfigHandle::new(config),
         }
     }
     
+    /// Create a connection manager from validated app settings
+    pub fn from_config(crypto: Arc<Mutex<SilenceCrypto>>, config: &Config) -> Result<Self, NetworkError> {
+        Self::with_handle(crypto, ConfigHandle::new(config.clone()))
+    }
+
+    /// Create a connection manager that follows a live config
+    pub fn with_handle(crypto: Arc<Mutex<SilenceCrypto>>, config: ConfigHandle) -> Result<Self, NetworkError> {
+        config.current().validate().map_err(NetworkError::InvalidConfig)?;
+        Ok(Self { crypto, config })
+    }
+
+    /// The settings this manager connects with
+    pub fn config(&self) -> &ConfigHandle {
+        &self.config
+    }
+
     /// Start server and accept a single connection (P2P)
     pub async fn start_server(&self, bind_addr: SocketAddr) -> Result<P2PConnection, NetworkError> {
+        let server = self.listen(bind_addr).await?;
+        self.accept(&server).await
+    }
+
+    /// Start a server for `accept` to take any number of connections from
+    pub async fn listen(&self, bind_addr: SocketAddr) -> Result<P2PServer, NetworkError> {
         let server = P2PServer::new(
             bind_addr,
             Arc::clone(&self.crypto),
-            self.max_message_size,
+            self.config.current().max_message_size,
         ).await?;
         
-        println!("Server listening on {}", server.local_addr()?);
+        tracing::info!(addr = %server.local_addr()?, "Server listening");
+        Ok(server)
+    }
+
+    /// The next connection to `server`, set up like the ones we make
+    pub async fn accept(&self, server: &P2PServer) -> Result<P2PConnection, NetworkError> {
         let connection = server.accept().await?;
+        connection.set_nodelay(self.config.current().tcp_nodelay)?;
         Ok(connection)
     }
     
@@ -318,26 +1316,26 @@ impl ConnectionManager {
         match mode {
             crate::ConnectionMode::Auto => {
                 // Try direct connection first
-                match P2PConnection::connect(addr, Arc::clone(&self.crypto), self.max_message_size, false).await {
+                match self.connect_direct(addr).await {
                     Ok(connection) => {
-                        tracing::info!("Direct P2P connection established to {}", addr);
+                        tracing::info!(peer = %addr, "Direct P2P connection established");
                         Ok(connection)
                     }
                     Err(direct_err) => {
-                        tracing::warn!("Direct connection failed: {}, trying relay servers", direct_err);
+                        tracing::warn!(peer = %addr, error = %direct_err, "Direct connection failed, trying relay servers");
                         self.connect_via_relay().await.or(Err(direct_err))
                     }
                 }
             }
             crate::ConnectionMode::DirectOnly => {
                 // Only try direct connection
-                match P2PConnection::connect(addr, Arc::clone(&self.crypto), self.max_message_size, false).await {
+                match self.connect_direct(addr).await {
                     Ok(connection) => {
-                        tracing::info!("Direct P2P connection established to {}", addr);
+                        tracing::info!(peer = %addr, "Direct P2P connection established");
                         Ok(connection)
                     }
                     Err(err) => {
-                        tracing::error!("Direct connection failed (direct-only mode): {}", err);
+                        tracing::error!(peer = %addr, error = %err, "Direct connection failed (direct-only mode)");
                         Err(err)
                     }
                 }
@@ -350,19 +1348,105 @@ impl ConnectionManager {
         }
     }
     
-    /// Connect via relay servers only
+    /// Probe every configured relay at once, fastest first and unreachable
+    /// relays last; each gets `timeout` to resolve and answer
+    pub async fn probe_relays(&self, timeout: Duration) -> Vec<RelayProbe> {
+        let relay_servers = self.config.current().relay_servers;
+        let mut probes = tokio::task::JoinSet::new();
+        for entry in relay_servers.iter().cloned() {
+            probes.spawn(async move {
+                let started = std::time::Instant::now();
+                let rtt = match tokio::time::timeout(timeout, resolve_relay(&entry)).await {
+                    Ok(Ok(addr)) => probe_relay(addr, timeout.saturating_sub(started.elapsed())).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(NetworkError::Timeout),
+                };
+                RelayProbe { relay: entry.addr, rtt }
+            });
+        }
+
+        let mut results = Vec::with_capacity(relay_servers.len());
+        while let Some(probe) = probes.join_next().await {
+            if let Ok(probe) = probe {
+                results.push(probe);
+            }
+        }
+        results.sort_by_key(|probe| probe.rtt.as_ref().map_or(Duration::MAX, |rtt| *rtt));
+        results
+    }
+
+    /// Our address as the configured relays see it, asking them highest
+    /// priority first until one answers; `None` when none does. Returns the
+    /// relay that answered along with the address.
+    pub async fn observed_address(&self) -> Option<(String, SocketAddr)> {
+        let config = self.config.current();
+        let mut relays = config.relay_servers.clone();
+        relays.sort_by_key(|entry| std::cmp::Reverse(entry.priority));
+
+        for entry in &relays {
+            let timeout = entry.connect_timeout.unwrap_or(config.connection_timeout);
+            let result = match resolve_relay(entry).await {
+                Ok(addr) => observed_address(addr, timeout).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(observed) => return Some((entry.addr.clone(), observed)),
+                Err(e) => tracing::debug!(relay = %entry.addr, error = %e, "Relay didn't tell our address"),
+            }
+        }
+        None
+    }
+
+    /// Direct connection to a peer, with our socket options applied
+    async fn connect_direct(&self, addr: SocketAddr) -> Result<P2PConnection, NetworkError> {
+        let config = self.config.current();
+        let connect = P2PConnection::connect(addr, Arc::clone(&self.crypto), config.max_message_size, false);
+        let connection = tokio::time::timeout(config.connection_timeout, connect)
+            .await
+            .map_err(|_| NetworkError::Timeout)??;
+        connection.set_nodelay(config.tcp_nodelay)?;
+        Ok(connection)
+    }
+
+    /// Connect via relay servers only, joining the default session
     async fn connect_via_relay(&self) -> Result<P2PConnection, NetworkError> {
-        for relay in &self.relay_servers {
-            if let Ok(relay_addr) = relay.parse::<SocketAddr>() {
-                match P2PConnection::connect(relay_addr, Arc::clone(&self.crypto), self.max_message_size, true).await {
-                    Ok(connection) => {
-                        tracing::info!("Relay connection established via {}", relay);
-                        return Ok(connection);
-                    }
-                    Err(relay_err) => {
-                        tracing::warn!("Relay {} failed: {}", relay, relay_err);
-                        continue;
-                    }
+        self.connect_via_relay_session(DEFAULT_RELAY_SESSION).await
+    }
+
+    /// Connect via relay servers only, joining the given session. Relays are
+    /// tried highest priority first, each with its own token and timeout.
+    pub async fn connect_via_relay_session(&self, session_code: &str) -> Result<P2PConnection, NetworkError> {
+        let config = self.config.current();
+        let mut relays = config.relay_servers.clone();
+        // Stable, so equal priorities keep their order in the config
+        relays.sort_by_key(|entry| std::cmp::Reverse(entry.priority));
+
+        for entry in &relays {
+            let connect = async {
+                let addr = resolve_relay(entry).await?;
+                P2PConnection::connect_relay_with_token(
+                    addr,
+                    session_code,
+                    entry.token.as_deref(),
+                    Arc::clone(&self.crypto),
+                    config.max_message_size,
+                )
+                .await
+            };
+            let timeout = entry.connect_timeout.unwrap_or(config.connection_timeout);
+            let result = tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or(Err(NetworkError::Timeout));
+            match result {
+                Ok(connection) => {
+                    connection.set_nodelay(config.tcp_nodelay)?;
+                    let peer_present = connection.relay_hello().is_some_and(|hello| hello.peer_present);
+                    tracing::info!(relay = %entry.addr, peer_present, "Relay connection established");
+                    return Ok(connection);
+                }
+                Err(relay_err) => {
+                    tracing::warn!(relay = %entry.addr, error = %relay_err, "Relay failed");
+                    continue;
                 }
             }
         }
@@ -374,6 +1458,25 @@ impl ConnectionManager {
     }
 }
 
+/// Address to reach a relay at, or why it can't be used
+async fn resolve_relay(entry: &RelayEntry) -> Result<SocketAddr, NetworkError> {
+    if entry.tls {
+        return Err(NetworkError::Connection(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "TLS relays aren't supported by this client yet",
+        )));
+    }
+    tokio::net::lookup_host(entry.addr.as_str())
+        .await?
+        .next()
+        .ok_or_else(|| {
+            NetworkError::Connection(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "relay address didn't resolve",
+            ))
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -381,6 +1484,28 @@ mod tests {
     use std::time::Duration;
     use tokio::time::timeout;
     
+    #[test]
+    fn test_message_overhead_matches_serialized_size() {
+        let mut crypto = SilenceCrypto::new(60).unwrap();
+        let message = NetworkMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            message_type: MessageType::Text,
+            encrypted_data: crypto.encrypt(b"").unwrap(),
+        };
+        assert_eq!(bincode::serialize(&message).unwrap().len(), MESSAGE_OVERHEAD);
+        assert_eq!(bincode::serialize(&message.encrypted_data).unwrap().len(), ENCRYPTION_OVERHEAD);
+    }
+
+    #[test]
+    fn test_from_config_rejects_invalid_settings() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        assert!(ConnectionManager::from_config(Arc::clone(&crypto), &Config::default()).is_ok());
+
+        let config = Config { max_message_size: 0, ..Config::default() };
+        let result = ConnectionManager::from_config(crypto, &config);
+        assert!(matches!(result, Err(NetworkError::InvalidConfig(errors)) if errors.len() == 1));
+    }
+
     #[tokio::test]
     async fn test_local_connection() {
         let crypto1 = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
@@ -409,4 +1534,588 @@ mod tests {
             .unwrap()
             .unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_max_plaintext_fills_a_direct_frame() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 1024).await.unwrap();
+        let mut client = P2PConnection::connect(server.local_addr().unwrap(), crypto, 1024, false).await.unwrap();
+        assert_eq!(client.mode_used(), crate::ConnectionMode::DirectOnly);
+        assert_eq!(client.max_plaintext(), 1024 - MESSAGE_OVERHEAD);
+
+        let longest = "x".repeat(client.max_plaintext());
+        client.send_text(&longest).await.unwrap();
+        assert!(matches!(client.send_text(&format!("{}x", longest)).await, Err(NetworkError::MessageTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn test_file_chunks_fill_a_direct_frame() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 1024).await.unwrap();
+        let client = P2PConnection::connect(server.local_addr().unwrap(), crypto, 1024, false).await.unwrap();
+        let mut remote = server.accept().await.unwrap();
+        let (_, writer) = client.into_split();
+
+        let chunk = |data: Vec<u8>| {
+            let transfer_id = uuid::Uuid::new_v4().to_string();
+            FileMessage::Chunk(FileChunk { transfer_id, offset: 0, data })
+        };
+        let fullest = chunk(vec![7; writer.max_chunk()]);
+        writer.send_file_message(&fullest).await.unwrap();
+        let received = timeout(Duration::from_secs(5), remote.reader.next_message()).await.unwrap();
+        assert_eq!(received.unwrap(), Some(PeerMessage::File(fullest)));
+        let over = chunk(vec![7; writer.max_chunk() + 1]);
+        assert!(matches!(writer.send_file_message(&over).await, Err(NetworkError::MessageTooLarge)));
+
+        let answer = FileMessage::Reject { transfer_id: "t".to_string() };
+        writer.send_file_message(&answer).await.unwrap();
+        let received = timeout(Duration::from_secs(5), remote.reader.next_message()).await.unwrap();
+        assert_eq!(received.unwrap(), Some(PeerMessage::File(answer)));
+    }
+
+    #[tokio::test]
+    async fn test_split_connection_sends_while_receiving() {
+        // Shared, in place of a key exchange
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 4096).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = P2PConnection::connect(addr, crypto, 4096, false).await.unwrap();
+        let mut remote = server.accept().await.unwrap();
+
+        let (mut reader, writer) = client.into_split();
+        // Passing over the confirmations of what we send meanwhile
+        let receiving = tokio::spawn(async move {
+            let mut delivered = Vec::new();
+            loop {
+                match reader.next_message().await {
+                    Ok(Some(PeerMessage::Delivered { id })) => delivered.push(id),
+                    other => return (delivered, other),
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Neither send waits on the pending receive
+        let other_writer = writer.clone();
+        timeout(Duration::from_secs(5), writer.send_text("one")).await.unwrap().unwrap();
+        timeout(Duration::from_secs(5), other_writer.send_text("two")).await.unwrap().unwrap();
+        for expected in ["one", "two"] {
+            let message = timeout(Duration::from_secs(5), remote.receive_message()).await.unwrap();
+            assert_eq!(message.unwrap().unwrap(), expected);
+        }
+        assert!(!receiving.is_finished());
+
+        remote.send_text("back").await.unwrap();
+        let (delivered, received) = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert_eq!(received.unwrap(), Some(PeerMessage::Text("back".to_string())));
+        assert_eq!(delivered.len(), 2, "both sends confirmed");
+        let ack = timeout(Duration::from_secs(5), remote.reader.next_message()).await.unwrap();
+        assert!(matches!(ack, Ok(Some(PeerMessage::Delivered { .. }))));
+
+        // Both halves count the same connection, frames with their length
+        let stats = writer.stats();
+        assert_eq!((stats.messages_sent, stats.messages_received), (2, 1));
+        assert_eq!(stats.bytes_received, remote.stats().bytes_sent);
+        assert_eq!(stats.bytes_sent, remote.stats().bytes_received);
+        assert!(stats.bytes_sent > 2 * (4 + MESSAGE_OVERHEAD as u64));
+
+        // Closing the writer ends the stream for the peer
+        writer.close().await.unwrap();
+        let end = timeout(Duration::from_secs(5), remote.receive_message()).await.unwrap();
+        assert!(matches!(end, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_ping_times_the_round_trip() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 4096).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = P2PConnection::connect(addr, crypto, 4096, false).await.unwrap();
+        let (mut remote, _remote_writer) = server.accept().await.unwrap().into_split();
+        // The peer answers as it reads
+        let answering = tokio::spawn(async move { remote.next_message().await });
+
+        let (mut reader, writer) = client.into_split();
+        assert_eq!(writer.stats().rtt_ms, None);
+        writer.send_ping().await.unwrap();
+        let answer = timeout(Duration::from_secs(5), reader.next_message()).await.unwrap().unwrap();
+        let Some(PeerMessage::Pong { rtt }) = answer else {
+            panic!("{:?}", answer);
+        };
+        assert_eq!(writer.stats().rtt_ms, Some(rtt.as_millis() as u64));
+        assert!(!answering.is_finished());
+    }
+
+    /// Every event's fields as `name=value` text, in order
+    #[derive(Clone, Default)]
+    struct RecordedEvents(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Fields(String);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0 += &format!("{}={:?} ", field.name(), value);
+                }
+            }
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_events_are_traced() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let events = RecordedEvents::default();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(events.clone()));
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let server = P2PServer::new("127.0.0.1:0".parse().unwrap(), Arc::clone(&crypto), 4096).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let (accepted, client) = tokio::join!(
+            server.accept(),
+            P2PConnection::connect(server_addr, Arc::clone(&crypto), 4096, false),
+        );
+        let (mut accepted, mut client) = (accepted.unwrap(), client.unwrap());
+        client.send_text("traced").await.unwrap();
+        assert_eq!(accepted.receive_message().await.unwrap().as_deref(), Some("traced"));
+
+        let events = events.0.lock().unwrap();
+        let find = |message: &str| {
+            events
+                .iter()
+                .find(|event| event.starts_with(&format!("message={} ", message)))
+                .unwrap_or_else(|| panic!("no {:?} event in {:?}", message, events))
+        };
+        assert!(find("Peer connected").contains("peer="));
+        let sent = find("Message sent");
+        assert!(sent.contains(&format!("peer={} ", server_addr)) && sent.contains("bytes="), "{}", sent);
+        let received = find("Message received");
+        assert!(received.contains("bytes=") && received.contains("relay=false"), "{}", received);
+    }
+
+    /// Accept one client, check its join frame and answer with `reply`
+    async fn fake_relay(reply: Vec<u8>, expected_session: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let length = stream.read_u32().await.unwrap() as usize;
+            let mut join = vec![0u8; length];
+            stream.read_exact(&mut join).await.unwrap();
+            assert_eq!(join[0], WIRE_VERSION);
+            let (session, size_limit) = join[1..].split_at(expected_session.len());
+            assert_eq!(session, expected_session.as_bytes());
+            assert_eq!(size_limit[0], RELAY_SIZE_SEPARATOR);
+
+            stream.write_u32(reply.len() as u32).await.unwrap();
+            stream.write_all(&reply).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_relay_hello_parsed() {
+        let client_id = uuid::Uuid::new_v4();
+        let mut hello = vec![WIRE_VERSION];
+        hello.extend_from_slice(client_id.as_bytes());
+        hello.extend_from_slice(&2048u32.to_be_bytes());
+        hello.push(1);
+
+        let addr = fake_relay(hello, "pair-7").await;
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let connection = P2PConnection::connect_relay(addr, "pair-7", crypto, 4096).await.unwrap();
+
+        let hello = connection.relay_hello().expect("relay hello missing");
+        assert_eq!(hello.version, WIRE_VERSION);
+        assert_eq!(hello.features(), Features::CURRENT);
+        assert_eq!(hello.client_id, client_id);
+        assert_eq!(hello.max_message_size, 2048);
+        assert!(hello.peer_present);
+    }
+
+    #[tokio::test]
+    async fn test_relay_hello_in_unknown_version_refused() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        for version in [1, WIRE_VERSION + 1] {
+            let mut hello = vec![version];
+            hello.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+            hello.extend_from_slice(&4096u32.to_be_bytes());
+            hello.push(0);
+
+            let addr = fake_relay(hello, "pair-8").await;
+            let result = P2PConnection::connect_relay(addr, "pair-8", Arc::clone(&crypto), 4096).await;
+            assert!(
+                matches!(result, Err(NetworkError::IncompatibleVersion { version: v }) if v == version),
+                "version {}", version
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_frame_limit_is_negotiated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let join = read_relay_frame(&mut stream).await;
+
+            // The relay only goes along with part of what we asked for
+            let mut hello = vec![WIRE_VERSION];
+            hello.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+            hello.extend_from_slice(&1024u32.to_be_bytes());
+            hello.push(0);
+            write_relay_frame(&mut stream, &hello).await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            join
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let mut connection = P2PConnection::connect_relay(addr, "sized", crypto, 4096).await.unwrap();
+        assert_eq!(connection.max_message_size(), 1024);
+        assert_eq!(connection.mode_used(), crate::ConnectionMode::RelayOnly);
+        let longest = "x".repeat(connection.max_plaintext());
+        connection.send_text(&longest).await.unwrap();
+        assert!(matches!(connection.send_text(&format!("{}x", longest)).await, Err(NetworkError::MessageTooLarge)));
+
+        let join = relay.await.unwrap();
+        assert_eq!(&join[1..], b"sized\x01\x00\x00\x10\x00", "we ask for our own limit");
+    }
+
+    #[tokio::test]
+    async fn test_relay_garbage_hello_rejected() {
+        let addr = fake_relay(b"not a hello".to_vec(), DEFAULT_RELAY_SESSION).await;
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+
+        let result = P2PConnection::connect(addr, crypto, 4096, true).await;
+        assert!(matches!(result, Err(NetworkError::InvalidMessage)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejection_reason_surfaced() {
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+
+        let addr = fake_relay(vec![RELAY_REJECT_MARKER, 4], "busy").await;
+        let result = P2PConnection::connect_relay(addr, "busy", Arc::clone(&crypto), 4096).await;
+        assert!(matches!(result, Err(NetworkError::RelayRejected { reason: RelayRejectReason::ServerFull })));
+
+        let addr = fake_relay(vec![RELAY_REJECT_MARKER, 2], "busy").await;
+        let result = P2PConnection::connect_relay(addr, "busy", Arc::clone(&crypto), 4096).await;
+        let error = result.err().expect("draining relay accepted");
+        assert!(matches!(error, NetworkError::RelayRejected { reason: RelayRejectReason::Draining }));
+        assert_eq!(error.to_string(), "Relay rejected connection: relay is draining");
+
+        let addr = fake_relay(vec![RELAY_REJECT_MARKER, 14], "private").await;
+        let error = P2PConnection::connect_relay(addr, "private", crypto, 4096).await.err().expect("relay let us in");
+        assert!(matches!(error, NetworkError::RelayRejected { reason: RelayRejectReason::NotAllowed }));
+        assert_eq!(error.to_string(), "Relay rejected connection: relay doesn't admit this address");
+    }
+
+    /// Accept one client on `listener`, read its join and answer with a hello
+    async fn accept_relay_client(listener: TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let length = stream.read_u32().await.unwrap() as usize;
+        let mut join = vec![0u8; length];
+        stream.read_exact(&mut join).await.unwrap();
+
+        let mut hello = vec![WIRE_VERSION];
+        hello.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+        hello.extend_from_slice(&4096u32.to_be_bytes());
+        hello.push(0);
+        stream.write_u32(hello.len() as u32).await.unwrap();
+        stream.write_all(&hello).await.unwrap();
+        stream
+    }
+
+    async fn write_relay_frame(stream: &mut TcpStream, frame: &[u8]) {
+        stream.write_u32(frame.len() as u32).await.unwrap();
+        stream.write_all(frame).await.unwrap();
+    }
+
+    async fn read_relay_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let length = stream.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0u8; length];
+        stream.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_relay_ping_is_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+
+            // Ping, then expect a pong back
+            write_relay_frame(&mut stream, &[RELAY_FRAME_PING]).await;
+            read_relay_frame(&mut stream).await
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let mut connection = P2PConnection::connect_relay(addr, "pinged", crypto, 4096).await.unwrap();
+        assert!(connection.receive_message().await.unwrap().is_none());
+        assert_eq!(relay.await.unwrap(), vec![RELAY_FRAME_PONG]);
+    }
+
+    #[tokio::test]
+    async fn test_relay_pong_and_data_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+            write_relay_frame(&mut stream, &[RELAY_FRAME_PONG]).await;
+            read_relay_frame(&mut stream).await
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let mut connection = P2PConnection::connect_relay(addr, "typed", crypto, 4096).await.unwrap();
+        assert!(connection.receive_message().await.unwrap().is_none());
+
+        // What we send goes out as a data frame
+        connection.send_text("hello").await.unwrap();
+        let frame = relay.await.unwrap();
+        assert_eq!(frame[0], RELAY_FRAME_DATA);
+        assert!(bincode::deserialize::<crate::crypto::EncryptedMessage>(&frame[1..]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_relayed_file_messages_are_told_from_text() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Passes back whatever we send, as if the peer had sent it
+        tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+            for _ in 0..3 {
+                let frame = read_relay_frame(&mut stream).await;
+                write_relay_frame(&mut stream, &frame).await;
+            }
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let connection = P2PConnection::connect_relay(addr, "files", crypto, 4096).await.unwrap();
+        let (mut reader, writer) = connection.into_split();
+        assert_eq!(writer.max_chunk(), writer.max_plaintext() - FILE_CHUNK_OVERHEAD - 2);
+
+        let offer = FileMessage::Offer(FileOffer { transfer_id: "t".to_string(), name: "notes.txt".to_string(), size: 5 });
+        let chunk = FileMessage::Chunk(FileChunk { transfer_id: "t".to_string(), offset: 0, data: vec![0xFF; writer.max_chunk()] });
+        writer.send_file_message(&offer).await.unwrap();
+        writer.send_text("\u{ff} is text").await.unwrap();
+        writer.send_file_message(&chunk).await.unwrap();
+        for expected in [PeerMessage::File(offer), PeerMessage::Text("\u{ff} is text".to_string()), PeerMessage::File(chunk)] {
+            let received = timeout(Duration::from_secs(5), reader.next_message()).await.unwrap();
+            assert_eq!(received.unwrap(), Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relayed_control_messages_get_through() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Passes back whatever we send, as if the peer had sent it
+        tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+            for _ in 0..5 {
+                let frame = read_relay_frame(&mut stream).await;
+                write_relay_frame(&mut stream, &frame).await;
+            }
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let connection = P2PConnection::connect_relay(addr, "control", Arc::clone(&crypto), 4096).await.unwrap();
+        let (mut reader, writer) = connection.into_split();
+
+        writer.send_hello("instance").await.unwrap();
+        writer.send_heartbeat().await.unwrap();
+        let received = timeout(Duration::from_secs(5), reader.next_message()).await.unwrap();
+        let fingerprint = crypto.lock().await.fingerprint();
+        let hello = PeerMessage::Hello { instance: "instance".to_string(), fingerprint: fingerprint.shared_with(&fingerprint) };
+        assert_eq!(received.unwrap(), Some(hello));
+
+        // Our own request comes back to be answered, then the answer to confirm it
+        let asked = tokio::spawn(async move {
+            writer.request_key_rotation(Duration::from_secs(5)).await.unwrap();
+            writer.send_text("rotated").await.unwrap();
+        });
+        // The heartbeat, request and answer are passed over
+        let received = timeout(Duration::from_secs(5), reader.next_message()).await.unwrap();
+        assert_eq!(received.unwrap(), Some(PeerMessage::Text("rotated".to_string())));
+        asked.await.unwrap();
+        assert_eq!(crypto.lock().await.key_epoch(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_relay_empty_and_untyped_frames_are_invalid() {
+        for frame in [Vec::new(), vec![RELAY_FRAME_DATA], vec![RELAY_REJECT_MARKER, 9], vec![0x42, 1, 2]] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut stream = accept_relay_client(listener).await;
+                write_relay_frame(&mut stream, &frame).await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            });
+
+            let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+            let mut connection = P2PConnection::connect_relay(addr, "strict", crypto, 4096).await.unwrap();
+            assert!(matches!(connection.receive_message().await, Err(NetworkError::InvalidMessage)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_oversized_notice_maps_to_message_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+            write_relay_frame(&mut stream, &[RELAY_FRAME_ERROR, RELAY_FRAME_TOO_LARGE, 0, 0, 4, 0]).await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let mut connection = P2PConnection::connect_relay(addr, "sized", crypto, 4096).await.unwrap();
+        assert!(matches!(connection.receive_message().await, Err(NetworkError::MessageTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_disconnect_notice_reports_reason() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = accept_relay_client(listener).await;
+            write_relay_frame(&mut stream, &[RELAY_FRAME_GOODBYE, 9]).await;
+            write_relay_frame(&mut stream, &[RELAY_FRAME_GOODBYE, RELAY_FRAME_TOO_LARGE]).await;
+        });
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let mut connection = P2PConnection::connect_relay(addr, "shed", crypto, 4096).await.unwrap();
+        let error = connection.receive_message().await.unwrap_err();
+        assert!(matches!(error, NetworkError::RelayClosed { reason: RelayRejectReason::Overloaded }));
+        assert_eq!(error.to_string(), "Relay closed connection: relay is overloaded");
+        assert!(error.fail_over_immediately());
+        let error = connection.receive_message().await.unwrap_err();
+        assert!(matches!(error, NetworkError::RelayClosed { reason: RelayRejectReason::FrameTooLarge }));
+    }
+
+    /// A relay that answers probes after `delay`, or refuses with `rejection`
+    async fn probe_relay_server(delay: Duration, rejection: Option<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for received_us in 0u64.. {
+                let length = match stream.read_u32().await {
+                    Ok(length) => length as usize,
+                    Err(_) => return,
+                };
+                let mut probe = vec![0u8; length];
+                stream.read_exact(&mut probe).await.unwrap();
+                assert_eq!(&probe[..2], &[RELAY_REJECT_MARKER, RELAY_FRAME_PING]);
+
+                if let Some(reason) = rejection {
+                    write_relay_frame(&mut stream, &[RELAY_REJECT_MARKER, reason]).await;
+                    return;
+                }
+                tokio::time::sleep(delay).await;
+                let mut reply = vec![RELAY_FRAME_PONG];
+                reply.extend_from_slice(&probe[2..]);
+                reply.extend_from_slice(&received_us.to_be_bytes());
+                write_relay_frame(&mut stream, &reply).await;
+            }
+        });
+        addr
+    }
+
+    /// A relay that answers one address request with `reply`
+    async fn address_relay_server(reply: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let length = stream.read_u32().await.unwrap() as usize;
+            let mut request = vec![0u8; length];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [RELAY_REJECT_MARKER, RELAY_FRAME_ADDRESS]);
+            write_relay_frame(&mut stream, &reply).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_observed_address_from_relay() {
+        let reply = vec![RELAY_FRAME_ADDRESS, 4, 203, 0, 113, 9, 0xc8, 0x01];
+        let addr = address_relay_server(reply).await;
+        let observed = observed_address(addr, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(observed, "203.0.113.9:51201".parse().unwrap());
+
+        let mut reply = vec![RELAY_FRAME_ADDRESS, 6];
+        reply.extend_from_slice(&"2001:db8::7".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        reply.extend_from_slice(&443u16.to_be_bytes());
+        let addr = address_relay_server(reply).await;
+        let observed = observed_address(addr, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(observed, "[2001:db8::7]:443".parse().unwrap());
+
+        // An older relay refuses what it takes for a join of version 0
+        let addr = address_relay_server(vec![RELAY_REJECT_MARKER, 7, 3, 3]).await;
+        let result = observed_address(addr, Duration::from_secs(5)).await;
+        assert!(
+            matches!(result, Err(NetworkError::RelayRejected { reason: RelayRejectReason::UnsupportedVersion { .. } })),
+            "{:?}",
+            result
+        );
+
+        for reply in [vec![RELAY_FRAME_ADDRESS, 4, 1, 2, 3], vec![RELAY_FRAME_ADDRESS, 5, 1, 2, 3, 4, 0, 1], vec![RELAY_FRAME_PONG]] {
+            let addr = address_relay_server(reply.clone()).await;
+            let result = observed_address(addr, Duration::from_secs(5)).await;
+            assert!(matches!(result, Err(NetworkError::InvalidMessage)), "{:?}: {:?}", reply, result);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_relay_measures_round_trip() {
+        let addr = probe_relay_server(Duration::from_millis(30), None).await;
+        let rtt = probe_relay(addr, Duration::from_secs(5)).await.unwrap();
+        assert!(rtt >= Duration::from_millis(30) && rtt < Duration::from_secs(1), "{:?}", rtt);
+
+        let addr = probe_relay_server(Duration::ZERO, Some(5)).await;
+        let result = probe_relay(addr, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(NetworkError::RelayRejected { reason: RelayRejectReason::Banned })));
+
+        let addr = probe_relay_server(Duration::from_secs(10), None).await;
+        let result = probe_relay(addr, Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(NetworkError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_probe_relays_ranks_fastest_first() {
+        let slow = probe_relay_server(Duration::from_millis(80), None).await;
+        let fast = probe_relay_server(Duration::ZERO, None).await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+        let relays = vec![closed.to_string(), slow.to_string(), "not an address".to_string(), fast.to_string()];
+        let manager = ConnectionManager::with_relays(crypto, 4096, relays);
+        let probes = manager.probe_relays(Duration::from_secs(5)).await;
+
+        assert_eq!(probes.len(), 4);
+        assert_eq!(probes[0].relay, fast.to_string());
+        assert_eq!(probes[1].relay, slow.to_string());
+        assert!(probes[0].rtt.as_ref().unwrap() < probes[1].rtt.as_ref().unwrap());
+        assert!(probes[2..].iter().all(|probe| probe.rtt.is_err()));
+    }
+
+    #[tokio::test]
+    async fn test_relay_unsupported_version_reports_range() {
+        let addr = fake_relay(vec![RELAY_REJECT_MARKER, 7, 3, 4], "future").await;
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+
+        let result = P2PConnection::connect_relay(addr, "future", crypto, 4096).await;
+        assert!(matches!(
+            result,
+            Err(NetworkError::RelayRejected { reason: RelayRejectReason::UnsupportedVersion { min: 3, max: 4 } })
+        ));
+    }
+}
@@ -0,0 +1,773 @@
+// Port mapping on the home router, so peers can dial the listen port
+//
+// Two protocols are tried, UPnP first:
+// - UPnP IGD: an SSDP search finds the router's description, which names
+//   the control URL of its WANIPConnection (or WANPPPConnection) service;
+//   SOAP calls there add and delete the mapping and tell the external
+//   address
+// - NAT-PMP: UDP requests to port 5351 on the default gateway, which is
+//   only known on Linux for now
+//
+// Mappings are leases the router drops unless renewed; `MappedPort` renews
+// at half the lease and removes the mapping when stopped. Everything here
+// talks plain HTTP and UDP by hand, so there is no dependency to pull in.
+
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Where SSDP searches go
+pub const SSDP_MULTICAST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+/// The port NAT-PMP gateways listen on
+pub const NATPMP_PORT: u16 = 5351;
+
+/// Lease asked for; the router may grant less
+pub const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// How long one attempt to map, renew or remove may take
+pub const MAPPING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Shown in the router's mapping list
+const DESCRIPTION: &str = "Silence";
+
+/// Shortest wait between renewals, whatever lease was granted
+const MIN_RENEWAL: Duration = Duration::from_secs(1);
+
+/// A UPnP router that only takes leases without an end
+const UPNP_ONLY_PERMANENT_LEASES: &str = "725";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingProtocol {
+    Upnp,
+    NatPmp,
+}
+
+impl std::fmt::Display for MappingProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MappingProtocol::Upnp => write!(f, "UPnP"),
+            MappingProtocol::NatPmp => write!(f, "NAT-PMP"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PortMapError {
+    /// Nothing answered, or there is nowhere to ask
+    NoGateway,
+    Timeout,
+    Io(std::io::Error),
+    /// The router answered, but wouldn't
+    Refused(String),
+    /// The router's answer made no sense
+    InvalidResponse(String),
+    /// Every protocol failed, each for its own reason
+    Unavailable(Vec<(MappingProtocol, PortMapError)>),
+}
+
+impl std::fmt::Display for PortMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PortMapError::NoGateway => write!(f, "No router answered"),
+            PortMapError::Timeout => write!(f, "The router didn't answer in time"),
+            PortMapError::Io(e) => write!(f, "Network error: {}", e),
+            PortMapError::Refused(reason) => write!(f, "The router refused: {}", reason),
+            PortMapError::InvalidResponse(what) => write!(f, "Invalid answer from the router: {}", what),
+            PortMapError::Unavailable(errors) => {
+                let errors: Vec<_> = errors.iter().map(|(protocol, e)| format!("{}: {}", protocol, e)).collect();
+                write!(f, "Port mapping unavailable ({})", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PortMapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PortMapError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PortMapError {
+    fn from(err: std::io::Error) -> Self {
+        PortMapError::Io(err)
+    }
+}
+
+/// Where to look for a router
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gateways {
+    /// Where the SSDP search goes
+    pub ssdp: SocketAddr,
+    /// The NAT-PMP gateway, if known
+    pub natpmp: Option<SocketAddr>,
+}
+
+impl Gateways {
+    /// The SSDP multicast group and the default gateway
+    pub fn system() -> Self {
+        Self {
+            ssdp: SSDP_MULTICAST,
+            natpmp: default_gateway().map(|gateway| SocketAddr::new(IpAddr::V4(gateway), NATPMP_PORT)),
+        }
+    }
+}
+
+/// A port mapped on a router
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    /// Where peers outside can reach the mapped port
+    pub external: SocketAddr,
+    pub internal_port: u16,
+    /// The lease granted; zero for one that doesn't run out
+    pub lifetime: Duration,
+    router: Router,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Router {
+    Upnp { control: Endpoint, service_type: String, internal_client: IpAddr },
+    NatPmp(SocketAddr),
+}
+
+impl PortMapping {
+    /// Map TCP `port` to the same port outside, through UPnP or else
+    /// NAT-PMP, each given `timeout`
+    pub async fn create(gateways: &Gateways, port: u16, lifetime: Duration, timeout: Duration) -> Result<Self, PortMapError> {
+        let upnp = match with_timeout(timeout, upnp::map(gateways.ssdp, port, lifetime)).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e,
+        };
+        let natpmp = match gateways.natpmp {
+            Some(gateway) => match with_timeout(timeout, natpmp::map(gateway, port, port, lifetime)).await {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => e,
+            },
+            None => PortMapError::NoGateway,
+        };
+        Err(PortMapError::Unavailable(vec![(MappingProtocol::Upnp, upnp), (MappingProtocol::NatPmp, natpmp)]))
+    }
+
+    /// Extend the lease, keeping the external port
+    pub async fn renew(&mut self, timeout: Duration) -> Result<(), PortMapError> {
+        match &self.router {
+            Router::Upnp { control, service_type, internal_client } => {
+                let lifetime = if self.lifetime.is_zero() { Duration::ZERO } else { MAPPING_LIFETIME };
+                let add = upnp::add(control, service_type, *internal_client, self.internal_port, self.external.port(), lifetime);
+                with_timeout(timeout, add).await
+            }
+            Router::NatPmp(gateway) => {
+                // The router may hand out another port, or lease, this time
+                let renew = natpmp::map(*gateway, self.internal_port, self.external.port(), MAPPING_LIFETIME);
+                let renewed = with_timeout(timeout, renew).await?;
+                self.external = renewed.external;
+                self.lifetime = renewed.lifetime;
+                Ok(())
+            }
+        }
+    }
+
+    /// Take the mapping off the router
+    pub async fn remove(self, timeout: Duration) -> Result<(), PortMapError> {
+        match &self.router {
+            Router::Upnp { control, service_type, .. } => {
+                with_timeout(timeout, upnp::delete(control, service_type, self.external.port())).await
+            }
+            Router::NatPmp(gateway) => {
+                // A zero lifetime deletes
+                with_timeout(timeout, natpmp::map(*gateway, self.internal_port, 0, Duration::ZERO)).await.map(drop)
+            }
+        }
+    }
+
+    /// How long to wait before renewing, `None` for a lease that doesn't run out
+    pub fn renew_after(&self) -> Option<Duration> {
+        (!self.lifetime.is_zero()).then(|| (self.lifetime / 2).max(MIN_RENEWAL))
+    }
+}
+
+/// A mapping kept alive in the background until `stop`
+pub struct MappedPort {
+    mapping: Arc<Mutex<PortMapping>>,
+    renewal: JoinHandle<()>,
+}
+
+impl MappedPort {
+    /// Keep renewing `mapping`, calling `renew_failed` whenever that fails;
+    /// renewing carries on, in case the router comes back
+    pub fn start(mapping: PortMapping, renew_failed: impl Fn(PortMapError) + Send + 'static) -> Self {
+        let mapping = Arc::new(Mutex::new(mapping));
+        let renewed = Arc::clone(&mapping);
+        let renewal = tokio::spawn(async move {
+            loop {
+                let Some(after) = renewed.lock().await.renew_after() else {
+                    return;
+                };
+                tokio::time::sleep(after).await;
+                if let Err(e) = renewed.lock().await.renew(MAPPING_TIMEOUT).await {
+                    tracing::warn!(error = %e, "Renewing the port mapping failed");
+                    renew_failed(e);
+                }
+            }
+        });
+        Self { mapping, renewal }
+    }
+
+    pub async fn mapping(&self) -> PortMapping {
+        self.mapping.lock().await.clone()
+    }
+
+    /// Stop renewing and take the mapping off the router
+    pub async fn stop(self, timeout: Duration) -> Result<(), PortMapError> {
+        self.renewal.abort();
+        let mapping = self.mapping.lock().await.clone();
+        mapping.remove(timeout).await
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    operation: impl std::future::Future<Output = Result<T, PortMapError>>,
+) -> Result<T, PortMapError> {
+    tokio::time::timeout(timeout, operation).await.unwrap_or(Err(PortMapError::Timeout))
+}
+
+/// The IPv4 default gateway, from the kernel's routing table; Linux only
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            // Interface, destination, gateway, all in little-endian hex
+            [_, "00000000", gateway, ..] => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.swap_bytes())).filter(|gateway| !gateway.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Our address on the way to `addr`; nothing is sent
+async fn local_ip_towards(addr: SocketAddr) -> Result<IpAddr, PortMapError> {
+    let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// An HTTP address, as `host:port` and path
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Endpoint {
+    addr: SocketAddr,
+    path: String,
+}
+
+impl Endpoint {
+    /// From `http://ip:port/path`; routers give addresses, not names
+    fn parse(url: &str) -> Option<Self> {
+        let rest = url.trim().strip_prefix("http://")?;
+        let (host, path) = rest.split_once('/').map_or((rest, "/".to_string()), |(host, path)| (host, format!("/{}", path)));
+        let addr = host.parse().ok().or_else(|| host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 80)))?;
+        Some(Self { addr, path })
+    }
+
+    /// `reference` as found in a document from here: a full URL or a path
+    fn join(&self, reference: &str) -> Option<Self> {
+        let reference = reference.trim();
+        if reference.starts_with("http://") {
+            return Self::parse(reference);
+        }
+        let path = if reference.starts_with('/') { reference.to_string() } else { format!("/{}", reference) };
+        Some(Self { addr: self.addr, path })
+    }
+}
+
+/// Send an HTTP/1.1 request to `endpoint`, returning the status and body
+async fn http(endpoint: &Endpoint, method: &str, headers: &[(&str, &str)], body: &str) -> Result<(u16, String), PortMapError> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        endpoint.path,
+        endpoint.addr,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let mut stream = TcpStream::connect(endpoint.addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+
+    let invalid = || PortMapError::InvalidResponse("not HTTP".to_string());
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).ok_or_else(invalid)?;
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    let body = if chunked { dechunk(body).ok_or_else(invalid)? } else { body.to_string() };
+    Ok((status, body))
+}
+
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(out);
+        }
+        out.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+/// The text of the first `<name>` element in `xml`, namespace prefix or not
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let open = rest.find('<')?;
+        rest = &rest[open + 1..];
+        let tag_end = rest.find('>')?;
+        let tag = &rest[..tag_end];
+        let tag_name = tag.split_whitespace().next().unwrap_or("");
+        let local = tag_name.rsplit(':').next().unwrap_or(tag_name);
+        if local == name && !tag.ends_with('/') {
+            let content = &rest[tag_end + 1..];
+            let close = content.find(&format!("</{}>", tag_name))?;
+            return Some(content[..close].trim());
+        }
+        rest = &rest[tag_end + 1..];
+    }
+}
+
+/// Every `<name>` element's text in `xml`
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(content) = element(rest, name) {
+        found.push(content);
+        // Past this element's end
+        let end = content.as_ptr() as usize - rest.as_ptr() as usize + content.len();
+        rest = match rest[end..].find(&close) {
+            Some(at) => &rest[end + at + close.len()..],
+            None => break,
+        };
+    }
+    found
+}
+
+mod upnp {
+    use super::*;
+
+    const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+    const WAN_SERVICES: [&str; 2] = ["urn:schemas-upnp-org:service:WANIPConnection:", "urn:schemas-upnp-org:service:WANPPPConnection:"];
+
+    pub(super) async fn map(ssdp: SocketAddr, port: u16, lifetime: Duration) -> Result<PortMapping, PortMapError> {
+        let location = search(ssdp).await?;
+        let (control, service_type) = wan_service(&location).await?;
+        let internal_client = local_ip_towards(control.addr).await?;
+        let lifetime = match add(&control, &service_type, internal_client, port, port, lifetime).await {
+            Err(PortMapError::Refused(reason)) if reason.starts_with(UPNP_ONLY_PERMANENT_LEASES) => {
+                add(&control, &service_type, internal_client, port, port, Duration::ZERO).await?;
+                Duration::ZERO
+            }
+            result => result.map(|()| lifetime)?,
+        };
+        let response = soap(&control, &service_type, "GetExternalIPAddress", &[]).await?;
+        let ip = element(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| PortMapError::InvalidResponse("no external address".to_string()))?;
+        tracing::info!(%ip, port, "Port mapped through UPnP");
+        Ok(PortMapping {
+            protocol: MappingProtocol::Upnp,
+            external: SocketAddr::new(ip, port),
+            internal_port: port,
+            lifetime,
+            router: Router::Upnp { control, service_type, internal_client },
+        })
+    }
+
+    /// The first gateway's description URL
+    async fn search(ssdp: SocketAddr) -> Result<Endpoint, PortMapError> {
+        let bind: SocketAddr = if ssdp.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+        let socket = UdpSocket::bind(bind).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+            SSDP_MULTICAST, SEARCH_TARGET
+        );
+        socket.send_to(search.as_bytes(), ssdp).await?;
+
+        let mut buffer = [0; 2048];
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await?;
+            let response = String::from_utf8_lossy(&buffer[..length]);
+            let location = response.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+            });
+            if let Some(location) = location.as_deref().and_then(Endpoint::parse) {
+                return Ok(location);
+            }
+        }
+    }
+
+    /// The control endpoint and type of the gateway's WAN connection service
+    async fn wan_service(location: &Endpoint) -> Result<(Endpoint, String), PortMapError> {
+        let (status, description) = http(location, "GET", &[], "").await?;
+        if status != 200 {
+            return Err(PortMapError::InvalidResponse(format!("description answered {}", status)));
+        }
+        elements(&description, "service")
+            .into_iter()
+            .find_map(|service| {
+                let service_type = element(service, "serviceType")?;
+                if !WAN_SERVICES.iter().any(|wan| service_type.starts_with(wan)) {
+                    return None;
+                }
+                Some((location.join(element(service, "controlURL")?)?, service_type.to_string()))
+            })
+            .ok_or_else(|| PortMapError::InvalidResponse("no WAN connection service".to_string()))
+    }
+
+    pub(super) async fn add(
+        control: &Endpoint,
+        service_type: &str,
+        internal_client: IpAddr,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<(), PortMapError> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", "TCP".to_string()),
+            ("NewInternalPort", internal_port.to_string()),
+            ("NewInternalClient", internal_client.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lifetime.as_secs().to_string()),
+        ];
+        soap(control, service_type, "AddPortMapping", &args).await.map(drop)
+    }
+
+    pub(super) async fn delete(control: &Endpoint, service_type: &str, external_port: u16) -> Result<(), PortMapError> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", "TCP".to_string()),
+        ];
+        soap(control, service_type, "DeletePortMapping", &args).await.map(drop)
+    }
+
+    /// Call `action` on the service, returning the response body; a fault
+    /// comes back as `Refused` with the UPnP error code first
+    async fn soap(control: &Endpoint, service_type: &str, action: &str, args: &[(&str, String)]) -> Result<String, PortMapError> {
+        let args: String = args.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, value)).collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service_type}\">{args}</u:{action}></s:Body></s:Envelope>"
+        );
+        let soap_action = format!("\"{}#{}\"", service_type, action);
+        let headers = [("Content-Type", "text/xml; charset=\"utf-8\""), ("SOAPAction", soap_action.as_str())];
+        let (status, response) = http(control, "POST", &headers, &body).await?;
+        match status {
+            200 => Ok(response),
+            _ => {
+                let code = element(&response, "errorCode").unwrap_or("?");
+                let description = element(&response, "errorDescription").unwrap_or("no reason given");
+                Err(PortMapError::Refused(format!("{} {} ({})", code, description, action)))
+            }
+        }
+    }
+}
+
+mod natpmp {
+    use super::*;
+
+    const VERSION: u8 = 0;
+    const OP_EXTERNAL_ADDRESS: u8 = 0;
+    const OP_MAP_TCP: u8 = 2;
+    /// Added to the opcode in responses
+    const RESPONSE: u8 = 128;
+    /// Wait before the first resend, doubled after each
+    const FIRST_RESEND: Duration = Duration::from_millis(250);
+
+    pub(super) async fn map(gateway: SocketAddr, internal_port: u16, external_port: u16, lifetime: Duration) -> Result<PortMapping, PortMapError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(gateway).await?;
+
+        let response = request(&socket, &[VERSION, OP_EXTERNAL_ADDRESS], OP_EXTERNAL_ADDRESS, 12).await?;
+        let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        let mut map = vec![VERSION, OP_MAP_TCP, 0, 0];
+        map.extend_from_slice(&internal_port.to_be_bytes());
+        map.extend_from_slice(&external_port.to_be_bytes());
+        let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+        map.extend_from_slice(&lifetime.to_be_bytes());
+        let response = request(&socket, &map, OP_MAP_TCP, 16).await?;
+        let mapped_port = u16::from_be_bytes([response[10], response[11]]);
+        let granted = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+        if lifetime > 0 {
+            tracing::info!(%ip, port = mapped_port, lifetime = granted, "Port mapped through NAT-PMP");
+        }
+        Ok(PortMapping {
+            protocol: MappingProtocol::NatPmp,
+            external: SocketAddr::new(IpAddr::V4(ip), mapped_port),
+            internal_port,
+            lifetime: Duration::from_secs(granted.into()),
+            router: Router::NatPmp(gateway),
+        })
+    }
+
+    /// Send `packet` until the answer to `op` arrives, resending at growing
+    /// intervals as the protocol asks; the caller's timeout ends it
+    async fn request(socket: &UdpSocket, packet: &[u8], op: u8, length: usize) -> Result<Vec<u8>, PortMapError> {
+        let mut resend = FIRST_RESEND;
+        let mut buffer = [0; 16];
+        loop {
+            socket.send(packet).await?;
+            let Ok(received) = tokio::time::timeout(resend, socket.recv(&mut buffer)).await else {
+                resend *= 2;
+                continue;
+            };
+            let received = received?;
+            if received < 4 || buffer[0] != VERSION || buffer[1] != RESPONSE + op {
+                continue;
+            }
+            match u16::from_be_bytes([buffer[2], buffer[3]]) {
+                0 if received >= length => return Ok(buffer[..length].to_vec()),
+                0 => return Err(PortMapError::InvalidResponse(format!("{} byte answer", received))),
+                code => return Err(PortMapError::Refused(result_code(code).to_string())),
+            }
+        }
+    }
+
+    fn result_code(code: u16) -> &'static str {
+        match code {
+            1 => "unsupported version",
+            2 => "not authorized",
+            3 => "network failure",
+            4 => "out of resources",
+            5 => "unsupported opcode",
+            _ => "unknown error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const EXTERNAL_IP: &str = "203.0.113.9";
+
+    /// A UPnP router: answers SSDP searches on one socket and HTTP on a
+    /// listener, recording the SOAP actions called
+    async fn mock_igd() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let actions = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+            loop {
+                let (length, from) = ssdp.recv_from(&mut buffer).await.unwrap();
+                assert!(String::from_utf8_lossy(&buffer[..length]).starts_with("M-SEARCH"));
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLocation: http://{}/rootDesc.xml\r\n\r\n",
+                    http_addr
+                );
+                ssdp.send_to(response.as_bytes(), from).await.unwrap();
+            }
+        });
+
+        let recorded = Arc::clone(&actions);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = http.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                // Headers, then the body by its length
+                let (head, mut body) = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        break (head.to_string(), body.to_string());
+                    }
+                };
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                while body.len() < length {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    body.push_str(&String::from_utf8_lossy(&buffer[..read]));
+                }
+
+                let (status, response) = if head.starts_with("GET /rootDesc.xml") {
+                    // Chunked, as some routers send it
+                    let description = "<root><device><serviceList>\
+                        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service>\
+                        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service>\
+                        </serviceList></device></root>";
+                    let (first, second) = description.split_at(40);
+                    let chunked = format!("{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n", first.len(), first, second.len(), second);
+                    let response = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}", chunked);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    continue;
+                } else {
+                    let action = head.lines().find_map(|line| line.strip_prefix("SOAPAction: ")).unwrap();
+                    let action = action.trim_matches('"').rsplit('#').next().unwrap().to_string();
+                    recorded.lock().unwrap().push(action.clone());
+                    match action.as_str() {
+                        "AddPortMapping" if body.contains("<NewInternalClient>127.0.0.1</NewInternalClient>") => (200, String::new()),
+                        "GetExternalIPAddress" => (200, format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", EXTERNAL_IP)),
+                        "DeletePortMapping" => (200, String::new()),
+                        _ => (500, "<UPnPError><errorCode>402</errorCode><errorDescription>Invalid Args</errorDescription></UPnPError>".to_string()),
+                    }
+                };
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (ssdp_addr, actions)
+    }
+
+    /// A NAT-PMP gateway granting `lifetime` seconds, or failing every
+    /// request with `result` when it isn't 0; counts the map requests
+    async fn mock_natpmp(result: u16, lifetime: u32) -> (SocketAddr, Arc<std::sync::Mutex<Vec<u32>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requested);
+        tokio::spawn(async move {
+            let mut buffer = [0; 16];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let mut response = vec![0, 128 + buffer[1]];
+                response.extend_from_slice(&result.to_be_bytes());
+                response.extend_from_slice(&7u32.to_be_bytes());
+                match (buffer[1], length) {
+                    (0, 2) => response.extend_from_slice(&EXTERNAL_IP.parse::<Ipv4Addr>().unwrap().octets()),
+                    (2, 12) => {
+                        let asked = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
+                        recorded.lock().unwrap().push(asked);
+                        response.extend_from_slice(&buffer[4..6]);
+                        // 7642 is taken, so it gets the next port up
+                        let external = u16::from_be_bytes([buffer[6], buffer[7]]);
+                        let external = if external == 7642 { 7643 } else { external };
+                        response.extend_from_slice(&external.to_be_bytes());
+                        response.extend_from_slice(&if asked == 0 { 0 } else { lifetime }.to_be_bytes());
+                    }
+                    _ => panic!("unexpected request {:?}", &buffer[..length]),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, requested)
+    }
+
+    /// Nothing answers here
+    async fn silent() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    #[tokio::test]
+    async fn test_upnp_mapping_lifecycle() {
+        let (ssdp, actions) = mock_igd().await;
+        let gateways = Gateways { ssdp, natpmp: None };
+        let mut mapping = PortMapping::create(&gateways, 7642, MAPPING_LIFETIME, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::Upnp);
+        assert_eq!(mapping.external, format!("{}:7642", EXTERNAL_IP).parse().unwrap());
+        assert_eq!(mapping.renew_after(), Some(MAPPING_LIFETIME / 2));
+
+        mapping.renew(Duration::from_secs(5)).await.unwrap();
+        mapping.remove(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(
+            *actions.lock().unwrap(),
+            ["AddPortMapping", "GetExternalIPAddress", "AddPortMapping", "DeletePortMapping"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_natpmp_when_upnp_is_silent() {
+        let (_ssdp, ssdp) = silent().await;
+        let (natpmp, requested) = mock_natpmp(0, 2).await;
+        let gateways = Gateways { ssdp, natpmp: Some(natpmp) };
+        let mapping = PortMapping::create(&gateways, 7642, MAPPING_LIFETIME, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::NatPmp);
+        assert_eq!(mapping.external, format!("{}:7643", EXTERNAL_IP).parse().unwrap());
+        assert_eq!(mapping.lifetime, Duration::from_secs(2));
+
+        // Renewed at half the two second lease, then removed
+        let mapped = MappedPort::start(mapping, |e| panic!("renewal failed: {}", e));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(mapped.mapping().await.external.port(), 7643, "renewing keeps the external port");
+        mapped.stop(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(*requested.lock().unwrap(), [3600, 3600, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_failures_name_every_protocol() {
+        let (_ssdp, ssdp) = silent().await;
+        let (natpmp, _) = mock_natpmp(2, 0).await;
+        let gateways = Gateways { ssdp, natpmp: Some(natpmp) };
+        let error = PortMapping::create(&gateways, 7642, MAPPING_LIFETIME, Duration::from_millis(300)).await.unwrap_err();
+        assert!(
+            matches!(&error, PortMapError::Unavailable(errors) if matches!(errors.as_slice(), [
+                (MappingProtocol::Upnp, PortMapError::Timeout),
+                (MappingProtocol::NatPmp, PortMapError::Refused(_)),
+            ])),
+            "{:?}",
+            error
+        );
+        assert_eq!(
+            error.to_string(),
+            "Port mapping unavailable (UPnP: The router didn't answer in time; NAT-PMP: The router refused: not authorized)"
+        );
+
+        let gateways = Gateways { ssdp, natpmp: None };
+        let error = PortMapping::create(&gateways, 7642, MAPPING_LIFETIME, Duration::from_millis(100)).await.unwrap_err();
+        assert!(error.to_string().ends_with("NAT-PMP: No router answered)"), "{}", error);
+    }
+
+    #[test]
+    fn test_reading_router_documents() {
+        let base = Endpoint::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(base.addr, "192.168.1.1:5000".parse().unwrap());
+        assert_eq!(base.join("ctl/IPConn").unwrap().path, "/ctl/IPConn");
+        assert_eq!(base.join("http://192.168.1.1:5001/ctl").unwrap().addr.port(), 5001);
+        assert_eq!(Endpoint::parse("http://192.168.1.1").unwrap().addr.port(), 80);
+        assert_eq!(Endpoint::parse("http://router.local/desc.xml"), None);
+
+        let xml = "<s:Body><u:R xmlns:u=\"x\"><NewExternalIPAddress>203.0.113.9</NewExternalIPAddress></u:R></s:Body>";
+        assert_eq!(element(xml, "NewExternalIPAddress"), Some("203.0.113.9"));
+        assert_eq!(element(xml, "Body").map(|body| body.starts_with("<u:R")), Some(true));
+        assert_eq!(elements("<a><b>1</b><b>2</b></a>", "b"), ["1", "2"]);
+        assert_eq!(dechunk("3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n").as_deref(), Some("abcde"));
+    }
+}
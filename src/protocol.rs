@@ -0,0 +1,178 @@
+// Relay wire protocol versions, shared by the client and the relay
+//
+// The client's join frame leads with the version it speaks and the relay's
+// hello answers with the version the session will speak. Each version is a
+// fixed set of features; a side that is newer than its peer talks down to
+// the peer's version, as the older side can't know what a newer one changed.
+
+/// Wire version spoken by this build; version 2 added the join version byte,
+/// version 3 the frame type byte
+pub const WIRE_VERSION: u8 = 3;
+
+/// Oldest wire version still spoken; older peers send untyped frames and
+/// empty keepalives
+pub const MIN_SUPPORTED: u8 = 3;
+
+/// Set of protocol features, as a bitmask
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Features(u16);
+
+impl Features {
+    /// No features
+    pub const NONE: Features = Features(0);
+    /// The join frame leads with the client's wire version
+    pub const VERSIONED_JOIN: Features = Features(1 << 0);
+    /// Frames after the hello lead with a type byte, and keepalives are pings
+    pub const TYPED_FRAMES: Features = Features(1 << 1);
+    /// Joins may carry an auth token
+    pub const AUTH_TOKEN: Features = Features(1 << 2);
+    /// Latency probes answered before joining
+    pub const PROBES: Features = Features(1 << 3);
+    /// The relay says goodbye with a reason before closing
+    pub const GOODBYE: Features = Features(1 << 4);
+    /// Joins may ask for a smaller frame size limit
+    pub const FRAME_SIZE: Features = Features(1 << 5);
+
+    /// Everything this build's wire version has
+    pub const CURRENT: Features = Features::for_version(WIRE_VERSION);
+
+    /// Names, in bit order, for display
+    const NAMES: [(Features, &'static str); 6] = [
+        (Features::VERSIONED_JOIN, "versioned_join"),
+        (Features::TYPED_FRAMES, "typed_frames"),
+        (Features::AUTH_TOKEN, "auth_token"),
+        (Features::PROBES, "probes"),
+        (Features::GOODBYE, "goodbye"),
+        (Features::FRAME_SIZE, "frame_size"),
+    ];
+
+    /// Features a wire version has; nothing for versions this build doesn't
+    /// know, whether older or newer
+    pub const fn for_version(version: u8) -> Features {
+        match version {
+            2 => Features::VERSIONED_JOIN,
+            3 => Features(
+                Features::VERSIONED_JOIN.0
+                    | Features::TYPED_FRAMES.0
+                    | Features::AUTH_TOKEN.0
+                    | Features::PROBES.0
+                    | Features::GOODBYE.0
+                    | Features::FRAME_SIZE.0,
+            ),
+            _ => Features::NONE,
+        }
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Features in `self` but not in `other`
+    pub const fn difference(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl std::fmt::Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let mut first = true;
+        for (feature, name) in Features::NAMES {
+            if self.contains(feature) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How well this build can talk to a peer speaking some wire version
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    /// Same version, every feature available
+    Full,
+    /// An older version still spoken, lacking these features
+    Degraded(Features),
+    /// Older than `MIN_SUPPORTED`, or newer than `WIRE_VERSION`
+    Incompatible,
+}
+
+/// How well this build can talk to a peer speaking `theirs`
+pub fn is_compatible(theirs: u8) -> Compatibility {
+    compatibility(MIN_SUPPORTED, WIRE_VERSION, theirs)
+}
+
+fn compatibility(min: u8, ours: u8, theirs: u8) -> Compatibility {
+    if theirs == ours {
+        Compatibility::Full
+    } else if (min..ours).contains(&theirs) {
+        Compatibility::Degraded(Features::for_version(ours).difference(Features::for_version(theirs)))
+    } else {
+        Compatibility::Incompatible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_versions_pinned() {
+        // Changing these changes what is on the wire; old clients and relays
+        // in the field depend on them
+        assert_eq!(WIRE_VERSION, 3);
+        assert_eq!(MIN_SUPPORTED, 3);
+        assert_eq!(Features::CURRENT.bits(), 0b11_1111);
+        assert_eq!(Features::CURRENT.to_string(), "versioned_join, typed_frames, auth_token, probes, goodbye, frame_size");
+    }
+
+    #[test]
+    fn test_compatibility_matrix() {
+        let cases = [
+            (0, Compatibility::Incompatible),
+            (1, Compatibility::Incompatible),
+            (2, Compatibility::Incompatible),
+            (3, Compatibility::Full),
+            (4, Compatibility::Incompatible),
+            (u8::MAX, Compatibility::Incompatible),
+        ];
+        for (theirs, expected) in cases {
+            assert_eq!(is_compatible(theirs), expected, "version {}", theirs);
+        }
+    }
+
+    #[test]
+    fn test_older_supported_versions_are_degraded() {
+        // As if this build still spoke version 2
+        let missing = match compatibility(2, 3, 2) {
+            Compatibility::Degraded(missing) => missing,
+            other => panic!("{:?}", other),
+        };
+        assert!(missing.contains(Features::TYPED_FRAMES | Features::GOODBYE));
+        assert!(!missing.contains(Features::VERSIONED_JOIN));
+        assert_eq!(compatibility(2, 3, 1), Compatibility::Incompatible);
+        assert_eq!(Features::NONE.to_string(), "none");
+    }
+}
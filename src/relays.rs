@@ -0,0 +1,145 @@
+// Whether the configured relays answer, for the GUI to show before connecting
+//
+// `get_relay_status` probes every relay at once with `RELAY_STATUS_TIMEOUT`
+// for the lot, and keeps the answer for `RELAY_STATUS_TTL` so a GUI polling
+// it doesn't have every relay probed on each poll. Probes go on their own
+// connections and never join, so they leave an active session alone.
+
+use crate::network::{ConnectionManager, RelayProbe};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How long probing all the relays may take together
+pub const RELAY_STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a probe's answer is given again rather than probing anew
+pub const RELAY_STATUS_TTL: Duration = Duration::from_secs(10);
+
+/// How one relay answered, as `get_relay_status` reports it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RelayStatus {
+    /// The relay as configured
+    pub addr: String,
+    pub reachable: bool,
+    /// Fastest round trip, when reachable
+    pub rtt_ms: Option<u64>,
+    /// Why not, when unreachable
+    pub error: Option<String>,
+}
+
+impl From<RelayProbe> for RelayStatus {
+    fn from(probe: RelayProbe) -> Self {
+        match probe.rtt {
+            Ok(rtt) => Self { addr: probe.relay, reachable: true, rtt_ms: Some(rtt.as_millis() as u64), error: None },
+            Err(e) => Self { addr: probe.relay, reachable: false, rtt_ms: None, error: Some(e.to_string()) },
+        }
+    }
+}
+
+struct Probed {
+    at: Instant,
+    /// Which relays were probed, so a config change isn't answered from
+    /// the old list
+    relays: Vec<String>,
+    statuses: Vec<RelayStatus>,
+}
+
+/// The latest relay statuses, probed again once they're `ttl` old
+pub struct RelayStatusCache {
+    ttl: Duration,
+    timeout: Duration,
+    latest: Mutex<Option<Probed>>,
+}
+
+impl Default for RelayStatusCache {
+    fn default() -> Self {
+        Self::new(RELAY_STATUS_TTL, RELAY_STATUS_TIMEOUT)
+    }
+}
+
+impl RelayStatusCache {
+    pub fn new(ttl: Duration, timeout: Duration) -> Self {
+        Self { ttl, timeout, latest: Mutex::new(None) }
+    }
+
+    /// Status of each of `manager`'s relays, reachable fastest first;
+    /// callers arriving while a probe runs wait for its answer rather
+    /// than starting another
+    pub async fn get(&self, manager: &ConnectionManager) -> Vec<RelayStatus> {
+        let relays: Vec<String> = manager.config().current().relay_servers.into_iter().map(|entry| entry.addr).collect();
+        let mut latest = self.latest.lock().await;
+        if let Some(probed) = latest.as_ref().filter(|probed| probed.at.elapsed() < self.ttl && probed.relays == relays) {
+            return probed.statuses.clone();
+        }
+
+        let statuses: Vec<RelayStatus> = manager.probe_relays(self.timeout).await.into_iter().map(RelayStatus::from).collect();
+        *latest = Some(Probed { at: Instant::now(), relays, statuses: statuses.clone() });
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NetworkError, SilenceCrypto};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// A "relay" that hangs up on every connection, counting them
+    async fn hanging_up_relay() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn manager(relays: Vec<String>) -> ConnectionManager {
+        let crypto = Arc::new(tokio::sync::Mutex::new(SilenceCrypto::new(60).unwrap()));
+        ConnectionManager::with_relays(crypto, 4096, relays)
+    }
+
+    #[test]
+    fn test_probe_outcomes_become_statuses() {
+        let reached = RelayProbe { relay: "relay.example:8080".to_string(), rtt: Ok(Duration::from_micros(12_400)) };
+        assert_eq!(
+            RelayStatus::from(reached),
+            RelayStatus { addr: "relay.example:8080".to_string(), reachable: true, rtt_ms: Some(12), error: None }
+        );
+
+        let missed = RelayProbe { relay: "relay.example:8080".to_string(), rtt: Err(NetworkError::Timeout) };
+        let status = RelayStatus::from(missed);
+        assert!(!status.reachable && status.rtt_ms.is_none());
+        assert_eq!(status.error.as_deref(), Some(NetworkError::Timeout.to_string().as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_answers_from_cache_until_stale() {
+        let (relay, accepted) = hanging_up_relay().await;
+        let manager = manager(vec![relay]);
+        let cache = RelayStatusCache::new(Duration::from_millis(300), Duration::from_secs(2));
+
+        let first = cache.get(&manager).await;
+        assert_eq!(first.len(), 1);
+        assert!(!first[0].reachable);
+        assert_eq!(cache.get(&manager).await, first);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        cache.get(&manager).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // A changed relay list is probed straight away
+        let (other, _) = hanging_up_relay().await;
+        manager.config().update(|config| config.relay_servers.push(crate::RelayEntry::new(other))).unwrap();
+        assert_eq!(cache.get(&manager).await.len(), 2);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+}
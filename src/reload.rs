@@ -0,0 +1,533 @@
+// Live configuration for the running app
+//
+// A ConfigHandle holds the current settings. The connection manager and the
+// key rotation task read from it when they need a setting instead of keeping
+// their own copy, so `update`, or the file watcher, changes them without a
+// restart; `update_and_save` keeps the config file in step as well. Not
+// every setting can take effect on the spot:
+//
+// - at once: relay_servers, key_rotation_interval, connection_timeout and
+//   connection_mode apply to the next rotation or connection attempt,
+//   message_history trims the app's history, max_file_size applies to the
+//   next file offered either way, and the profile name is only a label
+// - on reconnecting: max_message_size and tcp_nodelay apply to new
+//   connections, while open ones keep what they started with
+// - on listening again: listen_port, as a running listener stays bound to
+//   the port it started on, and port_mapping, as the router is asked then
+//
+// Every accepted change is broadcast as a ConfigChange listing which fields
+// took effect and which were deferred.
+
+use crate::config::{Config, ConfigError};
+use crate::crypto::{CryptoError, SilenceCrypto};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+
+/// Fields a running app picks up as soon as they change
+const APPLIED_AT_ONCE: &[&str] = &[
+    "profile",
+    "relay_servers",
+    "key_rotation_interval",
+    "connection_timeout",
+    "connection_mode",
+    "message_history",
+    "max_file_size",
+];
+
+/// Change events kept for subscribers that fall behind
+const CHANGE_BACKLOG: usize = 16;
+
+/// How long the watcher lets a burst of file events settle before reloading
+const WATCH_SETTLE: Duration = Duration::from_millis(100);
+
+/// What a config update changed, split by when it takes effect
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConfigChange {
+    /// Fields already in effect
+    pub applied: Vec<&'static str>,
+    /// Fields that wait for a new connection or listener
+    pub deferred: Vec<&'static str>,
+}
+
+impl ConfigChange {
+    fn between(old: &Config, new: &Config) -> Self {
+        let (applied, deferred) = old
+            .changed_fields(new)
+            .into_iter()
+            .partition(|field| APPLIED_AT_ONCE.contains(field));
+        Self { applied, deferred }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.deferred.is_empty()
+    }
+}
+
+/// Shared, updatable settings; every clone sees the same config
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<watch::Sender<Config>>,
+    changes: broadcast::Sender<ConfigChange>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: Arc::new(watch::Sender::new(config)),
+            changes: broadcast::channel(CHANGE_BACKLOG).0,
+        }
+    }
+
+    /// Snapshot of the settings right now
+    pub fn current(&self) -> Config {
+        self.current.borrow().clone()
+    }
+
+    /// Receiver woken by every new config
+    pub fn subscribe(&self) -> watch::Receiver<Config> {
+        self.current.subscribe()
+    }
+
+    /// Receiver for the change events
+    pub fn changes(&self) -> broadcast::Receiver<ConfigChange> {
+        self.changes.subscribe()
+    }
+
+    /// Edit the settings; the edit only lands if the result passes
+    /// `Config::validate`
+    pub fn update(&self, edit: impl FnOnce(&mut Config)) -> Result<ConfigChange, Vec<ConfigError>> {
+        self.apply(|current| {
+            let mut config = current.clone();
+            edit(&mut config);
+            config
+        })
+    }
+
+    /// `update`, also writing the edit to the config file at `path`. It's the
+    /// file's own settings that are edited and written, so SILENCE_*
+    /// overrides in effect stay out of it. Either both the running settings
+    /// and the file pass `Config::validate` and the file is written, or
+    /// neither changes.
+    pub fn update_and_save(&self, path: &Path, edit: impl Fn(&mut Config)) -> Result<ConfigChange, Vec<ConfigError>> {
+        let mut saved = Config::load_or_default(path).map_err(|e| vec![e])?;
+        edit(&mut saved);
+        saved.validate()?;
+        self.apply_then(
+            |current| {
+                let mut config = current.clone();
+                edit(&mut config);
+                config
+            },
+            || saved.save(path).map_err(|e| vec![e]),
+        )
+    }
+
+    /// Swap in a whole new config, if it passes `Config::validate`
+    pub fn replace(&self, config: Config) -> Result<ConfigChange, Vec<ConfigError>> {
+        self.apply(|_| config)
+    }
+
+    /// Load a config file the way startup does, SILENCE_* overrides
+    /// included, and swap it in
+    pub fn reload_from(&self, path: &Path) -> Result<ConfigChange, Vec<ConfigError>> {
+        let config = Config::load_or_default(path)
+            .and_then(Config::apply_env_overrides)
+            .map_err(|e| vec![e])?;
+        self.replace(config)
+    }
+
+    /// Reload `path` whenever it changes, until the returned watcher is
+    /// dropped. A file that fails to load or validate is logged and leaves
+    /// the settings as they were. Must be called within a tokio runtime.
+    pub fn watch_file(&self, path: impl Into<PathBuf>) -> Result<ConfigWatcher, ConfigError> {
+        use notify::Watcher;
+
+        let path = path.into();
+        let watch_error = |e: notify::Error| ConfigError::Watch { path: path.clone(), message: e.to_string() };
+
+        // Saves replace the file by renaming over it, which a watch on the
+        // file itself would lose track of, so watch its directory
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = path.file_name().map(ToOwned::to_owned);
+        let (events, mut pending) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let touches_file = event.is_ok_and(|event| {
+                event.paths.iter().any(|changed| changed.file_name() == file_name.as_deref())
+            });
+            if touches_file {
+                let _ = events.send(());
+            }
+        })
+        .map_err(watch_error)?;
+        watcher.watch(&dir, notify::RecursiveMode::NonRecursive).map_err(watch_error)?;
+
+        let handle = self.clone();
+        let task = tokio::spawn(async move {
+            while pending.recv().await.is_some() {
+                tokio::time::sleep(WATCH_SETTLE).await;
+                while pending.try_recv().is_ok() {}
+
+                if let Err(errors) = handle.reload_from(&path) {
+                    for error in errors {
+                        tracing::warn!(path = %path.display(), %error, "Config not reloaded");
+                    }
+                }
+            }
+        });
+        Ok(ConfigWatcher { _watcher: watcher, task })
+    }
+
+    fn apply(&self, next: impl FnOnce(&Config) -> Config) -> Result<ConfigChange, Vec<ConfigError>> {
+        self.apply_then(next, || Ok(()))
+    }
+
+    /// `apply`, with `commit` run once the new config passed validation and
+    /// before it's swapped in; the swap only happens if `commit` succeeds
+    fn apply_then(
+        &self,
+        next: impl FnOnce(&Config) -> Config,
+        commit: impl FnOnce() -> Result<(), Vec<ConfigError>>,
+    ) -> Result<ConfigChange, Vec<ConfigError>> {
+        let mut result = Ok(ConfigChange::default());
+        self.current.send_if_modified(|current| {
+            let config = next(current);
+            if let Err(errors) = config.validate().and_then(|()| commit()) {
+                result = Err(errors);
+                return false;
+            }
+            let change = ConfigChange::between(current, &config);
+            *current = config;
+            let modified = !change.is_empty();
+            result = Ok(change);
+            modified
+        });
+
+        let change = result?;
+        if !change.is_empty() {
+            tracing::info!(applied = ?change.applied, deferred = ?change.deferred, "Config updated");
+            // Nobody listening is fine
+            let _ = self.changes.send(change.clone());
+        }
+        Ok(change)
+    }
+}
+
+/// Keeps a config file watched; stops watching when dropped
+pub struct ConfigWatcher {
+    _watcher: notify::RecommendedWatcher,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What the key rotation task did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// The keys were replaced, for the `epoch`th time; the next rotation is
+    /// due `next_due` from now
+    Rotated { epoch: u64, next_due: Duration },
+    /// The old keys stay in use; the task tries again after
+    /// `MIN_ROTATION_INTERVAL`
+    Failed { error: String },
+}
+
+impl Rotation {
+    fn after(result: Result<(), CryptoError>, crypto: &SilenceCrypto) -> Self {
+        match result {
+            Ok(()) => Rotation::Rotated { epoch: crypto.key_epoch(), next_due: crypto.time_until_rotation() },
+            Err(e) => Rotation::Failed { error: e.to_string() },
+        }
+    }
+}
+
+/// Rotate keys on the configured interval, following changes to it. The
+/// task sleeps until the engine says rotation is due, so a new interval
+/// counts from when the current keys were made, as do manual rotations.
+pub fn spawn_key_rotation(crypto: Arc<Mutex<SilenceCrypto>>, config: &ConfigHandle) -> JoinHandle<()> {
+    spawn_key_rotation_with(crypto, config, |_| {})
+}
+
+/// `spawn_key_rotation`, passing `report` every rotation and every failed
+/// one. Rotations the task didn't make itself, like one set off by
+/// encrypting, are reported when it next wakes.
+pub fn spawn_key_rotation_with(
+    crypto: Arc<Mutex<SilenceCrypto>>,
+    config: &ConfigHandle,
+    report: impl Fn(Rotation) + Send + 'static,
+) -> JoinHandle<()> {
+    let mut settings = config.subscribe();
+    tokio::spawn(async move {
+        let interval = settings.borrow_and_update().key_rotation_interval;
+        let mut reported_epoch = {
+            let mut crypto = crypto.lock().await;
+            crypto.set_rotation_interval(interval);
+            crypto.key_epoch()
+        };
+        // Once every handle is gone the interval can't change any more
+        let mut watching = true;
+
+        loop {
+            let due = crypto.lock().await.time_until_rotation();
+            tokio::select! {
+                _ = tokio::time::sleep(due) => {
+                    let mut crypto = crypto.lock().await;
+                    // Encrypting may have rotated the keys in the meantime
+                    let rotation = if crypto.time_until_rotation().is_zero() {
+                        let result = crypto.rotate_keys();
+                        Rotation::after(result, &crypto)
+                    } else if crypto.key_epoch() != reported_epoch {
+                        Rotation::after(Ok(()), &crypto)
+                    } else {
+                        continue;
+                    };
+                    reported_epoch = crypto.key_epoch();
+                    drop(crypto);
+                    match &rotation {
+                        Rotation::Rotated { epoch, next_due } => tracing::debug!(epoch, ?next_due, "Keys rotated"),
+                        Rotation::Failed { error } => tracing::warn!(%error, "Automatic key rotation failed"),
+                    }
+                    let failed = matches!(rotation, Rotation::Failed { .. });
+                    report(rotation);
+                    if failed {
+                        tokio::time::sleep(crate::MIN_ROTATION_INTERVAL).await;
+                    }
+                }
+                changed = settings.changed(), if watching => {
+                    if changed.is_err() {
+                        watching = false;
+                        continue;
+                    }
+                    let interval = settings.borrow_and_update().key_rotation_interval;
+                    crypto.lock().await.set_rotation_interval(interval);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RelayEntry;
+
+    fn config_with_interval(secs: u64) -> Config {
+        Config::builder().rotation_interval(Duration::from_secs(secs)).build().unwrap()
+    }
+
+    #[test]
+    fn test_update_reports_applied_and_deferred_fields() {
+        let handle = ConfigHandle::new(Config::default());
+        let mut changes = handle.changes();
+
+        let change = handle
+            .update(|config| {
+                config.relay_servers = vec![RelayEntry::new("relay.example:8080")];
+                config.listen_port = 9000;
+                config.tcp_nodelay = true;
+            })
+            .unwrap();
+        assert_eq!(change.applied, vec!["relay_servers"]);
+        assert_eq!(change.deferred, vec!["listen_port", "tcp_nodelay"]);
+        assert_eq!(changes.try_recv().unwrap(), change);
+        assert_eq!(handle.current().listen_port, 9000);
+
+        // Setting what's already there changes nothing and says nothing
+        assert!(handle.update(|config| config.listen_port = 9000).unwrap().is_empty());
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_invalid_update_leaves_config_alone() {
+        let handle = ConfigHandle::new(Config::default());
+        let mut changes = handle.changes();
+        let subscriber = handle.subscribe();
+
+        let errors = handle
+            .update(|config| {
+                config.listen_port = 9000;
+                config.connection_timeout = Duration::ZERO;
+            })
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(handle.current(), Config::default());
+        assert!(!subscriber.has_changed().unwrap());
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_update_and_save_writes_the_file() {
+        let dir = std::env::temp_dir().join(format!("silence-save-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.toml");
+        Config::builder().listen_port(9000).build().unwrap().save(&path).unwrap();
+        // Running with an override the file doesn't have
+        let handle = ConfigHandle::new(Config::builder().listen_port(9100).build().unwrap());
+
+        let change = handle
+            .update_and_save(&path, |config| {
+                config.relay_servers = vec![RelayEntry::new("relay.example:8080")];
+                config.max_message_size = 8192;
+            })
+            .unwrap();
+        assert_eq!(change.applied, vec!["relay_servers"]);
+        assert_eq!(change.deferred, vec!["max_message_size"]);
+        assert_eq!(handle.current().listen_port, 9100);
+        assert_eq!(handle.current().max_message_size, 8192);
+
+        let saved = Config::from_file(&path).unwrap();
+        assert_eq!(saved.listen_port, 9000);
+        assert_eq!(saved.max_message_size, 8192);
+        assert_eq!(saved.relay_servers, vec![RelayEntry::new("relay.example:8080")]);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("relay_servers = [\"relay.example:8080\"]"), "{}", text);
+        assert!(text.contains("max_message_size = 8192"), "{}", text);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refused_update_and_save_changes_nothing() {
+        let dir = std::env::temp_dir().join(format!("silence-save-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.toml");
+        Config::default().save(&path).unwrap();
+        let before = std::fs::read_to_string(&path).unwrap();
+        let handle = ConfigHandle::new(Config::default());
+        let mut changes = handle.changes();
+
+        // One good field and one bad: neither lands
+        let errors = handle
+            .update_and_save(&path, |config| {
+                config.listen_port = 9000;
+                config.connection_timeout = Duration::ZERO;
+            })
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(handle.current(), Config::default());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+        assert!(changes.try_recv().is_err());
+
+        // Valid for the file but not for what's running, as relay_only needs
+        // the relays an override took away
+        let handle = ConfigHandle::new(Config::builder().relay_servers(Vec::<RelayEntry>::new()).build().unwrap());
+        let errors = handle
+            .update_and_save(&path, |config| config.connection_mode = crate::ConnectionMode::RelayOnly)
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+
+        // A file that can't be read isn't overwritten
+        std::fs::write(&path, "listen_port = \"soon\"\n").unwrap();
+        assert!(handle.update_and_save(&path, |config| config.listen_port = 9000).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "listen_port = \"soon\"\n");
+        assert_eq!(handle.current().listen_port, Config::default().listen_port);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotation_task_follows_interval_changes() {
+        let handle = ConfigHandle::new(config_with_interval(3600));
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&handle.current()).unwrap()));
+        let task = spawn_key_rotation(Arc::clone(&crypto), &handle);
+        let sealed = crypto.lock().await.encrypt(b"before the change").unwrap();
+        // Let the task start on the old interval first
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(crypto.lock().await.seconds_until_rotation() > 3500);
+
+        let change = handle.update(|config| config.key_rotation_interval = Duration::from_secs(1)).unwrap();
+        assert_eq!(change.applied, vec!["key_rotation_interval"]);
+
+        // Rotation replaces the keys, so the old message stops decrypting
+        let rotated = async {
+            while crypto.lock().await.decrypt(&sealed).is_ok() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), rotated).await.expect("keys never rotated");
+        assert!(crypto.lock().await.seconds_until_rotation() <= 1);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rotation_task_reports_rotations() {
+        let handle = ConfigHandle::new(config_with_interval(1));
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&handle.current()).unwrap()));
+        let (sender, mut reports) = mpsc::unbounded_channel();
+        let task = spawn_key_rotation_with(Arc::clone(&crypto), &handle, move |rotation| {
+            let _ = sender.send(rotation);
+        });
+
+        for expected in 1..=2 {
+            let report = tokio::time::timeout(Duration::from_secs(5), reports.recv()).await.unwrap().unwrap();
+            let Rotation::Rotated { epoch, next_due } = report else {
+                panic!("{:?}", report);
+            };
+            assert_eq!(epoch, expected);
+            assert!(next_due <= Duration::from_secs(1));
+        }
+        assert_eq!(crypto.lock().await.key_epoch(), 2);
+        task.abort();
+    }
+
+    #[test]
+    fn test_failed_rotation_is_reported() {
+        let crypto = SilenceCrypto::new(60).unwrap();
+        assert_eq!(
+            Rotation::after(Err(CryptoError::KeyDerivation), &crypto),
+            Rotation::Failed { error: "Key derivation failed".to_string() }
+        );
+        assert!(matches!(Rotation::after(Ok(()), &crypto), Rotation::Rotated { epoch: 0, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_rotation_task_holds_off_when_interval_grows() {
+        let handle = ConfigHandle::new(config_with_interval(1));
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&handle.current()).unwrap()));
+        let task = spawn_key_rotation(Arc::clone(&crypto), &handle);
+        let sealed = crypto.lock().await.encrypt(b"before the change").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        handle.update(|config| config.key_rotation_interval = Duration::from_secs(3600)).unwrap();
+        // Past the old deadline, the keys are still the same
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(crypto.lock().await.decrypt(&sealed).is_ok());
+        assert!(crypto.lock().await.seconds_until_rotation() > 3500);
+        task.abort();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)] // the env lock has to outlast the reloads
+    async fn test_watcher_reloads_saved_file() {
+        // Reloads apply SILENCE_* overrides, which other tests set
+        let _env = crate::config::tests::ScopedEnv::set(&[]);
+        let dir = std::env::temp_dir().join(format!("silence-watch-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.toml");
+        Config::default().save(&path).unwrap();
+
+        let handle = ConfigHandle::new(Config::default());
+        let mut changes = handle.changes();
+        let _watcher = handle.watch_file(&path).unwrap();
+
+        // A broken file is skipped, and the next good save still lands
+        std::fs::write(&path, "listen_port = \"soon\"\n").unwrap();
+        tokio::time::sleep(WATCH_SETTLE * 3).await;
+        assert_eq!(handle.current(), Config::default());
+
+        config_with_interval(60).save(&path).unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(5), changes.recv()).await.unwrap().unwrap();
+        assert_eq!(change.applied, vec!["key_rotation_interval"]);
+        assert_eq!(handle.current().key_rotation_interval, Duration::from_secs(60));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
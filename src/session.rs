@@ -0,0 +1,2479 @@
+// The desktop app's connections, one per peer
+//
+// Every connection gets an id when it's added, which the GUI uses to pick
+// the conversation; the most recently added one stands in wherever no id is
+// given. Each connection is split: its receive loop owns the reading half,
+// and the sending half stays here for the Tauri commands, so sending never
+// waits on a loop waiting for the peer. Hanging up reaches the loop through
+// a signal it waits on alongside the peer.
+//
+// The app can dial out and accept connections at the same time, so two
+// instances dialing each other at once end up connected twice. Each side
+// says which instance it is with a hello, and once a second connection to
+// the same instance shows up, both sides drop the same one of the two:
+// keeping a direct one over a relayed one, otherwise the one dialed by the
+// instance with the smaller id, or the first if both went the same way. Two
+// relayed connections both stay, as both ends dialed the relay and neither
+// can tell which the other would keep.
+//
+// A peer counts as verified once the user has compared the connection's
+// fingerprint with them, which both ends work out alike from the session
+// fingerprints the hellos carry. That carries over to the next connection to
+// the same instance with the same fingerprint; an instance coming back with
+// another one is reported, and isn't verified any more.
+//
+// The roster has the peers connected and the last few that weren't any
+// more, most recent first, until their instance connects again; that takes
+// over the name the user gave it. Every peer is pinged on its hello and
+// every `PING_INTERVAL`, for the round trip.
+//
+// The last peer we dialed is kept in a file next to the config, so it can be
+// dialed again after a restart.
+//
+// Rotating keys by hand asks every peer to rotate along, each of which
+// confirms under the new keys.
+//
+// Messages sent to a direct peer wait for it to confirm they arrived, until
+// `DELIVERY_TIMEOUT` or the connection ends, whichever comes first; relayed
+// ones can't be confirmed, as relayed text goes without the id to confirm.
+//
+// Messages received while the GUI shows some other conversation, or none,
+// count as unread until it marks them read; the counts outlive the
+// connection, as the messages stay in the history. Peers can't be told what
+// was read yet, so marking read only calls the hook set with `on_read`,
+// where read receipts go once the connection carries them.
+//
+// A file goes to a peer in three steps: an offer naming it and its size,
+// the peer's answer, and, if it takes the file, the bytes in order, in
+// chunks that fit a frame. A file coming in is written to a hidden partial
+// copy in the folder it was taken into, and renamed once the last byte is
+// there; turned down, given up on or cut off by the connection ending, the
+// partial copy is deleted. Offers over `max_file_size` are turned down
+// without asking.
+
+use crate::config::{write_atomically, ConfigError};
+use crate::crypto::{CryptoError, Fingerprint, SilenceCrypto};
+use crate::events::KeyRotated;
+use crate::invite::{unix_now, Invite, PAIRING_SECRET_LEN};
+use crate::network::{
+    ConnectionReader, ConnectionStats, ConnectionWriter, FileChunk, FileMessage, FileOffer, NetworkError, P2PConnection,
+    PeerMessage,
+};
+use crate::ConnectionMode;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex};
+
+/// Shortest time between two `Peers::rotate_keys`, so repeated requests
+/// can't run the peers' key chains apart
+pub const ROTATE_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// How long the app waits for each peer to confirm a rotation
+pub const ROTATION_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a sent message waits for the peer to confirm it arrived before
+/// it counts as failed
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes of a file between two progress reports on its transfer
+pub const FILE_PROGRESS_STEP: u64 = 256 * 1024;
+
+/// How often the app pings every peer, for the round trip in `PeerInfo`
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Disconnected peers `Peers::recent` remembers
+pub const RECENT_PEERS: usize = 10;
+
+/// The app's connections, by peer id
+#[derive(Clone)]
+pub struct Peers {
+    /// A plain mutex, never held across an await
+    inner: Arc<std::sync::Mutex<Inner>>,
+    /// This app instance's id, for the hellos
+    instance: Arc<str>,
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        let inner = Inner { max_file_size: crate::Config::default().max_file_size, ..Inner::default() };
+        Self { inner: Arc::new(std::sync::Mutex::new(inner)), instance: uuid::Uuid::new_v4().to_string().into() }
+    }
+}
+
+/// Which way a connection was made
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    /// We dialed the peer
+    Outbound,
+    /// The peer dialed us
+    Inbound,
+}
+
+#[derive(Default)]
+struct Inner {
+    peers: HashMap<String, Arc<Peer>>,
+    /// Ids in the order they were added, most recent last
+    order: Vec<String>,
+    /// When `rotate_keys` last rotated
+    rotated_at: Option<Instant>,
+    /// Peer ids of the messages sent but not yet confirmed, by message id;
+    /// outlives the peer, for `take_undelivered`
+    awaiting: HashMap<String, String>,
+    /// The conversation the GUI shows, if it's in front
+    active: Option<String>,
+    /// Ids of the unread messages by peer id, oldest first
+    unread: HashMap<String, Vec<String>>,
+    on_read: Option<ReadHook>,
+    /// Largest file sent or taken, as `set_max_file_size` last set it
+    max_file_size: u64,
+    /// Files peers offered us, by transfer id, until received or given up on
+    incoming: HashMap<String, IncomingFile>,
+    /// Files we offered, by transfer id, until sent or given up on
+    outgoing: HashMap<String, OutgoingFile>,
+    /// Transfer ids of the files coming in when a connection ended, by peer
+    /// id; outlives the peer, for `take_abandoned_files`
+    abandoned: HashMap<String, Vec<String>>,
+    /// Pairing secrets of the invites handed out and not yet used, with
+    /// when each expires in Unix seconds
+    invites: HashMap<[u8; PAIRING_SECRET_LEN], u64>,
+    /// The connection fingerprint last seen for each app instance, and
+    /// whether the user compared it
+    known: HashMap<String, Known>,
+    /// The peers disconnected lately, most recent first, up to
+    /// `RECENT_PEERS`
+    departed: VecDeque<Departed>,
+}
+
+struct Known {
+    fingerprint: Fingerprint,
+    verified: bool,
+}
+
+struct Departed {
+    /// Its app instance, if its hello arrived
+    instance: Option<String>,
+    info: PeerInfo,
+}
+
+impl Inner {
+    fn unread_count(&self, id: &str) -> usize {
+        self.unread.get(id).map_or(0, Vec::len)
+    }
+}
+
+/// Called with a peer id and the ids of its messages just marked read
+pub type ReadHook = Arc<dyn Fn(&str, &[String]) + Send + Sync>;
+
+struct IncomingFile {
+    peer_id: String,
+    offer: FileOffer,
+    /// Where it goes, once taken
+    accepted: Option<Accepted>,
+}
+
+struct Accepted {
+    dest_dir: PathBuf,
+    /// The partial copy, renamed into `dest_dir` once complete
+    part: PathBuf,
+    /// Bytes written so far
+    received: u64,
+}
+
+struct OutgoingFile {
+    peer_id: String,
+    /// Told the peer's answer to the offer, then gone
+    answer: Option<oneshot::Sender<bool>>,
+}
+
+struct Peer {
+    addr: SocketAddr,
+    relay: bool,
+    origin: Origin,
+    /// The peer's app instance, once its hello arrives
+    instance: std::sync::Mutex<Option<String>>,
+    /// Milliseconds since the Unix epoch
+    connected_at: u64,
+    writer: ConnectionWriter,
+    /// Ends the receive loop
+    hang_up: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+    /// The connection's fingerprint, once the peer's hello arrives
+    fingerprint: std::sync::Mutex<Option<Fingerprint>>,
+    /// The fingerprint the user compared with this peer, if they did
+    verified: std::sync::Mutex<Option<Fingerprint>>,
+    /// What the user calls the peer
+    label: std::sync::Mutex<Option<String>>,
+}
+
+impl Peer {
+    fn info(&self, id: &str, unread: usize) -> PeerInfo {
+        PeerInfo {
+            peer_id: id.to_string(),
+            label: self.label.lock().unwrap().clone(),
+            peer: self.addr,
+            fingerprint: self.fingerprint.lock().unwrap().map(|fingerprint| fingerprint.to_string()),
+            verified: self.verified.lock().unwrap().is_some(),
+            state: PeerState::Connected,
+            relay: self.relay,
+            origin: self.origin,
+            connected_at: self.connected_at,
+            disconnected_at: None,
+            stats: self.writer.stats(),
+            unread,
+        }
+    }
+
+    fn connection_info(&self, id: &str, dialed: SocketAddr, fingerprint: Fingerprint) -> ConnectionInfo {
+        let (mode_used, relay) = match self.relay {
+            true => (ConnectionMode::RelayOnly, Some(self.addr)),
+            false => (ConnectionMode::DirectOnly, None),
+        };
+        ConnectionInfo {
+            peer_id: id.to_string(),
+            peer_addr: dialed,
+            mode_used,
+            relay,
+            established_at: self.connected_at,
+            fingerprint: fingerprint.to_string(),
+            max_plaintext: self.writer.max_plaintext(),
+        }
+    }
+
+    fn budget(&self, id: &str) -> MessageBudget {
+        let frame_bytes = self.writer.max_message_size();
+        let max_bytes = self.writer.max_plaintext();
+        MessageBudget {
+            peer_id: id.to_string(),
+            max_bytes,
+            frame_bytes,
+            overhead: frame_bytes - max_bytes,
+            relay: self.relay,
+        }
+    }
+}
+
+/// What the GUI is told about a peer, as of when it was asked
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    /// What the user calls the peer, if they named it
+    pub label: Option<String>,
+    pub peer: SocketAddr,
+    /// The connection's fingerprint, in hex, once the peer's hello arrives
+    pub fingerprint: Option<String>,
+    pub verified: bool,
+    pub state: PeerState,
+    /// Whether the connection goes, or went, through a relay
+    pub relay: bool,
+    pub origin: Origin,
+    /// Milliseconds since the Unix epoch
+    pub connected_at: u64,
+    /// Milliseconds since the Unix epoch
+    pub disconnected_at: Option<u64>,
+    /// As of the disconnect, for a peer disconnected
+    pub stats: ConnectionStats,
+    /// Messages from the peer not marked read
+    pub unread: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    Connected,
+    Disconnected,
+}
+
+/// How a connection we dialed was made, as `connect_to_peer` answers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    pub peer_id: String,
+    /// The address dialed, which for a relayed connection isn't the one
+    /// the connection goes to
+    pub peer_addr: SocketAddr,
+    /// `direct_only` or `relay_only`, whichever path was taken; for `auto`
+    /// that tells whether it fell back on a relay
+    pub mode_used: ConnectionMode,
+    /// The relay the connection goes through
+    pub relay: Option<SocketAddr>,
+    /// Milliseconds since the Unix epoch
+    pub established_at: u64,
+    /// The session fingerprint, in hex
+    pub fingerprint: String,
+    /// Longest message text, in bytes, the connection carries
+    pub max_plaintext: usize,
+}
+
+/// How much text a message to a peer can hold, as `get_message_budget`
+/// answers. The limit is on the message's UTF-8 bytes, not its characters,
+/// so a counter should measure what's typed the same way.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MessageBudget {
+    pub peer_id: String,
+    /// Longest message text, in bytes, that `Peers::send` takes
+    pub max_bytes: usize,
+    /// Largest frame the connection sends, `max_message_size` or the lower
+    /// limit a relay agreed to
+    pub frame_bytes: usize,
+    /// What framing and encryption add to the text: `MESSAGE_OVERHEAD`
+    /// direct, `RELAY_MESSAGE_OVERHEAD` relayed
+    pub overhead: usize,
+    pub relay: bool,
+}
+
+/// How a rotation asked for with `Peers::rotate_keys` went
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RotationReport {
+    #[serde(flatten)]
+    pub rotated: KeyRotated,
+    /// Peers that rotated along
+    pub confirmed: Vec<String>,
+    /// Peers that didn't confirm, whose messages may no longer decrypt
+    pub unconfirmed: Vec<Unconfirmed>,
+    /// No peer was connected, so only our keys rotated
+    pub local_only: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Unconfirmed {
+    pub peer_id: String,
+    pub error: String,
+}
+
+/// Why `Peers::send` didn't send
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SendError {
+    /// No connection with that id, or none at all
+    NoConnection,
+    /// More text than one frame on the connection carries
+    TooLarge { bytes: usize, limit: usize },
+    /// Writing to the connection failed, so the message is lost
+    Failed { error: String },
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::NoConnection => write!(f, "No active connection"),
+            SendError::TooLarge { bytes, limit } => {
+                write!(f, "Message too large: {} bytes, the connection carries {}", bytes, limit)
+            }
+            SendError::Failed { error } => write!(f, "Send failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Why a file didn't go, or didn't come in
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileError {
+    /// No connection with that id
+    NoConnection,
+    /// No file offered under that transfer id, or none still waiting for an
+    /// answer
+    UnknownTransfer,
+    /// Over `max_file_size`
+    TooLarge { bytes: u64, limit: u64 },
+    /// Reading the file, or writing it where asked, failed
+    Io { error: String },
+    /// The peer turned the file down, or gave up on it partway
+    Rejected,
+    /// Sending failed or the connection ended, so the file didn't get there
+    Failed { error: String },
+}
+
+impl FileError {
+    fn connection_ended() -> Self {
+        FileError::Failed { error: "Connection ended".to_string() }
+    }
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileError::NoConnection => write!(f, "No active connection"),
+            FileError::UnknownTransfer => write!(f, "No such file offered"),
+            FileError::TooLarge { bytes, limit } => {
+                write!(f, "File too large: {} bytes, the limit is {}", bytes, limit)
+            }
+            FileError::Io { error } => write!(f, "File error: {}", error),
+            FileError::Rejected => write!(f, "The peer turned the file down"),
+            FileError::Failed { error } => write!(f, "File transfer failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+/// A file offered with `Peers::offer_file`, for `Peers::send_file` to send
+/// once the peer takes it
+#[derive(Debug)]
+pub struct OfferedFile {
+    pub transfer_id: String,
+    pub peer_id: String,
+    pub path: PathBuf,
+    /// Bytes
+    pub size: u64,
+    answer: oneshot::Receiver<bool>,
+}
+
+/// Why `Peers::rotate_keys` failed
+#[derive(Debug)]
+pub enum RotateError {
+    /// The last rotation was less than `ROTATE_COOLDOWN` ago
+    TooSoon { retry_in: Duration },
+    Crypto(CryptoError),
+    /// Asking peer `peer_id` to rotate along broke off without an answer
+    /// either way; our keys rotated regardless, as `rotated` says
+    Request { peer_id: String, error: String, rotated: KeyRotated },
+}
+
+impl std::fmt::Display for RotateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RotateError::TooSoon { retry_in } => {
+                write!(f, "Keys were just rotated, try again in {}s", retry_in.as_secs().max(1))
+            }
+            RotateError::Crypto(e) => write!(f, "Key rotation failed: {}", e),
+            RotateError::Request { peer_id, error, .. } => {
+                write!(f, "Keys rotated, but asking peer {} to rotate along failed: {}", peer_id, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RotateError {}
+
+/// Name of the last peer's file, in the config directory
+pub const LAST_PEER_FILE: &str = "last_peer.toml";
+
+/// How the last connection we made was set up, to make it again
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastPeer {
+    pub address: SocketAddr,
+    /// As written in the config file; kept as text, so a file from a
+    /// version with other modes still loads
+    pub mode: String,
+    /// Whether the connection went through a relay
+    pub relay: bool,
+}
+
+impl LastPeer {
+    pub fn new(address: SocketAddr, mode: ConnectionMode, relay: bool) -> Self {
+        Self { address, mode: mode.to_string(), relay }
+    }
+
+    /// The last peer saved at `path`; `None` when there is none, or the
+    /// file can't be read, which is logged
+    pub fn load(path: &Path) -> Option<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Couldn't read the last peer");
+                return None;
+            }
+        };
+        toml::from_str(&text)
+            .map_err(|e| tracing::warn!(path = %path.display(), error = %e, "Ignoring the saved last peer"))
+            .ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        // Nothing in here can fail to serialize
+        write_atomically(path, &toml::to_string(self).unwrap())
+    }
+
+    /// The saved mode, if this version still has it
+    pub fn mode(&self) -> Result<ConnectionMode, String> {
+        self.mode.parse().map_err(|_| format!("The last peer's connection mode {:?} is no longer supported", self.mode))
+    }
+}
+
+/// A peer's reading half, handed to its receive loop, with the signal that
+/// ends the loop on hang up
+pub struct Inbox {
+    reader: ConnectionReader,
+    hang_up: oneshot::Receiver<()>,
+    /// Files coming in, open for writing, with their partial copies, by
+    /// transfer id
+    files: HashMap<String, (PathBuf, tokio::fs::File)>,
+}
+
+impl Inbox {
+    /// Close the file coming in as `transfer_id`, which was given up on,
+    /// deleting its partial copy
+    fn drop_file(&mut self, transfer_id: &str) {
+        if let Some((part, file)) = self.files.remove(transfer_id) {
+            drop(file);
+            remove_partial(&part);
+        }
+    }
+
+    /// Close every file coming in, deleting their partial copies, as the
+    /// connection is gone
+    fn drop_files(&mut self) {
+        for (_, (part, file)) in self.files.drain() {
+            drop(file);
+            remove_partial(&part);
+        }
+    }
+}
+
+/// What one wait in a receive loop came back with
+#[derive(Debug)]
+pub enum Received {
+    Message { from: SocketAddr, body: String },
+    /// The peer closed the connection, which is gone now
+    Closed { peer: SocketAddr },
+    /// Receiving failed and the connection was dropped
+    Failed { peer: SocketAddr, error: NetworkError },
+    /// The peer confirmed the message sent as `message_id` arrived
+    Delivered { message_id: String },
+    /// Peer `dropped` turned out to be a second connection to the instance
+    /// behind `kept`, and was hung up on; when it's the one being received
+    /// from, the loop should end
+    Duplicate { dropped: String, peer: SocketAddr, kept: String },
+    /// We hung up, or there is no such connection; the loop should end
+    HungUp,
+    /// The peer offers a file, for `accept_file` or `reject_file` to answer
+    FileOffered { offer: FileOffer },
+    /// Another `FILE_PROGRESS_STEP` of a file coming in was written
+    FileProgress { transfer_id: String, received: u64, size: u64 },
+    /// A file came in whole and was saved at `path`
+    FileReceived { transfer_id: String, path: PathBuf },
+    /// A file coming in, or offered, was turned down or given up on;
+    /// nothing of it is kept
+    FileFailed { transfer_id: String, error: String },
+    /// The peer's app instance, seen before with connection fingerprint
+    /// `previous` and `verified` or not, came back with `fingerprint`; it
+    /// isn't verified any more
+    FingerprintChanged { peer: SocketAddr, previous: Fingerprint, fingerprint: Fingerprint, verified: bool },
+    /// The peer answered a ping after `rtt`, which its `PeerInfo` has now
+    RoundTrip { rtt: Duration },
+    /// The peer came with an invite that's used up, expired or was never
+    /// handed out here, and was hung up on
+    Refused { peer: SocketAddr },
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// This app instance's id, as sent in hellos
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// Add `connection`, made the way `origin` says, as the most recent
+    /// peer, returning its id and the inbox for its receive loop
+    pub fn add(&self, connection: P2PConnection, origin: Origin) -> (String, Inbox) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        let addr = connection.peer_addr();
+        let relay = connection.relay_hello().is_some();
+        let (reader, writer) = connection.into_split();
+        let peer = Peer {
+            addr,
+            relay,
+            origin,
+            instance: std::sync::Mutex::new(None),
+            connected_at: crate::events::now_millis(),
+            writer,
+            hang_up: std::sync::Mutex::new(Some(sender)),
+            fingerprint: std::sync::Mutex::new(None),
+            verified: std::sync::Mutex::new(None),
+            label: std::sync::Mutex::new(None),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.insert(id.clone(), Arc::new(peer));
+        inner.order.push(id.clone());
+        (id, Inbox { reader, hang_up: receiver, files: HashMap::new() })
+    }
+
+    /// The most recently added peer still connected
+    pub fn latest(&self) -> Option<String> {
+        self.inner.lock().unwrap().order.last().cloned()
+    }
+
+    /// The connected peers, oldest first
+    pub fn list(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.order.iter().map(|id| inner.peers[id].info(id, inner.unread_count(id))).collect()
+    }
+
+    /// The peers disconnected lately, most recent first, as they were when
+    /// they disconnected but for their unread counts
+    pub fn recent(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .departed
+            .iter()
+            .map(|departed| PeerInfo { unread: inner.unread_count(&departed.info.peer_id), ..departed.info.clone() })
+            .collect()
+    }
+
+    /// Call peer `id` `label`, or with `None` nothing; false for an unknown
+    /// id
+    pub fn set_label(&self, id: &str, label: Option<String>) -> bool {
+        match self.get(id) {
+            Some(peer) => {
+                *peer.label.lock().unwrap() = label;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn info(&self, id: &str) -> Option<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.get(id).map(|peer| peer.info(id, inner.unread_count(id)))
+    }
+
+    /// Which conversation the GUI shows: messages from that peer arrive
+    /// read, everyone else's unread. `None` while the window isn't in front.
+    pub fn set_active(&self, id: Option<String>) {
+        self.inner.lock().unwrap().active = id;
+    }
+
+    /// Count message `message_id` from peer `id` as unread, unless its
+    /// conversation is the one shown; true if it counted
+    pub fn received(&self, id: &str, message_id: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.active.as_deref() == Some(id) {
+            return false;
+        }
+        inner.unread.entry(id.to_string()).or_default().push(message_id.to_string());
+        true
+    }
+
+    /// Unread message counts by peer id, for peers with any, connected or
+    /// not
+    pub fn unread_counts(&self) -> HashMap<String, usize> {
+        let inner = self.inner.lock().unwrap();
+        inner.unread.iter().map(|(id, unread)| (id.clone(), unread.len())).collect()
+    }
+
+    /// Mark peer `id`'s messages read up to and including `up_to`,
+    /// returning the ids that were unread, which the `on_read` hook gets
+    /// too. Nothing is read for an id that isn't unread, as when it was
+    /// already marked.
+    pub fn mark_read(&self, id: &str, up_to: &str) -> Vec<String> {
+        let (read, hook) = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(unread) = inner.unread.get_mut(id) else { return Vec::new() };
+            let Some(position) = unread.iter().position(|message_id| message_id == up_to) else { return Vec::new() };
+            let read: Vec<String> = unread.drain(..=position).collect();
+            if unread.is_empty() {
+                inner.unread.remove(id);
+            }
+            (read, inner.on_read.clone())
+        };
+        if let Some(hook) = hook {
+            hook(id, &read);
+        }
+        read
+    }
+
+    /// Have `hook` called whenever messages are marked read
+    pub fn on_read(&self, hook: impl Fn(&str, &[String]) + Send + Sync + 'static) {
+        self.inner.lock().unwrap().on_read = Some(Arc::new(hook));
+    }
+
+    /// Forget every unread message, as when the history is cleared
+    pub fn clear_unread(&self) {
+        self.inner.lock().unwrap().unread.clear();
+    }
+
+    /// How the connection to peer `id`, dialed at `dialed`, was made
+    pub fn connection_info(&self, id: &str, dialed: SocketAddr, fingerprint: Fingerprint) -> Option<ConnectionInfo> {
+        self.get(id).map(|peer| peer.connection_info(id, dialed, fingerprint))
+    }
+
+    /// How long a message to peer `id` can be, `None` for an unknown id
+    pub fn budget(&self, id: &str) -> Option<MessageBudget> {
+        self.get(id).map(|peer| peer.budget(id))
+    }
+
+    /// The fingerprint of the connection to peer `id`, for the user to
+    /// compare with the peer's; `None` for an unknown id, or before the
+    /// peer's hello arrives
+    pub fn fingerprint(&self, id: &str) -> Option<Fingerprint> {
+        *self.get(id)?.fingerprint.lock().unwrap()
+    }
+
+    /// Mark peer `id` as verified or not against its connection's
+    /// fingerprint, remembered for its app instance's next connection;
+    /// false for an unknown id, or before the peer's hello arrives
+    pub fn set_verified(&self, id: &str, verified: bool) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(peer) = inner.peers.get(id).map(Arc::clone) else {
+            return false;
+        };
+        let Some(fingerprint) = *peer.fingerprint.lock().unwrap() else {
+            return false;
+        };
+        *peer.verified.lock().unwrap() = verified.then_some(fingerprint);
+        if let Some(instance) = peer.instance.lock().unwrap().clone() {
+            inner.known.insert(instance, Known { fingerprint, verified });
+        }
+        true
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.inner.lock().unwrap().peers.is_empty()
+    }
+
+    /// The sending half of peer `id`'s connection, `None` for an unknown id
+    pub fn writer(&self, id: &str) -> Option<ConnectionWriter> {
+        self.get(id).map(|peer| peer.writer.clone())
+    }
+
+    /// Send `text` to peer `id` as message `message_id`. Answers whether
+    /// the peer will confirm it, with `Received::Delivered` on its receive
+    /// loop; if it doesn't, `expire` or `take_undelivered` tell.
+    pub async fn send(&self, id: &str, message_id: &str, text: &str) -> Result<bool, SendError> {
+        let peer = self.get(id).ok_or(SendError::NoConnection)?;
+        let limit = peer.writer.max_plaintext();
+        if text.len() > limit {
+            return Err(SendError::TooLarge { bytes: text.len(), limit });
+        }
+        // Before sending, so a quick confirmation isn't missed
+        let confirmed = !peer.relay;
+        if confirmed {
+            self.inner.lock().unwrap().awaiting.insert(message_id.to_string(), id.to_string());
+        }
+        match peer.writer.send_text_as(message_id, text).await {
+            Ok(()) => Ok(confirmed),
+            Err(e) => {
+                self.inner.lock().unwrap().awaiting.remove(message_id);
+                Err(SendError::Failed { error: e.to_string() })
+            }
+        }
+    }
+
+    /// Give up waiting for message `message_id` to be confirmed; true when
+    /// it was still waiting, so it never arrived as far as we know
+    pub fn expire(&self, message_id: &str) -> bool {
+        self.inner.lock().unwrap().awaiting.remove(message_id).is_some()
+    }
+
+    /// The messages sent to peer `id` that it never confirmed, no longer
+    /// waited for; for once its connection is gone
+    pub fn take_undelivered(&self, id: &str) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let undelivered: Vec<_> =
+            inner.awaiting.iter().filter(|(_, peer_id)| *peer_id == id).map(|(message_id, _)| message_id.clone()).collect();
+        for message_id in &undelivered {
+            inner.awaiting.remove(message_id);
+        }
+        undelivered
+    }
+
+    /// Send peer `id` our hello, which settles which connection stays if
+    /// it turns out to be connected twice, and a first ping
+    pub async fn introduce(&self, id: &str) -> Result<(), NetworkError> {
+        match self.get(id) {
+            Some(peer) => {
+                peer.writer.send_hello(&self.instance).await?;
+                peer.writer.send_ping().await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Ping every peer, its answer timing the round trip; a connection too
+    /// broken to take the ping shows up in its receive loop
+    pub async fn ping_all(&self) {
+        let writers: Vec<_> = {
+            let inner = self.inner.lock().unwrap();
+            inner.order.iter().map(|id| (id.clone(), inner.peers[id].writer.clone())).collect()
+        };
+        for (id, writer) in writers {
+            if let Err(e) = writer.send_ping().await {
+                tracing::debug!(peer_id = %id, error = %e, "Couldn't ping peer");
+            }
+        }
+    }
+
+    /// Take `invite`, about to be handed out, as good for one peer to
+    /// connect with until it expires
+    pub fn expect_invite(&self, invite: &Invite) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = unix_now();
+        inner.invites.retain(|_, expires_at| *expires_at >= now);
+        inner.invites.insert(invite.secret, invite.expires_at);
+    }
+
+    /// Hand peer `id`, dialed through `invite`, the invite's pairing secret
+    pub async fn present_invite(&self, id: &str, invite: &Invite) -> Result<(), NetworkError> {
+        match self.get(id) {
+            Some(peer) => peer.writer.send_pairing(&invite.secret).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Use up pairing secret `secret`: whether it was an invite's handed
+    /// out here, still good and not used before
+    fn redeem(&self, secret: &[u8]) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let now = unix_now();
+        inner.invites.retain(|_, expires_at| *expires_at >= now);
+        <[u8; PAIRING_SECRET_LEN]>::try_from(secret).is_ok_and(|secret| inner.invites.remove(&secret).is_some())
+    }
+
+    /// Set the largest file to send or take, `Config::max_file_size`
+    pub fn set_max_file_size(&self, bytes: u64) {
+        self.inner.lock().unwrap().max_file_size = bytes;
+    }
+
+    /// Offer peer `id` the file at `path`. The peer's answer comes in on its
+    /// receive loop, which `send_file` waits for.
+    pub async fn offer_file(&self, id: &str, path: &Path) -> Result<OfferedFile, FileError> {
+        let peer = self.get(id).ok_or(FileError::NoConnection)?;
+        let io_error = |e: std::io::Error| FileError::Io { error: format!("{}: {}", path.display(), e) };
+        let metadata = tokio::fs::metadata(path).await.map_err(io_error)?;
+        if !metadata.is_file() {
+            return Err(FileError::Io { error: format!("{} isn't a file", path.display()) });
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return Err(FileError::Io { error: format!("{} has no usable name", path.display()) });
+        };
+        let size = metadata.len();
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        let (sender, answer) = oneshot::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if size > inner.max_file_size {
+                return Err(FileError::TooLarge { bytes: size, limit: inner.max_file_size });
+            }
+            // Before offering, so a quick answer isn't missed
+            inner.outgoing.insert(transfer_id.clone(), OutgoingFile { peer_id: id.to_string(), answer: Some(sender) });
+        }
+        let offer = FileOffer { transfer_id: transfer_id.clone(), name: name.to_string(), size };
+        if let Err(e) = peer.writer.send_file_message(&FileMessage::Offer(offer)).await {
+            self.inner.lock().unwrap().outgoing.remove(&transfer_id);
+            return Err(FileError::Failed { error: e.to_string() });
+        }
+        Ok(OfferedFile { transfer_id, peer_id: id.to_string(), path: path.to_path_buf(), size, answer })
+    }
+
+    /// Wait for the peer to answer `offered` and, if it takes the file,
+    /// send it, calling `progress` with the bytes sent every
+    /// `FILE_PROGRESS_STEP`. Done once the last chunk is written; given up
+    /// on, the peer is told, so it deletes what it has.
+    pub async fn send_file(&self, offered: OfferedFile, mut progress: impl FnMut(u64)) -> Result<(), FileError> {
+        let OfferedFile { transfer_id, peer_id, path, size, answer } = offered;
+        let result = match answer.await {
+            Ok(true) => self.send_chunks(&transfer_id, &peer_id, &path, size, &mut progress).await,
+            Ok(false) => Err(FileError::Rejected),
+            // Dropped with the connection
+            Err(_) => Err(FileError::connection_ended()),
+        };
+        // Still here when it's our side that gave up
+        let ours = self.inner.lock().unwrap().outgoing.remove(&transfer_id).is_some();
+        if ours && result.is_err() {
+            self.tell(&peer_id, FileMessage::Reject { transfer_id }).await;
+        }
+        result
+    }
+
+    /// Take the file offered as `transfer_id` into folder `dest_dir`. It
+    /// comes in on the peer's receive loop, and is saved under its own name,
+    /// or a free one like it, once complete.
+    pub async fn accept_file(&self, transfer_id: &str, dest_dir: &Path) -> Result<(), FileError> {
+        let peer_id = {
+            let inner = self.inner.lock().unwrap();
+            let file = inner.incoming.get(transfer_id).filter(|file| file.accepted.is_none());
+            file.ok_or(FileError::UnknownTransfer)?.peer_id.clone()
+        };
+        let peer = self.get(&peer_id).ok_or(FileError::NoConnection)?;
+        // Made now, so a folder it can't go in fails here and not on the
+        // first chunk
+        let part = partial_path(dest_dir, transfer_id);
+        let created = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&part).await;
+        created.map_err(|e| FileError::Io { error: format!("{}: {}", dest_dir.display(), e) })?;
+        let accepted = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.incoming.get_mut(transfer_id).filter(|file| file.accepted.is_none()) {
+                Some(file) => {
+                    file.accepted = Some(Accepted { dest_dir: dest_dir.to_path_buf(), part: part.clone(), received: 0 });
+                    true
+                }
+                None => false,
+            }
+        };
+        // Turned down or cut off meanwhile
+        if !accepted {
+            remove_partial(&part);
+            return Err(FileError::UnknownTransfer);
+        }
+        let answer = FileMessage::Accept { transfer_id: transfer_id.to_string() };
+        if let Err(e) = peer.writer.send_file_message(&answer).await {
+            self.drop_incoming(&peer_id, transfer_id);
+            return Err(FileError::Failed { error: e.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Turn down the file offered as `transfer_id`, or give up on it partway
+    /// if it was taken, deleting what arrived of it
+    pub async fn reject_file(&self, transfer_id: &str) -> Result<(), FileError> {
+        let peer_id = {
+            let inner = self.inner.lock().unwrap();
+            inner.incoming.get(transfer_id).map(|file| file.peer_id.clone()).ok_or(FileError::UnknownTransfer)?
+        };
+        self.drop_incoming(&peer_id, transfer_id);
+        self.tell(&peer_id, FileMessage::Reject { transfer_id: transfer_id.to_string() }).await;
+        Ok(())
+    }
+
+    /// The files peer `id` offered or was sending when its connection
+    /// ended, by transfer id, with their partial copies deleted; for once
+    /// it's gone
+    pub fn take_abandoned_files(&self, id: &str) -> Vec<String> {
+        self.inner.lock().unwrap().abandoned.remove(id).unwrap_or_default()
+    }
+
+    /// Wait for the next message from peer `id`, or for its connection to end
+    pub async fn receive(&self, id: &str, inbox: &mut Inbox) -> Received {
+        if self.get(id).is_none() {
+            return Received::HungUp;
+        }
+        let peer = inbox.reader.peer_addr();
+        loop {
+            // Dropping a half-read frame is fine, the connection goes with it
+            let result = tokio::select! {
+                biased;
+                _ = &mut inbox.hang_up => {
+                    inbox.drop_files();
+                    return Received::HungUp;
+                }
+                result = inbox.reader.next_message() => result,
+            };
+            let ended = match result {
+                Ok(Some(PeerMessage::Text(body))) => return Received::Message { from: peer, body },
+                Ok(Some(PeerMessage::Hello { instance, fingerprint })) => {
+                    let changed = self.recognise(id, &instance, fingerprint);
+                    if let Some(duplicate) = self.settle(id, instance).await {
+                        return duplicate;
+                    }
+                    match changed {
+                        Some(changed) => return changed,
+                        None => continue,
+                    }
+                }
+                Ok(Some(PeerMessage::Pong { rtt })) => return Received::RoundTrip { rtt },
+                Ok(Some(PeerMessage::Delivered { id: message_id })) => {
+                    // Only for messages sent here, and still waited for
+                    let mut inner = self.inner.lock().unwrap();
+                    if inner.awaiting.get(&message_id).is_some_and(|peer_id| peer_id == id) {
+                        inner.awaiting.remove(&message_id);
+                        return Received::Delivered { message_id };
+                    }
+                    continue;
+                }
+                Ok(Some(PeerMessage::File(file))) => match self.file_message(id, inbox, file).await {
+                    Some(received) => return received,
+                    None => continue,
+                },
+                Ok(Some(PeerMessage::Pairing { secret })) => {
+                    if self.redeem(&secret) {
+                        tracing::info!(peer_id = %id, "Peer connected with an invite");
+                        continue;
+                    }
+                    tracing::warn!(peer_id = %id, "Peer's invite is used up, expired or not ours");
+                    Received::Refused { peer }
+                }
+                Ok(None) => Received::Closed { peer },
+                Err(error) => Received::Failed { peer, error },
+            };
+            inbox.drop_files();
+            self.remove(id);
+            return ended;
+        }
+    }
+
+    /// Record `fingerprint` as that of the connection to peer `id`, app
+    /// instance `instance`, checking it against the one last seen for that
+    /// instance: the same one keeps it verified if it was, another is
+    /// reported
+    fn recognise(&self, id: &str, instance: &str, fingerprint: Fingerprint) -> Option<Received> {
+        let mut inner = self.inner.lock().unwrap();
+        let peer = inner.peers.get(id).map(Arc::clone)?;
+        *peer.fingerprint.lock().unwrap() = Some(fingerprint);
+        // Back, so not among those gone, and called what it was
+        let (back, departed): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut inner.departed).into_iter().partition(|departed| departed.instance.as_deref() == Some(instance));
+        inner.departed = departed.into();
+        let mut label = peer.label.lock().unwrap();
+        if label.is_none() {
+            *label = back.into_iter().find_map(|departed| departed.info.label);
+        }
+        drop(label);
+        let known = inner.known.entry(instance.to_string()).or_insert(Known { fingerprint, verified: false });
+        if known.fingerprint == fingerprint {
+            if known.verified {
+                *peer.verified.lock().unwrap() = Some(fingerprint);
+            }
+            return None;
+        }
+        let previous = std::mem::replace(known, Known { fingerprint, verified: false });
+        *peer.verified.lock().unwrap() = None;
+        tracing::warn!(peer_id = %id, %instance, "Peer came back with another connection fingerprint");
+        Some(Received::FingerprintChanged {
+            peer: peer.addr,
+            previous: previous.fingerprint,
+            fingerprint,
+            verified: previous.verified,
+        })
+    }
+
+    /// Record that peer `id` is app instance `instance`, hanging up on one
+    /// of the two connections if there's another to that instance
+    async fn settle(&self, id: &str, instance: String) -> Option<Received> {
+        let (dropped, kept) = {
+            let inner = self.inner.lock().unwrap();
+            let peer = inner.peers.get(id)?;
+            *peer.instance.lock().unwrap() = Some(instance.clone());
+            let other = inner.order.iter().find(|other| {
+                let twin = &inner.peers[*other];
+                *other != id
+                    && !(twin.relay && peer.relay)
+                    && twin.instance.lock().unwrap().as_deref() == Some(instance.as_str())
+            })?;
+            let position = |known: &str| inner.order.iter().position(|other| other == known);
+            let (first, second) = if position(id) < position(other) { (id, other.as_str()) } else { (other.as_str(), id) };
+            // Both ends have to pick the same one without talking it over:
+            // a relayed connection is relayed at both ends, and of one dialed
+            // each way, the one the smaller instance id dialed
+            let keep_first = match (&inner.peers[first], &inner.peers[second]) {
+                (a, b) if a.relay != b.relay => !a.relay,
+                (a, b) if a.origin == b.origin => true,
+                (a, _) if a.origin == Origin::Outbound => *self.instance < *instance,
+                _ => *instance < *self.instance,
+            };
+            let (dropped, kept) = if keep_first { (second, first) } else { (first, second) };
+            (dropped.to_string(), kept.to_string())
+        };
+        tracing::info!(peer_id = %dropped, %kept, "Dropping a second connection to the same peer");
+        let peer = self.disconnect(&dropped).await?;
+        Some(Received::Duplicate { dropped, peer, kept })
+    }
+
+    /// Send file `transfer_id` at `path` to peer `peer_id`, which took it
+    async fn send_chunks(
+        &self,
+        transfer_id: &str,
+        peer_id: &str,
+        path: &Path,
+        size: u64,
+        progress: &mut impl FnMut(u64),
+    ) -> Result<(), FileError> {
+        let io_error = |e: std::io::Error| FileError::Io { error: format!("{}: {}", path.display(), e) };
+        let writer = self.writer(peer_id).ok_or_else(FileError::connection_ended)?;
+        let mut buffer = vec![0; writer.max_chunk()];
+        if buffer.is_empty() {
+            return Err(FileError::Failed { error: "The connection's frames are too small for file chunks".to_string() });
+        }
+        let mut file = tokio::fs::File::open(path).await.map_err(io_error)?;
+        let mut sent = 0;
+        // An empty file still goes as one empty chunk, which completes it
+        loop {
+            let wanted = (size - sent).min(buffer.len() as u64) as usize;
+            let read = file.read(&mut buffer[..wanted]).await.map_err(io_error)?;
+            if read == 0 && wanted > 0 {
+                return Err(FileError::Io { error: format!("{} got shorter while being sent", path.display()) });
+            }
+            {
+                let inner = self.inner.lock().unwrap();
+                if !inner.outgoing.contains_key(transfer_id) {
+                    let gone = !inner.peers.contains_key(peer_id);
+                    return Err(if gone { FileError::connection_ended() } else { FileError::Rejected });
+                }
+            }
+            let chunk = FileChunk { transfer_id: transfer_id.to_string(), offset: sent, data: buffer[..read].to_vec() };
+            let written = writer.send_file_message(&FileMessage::Chunk(chunk)).await;
+            written.map_err(|e| FileError::Failed { error: e.to_string() })?;
+            let before = sent;
+            sent += read as u64;
+            if sent == size {
+                return Ok(());
+            }
+            if sent / FILE_PROGRESS_STEP > before / FILE_PROGRESS_STEP {
+                progress(sent);
+            }
+        }
+    }
+
+    /// Act on file transfer message `file` from peer `id`, answering what
+    /// its receive loop reports, if anything
+    async fn file_message(&self, id: &str, inbox: &mut Inbox, file: FileMessage) -> Option<Received> {
+        match file {
+            FileMessage::Offer(offer) => self.offered(id, offer).await,
+            FileMessage::Chunk(chunk) => self.chunk(id, inbox, chunk).await,
+            FileMessage::Accept { transfer_id } => {
+                let mut inner = self.inner.lock().unwrap();
+                let file = inner.outgoing.get_mut(&transfer_id).filter(|file| file.peer_id == id);
+                if let Some(answer) = file.and_then(|file| file.answer.take()) {
+                    let _ = answer.send(true);
+                }
+                None
+            }
+            FileMessage::Reject { transfer_id } => {
+                // Our offer turned down, or our file given up on partway
+                let ours = {
+                    let mut inner = self.inner.lock().unwrap();
+                    match inner.outgoing.get(&transfer_id) {
+                        Some(file) if file.peer_id == id => inner.outgoing.remove(&transfer_id),
+                        _ => None,
+                    }
+                };
+                if let Some(file) = ours {
+                    if let Some(answer) = file.answer {
+                        let _ = answer.send(false);
+                    }
+                    return None;
+                }
+                // The peer gave up on one it was sending us
+                inbox.drop_file(&transfer_id);
+                let error = "The peer gave up on the file".to_string();
+                self.drop_incoming(id, &transfer_id).then_some(Received::FileFailed { transfer_id, error })
+            }
+        }
+    }
+
+    /// Take note of `offer` from peer `id`, unless it's over the limit or
+    /// names no plain file, which the peer is told at once
+    async fn offered(&self, id: &str, offer: FileOffer) -> Option<Received> {
+        let refused = {
+            let mut inner = self.inner.lock().unwrap();
+            // Offered twice; the first offer stands
+            if inner.incoming.contains_key(&offer.transfer_id) {
+                return None;
+            }
+            // Only the hyphenated form is 36 long; the id goes in a file name
+            let plain_id = offer.transfer_id.len() == 36 && uuid::Uuid::parse_str(&offer.transfer_id).is_ok();
+            if !plain_id || !is_plain_file_name(&offer.name) {
+                Some(format!("Offered under an unusable name: {:?}", offer.name))
+            } else if offer.size > inner.max_file_size {
+                Some(FileError::TooLarge { bytes: offer.size, limit: inner.max_file_size }.to_string())
+            } else {
+                let file = IncomingFile { peer_id: id.to_string(), offer: offer.clone(), accepted: None };
+                inner.incoming.insert(offer.transfer_id.clone(), file);
+                None
+            }
+        };
+        let Some(error) = refused else { return Some(Received::FileOffered { offer }) };
+        tracing::info!(peer_id = %id, transfer_id = %offer.transfer_id, %error, "Turned a file down");
+        self.tell(id, FileMessage::Reject { transfer_id: offer.transfer_id.clone() }).await;
+        Some(Received::FileFailed { transfer_id: offer.transfer_id, error })
+    }
+
+    /// Write `chunk` of a file peer `id` is sending, which has to come
+    /// right after what's written already
+    async fn chunk(&self, id: &str, inbox: &mut Inbox, chunk: FileChunk) -> Option<Received> {
+        let FileChunk { transfer_id, offset, data } = chunk;
+        let expected = {
+            let inner = self.inner.lock().unwrap();
+            inner.incoming.get(&transfer_id).filter(|file| file.peer_id == id).and_then(|file| {
+                let accepted = file.accepted.as_ref()?;
+                Some((accepted.part.clone(), accepted.received, file.offer.size))
+            })
+        };
+        // Given up on here, or never taken
+        let Some((part, received, size)) = expected else {
+            inbox.drop_file(&transfer_id);
+            return None;
+        };
+        if offset != received || data.len() as u64 > size - received {
+            let error = "The peer sent the file out of order".to_string();
+            return self.fail_incoming(id, inbox, transfer_id, error).await;
+        }
+        if let Err(e) = write_chunk(inbox, &transfer_id, part, &data).await {
+            return self.fail_incoming(id, inbox, transfer_id, e.to_string()).await;
+        }
+
+        let received = received + data.len() as u64;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.incoming.get_mut(&transfer_id).and_then(|file| file.accepted.as_mut()) {
+                Some(accepted) => accepted.received = received,
+                // Given up on while writing
+                None => {
+                    drop(inner);
+                    inbox.drop_file(&transfer_id);
+                    return None;
+                }
+            }
+        }
+        if received < size {
+            let reported = received / FILE_PROGRESS_STEP > (received - data.len() as u64) / FILE_PROGRESS_STEP;
+            return reported.then_some(Received::FileProgress { transfer_id, received, size });
+        }
+
+        let (_, mut file) = inbox.files.remove(&transfer_id)?;
+        if let Err(e) = file.flush().await {
+            return self.fail_incoming(id, inbox, transfer_id, e.to_string()).await;
+        }
+        drop(file);
+        let incoming = self.inner.lock().unwrap().incoming.remove(&transfer_id)?;
+        let accepted = incoming.accepted?;
+        match move_into_place(&accepted.part, &accepted.dest_dir, &incoming.offer.name).await {
+            Ok(path) => {
+                tracing::info!(peer_id = %id, %transfer_id, bytes = size, "File received");
+                Some(Received::FileReceived { transfer_id, path })
+            }
+            Err(e) => {
+                remove_partial(&accepted.part);
+                let error = format!("{}: {}", accepted.dest_dir.display(), e);
+                self.tell(id, FileMessage::Reject { transfer_id: transfer_id.clone() }).await;
+                Some(Received::FileFailed { transfer_id, error })
+            }
+        }
+    }
+
+    /// Give up on file `transfer_id` coming in from peer `id` over `error`,
+    /// telling the peer; `None` if it was given up on already
+    async fn fail_incoming(&self, id: &str, inbox: &mut Inbox, transfer_id: String, error: String) -> Option<Received> {
+        inbox.drop_file(&transfer_id);
+        if !self.drop_incoming(id, &transfer_id) {
+            return None;
+        }
+        tracing::warn!(peer_id = %id, %transfer_id, %error, "Receiving a file failed");
+        self.tell(id, FileMessage::Reject { transfer_id: transfer_id.clone() }).await;
+        Some(Received::FileFailed { transfer_id, error })
+    }
+
+    /// Forget file `transfer_id` coming in from peer `id`, deleting what was
+    /// written of it; false if there's no such file
+    fn drop_incoming(&self, id: &str, transfer_id: &str) -> bool {
+        let file = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.incoming.get(transfer_id) {
+                Some(file) if file.peer_id == id => inner.incoming.remove(transfer_id),
+                _ => return false,
+            }
+        };
+        if let Some(accepted) = file.and_then(|file| file.accepted) {
+            remove_partial(&accepted.part);
+        }
+        true
+    }
+
+    /// Send peer `id` a file answer if it's still connected; one that can't
+    /// be sent doesn't matter, as the connection is going
+    async fn tell(&self, id: &str, file: FileMessage) {
+        if let Some(peer) = self.get(id) {
+            if let Err(e) = peer.writer.send_file_message(&file).await {
+                tracing::debug!(peer_id = %id, error = %e, "Couldn't answer about a file");
+            }
+        }
+    }
+
+    /// Rotate the keys in `crypto` now, asking every connected peer to
+    /// rotate along and waiting up to `ack_timeout` for each to confirm.
+    /// Refused within `ROTATE_COOLDOWN` of the last time.
+    pub async fn rotate_keys(
+        &self,
+        crypto: &Arc<Mutex<SilenceCrypto>>,
+        ack_timeout: Duration,
+    ) -> Result<RotationReport, RotateError> {
+        let peers = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(since) = inner.rotated_at.map(|at| at.elapsed()).filter(|since| *since < ROTATE_COOLDOWN) {
+                return Err(RotateError::TooSoon { retry_in: ROTATE_COOLDOWN - since });
+            }
+            inner.rotated_at = Some(Instant::now());
+            inner.order.iter().map(|id| (id.clone(), Arc::clone(&inner.peers[id]))).collect::<Vec<_>>()
+        };
+
+        let rotated = {
+            let mut crypto = crypto.lock().await;
+            crypto.rotate_keys().map_err(RotateError::Crypto)?;
+            KeyRotated::new(crypto.key_epoch(), crypto.time_until_rotation())
+        };
+
+        // Asked all at once, so one slow peer doesn't hold up the rest
+        let asked: Vec<_> = peers
+            .into_iter()
+            .map(|(id, peer)| {
+                let writer = peer.writer.clone();
+                (id, tokio::spawn(async move { writer.request_key_rotation(ack_timeout).await }))
+            })
+            .collect();
+        let local_only = asked.is_empty();
+        let mut report = RotationReport { rotated, confirmed: Vec::new(), unconfirmed: Vec::new(), local_only };
+        for (peer_id, answer) in asked {
+            let answer = answer.await.map_err(|e| RotateError::Request {
+                peer_id: peer_id.clone(),
+                error: e.to_string(),
+                rotated: report.rotated.clone(),
+            })?;
+            match answer {
+                Ok(()) => report.confirmed.push(peer_id),
+                Err(e) => {
+                    tracing::warn!(%peer_id, error = %e, "Peer didn't confirm the key rotation");
+                    report.unconfirmed.push(Unconfirmed { peer_id, error: e.to_string() });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Drop every connection at once, ending the receive loops, without
+    /// shutting the connections down first, and forget the invites handed
+    /// out; returns how many connections there were
+    pub fn abandon_all(&self) -> usize {
+        let (peers, incoming) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.order.clear();
+            inner.unread.clear();
+            inner.outgoing.clear();
+            inner.abandoned.clear();
+            inner.invites.clear();
+            inner.known.clear();
+            inner.departed.clear();
+            (std::mem::take(&mut inner.peers), std::mem::take(&mut inner.incoming))
+        };
+        for accepted in incoming.into_values().filter_map(|file| file.accepted) {
+            remove_partial(&accepted.part);
+        }
+        for peer in peers.values() {
+            if let Some(hang_up) = peer.hang_up.lock().unwrap().take() {
+                let _ = hang_up.send(());
+            }
+        }
+        peers.len()
+    }
+
+    /// Hang up on peer `id`, returning its address; `None` when there is no
+    /// such connection, so calling this twice is fine
+    pub async fn disconnect(&self, id: &str) -> Option<SocketAddr> {
+        let peer = self.remove(id)?;
+        // Stops the receive loop, which drops the reading half
+        if let Some(hang_up) = peer.hang_up.lock().unwrap().take() {
+            let _ = hang_up.send(());
+        }
+        if let Err(e) = peer.writer.close().await {
+            tracing::debug!(peer = %peer.addr, error = %e, "Hanging up failed");
+        }
+        Some(peer.addr)
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Peer>> {
+        self.inner.lock().unwrap().peers.get(id).cloned()
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<Peer>> {
+        let (peer, cut_off) = {
+            let mut inner = self.inner.lock().unwrap();
+            let peer = inner.peers.remove(id)?;
+            inner.order.retain(|other| other != id);
+            // A second connection to a peer still connected isn't a peer gone
+            let instance = peer.instance.lock().unwrap().clone();
+            let twin = inner.peers.values().any(|other| instance.is_some() && *other.instance.lock().unwrap() == instance);
+            if !twin {
+                let info = PeerInfo {
+                    state: PeerState::Disconnected,
+                    disconnected_at: Some(crate::events::now_millis()),
+                    ..peer.info(id, 0)
+                };
+                inner.departed.push_front(Departed { instance, info });
+                inner.departed.truncate(RECENT_PEERS);
+            }
+            // Nothing more of these comes or goes
+            inner.outgoing.retain(|_, file| file.peer_id != id);
+            let (cut_off, kept): (HashMap<_, _>, HashMap<_, _>) =
+                std::mem::take(&mut inner.incoming).into_iter().partition(|(_, file)| file.peer_id == id);
+            inner.incoming = kept;
+            if !cut_off.is_empty() {
+                inner.abandoned.entry(id.to_string()).or_default().extend(cut_off.keys().cloned());
+            }
+            (peer, cut_off)
+        };
+        for accepted in cut_off.into_values().filter_map(|file| file.accepted) {
+            remove_partial(&accepted.part);
+        }
+        Some(peer)
+    }
+}
+
+/// Where the partial copy of file `transfer_id` goes in `dir`: hidden, and
+/// named after the transfer so no two clash
+fn partial_path(dir: &Path, transfer_id: &str) -> PathBuf {
+    dir.join(format!(".silence-{}.part", transfer_id))
+}
+
+/// Whether a peer's file name `name` names a file and nothing else: no
+/// directories on any platform, nothing hidden or special
+fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', ':', '\0'])
+}
+
+/// Write `data` to the file coming in as `transfer_id`, opening its partial
+/// copy `part` for the first chunk
+async fn write_chunk(inbox: &mut Inbox, transfer_id: &str, part: PathBuf, data: &[u8]) -> std::io::Result<()> {
+    let file = match inbox.files.entry(transfer_id.to_string()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        // Not created here: `accept_file` did, and gone means given up on
+        Entry::Vacant(entry) => {
+            let file = tokio::fs::OpenOptions::new().write(true).open(&part).await?;
+            entry.insert((part, file))
+        }
+    };
+    file.1.write_all(data).await
+}
+
+/// Rename partial copy `part` to `name` in `dir`, or to `name (1)`,
+/// `name (2)` and so on if that's taken, answering where it went. The name
+/// is claimed by creating it first, so a file that turns up there meanwhile
+/// is never overwritten.
+async fn move_into_place(part: &Path, dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut copy = 0;
+    loop {
+        let path = match copy {
+            0 => dir.join(name),
+            _ => dir.join(format!("{} ({}){}", stem, copy, extension)),
+        };
+        match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                copy += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        // Replaces only the empty file just created
+        if let Err(e) = tokio::fs::rename(part, &path).await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+        return Ok(path);
+    }
+}
+
+/// Delete the partial copy of a file that won't be finished
+fn remove_partial(part: &Path) {
+    match std::fs::remove_file(part) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            tracing::warn!(path = %part.display(), error = %e, "Couldn't delete a partly received file");
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SilenceCrypto;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    /// The two ends' crypto engines, each its own but starting from the
+    /// same master key, in place of a key exchange
+    struct Engines {
+        ours: Arc<Mutex<SilenceCrypto>>,
+        theirs: Arc<Mutex<SilenceCrypto>>,
+    }
+
+    /// Both ends of a direct connection, ours first
+    async fn connected_pair(crypto: &Engines) -> (P2PConnection, P2PConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (remote, remote_addr) = listener.accept().await.unwrap();
+        (
+            P2PConnection::new(stream, addr, Arc::clone(&crypto.ours), 4096, false).await,
+            P2PConnection::new(remote, remote_addr, Arc::clone(&crypto.theirs), 4096, false).await,
+        )
+    }
+
+    fn crypto() -> Engines {
+        let master_key: [u8; 32] = rand::random();
+        let engine = || Arc::new(Mutex::new(SilenceCrypto::from_master_key(master_key, 60).unwrap()));
+        Engines { ours: engine(), theirs: engine() }
+    }
+
+    async fn send(peers: &Peers, id: &str, text: &str) {
+        peers.writer(id).unwrap().send_text(text).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_idempotent() {
+        let peers = Peers::new();
+        assert_eq!(peers.disconnect("nobody").await, None);
+
+        let (connection, _remote) = connected_pair(&crypto()).await;
+        let addr = connection.peer_addr();
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+        assert!(peers.is_connected());
+        assert_eq!(peers.disconnect(&id).await, Some(addr));
+        assert!(!peers.is_connected());
+        assert_eq!(peers.disconnect(&id).await, None);
+        assert!(peers.writer(&id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_ends_a_waiting_receive_loop() {
+        let peers = Peers::new();
+        let (connection, remote) = connected_pair(&crypto()).await;
+        let (id, mut inbox) = peers.add(connection, Origin::Outbound);
+
+        // A loop waiting on a peer that stays quiet
+        let receiving = peers.clone();
+        let loop_id = id.clone();
+        let receive_loop = tokio::spawn(async move { receiving.receive(&loop_id, &mut inbox).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(timeout(Duration::from_secs(5), peers.disconnect(&id)).await.unwrap().is_some());
+        let received = timeout(Duration::from_secs(5), receive_loop).await.unwrap().unwrap();
+        assert!(matches!(received, Received::HungUp), "{:?}", received);
+        // The peer sees the connection end
+        let mut remote = remote;
+        let end = timeout(Duration::from_secs(5), remote.receive_message()).await.unwrap();
+        assert!(matches!(end, Ok(None)), "{:?}", end.map_err(|e| e.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_send_while_the_receive_loop_waits() {
+        let peers = Peers::new();
+        let (connection, mut remote) = connected_pair(&crypto()).await;
+        let (id, mut inbox) = peers.add(connection, Origin::Outbound);
+
+        let receiving = peers.clone();
+        let loop_id = id.clone();
+        let receive_loop = tokio::spawn(async move { receiving.receive(&loop_id, &mut inbox).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Goes out while the loop is still waiting
+        timeout(Duration::from_secs(5), send(&peers, &id, "while you wait")).await.unwrap();
+        let message = timeout(Duration::from_secs(5), remote.receive_message()).await.unwrap();
+        assert_eq!(message.unwrap().unwrap(), "while you wait");
+        assert!(!receive_loop.is_finished());
+
+        // And the loop still gets the answer
+        remote.send_text("got it").await.unwrap();
+        let received = timeout(Duration::from_secs(5), receive_loop).await.unwrap().unwrap();
+        assert!(matches!(&received, Received::Message { body, .. } if body == "got it"), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn test_sent_message_is_confirmed() {
+        let crypto = crypto();
+        let (alice, bob) = (Peers::new(), Peers::new());
+        let (to_bob, to_alice) = connected_pair(&crypto).await;
+        let (bob_id, mut bob_inbox) = alice.add(to_bob, Origin::Outbound);
+        let (alice_id, alice_inbox) = bob.add(to_alice, Origin::Inbound);
+        let bob_loop = receive_loop(&bob, alice_id, alice_inbox);
+
+        assert_eq!(alice.send(&bob_id, "m1", "hello").await, Ok(true));
+        let received = timeout(Duration::from_secs(5), alice.receive(&bob_id, &mut bob_inbox)).await.unwrap();
+        assert!(matches!(&received, Received::Delivered { message_id } if message_id == "m1"), "{:?}", received);
+        // Confirmed, so nothing left to give up on
+        assert!(!alice.expire("m1"));
+
+        alice.disconnect(&bob_id).await.unwrap();
+        let received = timeout(Duration::from_secs(5), bob_loop).await.unwrap().unwrap();
+        assert!(matches!(&received[0], Received::Message { body, .. } if body == "hello"), "{:?}", received);
+        assert!(alice.take_undelivered(&bob_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_messages_fail() {
+        let peers = Peers::new();
+        let (connection, remote) = connected_pair(&crypto()).await;
+        let (id, mut inbox) = peers.add(connection, Origin::Outbound);
+
+        // Nothing reads at the other end, so nothing is confirmed
+        assert_eq!(peers.send(&id, "timed out", "one").await, Ok(true));
+        assert_eq!(peers.send(&id, "cut off", "two").await, Ok(true));
+        assert!(peers.expire("timed out"));
+        assert!(!peers.expire("timed out"));
+
+        // The connection ending gives up on the rest
+        drop(remote);
+        let received = timeout(Duration::from_secs(5), peers.receive(&id, &mut inbox)).await.unwrap();
+        assert!(matches!(received, Received::Closed { .. } | Received::Failed { .. }), "{:?}", received);
+        assert_eq!(peers.take_undelivered(&id), ["cut off"]);
+        assert!(peers.take_undelivered(&id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_refuses_before_writing() {
+        let peers = Peers::new();
+        assert_eq!(peers.send("nobody", "m1", "hi").await, Err(SendError::NoConnection));
+
+        let (connection, _remote) = connected_pair(&crypto()).await;
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+        let limit = 4096 - crate::MESSAGE_OVERHEAD;
+        let error = peers.send(&id, "m2", &"x".repeat(limit + 1)).await.unwrap_err();
+        assert_eq!(error, SendError::TooLarge { bytes: limit + 1, limit });
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({ "kind": "too_large", "bytes": limit + 1, "limit": limit })
+        );
+        assert!(!peers.expire("m2"));
+        assert_eq!(peers.info(&id).unwrap().stats.messages_sent, 0);
+    }
+
+    #[tokio::test]
+    async fn test_budget_is_the_frame_less_its_overhead() {
+        let peers = Peers::new();
+        assert_eq!(peers.budget("nobody"), None);
+
+        let (connection, mut remote) = connected_pair(&crypto()).await;
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+        let budget = peers.budget(&id).unwrap();
+        assert_eq!(
+            budget,
+            MessageBudget {
+                peer_id: id.clone(),
+                max_bytes: 4096 - crate::MESSAGE_OVERHEAD,
+                frame_bytes: 4096,
+                overhead: crate::MESSAGE_OVERHEAD,
+                relay: false,
+            }
+        );
+        assert_eq!(crate::RELAY_MESSAGE_OVERHEAD, 1 + crate::ENCRYPTION_OVERHEAD);
+
+        // Counted in bytes, so two-byte characters fill it at half the count
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let longest = "é".repeat(budget.max_bytes / 2);
+        assert!(peers.send(&id, &message_id, &longest).await.unwrap());
+        let received = timeout(Duration::from_secs(5), remote.receive_message()).await.unwrap();
+        assert_eq!(received.unwrap().unwrap(), longest);
+        let over = format!("{}éé", longest);
+        assert_eq!(
+            peers.send(&id, "m2", &over).await,
+            Err(SendError::TooLarge { bytes: over.len(), limit: budget.max_bytes })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_two_peers_with_interleaved_messages() {
+        let crypto = crypto();
+        let peers = Peers::new();
+        let (alice, mut alice_remote) = connected_pair(&crypto).await;
+        let (bob, mut bob_remote) = connected_pair(&crypto).await;
+        let (alice_id, mut alice_inbox) = peers.add(alice, Origin::Outbound);
+        let (bob_id, mut bob_inbox) = peers.add(bob, Origin::Outbound);
+        assert_eq!(peers.latest().as_deref(), Some(bob_id.as_str()));
+        assert_eq!(peers.list().iter().map(|info| info.peer_id.as_str()).collect::<Vec<_>>(), [&alice_id, &bob_id]);
+
+        // Our sends reach only the peer they were addressed to
+        for n in 0..3 {
+            send(&peers, &alice_id, &format!("to alice {}", n)).await;
+            send(&peers, &bob_id, &format!("to bob {}", n)).await;
+        }
+        for n in 0..3 {
+            let message = timeout(Duration::from_secs(5), alice_remote.receive_message()).await.unwrap();
+            assert_eq!(message.unwrap().unwrap(), format!("to alice {}", n));
+            let message = timeout(Duration::from_secs(5), bob_remote.receive_message()).await.unwrap();
+            assert_eq!(message.unwrap().unwrap(), format!("to bob {}", n));
+        }
+
+        // Each peer's counters see only its own traffic
+        let stats = |id: &str| peers.info(id).unwrap().stats;
+        assert_eq!((stats(&alice_id).messages_sent, stats(&bob_id).messages_sent), (3, 3));
+        assert_eq!(stats(&alice_id).messages_received, 0);
+
+        // And theirs come back under the right id
+        for n in 0..3 {
+            bob_remote.send_text(&format!("from bob {}", n)).await.unwrap();
+            alice_remote.send_text(&format!("from alice {}", n)).await.unwrap();
+        }
+        for n in 0..3 {
+            let received = timeout(Duration::from_secs(5), peers.receive(&alice_id, &mut alice_inbox)).await.unwrap();
+            assert!(matches!(received, Received::Message { body, .. } if body == format!("from alice {}", n)));
+            let received = timeout(Duration::from_secs(5), peers.receive(&bob_id, &mut bob_inbox)).await.unwrap();
+            assert!(matches!(received, Received::Message { body, .. } if body == format!("from bob {}", n)));
+        }
+
+        assert_eq!(stats(&alice_id).messages_received, 3);
+        assert_eq!(stats(&bob_id).bytes_received, bob_remote.stats().bytes_sent);
+
+        // Hanging up on one leaves the other
+        peers.disconnect(&bob_id).await.unwrap();
+        assert_eq!(peers.latest().as_deref(), Some(alice_id.as_str()));
+        send(&peers, &alice_id, "still here").await;
+        // After the confirmations of alice's own messages
+        let message = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(text) = alice_remote.receive_message().await.unwrap() {
+                    return text;
+                }
+            }
+        });
+        assert_eq!(message.await.unwrap(), "still here");
+        let end = timeout(Duration::from_secs(5), bob_remote.receive_message()).await.unwrap();
+        assert!(matches!(end, Ok(None)), "{:?}", end.map_err(|e| e.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_both_ends_work_out_the_same_connection_fingerprint() {
+        let crypto = crypto();
+        let (left, right) = (Peers::new(), Peers::new());
+        let (dialed, accepted) = connected_pair(&crypto).await;
+        let (left_id, left_inbox) = left.add(dialed, Origin::Outbound);
+        let (right_id, right_inbox) = right.add(accepted, Origin::Inbound);
+        assert_eq!(left.fingerprint(&left_id), None);
+        assert!(!left.set_verified(&left_id, true));
+
+        let _loops = (receive_loop(&left, left_id.clone(), left_inbox), receive_loop(&right, right_id.clone(), right_inbox));
+        left.introduce(&left_id).await.unwrap();
+        right.introduce(&right_id).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while left.fingerprint(&left_id).is_none() || right.fingerprint(&right_id).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let (ours, theirs) = (left.fingerprint(&left_id).unwrap(), right.fingerprint(&right_id).unwrap());
+        assert_eq!(ours, theirs);
+        assert_eq!(ours.short_auth_string(), theirs.short_auth_string());
+        assert_ne!(ours, crypto.ours.lock().await.fingerprint());
+
+        assert!(!left.info(&left_id).unwrap().verified);
+        assert!(left.set_verified(&left_id, true));
+        assert!(left.info(&left_id).unwrap().verified);
+        assert!(left.set_verified(&left_id, false));
+        assert!(!left.info(&left_id).unwrap().verified);
+        assert!(!left.set_verified("nobody", true));
+    }
+
+    /// Connect `peers` to app instance `instance`, with keys from `crypto`,
+    /// and wait for the instance's hello to arrive; the peer id, its
+    /// receive loop, and the instance's end of the connection
+    async fn hello_from(
+        peers: &Peers,
+        crypto: &Engines,
+        instance: &str,
+    ) -> (String, tokio::task::JoinHandle<Received>, (ConnectionReader, ConnectionWriter)) {
+        let (connection, remote) = connected_pair(crypto).await;
+        let (id, inbox) = peers.add(connection, Origin::Outbound);
+        let receiving = receiving(peers, &id, inbox);
+        let (reader, writer) = remote.into_split();
+        writer.send_hello(instance).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while peers.fingerprint(&id).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        (id, receiving, (reader, writer))
+    }
+
+    #[tokio::test]
+    async fn test_an_instance_back_with_another_fingerprint_is_reported() {
+        let (before, after) = (crypto(), crypto());
+        let peers = Peers::new();
+        let (id, receiving, remote) = hello_from(&peers, &before, "them").await;
+        let verified = peers.fingerprint(&id).unwrap();
+        assert!(peers.set_verified(&id, true));
+        drop(remote);
+        let received = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert!(matches!(received, Received::Closed { .. }), "{:?}", received);
+
+        // Back with the same fingerprint, so still verified
+        let (id, receiving, remote) = hello_from(&peers, &before, "them").await;
+        assert!(peers.info(&id).unwrap().verified);
+        drop(remote);
+        timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+
+        let (id, receiving, _remote) = hello_from(&peers, &after, "them").await;
+        let received = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        match received {
+            Received::FingerprintChanged { previous, fingerprint, verified: true, .. } => {
+                assert_eq!(previous, verified);
+                assert_eq!(Some(fingerprint), peers.fingerprint(&id));
+                assert_ne!(fingerprint, verified);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(!peers.info(&id).unwrap().verified);
+
+        // Someone else isn't compared with them
+        let (id, receiving, _remote) = hello_from(&peers, &before, "someone else").await;
+        assert!(!peers.info(&id).unwrap().verified);
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unread_follows_the_conversation_shown() {
+        let crypto = crypto();
+        let peers = Peers::new();
+        let (alice, _alice_remote) = connected_pair(&crypto).await;
+        let (bob, _bob_remote) = connected_pair(&crypto).await;
+        let (alice_id, _alice_inbox) = peers.add(alice, Origin::Outbound);
+        let (bob_id, _bob_inbox) = peers.add(bob, Origin::Outbound);
+
+        // Looking at alice, only bob's messages go unread
+        peers.set_active(Some(alice_id.clone()));
+        assert!(!peers.received(&alice_id, "a1"));
+        assert!(peers.received(&bob_id, "b1"));
+
+        // Switched to bob, then the window went to the background
+        peers.set_active(Some(bob_id.clone()));
+        assert!(!peers.received(&bob_id, "b2"));
+        assert!(peers.received(&alice_id, "a2"));
+        peers.set_active(None);
+        assert!(peers.received(&bob_id, "b3"));
+        assert!(peers.received(&alice_id, "a3"));
+
+        let counts = peers.unread_counts();
+        assert_eq!((counts[&alice_id], counts[&bob_id]), (2, 2));
+        let listed: Vec<_> = peers.list().iter().map(|info| info.unread).collect();
+        assert_eq!(listed, [2, 2]);
+        assert_eq!(peers.info(&bob_id).unwrap().unread, 2);
+    }
+
+    #[tokio::test]
+    async fn test_mark_read_calls_the_read_hook() {
+        let peers = Peers::new();
+        let (connection, _remote) = connected_pair(&crypto()).await;
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+        let receipts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sent = Arc::clone(&receipts);
+        peers.on_read(move |peer_id, read| sent.lock().unwrap().push((peer_id.to_string(), read.to_vec())));
+
+        for message_id in ["m1", "m2", "m3"] {
+            peers.received(&id, message_id);
+        }
+        assert_eq!(peers.mark_read(&id, "m2"), ["m1", "m2"]);
+        assert_eq!(peers.info(&id).unwrap().unread, 1);
+
+        // Already read, unknown, or someone else's: nothing to tell
+        assert!(peers.mark_read(&id, "m1").is_empty());
+        assert!(peers.mark_read(&id, "m9").is_empty());
+        assert!(peers.mark_read("nobody", "m3").is_empty());
+
+        assert_eq!(peers.mark_read(&id, "m3"), ["m3"]);
+        assert!(peers.unread_counts().is_empty());
+        assert_eq!(
+            *receipts.lock().unwrap(),
+            [(id.clone(), vec!["m1".to_string(), "m2".to_string()]), (id.clone(), vec!["m3".to_string()])]
+        );
+
+        // Still counted once the peer is gone, until the history is cleared
+        peers.received(&id, "m4");
+        peers.disconnect(&id).await;
+        assert_eq!(peers.unread_counts()[&id], 1);
+        peers.clear_unread();
+        assert!(peers.unread_counts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abandon_all_forgets_every_peer() {
+        let crypto = crypto();
+        let peers = Peers::new();
+        let (first, _first_remote) = connected_pair(&crypto).await;
+        let (second, _second_remote) = connected_pair(&crypto).await;
+        let (first_id, mut inbox) = peers.add(first, Origin::Outbound);
+        let (second_id, _inbox) = peers.add(second, Origin::Outbound);
+
+        let receiving = peers.clone();
+        let loop_id = first_id.clone();
+        let receive_loop = tokio::spawn(async move { receiving.receive(&loop_id, &mut inbox).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(peers.abandon_all(), 2);
+        let received = timeout(Duration::from_secs(5), receive_loop).await.unwrap().unwrap();
+        assert!(matches!(received, Received::HungUp), "{:?}", received);
+        assert!(!peers.is_connected());
+        assert!(peers.list().is_empty());
+        assert_eq!(peers.latest(), None);
+        assert!(peers.writer(&second_id).is_none());
+        assert_eq!(peers.info(&first_id), None);
+        assert_eq!(peers.abandon_all(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_info_payload() {
+        let peers = Peers::new();
+        let (connection, _remote) = connected_pair(&crypto()).await;
+        let addr = connection.peer_addr();
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+        send(&peers, &id, "hi").await;
+
+        let info = peers.info(&id).unwrap();
+        let bytes_sent = info.stats.bytes_sent;
+        assert!(bytes_sent > 0);
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({
+                "peer_id": id,
+                "label": null,
+                "peer": addr.to_string(),
+                "fingerprint": null,
+                "verified": false,
+                "state": "connected",
+                "relay": false,
+                "origin": "outbound",
+                "connected_at": info.connected_at,
+                "disconnected_at": null,
+                "stats": {
+                    "messages_sent": 1,
+                    "messages_received": 0,
+                    "bytes_sent": bytes_sent,
+                    "bytes_received": 0,
+                    "rtt_ms": null,
+                },
+                "unread": 0,
+            })
+        );
+
+        peers.set_label(&id, Some("Alice".to_string()));
+        peers.disconnect(&id).await.unwrap();
+        let gone = serde_json::to_value(&peers.recent()[0]).unwrap();
+        assert_eq!((&gone["label"], &gone["state"]), (&serde_json::json!("Alice"), &serde_json::json!("disconnected")));
+        assert!(gone["disconnected_at"].as_u64().unwrap() >= info.connected_at);
+    }
+
+    /// Connect `ours` to `theirs`, each end saying hello and running its
+    /// receive loop; our peer id and theirs
+    async fn introduced(crypto: &Engines, ours: &Peers, theirs: &Peers) -> (String, String) {
+        let (dialed, accepted) = connected_pair(crypto).await;
+        let (our_id, our_inbox) = ours.add(dialed, Origin::Outbound);
+        let (their_id, their_inbox) = theirs.add(accepted, Origin::Inbound);
+        receive_loop(ours, our_id.clone(), our_inbox);
+        receive_loop(theirs, their_id.clone(), their_inbox);
+        ours.introduce(&our_id).await.unwrap();
+        theirs.introduce(&their_id).await.unwrap();
+        (our_id, their_id)
+    }
+
+    #[tokio::test]
+    async fn test_roster_of_two_peers() {
+        let crypto = crypto();
+        let (ours, alice, bob) = (Peers::new(), Peers::new(), Peers::new());
+        let (alice_id, alice_side) = introduced(&crypto, &ours, &alice).await;
+        let (bob_id, _) = introduced(&crypto, &ours, &bob).await;
+        // Both said hello and answered a ping
+        timeout(Duration::from_secs(5), async {
+            while ours.list().iter().any(|peer| peer.fingerprint.is_none() || peer.stats.rtt_ms.is_none()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(ours.set_label(&alice_id, Some("Alice".to_string())));
+        assert!(!ours.set_label("nobody", None));
+        let roster: Vec<_> =
+            ours.list().into_iter().map(|peer| (peer.peer_id, peer.label, peer.state, peer.disconnected_at)).collect();
+        assert_eq!(
+            roster,
+            [
+                (alice_id.clone(), Some("Alice".to_string()), PeerState::Connected, None),
+                (bob_id.clone(), None, PeerState::Connected, None),
+            ]
+        );
+        assert!(ours.recent().is_empty());
+
+        // Alice hangs up, and is among the peers gone, as she was
+        alice.disconnect(&alice_side).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while ours.list().len() > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(ours.list()[0].peer_id, bob_id);
+        let gone = ours.recent();
+        assert_eq!(gone.len(), 1);
+        assert_eq!((&gone[0].peer_id, gone[0].label.as_deref()), (&alice_id, Some("Alice")));
+        assert_eq!(gone[0].state, PeerState::Disconnected);
+        assert!(gone[0].disconnected_at.is_some());
+        assert!(gone[0].stats.rtt_ms.is_some());
+
+        // Back, she's called what she was, and gone no more
+        let (alice_id, _) = introduced(&crypto, &ours, &alice).await;
+        timeout(Duration::from_secs(5), async {
+            while ours.info(&alice_id).unwrap().label.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(ours.info(&alice_id).unwrap().label.as_deref(), Some("Alice"));
+        assert!(ours.recent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_info_payload() {
+        let crypto = crypto();
+        let fingerprint = crypto.ours.lock().await.fingerprint();
+        let peers = Peers::new();
+        let (connection, _remote) = connected_pair(&crypto).await;
+        let addr = connection.peer_addr();
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+
+        let info = peers.connection_info(&id, addr, fingerprint).unwrap();
+        assert_eq!(info.established_at, peers.info(&id).unwrap().connected_at);
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({
+                "peer_id": id,
+                "peer_addr": addr.to_string(),
+                "mode_used": "direct_only",
+                "relay": null,
+                "established_at": info.established_at,
+                "fingerprint": fingerprint.to_string(),
+                "max_plaintext": 4096 - crate::MESSAGE_OVERHEAD,
+            })
+        );
+        assert_eq!(peers.connection_info("nobody", addr, fingerprint), None);
+    }
+
+    #[test]
+    fn test_last_peer_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("silence-last-peer-{}", uuid::Uuid::new_v4()));
+        let path = dir.join(LAST_PEER_FILE);
+        assert_eq!(LastPeer::load(&path), None);
+
+        let last = LastPeer::new("192.0.2.7:8080".parse().unwrap(), ConnectionMode::RelayOnly, true);
+        last.save(&path).unwrap();
+        // As the next run would find it
+        let loaded = LastPeer::load(&path).unwrap();
+        assert_eq!(loaded, last);
+        assert_eq!(loaded.mode(), Ok(ConnectionMode::RelayOnly));
+
+        std::fs::write(&path, "address = \"192.0.2.7:8080\"\nmode = \"carrier_pigeon\"\nrelay = false\n").unwrap();
+        assert!(LastPeer::load(&path).unwrap().mode().unwrap_err().contains("carrier_pigeon"));
+        std::fs::write(&path, "not toml").unwrap();
+        assert_eq!(LastPeer::load(&path), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_leaves_the_map() {
+        let peers = Peers::new();
+        let (connection, remote) = connected_pair(&crypto()).await;
+        let (id, mut inbox) = peers.add(connection, Origin::Outbound);
+        remote.close().await.unwrap();
+
+        let received = timeout(Duration::from_secs(5), peers.receive(&id, &mut inbox)).await.unwrap();
+        assert!(matches!(received, Received::Closed { .. }), "{:?}", received);
+        assert!(!peers.is_connected());
+        assert_eq!(peers.latest(), None);
+    }
+
+    /// Receive from peer `id` until its loop would end, collecting what came
+    fn receive_loop(peers: &Peers, id: String, mut inbox: Inbox) -> tokio::task::JoinHandle<Vec<Received>> {
+        let peers = peers.clone();
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            loop {
+                let next = peers.receive(&id, &mut inbox).await;
+                let ends = match &next {
+                    Received::Message { .. } | Received::Delivered { .. } | Received::RoundTrip { .. } => false,
+                    Received::Duplicate { dropped, .. } => *dropped == id,
+                    _ => true,
+                };
+                received.push(next);
+                if ends {
+                    return received;
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_dialing_each_other_at_once_keeps_one_connection() {
+        let crypto = crypto();
+        let (left, right) = (Peers::new(), Peers::new());
+        let (left_dialed, right_accepted) = connected_pair(&crypto).await;
+        let (right_dialed, left_accepted) = connected_pair(&crypto).await;
+        let mut loops = Vec::new();
+        for (peers, connection, origin) in [
+            (&left, left_dialed, Origin::Outbound),
+            (&right, right_accepted, Origin::Inbound),
+            (&right, right_dialed, Origin::Outbound),
+            (&left, left_accepted, Origin::Inbound),
+        ] {
+            let (id, inbox) = peers.add(connection, origin);
+            loops.push((id.clone(), receive_loop(peers, id, inbox)));
+        }
+        for (peers, (id, _)) in [&left, &right, &right, &left].into_iter().zip(&loops) {
+            peers.introduce(id).await.unwrap();
+        }
+
+        timeout(Duration::from_secs(5), async {
+            while left.list().len() + right.list().len() > 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let (kept_left, kept_right) = (left.list().remove(0), right.list().remove(0));
+        // Both kept the connection the instance with the smaller id dialed
+        let left_dialed_kept = left.instance() < right.instance();
+        assert_eq!(kept_left.origin == Origin::Outbound, left_dialed_kept);
+        assert_eq!(kept_right.origin == Origin::Inbound, left_dialed_kept);
+
+        send(&left, &kept_left.peer_id, "through the one left").await;
+        timeout(Duration::from_secs(5), async {
+            while right.info(&kept_right.peer_id).unwrap().stats.messages_received == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        left.disconnect(&kept_left.peer_id).await.unwrap();
+        right.disconnect(&kept_right.peer_id).await.unwrap();
+        let mut duplicates = 0;
+        for (id, receive_loop) in loops {
+            let received = timeout(Duration::from_secs(5), receive_loop).await.unwrap().unwrap();
+            if id == kept_right.peer_id {
+                let message = received.iter().find(|next| matches!(next, Received::Message { .. }));
+                assert!(matches!(message, Some(Received::Message { body, .. }) if body == "through the one left"), "{:?}", received);
+            }
+            for next in &received {
+                if let Received::Duplicate { dropped, kept, .. } = next {
+                    assert!([&kept_left.peer_id, &kept_right.peer_id].contains(&kept), "{:?}", next);
+                    assert_ne!(dropped, kept);
+                    duplicates += 1;
+                }
+            }
+        }
+        assert!(duplicates > 0);
+    }
+
+    #[tokio::test]
+    async fn test_invites_are_good_once() {
+        let crypto = crypto();
+        let inviting = Peers::new();
+        let fingerprint = crypto.ours.lock().await.fingerprint();
+        let invite = Invite::new(vec!["192.0.2.7:7642".parse().unwrap()], fingerprint, crate::invite::INVITE_LIFETIME);
+        let expired = Invite { secret: rand::random(), expires_at: unix_now() - 1, ..invite.clone() };
+        inviting.expect_invite(&invite);
+        inviting.expect_invite(&expired);
+        let never_handed_out = Invite { secret: rand::random(), ..invite.clone() };
+
+        for (presented, good) in [(&invite, true), (&invite, false), (&expired, false), (&never_handed_out, false)] {
+            let (ours, theirs) = connected_pair(&crypto).await;
+            let (id, mut inbox) = inviting.add(ours, Origin::Inbound);
+            let joining = Peers::new();
+            let (joining_id, _joining_inbox) = joining.add(theirs, Origin::Outbound);
+            joining.present_invite(&joining_id, presented).await.unwrap();
+            send(&joining, &joining_id, "hi").await;
+
+            let received = timeout(Duration::from_secs(5), inviting.receive(&id, &mut inbox)).await.unwrap();
+            if good {
+                assert!(matches!(received, Received::Message { ref body, .. } if body == "hi"), "{:?}", received);
+            } else {
+                assert!(matches!(received, Received::Refused { .. }), "{:?}", received);
+                assert!(inviting.get(&id).is_none());
+            }
+        }
+    }
+
+    /// Spawn a receive loop for peer `id` that returns what ends it
+    fn receiving(peers: &Peers, id: &str, mut inbox: Inbox) -> tokio::task::JoinHandle<Received> {
+        let peers = peers.clone();
+        let id = id.to_string();
+        tokio::spawn(async move { peers.receive(&id, &mut inbox).await })
+    }
+
+    #[tokio::test]
+    async fn test_rotating_keys_with_a_peer() {
+        let crypto = crypto();
+        let peers = Peers::new();
+        let (connection, mut remote) = connected_pair(&crypto).await;
+        let (id, inbox) = peers.add(connection, Origin::Outbound);
+        let receiving = receiving(&peers, &id, inbox);
+        let answering = tokio::spawn(async move {
+            // Takes the rotation and confirms under its new keys, then
+            // answers a message sent under ours
+            assert!(remote.receive_message().await.unwrap().is_none());
+            let text = remote.receive_message().await.unwrap();
+            remote.send_text("after rotating").await.unwrap();
+            (remote, text)
+        });
+
+        let report = timeout(Duration::from_secs(5), peers.rotate_keys(&crypto.ours, Duration::from_secs(5)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.rotated.epoch, 1);
+        assert_eq!(report.confirmed, [id.as_str()]);
+        assert!(report.unconfirmed.is_empty());
+        assert!(!report.local_only);
+        assert_eq!(crypto.theirs.lock().await.key_epoch(), crypto.ours.lock().await.key_epoch());
+
+        // Text still goes both ways, and the confirmation didn't end the
+        // receive loop
+        send(&peers, &id, "rotated too").await;
+        let (_remote, text) = timeout(Duration::from_secs(5), answering).await.unwrap().unwrap();
+        assert_eq!(text.as_deref(), Some("rotated too"));
+        let received = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert!(matches!(&received, Received::Message { body, .. } if body == "after rotating"), "{:?}", received);
+        assert!(peers.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_confirmed_under_other_keys() {
+        let crypto = crypto();
+        // The peer is a rotation ahead, so ends up two ahead once it takes ours
+        crypto.theirs.lock().await.rotate_keys().unwrap();
+        let peers = Peers::new();
+        let (connection, mut remote) = connected_pair(&crypto).await;
+        let (id, inbox) = peers.add(connection, Origin::Outbound);
+        let _receiving = receiving(&peers, &id, inbox);
+        let answering = tokio::spawn(async move {
+            assert!(remote.receive_message().await.unwrap().is_none());
+            remote
+        });
+
+        // Turned down when the confirmation arrives, well before the timeout
+        let report = timeout(Duration::from_secs(5), peers.rotate_keys(&crypto.ours, Duration::from_secs(30)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(report.confirmed.is_empty());
+        assert_eq!(report.unconfirmed, [Unconfirmed { peer_id: id, error: NetworkError::KeysOutOfStep.to_string() }]);
+        let _remote = timeout(Duration::from_secs(5), answering).await.unwrap().unwrap();
+        assert_eq!(crypto.ours.lock().await.key_epoch(), 1);
+        assert_eq!(crypto.theirs.lock().await.key_epoch(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rotating_keys_alone_and_too_often() {
+        let crypto = crypto().ours;
+        let peers = Peers::new();
+        let report = peers.rotate_keys(&crypto, Duration::from_secs(5)).await.unwrap();
+        assert!(report.local_only);
+        assert!(report.confirmed.is_empty() && report.unconfirmed.is_empty());
+        assert_eq!(crypto.lock().await.key_epoch(), 1);
+
+        let again = peers.rotate_keys(&crypto, Duration::from_secs(5)).await;
+        assert!(matches!(again, Err(RotateError::TooSoon { retry_in }) if retry_in <= ROTATE_COOLDOWN));
+        assert_eq!(crypto.lock().await.key_epoch(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_not_confirming_a_rotation() {
+        let crypto = crypto();
+        let peers = Peers::new();
+        // The remote end never reads, so never confirms
+        let (connection, _remote) = connected_pair(&crypto).await;
+        let (id, _inbox) = peers.add(connection, Origin::Outbound);
+
+        let report = timeout(Duration::from_secs(5), peers.rotate_keys(&crypto.ours, Duration::from_millis(200)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(report.confirmed.is_empty());
+        assert_eq!(report.unconfirmed, [Unconfirmed { peer_id: id, error: NetworkError::Timeout.to_string() }]);
+        assert!(!report.local_only);
+        // Our keys rotated all the same, leaving the peer's a step behind
+        assert_eq!(crypto.ours.lock().await.key_epoch(), 1);
+        assert_eq!(crypto.theirs.lock().await.key_epoch(), 0);
+    }
+
+    /// An empty folder of its own, for files to go in
+    fn folder() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("silence-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> =
+            std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    /// Two app instances connected to each other: the sender, with the
+    /// receiver's id and its receive loop running, then the receiver, with
+    /// the sender's id and inbox
+    async fn sender_and_receiver() -> ((Peers, String), (Peers, String, Inbox)) {
+        let (ours, theirs) = connected_pair(&crypto()).await;
+        let (sender, receiver) = (Peers::new(), Peers::new());
+        let (receiver_id, sender_inbox) = sender.add(ours, Origin::Outbound);
+        let (sender_id, inbox) = receiver.add(theirs, Origin::Inbound);
+        receiving(&sender, &receiver_id, sender_inbox);
+        ((sender, receiver_id), (receiver, sender_id, inbox))
+    }
+
+    /// Send `offered` on its way, answering how that went and the progress
+    /// reported
+    fn sending(peers: &Peers, offered: OfferedFile) -> tokio::task::JoinHandle<(Result<(), FileError>, Vec<u64>)> {
+        let peers = peers.clone();
+        tokio::spawn(async move {
+            let mut progress = Vec::new();
+            let result = peers.send_file(offered, |sent| progress.push(sent)).await;
+            (result, progress)
+        })
+    }
+
+    async fn next_offer(peers: &Peers, id: &str, inbox: &mut Inbox) -> FileOffer {
+        match timeout(Duration::from_secs(5), peers.receive(id, inbox)).await.unwrap() {
+            Received::FileOffered { offer } => offer,
+            other => panic!("expected an offer, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepted_file_arrives_whole() {
+        let ((sender, receiver_id), (receiver, sender_id, mut inbox)) = sender_and_receiver().await;
+        let (source, dest) = (folder(), folder());
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.join("notes.txt"), &contents).unwrap();
+        std::fs::write(dest.join("notes.txt"), b"already here").unwrap();
+
+        let offered = sender.offer_file(&receiver_id, &source.join("notes.txt")).await.unwrap();
+        let transfer_id = offered.transfer_id.clone();
+        let sent = sending(&sender, offered);
+        let offer = next_offer(&receiver, &sender_id, &mut inbox).await;
+        assert_eq!(offer, FileOffer { transfer_id: transfer_id.clone(), name: "notes.txt".to_string(), size: 300_000 });
+
+        receiver.accept_file(&transfer_id, &dest).await.unwrap();
+        assert_eq!(receiver.accept_file(&transfer_id, &dest).await, Err(FileError::UnknownTransfer), "taken once");
+        let mut progress = Vec::new();
+        let path = loop {
+            match timeout(Duration::from_secs(5), receiver.receive(&sender_id, &mut inbox)).await.unwrap() {
+                Received::FileProgress { transfer_id: id, received, size } if id == transfer_id && size == 300_000 => {
+                    progress.push(received)
+                }
+                Received::FileReceived { transfer_id: id, path } if id == transfer_id => break path,
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        // Saved beside the file already there, under a free name
+        assert_eq!(path, dest.join("notes (1).txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert_eq!(listing(&dest), ["notes (1).txt", "notes.txt"]);
+
+        let (result, sent_progress) = timeout(Duration::from_secs(5), sent).await.unwrap().unwrap();
+        assert_eq!(result, Ok(()));
+        for reported in [progress, sent_progress] {
+            assert!(matches!(reported[..], [bytes] if (FILE_PROGRESS_STEP..300_000).contains(&bytes)), "{:?}", reported);
+        }
+        std::fs::remove_dir_all(source).unwrap();
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_moving_into_place_never_overwrites() {
+        let dest = folder();
+        std::fs::write(dest.join("notes.txt"), b"first").unwrap();
+        std::fs::write(dest.join("notes (1).txt"), b"second").unwrap();
+        let part = partial_path(&dest, "t1");
+        std::fs::write(&part, b"arrived").unwrap();
+
+        let path = move_into_place(&part, &dest, "notes.txt").await.unwrap();
+        assert_eq!(path, dest.join("notes (2).txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"arrived");
+        assert_eq!(std::fs::read(dest.join("notes.txt")).unwrap(), b"first");
+        assert_eq!(std::fs::read(dest.join("notes (1).txt")).unwrap(), b"second");
+        assert_eq!(listing(&dest), ["notes (1).txt", "notes (2).txt", "notes.txt"]);
+
+        // A partial copy that's gone leaves no empty file behind
+        assert!(move_into_place(&part, &dest, "notes.txt").await.is_err());
+        assert_eq!(listing(&dest), ["notes (1).txt", "notes (2).txt", "notes.txt"]);
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_file_is_not_sent() {
+        let ((sender, receiver_id), (receiver, sender_id, mut inbox)) = sender_and_receiver().await;
+        let (source, dest) = (folder(), folder());
+        std::fs::write(source.join("photo.jpg"), [1; 5000]).unwrap();
+
+        let offered = sender.offer_file(&receiver_id, &source.join("photo.jpg")).await.unwrap();
+        let sent = sending(&sender, offered);
+        let offer = next_offer(&receiver, &sender_id, &mut inbox).await;
+        receiver.reject_file(&offer.transfer_id).await.unwrap();
+
+        let (result, progress) = timeout(Duration::from_secs(5), sent).await.unwrap().unwrap();
+        assert_eq!(result, Err(FileError::Rejected));
+        assert!(progress.is_empty());
+        // Nothing is left to answer
+        assert_eq!(receiver.reject_file(&offer.transfer_id).await, Err(FileError::UnknownTransfer));
+        assert_eq!(receiver.accept_file(&offer.transfer_id, &dest).await, Err(FileError::UnknownTransfer));
+        assert!(listing(&dest).is_empty());
+        std::fs::remove_dir_all(source).unwrap();
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_files_over_the_limit_are_refused() {
+        let ((sender, receiver_id), (receiver, sender_id, mut inbox)) = sender_and_receiver().await;
+        let source = folder();
+        let path = source.join("big.bin");
+        std::fs::write(&path, [0; 101]).unwrap();
+
+        // Turned down at the other end without asking
+        receiver.set_max_file_size(100);
+        let offered = sender.offer_file(&receiver_id, &path).await.unwrap();
+        let transfer_id = offered.transfer_id.clone();
+        let sent = sending(&sender, offered);
+        let received = timeout(Duration::from_secs(5), receiver.receive(&sender_id, &mut inbox)).await.unwrap();
+        let expected = FileError::TooLarge { bytes: 101, limit: 100 }.to_string();
+        assert!(matches!(&received, Received::FileFailed { transfer_id: id, error } if *id == transfer_id && *error == expected));
+        let (result, _) = timeout(Duration::from_secs(5), sent).await.unwrap().unwrap();
+        assert_eq!(result, Err(FileError::Rejected));
+
+        // And not offered at all over our own
+        sender.set_max_file_size(100);
+        let refused = sender.offer_file(&receiver_id, &path).await.unwrap_err();
+        assert_eq!(refused, FileError::TooLarge { bytes: 101, limit: 100 });
+        assert_eq!(refused.to_string(), "File too large: 101 bytes, the limit is 100");
+        std::fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_transfer_deletes_the_partial_file() {
+        let peers = Peers::new();
+        let (connection, remote) = connected_pair(&crypto()).await;
+        let (id, mut inbox) = peers.add(connection, Origin::Outbound);
+        let (mut remote_reader, remote_writer) = remote.into_split();
+        let dest = folder();
+
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        let offer = FileOffer { transfer_id: transfer_id.clone(), name: "big.bin".to_string(), size: 10_000 };
+        remote_writer.send_file_message(&FileMessage::Offer(offer.clone())).await.unwrap();
+        assert_eq!(next_offer(&peers, &id, &mut inbox).await, offer);
+        peers.accept_file(&transfer_id, &dest).await.unwrap();
+        let accepted = timeout(Duration::from_secs(5), remote_reader.next_message()).await.unwrap().unwrap();
+        assert_eq!(accepted, Some(PeerMessage::File(FileMessage::Accept { transfer_id: transfer_id.clone() })));
+
+        // Some of it arrives, then the connection drops
+        let chunk = FileChunk { transfer_id: transfer_id.clone(), offset: 0, data: vec![9; 1000] };
+        remote_writer.send_file_message(&FileMessage::Chunk(chunk)).await.unwrap();
+        let receiving = receiving(&peers, &id, inbox);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(listing(&dest), [format!(".silence-{}.part", transfer_id)]);
+        assert_eq!(std::fs::metadata(partial_path(&dest, &transfer_id)).unwrap().len(), 1000);
+        remote_writer.close().await.unwrap();
+
+        let ended = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert!(matches!(ended, Received::Closed { .. }), "{:?}", ended);
+        assert!(listing(&dest).is_empty());
+        assert_eq!(peers.take_abandoned_files(&id), [transfer_id]);
+        assert!(peers.take_abandoned_files(&id).is_empty());
+        assert_eq!(peers.accept_file(&offer.transfer_id, &dest).await, Err(FileError::UnknownTransfer));
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+// Where the desktop app's connection stands, for the GUI
+//
+// The app sends a `connection-status` event every time this changes and
+// reports the latest one in `get_security_status` for a GUI that polls.
+// With several conversations open it follows the most recent one: changes
+// about a peer other than the one it's about don't apply, the
+// `peer-disconnected` events cover those.
+
+use crate::ConnectionMode;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    /// Waiting for a peer to connect to us
+    Listening,
+    Connecting,
+    Connected,
+    /// Lost the connection and trying to get it back
+    Reconnecting,
+    /// Connecting or receiving failed, with the error in `error`
+    Failed,
+}
+
+/// The `connection-status` payload
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Id of the conversation, once connected
+    pub peer_id: Option<String>,
+    /// Address connected or being connected to
+    pub peer: Option<String>,
+    /// The mode asked for when connecting; `None` for accepted connections
+    pub mode: Option<ConnectionMode>,
+    /// Whether the connection goes through a relay
+    pub relay: bool,
+    pub error: Option<String>,
+}
+
+/// Something that moves the connection status along
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// `connect_to_peer` started
+    Connecting { peer: SocketAddr, mode: ConnectionMode },
+    /// `start_listening` started
+    Listening,
+    /// `stop_listening` stopped it
+    StoppedListening,
+    /// Connecting or listening got a connection
+    Connected { peer_id: String, peer: SocketAddr, relay: bool },
+    /// Connecting or listening gave up
+    ConnectFailed { error: String },
+    /// The connection dropped and is being re-established
+    Reconnecting { peer_id: String },
+    /// The connection ended, whichever side closed it
+    Closed { peer_id: String },
+    /// The connection was a second one to the same peer and was dropped for
+    /// the other, `by`, which is at `peer`
+    Replaced { peer_id: String, by: String, peer: SocketAddr },
+    /// Receiving failed and the connection was dropped
+    ReceiveFailed { peer_id: String, error: String },
+}
+
+impl ConnectionStatus {
+    /// The status after `change`, or `None` when it can't follow this one:
+    /// - a new attempt can start from anything but another attempt
+    /// - listening starts when there is no connection, and only stops
+    ///   while waiting for one
+    /// - only an attempt, listening or reconnecting ends up connected or
+    ///   failing to connect
+    /// - only the connection this status is about can drop, end, fail or
+    ///   be replaced
+    pub fn apply(&self, change: Change) -> Option<ConnectionStatus> {
+        use ConnectionState::*;
+        match (self.state, change) {
+            (Connecting, Change::Connecting { .. }) => None,
+            (_, Change::Connecting { peer, mode }) => Some(ConnectionStatus {
+                state: Connecting,
+                peer: Some(peer.to_string()),
+                mode: Some(mode),
+                ..Default::default()
+            }),
+            (Disconnected | Failed, Change::Listening) => Some(ConnectionStatus { state: Listening, ..Default::default() }),
+            (Listening, Change::StoppedListening) => Some(ConnectionStatus::default()),
+            (Connecting | Listening | Reconnecting, Change::Connected { peer_id, peer, relay }) => {
+                Some(ConnectionStatus {
+                    state: Connected,
+                    peer_id: Some(peer_id),
+                    peer: Some(peer.to_string()),
+                    mode: self.mode,
+                    relay,
+                    error: None,
+                })
+            }
+            (Connecting | Listening | Reconnecting, Change::ConnectFailed { error }) => {
+                Some(ConnectionStatus { state: Failed, error: Some(error), ..self.clone() })
+            }
+            (Connected | Failed, Change::Reconnecting { peer_id }) if self.is_about(&peer_id) => {
+                Some(ConnectionStatus { state: Reconnecting, error: None, ..self.clone() })
+            }
+            (Connected | Reconnecting, Change::Closed { peer_id }) if self.is_about(&peer_id) => {
+                Some(ConnectionStatus { state: Disconnected, error: None, ..self.clone() })
+            }
+            (Connected | Reconnecting, Change::Replaced { peer_id, by, peer }) if self.is_about(&peer_id) => {
+                Some(ConnectionStatus { state: Connected, peer_id: Some(by), peer: Some(peer.to_string()), ..self.clone() })
+            }
+            (Connected | Reconnecting, Change::ReceiveFailed { peer_id, error }) if self.is_about(&peer_id) => {
+                Some(ConnectionStatus { state: Failed, error: Some(error), ..self.clone() })
+            }
+            _ => None,
+        }
+    }
+
+    fn is_about(&self, peer_id: &str) -> bool {
+        self.peer_id.as_deref() == Some(peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "192.0.2.7:8080";
+
+    fn connected(peer_id: &str, relay: bool) -> Change {
+        Change::Connected { peer_id: peer_id.to_string(), peer: PEER.parse().unwrap(), relay }
+    }
+
+    fn connecting() -> Change {
+        Change::Connecting { peer: PEER.parse().unwrap(), mode: ConnectionMode::Auto }
+    }
+
+    /// Apply `changes` in turn from disconnected, failing on any refused
+    fn run(changes: Vec<Change>) -> ConnectionStatus {
+        changes.into_iter().fold(ConnectionStatus::default(), |status, change| {
+            let description = format!("{:?} after {:?}", change, status.state);
+            status.apply(change).unwrap_or_else(|| panic!("refused {}", description))
+        })
+    }
+
+    #[test]
+    fn test_connect_then_close() {
+        let status = run(vec![connecting()]);
+        assert_eq!(status.state, ConnectionState::Connecting);
+        assert_eq!(status.peer.as_deref(), Some(PEER));
+
+        let status = status.apply(connected("a", true)).unwrap();
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.peer_id.as_deref(), Some("a"));
+        assert_eq!(status.mode, Some(ConnectionMode::Auto));
+        assert!(status.relay);
+
+        let status = status.apply(Change::Closed { peer_id: "a".to_string() }).unwrap();
+        assert_eq!(status.state, ConnectionState::Disconnected);
+        assert_eq!(status.peer.as_deref(), Some(PEER));
+    }
+
+    #[test]
+    fn test_failures_carry_the_error() {
+        let status = run(vec![connecting(), Change::ConnectFailed { error: "Operation timeout".to_string() }]);
+        assert_eq!(status.state, ConnectionState::Failed);
+        assert_eq!(status.error.as_deref(), Some("Operation timeout"));
+
+        // A new attempt starts clean
+        let status = status.apply(connecting()).unwrap();
+        assert_eq!(status.error, None);
+
+        let status = run(vec![
+            Change::Listening,
+            connected("a", false),
+            Change::ReceiveFailed { peer_id: "a".to_string(), error: "Invalid message format".to_string() },
+        ]);
+        assert_eq!(status.state, ConnectionState::Failed);
+        assert_eq!(status.mode, None);
+        assert_eq!(status.error.as_deref(), Some("Invalid message format"));
+    }
+
+    #[test]
+    fn test_reconnecting_back_to_connected() {
+        let status = run(vec![
+            connecting(),
+            connected("a", false),
+            Change::Reconnecting { peer_id: "a".to_string() },
+        ]);
+        assert_eq!(status.state, ConnectionState::Reconnecting);
+        assert_eq!(status.apply(connected("a", true)).unwrap().state, ConnectionState::Connected);
+        assert_eq!(status.apply(Change::Closed { peer_id: "a".to_string() }).unwrap().state, ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn test_replaced_connection_carries_on() {
+        let status = run(vec![connecting(), connected("a", false)]);
+        let other: SocketAddr = "192.0.2.7:51000".parse().unwrap();
+        let replaced = Change::Replaced { peer_id: "a".to_string(), by: "b".to_string(), peer: other };
+        let status = status.apply(replaced.clone()).unwrap();
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.peer_id.as_deref(), Some("b"));
+        assert_eq!(status.peer.as_deref(), Some("192.0.2.7:51000"));
+        // Only the connection it's about
+        assert_eq!(status.apply(replaced), None);
+    }
+
+    #[test]
+    fn test_refused_transitions() {
+        let disconnected = ConnectionStatus::default();
+        assert_eq!(disconnected.apply(connected("a", false)), None);
+        assert_eq!(disconnected.apply(Change::Closed { peer_id: "a".to_string() }), None);
+        assert_eq!(disconnected.apply(Change::ConnectFailed { error: "no".to_string() }), None);
+
+        let attempt = run(vec![connecting()]);
+        assert_eq!(attempt.apply(connecting()), None);
+        assert_eq!(attempt.apply(Change::Listening), None);
+
+        // Another conversation ending leaves this one be
+        let status = run(vec![connecting(), connected("b", false)]);
+        assert_eq!(status.apply(Change::Closed { peer_id: "a".to_string() }), None);
+        assert_eq!(status.apply(Change::ReceiveFailed { peer_id: "a".to_string(), error: "no".to_string() }), None);
+        assert_eq!(status.apply(Change::Listening), None);
+        assert_eq!(status.apply(Change::StoppedListening), None);
+    }
+
+    #[test]
+    fn test_stop_listening() {
+        let status = run(vec![Change::Listening, Change::StoppedListening]);
+        assert_eq!(status, ConnectionStatus::default());
+    }
+}
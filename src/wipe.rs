@@ -0,0 +1,178 @@
+// The panic wipe: everything the app holds about its conversations, gone
+// at once
+//
+// Listeners stop and connections drop first, so nothing new gets in while
+// the rest goes. Then the port mapping is taken off the router, the keys are
+// replaced, which zeroes the old ones as they drop, the chat history and
+// the last peer dialed are forgotten, its file deleted, and last the log
+// files emptied, so they keep nothing of the wipe either. A step that fails
+// doesn't stop the ones after it; what failed is reported instead.
+
+use crate::config::Config;
+use crate::crypto::SilenceCrypto;
+use crate::events::Wiped;
+use crate::history::MessageHistory;
+use crate::portmap::{MappedPort, MAPPING_TIMEOUT};
+use crate::session::{LastPeer, Peers};
+use std::path::Path;
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+
+/// What `wipe` destroys, wherever the app keeps it
+pub struct Session<'a> {
+    pub crypto: &'a Mutex<SilenceCrypto>,
+    /// The settings the new keys are made with
+    pub config: &'a Config,
+    pub peers: &'a Peers,
+    pub history: &'a Mutex<MessageHistory>,
+    pub last_peer: &'a std::sync::Mutex<Option<LastPeer>>,
+    /// Where the last peer is saved, if anywhere
+    pub last_peer_path: Option<&'a Path>,
+    /// The listeners running, stopped and cleared
+    pub listeners: &'a std::sync::Mutex<Vec<AbortHandle>>,
+    pub port_mapping: &'a Mutex<Option<MappedPort>>,
+    /// Where the log files are, `logging::log_dir`, if anywhere
+    pub log_dir: Option<&'a Path>,
+}
+
+/// Destroy `session`, leaving new keys and nothing connected, listening or
+/// kept. Never fails as a whole: the answer lists the steps that did.
+pub async fn wipe(session: Session<'_>) -> Wiped {
+    let mut errors = Vec::new();
+
+    // Nothing new gets in meanwhile
+    for listener in session.listeners.lock().unwrap().drain(..) {
+        listener.abort();
+    }
+    let dropped = session.peers.abandon_all();
+    if let Some(mapping) = session.port_mapping.lock().await.take() {
+        // The router drops it when the lease runs out regardless
+        if let Err(e) = mapping.stop(MAPPING_TIMEOUT).await {
+            tracing::warn!(error = %e, "Couldn't remove the port mapping");
+        }
+    }
+
+    // The old keys are zeroed as they drop
+    match SilenceCrypto::from_config(session.config) {
+        Ok(fresh) => *session.crypto.lock().await = fresh,
+        Err(e) => errors.push(format!("Couldn't replace the keys: {}", e)),
+    }
+
+    session.history.lock().await.clear();
+
+    *session.last_peer.lock().unwrap() = None;
+    if let Some(path) = session.last_peer_path {
+        delete(path, &mut errors);
+    }
+
+    if let Some(dir) = session.log_dir {
+        wipe_logs(dir, &mut errors);
+    }
+
+    Wiped::new(dropped, errors)
+}
+
+/// Empty the log files in `dir`: the newest, which may still be being
+/// written, is cut to nothing, the rest deleted
+fn wipe_logs(dir: &Path, errors: &mut Vec<String>) {
+    let mut files: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| Some(entry.ok()?.path())).filter(|path| path.is_file()).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            errors.push(format!("Couldn't list {}: {}", dir.display(), e));
+            return;
+        }
+    };
+    // Named by date, so this is oldest first
+    files.sort();
+    let newest = files.pop();
+    for file in &files {
+        delete(file, errors);
+    }
+    if let Some(file) = newest {
+        if let Err(e) = std::fs::OpenOptions::new().write(true).truncate(true).open(&file) {
+            errors.push(format!("Couldn't empty {}: {}", file.display(), e));
+        }
+    }
+}
+
+/// Delete `path`, which may well not be there
+fn delete(path: &Path, errors: &mut Vec<String>) {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => errors.push(format!("Couldn't delete {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::ChatMessage;
+    use crate::network::P2PConnection;
+    use crate::session::Origin;
+    use crate::ConnectionMode;
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_wipe_leaves_nothing_behind() {
+        let config = Config::default();
+        let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&config).unwrap()));
+        let fingerprint = crypto.lock().await.fingerprint();
+
+        let peers = Peers::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _remote = listener.accept().await.unwrap();
+        let connection = P2PConnection::new(stream, addr, Arc::clone(&crypto), 4096, false).await;
+        let (peer_id, _inbox) = peers.add(connection, Origin::Outbound);
+
+        let history = Mutex::new(MessageHistory::new(100));
+        history.lock().await.push(ChatMessage::sending(peer_id, "meet at noon".to_string()));
+
+        let dir = std::env::temp_dir().join(format!("silence-wipe-{}", uuid::Uuid::new_v4()));
+        let log_dir = dir.join("logs");
+        std::fs::create_dir_all(&log_dir).unwrap();
+        let last_peer_path = dir.join(crate::session::LAST_PEER_FILE);
+        let last_peer = LastPeer::new(addr, ConnectionMode::Auto, false);
+        last_peer.save(&last_peer_path).unwrap();
+        let last_peer = std::sync::Mutex::new(Some(last_peer));
+        let (old_log, new_log) = (log_dir.join("silence.2026-10-15.log"), log_dir.join("silence.2026-10-16.log"));
+        std::fs::write(&old_log, "connected to 192.0.2.7\n").unwrap();
+        std::fs::write(&new_log, "connected to 192.0.2.8\n").unwrap();
+
+        let listening = tokio::spawn(std::future::pending::<()>());
+        let listeners = std::sync::Mutex::new(vec![listening.abort_handle()]);
+        let port_mapping = Mutex::new(None);
+
+        let wiped = wipe(Session {
+            crypto: &crypto,
+            config: &config,
+            peers: &peers,
+            history: &history,
+            last_peer: &last_peer,
+            last_peer_path: Some(&last_peer_path),
+            listeners: &listeners,
+            port_mapping: &port_mapping,
+            log_dir: Some(&log_dir),
+        })
+        .await;
+
+        assert_eq!(wiped.dropped_connections, 1);
+        assert!(wiped.errors.is_empty(), "{:?}", wiped.errors);
+        assert!(!peers.is_connected());
+        assert!(peers.recent().is_empty());
+        assert!(history.lock().await.is_empty());
+        assert!(last_peer.lock().unwrap().is_none());
+        assert!(!last_peer_path.exists());
+        assert!(!old_log.exists());
+        assert_eq!(std::fs::read_to_string(&new_log).unwrap(), "");
+        assert_ne!(crypto.lock().await.fingerprint(), fingerprint);
+        assert!(listeners.lock().unwrap().is_empty());
+        assert!(listening.await.unwrap_err().is_cancelled());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
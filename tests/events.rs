@@ -0,0 +1,205 @@
+// The events the app sends its frontend, as the JavaScript side sees them
+
+#![cfg(feature = "app")]
+
+use serde_json::json;
+use silence::events::{
+    self, DisconnectReason, FileComplete, FileFailed, FileOffered, FileProgress, FingerprintChanged, KeyRotated,
+    KeyRotationFailed, MessageReceived, MessageStatusChanged, PeerConnected, PeerDisconnected, PortMappingFailed,
+    ReceiveError, Wiped,
+};
+use silence::history::MessageStatus;
+use silence::session::Origin;
+use silence::status::{Change, ConnectionStatus};
+use silence::{ConnectionMode, FileOffer};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[test]
+fn test_message_received_payload() {
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let message = MessageReceived::new("p1".to_string(), "192.0.2.7:7642".parse().unwrap(), "hi <b>there</b>".to_string());
+    let payload = serde_json::to_value(&message).unwrap();
+
+    assert_eq!(payload["peer_id"], "p1");
+    assert_eq!(payload["from"], "192.0.2.7:7642");
+    assert_eq!(payload["body"], "hi <b>there</b>");
+    assert!(uuid::Uuid::parse_str(payload["id"].as_str().unwrap()).is_ok(), "{}", payload);
+    let timestamp = payload["timestamp"].as_u64().unwrap();
+    assert!(timestamp >= before && timestamp - before < 60_000, "{}", payload);
+    assert_eq!(payload.as_object().unwrap().len(), 5, "{}", payload);
+
+    let other = MessageReceived::new("p1".to_string(), "192.0.2.7:7642".parse().unwrap(), "again".to_string());
+    assert_ne!(message.id, other.id);
+}
+
+#[test]
+fn test_connection_event_payloads() {
+    let connected = PeerConnected { peer_id: "p1".to_string(), peer: "192.0.2.7:8080".to_string(), relay: true, origin: Origin::Inbound };
+    assert_eq!(
+        serde_json::to_value(connected).unwrap(),
+        json!({ "peer_id": "p1", "peer": "192.0.2.7:8080", "relay": true, "origin": "inbound" })
+    );
+
+    let disconnected = PeerDisconnected { peer_id: "p1".to_string(), peer: "192.0.2.7:8080".to_string(), reason: DisconnectReason::Local };
+    assert_eq!(
+        serde_json::to_value(disconnected).unwrap(),
+        json!({ "peer_id": "p1", "peer": "192.0.2.7:8080", "reason": "local" })
+    );
+
+    let error = ReceiveError { peer_id: "p1".to_string(), peer: "192.0.2.7:8080".to_string(), error: "Operation timeout".to_string() };
+    assert_eq!(
+        serde_json::to_value(error).unwrap(),
+        json!({ "peer_id": "p1", "peer": "192.0.2.7:8080", "error": "Operation timeout" })
+    );
+
+    let changed = FingerprintChanged {
+        peer_id: "p1".to_string(),
+        peer: "192.0.2.7:8080".to_string(),
+        previous_fingerprint: "00".repeat(32),
+        fingerprint: "ff".repeat(32),
+        verified: true,
+    };
+    assert_eq!(
+        serde_json::to_value(changed).unwrap(),
+        json!({
+            "peer_id": "p1",
+            "peer": "192.0.2.7:8080",
+            "previous_fingerprint": "00".repeat(32),
+            "fingerprint": "ff".repeat(32),
+            "verified": true,
+        })
+    );
+}
+
+#[test]
+fn test_connection_status_payload() {
+    assert_eq!(
+        serde_json::to_value(ConnectionStatus::default()).unwrap(),
+        json!({ "state": "disconnected", "peer_id": null, "peer": null, "mode": null, "relay": false, "error": null })
+    );
+
+    let connecting = Change::Connecting { peer: "192.0.2.7:8080".parse().unwrap(), mode: ConnectionMode::RelayOnly };
+    let status = ConnectionStatus::default().apply(connecting).unwrap();
+    let status = status.apply(Change::ConnectFailed { error: "Operation timeout".to_string() }).unwrap();
+    assert_eq!(
+        serde_json::to_value(status).unwrap(),
+        json!({
+            "state": "failed",
+            "peer_id": null,
+            "peer": "192.0.2.7:8080",
+            "mode": "relay_only",
+            "relay": false,
+            "error": "Operation timeout",
+        })
+    );
+}
+
+#[test]
+fn test_key_rotation_payloads() {
+    let rotated = KeyRotated::new(3, Duration::from_secs(300));
+    assert_eq!(rotated.next_rotation_at - rotated.timestamp, 300_000);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    assert!(rotated.timestamp.abs_diff(now) < 60_000);
+    assert_eq!(
+        serde_json::to_value(&rotated).unwrap(),
+        json!({ "epoch": 3, "timestamp": rotated.timestamp, "next_rotation_at": rotated.timestamp + 300_000 })
+    );
+
+    let failed = KeyRotationFailed::new("Key derivation failed".to_string());
+    assert_eq!(
+        serde_json::to_value(&failed).unwrap(),
+        json!({ "error": "Key derivation failed", "timestamp": failed.timestamp })
+    );
+}
+
+#[test]
+fn test_wiped_payload() {
+    let wiped = Wiped::new(2, vec!["Couldn't delete last_peer.toml: Permission denied".to_string()]);
+    assert_eq!(
+        serde_json::to_value(&wiped).unwrap(),
+        json!({
+            "dropped_connections": 2,
+            "errors": ["Couldn't delete last_peer.toml: Permission denied"],
+            "timestamp": wiped.timestamp,
+        })
+    );
+}
+
+#[test]
+fn test_message_status_payload() {
+    let delivered = MessageStatusChanged::new("m1".to_string(), "p1".to_string(), MessageStatus::Delivered, None);
+    assert_eq!(
+        serde_json::to_value(&delivered).unwrap(),
+        json!({ "id": "m1", "peer_id": "p1", "status": "delivered", "error": null, "timestamp": delivered.timestamp })
+    );
+
+    let failed = MessageStatusChanged::new(
+        "m2".to_string(),
+        "p1".to_string(),
+        MessageStatus::Failed,
+        Some("Not confirmed in time".to_string()),
+    );
+    assert_eq!(serde_json::to_value(&failed).unwrap()["status"], "failed");
+    assert_eq!(serde_json::to_value(&failed).unwrap()["error"], "Not confirmed in time");
+}
+
+#[test]
+fn test_port_mapping_failed_payload() {
+    let failed = PortMappingFailed::new("Port mapping unavailable (UPnP: No router answered)".to_string());
+    assert_eq!(
+        serde_json::to_value(&failed).unwrap(),
+        json!({ "error": "Port mapping unavailable (UPnP: No router answered)", "timestamp": failed.timestamp })
+    );
+}
+
+#[test]
+fn test_file_transfer_payloads() {
+    let offer = FileOffer { transfer_id: "t1".to_string(), name: "notes.txt".to_string(), size: 5000 };
+    let offered = FileOffered::new("p1".to_string(), offer);
+    assert_eq!(
+        serde_json::to_value(&offered).unwrap(),
+        json!({ "transfer_id": "t1", "peer_id": "p1", "name": "notes.txt", "size": 5000, "timestamp": offered.timestamp })
+    );
+
+    let progress = FileProgress { transfer_id: "t1".to_string(), peer_id: "p1".to_string(), sent: 4000, total: 5000 };
+    assert_eq!(
+        serde_json::to_value(progress).unwrap(),
+        json!({ "transfer_id": "t1", "peer_id": "p1", "sent": 4000, "total": 5000 })
+    );
+
+    let received = FileComplete::new("t1".to_string(), "p1".to_string(), Some(std::path::Path::new("/tmp/notes.txt")));
+    assert_eq!(serde_json::to_value(&received).unwrap()["path"], "/tmp/notes.txt");
+    let sent = FileComplete::new("t1".to_string(), "p1".to_string(), None);
+    assert_eq!(
+        serde_json::to_value(&sent).unwrap(),
+        json!({ "transfer_id": "t1", "peer_id": "p1", "path": null, "timestamp": sent.timestamp })
+    );
+
+    let failed = FileFailed::new("t1".to_string(), "p1".to_string(), "The peer turned the file down".to_string());
+    assert_eq!(
+        serde_json::to_value(&failed).unwrap(),
+        json!({ "transfer_id": "t1", "peer_id": "p1", "error": "The peer turned the file down", "timestamp": failed.timestamp })
+    );
+}
+
+#[test]
+fn test_event_names() {
+    // The frontend listens for these by name
+    assert_eq!(events::MESSAGE_RECEIVED, "message-received");
+    assert_eq!(events::MESSAGE_STATUS, "message-status");
+    assert_eq!(events::PEER_CONNECTED, "peer-connected");
+    assert_eq!(events::PEER_DISCONNECTED, "peer-disconnected");
+    assert_eq!(events::RECEIVE_ERROR, "receive-error");
+    assert_eq!(events::CONNECTION_STATUS, "connection-status");
+    assert_eq!(events::FINGERPRINT_CHANGED, "fingerprint-changed");
+    assert_eq!(events::KEY_ROTATED, "key-rotated");
+    assert_eq!(events::KEY_ROTATION_FAILED, "key-rotation-failed");
+    assert_eq!(events::WIPED, "wiped");
+    assert_eq!(events::PEERS_CHANGED, "peers-changed");
+    assert_eq!(events::PORT_MAPPED, "port-mapped");
+    assert_eq!(events::PORT_MAPPING_FAILED, "port-mapping-failed");
+    assert_eq!(events::FILE_OFFER, "file-offer");
+    assert_eq!(events::FILE_PROGRESS, "file-progress");
+    assert_eq!(events::FILE_COMPLETE, "file-complete");
+    assert_eq!(events::FILE_FAILED, "file-failed");
+}
@@ -0,0 +1,289 @@
+// End-to-end tests of the client's relay path against an in-process relay
+
+#![cfg(feature = "network")]
+
+use silence::prelude::*;
+use silence::{ConfigHandle, NetworkError, RelayEntry, RelayRejectReason};
+use silence_relay::{Args, BoundInfo, CancellationToken};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Relay on an ephemeral loopback port, stopped when `shutdown` is cancelled
+async fn start_relay(extra_flags: &[&str], shutdown: &CancellationToken) -> BoundInfo {
+    let mut flags = vec!["--bind-address", "127.0.0.1", "--port", "0"];
+    flags.extend_from_slice(extra_flags);
+    silence_relay::run_relay(Args::from_flags(flags).unwrap(), shutdown.clone()).await.unwrap()
+}
+
+fn manager(crypto: &Arc<Mutex<SilenceCrypto>>, relays: &[SocketAddr]) -> ConnectionManager {
+    let relays = relays.iter().map(SocketAddr::to_string).collect();
+    ConnectionManager::with_relays(Arc::clone(crypto), 4096, relays)
+}
+
+/// Nothing listens here, so direct connections fail fast
+fn unreachable_peer() -> SocketAddr {
+    "127.0.0.1:1".parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_relay_only_mode_delivers_messages() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+
+    // Both ends share the key material, standing in for a completed key exchange
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let mut alice = manager(&crypto, &[relay.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert!(!alice.relay_hello().unwrap().peer_present);
+    let mut bob = manager(&crypto, &[relay.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert!(bob.relay_hello().unwrap().peer_present);
+
+    alice.send_text("hello through the relay").await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), bob.receive_message()).await.unwrap();
+    assert_eq!(received.unwrap().as_deref(), Some("hello through the relay"));
+
+    bob.send_text("and back").await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), alice.receive_message()).await.unwrap();
+    assert_eq!(received.unwrap().as_deref(), Some("and back"));
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_auto_mode_reports_the_path_taken() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let manager = manager(&crypto, &[relay.addr()]);
+
+    // A peer that answers keeps it direct
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let direct = manager.connect_with_mode(listener.local_addr().unwrap(), ConnectionMode::Auto).await.unwrap();
+    assert_eq!(direct.mode_used(), ConnectionMode::DirectOnly);
+    assert!(direct.relay_hello().is_none());
+
+    // One that doesn't falls back on the relay
+    let relayed = manager.connect_with_mode(unreachable_peer(), ConnectionMode::Auto).await.unwrap();
+    assert_eq!(relayed.mode_used(), ConnectionMode::RelayOnly);
+    assert_eq!(relayed.peer_addr(), relay.addr());
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_manager_built_from_config() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+
+    let config = Config::builder()
+        .add_relay(relay.addr().to_string())
+        .connection_mode_default(ConnectionMode::RelayOnly)
+        .tcp_nodelay(true)
+        .build()
+        .unwrap();
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&config).unwrap()));
+    let connection = ConnectionManager::from_config(crypto, &config)
+        .unwrap()
+        .connect_with_mode(unreachable_peer(), config.connection_mode)
+        .await
+        .unwrap();
+    assert_eq!(connection.peer_addr(), relay.addr());
+    assert!(connection.nodelay().unwrap());
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_manager_follows_relay_list_updates() {
+    let shutdown = CancellationToken::new();
+    let full = start_relay(&["--max-clients", "0"], &shutdown).await;
+    let open = start_relay(&[], &shutdown).await;
+
+    let handle = ConfigHandle::new(Config::builder().add_relay(full.addr().to_string()).build().unwrap());
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&handle.current()).unwrap()));
+    let manager = ConnectionManager::with_handle(crypto, handle.clone()).unwrap();
+    let result = manager.connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly).await;
+    assert!(result.is_err());
+
+    let change = handle.update(|config| config.relay_servers = vec![open.addr().to_string().into()]).unwrap();
+    assert_eq!(change.applied, vec!["relay_servers"]);
+    let connection = manager.connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly).await.unwrap();
+    assert_eq!(connection.peer_addr(), open.addr());
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_relays_tried_by_priority_with_their_own_options() {
+    let shutdown = CancellationToken::new();
+    let open = start_relay(&[], &shutdown).await;
+    let private = start_relay(&["--auth-token", "s3cret"], &shutdown).await;
+
+    // Listed last, but the highest priority and holding the right token
+    let config = Config::builder()
+        .add_relay(open.addr().to_string())
+        .add_relay(RelayEntry::new("127.0.0.1:1").priority(1).connect_timeout(Duration::from_secs(1)))
+        .add_relay(RelayEntry::new(private.addr().to_string()).priority(2).token("s3cret"))
+        .build()
+        .unwrap();
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::from_config(&config).unwrap()));
+    let manager = ConnectionManager::from_config(Arc::clone(&crypto), &config).unwrap();
+    let connection = manager.connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly).await.unwrap();
+    assert_eq!(connection.peer_addr(), private.addr());
+
+    // Without the token the private relay refuses, and the rest are tried in order
+    let config = Config::builder()
+        .add_relay(RelayEntry::new(private.addr().to_string()).priority(2))
+        .add_relay(RelayEntry::new(open.addr().to_string()).tls(true).priority(1))
+        .add_relay(open.addr().to_string())
+        .build()
+        .unwrap();
+    let connection = ConnectionManager::from_config(crypto, &config)
+        .unwrap()
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert_eq!(connection.peer_addr(), open.addr());
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_relay_only_mode_skips_failing_relays() {
+    let shutdown = CancellationToken::new();
+    let full = start_relay(&["--max-clients", "0"], &shutdown).await;
+    let open = start_relay(&[], &shutdown).await;
+
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let connection = manager(&crypto, &[full.addr(), open.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert_eq!(connection.peer_addr(), open.addr());
+
+    let result = manager(&crypto, &[full.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await;
+    assert!(matches!(result, Err(NetworkError::Connection(_))));
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_frame_limits_are_negotiated_per_client() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&["--max-message-size", "2048"], &shutdown).await;
+    let relays = vec![relay.addr().to_string()];
+
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let mut alice = ConnectionManager::with_relays(Arc::clone(&crypto), 1024, relays.clone())
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert_eq!(alice.max_message_size(), 1024);
+    let mut bob = manager(&crypto, &[relay.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    assert_eq!(bob.max_message_size(), 2048, "clamped to the relay's limit");
+
+    // Alice can't send past her limit, and the relay won't deliver past it
+    let long = "x".repeat(1500);
+    assert!(matches!(alice.send_text(&long).await, Err(NetworkError::MessageTooLarge)));
+    bob.send_text(&long).await.unwrap();
+    let notice = tokio::time::timeout(Duration::from_secs(5), bob.receive_message()).await.unwrap();
+    assert!(matches!(notice, Err(NetworkError::MessageTooLarge)));
+
+    bob.send_text("short enough").await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), alice.receive_message()).await.unwrap();
+    assert_eq!(received.unwrap().as_deref(), Some("short enough"));
+
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn test_relay_shutdown_reaches_clients_as_goodbye() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let mut alice = manager(&crypto, &[relay.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+    let mut bob = manager(&crypto, &[relay.addr()])
+        .connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly)
+        .await
+        .unwrap();
+
+    shutdown.cancel();
+    for connection in [&mut alice, &mut bob] {
+        let error = tokio::time::timeout(Duration::from_secs(5), connection.receive_message())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            matches!(error, NetworkError::RelayClosed { reason: RelayRejectReason::ShuttingDown }),
+            "{}", error
+        );
+        assert!(error.fail_over_immediately());
+    }
+    relay.task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_probe_relays_reaches_a_running_relay() {
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let probes = manager(&crypto, &[relay.addr()]).probe_relays(Duration::from_secs(5)).await;
+    assert_eq!(probes.len(), 1);
+    assert!(probes[0].rtt.as_ref().unwrap() < &Duration::from_secs(1));
+
+    shutdown.cancel();
+    relay.task.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "app")]
+#[tokio::test]
+async fn test_relay_status_mixes_reachable_and_dead_relays() {
+    use silence::relays::RelayStatusCache;
+
+    let shutdown = CancellationToken::new();
+    let relay = start_relay(&[], &shutdown).await;
+    let crypto = Arc::new(Mutex::new(SilenceCrypto::new(60).unwrap()));
+    let dead = unreachable_peer();
+    let manager = manager(&crypto, &[dead, relay.addr()]);
+
+    // A session through the relay carries on around the probes
+    let mut alice = manager.connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly).await.unwrap();
+    let mut bob = manager.connect_with_mode(unreachable_peer(), ConnectionMode::RelayOnly).await.unwrap();
+
+    let statuses = RelayStatusCache::new(Duration::from_secs(10), Duration::from_secs(2)).get(&manager).await;
+    assert_eq!(statuses.len(), 2);
+    assert_eq!(statuses[0].addr, relay.addr().to_string());
+    assert!(statuses[0].reachable && statuses[0].error.is_none());
+    assert!(statuses[0].rtt_ms.unwrap() < 1000);
+    assert_eq!(statuses[1].addr, dead.to_string());
+    assert!(!statuses[1].reachable && statuses[1].rtt_ms.is_none());
+    assert!(statuses[1].error.is_some());
+
+    let json = serde_json::to_value(&statuses[1]).unwrap();
+    let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, ["addr", "error", "reachable", "rtt_ms"]);
+
+    alice.send_text("still relayed").await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), bob.receive_message()).await.unwrap();
+    assert_eq!(received.unwrap().as_deref(), Some("still relayed"));
+
+    shutdown.cancel();
+}
@@ -0,0 +1,30 @@
+// Checks over the crate's own source
+
+use std::path::Path;
+
+/// A windowed build has no console, so output has to go through `tracing`
+#[test]
+fn test_no_print_macros_in_src() {
+    // Built up so this file doesn't match itself
+    let macros = [["print", "ln!"].concat(), ["eprint", "ln!"].concat()];
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+
+    let mut found = Vec::new();
+    let mut dirs = vec![src];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                let text = std::fs::read_to_string(&path).unwrap();
+                for (number, line) in text.lines().enumerate() {
+                    if macros.iter().any(|m| line.contains(m.as_str())) {
+                        found.push(format!("{}:{}: {}", path.display(), number + 1, line.trim()));
+                    }
+                }
+            }
+        }
+    }
+    assert!(found.is_empty(), "use tracing instead:\n{}", found.join("\n"));
+}